@@ -159,6 +159,8 @@ impl <'a> RealmInfoRender <'a> {
             if let Some(pid) = self.realm.leader_pid() {
                 self.print(format!(" (Leader pid: {})", pid));
             }
+        } else if self.realm.config_error().is_some() {
+            self.print("  Config error");
         }
         self.newlines(2);
     }
@@ -1,26 +1,62 @@
 use std::fs;
 use std::process::exit;
 
-use libcitadel::{Result,ResourceImage,CommandLine,format_error,KeyRing,LogLevel,Logger};
+use clap::{App,SubCommand,ArgMatches};
+use clap::AppSettings::*;
+use libcitadel::{Result,ResourceImage,CommandLine,format_error,KeyRing,BootStatus,LogLevel,Logger};
 use libcitadel::RealmManager;
 use crate::boot::disks::DiskPartition;
+use crate::preflight::{self, Requirement};
 use std::path::Path;
 
 mod live;
 mod disks;
 mod rootfs;
 
-pub fn main(args: Vec<String>) {
+// The rootfs and setup commands run in the initramfs before /storage is mounted
+const INITRAMFS_REQUIREMENTS: &[Requirement] = &[
+    Requirement::Root,
+    Requirement::Command("/usr/bin/mount"),
+    Requirement::Command("/sbin/veritysetup"),
+];
+
+const START_REALMS_REQUIREMENTS: &[Requirement] = &[
+    Requirement::Root,
+    Requirement::Mounted("/storage"),
+    Requirement::Command("/usr/bin/machinectl"),
+    Requirement::Command("/usr/bin/systemctl"),
+];
+
+pub fn app() -> App<'static, 'static> {
+    App::new("citadel-boot")
+        .about("Boot time setup of the root filesystem, storage and realms")
+        .settings(&[ArgRequiredElseHelp,ColoredHelp, DisableHelpSubcommand, DisableVersion, DeriveDisplayOrder, SubcommandRequiredElseHelp])
+        .arg(crate::log_arg().global(true))
+
+        .subcommand(SubCommand::with_name("rootfs")
+            .about("Set up the root filesystem"))
+
+        .subcommand(SubCommand::with_name("setup")
+            .about("Set up the keyring, mount kernel and extra images and the rootfs overlay"))
+
+        .subcommand(SubCommand::with_name("start-realms")
+            .about("Start realms configured to start at boot"))
+}
+
+pub fn main(matches: &ArgMatches) {
     if CommandLine::debug() {
         Logger::set_log_level(LogLevel::Debug);
     } else if CommandLine::verbose() {
         Logger::set_log_level(LogLevel::Info);
     }
 
-    let result = match args.get(1) {
-        Some(s) if s == "rootfs" => do_rootfs(),
-        Some(s) if s == "setup" => do_setup(),
-        Some(s) if s == "start-realms" => do_start_realms(),
+    let (command, sub_matches) = matches.subcommand();
+    crate::apply_log_arg(sub_matches.unwrap_or(matches));
+
+    let result = match command {
+        "rootfs" => preflight::check("boot rootfs", INITRAMFS_REQUIREMENTS).and_then(|_| do_rootfs()),
+        "setup" => preflight::check("boot setup", INITRAMFS_REQUIREMENTS).and_then(|_| do_setup()),
+        "start-realms" => preflight::check("boot start-realms", START_REALMS_REQUIREMENTS).and_then(|_| do_start_realms()),
         _ => Err(format_err!("Bad or missing argument")),
     };
 
@@ -49,7 +85,10 @@ fn do_setup() -> Result<()> {
     if CommandLine::live_mode() || CommandLine::install_mode() {
         live::live_setup()?;
     } else if let Err(err) = setup_keyring() {
-        warn!("Failed to setup keyring: {}", err);
+        warn!("Failed to setup keyring: {}", format_error(&err));
+        if let Err(e) = BootStatus::record_keyring_failure(&format_error(&err)) {
+            warn!("Failed to record keyring failure: {}", e);
+        }
     }
 
     ResourceImage::mount_image_type("kernel")?;
@@ -26,7 +26,7 @@ fn setup_resource_unverified(img: &ResourceImage) -> Result<()> {
     if img.is_compressed() {
         img.decompress()?;
     }
-    let loopdev = LoopDevice::create(img.path(), Some(4096), true)?;
+    let loopdev = LoopDevice::create(img.path(), Some(img.header().size()), true)?;
     info!("Loop device created: {}", loopdev);
     setup_linear_mapping(loopdev.device())
 }
@@ -0,0 +1,130 @@
+use std::io::{self,Write};
+
+use clap::{App,Arg,ArgMatches,Shell};
+use clap::AppSettings::*;
+
+const BIN_NAME: &str = "citadel-tool";
+
+/// `citadel-tool realm` subcommands which take a realm name as their first argument
+const REALM_NAME_COMMANDS: &[&str] = &["check-config", "start", "stop", "shell", "run", "set-current"];
+
+// Realm names are completed with the output of this command, which falls back
+// to scanning the realms directory when realmsd is not running.
+const REALM_NAMES_COMMAND: &str = "citadel-tool realm list --names-only 2>/dev/null";
+
+pub fn app() -> App<'static, 'static> {
+    App::new("citadel-completions")
+        .about("Print a shell completion script")
+        .after_help("To enable completion in bash:\n    source <(citadel-tool completions bash)")
+        .settings(&[ColoredHelp, DisableHelpSubcommand, DisableVersion])
+        .arg(Arg::with_name("shell")
+            .required(true)
+            .possible_values(&["bash", "zsh", "fish"])
+            .help("Shell to generate completion script for"))
+}
+
+pub fn main(matches: &ArgMatches) {
+    let shell = match matches.value_of("shell") {
+        Some("zsh") => Shell::Zsh,
+        Some("fish") => Shell::Fish,
+        _ => Shell::Bash,
+    };
+    let script = completion_script(shell);
+    if let Err(e) = io::stdout().write_all(script.as_bytes()) {
+        eprintln!("Error: {}", e);
+    }
+}
+
+/// Completion script generated from the argument definitions of every command
+/// followed by the hooks which complete realm names.
+fn completion_script(shell: Shell) -> String {
+    let mut buffer = Vec::new();
+    crate::app().gen_completions_to(BIN_NAME, shell, &mut buffer);
+    let mut script = String::from_utf8_lossy(&buffer).into_owned();
+    match shell {
+        Shell::Bash => script.push_str(&bash_hooks()),
+        Shell::Zsh => {
+            // The generated function is invoked on the last line, replace it with the hook
+            let call = format!("_{} \"$@\"", BIN_NAME);
+            if script.trim_end().ends_with(&call) {
+                let len = script.trim_end().len() - call.len();
+                script.truncate(len);
+            }
+            script.push_str(&zsh_hooks());
+        },
+        _ => script.push_str(&fish_hooks()),
+    }
+    script
+}
+
+fn bash_hooks() -> String {
+    format!(r#"
+_citadel_tool_dynamic() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    local i words=()
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        [[ ${{COMP_WORDS[i]}} == -* ]] || words+=("${{COMP_WORDS[i]}}")
+    done
+    if [[ ${{#words[@]}} -eq 2 && ${{words[0]}} == realm && ${{cur}} != -* ]]; then
+        case "${{words[1]}}" in
+            {commands})
+                COMPREPLY=( $(compgen -W "$({names})" -- "${{cur}}") )
+                return 0
+                ;;
+        esac
+    fi
+    _{bin} "$@"
+}}
+
+complete -F _citadel_tool_dynamic -o bashdefault -o default {bin}
+"#, commands = REALM_NAME_COMMANDS.join("|"), names = REALM_NAMES_COMMAND, bin = BIN_NAME)
+}
+
+fn zsh_hooks() -> String {
+    format!(r#"
+_citadel_tool_dynamic() {{
+    local -a positional realms
+    positional=(${{words[2,CURRENT-1]:#-*}})
+    if [[ ${{#positional}} -eq 2 && ${{positional[1]}} == realm && ${{PREFIX}} != -* ]]; then
+        case "${{positional[2]}}" in
+            ({commands})
+                realms=(${{(f)"$({names})"}})
+                compadd -a realms
+                return
+                ;;
+        esac
+    fi
+    _{bin} "$@"
+}}
+
+_citadel_tool_dynamic "$@"
+"#, commands = REALM_NAME_COMMANDS.join("|"), names = REALM_NAMES_COMMAND, bin = BIN_NAME)
+}
+
+fn fish_hooks() -> String {
+    format!("\ncomplete -c {bin} -n \"__fish_seen_subcommand_from realm; and __fish_seen_subcommand_from {commands}\" -f -a \"({names})\"\n",
+            bin = BIN_NAME, commands = REALM_NAME_COMMANDS.join(" "), names = REALM_NAMES_COMMAND)
+}
+
+#[test]
+fn test_completion_scripts() {
+    let app = crate::app();
+    let subcommands = app.p.subcommands.iter()
+        .map(|sub| sub.p.meta.name.clone())
+        .collect::<Vec<_>>();
+    assert!(subcommands.contains(&"completions".to_string()));
+
+    for &shell in &[Shell::Bash, Shell::Zsh, Shell::Fish] {
+        let script = completion_script(shell);
+        for name in &subcommands {
+            assert!(script.contains(name.as_str()), "{:?} completion does not mention subcommand {}", shell, name);
+        }
+        assert!(script.contains(REALM_NAMES_COMMAND));
+    }
+    assert!(completion_script(Shell::Zsh).trim_end().ends_with("_citadel_tool_dynamic \"$@\""));
+
+    let realm = app.p.subcommands.iter().find(|sub| sub.p.meta.name == "realm").unwrap();
+    for name in REALM_NAME_COMMANDS {
+        assert!(realm.p.subcommands.iter().any(|sub| sub.p.meta.name == *name), "no realm subcommand {}", name);
+    }
+}
@@ -0,0 +1,148 @@
+use std::fs;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path,PathBuf};
+
+use clap::ArgMatches;
+use libcitadel::{Result,ImageHeader,Partition,ResourceImage,util};
+
+///
+/// Everything `citadel-image inspect` reports about an image file or a
+/// partition. Fields which cannot be determined because the header is not
+/// valid are `None`.
+///
+#[derive(Serialize,Default)]
+struct Inspection {
+    path: String,
+    block_device: bool,
+    rootfs_partition: Option<bool>,
+    magic_valid: bool,
+    header_version: Option<u8>,
+    status: Option<String>,
+    flags: Vec<String>,
+    compressed: bool,
+    hashtree: bool,
+    verity_root: Option<String>,
+    signed: bool,
+    metainfo: Option<toml::Value>,
+    #[serde(skip)]
+    metainfo_document: String,
+    expected_shasum: Option<String>,
+    actual_shasum: Option<String>,
+}
+
+pub fn inspect(arg_matches: &ArgMatches) -> Result<()> {
+    let path = Path::new(arg_matches.value_of("path").expect("path argument missing"));
+    if !path.exists() {
+        bail!("Cannot inspect {}: File does not exist", path.display());
+    }
+    let inspection = Inspection::load(path, arg_matches.is_present("verify"))?;
+    if arg_matches.is_present("json") {
+        println!("{}", serde_json::to_string_pretty(&inspection)?);
+    } else {
+        inspection.print();
+    }
+    Ok(())
+}
+
+impl Inspection {
+    fn load(path: &Path, verify: bool) -> Result<Self> {
+        let block_device = fs::metadata(path)?.file_type().is_block_device();
+        let header = if block_device {
+            ImageHeader::from_partition(path)?
+        } else {
+            ImageHeader::from_file(path)?
+        };
+
+        let mut inspection = Inspection {
+            path: path.display().to_string(),
+            block_device,
+            rootfs_partition: if block_device { is_rootfs_partition(path) } else { None },
+            header_version: header.magic_version(),
+            ..Default::default()
+        };
+
+        if inspection.header_version.is_none() {
+            return Ok(inspection);
+        }
+
+        let metainfo = header.metainfo();
+        inspection.magic_valid = true;
+        inspection.status = Some(header.status_code_label());
+        inspection.flags = header.flag_names();
+        inspection.compressed = header.has_flag(ImageHeader::FLAG_DATA_COMPRESSED);
+        inspection.hashtree = header.has_flag(ImageHeader::FLAG_HASH_TREE);
+        inspection.signed = header.has_signature();
+        if !metainfo.verity_root().is_empty() {
+            inspection.verity_root = Some(metainfo.verity_root().to_string());
+        }
+        inspection.metainfo = Some(toml::from_slice(&header.metainfo_bytes())?);
+        inspection.metainfo_document = String::from_utf8_lossy(&header.metainfo_bytes()).to_string();
+        inspection.expected_shasum = Some(metainfo.shasum().to_string());
+
+        if verify {
+            let shasum = if block_device {
+                // On a partition the image data starts at the first block and the header is in the last block
+                util::sha256_partial(path, 0, metainfo.nblocks() as u64 * 4096)?
+            } else {
+                ResourceImage::from_path(path)?.calculate_shasum()?
+            };
+            inspection.actual_shasum = Some(shasum);
+        }
+        Ok(inspection)
+    }
+
+    fn print(&self) {
+        let device = match self.rootfs_partition {
+            Some(true) => " (block device, rootfs partition)",
+            Some(false) => " (block device, not a rootfs partition)",
+            None if self.block_device => " (block device)",
+            None => "",
+        };
+        println!("Path:        {}{}", self.path, device);
+        let version = match self.header_version {
+            Some(version) => version,
+            None => {
+                println!("Header:      invalid (bad magic)");
+                return;
+            }
+        };
+        println!("Header:      valid (version {})", version);
+        println!("Status:      {}", self.status.as_ref().map(|s| s.as_str()).unwrap_or(""));
+        println!("Flags:       {}", if self.flags.is_empty() { "none".to_string() } else { self.flags.join(" ") });
+        println!("Compressed:  {}", yes_no(self.compressed));
+        match self.verity_root {
+            Some(ref root) => println!("Hash tree:   {} (verity-root = {})", yes_no(self.hashtree), root),
+            None => println!("Hash tree:   {}", yes_no(self.hashtree)),
+        }
+        println!("Signed:      {}", yes_no(self.signed));
+        if let Some(ref actual) = self.actual_shasum {
+            let expected = self.expected_shasum.as_ref().map(|s| s.as_str()).unwrap_or("");
+            if actual == expected {
+                println!("Shasum:      ok ({})", actual);
+            } else {
+                println!("Shasum:      MISMATCH (expected {}, actual {})", expected, actual);
+            }
+        }
+        println!("Metainfo:");
+        for line in self.metainfo_document.lines() {
+            println!("    {}", line);
+        }
+    }
+}
+
+fn yes_no(val: bool) -> &'static str {
+    if val { "yes" } else { "no" }
+}
+
+// Returns None if the rootfs partitions cannot be determined
+fn is_rootfs_partition(path: &Path) -> Option<bool> {
+    let canonical = |p: &Path| p.canonicalize().unwrap_or_else(|_| PathBuf::from(p));
+    let target = canonical(path);
+    match Partition::rootfs_partitions() {
+        Ok(partitions) => Some(partitions.iter().any(|p| canonical(p.path()) == target)),
+        Err(e) => {
+            warn!("Could not list rootfs partitions: {}", e);
+            None
+        }
+    }
+}
@@ -3,13 +3,18 @@ use std::process::exit;
 
 use clap::{App,Arg,SubCommand,ArgMatches};
 use clap::AppSettings::*;
-use libcitadel::{Result,ResourceImage,Logger,LogLevel,format_error,Partition,KeyPair,ImageHeader};
+use libcitadel::{Result,ResourceImage,Logger,LogLevel,format_error,Partition,KeyPair,ImageHeader,MountedImage,RealmManager,is_valid_kernel_flavor};
+use std::collections::HashSet;
 use std::fs;
 use hex;
 
-pub fn main(args: Vec<String>) {
+use crate::partition;
 
-    let app = App::new("citadel-image")
+mod inspect;
+
+pub fn app() -> App<'static, 'static> {
+
+    App::new("citadel-image")
         .about("Citadel update image builder")
         .settings(&[ArgRequiredElseHelp,ColoredHelp, DisableHelpSubcommand, DisableVersion, DeriveDisplayOrder])
 
@@ -25,6 +30,24 @@ pub fn main(args: Vec<String>) {
                 .required(true)
                 .help("Path to image file")))
 
+        .subcommand(SubCommand::with_name("inspect")
+            .about("Display header, metainfo and verity information for an image file or partition")
+            .arg(Arg::with_name("verify")
+                .long("verify")
+                .help("Calculate sha256 of image data and compare with metainfo"))
+            .arg(Arg::with_name("json")
+                .long("json")
+                .help("Display output as JSON"))
+            .arg(Arg::with_name("path")
+                .required(true)
+                .help("Path to image file or block device")))
+
+        .subcommand(SubCommand::with_name("mounts")
+            .about("List resource images mounted below /run/citadel/images")
+            .arg(Arg::with_name("cleanup")
+                .long("cleanup")
+                .help("Unmount images which are not in use by a running realm or the active kernel and extra images")))
+
         .subcommand(SubCommand::with_name("generate-verity")
             .about("Generate dm-verity hash tree for an image file")
             .arg(Arg::with_name("path")
@@ -48,6 +71,9 @@ pub fn main(args: Vec<String>) {
             .arg(Arg::with_name("no-prefer")
                 .long("no-prefer")
                 .help("Don't set PREFER_BOOT flag"))
+            .arg(Arg::with_name("verify-write")
+                .long("verify-write")
+                .help("Read back image data from partition after writing and verify sha256 value"))
             .arg(Arg::with_name("path")
                 .required_unless("choose")
                 .help("Path to image file")))
@@ -68,14 +94,17 @@ pub fn main(args: Vec<String>) {
             .about("Verify the sha256 sum of the image")
             .arg(Arg::with_name("path")
                 .required(true)
-                .help("Path to image file")));
+                .help("Path to image file")))
+}
 
+pub fn main(matches: &ArgMatches) {
     Logger::set_log_level(LogLevel::Debug);
 
-    let matches = app.get_matches_from(args);
     let result = match matches.subcommand() {
         ("metainfo", Some(m)) => metainfo(m),
         ("info", Some(m)) => info(m),
+        ("inspect", Some(m)) => inspect::inspect(m),
+        ("mounts", Some(m)) => mounts(m),
         ("generate-verity", Some(m)) => generate_verity(m),
         ("verify", Some(m)) => verify(m),
         ("sign-image", Some(m)) => sign_image(m),
@@ -125,6 +154,43 @@ fn metainfo(arg_matches: &ArgMatches) -> Result<()> {
     Ok(())
 }
 
+fn mounts(arg_matches: &ArgMatches) -> Result<()> {
+    let images = ResourceImage::mounted_images();
+    if !arg_matches.is_present("cleanup") {
+        for image in &images {
+            let backing = image.backing_file().map(|p| p.display().to_string()).unwrap_or_else(|| "unknown".to_string());
+            println!("{}", image.mountpoint().display());
+            println!("    device: {}  backing file: {}", image.source().display(), backing);
+        }
+        return Ok(());
+    }
+
+    let realmfs_in_use = RealmManager::load()?.active_realms(false).iter()
+        .map(|r| r.config().realmfs().to_string())
+        .collect::<HashSet<_>>();
+
+    for image in images.iter().filter(|image| !is_mount_in_use(image, &realmfs_in_use)) {
+        if let Err(e) = image.unmount() {
+            warn!("Failed to unmount {}: {}", image.mountpoint().display(), e);
+        }
+    }
+    Ok(())
+}
+
+// The kernel and extra images mounted at boot are always in use. A realmfs image
+// is in use if it is the realmfs of a running realm.
+fn is_mount_in_use(image: &MountedImage, realmfs_in_use: &HashSet<String>) -> bool {
+    let name = match image.mountpoint().file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => return false,
+    };
+    if name == "kernel.mountpoint" || name == "extra.mountpoint" {
+        return true;
+    }
+    let suffix = "-realmfs.mountpoint";
+    name.ends_with(suffix) && realmfs_in_use.contains(&name[..name.len() - suffix.len()])
+}
+
 fn generate_verity(arg_matches: &ArgMatches) -> Result<()> {
     let img = load_image(arg_matches)?;
     if img.has_verity_hashtree() {
@@ -193,7 +259,7 @@ fn install_rootfs(arg_matches: &ArgMatches) -> Result<()> {
         clear_prefer_boot()?;
         img.header().set_flag(ImageHeader::FLAG_PREFER_BOOT);
     }
-    img.write_to_partition(&partition)?;
+    img.write_to_partition(&partition, arg_matches.is_present("verify-write"))?;
     Ok(())
 }
 
@@ -239,7 +305,10 @@ fn install_image(arg_matches: &ArgMatches) -> Result<()> {
         if kernel_version.chars().any(|c| c == '/') {
             bail!("Kernel version field has / char");
         }
-        format!("citadel-kernel-{}-{:03}.img", kernel_version, metainfo.version())
+        if !is_valid_kernel_flavor(metainfo.kernel_flavor()) {
+            bail!("Kernel image has invalid kernel flavor '{}'", metainfo.kernel_flavor());
+        }
+        crate::update::kernel_image_filename(kernel_version, metainfo.kernel_flavor(), metainfo.version())
     } else {
         format!("citadel-extra-{:03}.img", metainfo.version())
     };
@@ -296,24 +365,11 @@ fn bless() -> Result<()> {
     Ok(())
 }
 
-fn bool_to_yesno(val: bool) -> &'static str {
-    if val {
-        "YES"
-    } else {
-        " NO"
-    }
-}
-
 fn choose_install_partition(verbose: bool) -> Result<Partition> {
     let partitions = Partition::rootfs_partitions()?;
 
     if verbose {
-        for p in &partitions {
-            info!("Partition: {}  (Mounted: {}) (Empty: {})",
-                  p.path().display(),
-                  bool_to_yesno(p.is_mounted()),
-                  bool_to_yesno(!p.is_initialized()));
-        }
+        partition::print_install_candidates(&partitions);
     }
 
     for p in &partitions {
@@ -328,8 +384,7 @@ fn choose_install_partition(verbose: bool) -> Result<Partition> {
         if !p.is_mounted() {
             if verbose {
                 info!("Choosing {} because it is not mounted", p.path().display());
-                info!("Header metainfo:");
-                print!("{}",String::from_utf8(p.header().metainfo_bytes())?);
+                partition::print_metainfo(p)?;
             }
             return Ok(p.clone())
         }
@@ -15,7 +15,7 @@ use libcitadel::KeyRing;
 use libcitadel::terminal::Base16Scheme;
 use libcitadel::UtsName;
 
-const LUKS_UUID: &str = "683a17fc-4457-42cc-a946-cde67195a101";
+pub(crate) const LUKS_UUID: &str = "683a17fc-4457-42cc-a946-cde67195a101";
 
 const EXTRA_IMAGE_NAME: &str = "citadel-extra.img";
 
@@ -6,7 +6,20 @@ mod disk;
 
 use libcitadel::format_error;
 
+use crate::preflight::{self, Requirement};
+
+const REQUIREMENTS: &[Requirement] = &[
+    Requirement::Root,
+    Requirement::Command("/sbin/parted"),
+    Requirement::Command("/sbin/cryptsetup"),
+    Requirement::Command("/sbin/pvcreate"),
+];
+
 pub fn main(args: Vec<String>) {
+    if let Err(ref err) = preflight::check("install", REQUIREMENTS) {
+        println!("Install failed: {}", format_error(err));
+        exit(1);
+    }
     let mut args = args.iter().skip(1);
     let result = if let Some(dev) = args.next() {
         cli::run_cli_install_with(dev)
@@ -0,0 +1,292 @@
+use std::fs::{self,File};
+use std::io::{self,Read,Write};
+use std::os::unix::io::{AsRawFd,FromRawFd};
+use std::path::Path;
+use std::process::{exit,Command,Stdio};
+
+use clap::{App,Arg,SubCommand,ArgMatches};
+use clap::AppSettings::*;
+use libcitadel::{Result,KeyRing,Logger,LogLevel,format_error};
+
+use crate::install::installer::LUKS_UUID;
+use crate::preflight::{self, Requirement};
+
+const KEYRING_PATH: &str = "/storage/keyring";
+const GENERATED_KEY_LEN: usize = 32;
+
+const CRYPTSETUP_REQUIREMENTS: &[Requirement] = &[Requirement::Command("/sbin/cryptsetup")];
+const ADD_KEY_REQUIREMENTS: &[Requirement] = &[Requirement::Root];
+
+pub fn app() -> App<'static, 'static> {
+
+    let keyring_arg = || Arg::with_name("keyring")
+        .long("keyring")
+        .takes_value(true)
+        .default_value(KEYRING_PATH)
+        .help("Path to keyring file");
+
+    App::new("citadel-keyring")
+        .about("Manage the storage keyring")
+        .settings(&[ArgRequiredElseHelp,ColoredHelp, DisableHelpSubcommand, DisableVersion, DeriveDisplayOrder])
+
+        .subcommand(SubCommand::with_name("change-passphrase")
+            .about("Re-encrypt the keyring with a new passphrase")
+            .arg(keyring_arg())
+            .arg(Arg::with_name("luks-device")
+                .long("luks-device")
+                .takes_value(true)
+                .help("LUKS device to also change passphrase on (default: installed storage partition)")))
+
+        .subcommand(SubCommand::with_name("list")
+            .about("List the keys in the keyring")
+            .arg(keyring_arg()))
+
+        .subcommand(SubCommand::with_name("backup")
+            .about("Write an encrypted backup of the keyring")
+            .arg(keyring_arg())
+            .arg(Arg::with_name("path")
+                .required(true)
+                .help("Path of backup file to create")))
+
+        .subcommand(SubCommand::with_name("restore")
+            .about("Restore the keyring from an encrypted backup")
+            .arg(keyring_arg())
+            .arg(Arg::with_name("force")
+                .long("force")
+                .help("Overwrite an existing keyring file"))
+            .arg(Arg::with_name("path")
+                .required(true)
+                .help("Path of backup file to restore")))
+
+        .subcommand(SubCommand::with_name("add-key")
+            .about("Add a key to the keyring and to the kernel keyring. The secret is read from stdin and a single trailing newline is removed")
+            .arg(keyring_arg())
+            .arg(Arg::with_name("generate")
+                .long("generate")
+                .help("Generate a random secret instead of reading it from stdin"))
+            .arg(Arg::with_name("name")
+                .required(true)
+                .help("Name of key to add")))
+}
+
+pub fn main(matches: &ArgMatches) {
+    Logger::set_log_level(LogLevel::Info);
+
+    let result = match matches.subcommand() {
+        ("change-passphrase", Some(m)) => change_passphrase(m),
+        ("list", Some(m)) => list(m),
+        ("backup", Some(m)) => backup(m),
+        ("restore", Some(m)) => restore(m),
+        ("add-key", Some(m)) => add_key(m),
+        _ => Ok(()),
+    };
+
+    if let Err(ref e) = result {
+        println!("Error: {}", format_error(e));
+        exit(1);
+    }
+}
+
+fn keyring_path<'a>(arg_matches: &'a ArgMatches) -> &'a Path {
+    Path::new(arg_matches.value_of("keyring").expect("keyring argument missing"))
+}
+
+fn load_keyring(path: &Path) -> Result<KeyRing> {
+    if !path.exists() {
+        bail!("Keyring file {} does not exist", path.display());
+    }
+    let passphrase = rpassword::read_password_from_tty(Some("Keyring passphrase : "))?;
+    KeyRing::load(path, &passphrase)
+}
+
+fn list(arg_matches: &ArgMatches) -> Result<()> {
+    let keyring = load_keyring(keyring_path(arg_matches))?;
+    for key in keyring.list_keys() {
+        println!("{}", key.name());
+        println!("    purpose:     {}", key.purpose());
+        match key.fingerprint() {
+            Some(fingerprint) => println!("    fingerprint: {}", fingerprint),
+            None => println!("    type:        secret"),
+        }
+    }
+    Ok(())
+}
+
+fn backup(arg_matches: &ArgMatches) -> Result<()> {
+    let target = Path::new(arg_matches.value_of("path").expect("path argument missing"));
+    if target.exists() {
+        bail!("Backup file {} already exists", target.display());
+    }
+    let keyring = load_keyring(keyring_path(arg_matches))?;
+    println!("Choose a passphrase to protect the backup file");
+    let passphrase = read_new_passphrase()?;
+    keyring.export_backup(target, &passphrase)?;
+    info!("Keyring backup written to {}", target.display());
+    Ok(())
+}
+
+fn restore(arg_matches: &ArgMatches) -> Result<()> {
+    let source = Path::new(arg_matches.value_of("path").expect("path argument missing"));
+    let path = keyring_path(arg_matches);
+    if path.exists() && !arg_matches.is_present("force") {
+        bail!("Keyring file {} already exists, use --force to overwrite it", path.display());
+    }
+
+    let backup_passphrase = rpassword::read_password_from_tty(Some("Backup passphrase : "))?;
+    let keyring = KeyRing::import_backup(source, &backup_passphrase)?;
+
+    println!("Choose the keyring passphrase. At boot the keyring is unlocked with the disk");
+    println!("encryption passphrase, so this should be the same passphrase.");
+    let passphrase = read_new_passphrase()?;
+    if path.exists() {
+        let saved = path.with_extension("bak");
+        fs::copy(path, &saved)?;
+        info!("Existing keyring saved as {}", saved.display());
+    }
+    keyring.write_atomic(path, &passphrase)?;
+    info!("Keyring restored to {}", path.display());
+    Ok(())
+}
+
+fn add_key(arg_matches: &ArgMatches) -> Result<()> {
+    preflight::check("keyring add-key", ADD_KEY_REQUIREMENTS)?;
+    let path = keyring_path(arg_matches);
+    let name = arg_matches.value_of("name").expect("name argument missing");
+    if !path.exists() {
+        bail!("Keyring file {} does not exist", path.display());
+    }
+
+    let mut secret = if arg_matches.is_present("generate") {
+        let mut buf = vec![0u8; GENERATED_KEY_LEN];
+        fs::File::open("/dev/urandom")?.read_exact(&mut buf)?;
+        buf
+    } else {
+        let mut buf = Vec::new();
+        io::stdin().read_to_end(&mut buf)?;
+        if buf.last() == Some(&b'\n') {
+            buf.pop();
+        }
+        buf
+    };
+    if secret.is_empty() {
+        bail!("Secret for key '{}' is empty", name);
+    }
+
+    let passphrase = rpassword::read_password_from_tty(Some("Keyring passphrase : "))?;
+    let result = KeyRing::add_key(path, &passphrase, name, &secret);
+    secret.iter_mut().for_each(|b| *b = 0);
+    result?;
+    println!("{}", name);
+    Ok(())
+}
+
+fn change_passphrase(arg_matches: &ArgMatches) -> Result<()> {
+    preflight::check("keyring change-passphrase", CRYPTSETUP_REQUIREMENTS)?;
+    let path = keyring_path(arg_matches);
+    if !path.exists() {
+        bail!("Keyring file {} does not exist", path.display());
+    }
+    let default_device = format!("/dev/disk/by-uuid/{}", LUKS_UUID);
+    let luks_device = Path::new(arg_matches.value_of("luks-device").unwrap_or(&default_device));
+
+    let old_passphrase = rpassword::read_password_from_tty(Some("Current keyring passphrase : "))?;
+    KeyRing::load(path, &old_passphrase)
+        .map_err(|e| format_err!("Current passphrase does not open {}: {}", path.display(), e))?;
+
+    let new_passphrase = read_new_passphrase()?;
+    let change_luks = if !luks_device.exists() {
+        warn!("LUKS device {} not found.", luks_device.display());
+        warn!("At boot the keyring is unlocked with the disk encryption passphrase, make sure they match.");
+        false
+    } else if is_luks_passphrase(luks_device, &new_passphrase)? {
+        false
+    } else {
+        println!();
+        println!("WARNING: The new passphrase is not the disk encryption passphrase of {}.", luks_device.display());
+        println!("WARNING: At boot the keyring is unlocked with the disk encryption passphrase, so");
+        println!("WARNING: the keyring will fail to load unless the disk passphrase is also changed.");
+        println!();
+        confirm(&format!("Also change disk encryption passphrase on {} with cryptsetup luksChangeKey?", luks_device.display()))?
+    };
+
+    KeyRing::change_passphrase(path, &old_passphrase, &new_passphrase)?;
+    info!("Keyring {} re-encrypted with new passphrase, previous keyring saved as {}",
+          path.display(), path.with_extension("bak").display());
+
+    if change_luks {
+        luks_change_key(luks_device, &old_passphrase, &new_passphrase)
+            .map_err(|e| format_err!("Keyring passphrase was changed but changing disk encryption passphrase failed: {}", e))?;
+        info!("Disk encryption passphrase changed on {}", luks_device.display());
+    }
+    Ok(())
+}
+
+fn read_new_passphrase() -> Result<String> {
+    loop {
+        let passphrase = rpassword::read_password_from_tty(Some("New passphrase             : "))?;
+        if passphrase.is_empty() {
+            println!("Passphrase cannot be empty");
+            continue;
+        }
+        let confirm = rpassword::read_password_from_tty(Some("Confirm new passphrase     : "))?;
+        if passphrase == confirm {
+            return Ok(passphrase);
+        }
+        println!("Passphrases do not match");
+    }
+}
+
+fn confirm(prompt: &str) -> Result<bool> {
+    print!("{} [y/N] : ", prompt);
+    io::stdout().flush()?;
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    let input = input.trim();
+    Ok(input == "y" || input == "Y")
+}
+
+// Passphrases are passed to cryptsetup on stdin with --key-file=- so that they
+// are never written to disk or appear on the command line.
+fn cryptsetup_with_key(args: &[&str], passphrase: &str) -> Result<bool> {
+    let mut child = Command::new("/sbin/cryptsetup")
+        .args(args)
+        .arg("--key-file=-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    child.stdin.take().expect("cryptsetup stdin").write_all(passphrase.as_bytes())?;
+    Ok(child.wait()?.success())
+}
+
+fn is_luks_passphrase(device: &Path, passphrase: &str) -> Result<bool> {
+    let device = device.to_string_lossy();
+    cryptsetup_with_key(&["open", "--type", "luks", "--test-passphrase", &device], passphrase)
+}
+
+fn luks_change_key(device: &Path, old_passphrase: &str, new_passphrase: &str) -> Result<()> {
+    // cryptsetup reads the new passphrase from a file. Write it into a pipe which
+    // cryptsetup inherits and opens as /dev/fd/N so that it never touches a filesystem.
+    let (reader, mut writer) = pipe()?;
+    writer.write_all(new_passphrase.as_bytes())?;
+    drop(writer);
+
+    let device = device.to_string_lossy();
+    let key_file = format!("/dev/fd/{}", reader.as_raw_fd());
+    let result = cryptsetup_with_key(&["luksChangeKey", &device, &key_file], old_passphrase);
+    drop(reader);
+    if !result? {
+        bail!("cryptsetup luksChangeKey failed on {}", device);
+    }
+    Ok(())
+}
+
+// Unlike std pipes the returned descriptors are not close-on-exec, so the read end
+// is inherited by child processes while it is open.
+fn pipe() -> Result<(File, File)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
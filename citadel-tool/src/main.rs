@@ -7,13 +7,24 @@ use std::env;
 use std::path::Path;
 use std::ffi::OsStr;
 use std::iter;
-use libcitadel::RealmManager;
+use std::process;
+use clap::{App,Arg,ArgMatches,SubCommand};
+use clap::AppSettings::*;
+use libcitadel::{Logger,RealmManager};
 
 mod boot;
+mod completions;
 mod image;
 mod install;
+mod keyring;
 mod mkimage;
+mod network;
+mod output;
+mod partition;
+mod preflight;
+mod realm;
 mod realmfs;
+mod status;
 mod sync;
 mod update;
 
@@ -26,17 +37,18 @@ fn main() {
     };
 
     let args = env::args().collect::<Vec<String>>();
+    output::init();
 
     if exe == Path::new("/usr/libexec/citadel-boot") {
-        boot::main(args);
+        boot::main(&boot::app().get_matches_from(args));
     } else if exe == Path::new("/usr/libexec/citadel-install") {
         install::main(args);
     } else if exe == Path::new("/usr/bin/citadel-image") {
-        image::main(args);
+        image::main(&image::app().get_matches_from(args));
     } else if exe == Path::new("/usr/bin/citadel-realmfs") {
-        realmfs::main(args);
+        realmfs::main(&realmfs::app().get_matches_from(args));
     } else if exe == Path::new("/usr/bin/citadel-update") {
-        update::main(args);
+        update::main(&update::app().get_matches_from(args));
     } else if exe == Path::new("/usr/libexec/citadel-desktop-sync") {
         sync::main(args);
     } else if exe == Path::new("/usr/libexec/citadel-run") {
@@ -50,33 +62,108 @@ fn main() {
     }
 }
 
-fn dispatch_command(args: Vec<String>) {
-    if let Some(command) = args.get(1) {
-        match command.as_str() {
-            "boot" => boot::main(rebuild_args("citadel-boot", args)),
-            "install" => install::main(rebuild_args("citadel-install", args)),
-            "image" => image::main(rebuild_args("citadel-image", args)),
-            "realmfs" => realmfs::main(rebuild_args("citadel-realmfs", args)),
-            "update" => update::main(rebuild_args("citadel-update", args)),
-            "mkimage" => mkimage::main(rebuild_args("citadel-mkimage", args)),
-            "sync" => sync::main(rebuild_args("citadel-desktop-sync", args)),
-            "run" => do_citadel_run(rebuild_args("citadel-run", args)),
-            _ => println!("Error: unknown command {}", command),
+fn app() -> App<'static, 'static> {
+    // Commands which still parse their own arguments receive everything after the command name
+    let passthrough = |name: &'static str, about: &'static str| SubCommand::with_name(name)
+        .about(about)
+        .settings(&[TrailingVarArg, AllowLeadingHyphen])
+        .arg(Arg::with_name("args").multiple(true));
+
+    App::new("citadel-tool")
+        .about("Citadel system management tool")
+        .settings(&[ArgRequiredElseHelp,ColoredHelp, DisableHelpSubcommand, DisableVersion, DeriveDisplayOrder, SubcommandRequiredElseHelp])
+        .subcommand(boot::app().name("boot"))
+        .subcommand(passthrough("install", "Install Citadel to a disk"))
+        .subcommand(image::app().name("image"))
+        .subcommand(realm::app().name("realm"))
+        .subcommand(realmfs::app().name("realmfs"))
+        .subcommand(update::app().name("update"))
+        .subcommand(keyring::app().name("keyring"))
+        .subcommand(passthrough("mkimage", "Build a resource image"))
+        .subcommand(partition::app().name("partition"))
+        .subcommand(network::app().name("network"))
+        .subcommand(passthrough("sync", "Synchronize desktop files from realms"))
+        .subcommand(passthrough("run", "Run a command in the current realm"))
+        .subcommand(status::app().name("status"))
+        .subcommand(status::doctor::app().name("doctor"))
+        .subcommand(completions::app().name("completions"))
+}
+
+/// Add the `--log <SPEC>` option which overrides the log level
+pub(crate) fn log_arg() -> Arg<'static, 'static> {
+    Arg::with_name("log")
+        .long("log")
+        .takes_value(true)
+        .value_name("SPEC")
+        .validator(|spec| Logger::check_log_spec(&spec).map_err(|e| e.to_string()))
+        .help("Log level specification such as 'debug' or 'info,realm=debug'")
+}
+
+/// Apply the log specification from `--log` if it was given
+pub(crate) fn apply_log_arg(matches: &ArgMatches) {
+    if let Some(spec) = matches.value_of("log") {
+        if let Err(e) = Logger::set_log_spec(spec) {
+            warn!("{}", e);
         }
-    } else {
-        println!("Must provide an argument");
     }
 }
 
-fn rebuild_args(command: &str, args: Vec<String>) -> Vec<String> {
+fn dispatch_command(args: Vec<String>) {
+    let matches = app().get_matches_from(args);
+    match matches.subcommand() {
+        ("boot", Some(m)) => boot::main(m),
+        ("install", Some(m)) => install::main(rebuild_args("citadel-install", m)),
+        ("image", Some(m)) => image::main(m),
+        ("realm", Some(m)) => realm::main(m),
+        ("realmfs", Some(m)) => realmfs::main(m),
+        ("update", Some(m)) => update::main(m),
+        ("keyring", Some(m)) => keyring::main(m),
+        ("mkimage", Some(m)) => mkimage::main(rebuild_args("citadel-mkimage", m)),
+        ("partition", Some(m)) => partition::main(m),
+        ("network", Some(m)) => network::main(m),
+        ("sync", Some(m)) => sync::main(rebuild_args("citadel-desktop-sync", m)),
+        ("run", Some(m)) => do_citadel_run(rebuild_args("citadel-run", m)),
+        ("status", Some(m)) => status::main(m),
+        ("doctor", Some(m)) => status::doctor::main(m),
+        ("completions", Some(m)) => completions::main(m),
+        _ => println!("Must provide an argument"),
+    }
+}
+
+fn rebuild_args(command: &str, matches: &ArgMatches) -> Vec<String> {
     iter::once(command.to_string())
-        .chain(args.into_iter().skip(2))
+        .chain(matches.values_of("args").into_iter().flatten().map(|s| s.to_string()))
         .collect()
 }
 
 fn do_citadel_run(args: Vec<String>) {
-    if let Err(e) = RealmManager::run_in_current(&args[1..], true) {
-        println!("RealmManager::run_in_current({:?}) failed: {}", &args[1..], e);
+    match RealmManager::run_in_current(&args[1..], true) {
+        Ok(status) => process::exit(status.code().unwrap_or(1)),
+        Err(e) => {
+            println!("RealmManager::run_in_current({:?}) failed: {}", &args[1..], e);
+            process::exit(1);
+        }
     }
 }
 
+
+#[test]
+fn test_command_args() {
+    let parse = |args: &[&str]| app().get_matches_from_safe(args.iter().cloned());
+
+    let m = parse(&["citadel-tool", "boot", "--log", "debug", "rootfs"]).unwrap();
+    assert_eq!(m.subcommand_matches("boot").unwrap().subcommand_name(), Some("rootfs"));
+    let m = parse(&["citadel-tool", "boot", "rootfs", "--log", "debug"]).unwrap();
+    let boot = m.subcommand_matches("boot").unwrap();
+    assert_eq!(boot.subcommand_matches("rootfs").unwrap().value_of("log"), Some("debug"));
+    assert!(parse(&["citadel-tool", "boot", "bogus"]).is_err());
+
+    let m = parse(&["citadel-tool", "mkimage", "--no-compress", "build.conf"]).unwrap();
+    assert_eq!(rebuild_args("citadel-mkimage", m.subcommand_matches("mkimage").unwrap()),
+               vec!["citadel-mkimage", "--no-compress", "build.conf"]);
+    let m = parse(&["citadel-tool", "run"]).unwrap();
+    assert_eq!(rebuild_args("citadel-run", m.subcommand_matches("run").unwrap()), vec!["citadel-run"]);
+    let m = parse(&["citadel-tool", "network", "show-rules"]).unwrap();
+    assert_eq!(m.subcommand_matches("network").unwrap().subcommand_name(), Some("show-rules"));
+    assert!(parse(&["citadel-tool", "unknown"]).is_err());
+}
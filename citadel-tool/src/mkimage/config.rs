@@ -1,37 +1,47 @@
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::Read;
 use std::path::{Path, PathBuf};
 
 use toml;
 
-use libcitadel::Result;
+use libcitadel::{ImageInfo,Result};
 
 #[derive(Deserialize)]
 pub struct BuildConfig {
     #[serde(rename = "image-type")]
     image_type: String,
     channel: String,
-    version: usize,
+    version: u32,
     timestamp: String,
     source: String,
     #[serde(default)]
     compress: bool,
+    #[serde(default = "default_true")]
+    verity: bool,
+    #[serde(default)]
+    hashtree: bool,
     #[serde(rename = "kernel-version")]
     kernel_version: Option<String>,
     #[serde(rename = "kernel-id")]
     kernel_id: Option<String>,
+    #[serde(rename = "kernel-flavor")]
+    kernel_flavor: Option<String>,
 
     #[serde(rename = "realmfs-name")]
     realmfs_name: Option<String>,
 
+    #[serde(default)]
+    extra: BTreeMap<String, String>,
+
     #[serde(skip)]
     basedir: PathBuf,
     #[serde(skip)]
     src_path: PathBuf,
-    #[serde(skip)]
-    img_name: String,
 }
 
+fn default_true() -> bool { true }
+
 impl BuildConfig {
     pub fn load<P: AsRef<Path>>(path: P) -> Result<BuildConfig> {
         let mut path = path.as_ref().to_owned();
@@ -47,10 +57,6 @@ impl BuildConfig {
         path.pop();
         config.basedir = path;
         config.src_path = PathBuf::from(&config.source);
-        config.img_name = match config.kernel_version {
-            Some(ref version) => format!("{}-{}", &config.image_type, version),
-            None => config.image_type.to_owned(),
-        };
         Ok(config)
     }
 
@@ -64,25 +70,24 @@ impl BuildConfig {
     }
 
     fn validate(&self) -> Result<()> {
-        let itype = self.image_type.as_str();
-        if itype != "extra" && itype != "rootfs" && itype != "kernel" && itype != "realmfs" {
-            bail!("Invalid image type '{}'", self.image_type);
-        };
+        self.image_info().validate()?;
         let src = Path::new(&self.source);
-        if !src.is_file() {
-            bail!(
-                "Source path '{}' does not exist or is not a regular file",
-                src.display()
-            );
+        if !src.exists() {
+            bail!("Source path '{}' does not exist", src.display());
         }
-        if self.image_type == "kernel" && self.kernel_version.is_none() {
-            bail!("Cannot build 'kernel' image without kernel-version field");
-        }
-
         Ok(())
     }
 
-    pub fn timestamp(&self) -> &str { &self.timestamp }
+    /// Metainfo fields of the image described by this config
+    pub fn image_info(&self) -> ImageInfo {
+        let mut info = ImageInfo::new(&self.image_type, &self.channel, self.version, &self.timestamp);
+        info.kernel_version = self.kernel_version.clone();
+        info.kernel_id = self.kernel_id.clone();
+        info.kernel_flavor = self.kernel_flavor.clone();
+        info.realmfs_name = self.realmfs_name.clone();
+        info.extra = self.extra.clone();
+        info
+    }
 
     pub fn source(&self) -> &Path {
         &self.src_path
@@ -92,35 +97,19 @@ impl BuildConfig {
         self.basedir.join(filename.as_ref())
     }
 
-    pub fn img_name(&self) -> &str {
-        &self.img_name
-    }
-
-    pub fn kernel_version(&self) -> Option<&str> {
-        self.kernel_version.as_ref().map(|s| s.as_str())
-    }
-
-    pub fn kernel_id(&self) -> Option<&str> {
-        self.kernel_id.as_ref().map(|s| s.as_str())
-    }
-
-    pub fn realmfs_name(&self) -> Option<&str> {
-        self.realmfs_name.as_ref().map(|s| s.as_str())
-    }
-
-    pub fn version(&self) -> usize {
-        self.version
-    }
-
     pub fn channel(&self) -> &str {
         &self.channel
     }
 
-    pub fn image_type(&self) -> &str {
-        &self.image_type
-    }
-
     pub fn compress(&self) -> bool {
         self.compress
     }
+
+    pub fn verity(&self) -> bool {
+        self.verity
+    }
+
+    pub fn hashtree(&self) -> bool {
+        self.hashtree
+    }
 }
@@ -1,10 +1,10 @@
 
+use std::fs;
 use std::process::exit;
 
-use libcitadel::Result;
+use libcitadel::{Result,ResourceImage,ResourceImageBuilder,devkeys};
 
 mod config;
-mod build;
 
 pub fn main(args: Vec<String>) {
 
@@ -26,6 +26,23 @@ pub fn main(args: Vec<String>) {
 
 fn build_image(config_path: &str) -> Result<()> {
     let conf = config::BuildConfig::load(config_path)?;
-    let mut builder = build::UpdateBuilder::new(conf);
-    builder.build()
-}
\ No newline at end of file
+    let info = conf.image_info();
+    let builder = if conf.source().is_dir() {
+        ResourceImageBuilder::from_directory(conf.source(), info.clone())
+    } else {
+        ResourceImageBuilder::from_squashfs(conf.source(), info.clone())
+    };
+    let mut builder = builder
+        .compress(conf.compress())
+        .verity(conf.verity())
+        .hashtree(conf.hashtree());
+    if conf.channel() == "dev" {
+        builder = builder.signing_key(devkeys());
+    }
+    let target = conf.workdir_path(info.image_filename());
+    builder.build(&target)?;
+
+    let image = ResourceImage::from_path(&target)?;
+    fs::write(conf.workdir_path("metainfo"), image.header().metainfo_bytes())?;
+    Ok(())
+}
@@ -0,0 +1,32 @@
+use std::process::exit;
+
+use clap::{App,ArgMatches,SubCommand};
+use clap::AppSettings::*;
+use libcitadel::{Result,Firewall,NetworkZone,format_error};
+
+pub fn app() -> App<'static, 'static> {
+    App::new("citadel-network")
+        .about("Inspect realm networking")
+        .settings(&[ArgRequiredElseHelp,ColoredHelp, DisableHelpSubcommand, DisableVersion, DeriveDisplayOrder, SubcommandRequiredElseHelp])
+
+        .subcommand(SubCommand::with_name("show-rules")
+            .about("Print the nftables ruleset installed by realmsd for the configured network zones"))
+}
+
+pub fn main(matches: &ArgMatches) {
+    let result = match matches.subcommand() {
+        ("show-rules", Some(_)) => show_rules(),
+        _ => Ok(()),
+    };
+
+    if let Err(ref e) = result {
+        println!("Error: {}", format_error(e));
+        exit(1);
+    }
+}
+
+fn show_rules() -> Result<()> {
+    let zones = NetworkZone::load_all()?;
+    print!("{}", Firewall::ruleset(&zones));
+    Ok(())
+}
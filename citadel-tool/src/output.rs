@@ -0,0 +1,253 @@
+use std::env;
+use std::io::{self,Write};
+use std::sync::atomic::{AtomicBool,Ordering};
+
+use libcitadel::{Result,Logger,LogLevel,LogOutput,JournalLogOutput,util};
+
+lazy_static! {
+    static ref COLOR_ENABLED: bool = {
+        let term = env::var("TERM").ok();
+        color_supported(env::var_os("NO_COLOR").is_some(), term.as_deref(), util::is_stdout_tty())
+    };
+}
+
+static JSON_MODE: AtomicBool = AtomicBool::new(false);
+
+// Color is used only when writing to a terminal and can be disabled by setting
+// NO_COLOR to any value (https://no-color.org)
+fn color_supported(no_color: bool, term: Option<&str>, tty: bool) -> bool {
+    tty && !no_color && term.is_some() && term != Some("dumb")
+}
+
+/// Returns `true` if output written to stdout should be colorized
+pub fn color_enabled() -> bool {
+    *COLOR_ENABLED && !JSON_MODE.load(Ordering::Relaxed)
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Style {
+    Header,
+    Good,
+    Warning,
+    Error,
+    Dim,
+}
+
+impl Style {
+    fn escape_code(self) -> &'static str {
+        match self {
+            Style::Header  => "\x1b[1m",
+            Style::Good    => "\x1b[32m",
+            Style::Warning => "\x1b[33m",
+            Style::Error   => "\x1b[1;31m",
+            Style::Dim     => "\x1b[2m",
+        }
+    }
+
+    fn for_level(level: LogLevel) -> Option<Style> {
+        match level {
+            LogLevel::Warn => Some(Style::Warning),
+            LogLevel::Notice => Some(Style::Header),
+            LogLevel::Info => None,
+            LogLevel::Verbose | LogLevel::Debug => Some(Style::Dim),
+        }
+    }
+}
+
+const RESET: &str = "\x1b[0m";
+
+/// Return `text` wrapped in the escape codes for `style` if color output is enabled
+pub fn paint(style: Style, text: impl AsRef<str>) -> String {
+    paint_with(color_enabled(), style, text.as_ref())
+}
+
+fn paint_with(color: bool, style: Style, text: &str) -> String {
+    if color && !text.is_empty() {
+        format!("{}{}{}", style.escape_code(), text, RESET)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Set up console output for the tool. Log lines written to a terminal
+/// have their level prefix colorized.
+pub fn init() {
+    if *COLOR_ENABLED && !JournalLogOutput::is_journal_stream() {
+        Logger::set_log_output(Box::new(ConsoleLogOutput { color: true, stderr: false }));
+    }
+}
+
+///
+/// Called by commands which write JSON to stdout. Decorative output such as
+/// headings is suppressed, colors are disabled and log lines are written to
+/// stderr so that stdout contains only the JSON document.
+///
+pub fn set_json_mode() {
+    JSON_MODE.store(true, Ordering::Relaxed);
+    Logger::set_log_output(Box::new(ConsoleLogOutput { color: false, stderr: true }));
+}
+
+pub fn is_json_mode() -> bool {
+    JSON_MODE.load(Ordering::Relaxed)
+}
+
+/// Print a section heading unless JSON output was requested
+pub fn heading(text: &str) {
+    if !is_json_mode() {
+        println!("{}", paint(Style::Header, text));
+    }
+}
+
+/// Print a blank line separating sections unless JSON output was requested
+pub fn separator() {
+    if !is_json_mode() {
+        println!();
+    }
+}
+
+struct ConsoleLogOutput {
+    color: bool,
+    stderr: bool,
+}
+
+impl LogOutput for ConsoleLogOutput {
+    fn log_output(&mut self, level: LogLevel, line: &str) -> Result<()> {
+        let mut line = Logger::format_logline(level, line);
+        if let (true, Some(style)) = (self.color, Style::for_level(level)) {
+            // Only the level prefix is colored, messages may contain their own escapes
+            if let Some(idx) = line.find(' ') {
+                line = format!("{}{}", paint_with(true, style, &line[..idx]), &line[idx..]);
+            }
+        }
+        if self.stderr {
+            io::stderr().write_all(line.as_bytes())?;
+        } else {
+            let stdout = io::stdout();
+            let mut lock = stdout.lock();
+            lock.write_all(line.as_bytes())?;
+            lock.flush()?;
+        }
+        Ok(())
+    }
+}
+
+// Number of characters displayed for `s`, not counting ANSI escape sequences
+fn display_width(s: &str) -> usize {
+    let mut width = 0;
+    let mut in_escape = false;
+    for c in s.chars() {
+        if in_escape {
+            in_escape = !c.is_ascii_alphabetic();
+        } else if c == '\x1b' {
+            in_escape = true;
+        } else {
+            width += 1;
+        }
+    }
+    width
+}
+
+///
+/// Formats rows of text into aligned columns below a row of column headers.
+///
+///     let mut table = Table::new(&["NAME", "STATUS"]);
+///     table.row(["main", "running"]);
+///     table.print();
+///
+pub struct Table {
+    headers: Vec<String>,
+    rows: Vec<Vec<String>>,
+    indent: usize,
+}
+
+impl Table {
+    const COLUMN_GAP: usize = 2;
+
+    pub fn new(headers: &[&str]) -> Self {
+        Table {
+            headers: headers.iter().map(|s| s.to_string()).collect(),
+            rows: Vec::new(),
+            indent: 0,
+        }
+    }
+
+    /// Indent every line of the table by `indent` spaces
+    pub fn indent(mut self, indent: usize) -> Self {
+        self.indent = indent;
+        self
+    }
+
+    /// Add a row. Missing cells are displayed as empty.
+    pub fn row<I,S>(&mut self, cells: I) -> &mut Self
+        where I: IntoIterator<Item=S>, S: ToString
+    {
+        self.rows.push(cells.into_iter().map(|s| s.to_string()).collect());
+        self
+    }
+
+    pub fn print(&self) {
+        print!("{}", self.render(color_enabled()));
+    }
+
+    fn render(&self, color: bool) -> String {
+        let columns = self.rows.iter().map(|r| r.len()).max().unwrap_or(0).max(self.headers.len());
+        let mut widths = vec![0; columns];
+        for row in Some(&self.headers).into_iter().chain(&self.rows) {
+            for (i, cell) in row.iter().enumerate() {
+                widths[i] = widths[i].max(display_width(cell));
+            }
+        }
+
+        let mut out = String::new();
+        let header = self.render_row(&self.headers, &widths);
+        if !header.is_empty() {
+            out.push_str(&" ".repeat(self.indent));
+            out.push_str(&paint_with(color, Style::Header, &header));
+            out.push('\n');
+        }
+        for row in &self.rows {
+            out.push_str(&" ".repeat(self.indent));
+            out.push_str(&self.render_row(row, &widths));
+            out.push('\n');
+        }
+        out
+    }
+
+    // The last column is not padded so that lines have no trailing whitespace
+    fn render_row(&self, row: &[String], widths: &[usize]) -> String {
+        let mut line = String::new();
+        let last = row.iter().rposition(|cell| !cell.is_empty());
+        for (i, cell) in row.iter().enumerate().take(last.map_or(0, |n| n + 1)) {
+            line.push_str(cell);
+            if Some(i) != last {
+                line.push_str(&" ".repeat(widths[i] - display_width(cell) + Self::COLUMN_GAP));
+            }
+        }
+        line
+    }
+}
+
+#[test]
+fn test_table_render() {
+    let mut table = Table::new(&["NAME", "STATUS", "CAMERAS"]);
+    table.row(["main", "running", "0"])
+        .row(["personal-work", "stopped"])
+        .row(vec!["x".to_string(), paint_with(true, Style::Error, "failed"), "2".to_string()]);
+    assert_eq!(table.render(false),
+               "NAME           STATUS   CAMERAS\n\
+                main           running  0\n\
+                personal-work  stopped\n\
+                x              \x1b[1;31mfailed\x1b[0m   2\n");
+
+    let mut table = Table::new(&["KEY", "VALUE"]).indent(4);
+    table.row(["channel", "dev"]);
+    assert_eq!(table.render(true), "    \x1b[1mKEY      VALUE\x1b[0m\n    channel  dev\n");
+    assert_eq!(Table::new(&[]).render(true), "");
+
+    assert_eq!(display_width("\x1b[1;31mfailed\x1b[0m"), 6);
+    assert_eq!(paint_with(false, Style::Good, "PASS"), "PASS");
+    assert!(color_supported(false, Some("xterm"), true));
+    assert!(!color_supported(true, Some("xterm"), true));
+    assert!(!color_supported(false, Some("dumb"), true));
+    assert!(!color_supported(false, Some("xterm"), false));
+}
@@ -0,0 +1,197 @@
+use std::path::Path;
+use std::process::exit;
+
+use clap::{App,Arg,SubCommand,ArgMatches};
+use clap::AppSettings::*;
+use libcitadel::{Result,Partition,ImageHeader,Logger,LogLevel,format_error};
+
+use crate::output::{self,Style,Table,paint};
+use crate::preflight::{self, Requirement};
+
+const CHANGE_FLAG_REQUIREMENTS: &[Requirement] = &[Requirement::Root];
+
+pub fn app() -> App<'static, 'static> {
+
+    let flag_arg = || Arg::with_name("flag")
+        .required(true)
+        .help("Name of header flag (PREFER_BOOT, HASH_TREE or DATA_COMPRESSED)");
+
+    App::new("citadel-partition")
+        .about("Inspect and modify rootfs partition headers")
+        .settings(&[ArgRequiredElseHelp,ColoredHelp, DisableHelpSubcommand, DisableVersion, DeriveDisplayOrder])
+
+        .subcommand(SubCommand::with_name("status")
+            .about("Display state, header flags and metainfo of each rootfs partition")
+            .arg(Arg::with_name("json")
+                .long("json")
+                .help("Display output as JSON")))
+
+        .subcommand(SubCommand::with_name("set-flag")
+            .about("Set a flag in the header of a rootfs partition")
+            .arg(Arg::with_name("force")
+                .long("force")
+                .help("Allow changing PREFER_BOOT on the mounted partition"))
+            .arg(Arg::with_name("device")
+                .required(true)
+                .help("Path to rootfs partition device"))
+            .arg(flag_arg()))
+
+        .subcommand(SubCommand::with_name("clear-flag")
+            .about("Clear a flag in the header of a rootfs partition")
+            .arg(Arg::with_name("force")
+                .long("force")
+                .help("Allow changing PREFER_BOOT on the mounted partition"))
+            .arg(Arg::with_name("device")
+                .required(true)
+                .help("Path to rootfs partition device"))
+            .arg(flag_arg()))
+}
+
+pub fn main(matches: &ArgMatches) {
+    Logger::set_log_level(LogLevel::Info);
+
+    let result = match matches.subcommand() {
+        ("status", Some(m)) => status(m),
+        ("set-flag", Some(m)) => change_flag(m, true),
+        ("clear-flag", Some(m)) => change_flag(m, false),
+        _ => Ok(()),
+    };
+
+    if let Err(ref e) = result {
+        println!("Error: {}", format_error(e));
+        exit(1);
+    }
+}
+
+#[derive(Serialize)]
+pub struct PartitionStatus {
+    pub path: String,
+    pub mounted: bool,
+    pub initialized: bool,
+    pub status: Option<String>,
+    pub flags: Vec<String>,
+    pub image_type: Option<String>,
+    pub channel: Option<String>,
+    pub version: Option<u32>,
+    pub timestamp: Option<String>,
+    pub signature_valid: Option<bool>,
+}
+
+impl PartitionStatus {
+    pub fn new(p: &Partition) -> Self {
+        let mut st = PartitionStatus {
+            path: p.path().display().to_string(),
+            mounted: p.is_mounted(),
+            initialized: p.is_initialized(),
+            status: None, flags: Vec::new(), image_type: None, channel: None,
+            version: None, timestamp: None, signature_valid: None,
+        };
+        if p.is_initialized() {
+            let metainfo = p.metainfo();
+            st.status = Some(p.header().status_code_label());
+            st.flags = p.header().flag_names();
+            st.image_type = Some(metainfo.image_type().to_string());
+            st.channel = Some(metainfo.channel().to_string());
+            st.version = Some(metainfo.version());
+            st.timestamp = Some(metainfo.timestamp().to_string());
+            st.signature_valid = Some(p.is_signature_valid());
+        }
+        st
+    }
+
+    pub fn print_table(partitions: &[PartitionStatus]) {
+        let mut table = Table::new(&["PARTITION", "MOUNTED", "STATUS", "FLAGS", "CHANNEL", "VERSION", "TIMESTAMP", "SIGNATURE"]);
+        for p in partitions {
+            let mounted = if p.mounted { paint(Style::Good, "yes") } else { "no".to_string() };
+            if !p.initialized {
+                table.row(vec![p.path.clone(), mounted, paint(Style::Dim, "empty")]);
+                continue;
+            }
+            let signature = if p.signature_valid == Some(true) {
+                paint(Style::Good, "valid")
+            } else {
+                paint(Style::Error, "INVALID")
+            };
+            table.row(vec![
+                p.path.clone(),
+                mounted,
+                p.status.clone().unwrap_or_default(),
+                if p.flags.is_empty() { "none".to_string() } else { p.flags.join(",") },
+                p.channel.clone().unwrap_or_default(),
+                p.version.map(|v| v.to_string()).unwrap_or_default(),
+                p.timestamp.clone().unwrap_or_default(),
+                signature,
+            ]);
+        }
+        table.print();
+    }
+}
+
+/// Display the rootfs partitions considered when choosing a partition to install to
+pub fn print_install_candidates(partitions: &[Partition]) {
+    let mut table = Table::new(&["PARTITION", "MOUNTED", "EMPTY"]);
+    for p in partitions {
+        let yesno = |val: bool| if val { "yes" } else { "no" };
+        table.row(vec![p.path().display().to_string(), yesno(p.is_mounted()).to_string(), yesno(!p.is_initialized()).to_string()]);
+    }
+    table.print();
+}
+
+/// Display the metainfo of the image installed on `partition` as a table of keys and values
+pub fn print_metainfo(partition: &Partition) -> Result<()> {
+    let metainfo = String::from_utf8(partition.header().metainfo_bytes())?;
+    let mut table = Table::new(&["METAINFO", "VALUE"]).indent(4);
+    for line in metainfo.lines().filter(|line| !line.trim().is_empty()) {
+        match line.find('=') {
+            Some(idx) => table.row([line[..idx].trim(), line[idx+1..].trim().trim_matches('"')]),
+            None => table.row([line.trim()]),
+        };
+    }
+    table.print();
+    Ok(())
+}
+
+fn status(arg_matches: &ArgMatches) -> Result<()> {
+    let partitions = Partition::rootfs_partitions()?
+        .iter()
+        .map(PartitionStatus::new)
+        .collect::<Vec<_>>();
+
+    if arg_matches.is_present("json") {
+        output::set_json_mode();
+        println!("{}", serde_json::to_string_pretty(&partitions)?);
+    } else {
+        PartitionStatus::print_table(&partitions);
+    }
+    Ok(())
+}
+
+fn change_flag(arg_matches: &ArgMatches, set: bool) -> Result<()> {
+    preflight::check(if set { "partition set-flag" } else { "partition clear-flag" }, CHANGE_FLAG_REQUIREMENTS)?;
+    let device = Path::new(arg_matches.value_of("device").expect("device argument missing"));
+    let name = arg_matches.value_of("flag").expect("flag argument missing");
+    let flag = ImageHeader::flag_from_name(name)
+        .ok_or_else(|| format_err!("Unknown header flag '{}'", name))?;
+
+    let target = device.canonicalize()
+        .map_err(|e| format_err!("Cannot access {}: {}", device.display(), e))?;
+    let mut partition = Partition::rootfs_partitions()?
+        .into_iter()
+        .find(|p| p.path().canonicalize().ok().as_ref() == Some(&target))
+        .ok_or_else(|| format_err!("{} is not a rootfs partition", device.display()))?;
+
+    if !partition.is_initialized() {
+        bail!("Partition {} does not have a valid image header", device.display());
+    }
+    if flag == ImageHeader::FLAG_PREFER_BOOT && partition.is_mounted() && !arg_matches.is_present("force") {
+        bail!("Refusing to change PREFER_BOOT on mounted partition {} without --force", device.display());
+    }
+
+    let name = ImageHeader::flag_name(flag).unwrap_or(name);
+    if partition.change_flag_and_write(flag, set)? {
+        info!("{} {} on {}", if set { "Set" } else { "Cleared" }, name, device.display());
+    } else {
+        info!("{} is already {} on {}", name, if set { "set" } else { "clear" }, device.display());
+    }
+    Ok(())
+}
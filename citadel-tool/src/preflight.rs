@@ -0,0 +1,116 @@
+use std::env;
+use std::path::Path;
+
+use libcitadel::{Result,Mounts,util};
+
+///
+/// Something a command needs from the environment in order to run. Each command
+/// declares a list of requirements which are checked before it starts, so that a
+/// missing requirement is reported with a single clear message instead of as an
+/// I/O error from some later step.
+///
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Requirement {
+    /// Must run with effective uid 0
+    Root,
+    /// A filesystem must be mounted at this path
+    Mounted(&'static str),
+    /// An external program which will be executed
+    Command(&'static str),
+}
+
+impl Requirement {
+    fn is_met(self, env: &dyn Environment) -> bool {
+        match self {
+            Requirement::Root => env.is_root(),
+            Requirement::Mounted(path) => env.is_mounted(path),
+            Requirement::Command(cmd) => env.command_exists(cmd),
+        }
+    }
+
+    fn describe(self) -> String {
+        match self {
+            Requirement::Root => "root".to_string(),
+            Requirement::Mounted(path) => format!("a mounted {}", path),
+            Requirement::Command(cmd) => format!("the {} command", cmd),
+        }
+    }
+}
+
+/// The parts of the system which requirements are checked against
+pub trait Environment {
+    fn is_root(&self) -> bool;
+    fn is_mounted(&self, path: &str) -> bool;
+    fn command_exists(&self, cmd: &str) -> bool;
+}
+
+struct SystemEnvironment;
+
+impl Environment for SystemEnvironment {
+    fn is_root(&self) -> bool {
+        util::is_euid_root()
+    }
+
+    fn is_mounted(&self, path: &str) -> bool {
+        // Accessing the path first triggers an automount
+        Path::new(path).exists() && Mounts::is_target_mounted(path).unwrap_or(false)
+    }
+
+    fn command_exists(&self, cmd: &str) -> bool {
+        util::ensure_command_exists(cmd).is_ok()
+    }
+}
+
+/// Check that all `requirements` of `command` are met by the running system
+/// and return an error naming every requirement which is not.
+pub fn check(command: &str, requirements: &[Requirement]) -> Result<()> {
+    let invocation = env::args().collect::<Vec<_>>().join(" ");
+    check_environment(command, requirements, &SystemEnvironment, &invocation)
+}
+
+fn check_environment(command: &str, requirements: &[Requirement], env: &dyn Environment, invocation: &str) -> Result<()> {
+    let missing = requirements.iter()
+        .filter(|r| !r.is_met(env))
+        .collect::<Vec<_>>();
+
+    if missing.is_empty() {
+        return Ok(());
+    }
+    let mut needs = missing.iter().map(|r| r.describe()).collect::<Vec<_>>();
+    let last = needs.pop().unwrap_or_default();
+    let needs = if needs.is_empty() { last } else { format!("{} and {}", needs.join(", "), last) };
+
+    if missing.contains(&&Requirement::Root) {
+        bail!("{} requires {} (try: sudo {})", command, needs, invocation);
+    }
+    bail!("{} requires {}", command, needs);
+}
+
+#[test]
+fn test_preflight_check() {
+    struct MockEnvironment {
+        root: bool,
+        mounted: &'static [&'static str],
+        commands: &'static [&'static str],
+    }
+    impl Environment for MockEnvironment {
+        fn is_root(&self) -> bool { self.root }
+        fn is_mounted(&self, path: &str) -> bool { self.mounted.contains(&path) }
+        fn command_exists(&self, cmd: &str) -> bool { self.commands.contains(&cmd) }
+    }
+    let requirements = [Requirement::Root, Requirement::Mounted("/storage"), Requirement::Command("/sbin/veritysetup")];
+    let check = |env: &MockEnvironment| check_environment("update", &requirements, env, "citadel-tool update a.img")
+        .map_err(|e| e.to_string());
+
+    let env = MockEnvironment { root: true, mounted: &["/storage"], commands: &["/sbin/veritysetup"] };
+    assert_eq!(check(&env), Ok(()));
+
+    let env = MockEnvironment { root: false, mounted: &[], commands: &["/sbin/veritysetup"] };
+    assert_eq!(check(&env), Err("update requires root and a mounted /storage (try: sudo citadel-tool update a.img)".to_string()));
+
+    let env = MockEnvironment { root: true, mounted: &[], commands: &[] };
+    assert_eq!(check(&env), Err("update requires a mounted /storage and the /sbin/veritysetup command".to_string()));
+
+    let env = MockEnvironment { root: false, mounted: &[], commands: &[] };
+    assert!(check(&env).unwrap_err().starts_with("update requires root, a mounted /storage and the /sbin/veritysetup command"));
+}
@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+
+use dbus::{BusType, Connection, Message};
+use libcitadel::Result;
+
+const BUS_NAME: &str = "com.subgraph.realms";
+const OBJECT_PATH: &str = "/com/subgraph/realms";
+const INTERFACE_NAME: &str = "com.subgraph.realms.Manager";
+
+/// Timeout in milliseconds for method calls. Terminal starts the realm before replying.
+const CALL_TIMEOUT: i32 = 30_000;
+/// Timeout in milliseconds for RunWithOutput which replies when the command exits
+const RUN_TIMEOUT: i32 = 600_000;
+
+const ERROR_UNKNOWN_METHOD: &str = "org.freedesktop.DBus.Error.UnknownMethod";
+const ERROR_SERVICE_UNKNOWN: &str = "org.freedesktop.DBus.Error.ServiceUnknown";
+const ERROR_NAME_HAS_NO_OWNER: &str = "org.freedesktop.DBus.Error.NameHasNoOwner";
+
+/// Error returned when the system bus or the realms daemon cannot be reached.
+#[derive(Debug,Fail)]
+#[fail(display = "cannot connect to realms daemon: {}", _0)]
+pub struct DaemonUnavailable(String);
+
+// Labels of the realm status values reported by List and ListDetailed
+fn status_label(code: u8) -> String {
+    match code {
+        0 => "stopped".to_string(),
+        1 => "running".to_string(),
+        2 => "current".to_string(),
+        3 => "frozen".to_string(),
+        4 => "config-error".to_string(),
+        n => format!("unknown ({})", n),
+    }
+}
+
+#[derive(Serialize)]
+pub struct RealmEntry {
+    pub name: String,
+    pub status: String,
+    /// Only reported by ListDetailed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub camera_devices: Option<u32>,
+    /// Seconds a running realm has been running, only reported by ListDetailed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uptime_secs: Option<u64>,
+    /// Bytes used by the home directory, only reported by ListDetailed for realms with a home quota
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub home_usage: Option<u64>,
+    /// Home quota in bytes, only reported by ListDetailed for realms with a home quota
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub home_quota: Option<u64>,
+    /// Clipboard policy in effect, only reported by ListDetailed for running realms with a clipboard socket
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clipboard: Option<String>,
+}
+
+/// Output of a command run with RunWithOutput
+pub struct RunOutput {
+    pub status: i32,
+    pub output: String,
+}
+
+// (name, status, cameras, uptime, home usage, home quota) reported by ListDetailed
+// before the clipboard policy was added
+type ListDetailedV3 = (String, u8, u32, u64, u64, u64);
+
+/// Launch config files returned by PreviewLaunchConfig
+pub struct LaunchPreview {
+    pub nspawn: String,
+    pub service: String,
+    pub address: Option<String>,
+    /// Optional bindings requested by the realm config which would not be
+    /// added, mapped to the reason. Empty if the daemon does not report bindings.
+    pub missing: Vec<(String, String)>,
+}
+
+/// Thin client for the `com.subgraph.realms.Manager` interface of realmsd.
+pub struct RealmsClient {
+    connection: Connection,
+}
+
+impl RealmsClient {
+    pub fn connect() -> Result<Self> {
+        let connection = Connection::get_private(BusType::System)
+            .map_err(|e| DaemonUnavailable(error_text(&e)))?;
+        Ok(RealmsClient { connection })
+    }
+
+    fn method_call(method: &str) -> Result<Message> {
+        Message::new_method_call(BUS_NAME, OBJECT_PATH, INTERFACE_NAME, method)
+            .map_err(|e| format_err!("failed to create {} method call: {}", method, e))
+    }
+
+    fn call(&self, method: &str, msg: Message, timeout: i32) -> Result<Message> {
+        self.connection.send_with_reply_and_block(msg, timeout)
+            .map_err(|e| call_error(method, &e))
+    }
+
+    /// Returns `None` if the daemon does not implement `method`.
+    fn call_optional(&self, method: &str, msg: Message, timeout: i32) -> Result<Option<Message>> {
+        match self.connection.send_with_reply_and_block(msg, timeout) {
+            Ok(reply) => Ok(Some(reply)),
+            Err(ref e) if e.name() == Some(ERROR_UNKNOWN_METHOD) => Ok(None),
+            Err(e) => Err(call_error(method, &e)),
+        }
+    }
+
+    /// List realms with ListDetailed, falling back to List on daemons
+    /// which do not implement it.
+    pub fn list(&self) -> Result<Vec<RealmEntry>> {
+        if let Some(reply) = self.call_optional("ListDetailed", Self::method_call("ListDetailed")?, CALL_TIMEOUT)? {
+            // Older daemons do not report the clipboard policy, home usage or uptime
+            let list: Vec<(String, u8, u32, u64, u64, u64, String)> = match reply.read1() {
+                Ok(list) => list,
+                Err(_) => Self::read_list_detailed_v3(&reply)?.into_iter()
+                    .map(|(name, status, cameras, uptime, usage, quota)| (name, status, cameras, uptime, usage, quota, String::new()))
+                    .collect(),
+            };
+            return Ok(list.into_iter()
+                .map(|(name, status, cameras, uptime, usage, quota, clipboard)| RealmEntry {
+                    name,
+                    status: status_label(status),
+                    camera_devices: Some(cameras),
+                    uptime_secs: Some(uptime).filter(|&secs| secs > 0),
+                    home_usage: Some(usage).filter(|_| quota > 0),
+                    home_quota: Some(quota).filter(|&quota| quota > 0),
+                    clipboard: Some(clipboard).filter(|policy| !policy.is_empty()),
+                })
+                .collect());
+        }
+        let reply = self.call("List", Self::method_call("List")?, CALL_TIMEOUT)?;
+        let map: HashMap<String, u8> = reply.read1()?;
+        let mut list = map.into_iter()
+            .map(|(name, status)| RealmEntry { name, status: status_label(status), camera_devices: None, uptime_secs: None, home_usage: None, home_quota: None, clipboard: None })
+            .collect::<Vec<_>>();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(list)
+    }
+
+    // ListDetailed replies of daemons which do not report the clipboard policy
+    fn read_list_detailed_v3(reply: &Message) -> Result<Vec<ListDetailedV3>> {
+        if let Ok(list) = reply.read1() {
+            return Ok(list);
+        }
+        let list = match reply.read1::<Vec<(String, u8, u32, u64)>>() {
+            Ok(list) => list,
+            Err(_) => reply.read1::<Vec<(String, u8, u32)>>()?.into_iter()
+                .map(|(name, status, cameras)| (name, status, cameras, 0))
+                .collect(),
+        };
+        Ok(list.into_iter()
+            .map(|(name, status, cameras, uptime)| (name, status, cameras, uptime, 0, 0))
+            .collect())
+    }
+
+    pub fn start(&self, name: &str) -> Result<()> {
+        self.call("Start", Self::method_call("Start")?.append1(name), CALL_TIMEOUT)?;
+        Ok(())
+    }
+
+    pub fn stop(&self, name: &str) -> Result<()> {
+        self.call("Stop", Self::method_call("Stop")?.append1(name), CALL_TIMEOUT)?;
+        Ok(())
+    }
+
+    /// Open a terminal in the realm, starting the realm first if necessary.
+    pub fn terminal(&self, name: &str) -> Result<()> {
+        self.call("Terminal", Self::method_call("Terminal")?.append1(name), CALL_TIMEOUT)?;
+        Ok(())
+    }
+
+    /// Run a command in the realm. If the daemon implements RunWithOutput the exit
+    /// status and output of the command are returned, otherwise the command is
+    /// launched with Run and `None` is returned.
+    pub fn run(&self, name: &str, args: &[String]) -> Result<Option<RunOutput>> {
+        let msg = Self::method_call("RunWithOutput")?.append2(name, args.to_vec());
+        if let Some(reply) = self.call_optional("RunWithOutput", msg, RUN_TIMEOUT)? {
+            let (status, output): (i32, String) = reply.read2()?;
+            return Ok(Some(RunOutput { status, output }));
+        }
+        self.call("Run", Self::method_call("Run")?.append2(name, args.to_vec()), CALL_TIMEOUT)?;
+        Ok(None)
+    }
+
+    /// The .nspawn file and service unit realmsd would generate to start the
+    /// realm, and the address it would be allocated.
+    pub fn preview_launch_config(&self, name: &str) -> Result<LaunchPreview> {
+        let msg = Self::method_call("PreviewLaunchConfig")?.append1(name);
+        let reply = self.call("PreviewLaunchConfig", msg, CALL_TIMEOUT)?;
+        let (nspawn, service, address): (String, String, String) = reply.read3()?;
+        let address = Some(address).filter(|a| !a.is_empty());
+        let bindings = reply.get4::<String, String, String, HashMap<String, String>>().3.unwrap_or_default();
+        let mut missing = bindings.into_iter()
+            .flat_map(|(binding, result)| result.strip_prefix("missing: ").map(|reason| (binding.clone(), reason.to_string())))
+            .collect::<Vec<_>>();
+        missing.sort();
+        Ok(LaunchPreview { nspawn, service, address, missing })
+    }
+
+    /// Name of the current realm or `None` if no realm is current.
+    pub fn current(&self) -> Result<Option<String>> {
+        let reply = self.call("GetCurrent", Self::method_call("GetCurrent")?, CALL_TIMEOUT)?;
+        let name: String = reply.read1()?;
+        Ok(if name.is_empty() { None } else { Some(name) })
+    }
+
+    pub fn set_current(&self, name: &str) -> Result<()> {
+        self.call("SetCurrent", Self::method_call("SetCurrent")?.append1(name), CALL_TIMEOUT)?;
+        Ok(())
+    }
+}
+
+fn call_error(method: &str, e: &dbus::Error) -> failure::Error {
+    match e.name() {
+        Some(ERROR_SERVICE_UNKNOWN) | Some(ERROR_NAME_HAS_NO_OWNER) => DaemonUnavailable(error_text(e)).into(),
+        _ => format_err!("{} failed: {}", method, error_text(e)),
+    }
+}
+
+fn error_text(e: &dbus::Error) -> String {
+    e.message().or_else(|| e.name()).unwrap_or("unknown error").to_string()
+}
@@ -0,0 +1,368 @@
+use std::path::{Path,PathBuf};
+use std::process::exit;
+
+use clap::{App,Arg,SubCommand,ArgMatches};
+use clap::AppSettings::*;
+use libcitadel::{Result,ConfigCheck,Realm,RealmManager,Realms,Logger,LogLevel,format_error};
+
+use crate::output::{self,Style,Table,paint};
+use crate::preflight::{self,Requirement};
+use self::client::{DaemonUnavailable,RealmsClient};
+
+pub mod client;
+
+/// Exit code when the realms daemon cannot be reached
+const EXIT_DAEMON_UNAVAILABLE: i32 = 2;
+
+const CLEANUP_REQUIREMENTS: &[Requirement] = &[
+    Requirement::Root,
+    Requirement::Command("/usr/bin/systemctl"),
+];
+
+const ENCRYPT_HOME_REQUIREMENTS: &[Requirement] = &[
+    Requirement::Root,
+    Requirement::Command("/sbin/cryptsetup"),
+    Requirement::Command("/sbin/mkfs.ext4"),
+];
+
+const RECOVER_REQUIREMENTS: &[Requirement] = &[
+    Requirement::Root,
+    Requirement::Command("/usr/bin/systemctl"),
+    Requirement::Command("/usr/bin/machinectl"),
+];
+
+pub fn app() -> App<'static, 'static> {
+
+    let name_arg = || Arg::with_name("name")
+        .required(true)
+        .help("Name of realm");
+
+    let json_arg = || Arg::with_name("json")
+        .long("json")
+        .help("Display output as JSON");
+
+    App::new("citadel-realm")
+        .about("Realm management utilities")
+        .settings(&[ArgRequiredElseHelp,ColoredHelp, DisableHelpSubcommand, DisableVersion, DeriveDisplayOrder])
+
+        .subcommand(SubCommand::with_name("check-config")
+            .about("Check a realm config file for unknown keys and invalid values")
+            .arg(Arg::with_name("realm")
+                .required(true)
+                .help("Name of realm or path to a config file")))
+
+        .subcommand(SubCommand::with_name("list")
+            .about("List realms and their status")
+            .arg(json_arg())
+            .arg(Arg::with_name("names-only")
+                .long("names-only")
+                .conflicts_with("json")
+                .help("Display only realm names, one per line. Works when realmsd is not running")))
+
+        .subcommand(SubCommand::with_name("start")
+            .about("Start a realm")
+            .arg(name_arg()))
+
+        .subcommand(SubCommand::with_name("stop")
+            .about("Stop a realm")
+            .arg(name_arg()))
+
+        .subcommand(SubCommand::with_name("preview")
+            .about("Display the service unit and .nspawn file which would be generated to start a realm")
+            .arg(name_arg()))
+
+        .subcommand(SubCommand::with_name("shell")
+            .about("Open a terminal in a realm, starting the realm if it is not running")
+            .arg(name_arg()))
+
+        .subcommand(SubCommand::with_name("run")
+            .about("Run a command in a realm, starting the realm if it is not running")
+            .settings(&[TrailingVarArg, AllowLeadingHyphen])
+            .arg(json_arg())
+            .arg(name_arg())
+            .arg(Arg::with_name("command")
+                .required(true)
+                .multiple(true)
+                .help("Command and arguments to run")))
+
+        .subcommand(SubCommand::with_name("current")
+            .about("Display the name of the current realm")
+            .arg(json_arg()))
+
+        .subcommand(SubCommand::with_name("set-current")
+            .about("Make a running realm the current realm")
+            .arg(name_arg()))
+
+        .subcommand(SubCommand::with_name("cleanup")
+            .about("Remove service units and .nspawn files left in /run/systemd for realms which no longer exist"))
+
+        .subcommand(SubCommand::with_name("recover")
+            .about("Terminate a machine left registered by a crashed realm which prevents the realm from starting")
+            .arg(name_arg()))
+
+        .subcommand(SubCommand::with_name("encrypt-home")
+            .about("Move the home directory of a stopped realm into an encrypted container")
+            .arg(name_arg()))
+}
+
+pub fn main(matches: &ArgMatches) {
+    Logger::set_log_level(LogLevel::Info);
+    if matches.subcommand().1.map_or(false, |m| m.is_present("json")) {
+        output::set_json_mode();
+    }
+
+    let result = match matches.subcommand() {
+        ("check-config", Some(m)) => check_config(m).map(|ok| if ok { 0 } else { 1 }),
+        ("list", Some(m)) => list(m),
+        ("start", Some(m)) => with_name(m, RealmsClient::start),
+        ("stop", Some(m)) => with_name(m, RealmsClient::stop),
+        ("preview", Some(m)) => preview(m),
+        ("shell", Some(m)) => with_name(m, RealmsClient::terminal),
+        ("run", Some(m)) => run(m),
+        ("current", Some(m)) => current(m),
+        ("set-current", Some(m)) => with_name(m, RealmsClient::set_current),
+        ("cleanup", Some(_)) => cleanup(),
+        ("recover", Some(m)) => recover(m),
+        ("encrypt-home", Some(m)) => encrypt_home(m),
+        _ => Ok(0),
+    };
+
+    match result {
+        Ok(0) => {},
+        Ok(code) => exit(code),
+        Err(ref e) => {
+            println!("Error: {}", format_error(e));
+            if e.downcast_ref::<DaemonUnavailable>().is_some() {
+                println!("Is realmsd running?");
+                exit(EXIT_DAEMON_UNAVAILABLE);
+            }
+            exit(1);
+        }
+    }
+}
+
+fn with_name(arg_matches: &ArgMatches, f: fn(&RealmsClient, &str) -> Result<()>) -> Result<i32> {
+    let name = arg_matches.value_of("name").expect("name argument missing");
+    f(&RealmsClient::connect()?, name)?;
+    Ok(0)
+}
+
+fn list(arg_matches: &ArgMatches) -> Result<i32> {
+    if arg_matches.is_present("names-only") {
+        for name in realm_names()? {
+            println!("{}", name);
+        }
+        return Ok(0);
+    }
+    let realms = RealmsClient::connect()?.list()?;
+    if arg_matches.is_present("json") {
+        println!("{}", serde_json::to_string_pretty(&realms)?);
+        return Ok(0);
+    }
+    let mut table = Table::new(&["NAME", "STATUS", "CAMERAS", "UPTIME", "HOME"]);
+    for realm in &realms {
+        let status = match realm.status.as_str() {
+            "running" | "current" => paint(Style::Good, &realm.status),
+            "config-error" => paint(Style::Error, &realm.status),
+            _ => realm.status.clone(),
+        };
+        let cameras = realm.camera_devices.map(|n| n.to_string()).unwrap_or_default();
+        let uptime = realm.uptime_secs.map(format_uptime).unwrap_or_default();
+        let home = match (realm.home_usage, realm.home_quota) {
+            (Some(usage), Some(quota)) => format_home_usage(usage, quota),
+            _ => String::new(),
+        };
+        table.row(vec![realm.name.clone(), status, cameras, uptime, home]);
+    }
+    table.print();
+    Ok(0)
+}
+
+// Formats as "2h 13m", or "45s" for realms running less than a minute
+fn format_uptime(secs: u64) -> String {
+    let (days, hours, minutes) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m", minutes)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+// Formats as "1200/20480 MiB", in red once the home directory is over its quota
+fn format_home_usage(usage: u64, quota: u64) -> String {
+    let text = format!("{}/{} MiB", usage / (1024 * 1024), quota / (1024 * 1024));
+    if usage > quota {
+        paint(Style::Error, &text)
+    } else {
+        text
+    }
+}
+
+// Used for shell completion, so if realmsd is not running the realms
+// directory is scanned instead of failing.
+fn realm_names() -> Result<Vec<String>> {
+    match RealmsClient::connect().and_then(|client| client.list()) {
+        Ok(realms) => Ok(realms.into_iter().map(|r| r.name).collect()),
+        Err(ref e) if e.downcast_ref::<DaemonUnavailable>().is_some() => Realms::realm_names(),
+        Err(e) => Err(e),
+    }
+}
+
+// Nothing is written or started, so the address shown is the one the realm
+// would be given if it was started now.
+fn preview(arg_matches: &ArgMatches) -> Result<i32> {
+    let name = arg_matches.value_of("name").expect("name argument missing");
+    let preview = RealmsClient::connect()?.preview_launch_config(name)?;
+    println!("# realm-{}.service", name);
+    println!("{}", preview.service.trim_end());
+    println!();
+    println!("# {}.nspawn", name);
+    println!("{}", preview.nspawn.trim_end());
+    if let Some(address) = preview.address {
+        println!();
+        println!("# Network address: {}", address);
+    }
+    for (binding, reason) in &preview.missing {
+        warn!("{} will not be available in realm {}: {}", binding, name, reason);
+    }
+    Ok(0)
+}
+
+// Exits with the status of the command if the daemon reports it
+fn run(arg_matches: &ArgMatches) -> Result<i32> {
+    let name = arg_matches.value_of("name").expect("name argument missing");
+    let args = arg_matches.values_of("command").expect("command argument missing")
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>();
+    let json = arg_matches.is_present("json");
+
+    match RealmsClient::connect()?.run(name, &args)? {
+        Some(output) => {
+            if json {
+                println!("{}", serde_json::json!({ "status": output.status, "output": output.output }));
+            } else {
+                print!("{}", output.output);
+            }
+            Ok(output.status)
+        },
+        None => {
+            if json {
+                println!("{}", serde_json::json!({ "status": null, "output": null }));
+            }
+            Ok(0)
+        },
+    }
+}
+
+fn current(arg_matches: &ArgMatches) -> Result<i32> {
+    let current = RealmsClient::connect()?.current()?;
+    if arg_matches.is_present("json") {
+        println!("{}", serde_json::json!({ "current": current }));
+    } else if let Some(name) = current {
+        println!("{}", name);
+    }
+    Ok(0)
+}
+
+fn cleanup() -> Result<i32> {
+    preflight::check("realm cleanup", CLEANUP_REQUIREMENTS)?;
+    let manager = RealmManager::load()?;
+    let removed = manager.remove_orphaned_launch_config_files()?;
+    if removed.is_empty() {
+        println!("No launch config files of deleted realms found");
+    }
+    for path in removed {
+        println!("Removed {}", path.display());
+    }
+    Ok(0)
+}
+
+fn recover(arg_matches: &ArgMatches) -> Result<i32> {
+    preflight::check("realm recover", RECOVER_REQUIREMENTS)?;
+    let name = arg_matches.value_of("name").expect("name argument missing");
+    let manager = RealmManager::load()?;
+    let realm = manager.realm_by_name(name)
+        .ok_or_else(|| format_err!("realm '{}' not found", name))?;
+    if manager.recover_realm(&realm)? {
+        println!("Terminated stale machine of realm {}", name);
+    } else {
+        println!("No stale machine found for realm {}", name);
+    }
+    Ok(0)
+}
+
+fn encrypt_home(arg_matches: &ArgMatches) -> Result<i32> {
+    preflight::check("realm encrypt-home", ENCRYPT_HOME_REQUIREMENTS)?;
+    let name = arg_matches.value_of("name").expect("name argument missing");
+    let manager = RealmManager::load()?;
+    let realm = manager.realm_by_name(name)
+        .ok_or_else(|| format_err!("realm '{}' not found", name))?;
+    let plaintext = manager.encrypt_home(&realm)?;
+    println!("Home directory of realm {} is now encrypted", name);
+    println!("The plaintext files remain in {}, remove them once the realm has been started and checked", plaintext.display());
+    Ok(0)
+}
+
+// A realm name is resolved to the config file in the realm directory, anything
+// else is taken to be a path.
+fn config_path(target: &str) -> PathBuf {
+    if Realm::is_valid_name(target) && !Path::new(target).exists() {
+        Path::new(Realms::BASE_PATH).join(format!("realm-{}", target)).join("config")
+    } else {
+        PathBuf::from(target)
+    }
+}
+
+// Name of the realm if `path` is the config file in a realm directory
+fn installed_realm_name(path: &Path) -> Option<String> {
+    let dir = path.parent()?;
+    if path.file_name()? != "config" || dir.parent()? != Path::new(Realms::BASE_PATH) {
+        return None;
+    }
+    let name = dir.file_name()?.to_str()?.strip_prefix("realm-")?;
+    Some(name.to_string()).filter(|name| Realm::is_valid_name(name))
+}
+
+// Returns false if the config file has errors
+fn check_config(arg_matches: &ArgMatches) -> Result<bool> {
+    let path = config_path(arg_matches.value_of("realm").expect("realm argument missing"));
+    if !path.exists() {
+        bail!("Config file {} does not exist", path.display());
+    }
+    let mut check = ConfigCheck::check_file(&path)?;
+    if let Some(name) = installed_realm_name(&path) {
+        check.check_reserved_address(&name);
+    }
+    for issue in check.issues() {
+        println!("{}", issue);
+    }
+    if check.has_errors() {
+        return Ok(false);
+    }
+    if check.issues().is_empty() {
+        println!("{}: ok", path.display());
+    }
+    Ok(true)
+}
+
+#[test]
+fn test_run_args() {
+    let m = app().get_matches_from_safe(vec!["citadel-realm", "run", "--json", "work", "ls", "-l", "--all"]).unwrap();
+    let m = m.subcommand_matches("run").unwrap();
+    assert!(m.is_present("json"));
+    assert_eq!(m.value_of("name"), Some("work"));
+    assert_eq!(m.values_of("command").unwrap().collect::<Vec<_>>(), vec!["ls", "-l", "--all"]);
+    assert!(app().get_matches_from_safe(vec!["citadel-realm", "run", "work"]).is_err());
+}
+
+#[test]
+fn test_format_uptime() {
+    assert_eq!(format_uptime(45), "45s");
+    assert_eq!(format_uptime(600), "10m");
+    assert_eq!(format_uptime(2 * 3600 + 13 * 60 + 5), "2h 13m");
+    assert_eq!(format_uptime(3 * 86400 + 4 * 3600), "3d 4h");
+}
@@ -9,11 +9,8 @@ use libcitadel::ResizeSize;
 use libcitadel::format_error;
 use std::process::exit;
 
-pub fn main(args: Vec<String>) {
-
-    Logger::set_log_level(LogLevel::Debug);
-
-    let app = App::new("citadel-realmfs")
+pub fn app() -> App<'static, 'static> {
+    App::new("citadel-realmfs")
         .about("Citadel realmfs image tool")
         .settings(&[ArgRequiredElseHelp,ColoredHelp, DisableHelpSubcommand, DisableVersion, DeriveDisplayOrder,SubcommandsNegateReqs])
 
@@ -78,9 +75,12 @@ is the final absolute size of the image.")
 
         .arg(Arg::with_name("image")
             .help("Name of or path to RealmFS image to display information about")
-            .required(true));
+            .required(true))
+}
+
+pub fn main(matches: &ArgMatches) {
+    Logger::set_log_level(LogLevel::Debug);
 
-    let matches = app.get_matches_from(args);
     let result = match matches.subcommand() {
         ("resize", Some(m)) => resize(m),
         ("autoresize", Some(m)) => autoresize(m),
@@ -89,7 +89,7 @@ is the final absolute size of the image.")
         ("update", Some(m)) => update(m),
         ("activate", Some(m)) => activate(m),
         ("deactivate", Some(m)) => deactivate(m),
-        _ => image_info(&matches),
+        _ => image_info(matches),
     };
 
     if let Err(ref e) = result {
@@ -0,0 +1,209 @@
+use std::process::exit;
+
+use clap::{App,Arg,ArgMatches};
+use clap::AppSettings::*;
+use libcitadel::{Logger,LogLevel};
+
+use crate::output::{self,Style,Table,paint};
+use super::SystemStatus;
+
+#[derive(Serialize,Clone,Copy,PartialEq,Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl Outcome {
+    fn label(self) -> String {
+        match self {
+            Outcome::Pass => paint(Style::Good, "PASS"),
+            Outcome::Warn => paint(Style::Warning, "WARN"),
+            Outcome::Fail => paint(Style::Error, "FAIL"),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct CheckResult {
+    name: &'static str,
+    outcome: Outcome,
+    message: String,
+}
+
+impl CheckResult {
+    fn new(name: &'static str, outcome: Outcome, message: impl Into<String>) -> Self {
+        CheckResult { name, outcome, message: message.into() }
+    }
+}
+
+type Check = fn(&SystemStatus) -> CheckResult;
+
+/// Every check run by `citadel-tool doctor`. To add a check write a function
+/// which inspects the collected `SystemStatus` and add it here.
+const CHECKS: &[Check] = &[
+    check_prefer_boot,
+    check_boot_entries,
+    check_stale_mounts,
+    check_network_allocations,
+    check_status_errors,
+];
+
+pub fn app() -> App<'static, 'static> {
+    App::new("citadel-doctor")
+        .about("Display the state of the system and check it for common problems")
+        .settings(&[ColoredHelp, DisableHelpSubcommand, DisableVersion, DeriveDisplayOrder])
+        .arg(Arg::with_name("json")
+            .long("json")
+            .help("Display output as JSON"))
+}
+
+pub fn main(matches: &ArgMatches) {
+    Logger::set_log_level(LogLevel::Warn);
+    let json = matches.is_present("json");
+    if json {
+        output::set_json_mode();
+    }
+
+    let status = SystemStatus::collect();
+    let results = run_checks(&status);
+
+    if json {
+        let output = serde_json::json!({ "status": status, "checks": results });
+        println!("{}", serde_json::to_string_pretty(&output).unwrap_or_default());
+    } else {
+        status.print();
+        output::separator();
+        output::heading("Checks");
+        let mut table = Table::new(&["RESULT", "CHECK", "MESSAGE"]);
+        for r in &results {
+            table.row(vec![r.outcome.label(), r.name.to_string(), r.message.clone()]);
+        }
+        table.print();
+    }
+
+    if results.iter().any(|r| r.outcome == Outcome::Fail) {
+        exit(1);
+    }
+}
+
+fn run_checks(status: &SystemStatus) -> Vec<CheckResult> {
+    CHECKS.iter().map(|check| check(status)).collect()
+}
+
+// Exactly one initialized rootfs partition must have the PREFER_BOOT flag
+fn check_prefer_boot(status: &SystemStatus) -> CheckResult {
+    const NAME: &str = "prefer-boot";
+    if status.partitions.is_empty() {
+        return CheckResult::new(NAME, Outcome::Warn, "no rootfs partitions found");
+    }
+    let preferred = status.partitions.iter()
+        .filter(|p| p.initialized && p.flags.iter().any(|f| f == "PREFER_BOOT"))
+        .map(|p| p.path.as_str())
+        .collect::<Vec<_>>();
+    match preferred.len() {
+        1 => CheckResult::new(NAME, Outcome::Pass, format!("{} is preferred for boot", preferred[0])),
+        0 => CheckResult::new(NAME, Outcome::Fail, "no rootfs partition has PREFER_BOOT set"),
+        _ => CheckResult::new(NAME, Outcome::Fail, format!("PREFER_BOOT is set on more than one partition: {}", preferred.join(", "))),
+    }
+}
+
+fn check_boot_entries(status: &SystemStatus) -> CheckResult {
+    const NAME: &str = "boot-entries";
+    if status.boot_entries.is_empty() {
+        return CheckResult::new(NAME, Outcome::Warn, "no boot entries found, is /boot mounted?");
+    }
+    let broken = status.boot_entries.iter()
+        .filter(|e| !e.kernel_exists)
+        .map(|e| e.file.as_str())
+        .collect::<Vec<_>>();
+    if broken.is_empty() {
+        CheckResult::new(NAME, Outcome::Pass, format!("{} boot entries reference existing kernels", status.boot_entries.len()))
+    } else {
+        CheckResult::new(NAME, Outcome::Fail, format!("boot entries with missing kernel: {}", broken.join(", ")))
+    }
+}
+
+// An image is stale if the file backing its loop device has been removed
+fn check_stale_mounts(status: &SystemStatus) -> CheckResult {
+    const NAME: &str = "stale-image-mounts";
+    let stale = status.mounted_images.iter()
+        .filter(|m| m.backing_file.as_ref().map_or(false, |f| f.ends_with(" (deleted)")))
+        .map(|m| m.mountpoint.as_str())
+        .collect::<Vec<_>>();
+    if stale.is_empty() {
+        CheckResult::new(NAME, Outcome::Pass, format!("{} mounted images", status.mounted_images.len()))
+    } else {
+        CheckResult::new(NAME, Outcome::Fail, format!("images mounted from deleted files: {}", stale.join(", ")))
+    }
+}
+
+// Every network allocation should belong to a running realm
+fn check_network_allocations(status: &SystemStatus) -> CheckResult {
+    const NAME: &str = "network-allocations";
+    let running = match status.running_realms() {
+        Some(running) => running,
+        None => return CheckResult::new(NAME, Outcome::Warn, "realmsd is not running, cannot compare allocations with running realms"),
+    };
+    let leaked = status.network_allocations.iter()
+        .filter(|a| !running.contains(&a.realm.as_str()))
+        .map(|a| format!("{} ({} in zone {})", a.realm, a.address, a.zone))
+        .collect::<Vec<_>>();
+    if leaked.is_empty() {
+        CheckResult::new(NAME, Outcome::Pass, format!("{} allocations", status.network_allocations.len()))
+    } else {
+        CheckResult::new(NAME, Outcome::Fail, format!("allocations for realms which are not running: {}", leaked.join(", ")))
+    }
+}
+
+fn check_status_errors(status: &SystemStatus) -> CheckResult {
+    const NAME: &str = "status";
+    if status.errors.is_empty() {
+        CheckResult::new(NAME, Outcome::Pass, "all system information collected")
+    } else {
+        CheckResult::new(NAME, Outcome::Warn, status.errors.join("; "))
+    }
+}
+
+#[test]
+fn test_doctor_checks() {
+    use crate::partition::PartitionStatus;
+    use crate::realm::client::RealmEntry;
+    use super::{BootEntryStatus,MountedImageStatus,NetworkAllocationStatus};
+
+    let partition = |path: &str, flags: &[&str]| PartitionStatus {
+        path: path.to_string(), mounted: false, initialized: true, status: None,
+        flags: flags.iter().map(|s| s.to_string()).collect(),
+        image_type: None, channel: None, version: None, timestamp: None, signature_valid: None,
+    };
+    let outcome = |check: Check, status: &SystemStatus| check(status).outcome;
+
+    let mut status = SystemStatus::default();
+    assert_eq!(outcome(check_prefer_boot, &status), Outcome::Warn);
+    status.partitions = vec![partition("/dev/sda1", &["PREFER_BOOT"]), partition("/dev/sda2", &[])];
+    assert_eq!(outcome(check_prefer_boot, &status), Outcome::Pass);
+    status.partitions[1].flags.push("PREFER_BOOT".to_string());
+    assert_eq!(outcome(check_prefer_boot, &status), Outcome::Fail);
+
+    status.boot_entries = vec![BootEntryStatus { file: "boot.conf".to_string(), title: None, kernel: None, kernel_exists: false }];
+    assert_eq!(outcome(check_boot_entries, &status), Outcome::Fail);
+
+    let mounted = |backing: &str| MountedImageStatus {
+        mountpoint: "/run/citadel/images/extra.mountpoint".to_string(), source: "/dev/loop0".to_string(),
+        verity_device: None, backing_file: Some(backing.to_string()),
+    };
+    status.mounted_images = vec![mounted("/storage/resources/dev/citadel-extra-dev-001.img")];
+    assert_eq!(outcome(check_stale_mounts, &status), Outcome::Pass);
+    status.mounted_images.push(mounted("/storage/resources/dev/citadel-extra-dev-002.img (deleted)"));
+    assert_eq!(outcome(check_stale_mounts, &status), Outcome::Fail);
+
+    status.network_allocations = vec![NetworkAllocationStatus { zone: "clear".to_string(), realm: "work".to_string(), address: "172.17.0.3".to_string() }];
+    assert_eq!(outcome(check_network_allocations, &status), Outcome::Warn);
+    status.realms = Some(vec![RealmEntry { name: "work".to_string(), status: "running".to_string(), camera_devices: None, uptime_secs: None, home_usage: None, home_quota: None, clipboard: None }]);
+    assert_eq!(outcome(check_network_allocations, &status), Outcome::Pass);
+    status.realms.as_mut().unwrap()[0].status = "stopped".to_string();
+    assert_eq!(outcome(check_network_allocations, &status), Outcome::Fail);
+
+    assert_eq!(run_checks(&status).len(), CHECKS.len());
+}
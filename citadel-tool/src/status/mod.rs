@@ -0,0 +1,385 @@
+use std::fs;
+use std::path::Path;
+use std::process::exit;
+
+use clap::{App,Arg,ArgMatches};
+use clap::AppSettings::*;
+use libcitadel::{Result,Partition,ResourceImage,KernelKey,RealmFS,BootStatus,Logger,LogLevel,SystemRoot,format_error,util};
+
+use crate::output::{self,Style,Table,paint};
+use crate::partition::PartitionStatus;
+use crate::realm::client::{RealmEntry,RealmsClient};
+use crate::update::stage::StagedUpdate;
+
+pub mod doctor;
+
+const RESOURCES_PATH: &str = "/storage/resources";
+const BOOT_ENTRIES_PATH: &str = "/boot/loader/entries";
+const KEYRING_PATH: &str = "/storage/keyring";
+const STORAGE_PATH: &str = "/storage";
+
+pub fn app() -> App<'static, 'static> {
+    App::new("citadel-status")
+        .about("Display a summary of the state of the system")
+        .settings(&[ColoredHelp, DisableHelpSubcommand, DisableVersion, DeriveDisplayOrder])
+        .arg(Arg::with_name("json")
+            .long("json")
+            .help("Display output as JSON"))
+}
+
+pub fn main(matches: &ArgMatches) {
+    Logger::set_log_level(LogLevel::Warn);
+
+    let json = matches.is_present("json");
+    if json {
+        output::set_json_mode();
+    }
+    let status = SystemStatus::collect();
+    let result = if json {
+        serde_json::to_string_pretty(&status)
+            .map(|s| println!("{}", s))
+            .map_err(|e| e.into())
+    } else {
+        status.print();
+        Ok(())
+    };
+    if let Err(ref e) = result {
+        println!("Error: {}", format_error(e));
+        exit(1);
+    }
+}
+
+/// An installed resource image below /storage/resources
+#[derive(Serialize)]
+pub struct ResourceStatus {
+    pub path: String,
+    pub channel: String,
+    pub image_type: String,
+    pub version: u32,
+    pub kernel_version: Option<String>,
+}
+
+/// A rootfs image staged with `citadel-tool update --stage`
+#[derive(Serialize)]
+pub struct StagedUpdateStatus {
+    pub path: String,
+    pub channel: String,
+    pub version: u32,
+    pub staged: u64,
+    /// Why the staged update will be discarded instead of applied at shutdown
+    pub problem: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct MountedImageStatus {
+    pub mountpoint: String,
+    pub source: String,
+    pub verity_device: Option<String>,
+    /// As reported by the loop device, with a " (deleted)" suffix if the file was removed
+    pub backing_file: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct BootEntryStatus {
+    pub file: String,
+    pub title: Option<String>,
+    /// Path of the kernel in the 'linux' line
+    pub kernel: Option<String>,
+    pub kernel_exists: bool,
+}
+
+#[derive(Serialize,Default)]
+pub struct KeyringStatus {
+    pub file_exists: bool,
+    /// True if the keys from the keyring were added to the kernel keyring
+    pub loaded: bool,
+}
+
+#[derive(Serialize)]
+pub struct StorageStatus {
+    pub path: String,
+    pub total: u64,
+    pub available: u64,
+}
+
+#[derive(Serialize)]
+pub struct NetworkAllocationStatus {
+    pub zone: String,
+    pub realm: String,
+    pub address: String,
+}
+
+///
+/// Everything reported by `citadel-tool status` and checked by `citadel-tool doctor`.
+/// Information which could not be collected is recorded in `errors`.
+///
+#[derive(Serialize,Default)]
+pub struct SystemStatus {
+    pub partitions: Vec<PartitionStatus>,
+    pub resources: Vec<ResourceStatus>,
+    pub staged_update: Option<StagedUpdateStatus>,
+    pub mounted_images: Vec<MountedImageStatus>,
+    pub boot_entries: Vec<BootEntryStatus>,
+    pub keyring: KeyringStatus,
+    pub storage: Option<StorageStatus>,
+    /// `None` if realmsd could not be reached
+    pub realms: Option<Vec<RealmEntry>>,
+    pub network_allocations: Vec<NetworkAllocationStatus>,
+    /// Storage check and realm start times recorded during boot
+    pub boot: BootStatus,
+    pub errors: Vec<String>,
+}
+
+impl SystemStatus {
+    pub fn collect() -> Self {
+        let mut status = SystemStatus::default();
+
+        match Partition::rootfs_partitions() {
+            Ok(partitions) => status.partitions = partitions.iter().map(PartitionStatus::new).collect(),
+            Err(e) => status.error("rootfs partitions", e),
+        }
+        match installed_resources(Path::new(RESOURCES_PATH)) {
+            Ok(resources) => status.resources = resources,
+            Err(e) => status.error("resource images", e),
+        }
+        let root = SystemRoot::default();
+        match StagedUpdate::load(&root) {
+            Ok(stage) => status.staged_update = stage.map(|stage| {
+                // The image file is not hashed here because that reads the whole image
+                let channel = crate::update::running_channel().ok();
+                StagedUpdateStatus {
+                    path: stage.image_path(&root).display().to_string(),
+                    channel: stage.channel().to_string(),
+                    version: stage.version(),
+                    staged: stage.staged(),
+                    problem: stage.problem(&root, channel.as_deref(), false),
+                }
+            }),
+            Err(e) => status.error("staged update", e),
+        }
+        status.mounted_images = ResourceImage::mounted_images().iter()
+            .map(|m| MountedImageStatus {
+                mountpoint: m.mountpoint().display().to_string(),
+                source: m.source().display().to_string(),
+                verity_device: m.verity_device().map(|s| s.to_string()),
+                backing_file: m.backing_file().map(|p| p.display().to_string()),
+            })
+            .collect();
+        match boot_entries(Path::new(BOOT_ENTRIES_PATH)) {
+            Ok(entries) => status.boot_entries = entries,
+            Err(e) => status.error("boot entries", e),
+        }
+        status.keyring = KeyringStatus {
+            file_exists: Path::new(KEYRING_PATH).exists(),
+            loaded: KernelKey::request_key("user", RealmFS::USER_KEYNAME).is_ok(),
+        };
+        match util::filesystem_space(STORAGE_PATH) {
+            Ok((total, available)) => status.storage = Some(StorageStatus { path: STORAGE_PATH.to_string(), total, available }),
+            Err(e) => status.error("storage space", e.into()),
+        }
+        match RealmsClient::connect().and_then(|client| client.list()) {
+            Ok(realms) => status.realms = Some(realms),
+            Err(e) => status.error("realms", e),
+        }
+        status.network_allocations = libcitadel::network_allocations().into_iter()
+            .map(|(zone, realm, address)| NetworkAllocationStatus { zone, realm, address: address.to_string() })
+            .collect();
+        status.boot = BootStatus::load();
+        status
+    }
+
+    fn error(&mut self, what: &str, err: failure::Error) {
+        self.errors.push(format!("failed to read {}: {}", what, format_error(&err)));
+    }
+
+    /// Names of realms reported as running by realmsd or `None` if realmsd could not be reached
+    pub fn running_realms(&self) -> Option<Vec<&str>> {
+        self.realms.as_ref().map(|realms| realms.iter()
+            .filter(|r| r.status != "stopped" && r.status != "config-error")
+            .map(|r| r.name.as_str())
+            .collect())
+    }
+
+    fn print(&self) {
+        output::heading("Rootfs partitions");
+        PartitionStatus::print_table(&self.partitions);
+
+        output::separator();
+        output::heading("Resource images");
+        let mut table = Table::new(&["CHANNEL", "TYPE", "VERSION", "KERNEL", "PATH"]);
+        for r in &self.resources {
+            table.row(vec![r.channel.clone(), r.image_type.clone(), r.version.to_string(),
+                           r.kernel_version.clone().unwrap_or_default(), r.path.clone()]);
+        }
+        table.print();
+        if let Some(ref stage) = self.staged_update {
+            let state = match stage.problem {
+                Some(ref problem) => paint(Style::Error, format!("will be discarded: {}", problem)),
+                None => "will be installed at shutdown".to_string(),
+            };
+            println!("Staged update: rootfs version {} from channel '{}' {}", stage.version, stage.channel, state);
+        }
+
+        output::separator();
+        output::heading("Mounted images");
+        let mut table = Table::new(&["MOUNTPOINT", "SOURCE", "BACKING FILE"]);
+        for m in &self.mounted_images {
+            let backing = match m.backing_file {
+                Some(ref f) if f.ends_with(" (deleted)") => paint(Style::Error, f),
+                Some(ref f) => f.clone(),
+                None => "unknown".to_string(),
+            };
+            table.row(vec![m.mountpoint.clone(), m.source.clone(), backing]);
+        }
+        table.print();
+
+        output::separator();
+        output::heading("Boot entries");
+        let mut table = Table::new(&["ENTRY", "TITLE", "KERNEL"]);
+        for e in &self.boot_entries {
+            let kernel = e.kernel.clone().unwrap_or_else(|| "no kernel".to_string());
+            let kernel = if e.kernel_exists { kernel } else { paint(Style::Error, format!("{} (missing)", kernel)) };
+            table.row(vec![e.file.clone(), e.title.clone().unwrap_or_default(), kernel]);
+        }
+        table.print();
+
+        output::separator();
+        println!("Keyring: {}, {}",
+                 if self.keyring.file_exists { "present" } else { "missing" },
+                 if self.keyring.loaded { "loaded" } else { "not loaded" });
+        if let Some(err) = self.boot.keyring_failed() {
+            println!("{} {}", paint(Style::Error, "Keyring failed to load at boot:"), err);
+        }
+        if let Some(ref storage) = self.storage {
+            println!("Storage: {} MiB available of {} MiB on {}",
+                     storage.available / (1024 * 1024), storage.total / (1024 * 1024), storage.path);
+        }
+        if let Some(fsck) = self.boot.storage_fsck() {
+            println!("Storage check at boot: {}", fsck);
+        }
+        if self.boot.storage_degraded() {
+            let message = self.boot.storage_message().unwrap_or("storage is mounted read-only");
+            println!("{} {}", paint(Style::Error, "Storage degraded:"), message);
+        }
+        self.print_realm_startup();
+
+        output::separator();
+        match self.realms {
+            Some(ref realms) => {
+                output::heading("Realms");
+                let mut table = Table::new(&["NAME", "STATUS"]);
+                for r in realms {
+                    table.row([&r.name, &r.status]);
+                }
+                table.print();
+            },
+            None => println!("Realms: {}", paint(Style::Warning, "realmsd is not running")),
+        }
+        if !self.network_allocations.is_empty() {
+            output::separator();
+            output::heading("Network allocations");
+            let mut table = Table::new(&["ZONE", "REALM", "ADDRESS"]);
+            for a in &self.network_allocations {
+                table.row([&a.zone, &a.realm, &a.address]);
+            }
+            table.print();
+        }
+
+        if !self.errors.is_empty() {
+            output::separator();
+            for e in &self.errors {
+                println!("{} {}", paint(Style::Error, "Error:"), e);
+            }
+        }
+    }
+
+    fn print_realm_startup(&self) {
+        let total = match self.boot.realm_startup_duration() {
+            Some(total) => total,
+            None => return,
+        };
+        output::separator();
+        output::heading(&format!("Realms started at boot ({:.1}s)", total.as_secs_f64()));
+        let mut table = Table::new(&["NAME", "TIME", "RESULT"]);
+        for r in self.boot.realm_startup() {
+            let result = match r.error() {
+                Some(err) => paint(Style::Error, err),
+                None => paint(Style::Good, "started"),
+            };
+            table.row(vec![r.name().to_string(), format!("{:.1}s", r.duration().as_secs_f64()), result]);
+        }
+        table.print();
+    }
+}
+
+fn installed_resources(base: &Path) -> Result<Vec<ResourceStatus>> {
+    let mut resources = Vec::new();
+    if !base.exists() {
+        return Ok(resources);
+    }
+    for channel in fs::read_dir(base)? {
+        let channel = channel?.path();
+        // Skip hidden directories such as the .staged directory of staged updates
+        let hidden = channel.file_name().map_or(true, |name| name.to_string_lossy().starts_with('.'));
+        if !channel.is_dir() || hidden {
+            continue;
+        }
+        for entry in fs::read_dir(&channel)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |ext| ext != "img") {
+                continue;
+            }
+            match ResourceImage::from_path(&path) {
+                Ok(image) => {
+                    let metainfo = image.metainfo();
+                    resources.push(ResourceStatus {
+                        path: path.display().to_string(),
+                        channel: metainfo.channel().to_string(),
+                        image_type: metainfo.image_type().to_string(),
+                        version: metainfo.version(),
+                        kernel_version: metainfo.kernel_version().map(|s| s.to_string()),
+                    });
+                },
+                Err(e) => warn!("{}", e),
+            }
+        }
+    }
+    resources.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(resources)
+}
+
+fn boot_entries(base: &Path) -> Result<Vec<BootEntryStatus>> {
+    let mut entries = Vec::new();
+    if !base.exists() {
+        return Ok(entries);
+    }
+    for entry in fs::read_dir(base)? {
+        let path = entry?.path();
+        if path.extension().map_or(true, |ext| ext != "conf") {
+            continue;
+        }
+        entries.push(BootEntryStatus::load(&path)?);
+    }
+    entries.sort_by(|a, b| a.file.cmp(&b.file));
+    Ok(entries)
+}
+
+impl BootEntryStatus {
+    fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let value = |key: &str| content.lines()
+            .find(|line| line.starts_with(key) && line[key.len()..].starts_with(' '))
+            .map(|line| line[key.len()..].trim().to_string());
+
+        // Kernel paths in boot entries are relative to the ESP mounted on /boot
+        let boot = path.parent().and_then(|p| p.parent()).and_then(|p| p.parent())
+            .unwrap_or_else(|| Path::new("/boot"));
+        let kernel = value("linux").map(|k| boot.join(k.trim_start_matches('/')));
+        Ok(BootEntryStatus {
+            file: path.file_name().map(|s| s.to_string_lossy().to_string()).unwrap_or_default(),
+            title: value("title"),
+            kernel_exists: kernel.as_ref().map_or(false, |k| k.exists()),
+            kernel: kernel.map(|k| k.display().to_string()),
+        })
+    }
+}
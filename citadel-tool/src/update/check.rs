@@ -0,0 +1,451 @@
+//! Check for available updates without installing anything.
+//!
+//! `citadel-tool update --check [URL]` downloads the channel index published at
+//! `URL` together with its detached signature at `URL.sig`, compares the latest
+//! image versions it lists against the installed images and writes the result
+//! to `/run/citadel/update-available.json`.
+//!
+//! The channel index is a JSON document listing the latest image of each type
+//! for one channel:
+//!
+//! ```text
+//! {
+//!     "version": 1,
+//!     "channel": "prod",
+//!     "images": {
+//!         "rootfs": { "version": 14, "shasum": "<sha256>", "url": "https://.../citadel-rootfs-prod-014.img" },
+//!         "extra":  { "version": 12, "shasum": "<sha256>", "url": "https://.../citadel-extra-prod-012.img" },
+//!         "kernel": { "version": 3, "shasum": "<sha256>", "url": "https://.../citadel-kernel-5.2.0-prod-003.img",
+//!                     "kernel-version": "5.2.0", "kernel-flavor": "standard" }
+//!     }
+//! }
+//! ```
+//!
+//! * `version` is the version of the index format, which is currently 1.
+//! * `channel` must be the channel of the running system.
+//! * `images` maps each of the image types `rootfs`, `kernel` and `extra` to
+//!   the `version` of the latest image (greater than 0), the hex encoded
+//!   sha256 `shasum` of its image data and the `url` it can be downloaded
+//!   from. Image types which are not published for the channel are omitted.
+//! * A `kernel` entry also names the `kernel-version` of the kernel in the image
+//!   and optionally its `kernel-flavor`, which defaults to `standard`.
+//!
+//! Unknown fields are ignored so that later versions of the format can add them.
+//!
+//! The signature file contains the hex encoded ed25519 signature of the exact
+//! bytes of the index, made with the same channel key that signs image headers.
+//! An index is only parsed after the signature has been verified.
+//!
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use dbus::{BusType, Connection, Message};
+use libcitadel::{Result, Exec, ImageHeader, Partition, PublicKey, SystemRoot, DEFAULT_KERNEL_FLAVOR, is_valid_kernel_flavor, public_key_for_channel, util};
+
+use crate::preflight::{self, Requirement};
+
+/// Result of the last check, rewritten by every successful check
+pub const UPDATE_AVAILABLE_PATH: &str = "/run/citadel/update-available.json";
+
+pub const EXIT_UP_TO_DATE: i32 = 0;
+pub const EXIT_CHECK_FAILED: i32 = 1;
+pub const EXIT_INVALID_INDEX: i32 = 2;
+pub const EXIT_UPDATE_AVAILABLE: i32 = 10;
+
+const INDEX_FORMAT_VERSION: u32 = 1;
+const IMAGE_TYPES: &[&str] = &["rootfs", "kernel", "extra"];
+
+const CURL_PATH: &str = "/usr/bin/curl";
+// The index and its signature are small, refuse to download anything larger
+const MAX_DOWNLOAD_SIZE: &str = "65536";
+const DOWNLOAD_TIMEOUT_SECS: &str = "60";
+
+const SIGNAL_PATH: &str = "/com/subgraph/citadel/Update";
+const SIGNAL_INTERFACE: &str = "com.subgraph.citadel.Update";
+
+const CHECK_REQUIREMENTS: &[Requirement] = &[
+    Requirement::Root,
+    Requirement::Mounted("/storage"),
+    Requirement::Command(CURL_PATH),
+];
+
+/// Error for an index which is unsigned, has a bad signature, or does not follow the schema.
+#[derive(Debug,Fail)]
+#[fail(display = "invalid channel index: {}", _0)]
+pub struct InvalidIndex(String);
+
+fn invalid(msg: impl Into<String>) -> InvalidIndex {
+    InvalidIndex(msg.into())
+}
+
+#[derive(Deserialize,Debug)]
+pub struct ChannelIndex {
+    version: u32,
+    channel: String,
+    images: BTreeMap<String, IndexImage>,
+}
+
+#[derive(Deserialize,Debug,Clone)]
+struct IndexImage {
+    version: u32,
+    shasum: String,
+    url: String,
+    #[serde(rename = "kernel-version")]
+    kernel_version: Option<String>,
+    #[serde(rename = "kernel-flavor")]
+    kernel_flavor: Option<String>,
+}
+
+impl IndexImage {
+    fn kernel_flavor(&self) -> &str {
+        self.kernel_flavor.as_deref().unwrap_or(DEFAULT_KERNEL_FLAVOR)
+    }
+
+    fn validate(&self, image_type: &str) -> Result<()> {
+        if !IMAGE_TYPES.contains(&image_type) {
+            return Err(invalid(format!("unknown image type '{}'", image_type)).into());
+        }
+        if self.version == 0 {
+            return Err(invalid(format!("{} image has version 0", image_type)).into());
+        }
+        if self.shasum.len() != 64 || !self.shasum.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(invalid(format!("{} image has invalid shasum '{}'", image_type, self.shasum)).into());
+        }
+        if self.url.trim().is_empty() {
+            return Err(invalid(format!("{} image has no url", image_type)).into());
+        }
+        if image_type == "kernel" {
+            match self.kernel_version {
+                Some(ref kv) if !kv.is_empty() => {},
+                _ => return Err(invalid("kernel image has no kernel-version").into()),
+            }
+            if !is_valid_kernel_flavor(self.kernel_flavor()) {
+                return Err(invalid(format!("kernel image has invalid kernel-flavor '{}'", self.kernel_flavor())).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ChannelIndex {
+    /// Verify `signature` (hex encoded) over the bytes of `index` with `pubkey`
+    /// and parse the index. The index must list images for `channel`.
+    pub fn parse(index: &[u8], signature: &str, pubkey: &PublicKey, channel: &str) -> Result<Self> {
+        let signature = hex::decode(signature.trim())
+            .map_err(|_| invalid("signature is not hex encoded"))?;
+        if !pubkey.verify(index, &signature) {
+            return Err(invalid("signature verification failed").into());
+        }
+        let index: ChannelIndex = serde_json::from_slice(index)
+            .map_err(|e| invalid(e.to_string()))?;
+        index.validate(channel)?;
+        Ok(index)
+    }
+
+    fn validate(&self, channel: &str) -> Result<()> {
+        if self.version != INDEX_FORMAT_VERSION {
+            return Err(invalid(format!("unsupported index format version {}", self.version)).into());
+        }
+        if self.channel != channel {
+            return Err(invalid(format!("index is for channel '{}' but the running system is from channel '{}'", self.channel, channel)).into());
+        }
+        for (image_type, image) in &self.images {
+            image.validate(image_type)?;
+        }
+        Ok(())
+    }
+}
+
+/// Versions of the images installed for one channel
+#[derive(Default,Debug)]
+struct InstalledVersions {
+    // None when no rootfs partition is initialized, such as when running in live mode
+    rootfs: Option<u32>,
+    extra: Option<u32>,
+    // Highest version of a kernel image of each flavor with a kernel in /boot
+    kernels: HashMap<String, u32>,
+}
+
+impl InstalledVersions {
+    fn load(root: &SystemRoot, channel: &str, rootfs: Option<u32>) -> Result<Self> {
+        let mut installed = InstalledVersions { rootfs, ..Default::default() };
+        let dir = root.path("/storage/resources").join(channel);
+        if !dir.exists() {
+            return Ok(installed);
+        }
+        let boot_kernels = super::all_boot_kernel_versions(root)?;
+        for dirent in fs::read_dir(&dir)? {
+            let path = dirent?.path();
+            if path.extension().map_or(false, |ext| ext == "img") {
+                if let Err(e) = installed.add_image(&path, channel, &boot_kernels) {
+                    warn!("Ignoring image {}: {}", path.display(), e);
+                }
+            }
+        }
+        Ok(installed)
+    }
+
+    fn add_image(&mut self, path: &Path, channel: &str, boot_kernels: &HashSet<(String, String)>) -> Result<()> {
+        let metainfo = ImageHeader::from_file(path)?.metainfo();
+        if metainfo.channel() != channel {
+            return Ok(());
+        }
+        let version = metainfo.version();
+        match metainfo.image_type() {
+            "extra" => self.extra = self.extra.max(Some(version)),
+            "kernel" => {
+                let flavor = metainfo.kernel_flavor().to_string();
+                let kernel_version = metainfo.kernel_version().unwrap_or("").to_string();
+                if boot_kernels.contains(&(flavor.clone(), kernel_version)) {
+                    let entry = self.kernels.entry(flavor).or_insert(version);
+                    *entry = (*entry).max(version);
+                }
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    fn installed(&self, image_type: &str, image: &IndexImage) -> Option<u32> {
+        match image_type {
+            "rootfs" => self.rootfs,
+            "extra" => Some(self.extra.unwrap_or(0)),
+            "kernel" => Some(self.kernels.get(image.kernel_flavor()).cloned().unwrap_or(0)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize,Debug,PartialEq)]
+struct AvailableUpdate {
+    #[serde(rename = "image-type")]
+    image_type: String,
+    installed: u32,
+    available: u32,
+    url: String,
+    shasum: String,
+}
+
+#[derive(Serialize)]
+struct CheckResult {
+    checked: u64,
+    channel: String,
+    updates: Vec<AvailableUpdate>,
+}
+
+// Images listed in the index with a newer version than the installed image of the same type.
+// No rootfs update is reported when no rootfs partition is installed.
+fn available_updates(index: &ChannelIndex, installed: &InstalledVersions) -> Vec<AvailableUpdate> {
+    index.images.iter()
+        .filter_map(|(image_type, image)| {
+            let current = installed.installed(image_type, image)?;
+            if image.version <= current {
+                return None;
+            }
+            Some(AvailableUpdate {
+                image_type: image_type.clone(),
+                installed: current,
+                available: image.version,
+                url: image.url.clone(),
+                shasum: image.shasum.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Run a check and return the process exit code
+pub fn main(url: Option<&str>, notify: bool) -> i32 {
+    if let Err(e) = preflight::check("update --check", CHECK_REQUIREMENTS) {
+        warn!("{}", e);
+        return EXIT_CHECK_FAILED;
+    }
+    let root = SystemRoot::default();
+    let url = match url.map(|s| s.to_string()).or_else(|| super::UpdateConfig::load(&root).check_url) {
+        Some(url) => url,
+        None => {
+            warn!("No index url given and no check-url set in {}", super::UPDATE_CONFIG);
+            return EXIT_CHECK_FAILED;
+        }
+    };
+    match check(&root, &url, notify) {
+        Ok(true) => EXIT_UPDATE_AVAILABLE,
+        Ok(false) => EXIT_UP_TO_DATE,
+        Err(e) => {
+            warn!("Update check failed: {}", e);
+            if e.downcast_ref::<InvalidIndex>().is_some() {
+                EXIT_INVALID_INDEX
+            } else {
+                EXIT_CHECK_FAILED
+            }
+        }
+    }
+}
+
+fn check(root: &SystemRoot, url: &str, notify: bool) -> Result<bool> {
+    let channel = super::running_channel()?;
+    let pubkey = match public_key_for_channel(&channel)? {
+        Some(pubkey) => pubkey,
+        None => bail!("no image signing key is known for channel '{}'", channel),
+    };
+
+    let index = download(url)?;
+    let signature = download(&format!("{}.sig", url))?;
+    let index = ChannelIndex::parse(index.as_bytes(), &signature, &pubkey, &channel)?;
+
+    let installed = InstalledVersions::load(root, &channel, installed_rootfs_version(&channel)?)?;
+    let updates = available_updates(&index, &installed);
+    for update in &updates {
+        info!("{} image version {} is available (installed: {})", update.image_type, update.available, update.installed);
+    }
+    if updates.is_empty() {
+        info!("All images are up to date");
+    }
+
+    let result = CheckResult { checked: super::unix_time(), channel, updates };
+    write_result(&root.path(UPDATE_AVAILABLE_PATH), &result)?;
+    if notify && !result.updates.is_empty() {
+        if let Err(e) = send_update_available(&result) {
+            warn!("Failed to send UpdateAvailable signal: {}", e);
+        }
+    }
+    Ok(!result.updates.is_empty())
+}
+
+// Highest version of an initialized rootfs partition from `channel`. A partition
+// which cannot be read does not prevent checking for updates.
+fn installed_rootfs_version(channel: &str) -> Result<Option<u32>> {
+    Ok(Partition::loadable_rootfs_partitions()?.iter()
+        .filter(|p| p.is_initialized())
+        .map(|p| p.metainfo())
+        .filter(|m| m.channel() == channel)
+        .map(|m| m.version())
+        .max())
+}
+
+fn download(url: &str) -> Result<String> {
+    let output = Exec::new(CURL_PATH)
+        .args(["--fail", "--silent", "--show-error", "--location",
+               "--max-filesize", MAX_DOWNLOAD_SIZE, "--max-time", DOWNLOAD_TIMEOUT_SECS, url])
+        .capture()?;
+    output.check()?;
+    Ok(output.stdout().to_string())
+}
+
+fn write_result(path: &Path, result: &CheckResult) -> Result<()> {
+    util::write_file_atomic(path, serde_json::to_string_pretty(result)?)
+}
+
+// Broadcast UpdateAvailable(channel, [(image type, installed version, available version)])
+// on the system bus.
+fn send_update_available(result: &CheckResult) -> Result<()> {
+    let connection = Connection::get_private(BusType::System)?;
+    let updates = result.updates.iter()
+        .map(|u| (u.image_type.clone(), u.installed, u.available))
+        .collect::<Vec<_>>();
+    let msg = Message::new_signal(SIGNAL_PATH, SIGNAL_INTERFACE, "UpdateAvailable")
+        .map_err(|e| format_err!("{}", e))?
+        .append2(result.channel.as_str(), updates);
+    connection.send(msg)
+        .map_err(|()| format_err!("failed to send signal"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+fn signed(index: &str) -> (Vec<u8>, String) {
+    let signature = libcitadel::devkeys().sign(index.as_bytes());
+    (index.as_bytes().to_vec(), hex::encode(signature.to_bytes()))
+}
+
+#[cfg(test)]
+const SHASUM: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+#[cfg(test)]
+fn index_with(images: &str) -> String {
+    format!(r#"{{ "version": 1, "channel": "dev", "images": {{ {} }} }}"#, images)
+}
+
+#[test]
+fn test_parse_channel_index() {
+    let pubkey = libcitadel::devkeys().public_key();
+    let parse = |index: &str| {
+        let (bytes, signature) = signed(index);
+        ChannelIndex::parse(&bytes, &signature, &pubkey, "dev")
+    };
+    let rootfs = format!(r#""rootfs": {{ "version": 14, "shasum": "{}", "url": "https://example.com/rootfs.img" }}"#, SHASUM);
+    let kernel = format!(r#""kernel": {{ "version": 3, "shasum": "{}", "url": "https://example.com/kernel.img", "kernel-version": "5.2.0" }}"#, SHASUM);
+    let index = parse(&index_with(&format!("{}, {}", rootfs, kernel))).unwrap();
+    assert_eq!(index.images["rootfs"].version, 14);
+    assert_eq!(index.images["kernel"].kernel_flavor(), "standard");
+
+    // Unknown fields are ignored
+    assert!(parse(&index_with(&rootfs).replacen("{", r#"{ "published": "2019-06-21","#, 1)).is_ok());
+
+    let malformed = [
+        "".to_string(),
+        "[]".to_string(),
+        r#"{ "version": 1, "channel": "dev" }"#.to_string(),
+        index_with(&rootfs).replace(r#""version": 1,"#, r#""version": 2,"#),
+        index_with(&rootfs).replace(r#""channel": "dev""#, r#""channel": "prod""#),
+        index_with(&rootfs).replace("\"version\": 14", "\"version\": 0"),
+        index_with(&rootfs).replace("\"version\": 14", "\"version\": \"14\""),
+        index_with(&rootfs).replace(SHASUM, "abc123"),
+        index_with(&rootfs).replace("https://example.com/rootfs.img", " "),
+        index_with(&rootfs).replace("\"rootfs\"", "\"bootloader\""),
+        index_with(&kernel).replace(r#", "kernel-version": "5.2.0""#, ""),
+        index_with(&kernel).replace(r#""kernel-version": "5.2.0""#, r#""kernel-version": "5.2.0", "kernel-flavor": "Bad Flavor""#),
+    ];
+    for index in &malformed {
+        let err = parse(index).unwrap_err();
+        assert!(err.downcast_ref::<InvalidIndex>().is_some(), "{}: {}", index, err);
+    }
+
+    // A signature of different data, a signature by another key, and a signature that is not hex
+    let (bytes, _) = signed(&index_with(&rootfs));
+    let (_, other) = signed(&index_with(&kernel));
+    let wrong_key = hex::encode(libcitadel::KeyPair::generate().sign(&bytes).to_bytes());
+    for signature in &[other, wrong_key, "not hex".to_string(), String::new()] {
+        let err = ChannelIndex::parse(&bytes, signature, &pubkey, "dev").unwrap_err();
+        assert!(err.downcast_ref::<InvalidIndex>().is_some(), "{}", err);
+    }
+}
+
+#[test]
+fn test_available_updates() {
+    use super::fake_root::FakeRoot;
+
+    let fake = FakeRoot::new("check");
+    fake.add_kernel("5.1.3");
+    let images = fake.root().path("/storage/resources/dev");
+    fs::rename(fake.build_image("extra", 12, 1), images.join("citadel-extra-012.img")).unwrap();
+    fs::rename(fake.build_kernel_image("5.1.3", "standard", 2), images.join(super::kernel_image_filename("5.1.3", "standard", 1))).unwrap();
+    // A kernel image is not counted as installed unless its kernel is in /boot
+    fs::rename(fake.build_kernel_image("5.1.3", "hardened", 3), images.join(super::kernel_image_filename("5.1.3", "hardened", 1))).unwrap();
+
+    let index = |extra: u32, kernel: u32, rootfs: u32| {
+        let images = format!(r#""extra": {{ "version": {}, "shasum": "{}", "url": "https://example.com/extra.img" }},
+                                "kernel": {{ "version": {}, "shasum": "{}", "url": "https://example.com/kernel.img", "kernel-version": "5.2.0" }},
+                                "rootfs": {{ "version": {}, "shasum": "{}", "url": "https://example.com/rootfs.img" }}"#,
+                             extra, SHASUM, kernel, SHASUM, rootfs, SHASUM);
+        let (bytes, signature) = signed(&index_with(&images));
+        ChannelIndex::parse(&bytes, &signature, &libcitadel::devkeys().public_key(), "dev").unwrap()
+    };
+    let updated = |installed: &InstalledVersions, extra, kernel, rootfs| {
+        available_updates(&index(extra, kernel, rootfs), installed).iter()
+            .map(|u| format!("{} {}->{}", u.image_type, u.installed, u.available))
+            .collect::<Vec<_>>()
+    };
+
+    let installed = InstalledVersions::load(fake.root(), "dev", Some(7)).unwrap();
+    assert_eq!(installed.extra, Some(12));
+    assert_eq!(installed.kernels.get("standard"), Some(&1));
+    assert_eq!(installed.kernels.get("hardened"), None);
+    assert!(updated(&installed, 12, 1, 7).is_empty());
+    assert!(updated(&installed, 11, 1, 6).is_empty());
+    assert_eq!(updated(&installed, 13, 1, 8), vec!["extra 12->13", "rootfs 7->8"]);
+    assert_eq!(updated(&installed, 12, 2, 7), vec!["kernel 1->2"]);
+
+    // No rootfs partition to update in live mode, images of other channels are ignored
+    let live = InstalledVersions::load(fake.root(), "prod", None).unwrap();
+    assert_eq!(updated(&live, 1, 1, 99), vec!["extra 0->1", "kernel 0->1"]);
+}
@@ -0,0 +1,204 @@
+use std::fs;
+use std::path::{Path,PathBuf};
+
+use libcitadel::{ImageHeader,ImageInfo,ResourceImageBuilder,SystemRoot};
+use libcitadel::util::TempDir;
+
+use super::kernel::KernelInstaller;
+
+///
+/// A temporary directory laid out like the parts of a Citadel system which
+/// `citadel-tool update` installs to, so that installation can be tested
+/// without root. The directory is removed when the `FakeRoot` is dropped.
+///
+pub struct FakeRoot {
+    root: SystemRoot,
+    work: PathBuf,
+    _base: TempDir,
+}
+
+impl FakeRoot {
+    pub fn new(name: &str) -> Self {
+        let base = TempDir::new(&format!("fake-root-{}", name)).unwrap();
+        let root = SystemRoot::new(base.join("root"));
+        for dir in &["/storage/resources/dev", "/boot/loader/entries", "/run/citadel/images"] {
+            fs::create_dir_all(root.path(dir)).unwrap();
+        }
+        fs::write(root.path("/boot/loader/loader.conf"), "default boot*\n").unwrap();
+        let work = base.join("work");
+        fs::create_dir_all(&work).unwrap();
+        FakeRoot { root, work, _base: base }
+    }
+
+    pub fn root(&self) -> &SystemRoot {
+        &self.root
+    }
+
+    /// Build an image file outside of the fake root. Images built with the same
+    /// `version` and `seed` have identical image data. The image is flagged as
+    /// having a dm-verity hash tree so that installing it does not run veritysetup.
+    pub fn build_image(&self, image_type: &str, version: u32, seed: u8) -> PathBuf {
+        self.build_image_with_info(ImageInfo::new(image_type, "dev", version, "20190621120000"), seed)
+    }
+
+    /// Build a kernel image file outside of the fake root for a kernel of `flavor`
+    pub fn build_kernel_image(&self, kernel_version: &str, flavor: &str, seed: u8) -> PathBuf {
+        let mut info = ImageInfo::new("kernel", "dev", 1, "20190621120000");
+        info.kernel_version = Some(kernel_version.to_string());
+        info.kernel_flavor = Some(flavor.to_string());
+        self.build_image_with_info(info, seed)
+    }
+
+    fn build_image_with_info(&self, info: ImageInfo, seed: u8) -> PathBuf {
+        let squashfs = self.work.join(format!("{}-{}-{}.squashfs", info.image_type, info.version, seed));
+        let data = (0..4096 * 2).map(|i| (i % 251) as u8 ^ seed).collect::<Vec<_>>();
+        fs::write(&squashfs, data).unwrap();
+
+        let target = self.work.join(format!("{}-{}", seed, info.image_filename()));
+        ResourceImageBuilder::from_squashfs(&squashfs, info)
+            .verity(false)
+            .build(&target)
+            .unwrap();
+        let header = ImageHeader::from_file(&target).unwrap();
+        header.set_flag(ImageHeader::FLAG_HASH_TREE);
+        header.write_header_to(&target).unwrap();
+        target
+    }
+
+    /// Write a kernel bzImage file to /boot and return its path
+    pub fn add_kernel(&self, version: &str) -> PathBuf {
+        let path = self.root.path(format!("/boot/bzImage-{}", version));
+        fs::write(&path, format!("kernel {}", version)).unwrap();
+        path
+    }
+
+    /// Write a boot loader entry for a kernel added with `add_kernel()`
+    pub fn add_boot_entry(&self, filename: &str, kernel_version: &str) {
+        let content = format!("title Subgraph OS (Citadel {v})\nlinux /bzImage-{v}\noptions root=/dev/mapper/rootfs quiet\n", v = kernel_version);
+        fs::write(self.root.path("/boot/loader/entries").join(filename), content).unwrap();
+    }
+
+    /// Write a new kernel bzImage file outside of the fake root
+    pub fn new_kernel(&self, version: &str) -> PathBuf {
+        let path = self.work.join(format!("bzImage-{}", version));
+        fs::write(&path, format!("new kernel {}", version)).unwrap();
+        path
+    }
+
+    /// Sorted names of the files in the directory `dir` of the fake root
+    pub fn files(&self, dir: &str) -> Vec<String> {
+        let mut files = fs::read_dir(self.root.path(dir)).unwrap()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.file_type().unwrap().is_file())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+}
+
+fn installed_shasum(path: &Path) -> String {
+    ImageHeader::from_file(path).unwrap().metainfo().shasum().to_string()
+}
+
+#[test]
+fn test_install_extra_image() {
+    let fake = FakeRoot::new("extra");
+    let first = fake.build_image("extra", 12, 1);
+    let shasum = installed_shasum(&first);
+    super::install_image(fake.root(), &first, 0).unwrap();
+    assert!(!first.exists());
+    assert_eq!(fake.files("/storage/resources/dev"), vec!["citadel-extra-012.img"]);
+
+    // An image with the same version replaces the installed image, which is
+    // rotated to a .0 file and then removed because it is an older extra image
+    let replacement = fake.build_image("extra", 12, 2);
+    super::install_image(fake.root(), &replacement, 0).unwrap();
+    assert_eq!(fake.files("/storage/resources/dev"), vec!["citadel-extra-012.img"]);
+    let installed = fake.root().path("/storage/resources/dev/citadel-extra-012.img");
+    assert_ne!(installed_shasum(&installed), shasum);
+
+    let newer = fake.build_image("extra", 13, 3);
+    super::install_image(fake.root(), &newer, 0).unwrap();
+    assert_eq!(fake.files("/storage/resources/dev"), vec!["citadel-extra-013.img"]);
+}
+
+#[test]
+fn test_install_duplicate_image() {
+    let fake = FakeRoot::new("duplicate");
+    let image = fake.build_image("extra", 12, 1);
+    super::install_image(fake.root(), &image, 0).unwrap();
+
+    let duplicate = fake.build_image("extra", 12, 1);
+    let err = super::install_image(fake.root(), &duplicate, 0).unwrap_err();
+    assert!(err.to_string().starts_with("A duplicate image file with the same shasum already exists"), "{}", err);
+    assert!(duplicate.exists());
+    assert_eq!(fake.files("/storage/resources/dev"), vec!["citadel-extra-012.img"]);
+}
+
+#[test]
+fn test_install_kernel_entries() {
+    let fake = FakeRoot::new("kernel");
+    for (entry, version) in &[("boot.conf", "5.1.3"), ("boot.1.conf", "5.1.2"), ("boot.2.conf", "5.1.1")] {
+        fake.add_kernel(version);
+        fake.add_boot_entry(entry, version);
+    }
+
+    // Entries are rotated to make room for the new entry and the oldest entry is
+    // removed together with its kernel to keep at most three entries.
+    let kernel = fake.new_kernel("5.2.0");
+    KernelInstaller::install_kernel(fake.root(), &kernel, "5.2.0", "standard").unwrap();
+    assert_eq!(fake.files("/boot/loader/entries"), vec!["boot+3.conf", "boot.1.conf", "boot.2.conf"]);
+    assert_eq!(fake.files("/boot"), vec!["bzImage-5.1.2", "bzImage-5.1.3", "bzImage-5.2.0"]);
+
+    let entry = fs::read_to_string(fake.root().path("/boot/loader/entries/boot+3.conf")).unwrap();
+    assert_eq!(entry, "title Subgraph OS (Citadel 5.2.0)\nlinux /bzImage-5.2.0\noptions root=/dev/mapper/rootfs quiet\n");
+    assert!(fs::read_to_string(fake.root().path("/boot/loader/entries/boot.1.conf")).unwrap().contains("bzImage-5.1.3"));
+
+    let err = KernelInstaller::install_kernel(fake.root(), &kernel, "5.2.0", "standard").unwrap_err();
+    assert!(err.to_string().contains("already installed"), "{}", err);
+}
+
+#[test]
+fn test_install_kernel_flavors() {
+    let fake = FakeRoot::new("flavors");
+    for (entry, kernel) in &[("boot.conf", "5.1.3"), ("boot.1.conf", "5.1.2"), ("boot-hardened.conf", "hardened-5.1.3")] {
+        fake.add_kernel(kernel);
+        fake.add_boot_entry(entry, kernel);
+    }
+
+    // Each flavor has its own series of entries which is rotated separately
+    KernelInstaller::install_kernel(fake.root(), &fake.new_kernel("hardened-5.2.0"), "5.2.0", "hardened").unwrap();
+    assert_eq!(fake.files("/boot/loader/entries"), vec!["boot-hardened+3.conf", "boot-hardened.1.conf", "boot.1.conf", "boot.conf"]);
+    let entry = fs::read_to_string(fake.root().path("/boot/loader/entries/boot-hardened+3.conf")).unwrap();
+    assert_eq!(entry, "title Subgraph OS (Citadel 5.2.0 hardened)\nlinux /bzImage-hardened-5.2.0\noptions root=/dev/mapper/rootfs quiet\n");
+
+    KernelInstaller::install_kernel(fake.root(), &fake.new_kernel("5.2.0"), "5.2.0", "standard").unwrap();
+    assert_eq!(fake.files("/boot/loader/entries"),
+               vec!["boot+3.conf", "boot-hardened+3.conf", "boot-hardened.1.conf", "boot.1.conf", "boot.2.conf"]);
+    assert_eq!(fake.files("/boot"), vec!["bzImage-5.1.2", "bzImage-5.1.3", "bzImage-5.2.0", "bzImage-hardened-5.1.3", "bzImage-hardened-5.2.0"]);
+
+    let versions = super::all_boot_kernel_versions(fake.root()).unwrap();
+    assert!(versions.contains(&("hardened".to_string(), "5.2.0".to_string())));
+    assert!(versions.contains(&("standard".to_string(), "5.2.0".to_string())));
+
+    // Unused kernel images are only removed if they have the same flavor as the installed kernel
+    let images = fake.root().path("/storage/resources/dev");
+    let mut paths = Vec::new();
+    for (seed, (kernel_version, flavor)) in [("5.1.1", "standard"), ("5.1.3", "standard"), ("5.1.1", "hardened"), ("5.1.3", "hardened")].iter().enumerate() {
+        let path = images.join(super::kernel_image_filename(kernel_version, flavor, 1));
+        fs::rename(fake.build_kernel_image(kernel_version, flavor, seed as u8), &path).unwrap();
+        paths.push(path);
+    }
+    assert_eq!(fake.files("/storage/resources/dev")[0], "citadel-kernel-5.1.1-001.img");
+    let unused = |flavor: &str| paths.iter()
+        .filter(|p| super::is_unused_kernel_image(p, flavor, &versions).unwrap())
+        .map(|p| p.file_name().unwrap().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(unused("standard"), vec!["citadel-kernel-5.1.1-001.img"]);
+    assert_eq!(unused("hardened"), vec!["citadel-kernel-hardened-5.1.1-001.img"]);
+
+    KernelInstaller::set_default_flavor(fake.root(), "hardened").unwrap();
+    assert_eq!(fs::read_to_string(fake.root().path("/boot/loader/loader.conf")).unwrap(), "default boot-hardened.conf\n");
+    assert!(KernelInstaller::set_default_flavor(fake.root(), "rt").is_err());
+}
@@ -2,14 +2,60 @@ use std::fs;
 use std::fmt::{self,Write};
 use std::path::{Path,PathBuf};
 
-use libcitadel::{Result,util};
+use libcitadel::{Result,SystemRoot,util,DEFAULT_KERNEL_FLAVOR};
 
 const DEFAULT_MAX_ENTRIES: usize = 3;
 const DEFAULT_BOOT_COUNT: u32 = 3;
 const DEFAULT_KERNEL_CMDLINE: &str = "root=/dev/mapper/rootfs add_efi_memmap intel_iommu=off cryptomgr.notests rcupdate.rcu_expedited=1 rcu_nocbs=0-64 tsc=reliable no_timer_check noreplace-smp i915.fastboot=1 quiet splash";
 
+/// Name of the boot entries for kernels of the default flavor
+const DEFAULT_BOOT_ENTRY: &str = "boot";
+
+/// Name of the boot entries for kernels of `flavor`. Entries for the default
+/// flavor are named `boot` and entries for other flavors `boot-$flavor`, so
+/// that each flavor has its own series of entries.
+pub fn boot_entry_name(flavor: &str) -> String {
+    if flavor == DEFAULT_KERNEL_FLAVOR {
+        DEFAULT_BOOT_ENTRY.to_string()
+    } else {
+        format!("{}-{}", DEFAULT_BOOT_ENTRY, flavor)
+    }
+}
+
+// Filename of a kernel bzImage in /boot, such as bzImage-5.1.3 for the default
+// flavor or bzImage-hardened-5.1.3 for other flavors
+fn kernel_filename(flavor: &str, version: &KernelVersion) -> String {
+    if flavor == DEFAULT_KERNEL_FLAVOR {
+        format!("bzImage-{}", version)
+    } else {
+        format!("bzImage-{}-{}", flavor, version)
+    }
+}
+
+// Return `content` of loader.conf with the default entry set to `entry`
+fn loader_conf_with_default(content: &str, entry: &str) -> String {
+    let default_line = format!("default {}", entry);
+    let mut replaced = false;
+    let mut lines = content.lines()
+        .map(|line| {
+            if line.split_whitespace().next() == Some("default") {
+                replaced = true;
+                default_line.clone()
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>();
+    if !replaced {
+        lines.push(default_line);
+    }
+    lines.join("\n") + "\n"
+}
+
 pub struct KernelInstaller {
+    boot: PathBuf,
     max_entries: usize,
+    flavor: String,
     new_kernel: KernelBzImage,
     all_entries: BootEntries,
     boot_entries: BootEntries,
@@ -17,8 +63,8 @@ pub struct KernelInstaller {
 
 impl KernelInstaller {
 
-    pub fn install_kernel(new_kernel: &Path, version: &str) -> Result<()> {
-        let mut installer = Self::new(new_kernel, version)?;
+    pub fn install_kernel(root: &SystemRoot, new_kernel: &Path, version: &str, flavor: &str) -> Result<()> {
+        let mut installer = Self::new(root, new_kernel, version, flavor)?;
         if installer.is_already_installed() {
             bail!("identical kernel is is already installed");
         }
@@ -26,13 +72,16 @@ impl KernelInstaller {
         Ok(())
     }
 
-    pub fn new(new_kernel: &Path, version: &str) -> Result<KernelInstaller> {
-        let new_kernel = KernelBzImage::from_path_and_version(new_kernel.to_path_buf(), version)?;
-        let all_entries = BootEntries::load()?;
-        let boot_entries = all_entries.find_by_name("boot");
+    pub fn new(root: &SystemRoot, new_kernel: &Path, version: &str, flavor: &str) -> Result<KernelInstaller> {
+        let boot = root.boot();
+        let new_kernel = KernelBzImage::from_path_and_version(new_kernel.to_path_buf(), version, flavor)?;
+        let all_entries = BootEntries::load(&boot)?;
+        let boot_entries = all_entries.find_by_name(&boot_entry_name(flavor));
 
         Ok(KernelInstaller {
+            boot,
             max_entries: DEFAULT_MAX_ENTRIES,
+            flavor: flavor.to_string(),
             new_kernel,
             all_entries,
             boot_entries,
@@ -53,7 +102,8 @@ impl KernelInstaller {
         self.boot_entries.rotate()?;
 
         let options = self.generate_options_line();
-        let entry = BootEntry::create_for_kernel("boot", self.new_kernel.clone(), options, Some(DEFAULT_BOOT_COUNT.to_string()));
+        let name = boot_entry_name(&self.flavor);
+        let entry = BootEntry::create_for_kernel(&self.boot, &name, self.new_kernel.clone(), options, Some(DEFAULT_BOOT_COUNT.to_string()));
         entry.write(&install_path)?;
 
         while self.boot_entries.0.len() >= self.max_entries  {
@@ -77,25 +127,46 @@ impl KernelInstaller {
             Some(v) => v,
             None => bail!("new kernel does not have a version"),
         };
-        let mut path = Path::new("/boot").join(format!("bzImage-{}", version));
+        let filename = kernel_filename(&self.flavor, &version);
+        let mut path = self.boot.join(&filename);
 
         for i in 1..5 {
             if !path.exists() {
                 return Ok(path);
             }
-            path = Path::new("/boot").join(format!("bzImage-{}-{}", version, i));
+            path = self.boot.join(format!("{}-{}", filename, i));
         }
         bail!("Unable to find unused name for new kernel")
     }
 
-    // return kernel commandline from most recent boot entry.
+    // return kernel commandline from most recent boot entry of the same flavor,
+    // or of the default flavor when installing the first kernel of a flavor.
     // If no boot entries exist, return default kernel commandline
     fn generate_options_line(&self) -> &str {
-        if let Some(entry) = self.boot_entries.0.first() {
-            entry.options.as_str()
-        } else {
-            DEFAULT_KERNEL_CMDLINE
+        self.boot_entries.0.first()
+            .or_else(|| self.all_entries.0.iter()
+                .filter(|e| e.name == DEFAULT_BOOT_ENTRY)
+                .min_by_key(|e| e.index))
+            .map(|e| e.options.as_str())
+            .unwrap_or(DEFAULT_KERNEL_CMDLINE)
+    }
+
+    /// Set the default entry in /boot/loader/loader.conf to the newest boot
+    /// entry for kernels of `flavor`.
+    pub fn set_default_flavor(root: &SystemRoot, flavor: &str) -> Result<()> {
+        let boot = root.boot();
+        let name = boot_entry_name(flavor);
+        if BootEntries::load(&boot)?.find_by_name(&name).0.is_empty() {
+            bail!("no boot entries exist for kernel flavor {}", flavor);
+        }
+        let path = boot.join("loader/loader.conf");
+        let content = fs::read_to_string(&path)?;
+        let updated = loader_conf_with_default(&content, &format!("{}.conf", name));
+        if updated != content {
+            info!("Setting default boot entry in {} to {}.conf", path.display(), name);
+            fs::write(&path, updated)?;
         }
+        Ok(())
     }
 }
 
@@ -129,9 +200,15 @@ impl KernelVersion {
         })
     }
 
-    pub fn parse_from_path(path: &Path) -> Option<KernelVersion> {
-        Self::path_version_string(path)
-            .and_then(|s| Self::parse_from_str(&s))
+    /// Return the flavor and version of a kernel from a path such as
+    /// /boot/bzImage-1.2.3 or /boot/bzImage-hardened-1.2.3
+    pub fn parse_flavor_from_path(path: &Path) -> Option<(String, KernelVersion)> {
+        let s = Self::path_version_string(path)?;
+        let (flavor, version) = match s.find('-') {
+            Some(idx) if s.starts_with(|c: char| c.is_ascii_alphabetic()) => (&s[..idx], &s[idx + 1..]),
+            _ => (DEFAULT_KERNEL_FLAVOR, s.as_str()),
+        };
+        Self::parse_from_str(version).map(|v| (flavor.to_string(), v))
     }
 
     /// Return version as a string without including revision
@@ -182,36 +259,34 @@ impl fmt::Display for KernelVersion {
 struct BootEntries(Vec<BootEntry>);
 
 impl BootEntries {
-    const BASE_PATH: &'static str = "/boot/loader/entries";
-
-    // The directory where boot entries are found
-    fn base_path() -> &'static Path {
-        Path::new(Self::BASE_PATH)
+    // The directory below the boot partition where boot entries are found
+    fn base_path(boot: &Path) -> PathBuf {
+        boot.join("loader/entries")
     }
 
-    fn load() -> Result<BootEntries> {
+    fn load(boot: &Path) -> Result<BootEntries> {
         let mut entries = BootEntries(Vec::new());
-        entries.load_entries()?;
+        entries.load_entries(boot)?;
         Ok(entries)
     }
 
-    fn load_entries(&mut self) -> Result<()> {
-        let base_path = Self::base_path();
+    fn load_entries(&mut self, boot: &Path) -> Result<()> {
+        let base_path = Self::base_path(boot);
         if !base_path.exists() {
             return Ok(())
         }
         for dirent in fs::read_dir(base_path)? {
             let dirent = dirent?;
             if let Some(fname) = dirent.file_name().to_str() {
-                self.load_filename(fname);
+                self.load_filename(boot, fname);
             }
         }
         Ok(())
     }
 
-    fn load_filename(&mut self, fname: &str) {
+    fn load_filename(&mut self, boot: &Path, fname: &str) {
         if fname.ends_with(".conf") {
-            let mut entry = BootEntry::from_filename(fname);
+            let mut entry = BootEntry::from_filename(boot, fname);
             if let Err(e) = entry.load() {
                 warn!("Error loading boot entry {}: {}", fname, e);
             } else {
@@ -259,6 +334,8 @@ impl BootEntries {
 
 #[derive(Clone)]
 struct BootEntry {
+    // The boot partition mountpoint, kernel paths in entries are relative to it
+    boot: PathBuf,
     // The filename with index,bootcount,and suffix removed
     name: String,
     // An optional integer value parsed from filename
@@ -300,14 +377,15 @@ impl BootEntry {
         (name, None, boot_count)
     }
 
-    fn from_filename(filename: &str) -> BootEntry {
+    fn from_filename(boot: &Path, filename: &str) -> BootEntry {
         let (name, index, boot_count) = Self::parse_filename(filename);
-        Self::new(name, index, boot_count)
+        Self::new(boot, name, index, boot_count)
     }
 
-    fn new<S: AsRef<str>>(name: S, index: Option<u32>, boot_count: Option<String>) -> BootEntry {
+    fn new<S: AsRef<str>>(boot: &Path, name: S, index: Option<u32>, boot_count: Option<String>) -> BootEntry {
         let name = name.as_ref().to_string();
         BootEntry {
+            boot: boot.to_path_buf(),
             name, index, boot_count,
             title: String::new(),
             bzimage: None,
@@ -315,8 +393,8 @@ impl BootEntry {
         }
     }
 
-    fn create_for_kernel(name: &str, kernel: KernelBzImage, options: &str, boot_count: Option<String>) -> BootEntry {
-        let mut entry = BootEntry::new(name, None, boot_count);
+    fn create_for_kernel(boot: &Path, name: &str, kernel: KernelBzImage, options: &str, boot_count: Option<String>) -> BootEntry {
+        let mut entry = BootEntry::new(boot, name, None, boot_count);
         entry.options = options.to_string();
         entry.generate_title(&kernel);
         entry.bzimage = Some(kernel);
@@ -342,10 +420,15 @@ impl BootEntry {
     }
 
     fn generate_title(&mut self, kernel: &KernelBzImage) {
+        let flavor = if kernel.flavor == DEFAULT_KERNEL_FLAVOR {
+            String::new()
+        } else {
+            format!(" {}", kernel.flavor)
+        };
         if let Some(v) = kernel.version {
-            self.title = format!("Subgraph OS (Citadel {})", v);
+            self.title = format!("Subgraph OS (Citadel {}{})", v, flavor);
         } else {
-            self.title = format!("Subgraph OS (Citadel)");
+            self.title = format!("Subgraph OS (Citadel{})", flavor);
         }
     }
 
@@ -355,7 +438,7 @@ impl BootEntry {
             if line.starts_with("title ") {
                 self.title = line.trim_start_matches("title ").to_owned();
             } else if line.starts_with("linux /") {
-                let path = Path::new("/boot").join(line.trim_start_matches("linux /"));
+                let path = self.boot.join(line.trim_start_matches("linux /"));
                 if path.exists() {
                     let bzimage = KernelBzImage::from_path(&path)?;
                     self.bzimage = Some(bzimage);
@@ -390,7 +473,7 @@ impl BootEntry {
         } else {
             filename.push_str(".conf");
         }
-        BootEntries::base_path().join(filename)
+        BootEntries::base_path(&self.boot).join(filename)
     }
 
     // Increment index value and rename boot entry file. Return false
@@ -427,23 +510,28 @@ impl BootEntry {
 struct KernelBzImage {
     path: PathBuf,
     version: Option<KernelVersion>,
+    flavor: String,
     shasum: String,
 }
 
 impl KernelBzImage {
-    fn from_path_and_version(path: PathBuf, version: &str) -> Result<KernelBzImage> {
+    fn from_path_and_version(path: PathBuf, version: &str, flavor: &str) -> Result<KernelBzImage> {
         let shasum = util::sha256(&path)?;
         let version = KernelVersion::parse_from_str(version);
+        let flavor = flavor.to_string();
         Ok(KernelBzImage {
-            path, version, shasum
+            path, version, flavor, shasum
         })
     }
 
     fn from_path(path: &Path) -> Result<KernelBzImage> {
-        let version = KernelVersion::parse_from_path(&path);
+        let (flavor, version) = match KernelVersion::parse_flavor_from_path(path) {
+            Some((flavor, version)) => (flavor, Some(version)),
+            None => (DEFAULT_KERNEL_FLAVOR.to_string(), None),
+        };
         let shasum = util::sha256(path)?;
         let path = path.to_path_buf();
-        Ok(KernelBzImage { path, version, shasum })
+        Ok(KernelBzImage { path, version, flavor, shasum })
     }
 
     fn remove_file(&self) -> Result<()> {
@@ -455,7 +543,7 @@ impl KernelBzImage {
 #[test]
 fn test_version_parse() {
     let path = Path::new("/boot/bzImage-2.2-x");
-    let kv = KernelVersion::parse_from_path(path).unwrap();
+    let (_, kv) = KernelVersion::parse_flavor_from_path(path).unwrap();
     assert_eq!(kv.version, 2);
     assert_eq!(kv.major, 2);
     assert_eq!(kv.minor, None);
@@ -476,4 +564,20 @@ fn test_bootentry_parse_filename() {
     assert_eq!(fields, ("foo".to_string(), None, Some("abc".to_string())));
     let fields = BootEntry::parse_filename("foo.2.conf");
     assert_eq!(fields, ("foo".to_string(), Some(2), None));
+    let fields = BootEntry::parse_filename("boot-hardened.1+2.conf");
+    assert_eq!(fields, ("boot-hardened".to_string(), Some(1), Some("2".to_string())));
+}
+
+#[test]
+fn test_kernel_flavors() {
+    let (flavor, version) = KernelVersion::parse_flavor_from_path(Path::new("/boot/bzImage-hardened-5.1.3-1")).unwrap();
+    assert_eq!((flavor.as_str(), version.to_string()), ("hardened", "5.1.3-1".to_string()));
+    let (flavor, version) = KernelVersion::parse_flavor_from_path(Path::new("/boot/bzImage-5.1.3")).unwrap();
+    assert_eq!((flavor.as_str(), version.version()), ("standard", "5.1.3".to_string()));
+    assert!(KernelVersion::parse_flavor_from_path(Path::new("/boot/bzImage-hardened")).is_none());
+    assert_eq!(kernel_filename("hardened", &version), "bzImage-hardened-5.1.3");
+    assert_eq!((boot_entry_name("standard"), boot_entry_name("hardened")), ("boot".to_string(), "boot-hardened".to_string()));
+
+    assert_eq!(loader_conf_with_default("default boot\ntimeout 5\n", "boot-hardened.conf"), "default boot-hardened.conf\ntimeout 5\n");
+    assert_eq!(loader_conf_with_default("timeout 5", "boot.conf"), "timeout 5\ndefault boot.conf\n");
 }
\ No newline at end of file
@@ -1,56 +1,273 @@
 use std::path::{Path, PathBuf};
 use std::fs;
+use std::process::{self,exit};
 
-use libcitadel::{Result, Partition, ResourceImage, ImageHeader, LogLevel, Logger};
+use clap::{App,Arg,ArgMatches};
+use clap::AppSettings::*;
+use libcitadel::{Result, Partition, ResourceImage, MountedImage, ImageHeader, MetaInfo, LogLevel, Logger, Metrics, OsRelease, SystemRoot, DEFAULT_KERNEL_FLAVOR, is_valid_kernel_flavor};
+use crate::partition;
+use crate::preflight::{self, Requirement};
 use crate::update::kernel::{KernelInstaller, KernelVersion};
 use std::collections::HashSet;
 use std::fs::DirEntry;
+use std::sync::Arc;
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
 
 mod kernel;
+mod check;
+pub(crate) mod stage;
+#[cfg(test)]
+mod fake_root;
 
 const FLAG_SKIP_SHA: u32 = 0x01;
 const FLAG_NO_PREFER: u32 = 0x02;
 const FLAG_QUIET: u32 = 0x04;
+const FLAG_VERIFY_WRITE: u32 = 0x08;
+const FLAG_KEEP_COMPRESSED: u32 = 0x10;
+const FLAG_SWITCH_CHANNEL: u32 = 0x20;
+
+const UPDATE_CONFIG: &str = "/etc/citadel/update.conf";
+
+const REQUIREMENTS: &[Requirement] = &[
+    Requirement::Root,
+    Requirement::Mounted("/storage"),
+    Requirement::Command("/usr/bin/xz"),
+    Requirement::Command("/sbin/veritysetup"),
+];
+
+#[derive(Deserialize,Default)]
+struct UpdateConfig {
+    #[serde(rename = "keep-compressed", default)]
+    keep_compressed: bool,
+    // Flavor of the kernel booted by default when kernels of several flavors are installed
+    #[serde(rename = "default-kernel-flavor")]
+    default_kernel_flavor: Option<String>,
+    // Url of the channel index downloaded by --check when no url is given
+    #[serde(rename = "check-url")]
+    check_url: Option<String>,
+}
+
+impl UpdateConfig {
+    fn load(root: &SystemRoot) -> Self {
+        let path = root.path(UPDATE_CONFIG);
+        if !path.exists() {
+            return Self::default();
+        }
+        match Self::load_from(&path) {
+            Ok(config) => config,
+            Err(e) => {
+                warn!("Failed to load {}: {}", path.display(), e);
+                Self::default()
+            }
+        }
+    }
+
+    fn load_from(path: &Path) -> Result<Self> {
+        let s = fs::read_to_string(path)?;
+        Ok(toml::from_str(&s)?)
+    }
+}
+
+pub fn app() -> App<'static, 'static> {
+    App::new("citadel-update")
+        .about("Install resource images, kernel images and rootfs updates")
+        .settings(&[ArgRequiredElseHelp,ColoredHelp, DisableHelpSubcommand, DisableVersion, DeriveDisplayOrder])
+        .after_help("Options which change how an image is installed only apply to the image files listed after them.")
+        .arg(Arg::with_name("skip-sha")
+            .long("skip-sha")
+            .multiple(true)
+            .help("Do not verify the shasum of the image data"))
+        .arg(Arg::with_name("no-prefer")
+            .long("no-prefer")
+            .multiple(true)
+            .help("Do not set the PREFER_BOOT flag on an installed rootfs image"))
+        .arg(Arg::with_name("verify-write")
+            .long("verify-write")
+            .multiple(true)
+            .help("Verify the shasum of a rootfs image after writing it to the partition"))
+        .arg(Arg::with_name("keep-compressed")
+            .long("keep-compressed")
+            .multiple(true)
+            .help("Install resource images without decompressing them"))
+        .arg(Arg::with_name("switch-channel")
+            .long("switch-channel")
+            .multiple(true)
+            .help("Install images from a different channel than the running system"))
+        .arg(Arg::with_name("quiet")
+            .long("quiet")
+            .conflicts_with("verbose")
+            .help("Only display warnings and errors"))
+        .arg(Arg::with_name("verbose")
+            .long("verbose")
+            .help("Display debug messages"))
+        .arg(crate::log_arg())
+        .arg(Arg::with_name("choose-rootfs")
+            .long("choose-rootfs")
+            .conflicts_with_all(&["images", "show-metainfo"])
+            .help("Display the rootfs partition an update would be installed to"))
+        .arg(Arg::with_name("check")
+            .long("check")
+            .takes_value(true)
+            .value_name("URL")
+            .min_values(0)
+            .max_values(1)
+            .conflicts_with_all(&["images", "choose-rootfs", "show-metainfo"])
+            .help("Only check the channel index at URL (default: check-url in update.conf) for newer images. Exits with 10 if an update is available"))
+        .arg(Arg::with_name("notify")
+            .long("notify")
+            .requires("check")
+            .help("Send an UpdateAvailable D-Bus signal when --check finds an update"))
+        .arg(Arg::with_name("stage")
+            .long("stage")
+            .takes_value(true)
+            .value_name("PATH")
+            .conflicts_with_all(&["images", "choose-rootfs", "show-metainfo", "check"])
+            .help("Verify and prepare a rootfs image to be installed at the next shutdown"))
+        .arg(Arg::with_name("apply-staged")
+            .long("apply-staged")
+            .conflicts_with_all(&["images", "choose-rootfs", "show-metainfo", "check", "stage"])
+            .help("Install the staged rootfs image, run by a unit at shutdown"))
+        .arg(Arg::with_name("time-budget")
+            .long("time-budget")
+            .takes_value(true)
+            .value_name("SECS")
+            .requires("apply-staged")
+            .validator(|s| s.parse::<u64>().map(|_| ()).map_err(|_| format!("'{}' is not a number of seconds", s)))
+            .help("Abort --apply-staged and keep the stage if it takes longer than this (default: 240)"))
+        .arg(Arg::with_name("show-metainfo")
+            .long("show-metainfo")
+            .takes_value(true)
+            .value_name("PATH")
+            .conflicts_with("images")
+            .help("Display the header and metainfo of an image file"))
+        .arg(Arg::with_name("images")
+            .multiple(true)
+            .required_unless_one(&["choose-rootfs", "show-metainfo", "check", "stage", "apply-staged"])
+            .help("Image files to install"))
+}
+
+fn flags_from(matches: &ArgMatches, keep_compressed: bool) -> u32 {
+    flags_before(matches, keep_compressed, usize::MAX)
+}
+
+// As with the original hand written parser, an install option only applies to
+// the images which follow it, so `citadel-update a.img --skip-sha b.img` verifies
+// the shasum of a.img but not of b.img.
+fn image_flags<'a>(matches: &'a ArgMatches, keep_compressed: bool) -> Vec<(&'a str, u32)> {
+    let paths = matches.values_of("images").into_iter().flatten();
+    let indices = matches.indices_of("images").into_iter().flatten();
+    paths.zip(indices)
+        .map(|(path, index)| (path, flags_before(matches, keep_compressed, index)))
+        .collect()
+}
 
-pub fn main(args: Vec<String>) {
-    let mut args = args.iter().skip(1);
+// The flags set by options which appear on the command line before `index`
+fn flags_before(matches: &ArgMatches, keep_compressed: bool, index: usize) -> u32 {
     let mut flags = 0;
+    for &(name, flag) in &[("skip-sha", FLAG_SKIP_SHA), ("no-prefer", FLAG_NO_PREFER),
+                           ("quiet", FLAG_QUIET), ("verify-write", FLAG_VERIFY_WRITE),
+                           ("keep-compressed", FLAG_KEEP_COMPRESSED), ("switch-channel", FLAG_SWITCH_CHANNEL)] {
+        if matches.indices_of(name).into_iter().flatten().any(|i| i < index) {
+            flags |= flag;
+        }
+    }
+    if keep_compressed {
+        flags |= FLAG_KEEP_COMPRESSED;
+    }
+    flags
+}
+
+pub fn main(matches: &ArgMatches) {
+    if matches.is_present("quiet") {
+        Logger::set_log_level(LogLevel::Warn);
+    } else if matches.is_present("verbose") {
+        Logger::set_log_level(LogLevel::Debug);
+    } else {
+        Logger::set_log_level(LogLevel::Info);
+    }
+    crate::apply_log_arg(matches);
 
-    Logger::set_log_level(LogLevel::Info);
-
-    while let Some(arg) = args.next() {
-        if arg == "--skip-sha" {
-            flags |= FLAG_SKIP_SHA;
-        } else if arg == "--no-prefer" {
-            flags |= FLAG_NO_PREFER;
-        } else if arg == "--quiet" {
-            flags |= FLAG_QUIET;
-            Logger::set_log_level(LogLevel::Warn);
-        } else if arg == "--verbose" {
-            Logger::set_log_level(LogLevel::Debug);
-        } else if arg == "--choose-rootfs" {
-            let _ = choose_install_partition(true);
-            return;
-        } else {
-            let path = Path::new(arg);
-            if let Err(e) = install_image(path, flags) {
-                warn!("Update failed: {}", e);
+    if let Some(path) = matches.value_of("show-metainfo") {
+        if let Err(e) = show_metainfo(Path::new(path)) {
+            warn!("Failed to read metainfo: {}", e);
+        }
+        return;
+    }
+    if matches.is_present("check") {
+        exit(check::main(matches.value_of("check"), matches.is_present("notify")));
+    }
+    if let Err(e) = preflight::check("update", REQUIREMENTS) {
+        warn!("{}", e);
+        exit(1);
+    }
+    if matches.is_present("choose-rootfs") {
+        let _ = choose_install_partition(true);
+        return;
+    }
+
+    let root = SystemRoot::default();
+    let keep_compressed = UpdateConfig::load(&root).keep_compressed;
+    if let Some(path) = matches.value_of("stage") {
+        if let Err(e) = stage::stage_image(&root, Path::new(path), flags_from(matches, keep_compressed)) {
+            warn!("Failed to stage update: {}", e);
+            exit(1);
+        }
+        return;
+    }
+    if matches.is_present("apply-staged") {
+        let budget = matches.value_of("time-budget")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(stage::DEFAULT_APPLY_BUDGET);
+        match stage::apply_staged(&root, Duration::from_secs(budget)) {
+            Ok(true) => Metrics::update_installed(&root, "rootfs", true),
+            Ok(false) => {},
+            Err(e) => {
+                Metrics::update_installed(&root, "rootfs", false);
+                warn!("Failed to apply staged update: {}", e);
+                exit(1);
             }
         }
+        return;
     }
+    for (path, flags) in image_flags(matches, keep_compressed) {
+        let image_type = ResourceImage::from_path(path)
+            .map(|image| image.metainfo().image_type().to_string())
+            .unwrap_or_else(|_| "unknown".to_string());
+        let result = install_image(&root, Path::new(path), flags);
+        Metrics::update_installed(&root, &image_type, result.is_ok());
+        if let Err(e) = result {
+            warn!("Update failed: {}", e);
+        }
+    }
+}
+
+// Print the header fields and the complete metainfo document of an image file,
+// including any fields in the [extra] table.
+fn show_metainfo(path: &Path) -> Result<()> {
+    let header = ImageHeader::from_file(path)?;
+    let version = match header.magic_version() {
+        Some(version) => version,
+        None => bail!("{} does not have a valid image header", path.display()),
+    };
+    println!("# header-version = {}", version);
+    println!("# status = \"{}\"", header.status_code_label());
+    println!("# flags = 0x{:02x}", header.flags());
+    println!("# signed = {}", header.has_signature());
+    print!("{}", String::from_utf8_lossy(&header.metainfo_bytes()));
+    Ok(())
 }
 
 // Search directory containing installed image files for an
 // image file that has an identical shasum and abort the installation
 // if a duplicate is found.
-fn detect_duplicates(image: &ResourceImage) -> Result<()> {
+fn detect_duplicates(root: &SystemRoot, image: &ResourceImage) -> Result<()> {
     let metainfo = image.metainfo();
     let channel = metainfo.channel();
     let shasum = metainfo.shasum();
 
     validate_channel_name(&channel)?;
 
-    let resource_dir = Path::new("/storage/resources/")
+    let resource_dir = root.path("/storage/resources/")
         .join(channel);
 
     if !resource_dir.exists() {
@@ -69,26 +286,137 @@ fn detect_duplicates(image: &ResourceImage) -> Result<()> {
     Ok(())
 }
 
-fn install_image(path: &Path, flags: u32) -> Result<()> {
+// The metainfo of the rootfs partition the running system was booted from, or
+// None if no rootfs partition is mounted such as when running in live mode.
+fn active_metainfo() -> Result<Option<Arc<MetaInfo>>> {
+    let partitions = Partition::rootfs_partitions()?;
+    Ok(partitions.iter()
+        .find(|p| p.is_mounted() && p.is_initialized())
+        .map(|p| p.metainfo()))
+}
+
+// The channel of the mounted rootfs partition, or the channel in /etc/os-release
+// when no rootfs partition is mounted.
+pub(crate) fn running_channel() -> Result<String> {
+    if let Some(active) = active_metainfo()? {
+        return Ok(active.channel().to_string());
+    }
+    match OsRelease::citadel_channel() {
+        Some(channel) => Ok(channel.to_string()),
+        None => bail!("Cannot determine the channel of the running system"),
+    }
+}
+
+// Refuse to install an image from a different channel than the running system
+// unless the --switch-channel flag was passed.
+fn check_channel(image: &ResourceImage, flags: u32) -> Result<()> {
+    let active = match active_metainfo() {
+        Ok(active) => active,
+        Err(e) => {
+            warn!("Not checking image channel because the channel of the running system could not be determined: {}", e);
+            None
+        }
+    };
+    check_channel_change(active.as_deref(), &image.metainfo(), flags & FLAG_SWITCH_CHANNEL != 0)
+}
+
+fn check_channel_change(active: Option<&MetaInfo>, image: &MetaInfo, switch_channel: bool) -> Result<()> {
+    let active = match active {
+        Some(active) if active.channel() != image.channel() => active.channel(),
+        _ => return Ok(()),
+    };
+    if !switch_channel {
+        bail!("Refusing to install {} image from channel '{}' because the running system is from channel '{}'. Use --switch-channel to install it anyway",
+              image.image_type(), image.channel(), active);
+    }
+    warn!("**********************************************************************");
+    warn!("Switching channel: installing {} image from channel '{}' on a system running channel '{}'",
+          image.image_type(), image.channel(), active);
+    warn!("**********************************************************************");
+    Ok(())
+}
+
+fn install_image(root: &SystemRoot, path: &Path, flags: u32) -> Result<()> {
     if !path.exists() {
         bail!("file path {} does not exist", path.display());
     }
 
     let mut image = ResourceImage::from_path(path)?;
-    detect_duplicates(&image)?;
+    detect_duplicates(root, &image)?;
+    check_channel(&image, flags)?;
+
+    if flags & FLAG_KEEP_COMPRESSED != 0 && image.is_compressed() {
+        return install_keeping_compressed(root, &image, flags);
+    }
+
     prepare_image(&image, flags)?;
+    install_prepared_image(root, &mut image, flags)?;
+    Ok(())
+}
 
+// Install the image and return the path of the installed image file, or
+// None if the image was written to a partition.
+fn install_prepared_image(root: &SystemRoot, image: &mut ResourceImage, flags: u32) -> Result<Option<PathBuf>> {
     match image.metainfo().image_type() {
-        "kernel" => install_kernel_image(&mut image),
-        "extra" => install_extra_image(&image),
-        "rootfs" => install_rootfs_image(&image, flags),
+        "kernel" => install_kernel_image(root, image).map(Some),
+        "extra" => install_extra_image(root, image).map(Some),
+        "rootfs" => install_rootfs_image(image, flags).map(|_| None),
         image_type => bail!("Unknown image type: {}", image_type),
     }
 }
 
+// Verify and install an uncompressed copy of the image, then replace the
+// installed copy with the original compressed image file flagged so that it
+// is uncompressed to a temporary copy when mounted.
+fn install_keeping_compressed(root: &SystemRoot, image: &ResourceImage, flags: u32) -> Result<()> {
+    let work_path = image.path().with_extension("uncompressed.img");
+    let result = image.decompress_copy(&work_path)
+        .and_then(|mut work| {
+            prepare_image(&work, flags)?;
+            install_prepared_image(root, &mut work, flags)
+        });
+
+    let installed = match result {
+        Ok(installed) => installed,
+        Err(e) => {
+            remove_if_exists(&work_path);
+            return Err(e);
+        }
+    };
+
+    match installed {
+        Some(dest) => {
+            image.header().set_flag(ImageHeader::FLAG_KEEP_COMPRESSED);
+            image.header().write_header_to(image.path())?;
+            info!("replacing installed image {} with compressed image file {}", dest.display(), image.path().display());
+            fs::rename(image.path(), &dest)?;
+        },
+        None => remove_if_exists(&work_path),
+    }
+    Ok(())
+}
+
+fn remove_if_exists(path: &Path) {
+    if path.exists() {
+        if let Err(e) = fs::remove_file(path) {
+            warn!("Failed to remove temporary file {}: {}", path.display(), e);
+        }
+    }
+}
+
 // Prepare the image file for installation by decompressing and generating
 // dmverity hash tree.
 fn prepare_image(image: &ResourceImage, flags: u32) -> Result<()> {
+    verify_image_data(image, flags)?;
+
+    if !image.has_verity_hashtree() {
+        image.generate_verity_hashtree()?;
+    }
+    Ok(())
+}
+
+// Decompress the image data and check that it matches the shasum in the metainfo
+fn verify_image_data(image: &ResourceImage, flags: u32) -> Result<()> {
     if image.is_compressed() {
         image.decompress()?;
     }
@@ -100,24 +428,20 @@ fn prepare_image(image: &ResourceImage, flags: u32) -> Result<()> {
             bail!("image file does not have expected sha256 value");
         }
     }
-
-    if !image.has_verity_hashtree() {
-        image.generate_verity_hashtree()?;
-    }
     Ok(())
 }
 
-fn install_extra_image(image: &ResourceImage) -> Result<()> {
+fn install_extra_image(root: &SystemRoot, image: &ResourceImage) -> Result<PathBuf> {
     let filename = format!("citadel-extra-{:03}.img", image.header().metainfo().version());
-    install_image_file(image, filename.as_str())?;
-    remove_old_extra_images(image)?;
-    Ok(())
+    let dest = install_image_file(root, image, filename.as_str())?;
+    remove_old_extra_images(root, image)?;
+    Ok(dest)
 }
 
-fn remove_old_extra_images(image: &ResourceImage) -> Result<()> {
+fn remove_old_extra_images(root: &SystemRoot, image: &ResourceImage) -> Result<()> {
     let new_meta = image.header().metainfo();
     let shasum = new_meta.shasum();
-    let target_dir = target_directory(image)?;
+    let target_dir = target_directory(root, image)?;
     for dirent in fs::read_dir(target_dir)? {
         let dirent = dirent?;
         let path = dirent.path();
@@ -128,7 +452,7 @@ fn remove_old_extra_images(image: &ResourceImage) -> Result<()> {
 
 fn maybe_remove_old_extra_image(path: &Path, shasum: &str) -> Result<()> {
     let header = ImageHeader::from_file(&path)?;
-    if !header.is_magic_valid() {
+    if header.magic_version().is_none() {
         return Ok(());
     }
     let meta = header.metainfo();
@@ -144,8 +468,8 @@ fn maybe_remove_old_extra_image(path: &Path, shasum: &str) -> Result<()> {
 
 
 
-fn install_kernel_image(image: &mut ResourceImage) -> Result<()> {
-    if !Path::new("/boot/loader/loader.conf").exists() {
+fn install_kernel_image(root: &SystemRoot, image: &mut ResourceImage) -> Result<PathBuf> {
+    if !root.path("/boot/loader/loader.conf").exists() {
         bail!("failed to automount /boot partition. Please manually mount correct partition.");
     }
 
@@ -155,19 +479,23 @@ fn install_kernel_image(image: &mut ResourceImage) -> Result<()> {
         Some(kv) => kv,
         None => bail!("Kernel image does not have kernel version field"),
     };
-    info!("kernel version is {}", kernel_version);
-    install_kernel_file(image, &kernel_version)?;
+    let flavor = metainfo.kernel_flavor().to_string();
+    if !is_valid_kernel_flavor(&flavor) {
+        bail!("Kernel image has invalid kernel flavor '{}'", flavor);
+    }
+    info!("kernel version is {} ({} flavor)", kernel_version, flavor);
+    install_kernel_file(root, image, kernel_version, &flavor)?;
 
-    let filename = format!("citadel-kernel-{}-{:03}.img", kernel_version, version);
-    install_image_file(image, &filename)?;
+    let filename = kernel_image_filename(kernel_version, &flavor, version);
+    let dest = install_image_file(root, image, &filename)?;
 
-    let all_versions = all_boot_kernel_versions()?;
-    let image_dir = target_directory(image)?;
+    let all_versions = all_boot_kernel_versions(root)?;
+    let image_dir = target_directory(root, image)?;
     let mut remove_paths = Vec::new();
     for dirent in fs::read_dir(image_dir)? {
         let dirent = dirent?;
         let path = dirent.path();
-        if is_unused_kernel_image(&path, &all_versions)? {
+        if is_unused_kernel_image(&path, &flavor, &all_versions)? {
             remove_paths.push(path);
         }
     }
@@ -175,21 +503,39 @@ fn install_kernel_image(image: &mut ResourceImage) -> Result<()> {
     for p in remove_paths {
         fs::remove_file(p)?;
     }
-    Ok(())
+
+    if let Some(default_flavor) = UpdateConfig::load(root).default_kernel_flavor {
+        if let Err(e) = KernelInstaller::set_default_flavor(root, &default_flavor) {
+            warn!("Failed to make kernel flavor {} the default boot entry: {}", default_flavor, e);
+        }
+    }
+    Ok(dest)
 }
 
-fn is_unused_kernel_image(path: &Path, versions: &HashSet<String>) -> Result<bool> {
+/// Filename of an installed kernel image. The kernel flavor is included
+/// unless it is the default flavor.
+pub(crate) fn kernel_image_filename(kernel_version: &str, flavor: &str, version: u32) -> String {
+    if flavor == DEFAULT_KERNEL_FLAVOR {
+        format!("citadel-kernel-{}-{:03}.img", kernel_version, version)
+    } else {
+        format!("citadel-kernel-{}-{}-{:03}.img", flavor, kernel_version, version)
+    }
+}
+
+// Only kernel images of `flavor` are considered, so that installing a kernel
+// never removes the images of the kernels of another flavor.
+fn is_unused_kernel_image(path: &Path, flavor: &str, versions: &HashSet<(String, String)>) -> Result<bool> {
     let header = ImageHeader::from_file(path)?;
-    if !header.is_magic_valid() {
+    if header.magic_version().is_none() {
         return Ok(false);
     }
     let meta = header.metainfo();
-    if meta.image_type() != "kernel" {
+    if meta.image_type() != "kernel" || meta.kernel_flavor() != flavor {
         return Ok(false);
     }
     if let Some(version) = meta.kernel_version() {
-        if !versions.contains(version) {
-            info!("Removing kernel image {} because kernel version {} is unused", path.display(), version);
+        if !versions.contains(&(flavor.to_string(), version.to_string())) {
+            info!("Removing kernel image {} because {} kernel version {} is unused", path.display(), flavor, version);
             return Ok(true);
         }
     } else {
@@ -198,29 +544,76 @@ fn is_unused_kernel_image(path: &Path, versions: &HashSet<String>) -> Result<boo
     Ok(false)
 }
 
-fn install_kernel_file(image: &mut ResourceImage, kernel_version: &str) -> Result<()> {
-    let mountpoint = Path::new("/run/citadel/images/kernel-install.mountpoint");
-    info!("Temporarily mounting kernel resource image");
-    let mut handle = image.mount_at(mountpoint)?;
-    let kernel_path = mountpoint.join("kernel/bzImage");
+// Unmounts the temporary kernel image mount when dropped so that it is
+// removed on every return path from install_kernel_file()
+fn install_kernel_file(root: &SystemRoot, image: &mut ResourceImage, kernel_version: &str, flavor: &str) -> Result<()> {
+    unmount_stale_install_mounts();
+    let mountpoint = root.path(kernel_install_mountpoint(process::id(), unix_time()));
+    info!("Temporarily mounting kernel resource image at {}", mountpoint.display());
+    let mount = image.mount_at(&mountpoint)?.into_guard();
+    let kernel_path = mount.mountpoint().join("kernel/bzImage");
     if !kernel_path.exists() {
-        handle.unmount()?;
         bail!("kernel not found in kernel resource image at /kernel/bzImage")
     }
+    KernelInstaller::install_kernel(root, &kernel_path, kernel_version, flavor)
+}
+
+// Each install mounts the kernel image at a unique path so that concurrent or
+// interrupted installs cannot collide on the same mountpoint.
+fn kernel_install_mountpoint(pid: u32, timestamp: u64) -> String {
+    format!("/run/citadel/images/kernel-install-{}-{}.mountpoint", pid, timestamp)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Unmount kernel images left mounted by an earlier install which was interrupted
+fn unmount_stale_install_mounts() {
+    let is_running = |pid: u32| Path::new("/proc").join(pid.to_string()).exists();
+    for mounted in stale_install_mounts(ResourceImage::mounted_images(), is_running) {
+        warn!("Unmounting stale kernel install mount {}", mounted.mountpoint().display());
+        if let Err(e) = mounted.unmount() {
+            warn!("Failed to unmount {}: {}", mounted.mountpoint().display(), e);
+            continue;
+        }
+        let _ = fs::remove_dir(mounted.mountpoint());
+    }
+}
 
-    let result = KernelInstaller::install_kernel(&kernel_path, kernel_version);
-    info!("Unmounting kernel resource image");
-    handle.unmount()?;
-    result
+// Kernel install mountpoints in `mounts` which use the old fixed name or which
+// were created by a process which is no longer running.
+fn stale_install_mounts<F>(mounts: Vec<MountedImage>, is_running: F) -> Vec<MountedImage>
+    where F: Fn(u32) -> bool
+{
+    mounts.into_iter()
+        .filter(|m| {
+            let name = match m.mountpoint().file_name() {
+                Some(name) => name.to_string_lossy(),
+                None => return false,
+            };
+            if name == "kernel-install.mountpoint" {
+                return true;
+            }
+            name.strip_prefix("kernel-install-")
+                .and_then(|s| s.strip_suffix(".mountpoint"))
+                .and_then(|s| s.split('-').next())
+                .and_then(|pid| pid.parse::<u32>().ok())
+                .map_or(false, |pid| !is_running(pid))
+        })
+        .collect()
 }
 
-fn all_boot_kernel_versions() -> Result<HashSet<String>> {
+// The (flavor, version) of each kernel in /boot
+fn all_boot_kernel_versions(root: &SystemRoot) -> Result<HashSet<(String, String)>> {
     let mut result = HashSet::new();
-    for dirent in fs::read_dir("/boot")? {
+    for dirent in fs::read_dir(root.boot())? {
         let dirent = dirent?;
         if is_kernel_dirent(&dirent) {
-            if let Some(kv) = KernelVersion::parse_from_path(&dirent.path()) {
-                result.insert(kv.version());
+            if let Some((flavor, kv)) = KernelVersion::parse_flavor_from_path(&dirent.path()) {
+                result.insert((flavor, kv.version()));
             }
         }
     }
@@ -235,22 +628,22 @@ fn is_kernel_dirent(dirent: &DirEntry) -> bool {
     }
 }
 
-fn install_image_file(image: &ResourceImage, filename: &str) -> Result<()> {
-    let image_dir = target_directory(image)?;
+fn install_image_file(root: &SystemRoot, image: &ResourceImage, filename: &str) -> Result<PathBuf> {
+    let image_dir = target_directory(root, image)?;
     let image_dest = image_dir.join(filename);
     if image_dest.exists() {
         rotate(&image_dest)?;
     }
     info!("installing image file by moving from {} to {}", image.path().display(), image_dest.display());
-    fs::rename(image.path(), image_dest)?;
-    Ok(())
+    fs::rename(image.path(), &image_dest)?;
+    Ok(image_dest)
 }
 
-fn target_directory(image: &ResourceImage) -> Result<PathBuf> {
+fn target_directory(root: &SystemRoot, image: &ResourceImage) -> Result<PathBuf> {
     let metainfo = image.header().metainfo();
     let channel = metainfo.channel();
     validate_channel_name(channel)?;
-    Ok(Path::new("/storage/resources").join(channel))
+    Ok(root.path("/storage/resources").join(channel))
 }
 
 fn rotate(path: &Path) -> Result<()> {
@@ -282,7 +675,7 @@ fn install_rootfs_image(image: &ResourceImage, flags: u32) -> Result<()> {
         image.header().set_flag(ImageHeader::FLAG_PREFER_BOOT);
     }
 
-    image.write_to_partition(&partition)?;
+    image.write_to_partition(&partition, flags & FLAG_VERIFY_WRITE != 0)?;
     info!("Image written to {:?}", partition.path());
     Ok(())
 }
@@ -296,24 +689,11 @@ fn clear_prefer_boot() -> Result<()> {
     Ok(())
 }
 
-fn bool_to_yesno(val: bool) -> &'static str {
-    if val {
-        "YES"
-    } else {
-        " NO"
-    }
-}
-
 fn choose_install_partition(verbose: bool) -> Result<Partition> {
     let partitions = Partition::rootfs_partitions()?;
 
     if verbose {
-        for p in &partitions {
-            info!("Partition: {}  (Mounted: {}) (Empty: {})",
-                  p.path().display(),
-                  bool_to_yesno(p.is_mounted()),
-                  bool_to_yesno(!p.is_initialized()));
-        }
+        partition::print_install_candidates(&partitions);
     }
 
     for p in &partitions {
@@ -328,11 +708,132 @@ fn choose_install_partition(verbose: bool) -> Result<Partition> {
         if !p.is_mounted() {
             if verbose {
                 info!("Choosing {} because it is not mounted", p.path().display());
-                info!("Header metainfo:");
-                print!("{}",String::from_utf8(p.header().metainfo_bytes())?);
+                partition::print_metainfo(p)?;
             }
             return Ok(p.clone())
         }
     }
     Err(format_err!("No suitable install partition found"))
 }
+
+#[test]
+fn test_verify_built_image() {
+    use std::io::{Seek,SeekFrom,Write};
+    use libcitadel::{ImageInfo,ResourceImageBuilder};
+
+    let dir = libcitadel::util::TempDir::new("update-test").unwrap();
+    let squashfs = dir.join("extra.squashfs");
+    fs::write(&squashfs, (0..4096 * 3).map(|i| (i % 253) as u8).collect::<Vec<_>>()).unwrap();
+    let info = ImageInfo::new("extra", "dev", 12, "20190621120000");
+    let target = dir.join(info.image_filename());
+
+    for &compress in &[false, true] {
+        ResourceImageBuilder::from_squashfs(&squashfs, info.clone())
+            .verity(false)
+            .compress(compress)
+            .build(&target)
+            .unwrap();
+        let image = ResourceImage::from_path(&target).unwrap();
+        assert_eq!(image.is_compressed(), compress);
+        if compress {
+            let copy = image.decompress_copy(&dir.join("copy.img")).unwrap();
+            assert!(!copy.is_compressed());
+            verify_image_data(&copy, 0).unwrap();
+            assert!(ImageHeader::from_file(&target).unwrap().has_flag(ImageHeader::FLAG_DATA_COMPRESSED));
+        }
+        verify_image_data(&image, 0).unwrap();
+        assert!(!ImageHeader::from_file(&target).unwrap().has_flag(ImageHeader::FLAG_DATA_COMPRESSED));
+    }
+
+    let header_size = ImageHeader::from_file(&target).unwrap().size() as u64;
+    let mut f = fs::OpenOptions::new().write(true).open(&target).unwrap();
+    f.seek(SeekFrom::Start(header_size + 100)).unwrap();
+    f.write_all(b"corrupt").unwrap();
+    let image = ResourceImage::from_path(&target).unwrap();
+    assert!(verify_image_data(&image, 0).is_err());
+    assert!(verify_image_data(&image, FLAG_SKIP_SHA).is_ok());
+}
+
+#[test]
+fn test_update_args() {
+    let flags = |args: &[&str]| {
+        let args = ["citadel-update"].iter().chain(args).cloned().collect::<Vec<_>>();
+        app().get_matches_from_safe(args).map(|m| flags_from(&m, false))
+    };
+    let images = |args: &[&str]| {
+        let args = ["citadel-update"].iter().chain(args).cloned().collect::<Vec<_>>();
+        let matches = app().get_matches_from_safe(args).unwrap();
+        image_flags(&matches, false).into_iter().map(|(path, flags)| (path.to_string(), flags)).collect::<Vec<_>>()
+    };
+    assert_eq!(flags(&["--skip-sha", "--no-prefer", "a.img"]).unwrap(), FLAG_SKIP_SHA | FLAG_NO_PREFER);
+    assert_eq!(images(&["--skip-sha", "--no-prefer", "a.img"]), vec![("a.img".to_string(), FLAG_SKIP_SHA | FLAG_NO_PREFER)]);
+    // options only apply to the images which follow them
+    assert_eq!(images(&["a.img", "--verify-write", "b.img", "--skip-sha", "c.img"]), vec![
+        ("a.img".to_string(), 0),
+        ("b.img".to_string(), FLAG_VERIFY_WRITE),
+        ("c.img".to_string(), FLAG_VERIFY_WRITE | FLAG_SKIP_SHA),
+    ]);
+    assert_eq!(images(&["--skip-sha", "a.img", "--skip-sha", "b.img"]), vec![
+        ("a.img".to_string(), FLAG_SKIP_SHA),
+        ("b.img".to_string(), FLAG_SKIP_SHA),
+    ]);
+    assert_eq!(images(&["a.img", "--skip-sha"]), vec![("a.img".to_string(), 0)]);
+    assert_eq!(flags(&["--quiet", "--keep-compressed", "a.img"]).unwrap(), FLAG_QUIET | FLAG_KEEP_COMPRESSED);
+    assert!(flags(&["--log", "debug", "--choose-rootfs"]).is_ok());
+    assert!(flags(&["--show-metainfo", "a.img"]).is_ok());
+    assert!(flags(&["--quiet", "--verbose", "a.img"]).is_err());
+    assert!(flags(&["--bogus", "a.img"]).is_err());
+    assert!(flags(&["--log", "nonsense", "a.img"]).is_err());
+    assert!(flags(&["--skip-sha"]).is_err());
+    assert_eq!(flags(&["--switch-channel", "a.img"]).unwrap(), FLAG_SWITCH_CHANNEL);
+    assert!(flags(&["--check"]).is_ok());
+    assert!(flags(&["--check", "https://example.com/prod.json", "--notify"]).is_ok());
+    assert!(flags(&["a.img", "--check"]).is_err());
+    assert!(flags(&["--check", "https://example.com/prod.json", "a.img"]).is_err());
+    assert!(flags(&["--notify", "a.img"]).is_err());
+    assert_eq!(flags(&["--stage", "rootfs.img", "--skip-sha"]).unwrap(), FLAG_SKIP_SHA);
+    assert!(flags(&["--stage", "rootfs.img", "a.img"]).is_err());
+    assert!(flags(&["--apply-staged", "--time-budget", "120"]).is_ok());
+    assert!(flags(&["--apply-staged", "--time-budget", "soon"]).is_err());
+    assert!(flags(&["--time-budget", "120", "a.img"]).is_err());
+}
+
+#[test]
+fn test_check_channel_change() {
+    let metainfo = |image_type: &str, channel: &str| {
+        let header = ImageHeader::new();
+        header.set_metainfo_bytes(format!("image-type = \"{}\"\nchannel = \"{}\"\nversion = 1\n", image_type, channel).as_bytes()).unwrap();
+        header.metainfo()
+    };
+    let active = metainfo("rootfs", "prod");
+    for image_type in &["rootfs", "kernel", "extra"] {
+        assert!(check_channel_change(Some(&active), &metainfo(image_type, "prod"), false).is_ok());
+        let err = check_channel_change(Some(&active), &metainfo(image_type, "dev"), false).unwrap_err().to_string();
+        assert!(err.contains("from channel 'dev'") && err.contains("from channel 'prod'"), "{}", err);
+        assert!(err.contains(image_type), "{}", err);
+        assert!(check_channel_change(Some(&active), &metainfo(image_type, "dev"), true).is_ok());
+    }
+    // Nothing to compare against when no rootfs partition is mounted
+    assert!(check_channel_change(None, &metainfo("rootfs", "dev"), false).is_ok());
+}
+
+#[test]
+fn test_stale_install_mounts() {
+    // Mounts left by the old fixed mountpoint, by a process which exited and by
+    // a running install, followed by an unrelated image.
+    let mountinfo = "\
+98 25 7:1 / /run/citadel/images/kernel-install.mountpoint ro shared:50 - squashfs /dev/loop1 ro
+99 25 7:2 / /run/citadel/images/kernel-install-412-1561118400.mountpoint ro shared:51 - squashfs /dev/loop2 ro
+100 25 7:3 / /run/citadel/images/kernel-install-977-1561118460.mountpoint ro shared:52 - squashfs /dev/loop3 ro
+101 25 253:2 / /run/citadel/images/extra.mountpoint ro shared:53 - squashfs /dev/mapper/verity-extra-1234abcd ro
+";
+    let mounts = ResourceImage::mounted_images_from(mountinfo);
+    assert_eq!(mounts.len(), 4);
+    let stale = stale_install_mounts(mounts, |pid| pid == 977);
+    let stale = stale.iter().map(|m| m.mountpoint().to_path_buf()).collect::<Vec<_>>();
+    assert_eq!(stale, vec![
+        PathBuf::from("/run/citadel/images/kernel-install.mountpoint"),
+        PathBuf::from("/run/citadel/images/kernel-install-412-1561118400.mountpoint"),
+    ]);
+    assert_eq!(kernel_install_mountpoint(412, 1561118400), "/run/citadel/images/kernel-install-412-1561118400.mountpoint");
+}
@@ -0,0 +1,306 @@
+//! Staged rootfs updates which are written to a partition at shutdown.
+//!
+//! `citadel-tool update --stage IMAGE` verifies the image data and prepares the
+//! dm-verity hash tree, then moves the image file into `/storage/resources/.staged/`
+//! and records it in `stage.json` in the same directory. At shutdown
+//! `citadel-tool update --apply-staged` writes the staged image to a rootfs
+//! partition and makes it the preferred boot partition.
+//!
+//! A staged update is discarded instead of applied if the running system is
+//! no longer on the channel it was staged from or if the staged image file
+//! has changed since it was staged.
+//!
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libcitadel::{Result, ImageHeader, ResourceImage, SystemRoot, util};
+
+const STAGE_DIR: &str = "/storage/resources/.staged";
+const STAGE_FILE: &str = "stage.json";
+
+/// Seconds allowed for --apply-staged before it gives up and keeps the stage for the next shutdown
+pub const DEFAULT_APPLY_BUDGET: u64 = 240;
+
+const DD_PATH: &str = "/bin/dd";
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A verified rootfs image waiting in the stage directory to be written to a partition
+#[derive(Serialize,Deserialize,Debug,Clone,PartialEq)]
+pub struct StagedUpdate {
+    /// Name of the image file in the stage directory
+    image: String,
+    channel: String,
+    version: u32,
+    /// sha256 of the image file after it was prepared
+    #[serde(rename = "file-shasum")]
+    file_shasum: String,
+    #[serde(rename = "file-size")]
+    file_size: u64,
+    /// Channel of the running system when the image was staged
+    #[serde(rename = "system-channel")]
+    system_channel: String,
+    staged: u64,
+}
+
+impl StagedUpdate {
+    fn stage_dir(root: &SystemRoot) -> PathBuf {
+        root.path(STAGE_DIR)
+    }
+
+    /// The staged update, or None if nothing is staged
+    pub fn load(root: &SystemRoot) -> Result<Option<Self>> {
+        let path = Self::stage_dir(root).join(STAGE_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let s = fs::read_to_string(&path)?;
+        let stage = serde_json::from_str(&s)
+            .map_err(|e| format_err!("Failed to parse {}: {}", path.display(), e))?;
+        Ok(Some(stage))
+    }
+
+    /// Replace any staged update with the prepared rootfs `image`. The image
+    /// file is moved into the stage directory.
+    fn create(root: &SystemRoot, image: &ResourceImage, system_channel: &str) -> Result<Self> {
+        let filename = match image.path().file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => bail!("Image path {} has no file name", image.path().display()),
+        };
+        Self::discard(root)?;
+        let dir = Self::stage_dir(root);
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(&filename);
+        info!("Moving {} to {}", image.path().display(), path.display());
+        move_file(image.path(), &path)?;
+
+        let metainfo = image.metainfo();
+        let stage = StagedUpdate {
+            image: filename,
+            channel: metainfo.channel().to_string(),
+            version: metainfo.version(),
+            file_shasum: util::sha256(&path)?,
+            file_size: path.metadata()?.len(),
+            system_channel: system_channel.to_string(),
+            staged: super::unix_time(),
+        };
+        util::write_file_atomic(dir.join(STAGE_FILE), serde_json::to_string_pretty(&stage)?)?;
+        Ok(stage)
+    }
+
+    /// Remove the stage directory together with any staged image file
+    pub fn discard(root: &SystemRoot) -> Result<()> {
+        let dir = Self::stage_dir(root);
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        Ok(())
+    }
+
+    pub fn image_path(&self, root: &SystemRoot) -> PathBuf {
+        Self::stage_dir(root).join(&self.image)
+    }
+
+    pub fn channel(&self) -> &str {
+        &self.channel
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn staged(&self) -> u64 {
+        self.staged
+    }
+
+    /// The reason this staged update can no longer be applied, if any. Hashing
+    /// the image file is only done when `verify_hash` is set because it reads
+    /// the whole file.
+    pub fn problem(&self, root: &SystemRoot, running_channel: Option<&str>, verify_hash: bool) -> Option<String> {
+        if let Some(channel) = running_channel {
+            if channel != self.system_channel {
+                return Some(format!("running system changed from channel '{}' to '{}'", self.system_channel, channel));
+            }
+        }
+        let path = self.image_path(root);
+        let size = match path.metadata() {
+            Ok(meta) => meta.len(),
+            Err(_) => return Some(format!("staged image {} is missing", path.display())),
+        };
+        if size != self.file_size {
+            return Some(format!("staged image {} has changed size", path.display()));
+        }
+        if verify_hash {
+            match util::sha256(&path) {
+                Ok(ref shasum) if *shasum == self.file_shasum => {},
+                Ok(_) => return Some(format!("staged image {} does not match the recorded sha256", path.display())),
+                Err(e) => return Some(format!("failed to hash staged image {}: {}", path.display(), e)),
+            }
+        }
+        None
+    }
+}
+
+/// Verify and prepare the rootfs image at `path` and stage it to be applied at shutdown
+pub fn stage_image(root: &SystemRoot, path: &Path, flags: u32) -> Result<StagedUpdate> {
+    if !path.exists() {
+        bail!("file path {} does not exist", path.display());
+    }
+    let image = ResourceImage::from_path(path)?;
+    if image.metainfo().image_type() != "rootfs" {
+        bail!("Only rootfs images can be staged, {} is a {} image", path.display(), image.metainfo().image_type());
+    }
+    super::check_channel(&image, flags)?;
+    // The image data is always verified before staging
+    super::prepare_image(&image, flags & !super::FLAG_SKIP_SHA)?;
+    let stage = StagedUpdate::create(root, &image, &super::running_channel()?)?;
+    info!("Staged rootfs image version {} from channel '{}' to be installed at shutdown", stage.version, stage.channel);
+    Ok(stage)
+}
+
+/// Write the staged image to a rootfs partition and set it as the preferred boot
+/// partition. If this takes longer than `budget` the write is aborted, the partially
+/// written partition is cleared and the stage is kept for the next attempt.
+///
+/// Returns false if there was no staged update to apply or it was discarded.
+pub fn apply_staged(root: &SystemRoot, budget: Duration) -> Result<bool> {
+    let deadline = Instant::now() + budget;
+    let stage = match StagedUpdate::load(root)? {
+        Some(stage) => stage,
+        None => {
+            info!("No staged update to apply");
+            return Ok(false);
+        }
+    };
+    let channel = super::running_channel()?;
+    if let Some(problem) = stage.problem(root, Some(&channel), true) {
+        warn!("Discarding staged update: {}", problem);
+        StagedUpdate::discard(root)?;
+        return Ok(false);
+    }
+    check_deadline(deadline, "verifying the staged image")?;
+
+    let image = ResourceImage::from_path(stage.image_path(root))?;
+    let partition = super::choose_install_partition(false)?;
+    info!("Writing staged rootfs image version {} to {}", stage.version, partition.path().display());
+
+    // Clear the header first so an interrupted write never leaves a partition which
+    // appears to hold the image it contained before.
+    ImageHeader::clear_partition(partition.path())?;
+    let mut dd = Command::new(DD_PATH);
+    dd.arg(format!("if={}", image.path().display()))
+        .arg(format!("of={}", partition.path().display()))
+        .arg("bs=4096")
+        .arg(format!("skip={}", image.header().nblocks()))
+        .arg("conv=fsync")
+        .stdout(Stdio::null());
+    let status = match run_with_deadline(&mut dd, deadline) {
+        Ok(status) => status,
+        Err(e) => {
+            ImageHeader::clear_partition(partition.path())?;
+            return Err(e);
+        }
+    };
+    if !status.success() {
+        ImageHeader::clear_partition(partition.path())?;
+        bail!("Writing staged image to {} failed: {}", partition.path().display(), status);
+    }
+
+    super::clear_prefer_boot()?;
+    image.header().set_flag(ImageHeader::FLAG_PREFER_BOOT);
+    image.header().set_status(ImageHeader::STATUS_NEW);
+    image.header().write_partition(partition.path())?;
+    info!("Staged update written to {}", partition.path().display());
+    StagedUpdate::discard(root)?;
+    Ok(true)
+}
+
+// Rename `from` to `to`, or copy and remove it if they are on different filesystems
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    match fs::rename(from, to) {
+        Err(ref e) if e.raw_os_error() == Some(libc::EXDEV) => {},
+        result => return Ok(result?),
+    }
+    let copied = fs::copy(from, to)
+        .and_then(|_| fs::File::open(to))
+        .and_then(|f| f.sync_all());
+    if let Err(e) = copied {
+        let _ = fs::remove_file(to);
+        bail!("Failed to copy {} to {}: {}", from.display(), to.display(), e);
+    }
+    fs::remove_file(from)?;
+    Ok(())
+}
+
+fn check_deadline(deadline: Instant, step: &str) -> Result<()> {
+    if Instant::now() >= deadline {
+        bail!("Time budget exceeded while {}, keeping staged update for next time", step);
+    }
+    Ok(())
+}
+
+// Run `cmd` and wait for it to exit, killing it if it is still running at `deadline`
+fn run_with_deadline(cmd: &mut Command, deadline: Instant) -> Result<ExitStatus> {
+    let mut child = cmd.spawn()?;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!("Time budget exceeded, killed {:?} and kept staged update for next time", cmd);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[test]
+fn test_staged_update() {
+    use super::fake_root::FakeRoot;
+
+    let fake = FakeRoot::new("stage");
+    assert_eq!(StagedUpdate::load(fake.root()).unwrap(), None);
+
+    let image = ResourceImage::from_path(fake.build_image("rootfs", 14, 1)).unwrap();
+    let stage = StagedUpdate::create(fake.root(), &image, "dev").unwrap();
+    assert!(!image.path().exists());
+    assert_eq!(StagedUpdate::load(fake.root()).unwrap(), Some(stage.clone()));
+    assert_eq!((stage.channel(), stage.version()), ("dev", 14));
+    assert_eq!(stage.problem(fake.root(), Some("dev"), true), None);
+
+    let problem = stage.problem(fake.root(), Some("prod"), false).unwrap();
+    assert!(problem.contains("from channel 'dev' to 'prod'"), "{}", problem);
+
+    // Same size but different content is only detected when hashing
+    let path = stage.image_path(fake.root());
+    let mut data = fs::read(&path).unwrap();
+    let last = data.len() - 1;
+    data[last] ^= 0xFF;
+    fs::write(&path, &data).unwrap();
+    assert_eq!(stage.problem(fake.root(), Some("dev"), false), None);
+    assert!(stage.problem(fake.root(), Some("dev"), true).unwrap().contains("does not match"));
+
+    // Staging another image replaces the staged update
+    let image = ResourceImage::from_path(fake.build_image("rootfs", 15, 2)).unwrap();
+    let newer = StagedUpdate::create(fake.root(), &image, "dev").unwrap();
+    assert_eq!(fake.files(STAGE_DIR), vec![newer.image.clone(), STAGE_FILE.to_string()]);
+    assert!(stage.problem(fake.root(), None, false).unwrap().contains("is missing"));
+
+    StagedUpdate::discard(fake.root()).unwrap();
+    assert!(!fake.root().path(STAGE_DIR).exists());
+}
+
+#[test]
+fn test_run_with_deadline() {
+    let soon = || Instant::now() + Duration::from_secs(5);
+    assert!(run_with_deadline(Command::new("/bin/true").stdout(Stdio::null()), soon()).unwrap().success());
+    assert!(!run_with_deadline(Command::new("/bin/false").stdout(Stdio::null()), soon()).unwrap().success());
+
+    let start = Instant::now();
+    let err = run_with_deadline(Command::new("/bin/sleep").arg("10"), start + Duration::from_millis(300)).unwrap_err();
+    assert!(err.to_string().starts_with("Time budget exceeded"), "{}", err);
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
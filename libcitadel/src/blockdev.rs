@@ -1,10 +1,10 @@
 use std::path::Path;
 use std::fs::File;
-use std::io::{Read,Write,Seek,SeekFrom};
+use std::cmp;
+use std::io::{self,Read,Write,Seek,SeekFrom};
 use std::os::unix::io::AsRawFd;
 use std::fs::OpenOptions;
 use std::os::unix::fs::OpenOptionsExt;
-use libc;
 
 use crate::Result;
 
@@ -140,7 +140,7 @@ impl BlockDev {
         if addr & ALIGNMENT_MASK != 0 {
             bail!("block device i/o attempted with incorrectly aligned buffer: {:p}", buffer);
         }
-        if buffer.len() % SECTOR_SIZE != 0 {
+        if !buffer.len().is_multiple_of(SECTOR_SIZE) {
             bail!("buffer length {} is not a multiple of sector size", buffer.len());
         }
         let count = buffer.len() / SECTOR_SIZE;
@@ -168,3 +168,57 @@ impl BlockDev {
     }
 
 }
+
+///
+/// Reads the first `len` bytes of a block device sequentially. Since the device
+/// is opened with `O_DIRECT` the data is read from the device rather than from
+/// the page cache.
+///
+pub struct BlockDevReader {
+    dev: BlockDev,
+    buffer: AlignedBuffer,
+    sector: usize,
+    remaining: usize,
+    pos: usize,
+    filled: usize,
+}
+
+impl BlockDevReader {
+    const CHUNK_SIZE: usize = 1024 * 1024;
+
+    pub fn new(dev: BlockDev, len: usize) -> Result<Self> {
+        if !len.is_multiple_of(SECTOR_SIZE) {
+            bail!("read length {} is not a multiple of sector size", len);
+        }
+        Ok(BlockDevReader {
+            dev, buffer: AlignedBuffer::new(Self::CHUNK_SIZE),
+            sector: 0, remaining: len, pos: 0, filled: 0,
+        })
+    }
+
+    fn fill_buffer(&mut self) -> Result<()> {
+        let n = cmp::min(self.remaining, Self::CHUNK_SIZE);
+        self.dev.read_sectors(self.sector, &mut self.buffer.as_mut()[..n])?;
+        self.sector += n / SECTOR_SIZE;
+        self.remaining -= n;
+        self.pos = 0;
+        self.filled = n;
+        Ok(())
+    }
+}
+
+impl Read for BlockDevReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.filled {
+            if self.remaining == 0 {
+                return Ok(0);
+            }
+            self.fill_buffer()
+                .map_err(|e| io::Error::other(e.to_string()))?;
+        }
+        let n = cmp::min(buf.len(), self.filled - self.pos);
+        buf[..n].copy_from_slice(&self.buffer.as_ref()[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
@@ -15,12 +15,17 @@ lazy_static! {
 
 /// Kernel command line parsed from /proc/cmdline into a map
 /// of Key / Value pairs.  The value is optional since some
-/// variables are flags and do not have a value.
+/// variables are flags and do not have a value. A variable
+/// may appear more than once and every value is kept in the
+/// order it appears.
+///
+/// Variable names are compared with '_' and '-' treated as the
+/// same character.
 ///
 /// This class is a lazy constructed singleton.
 #[derive(Clone)]
 pub struct CommandLine {
-    varmap: HashMap<String,Option<String>>,
+    varmap: HashMap<String,Vec<Option<String>>>,
 }
 
 impl CommandLine {
@@ -32,41 +37,69 @@ impl CommandLine {
 
     /// Return a value for the variable `name` if a value is present on the kernel command line for this variable.
     /// Will return `None` if variable does not exist or if variable is present but does not have a value.
+    /// If the variable appears more than once, the last occurrence is used.
     pub fn get_value(name: &str) -> Option<&str> {
         CMDLINE._get_value(name)
     }
 
+    /// Return `true` if `citadel.<name>` is present on the kernel command line
+    /// either as a bare flag or with a value.
+    pub fn flag(name: &str) -> bool {
+        Self::var_exists(&Self::citadel_var(name))
+    }
+
+    /// Return the value of `citadel.<name>=value` from the kernel command line. If
+    /// the parameter is repeated the last value is returned.
+    pub fn value(name: &str) -> Option<&'static str> {
+        CMDLINE._get_value(&Self::citadel_var(name))
+    }
+
+    /// Return every value of a repeated `citadel.<name>=value` parameter in the
+    /// order they appear on the kernel command line.
+    pub fn values(name: &str) -> Vec<&'static str> {
+        CMDLINE._get_values(&Self::citadel_var(name))
+    }
+
+    fn citadel_var(name: &str) -> String {
+        format!("citadel.{}", name)
+    }
+
     /// Return `true` if variable citadel.noverity is present on kernel command line.
     pub fn noverity() -> bool {
-        Self::var_exists("citadel.noverity")
+        Self::flag("noverity")
+    }
+
+    /// Return `true` if variable citadel.nofsck is present on kernel command line.
+    pub fn nofsck() -> bool {
+        Self::flag("nofsck")
     }
 
     pub fn nosignatures() -> bool {
-        Self::var_exists("citadel.nosignatures")
+        Self::flag("nosignatures")
     }
 
     /// Return `true` if variable citadel.install is present on kernel command line.
     pub fn install_mode() -> bool {
-        Self::var_exists("citadel.install")
+        Self::flag("install")
     }
 
     /// Return `true` if variable citadel.live is present on kernel command line.
     pub fn live_mode() -> bool {
-        Self::var_exists("citadel.live")
+        Self::flag("live")
     }
 
     /// Return `true` if variable citadel.recovery is present on kernel command line.
     pub fn recovery_mode() -> bool {
-        Self::var_exists("citadel.recovery")
+        Self::flag("recovery")
     }
 
-    pub fn overlay() -> bool { Self::var_exists("citadel.overlay") }
+    pub fn overlay() -> bool { Self::flag("overlay") }
 
     /// Return `true` if sealed realmfs images are enabled on kernel command line
-    pub fn sealed() -> bool { Self::var_exists("citadel.sealed") }
+    pub fn sealed() -> bool { Self::flag("sealed") }
 
     pub fn channel() -> Option<&'static str> {
-        Self::get_value("citadel.channel")
+        Self::value("channel")
     }
 
     fn _channel() -> Option<(&'static str,Option<&'static str>)> {
@@ -95,12 +128,38 @@ impl CommandLine {
         None
     }
 
+    /// Return the number of seconds to wait for the storage device to appear during
+    /// boot if set with `citadel.storage_timeout=` on the kernel command line.
+    pub fn storage_timeout() -> Option<u64> {
+        let value = Self::value("storage_timeout")?;
+        match value.parse() {
+            Ok(secs) => Some(secs),
+            Err(_) => {
+                warn!("Ignoring invalid citadel.storage_timeout value '{}'", value);
+                None
+            }
+        }
+    }
+
+    /// Return the number of realms to start at the same time during boot if set
+    /// with `citadel.realm_parallelism=` on the kernel command line.
+    pub fn realm_parallelism() -> Option<usize> {
+        let value = Self::value("realm_parallelism")?;
+        match value.parse() {
+            Ok(n) if n > 0 => Some(n),
+            _ => {
+                warn!("Ignoring invalid citadel.realm_parallelism value '{}'", value);
+                None
+            }
+        }
+    }
+
     pub fn verbose() -> bool {
-        Self::var_exists("citadel.verbose")
+        Self::flag("verbose")
     }
 
     pub fn debug() -> bool {
-        Self::var_exists("citadel.debug")
+        Self::flag("debug")
     }
 
 
@@ -114,20 +173,27 @@ impl CommandLine {
         Ok(CommandLine{varmap})
     }
 
+    // The parser stores names with '_' replaced by '-'
+    fn normalize_name(name: &str) -> String {
+        name.replace('_', "-")
+    }
+
     fn _var_exists(&self, name: &str) -> bool {
-        self.varmap.contains_key(name)
+        self.varmap.contains_key(&Self::normalize_name(name))
     }
 
     fn _get_value(&self, name: &str) -> Option<&str> {
-        if let Some(val) = self.varmap.get(name) {
-            // 'name' exists
-            if let Some(ref v) = *val {
-                // has an associated value (name=value)
-                return Some(v)
-            }
+        self.varmap.get(&Self::normalize_name(name))
+            .and_then(|vals| vals.last())
+            .and_then(|val| val.as_ref())
+            .map(|v| v.as_str())
+    }
+
+    fn _get_values(&self, name: &str) -> Vec<&str> {
+        match self.varmap.get(&Self::normalize_name(name)) {
+            Some(vals) => vals.iter().flatten().map(|v| v.as_str()).collect(),
+            None => Vec::new(),
         }
-        // otherwise None
-        None
     }
 }
 
@@ -141,19 +207,26 @@ enum ParseState {
     Value(String,String),
     // First char was a '-', expecting double '--'
     InDash,
+    // Last char was '"' opening a quoted option, expecting option name
+    QuotedOption,
     // In quoted value, whitespace allowed
     InQuoted(String, String),
     // Last char was closing '"' char, expect only whitespace next
-    QuotedEnd(String, String),
+    QuotedEnd(String, Option<String>),
     // Failed to parse an option, remain in state BAD until whitespace
     Bad,
 }
 
 // Parser for kernel command line
+//
+// Like the kernel, an entire option may be quoted ("name=some value") as
+// well as just the value (name="some value").
 struct CommandLineParser {
     cmdline: String,
-    varmap: HashMap<String, Option<String>>,
+    varmap: HashMap<String, Vec<Option<String>>>,
     pos: usize,
+    // true while parsing an option which started with a '"' character
+    quoted_option: bool,
 }
 
 impl CommandLineParser {
@@ -162,10 +235,11 @@ impl CommandLineParser {
             cmdline,
             varmap: HashMap::new(),
             pos: 0,
+            quoted_option: false,
         }
     }
 
-    fn parse(mut self) -> HashMap<String, Option<String>> {
+    fn parse(mut self) -> HashMap<String, Vec<Option<String>>> {
         // Append a space to cause final item to be processed
         let cmdline = self.cmdline.clone() + " ";
         let mut state = ParseState::Whitespace;
@@ -175,6 +249,7 @@ impl CommandLineParser {
                 ParseState::Name(name) => self.parse_name(c, name),
                 ParseState::Value(name, value) => self.parse_value(c, name, value),
                 ParseState::InDash => self.parse_in_dash(c),
+                ParseState::QuotedOption => self.parse_quoted_option(c),
                 ParseState::InQuoted(name, value) => self.parse_in_quoted(c, name, value),
                 ParseState::QuotedEnd(name, value) => self.parse_quoted_end(c, name, value),
                 ParseState::Bad => self.parse_bad(c),
@@ -184,9 +259,18 @@ impl CommandLineParser {
         self.varmap
     }
 
+    fn add_var(&mut self, name: String, value: Option<String>) {
+        self.varmap.entry(name).or_default().push(value);
+    }
+
     fn parse_whitespace(&mut self, c: char) -> ParseState {
+        self.quoted_option = false;
         match c {
             ch if ch.is_whitespace() => ParseState::Whitespace,
+            '"' => {
+                self.quoted_option = true;
+                ParseState::QuotedOption
+            },
             ch if ch.is_ascii_alphanumeric() => ParseState::Name(ch.to_string()),
             _ => {
                 self.unexpected_char(c, "as initial character of option name")
@@ -204,8 +288,10 @@ impl CommandLineParser {
 
             '=' => ParseState::Value(name, String::new()),
 
-            ch if ch.is_whitespace() => {
-                self.varmap.insert(name, None);
+            '"' if self.quoted_option => ParseState::QuotedEnd(name, None),
+
+            ch if ch.is_whitespace() && !self.quoted_option => {
+                self.add_var(name, None);
                 ParseState::Whitespace
             },
 
@@ -223,10 +309,12 @@ impl CommandLineParser {
     fn parse_value(&mut self, c: char, name: String, mut value: String) -> ParseState {
         match c {
 
+            '"' if self.quoted_option => ParseState::QuotedEnd(name, Some(value)),
+
             '"' if value.is_empty() => ParseState::InQuoted(name, value),
 
-            ch if ch.is_whitespace() => {
-                self.varmap.insert(name, Some(value));
+            ch if ch.is_whitespace() && !self.quoted_option => {
+                self.add_var(name, Some(value));
                 ParseState::Whitespace
             },
 
@@ -252,18 +340,26 @@ impl CommandLineParser {
         }
     }
 
+    fn parse_quoted_option(&mut self, c: char) -> ParseState {
+        if c.is_ascii_alphanumeric() {
+            ParseState::Name(c.to_string())
+        } else {
+            self.unexpected_char(c, "as initial character of quoted option name")
+        }
+    }
+
     fn parse_in_quoted(&mut self, c: char, name: String, mut value: String) -> ParseState {
         if c == '"' {
-            ParseState::QuotedEnd(name, value)
+            ParseState::QuotedEnd(name, Some(value))
         } else {
             value.push(c);
             ParseState::InQuoted(name, value)
         }
     }
 
-    fn parse_quoted_end(&mut self, c: char, name: String, value: String) -> ParseState {
+    fn parse_quoted_end(&mut self, c: char, name: String, value: Option<String>) -> ParseState {
         if c.is_whitespace() {
-            self.varmap.insert(name, Some(value));
+            self.add_var(name, value);
             return ParseState::Whitespace
         }
         self.unexpected_char(c, "after closing quote character")
@@ -293,3 +389,34 @@ fn foo() {
 }
 
 
+
+#[test]
+fn test_cmdline_parser() {
+    let parse = |s: &str| CommandLine { varmap: CommandLineParser::new(s.to_string()).parse() };
+
+    let cl = parse("ro quiet citadel.live citadel.overlay_size=2G citadel.channel=dev:abc=def");
+    assert!(cl._var_exists("quiet"));
+    assert!(cl._var_exists("citadel.live"));
+    assert_eq!(cl._get_value("citadel.live"), None);
+    assert_eq!(cl._get_value("citadel.overlay_size"), Some("2G"));
+    assert_eq!(cl._get_value("citadel.overlay-size"), Some("2G"));
+    assert_eq!(cl._get_value("citadel.channel"), Some("dev:abc=def"));
+    assert!(!cl._var_exists("citadel.install"));
+
+    let cl = parse(r#"a="quoted value" "b=whole option quoted" "c" d="" e="x"y f=1"#);
+    assert_eq!(cl._get_value("a"), Some("quoted value"));
+    assert_eq!(cl._get_value("b"), Some("whole option quoted"));
+    assert!(cl._var_exists("c"));
+    assert_eq!(cl._get_value("d"), Some(""));
+    assert!(!cl._var_exists("e"));
+    assert_eq!(cl._get_value("f"), Some("1"));
+
+    let cl = parse("citadel.x=1 citadel.y citadel.x=2 citadel.x citadel.x=3");
+    assert_eq!(cl._get_values("citadel.x"), vec!["1", "2", "3"]);
+    assert_eq!(cl._get_value("citadel.x"), Some("3"));
+    assert!(cl._get_values("citadel.y").is_empty());
+    assert!(cl._get_values("citadel.z").is_empty());
+
+    let cl = parse("citadel.x=1 citadel.x");
+    assert_eq!(cl._get_value("citadel.x"), None);
+}
@@ -32,6 +32,10 @@ impl OsRelease {
     fn load_file(path: &Path) -> Result<OsRelease> {
         let mut vars = HashMap::new();
         for line in fs::read_to_string(path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
             let (k,v) = OsRelease::parse_line(line)?;
             vars.insert(k,v);
         }
@@ -41,7 +45,7 @@ impl OsRelease {
     fn parse_line(line: &str) -> Result<(String,String)> {
         let parts: Vec<&str> = line.splitn(2, '=').collect();
         if parts.len() != 2 {
-
+            bail!("invalid os-release line: {}", line);
         }
         let key = parts[0].trim().to_string();
         let val = OsRelease::remove_quotes(parts[1].trim())?;
@@ -1,8 +1,11 @@
 use std::env;
-use std::fs::File;
-use std::io::{self,Seek,Read,BufReader,BufRead,SeekFrom};
+use std::fs::{File,OpenOptions};
+use std::io::{self,Seek,Read,BufReader,SeekFrom,Write};
+use std::iter;
+use std::os::unix::process::ExitStatusExt;
 use std::path::{Path,PathBuf};
 use std::process::{Command,ExitStatus,Stdio};
+use std::time::{Instant,SystemTime,UNIX_EPOCH};
 
 use crate::Result;
 
@@ -24,9 +27,60 @@ macro_rules! cmd_with_output {
     ($cmd:expr, $fmt:expr, $($arg:tt)+) => { $crate::Exec::new($cmd).output(format!($fmt, $($arg)+)) };
 }
 
+/// Environment variable naming a file to which a line is appended for every command executed
+const CMD_LOG_VAR: &str = "CITADEL_CMD_LOG";
+/// Maximum number of bytes of stderr included in the error returned for a failed command
+const MAX_STDERR_LEN: usize = 2048;
+
+///
+/// Runs external commands. Every command executed is logged with its full argument
+/// list at debug level together with how long it ran and how it exited, and if the
+/// `CITADEL_CMD_LOG` environment variable is set a line is also appended to the file
+/// it names. When a command fails the error includes the stderr output of the command
+/// if it was captured.
+///
+/// Arguments can be passed as a single string which is split on whitespace to the
+/// `run`, `run_ok`, `output` and `pipe_input` methods, or added one at a time with
+/// `arg` and `args` and the command run with `status` or `capture`.
+///
 pub struct Exec {
     cmd_name: String,
     cmd: Command,
+    args: Vec<String>,
+}
+
+/// Exit status and captured output of a command run with `Exec::capture()`
+pub struct ExecOutput {
+    argv: String,
+    status: ExitStatus,
+    stdout: String,
+    stderr: String,
+}
+
+impl ExecOutput {
+    pub fn status(&self) -> ExitStatus {
+        self.status
+    }
+
+    pub fn success(&self) -> bool {
+        self.status.success()
+    }
+
+    pub fn stdout(&self) -> &str {
+        &self.stdout
+    }
+
+    pub fn stderr(&self) -> &str {
+        &self.stderr
+    }
+
+    /// Return an error including the captured stderr if the command did not exit successfully
+    pub fn check(&self) -> Result<()> {
+        if !self.status.success() {
+            bail!("{}", failure_message(&self.argv, self.status.code(), &self.stderr));
+        }
+        Ok(())
+    }
 }
 
 impl Exec {
@@ -34,6 +88,7 @@ impl Exec {
         Exec {
             cmd_name: cmd.as_ref().to_string(),
             cmd: Command::new(cmd.as_ref()),
+            args: Vec::new(),
         }
     }
 
@@ -44,37 +99,77 @@ impl Exec {
         self
     }
 
+    pub fn stdin(&mut self, cfg: Stdio) -> &mut Self {
+        self.cmd.stdin(cfg);
+        self
+    }
+
+    pub fn arg(&mut self, arg: impl AsRef<str>) -> &mut Self {
+        self.args.push(arg.as_ref().to_string());
+        self.cmd.arg(arg.as_ref());
+        self
+    }
+
+    pub fn args<I,S>(&mut self, args: I) -> &mut Self
+        where I: IntoIterator<Item=S>, S: AsRef<str>
+    {
+        for arg in args {
+            self.arg(arg);
+        }
+        self
+    }
+
+    /// Run the command with inherited stdin, stdout and stderr and return the exit status.
+    pub fn status(&mut self) -> io::Result<ExitStatus> {
+        let start = Instant::now();
+        let result = self.cmd.status();
+        self.record(result.as_ref().ok().cloned(), start);
+        result
+    }
+
+    /// Run the command and capture stdout and stderr. Unlike the other methods, a command
+    /// which exits with a failure status is not an error. Use `ExecOutput::check()` to
+    /// convert a failure into an error.
+    pub fn capture(&mut self) -> io::Result<ExecOutput> {
+        let start = Instant::now();
+        let result = self.cmd.output();
+        self.record(result.as_ref().ok().map(|out| out.status), start);
+        let output = result?;
+        Ok(ExecOutput {
+            argv: self.argv(),
+            status: output.status,
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        })
+    }
+
     pub fn run(&mut self, args: impl AsRef<str>) -> Result<()> {
         self.ensure_command_exists()?;
         verbose!("cmd {} {}", self.cmd_name, args.as_ref());
-        let args: Vec<&str> = args.as_ref().split_whitespace().collect();
-        let result = self.cmd
-            .args(args)
-            .output()?;
-
-        for line in BufReader::new(result.stderr.as_slice()).lines() {
-            verbose!("  {}", line?);
+        self.add_args(args);
+        let output = self.capture()?;
+        for line in output.stderr().lines() {
+            verbose!("  {}", line);
         }
-        self.check_cmd_status(result.status)
+        output.check()
     }
 
-
     pub fn run_ok(&mut self, args: impl AsRef<str>) -> Result<bool> {
         self.ensure_command_exists()?;
-        let args: Vec<&str> = args.as_ref().split_whitespace().collect();
-        let status = self.cmd
-            .args(args)
-            .status()?;
-
+        self.add_args(args);
+        let status = self.status()?;
         Ok(status.success())
     }
 
     pub fn output(&mut self, args: impl AsRef<str>) -> Result<String> {
         self.ensure_command_exists()?;
-        self.add_args(args.as_ref());
-        let result = self.cmd.stderr(Stdio::inherit()).output()?;
-        self.check_cmd_status(result.status)?;
-        Ok(String::from_utf8(result.stdout).unwrap().trim().to_owned())
+        self.add_args(args);
+        let output = self.capture()?;
+        output.check()?;
+        for line in output.stderr().lines() {
+            verbose!("  {}", line);
+        }
+        Ok(output.stdout().trim().to_owned())
     }
 
     ///
@@ -86,6 +181,7 @@ impl Exec {
         let mut r = ranged_reader(input.as_ref(), range)?;
         self.ensure_command_exists()?;
         self.add_args(args);
+        let start = Instant::now();
         let mut child = self.cmd
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
@@ -95,22 +191,37 @@ impl Exec {
         let stdin = child.stdin.as_mut().unwrap();
         io::copy(&mut r, stdin)?;
         let output = child.wait_with_output()?;
+        self.record(Some(output.status), start);
         Ok(String::from_utf8(output.stdout).unwrap().trim().to_owned())
     }
 
     fn add_args(&mut self, args: impl AsRef<str>) {
-        let args: Vec<&str> = args.as_ref().split_whitespace().collect();
-        self.cmd.args(args);
+        self.args(args.as_ref().split_whitespace());
     }
 
-    fn check_cmd_status(&self, status: ExitStatus) -> Result<()> {
-        if !status.success() {
-            match status.code() {
-                Some(code) => bail!("command {} failed with exit code: {}", self.cmd_name, code),
-                None => bail!("command {} failed with no exit code", self.cmd_name),
+    fn argv(&self) -> String {
+        iter::once(self.cmd_name.as_str())
+            .chain(self.args.iter().map(|s| s.as_str()))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    // `status` is None if the command could not be executed
+    fn record(&self, status: Option<ExitStatus>, start: Instant) {
+        let elapsed = start.elapsed().as_millis();
+        let status = status_label(status);
+        let argv = self.argv();
+        debug!("cmd {} ({}, {}ms)", argv, status, elapsed);
+
+        if let Some(path) = env::var_os(CMD_LOG_VAR) {
+            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            let line = format!("{} {}ms {} {}\n", timestamp, elapsed, status, argv);
+            let result = OpenOptions::new().create(true).append(true).open(&path)
+                .and_then(|mut f| f.write_all(line.as_bytes()));
+            if let Err(e) = result {
+                warn!("Failed to write command log {}: {}", Path::new(&path).display(), e);
             }
         }
-        Ok(())
     }
 
     fn ensure_command_exists(&self) -> Result<()> {
@@ -136,6 +247,39 @@ impl Exec {
     }
 }
 
+fn status_label(status: Option<ExitStatus>) -> String {
+    match status {
+        Some(status) => match (status.code(), status.signal()) {
+            (Some(code), _) => format!("exit={}", code),
+            (None, Some(signal)) => format!("signal={}", signal),
+            (None, None) => "exit=unknown".to_string(),
+        },
+        None => "spawn-failed".to_string(),
+    }
+}
+
+// Error message for a command which exited with `code`, or was killed by a
+// signal if `code` is None. Only the end of a long stderr output is included.
+fn failure_message(argv: &str, code: Option<i32>, stderr: &str) -> String {
+    let mut message = match code {
+        Some(code) => format!("command {} failed with exit code: {}", argv, code),
+        None => format!("command {} failed with no exit code", argv),
+    };
+    let stderr = stderr.trim();
+    if !stderr.is_empty() {
+        let mut start = stderr.len().saturating_sub(MAX_STDERR_LEN);
+        while !stderr.is_char_boundary(start) {
+            start += 1;
+        }
+        message.push_str(": ");
+        if start > 0 {
+            message.push_str("...");
+        }
+        message.push_str(&stderr[start..]);
+    }
+    message
+}
+
 pub enum FileRange {
     All,
     Offset(usize),
@@ -160,3 +304,24 @@ fn ranged_reader<P: AsRef<Path>>(path: P, range: FileRange) -> Result<Box<dyn Re
         Ok(Box::new(r))
     }
 }
+
+#[test]
+fn test_command_failure_message() {
+    assert_eq!(failure_message("/usr/bin/mount /dev/sda1 /mnt", Some(32), ""),
+               "command /usr/bin/mount /dev/sda1 /mnt failed with exit code: 32");
+    assert_eq!(failure_message("/usr/bin/mount /dev/sda1 /mnt", Some(32), "mount: /mnt: special device /dev/sda1 does not exist.\n"),
+               "command /usr/bin/mount /dev/sda1 /mnt failed with exit code: 32: mount: /mnt: special device /dev/sda1 does not exist.");
+    assert_eq!(failure_message("/bin/false", None, "  "), "command /bin/false failed with no exit code");
+
+    let long = format!("{}\u{e9}{}", "x".repeat(10), "y".repeat(MAX_STDERR_LEN - 1));
+    let message = failure_message("cmd", Some(1), &long);
+    assert!(message.starts_with("command cmd failed with exit code: 1: ...y"), "{}", message);
+    assert!(message.len() < MAX_STDERR_LEN + 50);
+
+    let output = Exec::new("/bin/sh").args(["-c", "echo out; echo problem >&2; exit 3"]).capture().unwrap();
+    assert_eq!(output.stdout(), "out\n");
+    assert_eq!(output.check().unwrap_err().to_string(),
+               "command /bin/sh -c echo out; echo problem >&2; exit 3 failed with exit code: 3: problem");
+    assert_eq!(status_label(Some(output.status())), "exit=3");
+    assert_eq!(status_label(None), "spawn-failed");
+}
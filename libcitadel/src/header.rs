@@ -1,8 +1,8 @@
+use std::collections::BTreeMap;
 use std::fs::{File,OpenOptions};
 use std::io::{Read, Write};
 use std::path::Path;
 
-use toml;
 
 use crate::blockdev::AlignedBuffer;
 use crate::{BlockDev,Result,public_key_for_channel,PublicKey};
@@ -13,23 +13,79 @@ use std::os::unix::fs::MetadataExt;
 /// Expected magic value in header
 const MAGIC: &[u8] = b"SGOS";
 
-/// Offset into header of the start of the metainfo document
-const METAINFO_OFFSET: usize = 8;
+/// Offset into a version 1 header of the start of the metainfo document
+const V1_METAINFO_OFFSET: usize = 8;
+
+/// Value stored at offset 6 of a version 2 header in place of the 16-bit
+/// version 1 length field. A version 1 length is always less than
+/// `HEADER_SIZE` so the high byte can never be 0xFF.
+const V2_MARKER: u8 = 0xFF;
+
+/// Offsets of the version 2 header fields
+const V2_VERSION_OFFSET: usize = 7;
+const V2_CRC_OFFSET: usize = 8;
+const V2_LENGTH_OFFSET: usize = 12;
+const V2_METAINFO_OFFSET: usize = 16;
 
 /// Signature is 64 bytes long
 const SIGNATURE_LENGTH: usize = 64;
 
-/// Maximum amount of space in block for metainfo document
-const MAX_METAINFO_LEN: usize = (ImageHeader::HEADER_SIZE - (METAINFO_OFFSET + SIGNATURE_LENGTH));
+/// Maximum length of a key in the `[extra]` table of the metainfo
+pub(crate) const MAX_EXTRA_KEY_LEN: usize = 32;
+
+/// Extra metainfo keys are ascii alphanumeric characters, '-' and '_'
+pub(crate) fn is_valid_extra_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= MAX_EXTRA_KEY_LEN &&
+        key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Kernel flavor of kernel images which do not have a `kernel-flavor` metainfo field
+pub const DEFAULT_KERNEL_FLAVOR: &str = "standard";
+
+/// Kernel flavors are used in file and boot entry names, so they are
+/// restricted to lowercase ascii letters and digits starting with a letter.
+pub fn is_valid_kernel_flavor(flavor: &str) -> bool {
+    flavor.len() <= 32 &&
+        flavor.starts_with(|c: char| c.is_ascii_lowercase()) &&
+        flavor.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+}
+
+/// Serialize extra metainfo fields as a TOML `[extra]` table. Since it is a
+/// table it must be appended after all other fields of the metainfo document.
+pub(crate) fn extra_section<V: serde::Serialize>(extra: &BTreeMap<String, V>) -> Result<String> {
+    if extra.is_empty() {
+        return Ok(String::new());
+    }
+    let s = toml::to_string(extra)
+        .map_err(|e| format_err!("Failed to serialize extra metainfo fields: {}", e))?;
+    Ok(format!("[extra]\n{}", s))
+}
+
+/// Error returned when a header with a valid magic value cannot be loaded because
+/// the header version, metainfo length, CRC or metainfo document is invalid.
+#[derive(Debug,Fail)]
+#[fail(display = "invalid image header: {}", reason)]
+pub struct InvalidHeaderError {
+    reason: String,
+}
+
+impl InvalidHeaderError {
+    fn new(reason: impl Into<String>) -> Self {
+        InvalidHeaderError { reason: reason.into() }
+    }
+}
 
 fn is_valid_status_code(code: u8) -> bool {
     code <= ImageHeader::STATUS_BAD_META
 }
 
 ///
-/// The Image Header structure is stored in a 4096 byte block at the start of
-/// every resource image file. When an image is installed to a partition it
-/// is stored at the last 4096 byte block of the block device for the partition.
+/// The Image Header structure is stored at the start of every resource image
+/// file and the image data follows immediately after it. When an image is
+/// installed to a partition it is stored at the end of the block device for
+/// the partition.
+///
+/// A version 1 header is a single 4096 byte block.
 ///
 /// The layout of this structure is the following:
 ///
@@ -45,6 +101,29 @@ fn is_valid_status_code(code: u8) -> bool {
 ///
 ///    signature    64              8 + length
 ///
+/// Images created by newer tools use a version 2 layout which spans two 4096
+/// byte blocks (8192 bytes) to make room for a larger metainfo document. The
+/// first block starts with the same fields as a version 1 header so the version
+/// can be identified from it. On a partition the first block is stored in the
+/// last 4096 bytes of the device and the second block immediately before it.
+/// Version 1 headers are still read transparently and keep their layout when
+/// the status, flags, metainfo or signature is changed and the header is written back.
+///
+///    field     size (bytes)        offset
+///    -----     ------------        ------
+///
+///    magic        4                  0
+///    status       1                  4
+///    flags        1                  5
+///    marker       1                  6
+///    version      1                  7
+///    crc32        4                  8
+///    length       4                  12
+///
+///    metainfo  <length>              16
+///
+///    signature    64              16 + length
+///
 /// magic     : Must match ascii bytes 'SGOS' for the header to be considered valid
 ///
 /// status    : One of the `STATUS` constants defined below
@@ -52,37 +131,55 @@ fn is_valid_status_code(code: u8) -> bool {
 /// flags     : May contain 'FLAG' values defined below.
 ///
 /// length    : The size of the metainfo field in bytes as a 16-bit Big Endian value
+///             in version 1 and as a 32-bit Big Endian value in version 2
+///
+/// marker    : Always 0xFF, which distinguishes a version 2 header from the
+///             version 1 length field at the same offset
+///
+/// version   : Header layout version, currently 2
+///
+/// crc32     : CRC32 (IEEE) as a 32-bit Big Endian value over the header up to the
+///             end of the signature, calculated with the crc32 field set to zero.
+///             The crc is updated every time the header is written.
 ///
 /// metainfo  : A utf-8 encoded TOML document with various fields describing the image
 ///
 /// signature : ed25519 signature over the bytes of the metainfo field
 ///
-
 pub struct ImageHeader {
     buffer: RwLock<HeaderBytes>,
     metainfo: Mutex<Option<Arc<MetaInfo>>>,
     timestamp: AtomicIsize,
 }
 
-struct HeaderBytes([u8; ImageHeader::HEADER_SIZE]);
+struct HeaderBytes([u8; ImageHeader::MAX_HEADER_SIZE]);
 
 impl HeaderBytes {
 
     fn create_empty() -> RwLock<Self> {
         let mut buffer = HeaderBytes::new();
         buffer.clear();
+        buffer.write_u8(6, V2_MARKER);
+        buffer.write_u8(V2_VERSION_OFFSET, ImageHeader::VERSION_2);
         RwLock::new(buffer)
     }
 
     fn create_from_slice(slice: &[u8]) -> RwLock<Self> {
-        assert_eq!(slice.len(), ImageHeader::HEADER_SIZE);
         let mut buffer = HeaderBytes::new();
-        buffer.0.copy_from_slice(slice);
+        buffer.0[..slice.len()].copy_from_slice(slice);
+        assert_eq!(slice.len(), buffer.size());
         RwLock::new(buffer)
     }
 
     fn new() -> Self {
-        HeaderBytes([0u8; ImageHeader::HEADER_SIZE])
+        HeaderBytes([0u8; ImageHeader::MAX_HEADER_SIZE])
+    }
+
+    /// Size of a header which starts with the header block `block`
+    fn size_from_first_block(block: &[u8]) -> usize {
+        let mut buffer = HeaderBytes::new();
+        buffer.0[..ImageHeader::HEADER_SIZE].copy_from_slice(&block[..ImageHeader::HEADER_SIZE]);
+        buffer.size()
     }
 
     fn clear(&mut self) {
@@ -113,8 +210,89 @@ impl HeaderBytes {
         self.write_u8(idx + 1, lo);
     }
 
+    fn read_u32(&self, idx: usize) -> u32 {
+        let hi = u32::from(self.read_u16(idx));
+        let lo = u32::from(self.read_u16(idx + 2));
+        (hi << 16) | lo
+    }
+
+    fn write_u32(&mut self, idx: usize, val: u32) {
+        self.write_u16(idx, (val >> 16) as u16);
+        self.write_u16(idx + 2, val as u16);
+    }
+
+    /// Header layout version or `None` if magic or version is not valid
+    fn version(&self) -> Option<u8> {
+        if self.read_bytes(0, 4) != MAGIC {
+            return None;
+        }
+        if self.read_u8(6) != V2_MARKER {
+            return Some(ImageHeader::VERSION_1);
+        }
+        match self.read_u8(V2_VERSION_OFFSET) {
+            ImageHeader::VERSION_2 => Some(ImageHeader::VERSION_2),
+            _ => None,
+        }
+    }
+
+    fn is_v2(&self) -> bool {
+        self.version() == Some(ImageHeader::VERSION_2)
+    }
+
+    fn size(&self) -> usize {
+        if self.is_v2() { ImageHeader::MAX_HEADER_SIZE } else { ImageHeader::HEADER_SIZE }
+    }
+
+    fn metainfo_offset(&self) -> usize {
+        if self.is_v2() { V2_METAINFO_OFFSET } else { V1_METAINFO_OFFSET }
+    }
+
+    /// Maximum amount of space in header for metainfo document
+    fn max_metainfo_len(&self) -> usize {
+        self.size() - (self.metainfo_offset() + SIGNATURE_LENGTH)
+    }
+
+    fn metainfo_len(&self) -> usize {
+        if self.is_v2() {
+            self.read_u32(V2_LENGTH_OFFSET) as usize
+        } else {
+            self.read_u16(6) as usize
+        }
+    }
+
     fn set_metainfo_len(&mut self, len: usize) {
-        self.write_u16(6, len as u16);
+        if self.is_v2() {
+            self.write_u32(V2_LENGTH_OFFSET, len as u32);
+        } else {
+            self.write_u16(6, len as u16);
+        }
+    }
+
+    fn is_metainfo_len_valid(&self) -> bool {
+        let mlen = self.metainfo_len();
+        mlen > 0 && mlen < self.max_metainfo_len()
+    }
+
+    fn calculate_crc(&self) -> u32 {
+        let end = if self.is_metainfo_len_valid() {
+            self.metainfo_offset() + self.metainfo_len() + SIGNATURE_LENGTH
+        } else {
+            self.size()
+        };
+        let mut bytes = self.read_bytes(0, end);
+        bytes[V2_CRC_OFFSET..V2_CRC_OFFSET + 4].iter_mut().for_each(|b| *b = 0);
+        crc32(&bytes)
+    }
+
+    fn update_crc(&mut self) {
+        if self.is_v2() {
+            let crc = self.calculate_crc();
+            self.write_u32(V2_CRC_OFFSET, crc);
+        }
+    }
+
+    fn is_crc_valid(&self) -> bool {
+        !self.is_v2() || self.read_u32(V2_CRC_OFFSET) == self.calculate_crc()
     }
 
     fn write_bytes(&mut self, offset: usize, data: &[u8]) {
@@ -126,6 +304,26 @@ impl HeaderBytes {
     }
 
 }
+
+// Bitwise CRC32 with the IEEE polynomial. Only ever run over a single header block.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+const FLAG_NAMES: [(u8, &str); 4] = [
+    (ImageHeader::FLAG_PREFER_BOOT, "PREFER_BOOT"),
+    (ImageHeader::FLAG_HASH_TREE, "HASH_TREE"),
+    (ImageHeader::FLAG_DATA_COMPRESSED, "DATA_COMPRESSED"),
+    (ImageHeader::FLAG_KEEP_COMPRESSED, "KEEP_COMPRESSED"),
+];
+
 const CODE_TO_LABEL: [&str; 7] = [
     "Invalid",
     "New",
@@ -140,6 +338,7 @@ impl ImageHeader {
     pub const FLAG_PREFER_BOOT: u8 = 0x01; // Set to override usual strategy for choosing a partition to boot and force this one.
     pub const FLAG_HASH_TREE: u8 = 0x02; // dm-verity hash tree data is appended to the image
     pub const FLAG_DATA_COMPRESSED: u8 = 0x04; // The image data is compressed and needs to be uncompressed before use.
+    pub const FLAG_KEEP_COMPRESSED: u8 = 0x08; // The image file is stored compressed and is uncompressed to a temporary copy when mounted.
 
     pub const STATUS_INVALID: u8 = 0; // Set on partition before writing a new rootfs disk image
     pub const STATUS_NEW: u8 = 1; // Set on partition after write of new rootfs disk image completes successfully
@@ -149,9 +348,16 @@ impl ImageHeader {
     pub const STATUS_BAD_SIG: u8 = 5; // Set on boot selected partition when signature fails to verify
     pub const STATUS_BAD_META: u8 = 6; // Set on partition when metainfo cannot be parsed
 
-    /// Size of header block
+    /// Size of header block, which is also the size of a version 1 header
     pub const HEADER_SIZE: usize = 4096;
+    /// Size of a version 2 header, which is the largest header layout
+    pub const MAX_HEADER_SIZE: usize = 2 * Self::HEADER_SIZE;
 
+    /// Header layout versions returned by `magic_version()`
+    pub const VERSION_1: u8 = 1;
+    pub const VERSION_2: u8 = 2;
+
+    /// Create an empty header with the version 2 layout
     pub fn new() -> Self {
         Self::default()
     }
@@ -202,11 +408,13 @@ impl ImageHeader {
     pub fn from_reader<R: Read>(r: &mut R) -> Result<Self> {
         let mut v = vec![0u8; Self::HEADER_SIZE];
         r.read_exact(&mut v)?;
+        let size = HeaderBytes::size_from_first_block(&v);
+        v.resize(size, 0);
+        r.read_exact(&mut v[Self::HEADER_SIZE..])?;
         Self::from_slice(&v)
     }
 
     fn from_slice(slice: &[u8]) -> Result<Self> {
-        assert_eq!(slice.len(), Self::HEADER_SIZE);
         let buffer = HeaderBytes::create_from_slice(slice);
         let metainfo = Mutex::new(None);
         let timestamp = AtomicIsize::new(0);
@@ -215,6 +423,9 @@ impl ImageHeader {
         Ok(header)
     }
 
+    /// Read the header stored at the end of a partition. The header blocks
+    /// are stored in reverse order so the first block is always the last 4096
+    /// bytes of the partition.
     pub fn from_partition<P: AsRef<Path>>(path: P) -> Result<Self> {
         let mut dev = BlockDev::open_ro(path.as_ref())?;
         let nsectors = dev.nsectors()?;
@@ -226,10 +437,47 @@ impl ImageHeader {
         );
         let mut buffer = AlignedBuffer::new(Self::HEADER_SIZE);
         dev.read_sectors(nsectors - 8, buffer.as_mut())?;
-        Self::from_slice(buffer.as_ref())
+        let mut v = buffer.as_ref().to_vec();
+        let size = HeaderBytes::size_from_first_block(&v);
+        ensure!(
+            nsectors >= size / 512,
+            "{} is too short ({} sectors) for an image header of {} bytes",
+            path.as_ref().display(),
+            nsectors,
+            size
+        );
+        for block in 1..size / Self::HEADER_SIZE {
+            dev.read_sectors(nsectors - 8 * (block + 1), buffer.as_mut())?;
+            v.extend_from_slice(buffer.as_ref());
+        }
+        Self::from_slice(&v)
     }
 
+    /// Write the header to the last 4096 byte blocks of a partition
     pub fn write_partition<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut dev = BlockDev::open_rw(path.as_ref())?;
+        let nsectors = dev.nsectors()?;
+        let mut lock = self.bytes_mut();
+        let size = lock.size();
+        ensure!(
+            nsectors >= size / 512,
+            "{} is a block device bit it's too short ({} sectors)",
+            path.as_ref().display(),
+            nsectors
+        );
+        lock.update_crc();
+        // Write the first block last so that a partially written header is not valid
+        for block in (0..size / Self::HEADER_SIZE).rev() {
+            let offset = block * Self::HEADER_SIZE;
+            let buffer = AlignedBuffer::from_slice(&lock.0[offset..offset + Self::HEADER_SIZE]);
+            dev.write_sectors(nsectors - 8 * (block + 1), buffer.as_ref())?;
+        }
+        Ok(())
+    }
+
+    /// Overwrite the header block of a partition with zeros so that the
+    /// partition is no longer considered initialized.
+    pub fn clear_partition<P: AsRef<Path>>(path: P) -> Result<()> {
         let mut dev = BlockDev::open_rw(path.as_ref())?;
         let nsectors = dev.nsectors()?;
         ensure!(
@@ -238,18 +486,16 @@ impl ImageHeader {
             path.as_ref().display(),
             nsectors
         );
-        let lock = self.bytes();
-        let buffer = AlignedBuffer::from_slice(&lock.0);
+        let buffer = AlignedBuffer::new(Self::HEADER_SIZE);
         dev.write_sectors(nsectors - 8, buffer.as_ref())?;
         Ok(())
     }
 
-
-    fn bytes(&self) -> RwLockReadGuard<HeaderBytes> {
+    fn bytes(&self) -> RwLockReadGuard<'_, HeaderBytes> {
         self.buffer.read().unwrap()
     }
 
-    fn bytes_mut(&self) -> RwLockWriteGuard<HeaderBytes> {
+    fn bytes_mut(&self) -> RwLockWriteGuard<'_, HeaderBytes> {
         self.buffer.write().unwrap()
     }
 
@@ -266,14 +512,23 @@ impl ImageHeader {
     }
 
     fn load_metainfo_if_magic_valid(&self) -> Result<()> {
-        if !self.is_magic_valid() {
+        if self.magic_version().is_none() {
+            if self.with_bytes(|bs| bs.read_bytes(0, 4) == MAGIC) {
+                return Err(InvalidHeaderError::new(format!("unsupported header version {}", self.read_u8(V2_VERSION_OFFSET))).into());
+            }
             return Ok(())
         }
+        if !self.with_bytes(|bs| bs.is_metainfo_len_valid()) {
+            return Err(InvalidHeaderError::new(format!("invalid metainfo length {}", self.metainfo_len())).into());
+        }
+        if !self.with_bytes(|bs| bs.is_crc_valid()) {
+            return Err(InvalidHeaderError::new("bad CRC").into());
+        }
 
         let mut lock = self.metainfo.lock().unwrap();
         let mb = self.metainfo_bytes();
         let metainfo = MetaInfo::parse_bytes(&mb)
-            .ok_or_else(|| format_err!("ImageHeader has invalid metainfo"))?;
+            .ok_or_else(|| InvalidHeaderError::new("invalid metainfo"))?;
         *lock = Some(Arc::new(metainfo));
         Ok(())
     }
@@ -283,8 +538,20 @@ impl ImageHeader {
         lock.as_ref().expect("Header has no metainfo set").clone()
     }
 
-    pub fn is_magic_valid(&self) -> bool {
-        self.with_bytes(|bs| bs.read_bytes(0,4) == MAGIC)
+    /// Returns the header layout version (`VERSION_1` or `VERSION_2`) if the
+    /// magic value is valid, or `None` if this is not a valid header.
+    pub fn magic_version(&self) -> Option<u8> {
+        self.with_bytes(|bs| bs.version())
+    }
+
+    /// Size of the header in bytes, which is the offset of the image data in an image file
+    pub fn size(&self) -> usize {
+        self.with_bytes(|bs| bs.size())
+    }
+
+    /// Size of the header in 4096 byte blocks
+    pub fn nblocks(&self) -> usize {
+        self.size() / Self::HEADER_SIZE
     }
 
     pub fn status(&self) -> u8 {
@@ -322,6 +589,31 @@ impl ImageHeader {
         self.change_flag(flag, false)
     }
 
+    /// Names of the flags which are set, such as `PREFER_BOOT`. Unknown flag
+    /// bits are listed as hex values.
+    pub fn flag_names(&self) -> Vec<String> {
+        let flags = self.flags();
+        (0..8).map(|bit| 1u8 << bit)
+            .filter(|&flag| flags & flag != 0)
+            .map(|flag| match Self::flag_name(flag) {
+                Some(name) => name.to_string(),
+                None => format!("0x{:02x}", flag),
+            })
+            .collect()
+    }
+
+    /// Name of a single `FLAG` value, such as `PREFER_BOOT` for `FLAG_PREFER_BOOT`
+    pub fn flag_name(flag: u8) -> Option<&'static str> {
+        FLAG_NAMES.iter().find(|&&(f,_)| f == flag).map(|&(_,name)| name)
+    }
+
+    /// Look up a `FLAG` value by name. The name is not case sensitive and
+    /// may use '-' in place of '_' (`prefer-boot`).
+    pub fn flag_from_name(name: &str) -> Option<u8> {
+        let name = name.to_ascii_uppercase().replace('-', "_");
+        FLAG_NAMES.iter().find(|&&(_,n)| n == name).map(|&(flag,_)| flag)
+    }
+
     fn change_flag(&self, flag: u8, set: bool) -> bool {
         let old = self.flags();
         let new = if set { old | flag } else { old & !flag };
@@ -330,7 +622,7 @@ impl ImageHeader {
     }
 
     pub fn metainfo_len(&self) -> usize {
-        self.read_u16(6) as usize
+        self.with_bytes(|bs| bs.metainfo_len())
     }
 
     pub fn set_metainfo_bytes(&self, bytes: &[u8]) -> Result<()> {
@@ -339,18 +631,24 @@ impl ImageHeader {
 
         let mut lock = self.metainfo.lock().unwrap();
         self.with_bytes_mut(|bs| {
-            bs.0.iter_mut().skip(8).for_each(|b| *b = 0);
+            if bytes.is_empty() || bytes.len() >= bs.max_metainfo_len() {
+                bail!("Metainfo document is too large for header ({} bytes)", bytes.len());
+            }
+            let offset = bs.metainfo_offset();
+            bs.0.iter_mut().skip(offset).for_each(|b| *b = 0);
             bs.set_metainfo_len(bytes.len());
-            bs.write_bytes(8,bytes);
-        });
+            bs.write_bytes(offset, bytes);
+            Ok(())
+        })?;
         *lock = Some(Arc::new(metainfo));
         Ok(())
     }
 
     pub fn metainfo_bytes(&self) -> Vec<u8> {
-        let mlen = self.metainfo_len();
-        assert!(mlen > 0 && mlen < MAX_METAINFO_LEN);
-        self.read_bytes(METAINFO_OFFSET, mlen)
+        self.with_bytes(|bs| {
+            assert!(bs.is_metainfo_len_valid());
+            bs.read_bytes(bs.metainfo_offset(), bs.metainfo_len())
+        })
     }
 
     pub fn has_signature(&self) -> bool {
@@ -358,17 +656,20 @@ impl ImageHeader {
     }
 
     pub fn signature(&self) -> Vec<u8> {
-        let mlen = self.metainfo_len();
-        assert!(mlen > 0 && mlen < MAX_METAINFO_LEN);
-        self.read_bytes(METAINFO_OFFSET + mlen, SIGNATURE_LENGTH)
+        self.with_bytes(|bs| {
+            assert!(bs.is_metainfo_len_valid());
+            bs.read_bytes(bs.metainfo_offset() + bs.metainfo_len(), SIGNATURE_LENGTH)
+        })
     }
 
     pub fn set_signature(&self, signature: &[u8]) -> Result<()> {
         if signature.len() != SIGNATURE_LENGTH {
             bail!("Signature has invalid length: {}", signature.len());
         }
-        let mlen = self.metainfo_len();
-        self.write_bytes(8 + mlen, signature);
+        self.with_bytes_mut(|bs| {
+            let offset = bs.metainfo_offset() + bs.metainfo_len();
+            bs.write_bytes(offset, signature);
+        });
         Ok(())
     }
 
@@ -386,7 +687,10 @@ impl ImageHeader {
     }
 
     pub fn write_header<W: Write>(&self, mut writer: W) -> Result<()> {
-        self.with_bytes(|bs| writer.write_all(&bs.0))?;
+        self.with_bytes_mut(|bs| {
+            bs.update_crc();
+            writer.write_all(&bs.0[..bs.size()])
+        })?;
         Ok(())
     }
 
@@ -402,17 +706,6 @@ impl ImageHeader {
         self.with_bytes_mut(|bs| bs.write_u8(idx, val))
     }
 
-    fn read_u16(&self, idx: usize) -> u16 {
-        self.with_bytes(|bs| bs.read_u16(idx))
-    }
-
-    fn write_bytes(&self, offset: usize, data: &[u8]) {
-        self.with_bytes_mut(|bs| bs.write_bytes(offset, data))
-    }
-
-    fn read_bytes(&self, offset: usize, len: usize) -> Vec<u8> {
-        self.with_bytes(|bs| bs.read_bytes(offset, len))
-    }
 }
 
 impl Default for ImageHeader {
@@ -438,6 +731,9 @@ pub struct MetaInfo {
     #[serde(rename = "kernel-id")]
     kernel_id: Option<String>,
 
+    #[serde(rename = "kernel-flavor")]
+    kernel_flavor: Option<String>,
+
     #[serde(rename = "realmfs-name")]
     realmfs_name: Option<String>,
 
@@ -461,11 +757,14 @@ pub struct MetaInfo {
 
     #[serde(default, rename = "verity-root")]
     verity_root: String,
+
+    #[serde(default)]
+    extra: BTreeMap<String, toml::Value>,
 }
 
 impl MetaInfo {
 
-    fn parse_bytes(bytes: &[u8]) -> Option<MetaInfo> {
+    pub(crate) fn parse_bytes(bytes: &[u8]) -> Option<MetaInfo> {
         toml::from_slice::<MetaInfo>(bytes).ok()
     }
 
@@ -492,6 +791,12 @@ impl MetaInfo {
         Self::str_ref(&self.kernel_id)
     }
 
+    /// Flavor of a kernel image such as `hardened`, or `standard` if the
+    /// metainfo does not have a `kernel-flavor` field.
+    pub fn kernel_flavor(&self) -> &str {
+        self.kernel_flavor.as_deref().unwrap_or(DEFAULT_KERNEL_FLAVOR)
+    }
+
     pub fn realmfs_name(&self) -> Option<&str> {
         Self::str_ref(&self.realmfs_name)
     }
@@ -527,5 +832,208 @@ impl MetaInfo {
     pub fn verity_tag(&self) -> String {
         self.verity_root().chars().take(8).collect()
     }
+
+    /// Value of a string field from the `[extra]` table of the metainfo. These
+    /// fields are informational only and are never used by citadel itself.
+    pub fn get_extra(&self, key: &str) -> Option<&str> {
+        self.extra.get(key).and_then(|v| v.as_str())
+    }
+
+    pub fn extra_keys(&self) -> impl Iterator<Item=&str> {
+        self.extra.keys().map(|k| k.as_str())
+    }
+
+    /// The `[extra]` table serialized so that it can be carried over when
+    /// a new metainfo document is generated for an image.
+    pub fn extra_section(&self) -> Result<String> {
+        extra_section(&self.extra)
+    }
+}
+
+#[test]
+fn test_header_versions() {
+    const TEST_METAINFO: &[u8] = b"image-type = \"rootfs\"\nchannel = \"dev\"\nversion = 3\n";
+
+    fn v1_header_bytes(metainfo: &[u8]) -> Vec<u8> {
+        let mut v = vec![0u8; ImageHeader::HEADER_SIZE];
+        v[..4].copy_from_slice(MAGIC);
+        v[4] = ImageHeader::STATUS_NEW;
+        v[6] = (metainfo.len() >> 8) as u8;
+        v[7] = metainfo.len() as u8;
+        v[8..8 + metainfo.len()].copy_from_slice(metainfo);
+        v[8 + metainfo.len()..8 + metainfo.len() + SIGNATURE_LENGTH].copy_from_slice(&[0xAB; SIGNATURE_LENGTH]);
+        v
+    }
+
+    fn header_to_vec(header: &ImageHeader) -> Vec<u8> {
+        let mut v = Vec::new();
+        header.write_header(&mut v).unwrap();
+        v
+    }
+
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+    // new headers are created with the version 2 layout
+    let header = ImageHeader::new();
+    header.set_metainfo_bytes(TEST_METAINFO).unwrap();
+    header.set_signature(&[0x11; SIGNATURE_LENGTH]).unwrap();
+    header.set_flag(ImageHeader::FLAG_HASH_TREE);
+    let bytes = header_to_vec(&header);
+    assert_eq!(bytes.len(), ImageHeader::MAX_HEADER_SIZE);
+    assert_eq!(header.size(), ImageHeader::MAX_HEADER_SIZE);
+    assert_eq!(header.nblocks(), 2);
+    assert_eq!(&bytes[6..8], &[V2_MARKER, ImageHeader::VERSION_2]);
+    assert_eq!(&bytes[V2_METAINFO_OFFSET..V2_METAINFO_OFFSET + TEST_METAINFO.len()], TEST_METAINFO);
+
+    let v2 = ImageHeader::from_reader(&mut &bytes[..]).unwrap();
+    assert_eq!(v2.magic_version(), Some(ImageHeader::VERSION_2));
+    assert_eq!(v2.metainfo_bytes(), TEST_METAINFO);
+    assert_eq!(v2.metainfo().version(), 3);
+    assert_eq!(v2.signature(), vec![0x11; SIGNATURE_LENGTH]);
+    assert!(v2.has_flag(ImageHeader::FLAG_HASH_TREE));
+    assert_eq!(v2.flag_names(), vec!["HASH_TREE"]);
+    v2.set_flag(ImageHeader::FLAG_PREFER_BOOT | 0x40);
+    assert_eq!(v2.flag_names(), vec!["PREFER_BOOT", "HASH_TREE", "0x40"]);
+    assert_eq!(ImageHeader::flag_from_name("prefer-boot"), Some(ImageHeader::FLAG_PREFER_BOOT));
+    assert_eq!(ImageHeader::flag_from_name("DATA_COMPRESSED"), Some(ImageHeader::FLAG_DATA_COMPRESSED));
+    assert_eq!(ImageHeader::flag_from_name("0x40"), None);
+    assert_eq!(ImageHeader::flag_name(ImageHeader::FLAG_HASH_TREE), Some("HASH_TREE"));
+    v2.clear_flag(ImageHeader::FLAG_PREFER_BOOT | 0x40);
+
+    // changing status updates crc when written
+    v2.set_status(ImageHeader::STATUS_GOOD);
+    let rewritten = header_to_vec(&v2);
+    assert_ne!(&rewritten[V2_CRC_OFFSET..V2_CRC_OFFSET + 4], &bytes[V2_CRC_OFFSET..V2_CRC_OFFSET + 4]);
+    assert_eq!(ImageHeader::from_reader(&mut &rewritten[..]).unwrap().status(), ImageHeader::STATUS_GOOD);
+
+    // version 1 headers are read and written back without changing layout
+    let v1 = ImageHeader::from_reader(&mut &v1_header_bytes(TEST_METAINFO)[..]).unwrap();
+    assert_eq!(v1.magic_version(), Some(ImageHeader::VERSION_1));
+    assert_eq!(v1.metainfo_len(), TEST_METAINFO.len());
+    assert_eq!(v1.metainfo().channel(), "dev");
+    assert_eq!(v1.signature(), vec![0xAB; SIGNATURE_LENGTH]);
+    v1.set_flag(ImageHeader::FLAG_PREFER_BOOT);
+    v1.set_status(ImageHeader::STATUS_TRY_BOOT);
+    let new_metainfo = b"image-type = \"rootfs\"\nchannel = \"dev\"\nversion = 4\n";
+    v1.set_metainfo_bytes(new_metainfo).unwrap();
+    v1.set_signature(&[0x22; SIGNATURE_LENGTH]).unwrap();
+    let bytes = header_to_vec(&v1);
+    assert_eq!(bytes.len(), ImageHeader::HEADER_SIZE);
+    assert_eq!(v1.nblocks(), 1);
+    assert_eq!(bytes[6..8], [0, new_metainfo.len() as u8]);
+    assert_eq!(&bytes[8..8 + new_metainfo.len()], &new_metainfo[..]);
+    let v1 = ImageHeader::from_reader(&mut &bytes[..]).unwrap();
+    assert_eq!(v1.magic_version(), Some(ImageHeader::VERSION_1));
+    assert_eq!(v1.status(), ImageHeader::STATUS_TRY_BOOT);
+    assert!(v1.has_flag(ImageHeader::FLAG_PREFER_BOOT));
+    assert_eq!(v1.metainfo().version(), 4);
+    assert_eq!(v1.signature(), vec![0x22; SIGNATURE_LENGTH]);
+
+    let good = header_to_vec(&{
+        let h = ImageHeader::new();
+        h.set_metainfo_bytes(TEST_METAINFO).unwrap();
+        h
+    });
+
+    // truncated input
+    assert!(ImageHeader::from_reader(&mut &good[..100]).is_err());
+    assert!(ImageHeader::from_reader(&mut &good[..ImageHeader::HEADER_SIZE - 1]).is_err());
+    assert!(ImageHeader::from_reader(&mut &good[..ImageHeader::HEADER_SIZE]).is_err());
+    let dir = crate::util::TempDir::new("header-test").unwrap();
+    let path = dir.join("header");
+    std::fs::write(&path, &good[..2048]).unwrap();
+    assert!(ImageHeader::from_file(&path).is_err());
+    std::fs::write(&path, &good).unwrap();
+    assert_eq!(ImageHeader::from_file(&path).unwrap().magic_version(), Some(ImageHeader::VERSION_2));
+
+    // wrong magic is not an error but the header is not valid
+    for bytes in &[good.clone(), v1_header_bytes(TEST_METAINFO)] {
+        let mut bad = bytes.clone();
+        bad[..4].copy_from_slice(b"SGOX");
+        assert_eq!(ImageHeader::from_reader(&mut &bad[..]).unwrap().magic_version(), None);
+    }
+    assert_eq!(ImageHeader::from_reader(&mut &vec![0u8; 4096][..]).unwrap().magic_version(), None);
+
+    // corrupting any byte covered by the crc of a version 2 header is detected
+    let region = V2_METAINFO_OFFSET + TEST_METAINFO.len() + SIGNATURE_LENGTH;
+    for &idx in &[4, 5, V2_CRC_OFFSET, V2_METAINFO_OFFSET + 3, region - 1] {
+        let mut bad = good.clone();
+        bad[idx] ^= 0x01;
+        assert!(ImageHeader::from_reader(&mut &bad[..]).is_err(), "corruption at offset {} not detected", idx);
+    }
+    // bytes after the signature are not covered
+    let mut padded = good.clone();
+    padded[region] = 0xFF;
+    assert!(ImageHeader::from_reader(&mut &padded[..]).is_ok());
+
+    // version 1 headers have no crc so corrupted status is accepted
+    let mut v1 = v1_header_bytes(TEST_METAINFO);
+    v1[4] = ImageHeader::STATUS_FAILED;
+    assert_eq!(ImageHeader::from_reader(&mut &v1[..]).unwrap().status(), ImageHeader::STATUS_FAILED);
+
+    // unsupported version and bad lengths are errors
+    let mut bad = good.clone();
+    bad[V2_VERSION_OFFSET] = 3;
+    assert!(ImageHeader::from_reader(&mut &bad[..]).is_err());
+    let mut bad = good.clone();
+    bad[V2_LENGTH_OFFSET] = 0x7F;
+    assert!(ImageHeader::from_reader(&mut &bad[..]).is_err());
+    let mut bad = v1_header_bytes(TEST_METAINFO);
+    bad[6] = 0x0F;
+    bad[7] = 0xFF;
+    assert!(ImageHeader::from_reader(&mut &bad[..]).is_err());
+    let mut bad = v1_header_bytes(TEST_METAINFO);
+    bad[6] = 0;
+    bad[7] = 0;
+    assert!(ImageHeader::from_reader(&mut &bad[..]).is_err());
+
+    // metainfo which does not fit in a version 1 header fits in the second block of a version 2 header
+    let big = format!("image-type = \"rootfs\"\n# {}\n", "x".repeat(6000)).into_bytes();
+    let v1 = ImageHeader::from_reader(&mut &v1_header_bytes(TEST_METAINFO)[..]).unwrap();
+    assert!(v1.set_metainfo_bytes(&big).is_err());
+    let header = ImageHeader::new();
+    header.set_metainfo_bytes(&big).unwrap();
+    header.set_signature(&[0x33; SIGNATURE_LENGTH]).unwrap();
+    let bytes = header_to_vec(&header);
+    let reread = ImageHeader::from_reader(&mut &bytes[..]).unwrap();
+    assert_eq!(reread.metainfo_bytes(), big);
+    assert_eq!(reread.signature(), vec![0x33; SIGNATURE_LENGTH]);
+    let mut bad = bytes.clone();
+    bad[ImageHeader::HEADER_SIZE + 100] ^= 0x01;
+    let err = ImageHeader::from_reader(&mut &bad[..]).err().unwrap();
+    assert!(err.downcast_ref::<InvalidHeaderError>().is_some());
+
+    // metainfo which does not fit is rejected by both layouts
+    let too_big = format!("image-type = \"rootfs\"\n# {}\n", "x".repeat(8100)).into_bytes();
+    assert!(ImageHeader::new().set_metainfo_bytes(&too_big).is_err());
 }
 
+#[test]
+fn test_metainfo_extra() {
+    let doc = b"image-type = \"extra\"\nchannel = \"dev\"\nversion = 2\n[extra]\nbuild-id = \"1234\"\ncompliance = \"FIPS \\\"140\\\"\"\ncount = 5\n";
+    let header = ImageHeader::new();
+    header.set_metainfo_bytes(doc).unwrap();
+    let metainfo = header.metainfo();
+    assert_eq!(metainfo.channel(), "dev");
+    assert_eq!(metainfo.get_extra("build-id"), Some("1234"));
+    assert_eq!(metainfo.get_extra("compliance"), Some("FIPS \"140\""));
+    assert_eq!(metainfo.get_extra("count"), None);
+    assert_eq!(metainfo.get_extra("missing"), None);
+    assert_eq!(metainfo.extra_keys().collect::<Vec<_>>(), vec!["build-id", "compliance", "count"]);
+
+    // regenerating a metainfo document with the extra section round trips all values
+    let mut regenerated = b"image-type = \"extra\"\n".to_vec();
+    regenerated.extend(metainfo.extra_section().unwrap().as_bytes());
+    let copy = MetaInfo::parse_bytes(&regenerated).unwrap();
+    assert_eq!(copy.extra_section().unwrap(), metainfo.extra_section().unwrap());
+    assert_eq!(copy.get_extra("compliance"), Some("FIPS \"140\""));
+    assert_eq!(MetaInfo::parse_bytes(b"image-type = \"extra\"\n").unwrap().extra_section().unwrap(), "");
+
+    // setting flags and rewriting the header keeps the extra fields
+    header.set_flag(ImageHeader::FLAG_HASH_TREE);
+    let mut bytes = Vec::new();
+    header.write_header(&mut bytes).unwrap();
+    let reread = ImageHeader::from_reader(&mut &bytes[..]).unwrap();
+    assert_eq!(reread.metainfo_bytes(), &doc[..]);
+    assert_eq!(reread.metainfo().get_extra("build-id"), Some("1234"));
+}
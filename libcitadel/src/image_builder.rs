@@ -0,0 +1,420 @@
+use std::collections::BTreeMap;
+use std::fs::{self,File,OpenOptions};
+use std::io::{self,Write};
+use std::path::{Path,PathBuf};
+
+use failure::ResultExt;
+
+use crate::{ImageHeader,KeyPair,Result,util};
+use crate::header::{self,MAX_EXTRA_KEY_LEN};
+use crate::verity::Verity;
+
+const BLOCK_SIZE: usize = 4096;
+
+const IMAGE_TYPES: &[&str] = &["extra", "rootfs", "kernel", "realmfs"];
+
+///
+/// Metainfo fields describing an image built by `ResourceImageBuilder`.
+///
+/// The `nblocks`, `shasum`, `verity-salt` and `verity-root` fields are
+/// calculated by the builder from the image data. Entries in `extra` are
+/// written to the `[extra]` table of the metainfo.
+///
+#[derive(Clone,Debug,Default)]
+pub struct ImageInfo {
+    pub image_type: String,
+    pub channel: String,
+    pub version: u32,
+    pub timestamp: String,
+    pub kernel_version: Option<String>,
+    pub kernel_id: Option<String>,
+    pub kernel_flavor: Option<String>,
+    pub realmfs_name: Option<String>,
+    pub extra: BTreeMap<String, String>,
+}
+
+impl ImageInfo {
+    pub fn new(image_type: &str, channel: &str, version: u32, timestamp: &str) -> Self {
+        ImageInfo {
+            image_type: image_type.to_string(),
+            channel: channel.to_string(),
+            version,
+            timestamp: timestamp.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !IMAGE_TYPES.contains(&self.image_type.as_str()) {
+            bail!("Invalid image type '{}'", self.image_type);
+        }
+        if !util::is_valid_name(&self.channel, 64) {
+            bail!("Invalid channel name '{}'", self.channel);
+        }
+        if self.image_type == "kernel" && self.kernel_version.is_none() {
+            bail!("Cannot build 'kernel' image without kernel-version field");
+        }
+        if let Some(ref flavor) = self.kernel_flavor {
+            if !header::is_valid_kernel_flavor(flavor) {
+                bail!("Invalid kernel flavor '{}' (must be lowercase ascii letters and digits)", flavor);
+            }
+        }
+        if self.image_type == "realmfs" && self.realmfs_name.is_none() {
+            bail!("Cannot build 'realmfs' image without realmfs-name field");
+        }
+        let quoted = [Some(&self.timestamp), self.kernel_version.as_ref(), self.kernel_id.as_ref(), self.realmfs_name.as_ref()];
+        if quoted.iter().flatten().any(|s| s.contains(|c: char| c == '"' || c == '\\' || c.is_control())) {
+            bail!("Metainfo fields cannot contain quotes, backslashes or control characters");
+        }
+        if let Some(key) = self.extra.keys().find(|k| !header::is_valid_extra_key(k)) {
+            bail!("Invalid extra metainfo key '{}' (must be at most {} ascii alphanumeric, '-' or '_' characters)", key, MAX_EXTRA_KEY_LEN);
+        }
+        Ok(())
+    }
+
+    /// Filename for the image in the form `citadel-$type-$channel-$version.img`.
+    /// For kernel images the kernel version is added to the image type, preceded
+    /// by the kernel flavor unless it is the default flavor.
+    pub fn image_filename(&self) -> String {
+        let flavor = self.kernel_flavor.as_deref()
+            .filter(|&flavor| flavor != header::DEFAULT_KERNEL_FLAVOR);
+        let name = match (&self.kernel_version, flavor) {
+            (Some(version), Some(flavor)) => format!("{}-{}-{}", self.image_type, flavor, version),
+            (Some(version), None) => format!("{}-{}", self.image_type, version),
+            (None, _) => self.image_type.clone(),
+        };
+        format!("citadel-{}-{}-{:03}.img", name, self.channel, self.version)
+    }
+}
+
+/// Values calculated from the image data which complete the metainfo document
+#[derive(Default)]
+struct ImageData {
+    nblocks: usize,
+    shasum: String,
+    verity_salt: String,
+    verity_root: String,
+}
+
+///
+/// Builds a resource image file from a directory or from an existing squashfs image.
+///
+/// ```text
+/// let info = ImageInfo::new("extra", "dev", 3, "20190621120000");
+/// ResourceImageBuilder::from_directory("/build/extra", info)
+///     .compress(true)
+///     .build("/build/citadel-extra-dev-003.img")?;
+/// ```
+///
+/// The image data is padded to a multiple of 4096 bytes, and the sha256 and
+/// dm-verity root hash are calculated before the data is optionally compressed.
+/// The resulting file can be loaded with `ResourceImage::from_path()`.
+///
+pub struct ResourceImageBuilder {
+    info: ImageInfo,
+    source: PathBuf,
+    squashfs: bool,
+    compress: bool,
+    verity: bool,
+    hashtree: bool,
+    signing_key: Option<KeyPair>,
+}
+
+impl ResourceImageBuilder {
+
+    /// Build an image by running `mksquashfs` on the directory `source`
+    pub fn from_directory<P: AsRef<Path>>(source: P, info: ImageInfo) -> Self {
+        Self::new(source.as_ref(), info, false)
+    }
+
+    /// Build an image from the prebuilt squashfs image file `source`
+    pub fn from_squashfs<P: AsRef<Path>>(source: P, info: ImageInfo) -> Self {
+        Self::new(source.as_ref(), info, true)
+    }
+
+    fn new(source: &Path, info: ImageInfo, squashfs: bool) -> Self {
+        ResourceImageBuilder {
+            info, squashfs,
+            source: source.to_path_buf(),
+            compress: false,
+            verity: true,
+            hashtree: false,
+            signing_key: None,
+        }
+    }
+
+    /// Compress the image data with xz
+    pub fn compress(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Calculate the dm-verity salt and root hash with `veritysetup`. This is enabled
+    /// by default, and images built without it can only be mounted with `citadel.noverity`.
+    pub fn verity(mut self, verity: bool) -> Self {
+        self.verity = verity;
+        self
+    }
+
+    /// Append the dm-verity hash tree to the image so that it does not need to be
+    /// generated when the image is installed.
+    pub fn hashtree(mut self, hashtree: bool) -> Self {
+        self.hashtree = hashtree;
+        self
+    }
+
+    /// Sign the metainfo document with `keys`
+    pub fn signing_key(mut self, keys: KeyPair) -> Self {
+        self.signing_key = Some(keys);
+        self
+    }
+
+    /// Build the image and write it to `target`. Temporary files are created
+    /// in the same directory as `target`.
+    pub fn build<P: AsRef<Path>>(&self, target: P) -> Result<()> {
+        let target = target.as_ref();
+        self.info.validate()?;
+        if self.hashtree && !self.verity {
+            bail!("Cannot add dm-verity hash tree to an image built without verity");
+        }
+        let data = target.with_extension("data");
+        let result = self.build_with_data_file(target, &data);
+        for path in &[data.clone(), data.with_extension("data.xz"), data.with_extension("verity"), data.with_extension("tmp")] {
+            if path.exists() {
+                let _ = fs::remove_file(path);
+            }
+        }
+        result
+    }
+
+    fn build_with_data_file(&self, target: &Path, data: &Path) -> Result<()> {
+        self.create_data_file(data)?;
+        let nblocks = Self::pad_data_file(data)
+            .context("failed writing padding to image")?;
+
+        let mut image = ImageData { nblocks, ..Default::default() };
+        image.shasum = util::sha256(data)?;
+        info!("Sha256 of image data is {}", image.shasum);
+
+        if self.verity {
+            self.generate_verity(data, &mut image)
+                .context("failed generating dm-verity hash tree")?;
+        }
+
+        let header = self.generate_header(&image)?;
+
+        if self.compress {
+            info!("Compressing image data");
+            Self::prepend_empty_header(data, header.size())?;
+            util::xz_compress(data)?;
+            fs::rename(data.with_extension("data.xz"), data)?;
+        }
+
+        Self::write_image(target, &header, data)
+            .context(format!("failed to write image file {}", target.display()))?;
+        info!("Wrote image file {}", target.display());
+        Ok(())
+    }
+
+    fn create_data_file(&self, data: &Path) -> Result<()> {
+        if self.squashfs {
+            if !self.source.is_file() {
+                bail!("Source path '{}' does not exist or is not a regular file", self.source.display());
+            }
+            info!("Copying source file to {}", data.display());
+            fs::copy(&self.source, data)?;
+        } else {
+            if !self.source.is_dir() {
+                bail!("Source path '{}' does not exist or is not a directory", self.source.display());
+            }
+            info!("Creating squashfs image of {}", self.source.display());
+            cmd!("mksquashfs", "{} {} -noappend -quiet", self.source.display(), data.display())
+                .context(format!("failed to create squashfs image of {}", self.source.display()))?;
+        }
+        Ok(())
+    }
+
+    // Pad the data file to a multiple of the block size and return the number of blocks
+    fn pad_data_file(data: &Path) -> Result<usize> {
+        let len = data.metadata()?.len() as usize;
+        if len == 0 || !len.is_multiple_of(512) {
+            bail!("Image file size is not a multiple of sector size (512 bytes)");
+        }
+        let padlen = (BLOCK_SIZE - len % BLOCK_SIZE) % BLOCK_SIZE;
+        if padlen > 0 {
+            info!("Padding image with {} zero bytes to 4096 byte block boundary", padlen);
+            let mut file = OpenOptions::new().append(true).open(data)?;
+            file.write_all(&vec![0u8; padlen])?;
+        }
+        let nblocks = (len + padlen) / BLOCK_SIZE;
+        info!("Image contains {} blocks of data", nblocks);
+        Ok(nblocks)
+    }
+
+    // When a compressed image is decompressed the header replaces the start of
+    // the decompressed data, so empty space for the header is added before compressing.
+    fn prepend_empty_header(data: &Path, header_size: usize) -> Result<()> {
+        let tmpfile = data.with_extension("tmp");
+        let mut out = File::create(&tmpfile)?;
+        out.write_all(&vec![0u8; header_size])?;
+        io::copy(&mut File::open(data)?, &mut out)?;
+        fs::rename(&tmpfile, data)?;
+        Ok(())
+    }
+
+    fn generate_verity(&self, data: &Path, image: &mut ImageData) -> Result<()> {
+        let hashfile = data.with_extension("verity");
+        let output = Verity::new(data, 0).generate_initial_hashtree(&hashfile)?;
+        image.verity_root = match output.root_hash() {
+            Some(s) => s.to_owned(),
+            None => bail!("no root hash found in verity format output"),
+        };
+        image.verity_salt = match output.salt() {
+            Some(s) => s.to_owned(),
+            None => bail!("no verity salt found in verity format output"),
+        };
+        info!("Verity hash tree calculated, verity-root = {}", image.verity_root);
+        if self.hashtree {
+            let mut input = File::open(&hashfile)?;
+            let mut output = OpenOptions::new().append(true).open(data)?;
+            io::copy(&mut input, &mut output)?;
+        }
+        Ok(())
+    }
+
+    fn generate_header(&self, image: &ImageData) -> Result<ImageHeader> {
+        let metainfo = self.generate_metainfo(image)?;
+        let hdr = ImageHeader::new();
+        hdr.set_metainfo_bytes(&metainfo)?;
+        if self.compress {
+            hdr.set_flag(ImageHeader::FLAG_DATA_COMPRESSED);
+        }
+        if self.hashtree {
+            hdr.set_flag(ImageHeader::FLAG_HASH_TREE);
+        }
+        if let Some(ref keys) = self.signing_key {
+            hdr.set_signature(keys.sign(&metainfo).to_bytes())?;
+        }
+        Ok(hdr)
+    }
+
+    fn generate_metainfo(&self, image: &ImageData) -> Result<Vec<u8>> {
+        let info = &self.info;
+        let mut v = Vec::new();
+        writeln!(v, "image-type = \"{}\"", info.image_type)?;
+        if let Some(ref kv) = info.kernel_version {
+            writeln!(v, "kernel-version = \"{}\"", kv)?;
+        }
+        if let Some(ref kid) = info.kernel_id {
+            writeln!(v, "kernel-id = \"{}\"", kid)?;
+        }
+        if let Some(ref flavor) = info.kernel_flavor {
+            writeln!(v, "kernel-flavor = \"{}\"", flavor)?;
+        }
+        if let Some(ref name) = info.realmfs_name {
+            writeln!(v, "realmfs-name = \"{}\"", name)?;
+        }
+        writeln!(v, "channel = \"{}\"", info.channel)?;
+        writeln!(v, "version = {}", info.version)?;
+        writeln!(v, "timestamp = \"{}\"", info.timestamp)?;
+        writeln!(v, "nblocks = {}", image.nblocks)?;
+        writeln!(v, "shasum = \"{}\"", image.shasum)?;
+        writeln!(v, "verity-salt = \"{}\"", image.verity_salt)?;
+        writeln!(v, "verity-root = \"{}\"", image.verity_root)?;
+        write!(v, "{}", header::extra_section(&info.extra)?)?;
+        Ok(v)
+    }
+
+    fn write_image(target: &Path, header: &ImageHeader, data: &Path) -> Result<()> {
+        let mut out = File::create(target)?;
+        header.write_header(&out)?;
+        let mut data = File::open(data)?;
+        io::copy(&mut data, &mut out)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_image_builder() {
+    use crate::{ResourceImage,devkeys};
+
+    let dir = crate::util::TempDir::new("builder-test").unwrap();
+    let squashfs = dir.join("source.squashfs");
+    fs::write(&squashfs, (0..9000u32).map(|i| (i % 251) as u8).chain(vec![0; 216]).collect::<Vec<_>>()).unwrap();
+
+    let mut info = ImageInfo::new("kernel", "dev", 7, "20190621120000");
+    assert!(info.validate().is_err());
+    info.kernel_version = Some("5.1.4".to_string());
+    assert_eq!(info.image_filename(), "citadel-kernel-5.1.4-dev-007.img");
+    let mut hardened = info.clone();
+    hardened.kernel_flavor = Some("hardened".to_string());
+    assert_eq!(hardened.image_filename(), "citadel-kernel-hardened-5.1.4-dev-007.img");
+    hardened.kernel_flavor = Some("hard.ened".to_string());
+    assert!(hardened.validate().is_err());
+    info.extra.insert("build-id".to_string(), "a1b2 \"c3\"".to_string());
+    info.extra.insert("git_commit".to_string(), "0123abcd".to_string());
+
+    for &compress in &[false, true] {
+        let target = dir.join(info.image_filename());
+        ResourceImageBuilder::from_squashfs(&squashfs, info.clone())
+            .verity(false)
+            .compress(compress)
+            .signing_key(devkeys())
+            .build(&target)
+            .unwrap();
+        assert!(!target.with_extension("data").exists());
+
+        let image = ResourceImage::from_path(&target).unwrap();
+        let metainfo = image.metainfo();
+        assert_eq!((metainfo.image_type(), metainfo.channel(), metainfo.version()), ("kernel", "dev", 7));
+        assert_eq!((metainfo.kernel_version(), metainfo.timestamp()), (Some("5.1.4"), "20190621120000"));
+        assert_eq!(metainfo.kernel_flavor(), "standard");
+        assert_eq!(metainfo.nblocks(), 3);
+        assert_eq!(metainfo.get_extra("build-id"), Some("a1b2 \"c3\""));
+        assert_eq!(metainfo.extra_keys().collect::<Vec<_>>(), vec!["build-id", "git_commit"]);
+        assert_eq!(image.is_compressed(), compress);
+        assert_eq!(image.calculate_shasum().unwrap(), metainfo.shasum());
+        assert!(image.header().verify_signature(devkeys().public_key()));
+        assert_eq!(image.generate_shasum().unwrap(), metainfo.shasum());
+        assert_eq!(target.metadata().unwrap().len(), (3 * 4096 + image.header().size()) as u64);
+        fs::remove_file(&target).unwrap();
+    }
+
+    // The verity round trip needs veritysetup from cryptsetup, which is not
+    // available on every build host.
+    let have_veritysetup = std::env::var_os("PATH")
+        .map(|paths| std::env::split_paths(&paths).any(|p| p.join("veritysetup").exists()))
+        .unwrap_or(false);
+    if have_veritysetup {
+        for &hashtree in &[false, true] {
+            let target = dir.join(info.image_filename());
+            ResourceImageBuilder::from_squashfs(&squashfs, info.clone())
+                .hashtree(hashtree)
+                .signing_key(devkeys())
+                .build(&target)
+                .unwrap();
+            assert!(!target.with_extension("verity").exists());
+
+            let image = ResourceImage::from_path(&target).unwrap();
+            let metainfo = image.metainfo();
+            assert_eq!(metainfo.verity_root().len(), 64);
+            assert!(!metainfo.verity_salt().is_empty());
+            assert_eq!(image.has_verity_hashtree(), hashtree);
+            assert_eq!(image.calculate_shasum().unwrap(), metainfo.shasum());
+            let data_len = (3 * 4096 + image.header().size()) as u64;
+            assert_eq!(target.metadata().unwrap().len() > data_len, hashtree);
+            fs::remove_file(&target).unwrap();
+        }
+    }
+
+    for key in &["", "with space", "ünicode", &"k".repeat(MAX_EXTRA_KEY_LEN + 1)] {
+        let mut bad = ImageInfo::new("extra", "dev", 1, "x");
+        bad.extra.insert(key.to_string(), "value".to_string());
+        assert!(bad.validate().is_err(), "extra key '{}' accepted", key);
+    }
+
+    let bad = ImageInfo::new("extra", "dev\"", 1, "x");
+    assert!(ResourceImageBuilder::from_squashfs(&squashfs, bad).verity(false).build(dir.join("bad.img")).is_err());
+    assert!(ResourceImageBuilder::from_squashfs(dir.join("missing"), ImageInfo::new("extra", "dev", 1, "x"))
+        .verity(false).build(dir.join("missing.img")).is_err());
+}
@@ -7,7 +7,6 @@ use std::os::raw::c_char;
 
 use libc::{self,c_long,c_ulong, c_int, int32_t};
 
-use hex;
 use sodiumoxide::randombytes::randombytes_into;
 use sodiumoxide::crypto::{
     sign::{
@@ -21,11 +20,65 @@ use sodiumoxide::crypto::{
     },
 };
 
-use crate::{Result,Error,KeyPair};
+use crate::{Result,Error,KeyPair,PublicKey,Signature,util};
+
+const MAX_KEY_NAME_LEN: usize = 64;
+
+// Key used by `KeyRing::sign()` and `KeyRing::verify()`
+const SIGNING_KEY_NAME: &str = "realmfs-user";
+
+// Backup file format:
+//
+//   magic | version (1 byte) | opslimit (u64 BE) | memlimit (u64 BE) | salt | nonce | ciphertext
+//
+// The KDF parameters are stored in the backup so that they can be changed
+// independently of the parameters used for the keyring file.
+const BACKUP_MAGIC: &[u8] = b"CITADEL-KEYRING-BACKUP";
+const BACKUP_VERSION: u8 = 1;
+const BACKUP_HEADER_LEN: usize = BACKUP_MAGIC.len() + 1 + 8 + 8 + SALTBYTES + NONCEBYTES;
+// Backups are stored outside of the encrypted storage partition so use
+// stronger KDF parameters than for the keyring file.
+const BACKUP_OPSLIMIT: usize = pwhash::OPSLIMIT_INTERACTIVE.0 * 4;
+const BACKUP_MEMLIMIT: usize = pwhash::MEMLIMIT_INTERACTIVE.0 * 4;
 
 #[derive(Serialize,Deserialize,Debug)]
 pub struct KeyRing {
+    // hex encoded ed25519 seeds
     keypairs: HashMap<String, String>,
+    // hex encoded secrets added with `KeyRing::add_key()`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    secrets: HashMap<String, String>,
+}
+
+/// Describes a key stored in a `KeyRing` without exposing the secret key.
+#[derive(Serialize,Clone,Debug)]
+pub struct KeyInfo {
+    name: String,
+    purpose: String,
+    fingerprint: Option<String>,
+}
+
+impl KeyInfo {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn purpose(&self) -> &str {
+        &self.purpose
+    }
+
+    /// Hex encoded public key if the key is a key pair, or `None` if it is a secret
+    pub fn fingerprint(&self) -> Option<&str> {
+        self.fingerprint.as_deref()
+    }
+
+    fn purpose_for_name(name: &str) -> &'static str {
+        match name {
+            "realmfs-user" => "Signing RealmFS images modified by the user and realm exports",
+            n if n.starts_with("realm-home-") => "Unlocking the encrypted home directory of a realm",
+            _ => "Unknown",
+        }
+    }
 }
 
 impl KeyRing {
@@ -33,7 +86,7 @@ impl KeyRing {
         let seed = Self::new_random_seed();
         let mut keypairs = HashMap::new();
         keypairs.insert("realmfs-user".to_string(), hex::encode(&seed.0));
-        KeyRing { keypairs }
+        KeyRing { keypairs, secrets: HashMap::new() }
     }
 
     pub fn load<P: AsRef<Path>>(path: P, passphrase: &str) -> Result<Self> {
@@ -45,16 +98,121 @@ impl KeyRing {
         Ok(keyring)
     }
 
+    /// Return a description of each key in the keyring, sorted by name.
+    pub fn list_keys(&self) -> Vec<KeyInfo> {
+        let keypairs = self.keypairs.iter().map(|(name, seed)| {
+            let fingerprint = KeyPair::from_hex(seed)
+                .map(|kp| kp.public_key().to_hex())
+                .unwrap_or_else(|_| "invalid key".to_string());
+            (name, Some(fingerprint))
+        });
+        let secrets = self.secrets.keys().map(|name| (name, None));
+        let mut keys = keypairs.chain(secrets).map(|(name, fingerprint)| KeyInfo {
+            name: name.clone(),
+            purpose: KeyInfo::purpose_for_name(name).to_string(),
+            fingerprint,
+        }).collect::<Vec<_>>();
+        keys.sort_by(|a, b| a.name.cmp(&b.name));
+        keys
+    }
+
+    fn signing_key(&self) -> Result<KeyPair> {
+        match self.keypairs.get(SIGNING_KEY_NAME) {
+            Some(seed) => KeyPair::from_hex(seed),
+            None => bail!("No {} key in keyring", SIGNING_KEY_NAME),
+        }
+    }
+
+    /// Sign `data` with the user signing key of the keyring (ed25519). The public
+    /// key is returned with the signature so that it can be stored beside it.
+    pub fn sign(&self, data: &[u8]) -> Result<(PublicKey, Signature)> {
+        let keypair = self.signing_key()?;
+        Ok((keypair.public_key(), keypair.sign(data)))
+    }
+
+    /// Returns true if `signature` is a signature of `data` made with the user
+    /// signing key of this keyring.
+    pub fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+        self.signing_key()
+            .map(|keypair| keypair.verify(data, signature))
+            .unwrap_or(false)
+    }
+
+    /// Write an encrypted backup of the keyring to `path`. The backup file is
+    /// self-describing and can be restored with `import_backup()` on any system.
+    pub fn export_backup<P: AsRef<Path>>(&self, path: P, passphrase: &str) -> Result<()> {
+        let salt = pwhash::gen_salt();
+        let nonce = secretbox::gen_nonce();
+        let (ops, mem) = (pwhash::OpsLimit(BACKUP_OPSLIMIT), pwhash::MemLimit(BACKUP_MEMLIMIT));
+        let key = SecretBox::passphrase_to_key_with_limits(passphrase, &salt, ops, mem)?;
+        let mut bytes = toml::to_vec(self)?;
+        let ciphertext = secretbox::seal(&bytes, &nonce, &key);
+        bytes.iter_mut().for_each(|b| *b = 0);
+
+        let mut file = fs::File::create(path.as_ref())?;
+        file.write_all(BACKUP_MAGIC)?;
+        file.write_all(&[BACKUP_VERSION])?;
+        file.write_all(&(ops.0 as u64).to_be_bytes())?;
+        file.write_all(&(mem.0 as u64).to_be_bytes())?;
+        file.write_all(&salt.0)?;
+        file.write_all(&nonce.0)?;
+        file.write_all(&ciphertext)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Decrypt and return a keyring from a backup file created with `export_backup()`.
+    pub fn import_backup<P: AsRef<Path>>(path: P, passphrase: &str) -> Result<Self> {
+        let path = path.as_ref();
+        let data = fs::read(path)
+            .map_err(|e| format_err!("Error reading keyring backup {}: {}", path.display(), e))?;
+        if data.len() < BACKUP_HEADER_LEN || !data.starts_with(BACKUP_MAGIC) {
+            bail!("{} is not a keyring backup file", path.display());
+        }
+        let mut offset = BACKUP_MAGIC.len();
+        let version = data[offset];
+        if version != BACKUP_VERSION {
+            bail!("Keyring backup {} has unsupported version {}", path.display(), version);
+        }
+        offset += 1;
+
+        let read_u64 = |offset: usize| {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(&data[offset..offset + 8]);
+            u64::from_be_bytes(buf) as usize
+        };
+        let ops = pwhash::OpsLimit(read_u64(offset));
+        let mem = pwhash::MemLimit(read_u64(offset + 8));
+        offset += 16;
+        if ops.0 > pwhash::OPSLIMIT_SENSITIVE.0 || mem.0 > pwhash::MEMLIMIT_SENSITIVE.0 {
+            bail!("Keyring backup {} has invalid KDF parameters", path.display());
+        }
+
+        let mut salt = Salt([0; SALTBYTES]);
+        salt.0.copy_from_slice(&data[offset..offset + SALTBYTES]);
+        offset += SALTBYTES;
+        let mut nonce = Nonce([0; NONCEBYTES]);
+        nonce.0.copy_from_slice(&data[offset..offset + NONCEBYTES]);
+        offset += NONCEBYTES;
+
+        let key = SecretBox::passphrase_to_key_with_limits(passphrase, &salt, ops, mem)?;
+        let mut bytes = secretbox::open(&data[offset..], &nonce, &key)
+            .map_err(|_| format_err!("Failed to decrypt keyring backup {}", path.display()))?;
+        let keyring = toml::from_slice::<KeyRing>(&bytes);
+        bytes.iter_mut().for_each(|b| *b = 0);
+        Ok(keyring?)
+    }
+
     pub fn load_with_cryptsetup_passphrase<P: AsRef<Path>>(path: P) -> Result<Self> {
         let passphrase = Self::get_cryptsetup_passphrase()?;
         Self::load(path, &passphrase)
     }
 
-    fn get_cryptsetup_passphrase() -> Result<String> {
+    pub(crate) fn get_cryptsetup_passphrase() -> Result<String> {
         let key = Self::get_key("cryptsetup")?;
         info!("Got key {}", key.0);
         let buf = key.read()?;
-        match buf.split(|b| *b == 0).map(|bs| String::from_utf8_lossy(bs).to_string()).last() {
+        match buf.split(|b| *b == 0).map(|bs| String::from_utf8_lossy(bs).to_string()).next_back() {
             Some(s) => Ok(s),
             None => Ok(String::new()),
         }
@@ -77,21 +235,101 @@ impl KeyRing {
     }
 
     pub fn add_keys_to_kernel(&self) -> Result<()> {
-        for (k,v) in self.keypairs.iter() {
-            info!("Adding {} to kernel keystore", k.as_str());
-            let bytes = hex::decode(v)?;
-            let key = KernelKey::add_key("user", k.as_str(), &bytes, KEY_SPEC_USER_KEYRING)?;
-            key.set_perm(0x3f03_0000)?;
+        for (k,v) in self.keypairs.iter().chain(self.secrets.iter()) {
+            Self::add_key_to_kernel(k, v)?;
+        }
+        Ok(())
+    }
+
+    fn add_key_to_kernel(name: &str, hexval: &str) -> Result<()> {
+        info!("Adding {} to kernel keystore", name);
+        let mut bytes = hex::decode(hexval)?;
+        let key = KernelKey::add_key("user", name, &bytes, KEY_SPEC_USER_KEYRING);
+        bytes.iter_mut().for_each(|b| *b = 0);
+        key?.set_perm(0x3f03_0000)?;
+        Ok(())
+    }
+
+    /// Add a new key `name` to the keyring file at `path` and to the kernel keyring.
+    /// Key names follow the same rules as realm names and must not already exist
+    /// in the keyring.
+    pub fn add_key<P: AsRef<Path>>(path: P, passphrase: &str, name: &str, secret: &[u8]) -> Result<()> {
+        if !util::is_valid_name(name, MAX_KEY_NAME_LEN) {
+            bail!("Invalid key name '{}'", name);
+        }
+        let path = path.as_ref();
+        let mut keyring = Self::load(path, passphrase)?;
+        if keyring.keypairs.contains_key(name) || keyring.secrets.contains_key(name) {
+            bail!("A key named '{}' already exists in keyring", name);
+        }
+        let hexval = hex::encode(secret);
+        keyring.secrets.insert(name.to_string(), hexval.clone());
+        keyring.write_atomic(path, passphrase)?;
+        Self::add_key_to_kernel(name, &hexval)
+    }
+
+    /// Add a new key `name` to the keyring file at `path` and to the kernel keyring
+    /// using the passphrase of the storage partition from the kernel keyring.
+    pub fn add_key_with_cryptsetup_passphrase<P: AsRef<Path>>(path: P, name: &str, secret: &[u8]) -> Result<()> {
+        let passphrase = Self::get_cryptsetup_passphrase()?;
+        Self::add_key(path, &passphrase, name, secret)
+    }
+
+    /// Remove key `name` from the keyring file at `path` and from the kernel keyring.
+    pub fn remove_key<P: AsRef<Path>>(path: P, passphrase: &str, name: &str) -> Result<()> {
+        let path = path.as_ref();
+        let mut keyring = Self::load(path, passphrase)?;
+        match keyring.keypairs.remove(name).or_else(|| keyring.secrets.remove(name)) {
+            Some(v) => v.into_bytes().iter_mut().for_each(|b| *b = 0),
+            None => bail!("No key named '{}' in keyring", name),
+        }
+        keyring.write_atomic(path, passphrase)?;
+        match KernelKey::user_keyring().search(name) {
+            Ok(key) => key.invalidate()?,
+            Err(_) => info!("Key {} was not present in kernel keyring", name),
         }
         Ok(())
     }
 
+    /// Read the secret of key `name` from the kernel keyring.
+    pub fn get_kernel_secret(name: &str) -> Result<Vec<u8>> {
+        Self::get_key(name)?.read()
+    }
+
     pub fn get_kernel_keypair(name: &str) -> Result<KeyPair> {
         let key = Self::get_key(name)?;
         let data = key.read()?;
         KeyPair::from_bytes(&data)
     }
 
+    /// Re-encrypt the keyring file at `path` with `new_passphrase`, using a newly
+    /// generated salt. The keyring is written to a temporary file which then
+    /// replaces the original file, and a copy of the previous keyring file is
+    /// kept as `path`.bak
+    pub fn change_passphrase<P: AsRef<Path>>(path: P, old_passphrase: &str, new_passphrase: &str) -> Result<()> {
+        let path = path.as_ref();
+        let keyring = Self::load(path, old_passphrase)?;
+        let tmp = path.with_extension("tmp");
+        keyring.write(&tmp, new_passphrase)?;
+        if let Err(e) = Self::load(&tmp, new_passphrase) {
+            let _ = fs::remove_file(&tmp);
+            bail!("Failed to verify re-encrypted keyring: {}", e);
+        }
+        fs::copy(path, path.with_extension("bak"))?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Write the keyring to a temporary file and then rename it to `path` so that
+    /// an existing keyring file is never left partially written.
+    pub fn write_atomic<P: AsRef<Path>>(&self, path: P, passphrase: &str) -> Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        self.write(&tmp, passphrase)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
     pub fn write<P: AsRef<Path>>(&self, path: P, passphrase: &str) -> Result<()> {
         let salt = pwhash::gen_salt();
         let nonce = secretbox::gen_nonce();
@@ -103,6 +341,7 @@ impl KeyRing {
         file.write_all(&salt.0)?;
         file.write_all(&nonce.0)?;
         file.write_all(&ciphertext)?;
+        file.sync_all()?;
         Ok(())
     }
 
@@ -116,7 +355,7 @@ impl KeyRing {
 
 impl Drop for KeyRing {
     fn drop(&mut self) {
-        for (_,v) in self.keypairs.drain() {
+        for (_,v) in self.keypairs.drain().chain(self.secrets.drain()) {
             v.into_bytes().iter_mut().for_each(|b| *b = 0);
         }
     }
@@ -158,8 +397,12 @@ impl SecretBox {
     }
 
     fn passphrase_to_key(passphrase: &str, salt: &Salt) -> Result<secretbox::Key> {
+        Self::passphrase_to_key_with_limits(passphrase, salt, pwhash::OPSLIMIT_INTERACTIVE, pwhash::MEMLIMIT_INTERACTIVE)
+    }
+
+    fn passphrase_to_key_with_limits(passphrase: &str, salt: &Salt, ops: pwhash::OpsLimit, mem: pwhash::MemLimit) -> Result<secretbox::Key> {
         let mut keybuf = [0; secretbox::KEYBYTES];
-        pwhash::derive_key(&mut keybuf, passphrase.as_bytes(), salt, pwhash::OPSLIMIT_INTERACTIVE, pwhash::MEMLIMIT_INTERACTIVE)
+        pwhash::derive_key(&mut keybuf, passphrase.as_bytes(), salt, ops, mem)
             .map_err(|_| format_err!("Failed to derive key"))?;
         Ok(secretbox::Key(keybuf))
     }
@@ -172,6 +415,7 @@ const KEYCTL_SETPERM             : c_int = 5;   // set perms on a key
 const KEYCTL_DESCRIBE            : c_int = 6;   // describe a key
 const KEYCTL_SEARCH              : c_int = 10;  // search for a key in a keyring
 const KEYCTL_READ                : c_int = 11;  // read a key or keyring's contents
+const KEYCTL_INVALIDATE          : c_int = 21;  // invalidate a key
 
 const KEY_SPEC_USER_KEYRING      : c_int = -4;  // - key ID for UID-specific keyring
 
@@ -227,6 +471,11 @@ impl KernelKey {
         Ok(KernelKey(serial as i32))
     }
 
+    pub fn invalidate(&self) -> Result<()> {
+        keyctl1(KEYCTL_INVALIDATE, self.id())?;
+        Ok(())
+    }
+
     pub fn read(&self) -> Result<Vec<u8>> {
         let mut size = 0;
         loop {
@@ -322,3 +571,105 @@ fn _add_key(key_type: *const c_char, description: *const c_char, payload: *const
         }
     }
 }
+
+#[test]
+fn test_change_passphrase() {
+    let dir = crate::util::TempDir::new("keyring-test").unwrap();
+    let path = dir.join("keyring");
+
+    let keyring = KeyRing::create_new();
+    keyring.write(&path, "old passphrase").unwrap();
+    let old_salt = fs::read(&path).unwrap()[..SALTBYTES].to_vec();
+
+    assert!(KeyRing::change_passphrase(&path, "wrong passphrase", "new passphrase").is_err());
+    KeyRing::change_passphrase(&path, "old passphrase", "new passphrase").unwrap();
+
+    let changed = KeyRing::load(&path, "new passphrase").unwrap();
+    assert_eq!(changed.keypairs, keyring.keypairs);
+    assert!(KeyRing::load(&path, "old passphrase").is_err());
+    assert_ne!(fs::read(&path).unwrap()[..SALTBYTES].to_vec(), old_salt);
+    assert!(KeyRing::load(path.with_extension("bak"), "old passphrase").is_ok());
+    assert!(!path.with_extension("tmp").exists());
+}
+
+#[test]
+fn test_keyring_backup() {
+    let dir = crate::util::TempDir::new("keyring-backup-test").unwrap();
+    let path = dir.join("keyring.backup");
+
+    let keyring = KeyRing::create_new();
+    keyring.export_backup(&path, "backup passphrase").unwrap();
+    let restored = KeyRing::import_backup(&path, "backup passphrase").unwrap();
+    assert_eq!(restored.keypairs, keyring.keypairs);
+    assert!(KeyRing::import_backup(&path, "wrong passphrase").is_err());
+
+    let keys = restored.list_keys();
+    assert_eq!(keys.len(), 1);
+    assert_eq!(keys[0].name(), "realmfs-user");
+    let seed = &keyring.keypairs["realmfs-user"];
+    assert_eq!(keys[0].fingerprint(), Some(KeyPair::from_hex(seed).unwrap().public_key().to_hex().as_str()));
+    assert_ne!(keys[0].fingerprint(), Some(seed.as_str()));
+
+    // corrupt the ciphertext
+    let mut data = fs::read(&path).unwrap();
+    let last = data.len() - 1;
+    data[last] ^= 0xFF;
+    fs::write(&path, &data).unwrap();
+    assert!(KeyRing::import_backup(&path, "backup passphrase").is_err());
+
+    // truncated and wrong version
+    fs::write(&path, &data[..BACKUP_HEADER_LEN - 1]).unwrap();
+    assert!(KeyRing::import_backup(&path, "backup passphrase").is_err());
+    data[BACKUP_MAGIC.len()] = BACKUP_VERSION + 1;
+    fs::write(&path, &data).unwrap();
+    let err = KeyRing::import_backup(&path, "backup passphrase").err().unwrap().to_string();
+    assert!(err.contains("unsupported version"), "{}", err);
+}
+
+#[test]
+fn test_keyring_sign() {
+    let keyring = KeyRing::create_new();
+    let (public_key, signature) = keyring.sign(b"manifest").unwrap();
+    assert!(keyring.verify(b"manifest", signature.to_bytes()));
+    assert!(public_key.verify(b"manifest", signature.to_bytes()));
+    assert!(!keyring.verify(b"tampered", signature.to_bytes()));
+    assert!(!keyring.verify(b"manifest", &signature.to_bytes()[1..]));
+    assert!(!KeyRing::create_new().verify(b"manifest", signature.to_bytes()));
+}
+
+#[test]
+fn test_add_key_validation() {
+    let dir = crate::util::TempDir::new("keyring-add-test").unwrap();
+    let path = dir.join("keyring");
+    KeyRing::create_new().write(&path, "passphrase").unwrap();
+
+    let err = KeyRing::add_key(&path, "passphrase", "realmfs-user", b"secret").err().unwrap().to_string();
+    assert!(err.contains("already exists"), "{}", err);
+    for name in &["", "1key", "bad name", "bad:name", "bad.name"] {
+        assert!(KeyRing::add_key(&path, "passphrase", name, b"secret").is_err(), "{}", name);
+    }
+    assert!(KeyRing::remove_key(&path, "passphrase", "missing").is_err());
+    assert_eq!(KeyRing::load(&path, "passphrase").unwrap().keypairs.len(), 1);
+}
+
+#[test]
+fn test_keyring_secrets() {
+    let dir = crate::util::TempDir::new("keyring-secrets-test").unwrap();
+    let path = dir.join("keyring");
+
+    let mut keyring = KeyRing::create_new();
+    keyring.secrets.insert("realm-home-main".to_string(), hex::encode(b"not a seed"));
+    keyring.write(&path, "passphrase").unwrap();
+
+    let loaded = KeyRing::load(&path, "passphrase").unwrap();
+    assert_eq!(loaded.keypairs, keyring.keypairs);
+    assert_eq!(loaded.secrets, keyring.secrets);
+    let keys = loaded.list_keys();
+    assert_eq!(keys.iter().map(|k| k.name()).collect::<Vec<_>>(), vec!["realm-home-main", "realmfs-user"]);
+    assert_eq!(keys[0].fingerprint(), None);
+    assert!(keys[1].fingerprint().is_some());
+
+    // keyring files written without secrets still load
+    KeyRing::create_new().write(&path, "passphrase").unwrap();
+    assert!(KeyRing::load(&path, "passphrase").unwrap().secrets.is_empty());
+}
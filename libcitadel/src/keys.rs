@@ -2,7 +2,6 @@ use crate::Result;
 
 use sodiumoxide::randombytes::randombytes_into;
 use sodiumoxide::crypto::sign::{self,Seed,SEEDBYTES,PUBLICKEYBYTES};
-use hex;
 
 ///
 /// Keys for signing or verifying signatures.  Small convenience
@@ -29,13 +28,16 @@ impl PublicKey {
     }
 
     pub fn to_hex(&self) -> String {
-        hex::encode(&(self.0).0)
+        hex::encode((self.0).0)
     }
 
+    /// Returns false if `signature` is not a valid signature of `data`, including
+    /// when it does not have the length of a signature.
     pub fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
-        let sig = sign::Signature::from_slice(signature)
-            .expect("Signature::from_slice() failed");
-        sign::verify_detached(&sig, data, &self.0)
+        match sign::Signature::from_slice(signature) {
+            Some(sig) => sign::verify_detached(&sig, data, &self.0),
+            None => false,
+        }
     }
 }
 
@@ -57,7 +59,7 @@ impl KeyPair {
         if bytes.len() != SEEDBYTES {
             bail!("Hex encoded keypair has incorrect length");
         }
-        let seed = sign::Seed::from_slice(&bytes).expect("Seed::from_slice() failed");
+        let seed = sign::Seed::from_slice(bytes).expect("Seed::from_slice() failed");
         Ok(KeyPair(seed))
     }
 
@@ -26,6 +26,8 @@ mod cmdline;
 mod header;
 mod partition;
 mod resource;
+mod storage;
+mod image_builder;
 pub mod util;
 pub mod verity;
 mod realmfs;
@@ -34,33 +36,50 @@ pub mod symlink;
 mod realm;
 pub mod terminal;
 mod system;
+mod metrics;
 
 
 pub use crate::config::OsRelease;
 pub use crate::blockdev::BlockDev;
 pub use crate::cmdline::CommandLine;
-pub use crate::header::{ImageHeader,MetaInfo};
+pub use crate::header::{ImageHeader,InvalidHeaderError,MetaInfo,DEFAULT_KERNEL_FLAVOR,is_valid_kernel_flavor};
 pub use crate::partition::Partition;
-pub use crate::resource::ResourceImage;
+pub use crate::resource::{ResourceImage,ResourceMount,MountGuard,MountedImage};
+pub use crate::image_builder::{ResourceImageBuilder,ImageInfo};
 pub use crate::keys::{KeyPair,PublicKey,Signature};
 pub use crate::realmfs::{RealmFS,Mountpoint,Activation};
-pub use crate::keyring::{KeyRing,KernelKey};
+pub use crate::keyring::{KeyRing,KernelKey,KeyInfo};
+pub use crate::storage::{BootStatus,BOOT_STATUS_PATH};
 pub use crate::exec::{Exec,FileRange};
 pub use crate::realmfs::resizer::{ImageResizer,ResizeSize};
 pub use crate::realm::overlay::RealmOverlay;
 pub use crate::realm::realm::Realm;
-pub use crate::realm::config::{RealmConfig,OverlayType,GLOBAL_CONFIG};
-pub use crate::realm::events::RealmEvent;
+pub use crate::realm::launcher::{LaunchPlan,LaunchReport,BindingResult};
+pub use crate::realm::config::{RealmConfig,OverlayType,HomeMode,ClipboardPolicy,GLOBAL_CONFIG};
+pub use crate::realm::events::{RealmEvent,EventMask,SubscriptionId};
 pub use crate::realm::realms::Realms;
 pub use crate::realm::manager::RealmManager;
-pub use crate::log::{LogLevel,Logger,DefaultLogOutput,LogOutput};
+pub use crate::realm::snapshot::RealmSnapshot;
+pub use crate::realm::home::{EncryptedHome,KeyringLockedError};
+pub use crate::realm::schema::{ConfigCheck,ConfigIssue};
+pub use crate::realm::defaults::{RealmDefaults,REALM_DEFAULTS_PATH};
+pub use crate::realm::systemd::ShellSpawnError;
+pub use crate::realm::network::{PortForward,Protocol,NetworkBlockState,NetworkZone,Firewall,network_allocations};
+pub use crate::realm::usb::{UsbDevice,UsbMatcher};
+pub use crate::realm::media::{RemovableMedia,HOST_MEDIA_PATH,REALM_MEDIA_PATH};
+pub use crate::realm::startup::RealmStartStatus;
+pub use crate::realm::resources::{StartThresholds,LowResourcesError};
+pub use crate::realm::quota::{HomeQuota,QuotaMode,QuotaExceededError};
+pub use crate::realm::manifest::{ExportManifest,ManifestEntry,ManifestTrust,ManifestVerifier,SignedManifest,VerifyingReader,MANIFEST_FILE};
+pub use crate::log::{LogLevel,LogBackend,Logger,DefaultLogOutput,JournalLogOutput,LogOutput,BoxedLogOutput};
 
-pub use crate::system::{FileLock,Mounts,LoopDevice,UtsName};
+pub use crate::system::{FileLock,Mounts,MountLine,LoopDevice,UtsName,SystemRoot};
+pub use crate::metrics::{Metrics,UPDATE_METRICS_PATH};
 
 const DEVKEYS_HEX: &str = "bc02a3a4fd4a0471a8cb2f96d8be0a0a2d060798c024e60d7a98482f23197fc0";
 
 pub fn devkeys() -> KeyPair {
-    KeyPair::from_hex(&DEVKEYS_HEX)
+    KeyPair::from_hex(DEVKEYS_HEX)
         .expect("Error parsing built in dev channel keys")
 }
 
@@ -1,6 +1,12 @@
 
+use std::collections::HashMap;
+use std::env;
+use std::fs;
 use std::sync::Mutex;
 use std::io::{self,Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
 
 use crate::Result;
 
@@ -10,35 +16,52 @@ lazy_static! {
 
 #[macro_export]
 macro_rules! debug {
-    ($e:expr) => { $crate::Logger::log($crate::LogLevel::Debug, String::from($e)) };
-    ($fmt:expr, $($arg:tt)+) => { $crate::Logger::log($crate::LogLevel::Debug, format!($fmt, $($arg)+)) };
+    ($e:expr) => { $crate::Logger::log_module(module_path!(), $crate::LogLevel::Debug, String::from($e)) };
+    ($fmt:expr, $($arg:tt)+) => { $crate::Logger::log_module(module_path!(), $crate::LogLevel::Debug, format!($fmt, $($arg)+)) };
 }
 
 #[macro_export]
 macro_rules! verbose {
-    ($e:expr) => { $crate::Logger::log($crate::LogLevel::Verbose, String::from($e)) };
-    ($fmt:expr, $($arg:tt)+) => { $crate::Logger::log($crate::LogLevel::Verbose, format!($fmt, $($arg)+)) };
+    ($e:expr) => { $crate::Logger::log_module(module_path!(), $crate::LogLevel::Verbose, String::from($e)) };
+    ($fmt:expr, $($arg:tt)+) => { $crate::Logger::log_module(module_path!(), $crate::LogLevel::Verbose, format!($fmt, $($arg)+)) };
 }
 
 #[macro_export]
 macro_rules! info {
-    ($e:expr) => { $crate::Logger::log($crate::LogLevel::Info, String::from($e)) };
-    ($fmt:expr, $($arg:tt)+) => { $crate::Logger::log($crate::LogLevel::Info, format!($fmt, $($arg)+)) };
+    ($e:expr) => { $crate::Logger::log_module(module_path!(), $crate::LogLevel::Info, String::from($e)) };
+    ($fmt:expr, $($arg:tt)+) => { $crate::Logger::log_module(module_path!(), $crate::LogLevel::Info, format!($fmt, $($arg)+)) };
 }
 
 #[macro_export]
 macro_rules! notify {
-    ($e:expr) => { $crate::Logger::log($crate::LogLevel::Notice, String::from($e)) };
-    ($fmt:expr, $($arg:tt)+) => { $crate::Logger::log($crate::LogLevel::Notice, format!($fmt, $($arg)+)) };
+    ($e:expr) => { $crate::Logger::log_module(module_path!(), $crate::LogLevel::Notice, String::from($e)) };
+    ($fmt:expr, $($arg:tt)+) => { $crate::Logger::log_module(module_path!(), $crate::LogLevel::Notice, format!($fmt, $($arg)+)) };
 }
 
 #[macro_export]
 macro_rules! warn {
-    ($e:expr) => { $crate::Logger::log($crate::LogLevel::Warn, String::from($e)) };
-    ($fmt:expr, $($arg:tt)+) => { $crate::Logger::log($crate::LogLevel::Warn, format!($fmt, $($arg)+)) };
+    ($e:expr) => { $crate::Logger::log_module(module_path!(), $crate::LogLevel::Warn, String::from($e)) };
+    ($fmt:expr, $($arg:tt)+) => { $crate::Logger::log_module(module_path!(), $crate::LogLevel::Warn, format!($fmt, $($arg)+)) };
 }
 
-#[derive(PartialOrd,PartialEq,Copy,Clone)]
+/// Log a message with additional structured fields. The fields are only
+/// recorded by the journal backend, other backends log just the message.
+///
+/// ```text
+/// log_kv!(LogLevel::Warn, {"REALM" => realm.name()}, "Failed to start realm: {}", err);
+/// ```
+///
+#[macro_export]
+macro_rules! log_kv {
+    ($level:expr, { $($key:expr => $val:expr),* $(,)* }, $e:expr) => {
+        $crate::Logger::log_fields(module_path!(), $level, &[$(($key, $val.to_string())),*], String::from($e))
+    };
+    ($level:expr, { $($key:expr => $val:expr),* $(,)* }, $fmt:expr, $($arg:tt)+) => {
+        $crate::Logger::log_fields(module_path!(), $level, &[$(($key, $val.to_string())),*], format!($fmt, $($arg)+))
+    };
+}
+
+#[derive(PartialOrd,PartialEq,Copy,Clone,Debug)]
 pub enum LogLevel {
     Warn,
     Notice,
@@ -47,13 +70,52 @@ pub enum LogLevel {
     Debug,
 }
 
+impl LogLevel {
+    pub fn from_name(name: &str) -> Option<LogLevel> {
+        match name.to_ascii_lowercase().as_str() {
+            "warn" | "warning" => Some(LogLevel::Warn),
+            "notice" => Some(LogLevel::Notice),
+            "info" => Some(LogLevel::Info),
+            "verbose" => Some(LogLevel::Verbose),
+            "debug" => Some(LogLevel::Debug),
+            _ => None,
+        }
+    }
+}
+
+/// Environment variable containing a log level specification which is
+/// applied when the logger is first used. See `Logger::set_log_spec()`.
+const LOG_SPEC_ENV: &str = "CITADEL_LOG";
+
 pub trait LogOutput: Send {
     fn log_output(&mut self, level: LogLevel, line: &str) -> Result<()>;
+
+    /// Log a line with structured fields. Outputs which cannot record fields
+    /// just log the line.
+    fn log_output_fields(&mut self, level: LogLevel, line: &str, _fields: &[(&str, String)]) -> Result<()> {
+        self.log_output(level, line)
+    }
+}
+
+/// A `LogOutput` implementation installed with `Logger::set_log_output()`
+pub type BoxedLogOutput = Box<dyn LogOutput>;
+
+/// A global log level and per-module log levels parsed from a log specification
+type LogSpec = (Option<LogLevel>, Vec<(String, LogLevel)>);
+
+/// Selects where `Logger` writes log lines.
+#[derive(PartialEq,Copy,Clone,Debug)]
+pub enum LogBackend {
+    /// Plain text lines written to the console by `DefaultLogOutput`
+    Console,
+    /// Native journald entries written by `JournalLogOutput`
+    Journal,
 }
 
 pub struct Logger {
     level: LogLevel,
-    output: Box<LogOutput>,
+    module_levels: HashMap<String, LogLevel>,
+    output: BoxedLogOutput,
 }
 
 impl Logger {
@@ -62,23 +124,133 @@ impl Logger {
         logger.level = level;
     }
 
-    pub fn set_log_output(output: Box<LogOutput>) {
+    /// Set the log level for messages logged from module `module` or any of
+    /// its submodules. The level for the longest matching module prefix is
+    /// used, and the global log level if no prefix matches.
+    ///
+    /// ```text
+    /// Logger::set_module_level("libcitadel::realm", LogLevel::Debug);
+    /// ```
+    ///
+    pub fn set_module_level(module: &str, level: LogLevel) {
+        let mut logger = LOGGER.lock().unwrap();
+        logger.module_levels.insert(module.to_string(), level);
+    }
+
+    /// Apply a log level specification, which is a comma separated list of
+    /// `module=level` entries and optionally a bare `level` which sets the
+    /// global log level.
+    ///
+    /// ```text
+    /// libcitadel::realm=debug,citadel_tool::update=warn,info
+    /// ```
+    ///
+    pub fn set_log_spec(spec: &str) -> Result<()> {
+        let (level, modules) = Self::parse_log_spec(spec)?;
+        let mut logger = LOGGER.lock().unwrap();
+        logger.apply_log_spec(level, modules);
+        Ok(())
+    }
+
+    fn apply_log_spec(&mut self, level: Option<LogLevel>, modules: Vec<(String, LogLevel)>) {
+        if let Some(level) = level {
+            self.level = level;
+        }
+        self.module_levels.extend(modules);
+    }
+
+    /// Return an error describing the problem if `spec` is not a valid log specification.
+    pub fn check_log_spec(spec: &str) -> Result<()> {
+        Self::parse_log_spec(spec).map(|_| ())
+    }
+
+    fn parse_log_spec(spec: &str) -> Result<LogSpec> {
+        let parse_level = |name: &str| LogLevel::from_name(name.trim())
+            .ok_or_else(|| format_err!("Invalid log level '{}' in log spec '{}'", name.trim(), spec));
+
+        let mut level = None;
+        let mut modules = Vec::new();
+        for item in spec.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            match item.find('=') {
+                Some(idx) => {
+                    let module = item[..idx].trim();
+                    if module.is_empty() {
+                        bail!("Missing module name in log spec '{}'", spec);
+                    }
+                    modules.push((module.to_string(), parse_level(&item[idx+1..])?));
+                },
+                None => level = Some(parse_level(item)?),
+            }
+        }
+        Ok((level, modules))
+    }
+
+    fn level_for_module(&self, module: &str) -> LogLevel {
+        self.module_levels.iter()
+            .filter(|&(prefix, _)| Self::module_matches(prefix, module))
+            .max_by_key(|&(prefix, _)| prefix.len())
+            .map(|(_, &level)| level)
+            .unwrap_or(self.level)
+    }
+
+    // `prefix` matches the module itself and its submodules, so "a::b" matches
+    // "a::b::c" but not "a::bc"
+    fn module_matches(prefix: &str, module: &str) -> bool {
+        module.starts_with(prefix) &&
+            (module.len() == prefix.len() || module[prefix.len()..].starts_with("::"))
+    }
+
+    pub fn set_log_output(output: BoxedLogOutput) {
         let mut logger = LOGGER.lock().unwrap();
         logger.output = output;
     }
 
+    pub fn set_backend(backend: LogBackend) {
+        Self::set_log_output(Self::backend_output(backend));
+    }
+
     pub fn log(level: LogLevel, message: impl AsRef<str>) {
         let mut logger = LOGGER.lock().unwrap();
-        logger.log_message(level, message.as_ref());
+        logger.log_message(level, "", message.as_ref(), &[]);
+    }
+
+    /// Log a message from `module`, which is filtered by the level set for the module.
+    pub fn log_module(module: &str, level: LogLevel, message: impl AsRef<str>) {
+        let mut logger = LOGGER.lock().unwrap();
+        logger.log_message(level, module, message.as_ref(), &[]);
+    }
+
+    pub fn log_fields(module: &str, level: LogLevel, fields: &[(&str, String)], message: impl AsRef<str>) {
+        let mut logger = LOGGER.lock().unwrap();
+        logger.log_message(level, module, message.as_ref(), fields);
     }
 
     fn new() -> Self {
-        Self { level: LogLevel::Notice, output: Box::new(DefaultLogOutput) }
+        let backend = if JournalLogOutput::is_journal_stream() {
+            LogBackend::Journal
+        } else {
+            LogBackend::Console
+        };
+        let mut logger = Self { level: LogLevel::Notice, module_levels: HashMap::new(), output: Self::backend_output(backend) };
+        if let Ok(spec) = env::var(LOG_SPEC_ENV) {
+            match Self::parse_log_spec(&spec) {
+                Ok((level, modules)) => logger.apply_log_spec(level, modules),
+                Err(e) => eprintln!("Ignoring {}: {}", LOG_SPEC_ENV, e),
+            }
+        }
+        logger
+    }
+
+    fn backend_output(backend: LogBackend) -> BoxedLogOutput {
+        match backend {
+            LogBackend::Console => Box::new(DefaultLogOutput),
+            LogBackend::Journal => Box::new(JournalLogOutput::new()),
+        }
     }
 
-    fn log_message(&mut self, level: LogLevel, message: &str) {
-        if self.level >= level {
-            if let Err(err) = self.output.log_output(level, message) {
+    fn log_message(&mut self, level: LogLevel, module: &str, message: &str, fields: &[(&str, String)]) {
+        if self.level_for_module(module) >= level {
+            if let Err(err) = self.output.log_output_fields(level, message, fields) {
                 eprintln!("Error writing logline: {}", err);
             }
         }
@@ -100,7 +272,7 @@ impl Logger {
 pub struct DefaultLogOutput;
 
 impl DefaultLogOutput {
-    pub fn new() -> Self { DefaultLogOutput::default() }
+    pub fn new() -> Self { DefaultLogOutput }
 }
 
 impl LogOutput for DefaultLogOutput {
@@ -114,3 +286,143 @@ impl LogOutput for DefaultLogOutput {
         Ok(())
     }
 }
+
+#[test]
+fn test_log_spec() {
+    let (level, modules) = Logger::parse_log_spec("libcitadel::realm=debug, warn ,citadel_tool=Info").unwrap();
+    assert_eq!(level, Some(LogLevel::Warn));
+    assert_eq!(modules, vec![("libcitadel::realm".to_string(), LogLevel::Debug), ("citadel_tool".to_string(), LogLevel::Info)]);
+    assert_eq!(Logger::parse_log_spec("").unwrap(), (None, Vec::new()));
+    assert!(Logger::parse_log_spec("libcitadel=loud").is_err());
+    assert!(Logger::parse_log_spec("=debug").is_err());
+    assert!(Logger::parse_log_spec("chatty").is_err());
+
+    let mut logger = Logger { level: LogLevel::Notice, module_levels: HashMap::new(), output: Box::new(DefaultLogOutput) };
+    logger.apply_log_spec(None, modules);
+    logger.module_levels.insert("libcitadel".to_string(), LogLevel::Warn);
+    assert_eq!(logger.level_for_module("libcitadel::realm"), LogLevel::Debug);
+    assert_eq!(logger.level_for_module("libcitadel::realm::launcher"), LogLevel::Debug);
+    assert_eq!(logger.level_for_module("libcitadel::realmfs"), LogLevel::Warn);
+    assert_eq!(logger.level_for_module("libcitadel"), LogLevel::Warn);
+    assert_eq!(logger.level_for_module("citadel_tool::update"), LogLevel::Info);
+    assert_eq!(logger.level_for_module("realmsd"), LogLevel::Notice);
+    assert_eq!(logger.level_for_module(""), LogLevel::Notice);
+}
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Writes log lines as native journald entries with a `PRIORITY` field
+/// mapped from the `LogLevel` and a `SYSLOG_IDENTIFIER` field set to the
+/// name of the running program. Lines are written to stderr if the journal
+/// socket cannot be written to.
+pub struct JournalLogOutput {
+    identifier: String,
+}
+
+impl Default for JournalLogOutput {
+    fn default() -> Self {
+        let identifier = env::args().next()
+            .and_then(|arg0| Path::new(&arg0).file_name().map(|s| s.to_string_lossy().to_string()))
+            .unwrap_or_else(|| "citadel".to_string());
+        JournalLogOutput { identifier }
+    }
+}
+
+impl JournalLogOutput {
+    pub fn new() -> Self { JournalLogOutput::default() }
+
+    /// Returns `true` if stdout is connected to the journal as indicated by
+    /// the `JOURNAL_STREAM` variable which systemd sets for services.
+    pub fn is_journal_stream() -> bool {
+        let stream = match env::var("JOURNAL_STREAM") {
+            Ok(stream) => stream,
+            Err(_) => return false,
+        };
+        match fs::metadata("/proc/self/fd/1") {
+            Ok(meta) => stream == format!("{}:{}", meta.dev(), meta.ino()),
+            Err(_) => false,
+        }
+    }
+
+    /// syslog(3) priority for `level`
+    pub fn priority(level: LogLevel) -> u8 {
+        match level {
+            LogLevel::Warn    => 4,
+            LogLevel::Notice  => 5,
+            LogLevel::Info    => 6,
+            LogLevel::Verbose => 7,
+            LogLevel::Debug   => 7,
+        }
+    }
+
+    // Journal field names may only contain uppercase letters, digits and '_'
+    // and cannot begin with '_' which is reserved for trusted fields.
+    fn field_name(name: &str) -> String {
+        let name = name.chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+            .collect::<String>();
+        name.trim_start_matches('_').to_string()
+    }
+
+    fn encode_field(buffer: &mut Vec<u8>, name: &str, value: &str) {
+        buffer.extend_from_slice(name.as_bytes());
+        if value.contains('\n') {
+            buffer.push(b'\n');
+            buffer.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            buffer.push(b'=');
+        }
+        buffer.extend_from_slice(value.as_bytes());
+        buffer.push(b'\n');
+    }
+
+    fn encode(&self, level: LogLevel, line: &str, fields: &[(&str, String)]) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        Self::encode_field(&mut buffer, "PRIORITY", &Self::priority(level).to_string());
+        Self::encode_field(&mut buffer, "SYSLOG_IDENTIFIER", &self.identifier);
+        Self::encode_field(&mut buffer, "MESSAGE", line);
+        for (name, value) in fields {
+            let name = Self::field_name(name);
+            if !name.is_empty() {
+                Self::encode_field(&mut buffer, &name, value);
+            }
+        }
+        buffer
+    }
+}
+
+impl LogOutput for JournalLogOutput {
+    fn log_output(&mut self, level: LogLevel, line: &str) -> Result<()> {
+        self.log_output_fields(level, line, &[])
+    }
+
+    fn log_output_fields(&mut self, level: LogLevel, line: &str, fields: &[(&str, String)]) -> Result<()> {
+        let entry = self.encode(level, line, fields);
+        let sent = UnixDatagram::unbound()
+            .and_then(|sock| sock.send_to(&entry, JOURNAL_SOCKET));
+        if sent.is_err() {
+            io::stderr().write_all(Logger::format_logline(level, line).as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_journal_encoding() {
+    assert_eq!(JournalLogOutput::priority(LogLevel::Warn), 4);
+    assert_eq!(JournalLogOutput::priority(LogLevel::Notice), 5);
+    assert_eq!(JournalLogOutput::priority(LogLevel::Info), 6);
+    assert_eq!(JournalLogOutput::priority(LogLevel::Debug), 7);
+
+    let output = JournalLogOutput { identifier: "realmsd".to_string() };
+    let fields = [("REALM", "main".to_string()), ("_uid", "0".to_string()), ("realm-fs", "base".to_string())];
+    let entry = output.encode(LogLevel::Info, "realm started", &fields);
+    assert_eq!(String::from_utf8(entry).unwrap(),
+               "PRIORITY=6\nSYSLOG_IDENTIFIER=realmsd\nMESSAGE=realm started\nREALM=main\nUID=0\nREALM_FS=base\n");
+
+    let entry = output.encode(LogLevel::Warn, "two\nlines", &[]);
+    let mut expected = b"PRIORITY=4\nSYSLOG_IDENTIFIER=realmsd\nMESSAGE\n".to_vec();
+    expected.extend_from_slice(&9u64.to_le_bytes());
+    expected.extend_from_slice(b"two\nlines\n");
+    assert_eq!(entry, expected);
+}
@@ -0,0 +1,330 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::{Result, SystemRoot};
+
+lazy_static! {
+    static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::new());
+}
+
+/// Counts of installed updates by image type and result. Updates are installed
+/// by `citadel-tool update` rather than realmsd so the counts are kept in a file.
+pub const UPDATE_METRICS_PATH: &str = "/storage/citadel-state/update-metrics.json";
+
+/// Upper bounds in seconds of the histogram buckets for realm start and stop durations
+const DURATION_BUCKETS: &[f64] = &[0.5, 1.0, 2.0, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+#[derive(Clone,Copy,PartialEq)]
+enum MetricKind {
+    Counter,
+    Histogram,
+}
+
+impl MetricKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Histogram => "histogram",
+        }
+    }
+}
+
+#[derive(Clone,Copy)]
+struct MetricDef {
+    name: &'static str,
+    help: &'static str,
+    kind: MetricKind,
+}
+
+// The names of these metrics are relied on by monitoring and must not change
+const REALM_START_TOTAL: MetricDef = MetricDef {
+    name: "citadel_realm_start_total",
+    help: "Number of realm start attempts by realm and result",
+    kind: MetricKind::Counter,
+};
+const REALM_START_SECONDS: MetricDef = MetricDef {
+    name: "citadel_realm_start_duration_seconds",
+    help: "Time taken to start a realm",
+    kind: MetricKind::Histogram,
+};
+const REALM_STOP_TOTAL: MetricDef = MetricDef {
+    name: "citadel_realm_stop_total",
+    help: "Number of realm stop attempts by realm and result",
+    kind: MetricKind::Counter,
+};
+const REALM_STOP_SECONDS: MetricDef = MetricDef {
+    name: "citadel_realm_stop_duration_seconds",
+    help: "Time taken to stop a realm",
+    kind: MetricKind::Histogram,
+};
+const NETWORK_ALLOCATION_FAILURES: MetricDef = MetricDef {
+    name: "citadel_network_allocation_failures_total",
+    help: "Number of failures to allocate a network address to a realm by bridge",
+    kind: MetricKind::Counter,
+};
+const REALM_EVENTS_TOTAL: MetricDef = MetricDef {
+    name: "citadel_realm_events_total",
+    help: "Number of realm status changes by realm and event",
+    kind: MetricKind::Counter,
+};
+const UPDATE_INSTALL_TOTAL: MetricDef = MetricDef {
+    name: "citadel_update_install_total",
+    help: "Number of update images installed by image type and result",
+    kind: MetricKind::Counter,
+};
+
+const ALL_METRICS: &[MetricDef] = &[
+    REALM_START_TOTAL, REALM_START_SECONDS, REALM_STOP_TOTAL, REALM_STOP_SECONDS,
+    NETWORK_ALLOCATION_FAILURES, REALM_EVENTS_TOTAL, UPDATE_INSTALL_TOTAL,
+];
+
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Clone)]
+enum Series {
+    Counter(u64),
+    // Count of observations in each bucket of DURATION_BUCKETS, sum and total count
+    Histogram { buckets: Vec<u64>, sum: f64, count: u64 },
+}
+
+#[derive(Clone)]
+struct Family {
+    def: MetricDef,
+    series: BTreeMap<Labels, Series>,
+}
+
+#[derive(Clone)]
+struct Registry {
+    families: BTreeMap<&'static str, Family>,
+}
+
+impl Registry {
+    fn new() -> Self {
+        let families = ALL_METRICS.iter()
+            .map(|&def| (def.name, Family { def, series: BTreeMap::new() }))
+            .collect();
+        Registry { families }
+    }
+
+    fn series(&mut self, def: MetricDef, labels: &[(&'static str, &str)]) -> &mut Series {
+        let labels = labels.iter().map(|&(k, v)| (k, v.to_string())).collect();
+        let family = self.families.get_mut(def.name).expect("metric not registered");
+        family.series.entry(labels).or_insert_with(|| match def.kind {
+            MetricKind::Counter => Series::Counter(0),
+            MetricKind::Histogram => Series::Histogram { buckets: vec![0; DURATION_BUCKETS.len()], sum: 0.0, count: 0 },
+        })
+    }
+
+    fn add(&mut self, def: MetricDef, labels: &[(&'static str, &str)], n: u64) {
+        if let Series::Counter(ref mut value) = self.series(def, labels) {
+            *value += n;
+        }
+    }
+
+    fn observe(&mut self, def: MetricDef, labels: &[(&'static str, &str)], duration: Duration) {
+        if let Series::Histogram { ref mut buckets, ref mut sum, ref mut count } = self.series(def, labels) {
+            let secs = duration.as_secs_f64();
+            for (i, &bound) in DURATION_BUCKETS.iter().enumerate() {
+                if secs <= bound {
+                    buckets[i] += 1;
+                }
+            }
+            *sum += secs;
+            *count += 1;
+        }
+    }
+
+    fn record_realm_operation(&mut self, total: MetricDef, seconds: MetricDef, realm: &str, duration: Duration, success: bool) {
+        self.add(total, &[("realm", realm), ("result", result_label(success))], 1);
+        self.observe(seconds, &[("realm", realm)], duration);
+    }
+
+    fn add_update_counts(&mut self, counts: &UpdateCounts) {
+        for (image_type, results) in counts {
+            for (result, &n) in results {
+                self.add(UPDATE_INSTALL_TOTAL, &[("image_type", image_type), ("result", result)], n);
+            }
+        }
+    }
+
+    /// Prometheus text exposition format
+    fn render(&self) -> String {
+        let mut out = String::new();
+        for family in self.families.values() {
+            let name = family.def.name;
+            let _ = writeln!(out, "# HELP {} {}", name, family.def.help);
+            let _ = writeln!(out, "# TYPE {} {}", name, family.def.kind.as_str());
+            for (labels, series) in &family.series {
+                match series {
+                    Series::Counter(value) => {
+                        let _ = writeln!(out, "{}{} {}", name, format_labels(labels, None), value);
+                    },
+                    Series::Histogram { buckets, sum, count } => {
+                        for (bound, n) in DURATION_BUCKETS.iter().zip(buckets) {
+                            let _ = writeln!(out, "{}_bucket{} {}", name, format_labels(labels, Some(&bound.to_string())), n);
+                        }
+                        let _ = writeln!(out, "{}_bucket{} {}", name, format_labels(labels, Some("+Inf")), count);
+                        let _ = writeln!(out, "{}_sum{} {}", name, format_labels(labels, None), sum);
+                        let _ = writeln!(out, "{}_count{} {}", name, format_labels(labels, None), count);
+                    },
+                }
+            }
+        }
+        out
+    }
+}
+
+fn result_label(success: bool) -> &'static str {
+    if success { "success" } else { "failure" }
+}
+
+fn format_labels(labels: &[(&'static str, String)], le: Option<&str>) -> String {
+    let mut pairs = labels.iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, escape_label_value(v)))
+        .collect::<Vec<_>>();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// image type -> result -> count
+type UpdateCounts = BTreeMap<String, BTreeMap<String, u64>>;
+
+fn load_update_counts(path: &Path) -> UpdateCounts {
+    match fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+            warn!("Failed to parse {}: {}", path.display(), e);
+            UpdateCounts::new()
+        }),
+        Err(_) => UpdateCounts::new(),
+    }
+}
+
+///
+/// Counters and histograms of realm and update operations, exported by realmsd
+/// in the Prometheus text exposition format.
+///
+pub struct Metrics;
+
+impl Metrics {
+    pub fn realm_started(realm: &str, duration: Duration, success: bool) {
+        REGISTRY.lock().unwrap().record_realm_operation(REALM_START_TOTAL, REALM_START_SECONDS, realm, duration, success);
+    }
+
+    pub fn realm_stopped(realm: &str, duration: Duration, success: bool) {
+        REGISTRY.lock().unwrap().record_realm_operation(REALM_STOP_TOTAL, REALM_STOP_SECONDS, realm, duration, success);
+    }
+
+    pub fn network_allocation_failed(bridge: &str) {
+        REGISTRY.lock().unwrap().add(NETWORK_ALLOCATION_FAILURES, &[("bridge", bridge)], 1);
+    }
+
+    pub fn realm_event(realm: &str, event: &str) {
+        REGISTRY.lock().unwrap().add(REALM_EVENTS_TOTAL, &[("realm", realm), ("event", event)], 1);
+    }
+
+    /// Add the result of installing an update image to the counts saved in
+    /// /storage/citadel-state/update-metrics.json
+    pub fn update_installed(root: &SystemRoot, image_type: &str, success: bool) {
+        let path = root.path(UPDATE_METRICS_PATH);
+        if let Err(e) = Self::save_update_result(&path, image_type, success) {
+            warn!("Failed to save update metrics to {}: {}", path.display(), e);
+        }
+    }
+
+    fn save_update_result(path: &Path, image_type: &str, success: bool) -> Result<()> {
+        let mut counts = load_update_counts(path);
+        *counts.entry(image_type.to_string()).or_default()
+            .entry(result_label(success).to_string()).or_default() += 1;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string(&counts)?)?;
+        Ok(())
+    }
+
+    /// All metrics in the Prometheus text exposition format
+    pub fn render() -> String {
+        let mut registry = REGISTRY.lock().unwrap().clone();
+        registry.add_update_counts(&load_update_counts(Path::new(UPDATE_METRICS_PATH)));
+        registry.render()
+    }
+}
+
+#[test]
+fn test_metrics_exposition() {
+    let mut registry = Registry::new();
+    let ms = Duration::from_millis;
+    registry.record_realm_operation(REALM_START_TOTAL, REALM_START_SECONDS, "main", ms(1500), true);
+    registry.record_realm_operation(REALM_START_TOTAL, REALM_START_SECONDS, "main", ms(250), false);
+    registry.record_realm_operation(REALM_STOP_TOTAL, REALM_STOP_SECONDS, "main", ms(3000), true);
+    registry.add(NETWORK_ALLOCATION_FAILURES, &[("bridge", "clear")], 1);
+    registry.add(REALM_EVENTS_TOTAL, &[("realm", "main"), ("event", "failed")], 2);
+
+    let dir = crate::util::TempDir::new("update-metrics").unwrap();
+    let path = dir.join("update-metrics.json");
+    for &(image_type, success) in &[("rootfs", true), ("kernel", false), ("rootfs", true)] {
+        Metrics::save_update_result(&path, image_type, success).unwrap();
+    }
+    registry.add_update_counts(&load_update_counts(&path));
+
+    assert_eq!(registry.render(), "\
+# HELP citadel_network_allocation_failures_total Number of failures to allocate a network address to a realm by bridge
+# TYPE citadel_network_allocation_failures_total counter
+citadel_network_allocation_failures_total{bridge=\"clear\"} 1
+# HELP citadel_realm_events_total Number of realm status changes by realm and event
+# TYPE citadel_realm_events_total counter
+citadel_realm_events_total{realm=\"main\",event=\"failed\"} 2
+# HELP citadel_realm_start_duration_seconds Time taken to start a realm
+# TYPE citadel_realm_start_duration_seconds histogram
+citadel_realm_start_duration_seconds_bucket{realm=\"main\",le=\"0.5\"} 1
+citadel_realm_start_duration_seconds_bucket{realm=\"main\",le=\"1\"} 1
+citadel_realm_start_duration_seconds_bucket{realm=\"main\",le=\"2\"} 2
+citadel_realm_start_duration_seconds_bucket{realm=\"main\",le=\"5\"} 2
+citadel_realm_start_duration_seconds_bucket{realm=\"main\",le=\"10\"} 2
+citadel_realm_start_duration_seconds_bucket{realm=\"main\",le=\"30\"} 2
+citadel_realm_start_duration_seconds_bucket{realm=\"main\",le=\"60\"} 2
+citadel_realm_start_duration_seconds_bucket{realm=\"main\",le=\"120\"} 2
+citadel_realm_start_duration_seconds_bucket{realm=\"main\",le=\"+Inf\"} 2
+citadel_realm_start_duration_seconds_sum{realm=\"main\"} 1.75
+citadel_realm_start_duration_seconds_count{realm=\"main\"} 2
+# HELP citadel_realm_start_total Number of realm start attempts by realm and result
+# TYPE citadel_realm_start_total counter
+citadel_realm_start_total{realm=\"main\",result=\"failure\"} 1
+citadel_realm_start_total{realm=\"main\",result=\"success\"} 1
+# HELP citadel_realm_stop_duration_seconds Time taken to stop a realm
+# TYPE citadel_realm_stop_duration_seconds histogram
+citadel_realm_stop_duration_seconds_bucket{realm=\"main\",le=\"0.5\"} 0
+citadel_realm_stop_duration_seconds_bucket{realm=\"main\",le=\"1\"} 0
+citadel_realm_stop_duration_seconds_bucket{realm=\"main\",le=\"2\"} 0
+citadel_realm_stop_duration_seconds_bucket{realm=\"main\",le=\"5\"} 1
+citadel_realm_stop_duration_seconds_bucket{realm=\"main\",le=\"10\"} 1
+citadel_realm_stop_duration_seconds_bucket{realm=\"main\",le=\"30\"} 1
+citadel_realm_stop_duration_seconds_bucket{realm=\"main\",le=\"60\"} 1
+citadel_realm_stop_duration_seconds_bucket{realm=\"main\",le=\"120\"} 1
+citadel_realm_stop_duration_seconds_bucket{realm=\"main\",le=\"+Inf\"} 1
+citadel_realm_stop_duration_seconds_sum{realm=\"main\"} 3
+citadel_realm_stop_duration_seconds_count{realm=\"main\"} 1
+# HELP citadel_realm_stop_total Number of realm stop attempts by realm and result
+# TYPE citadel_realm_stop_total counter
+citadel_realm_stop_total{realm=\"main\",result=\"success\"} 1
+# HELP citadel_update_install_total Number of update images installed by image type and result
+# TYPE citadel_update_install_total counter
+citadel_update_install_total{image_type=\"kernel\",result=\"failure\"} 1
+citadel_update_install_total{image_type=\"rootfs\",result=\"success\"} 2
+");
+    assert_eq!(format_labels(&[("realm", "a\"b\\".to_string())], None), "{realm=\"a\\\"b\\\\\"}");
+}
@@ -1,6 +1,6 @@
 use std::path::{Path,PathBuf};
 use std::fs;
-use crate::{Result,ImageHeader,MetaInfo,Mounts,PublicKey,public_key_for_channel};
+use crate::{Result,ImageHeader,InvalidHeaderError,MetaInfo,Mounts,PublicKey,public_key_for_channel};
 use std::sync::Arc;
 
 #[derive(Clone)]
@@ -28,15 +28,38 @@ impl Partition {
         Ok(v)
     }
 
+    /// Like `rootfs_partitions()` but a partition which fails to load is
+    /// skipped with a warning instead of failing the whole list.
+    pub fn loadable_rootfs_partitions() -> Result<Vec<Self>> {
+        let mut v = Vec::new();
+        for path in rootfs_partition_paths()? {
+            match Self::load(&path) {
+                Ok(partition) => v.push(partition),
+                Err(e) => warn!("Skipping partition {}: {}", path.display(), e),
+            }
+        }
+        v.sort_unstable_by(|a,b| a.path().cmp(b.path()));
+        Ok(v)
+    }
+
     fn load(dev: &Path) -> Result<Self> {
         let is_mounted = is_in_use(dev)?;
         let header = Self::load_header(dev)?;
         Ok(Partition::new(dev, header, is_mounted))
     }
 
+    // A header which is corrupted or has an unsupported version is treated the
+    // same as a partition which has no header.
     fn load_header(dev: &Path) -> Result<Option<HeaderInfo>> {
-        let header = ImageHeader::from_partition(dev)?;
-        if !header.is_magic_valid() {
+        let header = match ImageHeader::from_partition(dev) {
+            Ok(header) => header,
+            Err(ref e) if e.downcast_ref::<InvalidHeaderError>().is_some() => {
+                warn!("Partition {} has an invalid header: {}", dev.display(), e);
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+        if header.magic_version().is_none() {
             return Ok(None);
         }
 
@@ -125,13 +148,35 @@ impl Partition {
     }
 
     pub fn set_flag_and_write(&mut self, flag: u8) -> Result<()> {
-        self.header().set_flag(flag);
-        self.header().write_partition(&self.path)
+        self.change_flag_and_write(flag, true).map(|_| ())
     }
 
     pub fn clear_flag_and_write(&mut self, flag: u8) -> Result<()> {
-        self.header().clear_flag(flag);
-        self.header().write_partition(&self.path)
+        self.change_flag_and_write(flag, false).map(|_| ())
+    }
+
+    /// Set or clear `flag` in the header stored on the partition. The header is
+    /// read again from the device first so that changes made since this
+    /// `Partition` was loaded are not overwritten, and is only written if the
+    /// flag value changes. Returns `true` if the header was written.
+    pub fn change_flag_and_write(&mut self, flag: u8, set: bool) -> Result<bool> {
+        let header = ImageHeader::from_partition(&self.path)?;
+        if header.magic_version().is_none() {
+            bail!("Partition {} does not have a valid image header", self.path.display());
+        }
+        if header.has_flag(flag) == set {
+            return Ok(false);
+        }
+        if set {
+            header.set_flag(flag);
+        } else {
+            header.clear_flag(flag);
+        }
+        header.write_partition(&self.path)?;
+        if let Some(ref mut hinfo) = self.hinfo {
+            hinfo.header = Arc::new(header);
+        }
+        Ok(true)
     }
 
     /// Called at boot to perform various checks and possibly
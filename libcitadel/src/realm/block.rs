@@ -0,0 +1,213 @@
+use std::collections::HashSet;
+use std::fs;
+use std::os::unix::fs::{MetadataExt, FileTypeExt};
+use std::path::{Path,PathBuf};
+
+use crate::{Mounts,Partition,Result};
+
+/// Root owned host configuration which must set `allow-block-devices = true`
+/// before the `block-devices` option of any realm is honored.
+const HOST_REALMS_CONFIG: &str = "/etc/citadel/realms.conf";
+
+const SYS_CLASS_BLOCK: &str = "/sys/class/block";
+
+#[derive(Deserialize,Default)]
+struct HostRealmsConfig {
+    #[serde(rename="allow-block-devices")]
+    allow_block_devices: Option<bool>,
+}
+
+///
+/// Resolves the `block-devices` option of a realm to device nodes.
+///
+/// Patterns are device paths which may contain `*` and `?` wildcards in the
+/// final path component, such as `/dev/disk/by-id/usb-*`. Symlinks are
+/// resolved so that the actual device node is added to the realm. Devices
+/// which back the host rootfs or /storage, and whole disks containing them,
+/// are never granted.
+///
+pub struct BlockDevices {
+    protected: HashSet<String>,
+}
+
+impl BlockDevices {
+
+    /// Returns `true` if `allow-block-devices = true` is set in /etc/citadel/realms.conf
+    /// and the file is owned by root and not writable by other users.
+    pub fn allowed_by_host() -> bool {
+        let path = Path::new(HOST_REALMS_CONFIG);
+        let meta = match path.metadata() {
+            Ok(meta) => meta,
+            Err(_) => return false,
+        };
+        if meta.uid() != 0 || meta.mode() & 0o022 != 0 {
+            warn!("Ignoring {} because it is not owned by root or is writable by other users", path.display());
+            return false;
+        }
+        match fs::read_to_string(path).map(|s| toml::from_str::<HostRealmsConfig>(&s)) {
+            Ok(Ok(config)) => config.allow_block_devices.unwrap_or(false),
+            Ok(Err(e)) => { warn!("Failed to parse {}: {}", path.display(), e); false },
+            Err(e) => { warn!("Failed to read {}: {}", path.display(), e); false },
+        }
+    }
+
+    /// Load the set of block devices backing the rootfs partitions and /storage.
+    pub fn load() -> Result<Self> {
+        let mut sources = Partition::rootfs_partitions()?.iter()
+            .map(|p| p.path().to_path_buf())
+            .collect::<Vec<_>>();
+        let mounts = Mounts::load()?;
+        if let Some(storage) = mounts.mounts().find(|m| m.target() == "/storage") {
+            sources.push(storage.source_path().to_path_buf());
+        }
+        let sys = Path::new(SYS_CLASS_BLOCK);
+        let mut protected = HashSet::new();
+        for source in sources {
+            if let Some(name) = Self::kernel_name(&source) {
+                Self::add_protected(sys, &name, &mut protected);
+            }
+        }
+        Ok(BlockDevices { protected })
+    }
+
+    // Add `name`, the disk containing it if it is a partition, and any devices
+    // underlying it if it is a device mapper device.
+    fn add_protected(sys: &Path, name: &str, protected: &mut HashSet<String>) {
+        if !protected.insert(name.to_string()) {
+            return;
+        }
+        let dir = sys.join(name);
+        if dir.join("partition").exists() {
+            let disk = dir.canonicalize().ok()
+                .and_then(|p| p.parent().and_then(|p| p.file_name()).map(|n| n.to_string_lossy().to_string()));
+            if let Some(disk) = disk {
+                protected.insert(disk);
+            }
+        }
+        if let Ok(entries) = fs::read_dir(dir.join("slaves")) {
+            for entry in entries.flat_map(|e| e.ok()) {
+                Self::add_protected(sys, &entry.file_name().to_string_lossy(), protected);
+            }
+        }
+    }
+
+    /// Returns `true` if the device with kernel name `name` such as `sda2` backs
+    /// the host rootfs or /storage or is a disk containing such a device.
+    pub(crate) fn is_protected(&self, name: &str) -> bool {
+        self.protected.contains(name)
+    }
+
+    // Name of the device node `path` points to, such as `sda2` or `dm-0`
+    fn kernel_name(path: &Path) -> Option<String> {
+        let path = path.canonicalize().ok()?;
+        path.file_name().map(|name| name.to_string_lossy().to_string())
+    }
+
+    /// Expand `patterns` to block device nodes, skipping any which are protected.
+    pub fn resolve<S: AsRef<str>>(&self, patterns: &[S]) -> Vec<PathBuf> {
+        let mut nodes = Vec::new();
+        for pattern in patterns {
+            let pattern = pattern.as_ref();
+            let matched = expand_pattern(Path::new(pattern));
+            if matched.is_empty() {
+                info!("No block devices match '{}'", pattern);
+            }
+            for path in matched {
+                let node = match path.canonicalize() {
+                    Ok(node) => node,
+                    Err(_) => continue,
+                };
+                if !is_block_device(&node) {
+                    warn!("Not adding {} because it is not a block device", node.display());
+                } else if Self::kernel_name(&node).map(|n| self.protected.contains(&n)).unwrap_or(true) {
+                    warn!("Refusing to add block device {} because it backs the host rootfs or /storage", node.display());
+                } else if !nodes.contains(&node) {
+                    nodes.push(node);
+                }
+            }
+        }
+        nodes
+    }
+}
+
+fn is_block_device(path: &Path) -> bool {
+    path.metadata().map(|meta| meta.file_type().is_block_device()).unwrap_or(false)
+}
+
+// Expand wildcards in the final component of `pattern`. A pattern without
+// wildcards is returned if it exists.
+fn expand_pattern(pattern: &Path) -> Vec<PathBuf> {
+    let (dir, name) = match (pattern.parent(), pattern.file_name()) {
+        (Some(dir), Some(name)) => (dir, name.to_string_lossy()),
+        _ => return Vec::new(),
+    };
+    if !name.contains(['*', '?']) {
+        return if pattern.exists() { vec![pattern.to_path_buf()] } else { Vec::new() };
+    }
+    let mut paths = match fs::read_dir(dir) {
+        Ok(entries) => entries.flat_map(|e| e.ok())
+            .filter(|e| wildcard_match(&name, &e.file_name().to_string_lossy()))
+            .map(|e| e.path())
+            .collect::<Vec<_>>(),
+        Err(_) => Vec::new(),
+    };
+    paths.sort();
+    paths
+}
+
+// Match `name` against `pattern` where `*` matches any sequence of characters
+// and `?` matches a single character.
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((bp, bn)) = backtrack {
+            p = bp + 1;
+            n = bn + 1;
+            backtrack = Some((bp, bn + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[test]
+fn test_block_device_patterns() {
+    assert!(wildcard_match("usb-*", "usb-SanDisk_Ultra-0:0"));
+    assert!(wildcard_match("mmcblk?", "mmcblk0"));
+    assert!(wildcard_match("*-part*", "usb-x-part1"));
+    assert!(!wildcard_match("mmcblk?", "mmcblk0p1"));
+    assert!(!wildcard_match("usb-*", "ata-Samsung"));
+
+    let base = crate::util::TempDir::new("block-test").unwrap();
+    let by_id = base.join("by-id");
+    fs::create_dir_all(&by_id).unwrap();
+    for name in &["usb-b", "usb-a", "ata-c"] {
+        fs::write(by_id.join(name), "").unwrap();
+    }
+    assert_eq!(expand_pattern(&by_id.join("usb-*")), vec![by_id.join("usb-a"), by_id.join("usb-b")]);
+    assert_eq!(expand_pattern(&by_id.join("ata-c")), vec![by_id.join("ata-c")]);
+    assert!(expand_pattern(&by_id.join("nvme-*")).is_empty());
+
+    // sda2 is a partition of sda, dm-0 is a mapping on top of sda3
+    let sys = base.join("class");
+    fs::create_dir_all(base.join("devices/sda/sda2")).unwrap();
+    fs::write(base.join("devices/sda/sda2/partition"), "2").unwrap();
+    fs::create_dir_all(sys.join("dm-0/slaves/sda3")).unwrap();
+    std::os::unix::fs::symlink(base.join("devices/sda/sda2"), sys.join("sda2")).unwrap();
+    let mut protected = HashSet::new();
+    BlockDevices::add_protected(&sys, "sda2", &mut protected);
+    BlockDevices::add_protected(&sys, "dm-0", &mut protected);
+    let mut names = protected.into_iter().collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, vec!["dm-0", "sda", "sda2", "sda3"]);
+}
@@ -1,8 +1,14 @@
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::fs;
+use std::net::IpAddr;
 use std::os::unix::fs::MetadataExt;
-use toml;
 use crate::{Result, Realms};
+use crate::realm::security::{self, SyscallProfile};
+use crate::realm::systemd::Systemd;
+use crate::realm::network::{HostsEntry,PortForward};
+use crate::realm::usb::UsbMatcher;
+use crate::realm::schema::ConfigCheck;
+use crate::realm::quota::parse_quota;
 
 lazy_static! {
     pub static ref GLOBAL_CONFIG: RealmConfig = RealmConfig::load_global_config();
@@ -12,6 +18,18 @@ const DEFAULT_ZONE: &str = "clear";
 const DEFAULT_REALMFS: &str = "base";
 const DEFAULT_OVERLAY: &str = "storage";
 
+const GPU_VENDORS: &[&str] = &["amd", "intel", "nvidia"];
+
+const RESTART_POLICIES: &[&str] = &["no", "on-failure", "always"];
+
+const JOURNAL_MODES: &[&str] = &["auto", "host", "off"];
+
+const DEFAULT_NETWORK_WAIT_TIMEOUT: u32 = 10;
+const MAX_NETWORK_WAIT_TIMEOUT: u32 = 300;
+
+/// Config options which may also be applied to a running realm with `Systemd::apply_resource_properties()`
+const RESOURCE_LIMIT_OPTIONS: &[&str] = &["allowed-cpus", "cpu-weight", "nice"];
+
 /// Type of rootfs overlay a Realm is configured to use
 #[derive(PartialEq,Debug,Copy,Clone)]
 pub enum OverlayType {
@@ -44,50 +62,296 @@ impl OverlayType {
     }
 }
 
+/// How the home directory of a Realm is mounted
+#[derive(PartialEq,Debug,Copy,Clone)]
+pub enum HomeMode {
+    /// Bind mount /realms/realm-${name}/home as home directory
+    Persistent,
+    /// Mount a tmpfs as home directory
+    Ephemeral,
+    /// Mount /realms/realm-${name}/home read-only with a tmpfs overlay for writes
+    ReadOnlyOverlay,
+}
+
+impl HomeMode {
+    pub fn from_str_value(value: &str) -> Option<Self> {
+        match value {
+            "persistent" => Some(HomeMode::Persistent),
+            "ephemeral" => Some(HomeMode::Ephemeral),
+            "readonly-overlay" => Some(HomeMode::ReadOnlyOverlay),
+            _ => None,
+        }
+    }
+
+    pub fn to_str_value(self) -> &'static str {
+        match self {
+            HomeMode::Persistent => "persistent",
+            HomeMode::Ephemeral => "ephemeral",
+            HomeMode::ReadOnlyOverlay => "readonly-overlay",
+        }
+    }
+}
+
+/// How clipboard contents are relayed between a realm and the host
+#[derive(PartialEq,Debug,Copy,Clone)]
+pub enum ClipboardPolicy {
+    /// Nothing is relayed to or from the realm
+    Isolated,
+    /// Text copied in the realm reaches the host and the host clipboard can be pasted in the realm
+    Shared,
+    /// Text copied in the realm reaches the host but the host clipboard is not given to the realm
+    OutboundOnly,
+}
+
+impl ClipboardPolicy {
+    pub fn from_str_value(value: &str) -> Option<Self> {
+        match value {
+            "isolated" => Some(ClipboardPolicy::Isolated),
+            "shared" => Some(ClipboardPolicy::Shared),
+            "outbound-only" => Some(ClipboardPolicy::OutboundOnly),
+            _ => None,
+        }
+    }
+
+    pub fn to_str_value(self) -> &'static str {
+        match self {
+            ClipboardPolicy::Isolated => "isolated",
+            ClipboardPolicy::Shared => "shared",
+            ClipboardPolicy::OutboundOnly => "outbound-only",
+        }
+    }
+
+    /// Returns true if text copied in the realm may be relayed to the host
+    pub fn sends(self) -> bool {
+        self != ClipboardPolicy::Isolated
+    }
+
+    /// Returns true if the host clipboard may be relayed into the realm
+    pub fn receives(self) -> bool {
+        self == ClipboardPolicy::Shared
+    }
+}
+
+fn is_valid_timezone(tz: &str) -> bool {
+    !tz.is_empty() && !tz.starts_with('/') &&
+        tz.split('/').all(|part| !part.is_empty() && part != "." && part != "..") &&
+        tz.chars().all(|c| c.is_ascii_alphanumeric() || "/_-+.".contains(c))
+}
+
+// Linux limits interface names to 15 characters and does not allow '/' or whitespace
+fn is_valid_interface_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 15 && name != "." && name != ".." &&
+        name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+}
+
+// A well-known bus name such as org.freedesktop.Notifications, optionally
+// ending with '.*' as accepted by xdg-dbus-proxy
+fn is_valid_bus_name(name: &str) -> bool {
+    let name = name.strip_suffix(".*").unwrap_or(name);
+    let elements = name.split('.').collect::<Vec<_>>();
+    name.len() <= 255 && elements.len() >= 2 && elements.iter().all(|e| {
+        !e.is_empty() && !e.starts_with(|c: char| c.is_ascii_digit()) &&
+            e.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    })
+}
+
+// Normalize a path relative to the realm home directory, returning `None`
+// if it is absolute, empty or refers to a location outside of the home directory.
+fn normalize_home_subdir(dir: &str) -> Option<String> {
+    let mut parts = Vec::new();
+    for component in Path::new(dir).components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str()?),
+            Component::CurDir => {},
+            Component::ParentDir => { parts.pop()?; },
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    if parts.is_empty() || dir.contains(':') {
+        return None;
+    }
+    Some(parts.join("/"))
+}
+
+// A comma separated list of CPU indexes and ascending ranges as accepted by AllowedCPUs=
+fn is_valid_cpu_list(cpus: &str) -> bool {
+    let is_index = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit()) && s.parse::<u32>().is_ok();
+    !cpus.is_empty() && cpus.split(',').all(|item| {
+        let mut bounds = item.splitn(2, '-');
+        match (bounds.next(), bounds.next()) {
+            (Some(first), None) => is_index(first),
+            (Some(first), Some(last)) => is_index(first) && is_index(last) &&
+                first.parse::<u32>().unwrap() <= last.parse::<u32>().unwrap(),
+            _ => false,
+        }
+    })
+}
+
+fn is_valid_search_domain(domain: &str) -> bool {
+    !domain.is_empty() &&
+        domain.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+}
+
+fn is_valid_locale(locale: &str) -> bool {
+    !locale.is_empty() &&
+        locale.chars().all(|c| c.is_ascii_alphanumeric() || "._-@".contains(c))
+}
+
+/// Return `true` if `size` is a size accepted by journald.conf such as `200M`,
+/// a number of bytes with an optional K, M, G or T suffix.
+fn is_valid_journal_size(size: &str) -> bool {
+    let digits = size.strip_suffix(|c| "KMGT".contains(c)).unwrap_or(size);
+    !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) &&
+        digits.parse::<u64>().map(|n| n > 0).unwrap_or(false)
+}
+
+/// Return `true` if `item` is a valid `KEY=value` environment variable.
+///
+/// The key must match `[A-Za-z_][A-Za-z0-9_]*` and the value must not
+/// contain any newline characters.
+pub fn is_valid_environment_item(item: &str) -> bool {
+    let (key, value) = match item.find('=') {
+        Some(idx) => (&item[..idx], &item[idx + 1..]),
+        None => return false,
+    };
+    let mut chars = key.chars();
+    let first_ok = match chars.next() {
+        Some(c) => c.is_ascii_alphabetic() || c == '_',
+        None => false,
+    };
+    first_ok
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !value.contains('\n')
+        && !value.contains('\r')
+}
+
 /// Content of a Realm configuration file
 #[derive (Serialize,Deserialize,Clone)]
 pub struct RealmConfig {
-    #[serde(rename="use-shared-dir")]
+    #[serde(rename="use-shared-dir", alias="shared-dir")]
     pub use_shared_dir: Option<bool>,
 
+    #[serde(rename="share-opt")]
+    pub share_opt: Option<bool>,
+
     #[serde(rename="use-ephemeral-home")]
     pub use_ephemeral_home: Option<bool>,
 
     #[serde(rename="ephemeral-persistent-dirs")]
     pub ephemeral_persistent_dirs: Option<Vec<String>>,
 
+    #[serde(rename="home-mode")]
+    pub home_mode: Option<String>,
+
+    #[serde(rename="persistent-dirs")]
+    pub persistent_dirs: Option<Vec<String>>,
+
+    #[serde(rename="encrypted-home")]
+    pub encrypted_home: Option<bool>,
+
+    #[serde(rename="home-quota")]
+    pub home_quota: Option<String>,
+
+    #[serde(rename="ephemeral-dirs")]
+    pub ephemeral_dirs: Option<Vec<String>>,
+
     #[serde(rename="use-sound")]
     pub use_sound: Option<bool>,
 
+    #[serde(rename="use-pipewire")]
+    pub use_pipewire: Option<bool>,
+
     #[serde(rename="use-x11")]
     pub use_x11: Option<bool>,
 
     #[serde(rename="use-wayland")]
     pub use_wayland: Option<bool>,
 
+    #[serde(rename="desktop-integration")]
+    pub desktop_integration: Option<bool>,
+
+    #[serde(rename="wayland-socket")]
+    pub wayland_socket: Option<String>,
+
+    #[serde(rename="session-bus")]
+    pub session_bus: Option<String>,
+
+    #[serde(rename="session-bus-allow")]
+    pub session_bus_allow: Option<Vec<String>>,
+
+    #[serde(rename="clipboard")]
+    pub clipboard: Option<String>,
+
     #[serde(rename="use-kvm")]
     pub use_kvm: Option<bool>,
 
+    #[serde(rename="use-camera")]
+    pub use_camera: Option<bool>,
+
+    #[serde(rename="usb-devices")]
+    pub usb_devices: Option<Vec<String>>,
+
+    #[serde(rename="block-devices")]
+    pub block_devices: Option<Vec<String>>,
+
+    #[serde(rename="auto-mount-removable")]
+    pub auto_mount_removable: Option<bool>,
+
     #[serde(rename="use-gpu")]
     pub use_gpu: Option<bool>,
 
     #[serde(rename="use-gpu-card0")]
     pub use_gpu_card0: Option<bool>,
 
+    #[serde(rename="gpu-device")]
+    pub gpu_device: Option<String>,
+
+    #[serde(rename="gpu-vendor")]
+    pub gpu_vendor: Option<String>,
+
     #[serde(rename="use-network")]
     pub use_network: Option<bool>,
 
     #[serde(rename="network-zone")]
     pub network_zone: Option<String>,
 
+    #[serde(rename="vpn-required")]
+    pub vpn_required: Option<String>,
+
     #[serde(rename="reserved-ip")]
     pub reserved_ip: Option<u32>,
 
+    #[serde(rename="wait-for-network")]
+    pub wait_for_network: Option<bool>,
+
+    #[serde(rename="network-wait-timeout")]
+    pub network_wait_timeout: Option<u32>,
+
+    #[serde(rename="restart-policy")]
+    pub restart_policy: Option<String>,
+
+    #[serde(rename="restart-max-per-hour")]
+    pub restart_max_per_hour: Option<u32>,
+
+    #[serde(rename="allowed-cpus")]
+    pub allowed_cpus: Option<String>,
+
+    #[serde(rename="cpu-weight")]
+    pub cpu_weight: Option<u32>,
+
+    #[serde(rename="nice")]
+    pub nice: Option<i32>,
+
     #[serde(rename="system-realm")]
     pub system_realm: Option<bool>,
 
     pub autostart: Option<bool>,
 
+    #[serde(rename="follow-focus")]
+    pub follow_focus: Option<bool>,
+
+    pub description: Option<String>,
+
     #[serde(rename="extra-bindmounts")]
     pub extra_bindmounts: Option<Vec<String>>,
 
@@ -105,10 +369,47 @@ pub struct RealmConfig {
     #[serde(rename="terminal-scheme")]
     pub terminal_scheme: Option<String>,
 
+    #[serde(rename="terminal-command")]
+    pub terminal_command: Option<String>,
+
     pub overlay: Option<String>,
 
     pub netns: Option<String>,
 
+    pub environment: Option<Vec<String>>,
+
+    #[serde(rename="drop-capabilities")]
+    pub drop_capabilities: Option<Vec<String>>,
+
+    #[serde(rename="no-new-privileges")]
+    pub no_new_privileges: Option<bool>,
+
+    #[serde(rename="system-call-filter")]
+    pub system_call_filter: Option<String>,
+
+    #[serde(rename="private-users")]
+    pub private_users: Option<bool>,
+
+    pub timezone: Option<String>,
+
+    pub locale: Option<String>,
+
+    pub journal: Option<String>,
+
+    #[serde(rename="journal-max-size")]
+    pub journal_max_size: Option<String>,
+
+    pub dns: Option<Vec<String>>,
+
+    #[serde(rename="dns-search")]
+    pub dns_search: Option<Vec<String>>,
+
+    #[serde(rename="extra-hosts")]
+    pub extra_hosts: Option<Vec<String>>,
+
+    #[serde(rename="port-forwards")]
+    pub port_forwards: Option<Vec<String>>,
+
     #[serde(skip)]
     pub parent: Option<Box<RealmConfig>>,
 
@@ -117,6 +418,84 @@ pub struct RealmConfig {
 
     #[serde(skip)]
     path: PathBuf,
+
+    #[serde(skip)]
+    error: Option<String>,
+}
+
+impl Default for RealmConfig {
+    fn default() -> Self {
+        RealmConfig {
+            use_shared_dir: Some(true),
+            share_opt: Some(true),
+            use_ephemeral_home: Some(false),
+            use_sound: Some(true),
+            use_pipewire: Some(false),
+            use_x11: Some(true),
+            use_wayland: Some(true),
+            desktop_integration: None,
+            wayland_socket: None,
+            session_bus: None,
+            session_bus_allow: None,
+            clipboard: None,
+            use_kvm: Some(false),
+            use_camera: Some(false),
+            usb_devices: None,
+            block_devices: None,
+            auto_mount_removable: Some(false),
+            use_gpu: Some(false),
+            use_gpu_card0: Some(false),
+            gpu_device: None,
+            gpu_vendor: None,
+            use_network: Some(true),
+            ephemeral_persistent_dirs: Some(vec!["Documents".to_string()]),
+            home_mode: None,
+            persistent_dirs: None,
+            encrypted_home: Some(false),
+            home_quota: None,
+            ephemeral_dirs: None,
+            network_zone: Some(DEFAULT_ZONE.into()),
+            vpn_required: None,
+            reserved_ip: None,
+            wait_for_network: Some(false),
+            network_wait_timeout: None,
+            restart_policy: None,
+            restart_max_per_hour: None,
+            allowed_cpus: None,
+            cpu_weight: None,
+            nice: None,
+            system_realm: Some(false),
+            autostart: Some(false),
+            follow_focus: Some(false),
+            description: None,
+            extra_bindmounts: None,
+            extra_bindmounts_ro: None,
+            realm_depends: None,
+            realmfs: Some(DEFAULT_REALMFS.into()),
+            realmfs_write: Some(false),
+            overlay: Some(DEFAULT_OVERLAY.into()),
+            terminal_scheme: None,
+            terminal_command: None,
+            netns: None,
+            environment: None,
+            drop_capabilities: None,
+            no_new_privileges: Some(false),
+            system_call_filter: None,
+            private_users: Some(false),
+            timezone: None,
+            locale: None,
+            journal: None,
+            journal_max_size: None,
+            dns: None,
+            dns_search: None,
+            extra_hosts: None,
+            port_forwards: None,
+            parent: None,
+            loaded: None,
+            path: PathBuf::new(),
+            error: None,
+        }
+    }
 }
 
 impl RealmConfig {
@@ -170,78 +549,115 @@ impl RealmConfig {
         Some(self.read_mtime()) != self.loaded
     }
 
+    /// Reload the config file. If the file has errors the previously loaded
+    /// values are kept and the error is recorded so that it can be reported with
+    /// `error()` until the file is changed.
     pub fn reload(&mut self) -> Result<()> {
         let path = self.path.clone();
 
-        if self.path.exists() {
-            let s = fs::read_to_string(&self.path)?;
-            *self = toml::from_str(&s)?;
-        } else {
-            *self = Self::empty();
-        }
+        let result = self.load_checked();
         self.path = path;
         self.loaded = Some(self.read_mtime());
-        self.parent = Some(Box::new(GLOBAL_CONFIG.clone()));
-        Ok(())
+        self.error = result.as_ref().err().map(|e| e.to_string());
+        if result.is_ok() {
+            self.parent = Some(Box::new(GLOBAL_CONFIG.clone()));
+        }
+        result
     }
 
-    pub fn default() -> Self {
-        RealmConfig {
-            use_shared_dir: Some(true),
-            use_ephemeral_home: Some(false),
-            use_sound: Some(true),
-            use_x11: Some(true),
-            use_wayland: Some(true),
-            use_kvm: Some(false),
-            use_gpu: Some(false),
-            use_gpu_card0: Some(false),
-            use_network: Some(true),
-            ephemeral_persistent_dirs: Some(vec!["Documents".to_string()]),
-            network_zone: Some(DEFAULT_ZONE.into()),
-            reserved_ip: None,
-            system_realm: Some(false),
-            autostart: Some(false),
-            extra_bindmounts: None,
-            extra_bindmounts_ro: None,
-            realm_depends: None,
-            realmfs: Some(DEFAULT_REALMFS.into()),
-            realmfs_write: Some(false),
-            overlay: Some(DEFAULT_OVERLAY.into()),
-            terminal_scheme: None,
-            netns: None,
-            parent: None,
-            loaded: None,
-            path: PathBuf::new(),
+    fn load_checked(&mut self) -> Result<()> {
+        if !self.path.exists() {
+            *self = Self::empty();
+            return Ok(());
+        }
+        let s = fs::read_to_string(&self.path)?;
+        let check = ConfigCheck::check_str(&self.path, &s);
+        for warning in check.warnings() {
+            warn!("{}", warning);
         }
+        if let Some(error) = check.first_error() {
+            bail!("{}", error);
+        }
+        *self = toml::from_str(&s)?;
+        Ok(())
+    }
+
+    /// Return the error from the last attempt to load the config file if it failed.
+    /// A realm with a config error cannot be started.
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
     }
 
     pub fn empty() -> Self {
         RealmConfig {
             use_shared_dir: None,
+            share_opt: None,
             use_ephemeral_home: None,
             use_sound: None,
+            use_pipewire: None,
             use_x11: None,
             use_wayland: None,
+            desktop_integration: None,
+            wayland_socket: None,
+            session_bus: None,
+            session_bus_allow: None,
+            clipboard: None,
             use_kvm: None,
+            use_camera: None,
+            usb_devices: None,
+            block_devices: None,
+            auto_mount_removable: None,
             use_gpu: None,
             use_gpu_card0: None,
+            gpu_device: None,
+            gpu_vendor: None,
             use_network: None,
             network_zone: None,
+            vpn_required: None,
             reserved_ip: None,
+            wait_for_network: None,
+            network_wait_timeout: None,
+            restart_policy: None,
+            restart_max_per_hour: None,
+            allowed_cpus: None,
+            cpu_weight: None,
+            nice: None,
             system_realm: None,
             autostart: None,
+            follow_focus: None,
+            description: None,
             extra_bindmounts: None,
             extra_bindmounts_ro: None,
             realm_depends: None,
             ephemeral_persistent_dirs: None,
+            home_mode: None,
+            persistent_dirs: None,
+            encrypted_home: None,
+            home_quota: None,
+            ephemeral_dirs: None,
             realmfs: None,
             realmfs_write: None,
             overlay: None,
             terminal_scheme: None,
+            terminal_command: None,
             netns: None,
+            environment: None,
+            drop_capabilities: None,
+            no_new_privileges: None,
+            system_call_filter: None,
+            private_users: None,
+            timezone: None,
+            locale: None,
+            journal: None,
+            journal_max_size: None,
+            dns: None,
+            dns_search: None,
+            extra_hosts: None,
+            port_forwards: None,
             parent: None,
             loaded: None,
             path: PathBuf::new(),
+            error: None,
         }
     }
 
@@ -252,6 +668,41 @@ impl RealmConfig {
         self.bool_value(|c| c.use_kvm)
     }
 
+    /// If `true` all video capture devices /dev/video* and media controller
+    /// devices /dev/media* present when the realm starts will be added to realm
+    /// along with udev device information from /run/udev/data.
+    pub fn camera(&self) -> bool {
+        self.bool_value(|c| c.use_camera)
+    }
+
+    /// USB devices to add to realm in the form `vendor:product` such as `1050:0407`
+    /// or `0483:*` to match any product from a vendor.
+    ///
+    /// Matching devices are found only when the realm starts. A device which is
+    /// plugged in while the realm is running is not added until the realm is restarted.
+    pub fn usb_devices(&self) -> Vec<UsbMatcher> {
+        self.str_vec_value(|c| c.usb_devices.as_ref()).into_iter()
+            .flat_map(|s| UsbMatcher::parse(s).ok())
+            .collect()
+    }
+
+    /// Block device nodes to add to the realm, such as `/dev/mmcblk0`. The final
+    /// path component may contain `*` and `?` wildcards which are expanded when
+    /// the realm starts, for example `/dev/disk/by-id/usb-*`.
+    ///
+    /// This option is ignored unless `allow-block-devices = true` is set in
+    /// /etc/citadel/realms.conf on the host.
+    pub fn block_devices(&self) -> Vec<&str> {
+        self.str_vec_value(|c| c.block_devices.as_ref())
+    }
+
+    /// If `true` filesystems on removable devices such as USB sticks which are
+    /// plugged in while this realm is the current realm are mounted by realmsd
+    /// and bound into the realm below /run/media.
+    pub fn auto_mount_removable(&self) -> bool {
+        self.bool_value(|c| c.auto_mount_removable)
+    }
+
 
 
     /// If `true` render node device /dev/dri/renderD128 will be added to realm.
@@ -261,12 +712,24 @@ impl RealmConfig {
         self.bool_value(|c| c.use_gpu)
     }
 
-    /// If `true` and `self.gpu()` is also true, privileged device /dev/dri/card0 will be
-    /// added to realm.
+    /// If `true` and `self.gpu()` is also true, the privileged card device such as
+    /// /dev/dri/card0 which corresponds to each selected render node will be added to realm.
     pub fn gpu_card0(&self) -> bool {
         self.bool_value(|c| c.use_gpu_card0)
     }
 
+    /// Name of the render node such as `renderD129` to add to realm when `self.gpu()`
+    /// is true. If neither this nor `gpu-vendor` is set all render nodes are added.
+    pub fn gpu_device(&self) -> Option<&str> {
+        self.str_value(|c| c.gpu_device.as_ref())
+    }
+
+    /// Add the first render node with a driver from this vendor (`amd`, `intel` or `nvidia`)
+    /// to realm when `self.gpu()` is true.
+    pub fn gpu_vendor(&self) -> Option<&str> {
+        self.str_value(|c| c.gpu_vendor.as_ref())
+    }
+
     /// If `true` the /Shared directory will be mounted in home directory of realm.
     ///
     /// This directory is shared between all running realms and is an easy way to move files
@@ -275,6 +738,11 @@ impl RealmConfig {
         self.bool_value(|c| c.use_shared_dir)
     }
 
+    /// If `true` the host /opt/share directory will be mounted read-only in the realm.
+    pub fn share_opt(&self) -> bool {
+        self.bool_value(|c| c.share_opt)
+    }
+
     /// If `true` the home directory of this realm will be set up in ephemeral mode.
     ///
     /// The ephemeral home directory is set up with the following steps:
@@ -286,7 +754,49 @@ impl RealmConfig {
     ///      mounted from /realms/realm-${name}/home into ephemeral home directory.
     ///
     pub fn ephemeral_home(&self) -> bool {
-        self.bool_value(|c| c.use_ephemeral_home)
+        self.home_mode() == HomeMode::Ephemeral
+    }
+
+    /// The way the home directory of this realm is mounted. If `home-mode` is
+    /// not set the mode is chosen according to the `use-ephemeral-home` option.
+    pub fn home_mode(&self) -> HomeMode {
+        match self.str_value(|c| c.home_mode.as_ref()) {
+            Some(mode) => HomeMode::from_str_value(mode).unwrap_or_else(|| {
+                warn!("Invalid home mode: '{}'", mode);
+                HomeMode::Persistent
+            }),
+            None if self.bool_value(|c| c.use_ephemeral_home) => HomeMode::Ephemeral,
+            None => HomeMode::Persistent,
+        }
+    }
+
+    /// If `true` the home directory of this realm is stored in a LUKS2 container
+    /// file which is unlocked with a key from the kernel keyring and mounted on
+    /// /realms/realm-${name}/home while the realm is running.
+    pub fn encrypted_home(&self) -> bool {
+        self.bool_value(|c| c.encrypted_home)
+    }
+
+    /// Maximum disk space used by the home directory such as `20G`. Enforced with
+    /// a project quota if the filesystem supports it, otherwise the realm is not
+    /// started while its home directory is over the quota.
+    pub fn home_quota(&self) -> Option<&str> {
+        self.str_value(|c| c.home_quota.as_ref())
+    }
+
+    /// A list of subdirectories of /realms/realm-${name}/home which remain writable
+    /// and persistent when home-mode is set to "readonly-overlay".
+    pub fn persistent_dirs(&self) -> Vec<&str> {
+        self.str_vec_value(|c| c.persistent_dirs.as_ref())
+    }
+
+    /// A list of paths relative to /home/user which are replaced with an empty tmpfs
+    /// when home-mode is "persistent", so that caches are not written to the persistent
+    /// home directory. Paths are returned normalized and invalid entries are dropped.
+    pub fn ephemeral_dirs(&self) -> Vec<String> {
+        self.str_vec_value(|c| c.ephemeral_dirs.as_ref()).iter()
+            .flat_map(|dir| normalize_home_subdir(dir))
+            .collect()
     }
 
     /// A list of subdirectories of /realms/realm-${name}/home to bind mount into realm
@@ -311,6 +821,13 @@ impl RealmConfig {
         self.bool_value(|c| c.use_sound)
     }
 
+    /// If `true` the host PipeWire socket will be added to realm as
+    /// /run/user/host/pipewire-0. This is also done when `use-sound` is enabled
+    /// and the host is running PipeWire.
+    pub fn pipewire(&self) -> bool {
+        self.bool_value(|c| c.use_pipewire)
+    }
+
     /// If `true` access to the X11 server will be added to realm by bind mounting
     /// directory /tmp/.X11-unix
     pub fn x11(&self) -> bool {
@@ -320,11 +837,60 @@ impl RealmConfig {
     }
 
     /// If `true` access to Wayland display will be permitted in realm by adding
-    /// wayland socket /run/user/1000/wayland-0
+    /// the host wayland socket from /run/user/1000 as wayland-0
     pub fn wayland(&self) -> bool {
         self.bool_value(|c| c.use_wayland)
     }
 
+    /// If `true` host fonts, icon themes and theme environment variables are shared
+    /// read-only with the realm. Enabled by default when x11 or wayland is enabled.
+    pub fn desktop_integration(&self) -> bool {
+        self.desktop_integration_value()
+            .unwrap_or_else(|| self.x11() || self.wayland())
+    }
+
+    fn desktop_integration_value(&self) -> Option<bool> {
+        if let Some(val) = self.desktop_integration {
+            Some(val)
+        } else if let Some(ref parent) = self.parent {
+            parent.desktop_integration_value()
+        } else {
+            None
+        }
+    }
+
+    /// Name of the host wayland socket in /run/user/1000 or an absolute path to
+    /// the socket. When not set the socket is found from `WAYLAND_DISPLAY` or by
+    /// choosing the newest `wayland-*` socket in /run/user/1000.
+    pub fn wayland_socket(&self) -> Option<&str> {
+        self.str_value(|c| c.wayland_socket.as_ref())
+    }
+
+    /// If `true` the realm is given access to the host session bus through an
+    /// instance of xdg-dbus-proxy which only permits talking to the names listed
+    /// in `session-bus-allow`. This is enabled with `session-bus = "filtered"`.
+    pub fn session_bus_filtered(&self) -> bool {
+        self.str_value(|c| c.session_bus.as_ref()) == Some("filtered")
+    }
+
+    /// Well-known session bus names such as `org.freedesktop.Notifications` which
+    /// a realm with a filtered session bus may talk to. A name may end with `.*`
+    /// to allow all names with that prefix.
+    pub fn session_bus_allow(&self) -> Vec<&str> {
+        self.str_vec_value(|c| c.session_bus_allow.as_ref())
+    }
+
+    /// Policy for relaying clipboard contents between this realm and the host
+    /// through the clipboard socket realmsd provides to the realm. If not set
+    /// the realm has no clipboard socket.
+    pub fn clipboard(&self) -> Option<ClipboardPolicy> {
+        self.str_value(|c| c.clipboard.as_ref())
+            .and_then(|policy| ClipboardPolicy::from_str_value(policy).or_else(|| {
+                warn!("Invalid clipboard policy: '{}'", policy);
+                None
+            }))
+    }
+
     /// If `true` the realm will have access to the network through the zone specified
     /// by `self.network_zone()`
     pub fn network(&self) -> bool {
@@ -336,12 +902,20 @@ impl RealmConfig {
         self.str_value(|c| c.network_zone.as_ref()).unwrap_or(DEFAULT_ZONE)
     }
 
+    /// Name of a VPN network interface such as `wg0` which all traffic leaving the
+    /// host from this realm must pass through. realmsd drops traffic from the realm
+    /// while the interface is down.
+    pub fn vpn_required(&self) -> Option<&str> {
+        self.str_value(|c| c.vpn_required.as_ref())
+    }
+
 
-    /// If configured, this realm uses a fixed IP address on the zone subnet. The last
-    /// octet of the network address for this realm will be set to the provided value.
-    pub fn reserved_ip(&self) -> Option<u8> {
+    /// If configured, this realm uses a fixed IP address on the zone subnet. The value
+    /// is the offset of the address from the start of the subnet and must be in one of
+    /// the reserved ranges of the zone.
+    pub fn reserved_ip(&self) -> Option<u32> {
         if let Some(n) = self.reserved_ip {
-            Some(n as u8)
+            Some(n)
         } else if let Some(ref parent) = self.parent {
             parent.reserved_ip()
         } else {
@@ -349,6 +923,87 @@ impl RealmConfig {
         }
     }
 
+    /// If `true` starting the realm waits until the network address of the realm responds
+    /// before the realm is reported as started. Only applies to realms with network access
+    /// on a zone bridge. Disabled unless enabled for the realm.
+    pub fn wait_for_network(&self) -> bool {
+        self.network() && self.bool_value(|c| c.wait_for_network)
+    }
+
+    /// Number of seconds to wait for the realm network to come up when `wait-for-network` is enabled.
+    pub fn network_wait_timeout(&self) -> u32 {
+        if let Some(n) = self.network_wait_timeout {
+            n
+        } else if let Some(ref parent) = self.parent {
+            parent.network_wait_timeout()
+        } else {
+            DEFAULT_NETWORK_WAIT_TIMEOUT
+        }
+    }
+
+    /// When the realm service is restarted by systemd: `no`, `on-failure` or `always`.
+    /// If not set the realm is only restarted when it is rebooted from inside the realm.
+    pub fn restart_policy(&self) -> Option<&str> {
+        self.str_value(|c| c.restart_policy.as_ref())
+    }
+
+    /// Maximum number of times the realm service may be started in one hour
+    /// before systemd stops restarting it.
+    pub fn restart_max_per_hour(&self) -> Option<u32> {
+        if let Some(n) = self.restart_max_per_hour {
+            Some(n)
+        } else if let Some(ref parent) = self.parent {
+            parent.restart_max_per_hour()
+        } else {
+            None
+        }
+    }
+
+    /// CPUs the realm is restricted to running on, as a list of CPU indexes and ranges such as `2-5` or `0,3`
+    pub fn allowed_cpus(&self) -> Option<&str> {
+        self.str_value(|c| c.allowed_cpus.as_ref())
+    }
+
+    /// Relative share of CPU time given to the realm, from 1 to 10000. The systemd default is 100.
+    pub fn cpu_weight(&self) -> Option<u32> {
+        if let Some(n) = self.cpu_weight {
+            Some(n)
+        } else if let Some(ref parent) = self.parent {
+            parent.cpu_weight()
+        } else {
+            None
+        }
+    }
+
+    /// Scheduling priority of processes in the realm, from -20 to 19.
+    pub fn nice(&self) -> Option<i32> {
+        if let Some(n) = self.nice {
+            Some(n)
+        } else if let Some(ref parent) = self.parent {
+            parent.nice()
+        } else {
+            None
+        }
+    }
+
+    /// Build a config containing only the resource limit options in `limits`, which
+    /// are pairs of option name from `RESOURCE_LIMIT_OPTIONS` and value.
+    pub fn from_resource_limits(limits: &[(String, String)]) -> Result<Self> {
+        let mut config = Self::empty();
+        for (name, value) in limits {
+            match name.as_str() {
+                "allowed-cpus" => config.allowed_cpus = Some(value.clone()),
+                "cpu-weight" => config.cpu_weight = Some(value.parse()
+                    .map_err(|_| format_err!("invalid cpu-weight '{}'", value))?),
+                "nice" => config.nice = Some(value.parse()
+                    .map_err(|_| format_err!("invalid nice '{}'", value))?),
+                _ => bail!("unknown resource limit '{}'. Valid names are: {}", name, RESOURCE_LIMIT_OPTIONS.join(", ")),
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
     /// If `true` this realm is a system utility realm and should not be displayed
     /// in the usual list of user realms.
     pub fn system_realm(&self) -> bool {
@@ -360,6 +1015,19 @@ impl RealmConfig {
         self.bool_value(|c| c.autostart)
     }
 
+    /// If `true` this realm becomes the current realm when a window of the realm
+    /// receives input focus, as reported to realmsd by the compositor. Usually
+    /// enabled for all realms in the global realm config.
+    pub fn follow_focus(&self) -> bool {
+        self.bool_value(|c| c.follow_focus)
+    }
+
+    /// Short description of the realm shown to programs running in the realm
+    /// in the realm info document.
+    pub fn description(&self) -> &str {
+        self.str_value(|c| c.description.as_ref()).unwrap_or("")
+    }
+
     /// A list of additional directories to read-write bind mount into realm.
     pub fn extra_bindmounts(&self) -> Vec<&str> {
         self.str_vec_value(|c| c.extra_bindmounts.as_ref())
@@ -391,6 +1059,12 @@ impl RealmConfig {
         self.str_value(|c| c.terminal_scheme.as_ref())
     }
 
+    /// Command used to launch a terminal in this realm. If the program is not
+    /// available in the realm a list of known terminals is tried instead.
+    pub fn terminal_command(&self) -> Option<&str> {
+        self.str_value(|c| c.terminal_command.as_ref())
+    }
+
     /// The type of overlay on root filesystem to set up for this realm.
     pub fn overlay(&self) -> OverlayType {
         self.str_value(|c| c.overlay.as_ref())
@@ -411,6 +1085,284 @@ impl RealmConfig {
         self.netns().is_some()
     }
 
+    /// If `true` a network namespace for this realm is created when the realm is
+    /// started and removed when it is stopped. This is requested by setting
+    /// netns to the value "auto". Any other value for netns is the name of a
+    /// network namespace which must already exist in /run/netns.
+    pub fn managed_netns(&self) -> bool {
+        self.netns() == Some("auto")
+    }
+
+    /// A list of environment variables in the form `KEY=value` which will be set
+    /// for all processes in the realm. Invalid entries are ignored with a warning.
+    pub fn environment(&self) -> Vec<&str> {
+        self.str_vec_value(|c| c.environment.as_ref())
+            .into_iter()
+            .filter(|item| {
+                let valid = is_valid_environment_item(item);
+                if !valid {
+                    warn!("Ignoring invalid environment variable '{}' in realm config", item);
+                }
+                valid
+            })
+            .collect()
+    }
+
+    /// Capabilities which will be dropped from the realm container.
+    pub fn drop_capabilities(&self) -> Vec<&str> {
+        self.str_vec_value(|c| c.drop_capabilities.as_ref())
+    }
+
+    /// If `true` processes in the realm cannot gain privileges through setuid
+    /// binaries or file capabilities.
+    pub fn no_new_privileges(&self) -> bool {
+        self.bool_value(|c| c.no_new_privileges)
+    }
+
+    /// Name of a system call filter profile in /etc/citadel/seccomp to apply to the realm.
+    pub fn system_call_filter(&self) -> Option<&str> {
+        self.str_value(|c| c.system_call_filter.as_ref())
+    }
+
+    /// If `true` the realm runs in a user namespace so that root inside the
+    /// realm is not mapped to root on the host.
+    pub fn private_users(&self) -> bool {
+        self.bool_value(|c| c.private_users)
+    }
+
+    /// Timezone of the realm. Either the value "host" to use the same timezone
+    /// as the host or the name of a timezone such as "Europe/Berlin". If not set
+    /// the realm keeps whatever timezone is configured in the rootfs.
+    ///
+    /// The timezone is applied when the realm is started, so a change to the host
+    /// timezone only propagates to a running realm the next time it is started.
+    pub fn timezone(&self) -> Option<&str> {
+        self.str_value(|c| c.timezone.as_ref())
+    }
+
+    /// Locale of the realm. Either the value "host" to use /etc/locale.conf from
+    /// the host or the name of a locale such as "en_US.UTF-8". As with timezone
+    /// this is applied when the realm is started.
+    pub fn locale(&self) -> Option<&str> {
+        self.str_value(|c| c.locale.as_ref())
+    }
+
+    /// How the journal of the realm is linked to the host: `auto` and `host`
+    /// are passed to `--link-journal=` of systemd-nspawn and `off` does not
+    /// link the journal so realm logs are not kept on the host.
+    pub fn journal(&self) -> &str {
+        self.str_value(|c| c.journal.as_ref()).unwrap_or("auto")
+    }
+
+    /// Maximum disk space used by the journal inside the realm such as `200M`.
+    /// Applied with a journald.conf drop-in bind mounted into the realm.
+    pub fn journal_max_size(&self) -> Option<&str> {
+        self.str_value(|c| c.journal_max_size.as_ref())
+    }
+
+    /// DNS servers used by this realm. If set, a resolv.conf listing these servers
+    /// is bind mounted into the realm instead of the host resolv.conf. An empty
+    /// list means no resolv.conf is bind mounted at all, which is useful for a
+    /// realm running its own resolver. Changes take effect when the realm is
+    /// next started.
+    pub fn dns(&self) -> Option<Vec<&str>> {
+        if let Some(ref servers) = self.dns {
+            return Some(servers.iter().map(|s| s.as_str()).collect());
+        }
+        self.parent.as_ref().and_then(|parent| parent.dns())
+    }
+
+    /// Search domains added to the resolv.conf generated when `dns` is set.
+    pub fn dns_search(&self) -> Vec<&str> {
+        self.str_vec_value(|c| c.dns_search.as_ref())
+    }
+
+    /// Entries to add to /etc/hosts in the realm in the form `address hostname...`
+    /// where address is an ip address, `@host` for the gateway address of the realm
+    /// network zone, or `@realm:<name>` for the address allocated to another realm.
+    pub fn extra_hosts(&self) -> Vec<&str> {
+        self.str_vec_value(|c| c.extra_hosts.as_ref())
+    }
+
+    /// Ports on the host which are forwarded to the realm in the form
+    /// `protocol:host-port:realm-port` such as `tcp:8080:80`.
+    pub fn port_forwards(&self) -> Vec<PortForward> {
+        self.str_vec_value(|c| c.port_forwards.as_ref()).into_iter()
+            .flat_map(|entry| PortForward::parse(entry).ok())
+            .collect()
+    }
+
+    /// Check that capability names and the system call filter profile set in
+    /// this config are valid and that requested features are supported.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(ref mode) = self.home_mode {
+            if HomeMode::from_str_value(mode).is_none() {
+                bail!("invalid home-mode '{}'. Valid values are: persistent, ephemeral, readonly-overlay", mode);
+            }
+        }
+        if let Some(ref servers) = self.dns {
+            if let Some(bad) = servers.iter().find(|s| s.parse::<IpAddr>().is_err()) {
+                bail!("invalid dns server address '{}'", bad);
+            }
+        }
+        if let Some(ref domains) = self.dns_search {
+            if let Some(bad) = domains.iter().find(|d| !is_valid_search_domain(d)) {
+                bail!("invalid dns-search domain '{}'", bad);
+            }
+        }
+        if let Some(ref hosts) = self.extra_hosts {
+            for entry in hosts {
+                HostsEntry::parse(entry)
+                    .map_err(|e| format_err!("invalid extra-hosts entry '{}': {}", entry, e))?;
+            }
+        }
+        if let Some(ref forwards) = self.port_forwards {
+            let mut seen = Vec::new();
+            for entry in forwards {
+                let f = PortForward::parse(entry)
+                    .map_err(|e| format_err!("invalid port-forwards entry '{}': {}", entry, e))?;
+                if seen.contains(&(f.protocol(), f.host_port())) {
+                    bail!("port-forwards contains more than one entry for {} port {}", f.protocol().to_str_value(), f.host_port());
+                }
+                seen.push((f.protocol(), f.host_port()));
+            }
+        }
+        if let Some(ref device) = self.gpu_device {
+            let valid = device.starts_with("renderD") && device.len() > 7 &&
+                device[7..].chars().all(|c| c.is_ascii_digit());
+            if !valid {
+                bail!("invalid gpu-device '{}'. Expected a render node name such as 'renderD128'", device);
+            }
+        }
+        if let Some(ref vendor) = self.gpu_vendor {
+            if !GPU_VENDORS.contains(&vendor.as_str()) {
+                bail!("invalid gpu-vendor '{}'. Valid values are: {}", vendor, GPU_VENDORS.join(", "));
+            }
+        }
+        if let Some(ref socket) = self.wayland_socket {
+            let path = Path::new(socket);
+            if socket.is_empty() || path.components().any(|c| c == Component::ParentDir) ||
+                (!path.is_absolute() && socket.contains('/')) {
+                bail!("invalid wayland-socket '{}'. Expected a socket name such as 'wayland-1' or an absolute path", socket);
+            }
+        }
+        if let Some(ref policy) = self.restart_policy {
+            if !RESTART_POLICIES.contains(&policy.as_str()) {
+                bail!("invalid restart-policy '{}'. Valid values are: {}", policy, RESTART_POLICIES.join(", "));
+            }
+        }
+        if let Some(timeout) = self.network_wait_timeout {
+            if !(1..=MAX_NETWORK_WAIT_TIMEOUT).contains(&timeout) {
+                bail!("invalid network-wait-timeout {}. Must be between 1 and {} seconds", timeout, MAX_NETWORK_WAIT_TIMEOUT);
+            }
+        }
+        if self.restart_max_per_hour == Some(0) {
+            bail!("restart-max-per-hour must be greater than 0");
+        }
+        if let Some(ref dirs) = self.ephemeral_dirs {
+            let mut persistent = self.ephemeral_persistent_dirs();
+            persistent.extend(self.persistent_dirs().iter().map(|d| d.to_string()));
+            let persistent = persistent.iter()
+                .flat_map(|d| normalize_home_subdir(d))
+                .collect::<Vec<_>>();
+            for dir in dirs {
+                let normalized = normalize_home_subdir(dir)
+                    .ok_or_else(|| format_err!("invalid ephemeral-dirs entry '{}'. Expected a directory inside /home/user", dir))?;
+                let nested = |other: &str| Path::new(&normalized).starts_with(other) || Path::new(other).starts_with(&normalized);
+                if let Some(conflict) = persistent.iter().find(|p| nested(p)) {
+                    bail!("ephemeral-dirs entry '{}' conflicts with persistent directory '{}'", dir, conflict);
+                }
+            }
+        }
+        if let Some(ref cpus) = self.allowed_cpus {
+            if !is_valid_cpu_list(cpus) {
+                bail!("invalid allowed-cpus '{}'. Expected CPU indexes and ranges such as '2-5' or '0,3'", cpus);
+            }
+        }
+        if let Some(weight) = self.cpu_weight {
+            if !(1..=10000).contains(&weight) {
+                bail!("invalid cpu-weight {}. Must be between 1 and 10000", weight);
+            }
+        }
+        if let Some(nice) = self.nice {
+            if !(-20..=19).contains(&nice) {
+                bail!("invalid nice {}. Must be between -20 and 19", nice);
+            }
+        }
+        if let Some(ref mode) = self.session_bus {
+            if mode != "none" && mode != "filtered" {
+                bail!("invalid session-bus '{}'. Valid values are: none, filtered", mode);
+            }
+        }
+        if let Some(ref policy) = self.clipboard {
+            if ClipboardPolicy::from_str_value(policy).is_none() {
+                bail!("invalid clipboard '{}'. Valid values are: isolated, shared, outbound-only", policy);
+            }
+        }
+        if let Some(ref names) = self.session_bus_allow {
+            if let Some(bad) = names.iter().find(|n| !is_valid_bus_name(n)) {
+                bail!("invalid session-bus-allow name '{}'", bad);
+            }
+        }
+        if let Some(ref matchers) = self.usb_devices {
+            for m in matchers {
+                UsbMatcher::parse(m)
+                    .map_err(|e| format_err!("invalid usb-devices entry '{}': {}", m, e))?;
+            }
+        }
+        if let Some(ref patterns) = self.block_devices {
+            if let Some(bad) = patterns.iter().find(|p| {
+                let path = Path::new(p);
+                !path.starts_with("/dev/") || path.components().any(|c| c == Component::ParentDir) ||
+                    path.parent().map(|dir| dir.to_string_lossy().contains(['*', '?'])).unwrap_or(true)
+            }) {
+                bail!("invalid block-devices entry '{}'. Expected a path in /dev with wildcards only in the last component", bad);
+            }
+        }
+        if let Some(ref interface) = self.vpn_required {
+            if !is_valid_interface_name(interface) {
+                bail!("invalid vpn-required '{}'. Expected the name of a network interface such as 'wg0'", interface);
+            }
+        }
+        if let Some(ref tz) = self.timezone {
+            if !is_valid_timezone(tz) {
+                bail!("invalid timezone '{}'. Expected 'host' or a timezone name such as 'Europe/Berlin'", tz);
+            }
+        }
+        if let Some(ref locale) = self.locale {
+            if locale == "host" && !Path::new("/etc/locale.conf").exists() {
+                bail!("locale is set to 'host' but /etc/locale.conf does not exist");
+            } else if !is_valid_locale(locale) {
+                bail!("invalid locale '{}'. Expected 'host' or a locale name such as 'en_US.UTF-8'", locale);
+            }
+        }
+        if let Some(ref journal) = self.journal {
+            if !JOURNAL_MODES.contains(&journal.as_str()) {
+                bail!("invalid journal '{}'. Valid values are: {}", journal, JOURNAL_MODES.join(", "));
+            }
+        }
+        if let Some(ref size) = self.home_quota {
+            if let Err(e) = parse_quota(size) {
+                bail!("invalid home-quota '{}': {}", size, e);
+            }
+        }
+        if let Some(ref size) = self.journal_max_size {
+            if !is_valid_journal_size(size) {
+                bail!("invalid journal-max-size '{}'. Expected a size such as '200M'", size);
+            }
+        }
+        if self.private_users == Some(true) {
+            Systemd::check_private_users_supported()?;
+        }
+        if let Some(ref caps) = self.drop_capabilities {
+            security::validate_drop_capabilities(caps)?;
+        }
+        if let Some(ref profile) = self.system_call_filter {
+            SyscallProfile::load(profile)?;
+        }
+        Ok(())
+    }
+
     fn str_vec_value<F>(&self, get: F) -> Vec<&str>
         where F: Fn(&RealmConfig) -> Option<&Vec<String>>
     {
@@ -448,3 +1400,60 @@ impl RealmConfig {
         false
     }
 }
+
+#[test]
+fn test_environment_item_validation() {
+    assert!(is_valid_environment_item("HTTP_PROXY=http://proxy:3128"));
+    assert!(is_valid_environment_item("_X="));
+    assert!(is_valid_environment_item("QT_QPA_PLATFORM=wayland;xcb"));
+    assert!(!is_valid_environment_item("NOVALUE"));
+    assert!(!is_valid_environment_item("=value"));
+    assert!(!is_valid_environment_item("1ABC=value"));
+    assert!(!is_valid_environment_item("BAD-KEY=value"));
+    assert!(!is_valid_environment_item("KEY=two\nlines"));
+}
+
+#[test]
+fn test_ephemeral_dirs_validation() {
+    assert_eq!(normalize_home_subdir("./.cache/"), Some(".cache".to_string()));
+    assert_eq!(normalize_home_subdir(".local/share/../share/Trash"), Some(".local/share/Trash".to_string()));
+    for bad in &["/tmp", "..", ".cache/../..", ".", "", "a:b"] {
+        assert_eq!(normalize_home_subdir(bad), None, "{}", bad);
+    }
+
+    let mut config = RealmConfig::empty();
+    config.ephemeral_dirs = Some(vec![".cache".to_string(), "Downloads/../.local/share/Trash".to_string()]);
+    assert!(config.validate().is_ok());
+    assert_eq!(config.ephemeral_dirs(), vec![".cache", ".local/share/Trash"]);
+
+    config.ephemeral_persistent_dirs = Some(vec![".cache/mozilla".to_string()]);
+    assert!(config.validate().is_err());
+    config.ephemeral_persistent_dirs = Some(vec![".local".to_string()]);
+    assert!(config.validate().is_err());
+    config.ephemeral_persistent_dirs = Some(vec![".cache-old".to_string()]);
+    assert!(config.validate().is_ok());
+
+    config.ephemeral_dirs = Some(vec!["../other-realm".to_string()]);
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_journal_validation() {
+    for size in &["200M", "1G", "4096", "512K"] {
+        assert!(is_valid_journal_size(size), "{}", size);
+    }
+    for size in &["", "M", "0", "200MB", "-5M", "1.5G", "200 M"] {
+        assert!(!is_valid_journal_size(size), "{}", size);
+    }
+
+    let mut config = RealmConfig::empty();
+    assert_eq!(config.journal(), "auto");
+    config.journal = Some("off".to_string());
+    config.journal_max_size = Some("200M".to_string());
+    assert!(config.validate().is_ok());
+    config.journal = Some("none".to_string());
+    assert!(config.validate().is_err());
+    config.journal = Some("host".to_string());
+    config.journal_max_size = Some("lots".to_string());
+    assert!(config.validate().is_err());
+}
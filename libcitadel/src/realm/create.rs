@@ -1,6 +1,11 @@
 use std::path::{PathBuf, Path};
-use crate::{Realms, Result, util};
-use std::fs;
+use crate::{Realm, Realms, RealmConfig, Result, util};
+use crate::realm::defaults::{RealmDefaults, DEFAULTS_KEYS_FILE};
+use crate::realm::manifest::{self, ManifestTrust};
+use crate::realm::schema::ConfigCheck;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::process;
 
 /// Creation and removal of a Realm
 pub struct RealmCreateDestroy {
@@ -31,13 +36,14 @@ impl RealmCreateDestroy {
         format!("realm-{}", self.name)
     }
 
-    /// Create a new realm with the name `self.name`
-    pub fn create(&self) -> Result<()> {
+    /// Create a new realm with the name `self.name`. The realm is created with
+    /// `config` and any keys it does not set are taken from `defaults`.
+    pub fn create(&self, config: &RealmConfig, defaults: &RealmDefaults) -> Result<()> {
         if self.basepath().exists() {
             bail!("realm directory {} already exists", self.basepath().display());
         }
 
-        if let Err(e) = self.create_realm_directory() {
+        if let Err(e) = self.create_realm_directory(config, defaults) {
             let tmpdir = self.temp_basepath();
             if tmpdir.exists() {
                 let _ = fs::remove_dir_all(tmpdir);
@@ -47,8 +53,112 @@ impl RealmCreateDestroy {
         Ok(())
     }
 
-    fn create_realm_directory(&self) -> Result<()> {
+    /// Create a new realm with the name `self.name` as a copy of the realm `source`.
+    /// The realm is created with `config` and if `clone_home` is set the home
+    /// directory of `source` is copied, otherwise a fresh home directory is created.
+    /// `progress` is called with the number of files copied so far.
+    pub fn create_clone(&self, source: &str, config: &RealmConfig, clone_home: bool, progress: &mut dyn FnMut(usize)) -> Result<()> {
+        if self.basepath().exists() {
+            bail!("realm directory {} already exists", self.basepath().display());
+        }
+
+        if let Err(e) = self.create_clone_directory(source, config, clone_home, progress) {
+            let tmpdir = self.temp_basepath();
+            if tmpdir.exists() {
+                let _ = fs::remove_dir_all(tmpdir);
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Create the realm named in the manifest of the realm export archive at
+    /// `path`. The archive is unpacked into a temporary directory and each file
+    /// is verified against the manifest, which must be signed with the key
+    /// trusted by `trust` unless `allow_unsigned` is set. Returns the name of
+    /// the new realm.
+    pub fn import(path: &Path, trust: &ManifestTrust, allow_unsigned: bool) -> Result<String> {
+        let tmpdir = Self::tmpdir().join(format!("import-{}", process::id()));
+        if tmpdir.exists() {
+            fs::remove_dir_all(&tmpdir)?;
+        }
+        fs::create_dir_all(Self::tmpdir())?;
+
+        let result = Self::unpack_import(path, &tmpdir, trust, allow_unsigned)
+            .and_then(|name| {
+                RealmCreateDestroy::new(&name).finish_import(&tmpdir)?;
+                Ok(name)
+            });
+        if result.is_err() && tmpdir.exists() {
+            let _ = fs::remove_dir_all(&tmpdir);
+        }
+        result
+    }
+
+    fn unpack_import(path: &Path, tmpdir: &Path, trust: &ManifestTrust, allow_unsigned: bool) -> Result<String> {
+        let file = File::open(path)
+            .map_err(|e| format_err!("failed to open realm archive {}: {}", path.display(), e))?;
+        info!("Unpacking realm archive {} to {}", path.display(), tmpdir.display());
+        let manifest = manifest::unpack_archive(BufReader::new(file), tmpdir, trust, allow_unsigned)?;
+        let name = manifest.realm();
+        if !Realm::is_valid_name(name) {
+            bail!("realm archive {} contains realm with invalid name '{}'", path.display(), name);
+        }
+        Ok(name.to_string())
+    }
+
+    fn finish_import(&self, tmpdir: &Path) -> Result<()> {
+        let config = tmpdir.join("config");
+        if !config.exists() {
+            bail!("realm archive does not contain a config file");
+        }
+        if let Some(issue) = ConfigCheck::check_file(&config)?.first_error() {
+            bail!("config file in realm archive is not valid: {}", issue.message());
+        }
+        let home = tmpdir.join("home");
+        if !home.exists() {
+            fs::create_dir(&home)?;
+        }
+        util::chown_tree(&home, (1000, 1000), true)?;
+
+        if self.basepath().exists() {
+            bail!("realm directory {} already exists", self.basepath().display());
+        }
+        fs::rename(tmpdir, self.basepath())?;
+        Ok(())
+    }
+
+    fn create_clone_directory(&self, source: &str, config: &RealmConfig, clone_home: bool, progress: &mut dyn FnMut(usize)) -> Result<()> {
+        if clone_home {
+            self.clone_home(source, progress)?;
+        } else {
+            self.create_home()?;
+        }
+        config.write_config(self.temp_basepath().join("config"))?;
+        self.move_from_temp()?;
+        Ok(())
+    }
+
+    fn clone_home(&self, source: &str, progress: &mut dyn FnMut(usize)) -> Result<()> {
+        let from = RealmCreateDestroy::new(source).basepath().join("home");
+        let home = self.temp_basepath().join("home");
+        fs::create_dir_all(self.temp_basepath())
+            .map_err(|e| format_err!("failed to create directory {}: {}", self.temp_basepath().display(), e))?;
+
+        info!("Copying home directory {} to {}", from.display(), home.display());
+        util::clone_tree(&from, &home, progress).map_err(|e| format_err!("failed to copy home directory from {} to {}: {}", from.display(), home.display(), e))?;
+        Ok(())
+    }
+
+    fn create_realm_directory(&self, config: &RealmConfig, defaults: &RealmDefaults) -> Result<()> {
+        let (config, from_defaults) = defaults.apply(config)?;
         self.create_home()?;
+        defaults.copy_skeleton(&self.temp_basepath().join("skel"))?;
+        config.write_config(self.temp_basepath().join("config"))?;
+        if !from_defaults.is_empty() {
+            let content = from_defaults.iter().map(|key| format!("{}\n", key)).collect::<String>();
+            fs::write(self.temp_basepath().join(DEFAULTS_KEYS_FILE), content)?;
+        }
         self.move_from_temp()?;
         Ok(())
     }
@@ -0,0 +1,150 @@
+use std::fs;
+use std::path::{Path,PathBuf};
+use std::process::{Command,Stdio};
+use std::thread;
+use std::time::Duration;
+
+use crate::{Realm,Result,util};
+
+const XDG_DBUS_PROXY_PATH: &str = "/usr/bin/xdg-dbus-proxy";
+const SYSTEMD_RUN_PATH: &str = "/usr/bin/systemd-run";
+const SYSTEMCTL_PATH: &str = "/usr/bin/systemctl";
+
+/// Session bus socket of the host user
+const HOST_SESSION_BUS: &str = "/run/user/1000/bus";
+
+/// Directory in realm run path containing the filtered session bus socket
+const PROXY_DIR: &str = "dbus-proxy";
+const PROXY_SOCKET: &str = "bus";
+
+/// Location of the proxy directory inside the realm
+pub const REALM_PROXY_DIR: &str = "/run/user/host/dbus-proxy";
+
+/// How long to wait for the proxy to create the filtered socket
+const PROXY_START_TIMEOUT: Duration = Duration::from_secs(5);
+const PROXY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+///
+/// Runs `xdg-dbus-proxy` for a realm configured with `session-bus = "filtered"`.
+///
+/// The proxy runs on the host as a transient unit named `realm-$name-dbus-proxy.service`
+/// which is `PartOf=` the realm service so that it is stopped along with the realm.
+/// It exposes a socket in the realm run directory which only allows talking to
+/// the names listed in `session-bus-allow`.
+///
+pub struct SessionBusProxy<'a> {
+    realm: &'a Realm,
+}
+
+impl <'a> SessionBusProxy<'a> {
+    pub fn new(realm: &'a Realm) -> Self {
+        SessionBusProxy { realm }
+    }
+
+    fn unit_name(&self) -> String {
+        format!("realm-{}-dbus-proxy.service", self.realm.name())
+    }
+
+    /// Directory on the host containing the filtered socket, which is bind
+    /// mounted into the realm as /run/user/host/dbus-proxy
+    pub fn socket_dir(&self) -> PathBuf {
+        self.realm.run_path_file(PROXY_DIR)
+    }
+
+    fn socket_path(&self) -> PathBuf {
+        self.socket_dir().join(PROXY_SOCKET)
+    }
+
+    /// Value of DBUS_SESSION_BUS_ADDRESS inside the realm
+    pub fn realm_bus_address() -> String {
+        format!("unix:path={}/{}", REALM_PROXY_DIR, PROXY_SOCKET)
+    }
+
+    /// Start the proxy and wait until the filtered socket has been created.
+    pub fn start(&self) -> Result<()> {
+        if !Path::new(XDG_DBUS_PROXY_PATH).exists() {
+            bail!("{} is not installed", XDG_DBUS_PROXY_PATH);
+        }
+        if !Path::new(HOST_SESSION_BUS).exists() {
+            bail!("host session bus {} does not exist", HOST_SESSION_BUS);
+        }
+        // Clear any failed instance left from a previous run of the realm
+        self.stop()?;
+
+        let dir = self.socket_dir();
+        fs::create_dir_all(&dir)?;
+        util::chown_user(&dir)?;
+
+        let status = Command::new(SYSTEMD_RUN_PATH)
+            .args(self.systemd_run_args())
+            .stdout(Stdio::null())
+            .status()
+            .map_err(|e| format_err!("failed to execute {}: {}", SYSTEMD_RUN_PATH, e))?;
+        if !status.success() {
+            bail!("{} failed to start {}: {}", SYSTEMD_RUN_PATH, self.unit_name(), status);
+        }
+
+        let mut waited = Duration::from_secs(0);
+        while !self.socket_path().exists() {
+            if waited >= PROXY_START_TIMEOUT {
+                self.stop()?;
+                bail!("{} did not create socket {} (see journalctl -u {})", XDG_DBUS_PROXY_PATH, self.socket_path().display(), self.unit_name());
+            }
+            thread::sleep(PROXY_POLL_INTERVAL);
+            waited += PROXY_POLL_INTERVAL;
+        }
+        info!("Started filtered session bus proxy for realm {}", self.realm.name());
+        Ok(())
+    }
+
+    fn systemd_run_args(&self) -> Vec<String> {
+        let mut args = vec![
+            format!("--unit={}", self.unit_name()),
+            "--uid=1000".to_string(),
+            "--gid=1000".to_string(),
+            format!("--property=PartOf=realm-{}.service", self.realm.name()),
+            XDG_DBUS_PROXY_PATH.to_string(),
+            format!("unix:path={}", HOST_SESSION_BUS),
+            self.socket_path().display().to_string(),
+            "--filter".to_string(),
+        ];
+        for name in self.realm.config().session_bus_allow() {
+            args.push(format!("--talk={}", name));
+        }
+        args
+    }
+
+    /// Stop the proxy if it is running and remove the socket directory.
+    pub fn stop(&self) -> Result<()> {
+        let unit = self.unit_name();
+        Command::new(SYSTEMCTL_PATH)
+            .args(["--quiet", "stop", &unit])
+            .stderr(Stdio::null())
+            .status()?;
+        Command::new(SYSTEMCTL_PATH)
+            .args(["--quiet", "reset-failed", &unit])
+            .stderr(Stdio::null())
+            .status()?;
+        let dir = self.socket_dir();
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_proxy_args() {
+    let realm = Realm::new("proxytest");
+    realm.config();
+    realm.with_mut_config(|c| {
+        c.session_bus = Some("filtered".to_string());
+        c.session_bus_allow = Some(vec!["org.freedesktop.Notifications".to_string(), "org.freedesktop.portal.*".to_string()]);
+    });
+    let args = SessionBusProxy::new(&realm).systemd_run_args();
+    assert_eq!(&args[..4], &["--unit=realm-proxytest-dbus-proxy.service", "--uid=1000", "--gid=1000",
+        "--property=PartOf=realm-proxytest.service"]);
+    assert_eq!(&args[4..6], &[XDG_DBUS_PROXY_PATH, "unix:path=/run/user/1000/bus"]);
+    assert_eq!(args[6], "/run/citadel/realms/realm-proxytest/dbus-proxy/bus");
+    assert_eq!(&args[7..], &["--filter", "--talk=org.freedesktop.Notifications", "--talk=org.freedesktop.portal.*"]);
+}
@@ -0,0 +1,160 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use toml::Value;
+use toml::value::Table;
+
+use crate::{RealmConfig, Result, util};
+use crate::realm::schema::ConfigCheck;
+
+/// Site defaults applied to the config of newly created realms
+pub const REALM_DEFAULTS_PATH: &str = "/etc/citadel/realm-defaults.conf";
+
+/// Key in the defaults file naming a directory which is copied to the skel
+/// directory of new realms. All other keys are realm config keys.
+const SKELETON_KEY: &str = "skeleton";
+
+/// File in the realm base directory listing the config keys which were set
+/// from the defaults file when the realm was created.
+pub(crate) const DEFAULTS_KEYS_FILE: &str = "config-defaults";
+
+///
+/// Defaults for the config of new realms read from /etc/citadel/realm-defaults.conf.
+/// The file contains realm config keys and an optional skeleton directory:
+///
+/// ```text
+/// network-zone = "work"
+/// use-gpu = false
+/// home-mode = "ephemeral"
+/// skeleton = "/storage/realm-skel"
+/// ```
+///
+/// The defaults are written into the config file of a realm when it is created,
+/// so changing the defaults file does not affect realms which already exist.
+///
+#[derive(Default)]
+pub struct RealmDefaults {
+    values: Table,
+    skeleton: Option<PathBuf>,
+}
+
+impl RealmDefaults {
+    /// Read /etc/citadel/realm-defaults.conf, or return empty defaults if it does not exist.
+    pub fn load() -> Result<Self> {
+        let path = Path::new(REALM_DEFAULTS_PATH);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let text = fs::read_to_string(path)
+            .map_err(|e| format_err!("failed to read {}: {}", REALM_DEFAULTS_PATH, e))?;
+        Self::parse(path, &text)
+    }
+
+    fn parse(path: &Path, text: &str) -> Result<Self> {
+        let mut values = match text.parse::<Value>() {
+            Ok(Value::Table(table)) => table,
+            Ok(_) => bail!("{} is not a table of keys", path.display()),
+            Err(e) => bail!("syntax error in {}: {}", path.display(), e),
+        };
+        let skeleton = match values.remove(SKELETON_KEY) {
+            Some(Value::String(ref dir)) if Path::new(dir).is_absolute() => Some(PathBuf::from(dir)),
+            Some(value) => bail!("invalid value for key `{}` in {}: expected an absolute path but found {}", SKELETON_KEY, path.display(), value),
+            None => None,
+        };
+
+        let check = ConfigCheck::check_table(path, text, values.clone());
+        for warning in check.warnings() {
+            warn!("{}", warning);
+        }
+        if let Some(error) = check.first_error() {
+            bail!("{}", error);
+        }
+        Ok(RealmDefaults { values, skeleton })
+    }
+
+    /// Directory which is copied to the skel directory of new realms
+    pub fn skeleton(&self) -> Option<&Path> {
+        self.skeleton.as_deref()
+    }
+
+    ///
+    /// Return `config` with each key it does not set taken from the defaults,
+    /// together with the names of the keys which were taken from the defaults.
+    /// Values set explicitly in `config` always take precedence.
+    ///
+    pub fn apply(&self, config: &RealmConfig) -> Result<(RealmConfig, Vec<String>)> {
+        let mut table = match Value::try_from(config)? {
+            Value::Table(table) => table,
+            _ => bail!("realm config did not serialize to a table"),
+        };
+        let mut from_defaults = Vec::new();
+        for (key, value) in &self.values {
+            if !table.contains_key(key) {
+                table.insert(key.clone(), value.clone());
+                from_defaults.push(key.clone());
+            }
+        }
+        let merged = Value::Table(table).try_into::<RealmConfig>()?;
+        Ok((merged, from_defaults))
+    }
+
+    /// Copy the skeleton directory, if one is configured, to `target`
+    pub fn copy_skeleton(&self, target: &Path) -> Result<()> {
+        let skeleton = match self.skeleton() {
+            Some(skeleton) => skeleton,
+            None => return Ok(()),
+        };
+        if !skeleton.is_dir() {
+            bail!("skeleton directory {} in {} does not exist", skeleton.display(), REALM_DEFAULTS_PATH);
+        }
+        info!("Copying realm skeleton from {} to {}", skeleton.display(), target.display());
+        fs::create_dir_all(target)
+            .map_err(|e| format_err!("failed to create directory {}: {}", target.display(), e))?;
+        util::chown(target, 1000, 1000)
+            .map_err(|e| format_err!("failed to change ownership of {} to 1000:1000: {}", target.display(), e))?;
+        util::copy_tree_with_chown(skeleton, target, (1000, 1000))
+            .map_err(|e| format_err!("failed to copy skeleton directory {} to {}: {}", skeleton.display(), target.display(), e))
+    }
+}
+
+#[test]
+fn test_realm_defaults() {
+    let path = Path::new(REALM_DEFAULTS_PATH);
+    let defaults = RealmDefaults::parse(path, "network-zone = \"work\"\nuse-gpu = false\nhome-mode = \"ephemeral\"\nskeleton = \"/storage/skel\"\n").unwrap();
+    assert_eq!(defaults.skeleton(), Some(Path::new("/storage/skel")));
+
+    // Explicit values take precedence over defaults
+    let mut config = RealmConfig::empty();
+    config.use_gpu = Some(true);
+    config.use_sound = Some(false);
+    let (merged, from_defaults) = defaults.apply(&config).unwrap();
+    assert_eq!(from_defaults, vec!["home-mode", "network-zone"]);
+    assert_eq!(merged.use_gpu, Some(true));
+    assert_eq!(merged.use_sound, Some(false));
+    assert_eq!(merged.network_zone.as_deref(), Some("work"));
+    assert_eq!(merged.home_mode.as_deref(), Some("ephemeral"));
+
+    let (merged, from_defaults) = RealmDefaults::default().apply(&config).unwrap();
+    assert!(from_defaults.is_empty());
+    assert_eq!(merged.network_zone, None);
+
+    for bad in &["use-gpu = \"no\"", "home-mode = \"shared\"", "skeleton = \"skel\"", "skeleton = 1", "use-gpu ="] {
+        assert!(RealmDefaults::parse(path, bad).is_err(), "{}", bad);
+    }
+    // Unknown keys are only a warning as in realm config files
+    assert!(RealmDefaults::parse(path, "use-gpus = false").is_ok());
+
+    let base = crate::util::TempDir::new("defaults-test").unwrap();
+    fs::create_dir_all(base.join("skeleton/.config")).unwrap();
+    fs::write(base.join("skeleton/.config/settings"), "dark").unwrap();
+    let defaults = RealmDefaults::parse(path, &format!("skeleton = \"{}\"", base.join("skeleton").display())).unwrap();
+    let copied = defaults.copy_skeleton(&base.join("skel"))
+        .map(|_| fs::read_to_string(base.join("skel/.config/settings")).unwrap());
+    let missing = RealmDefaults::parse(path, "skeleton = \"/nonexistent/skel\"").unwrap().copy_skeleton(&base.join("other"));
+    // Changing ownership of the copied files is only possible as root
+    if util::is_euid_root() {
+        assert_eq!(copied.unwrap(), "dark");
+    }
+    assert!(missing.is_err());
+    assert!(RealmDefaults::default().copy_skeleton(&base.join("skel")).is_ok());
+}
@@ -1,12 +1,15 @@
+use std::collections::HashMap;
 use std::fs;
 use std::ffi::OsStr;
 use std::fmt::{Display,self};
+use std::ops::BitOr;
 use std::sync::{Arc, RwLock, Weak, RwLockWriteGuard, RwLockReadGuard};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread::{self,JoinHandle};
 use std::path;
 
 use crate::{RealmManager, Result, Realm};
+use super::quota::HomeQuota;
 use super::realms::HasCurrentChanged;
 use dbus::{Connection, BusType, ConnectionItem, Message, Path};
 use inotify::{Inotify, WatchMask, WatchDescriptor, Event};
@@ -17,6 +20,64 @@ pub enum RealmEvent {
     New(Realm),
     Removed(Realm),
     Current(Option<Realm>),
+    Failed(Realm, String),
+    Frozen(Realm),
+    Thawed(Realm),
+    ConfigChanged(Realm),
+}
+
+impl RealmEvent {
+    /// Return the `EventMask` bit for this type of event.
+    pub fn mask(&self) -> EventMask {
+        match self {
+            RealmEvent::Started(_) => EventMask::STARTED,
+            RealmEvent::Stopped(_) => EventMask::STOPPED,
+            RealmEvent::New(_) => EventMask::NEW,
+            RealmEvent::Removed(_) => EventMask::REMOVED,
+            RealmEvent::Current(_) => EventMask::CURRENT,
+            RealmEvent::Failed(..) => EventMask::FAILED,
+            RealmEvent::Frozen(_) => EventMask::FROZEN,
+            RealmEvent::Thawed(_) => EventMask::THAWED,
+            RealmEvent::ConfigChanged(_) => EventMask::CONFIG_CHANGED,
+        }
+    }
+}
+
+/// Set of `RealmEvent` types which an event handler is subscribed to.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct EventMask(u32);
+
+impl EventMask {
+    pub const STARTED: EventMask = EventMask(0x001);
+    pub const STOPPED: EventMask = EventMask(0x002);
+    pub const NEW: EventMask = EventMask(0x004);
+    pub const REMOVED: EventMask = EventMask(0x008);
+    pub const CURRENT: EventMask = EventMask(0x010);
+    pub const CONFIG_CHANGED: EventMask = EventMask(0x020);
+    pub const FAILED: EventMask = EventMask(0x040);
+    pub const FROZEN: EventMask = EventMask(0x080);
+    pub const THAWED: EventMask = EventMask(0x100);
+    pub const ALL: EventMask = EventMask(0x1ff);
+
+    /// Return `true` if every event type in `other` is also in this mask.
+    pub fn contains(self, other: EventMask) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for EventMask {
+    type Output = EventMask;
+    fn bitor(self, rhs: EventMask) -> EventMask {
+        EventMask(self.0 | rhs.0)
+    }
+}
+
+/// Identifies a registered event handler so that it can be removed with
+/// `RealmManager::remove_event_handler()`.
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub struct SubscriptionId {
+    slot: usize,
+    generation: u64,
 }
 
 impl Display for RealmEvent {
@@ -28,29 +89,89 @@ impl Display for RealmEvent {
             RealmEvent::Removed(ref realm)   => write!(f, "RealmRemoved({})", realm.name()),
             RealmEvent::Current(Some(realm)) => write!(f, "RealmCurrent({})", realm.name()),
             RealmEvent::Current(None)        => write!(f, "RealmCurrent(None)"),
+            RealmEvent::Failed(ref realm, _) => write!(f, "RealmFailed({})", realm.name()),
+            RealmEvent::Frozen(ref realm)    => write!(f, "RealmFrozen({})", realm.name()),
+            RealmEvent::Thawed(ref realm)    => write!(f, "RealmThawed({})", realm.name()),
+            RealmEvent::ConfigChanged(ref realm) => write!(f, "RealmConfigChanged({})", realm.name()),
         }
     }
 }
 
-pub type RealmEventHandler = Fn(&RealmEvent)+Send+Sync;
+pub type RealmEventHandler = dyn Fn(&RealmEvent)+Send+Sync;
+
+struct HandlerEntry {
+    generation: u64,
+    mask: EventMask,
+    handler: Arc<RealmEventHandler>,
+}
+
+/// Registered event handlers. Slots of removed handlers are reused and the
+/// generation counter makes sure that a stale `SubscriptionId` never removes
+/// a handler which was added later in the same slot.
+#[derive(Default)]
+struct HandlerSlab {
+    entries: Vec<Option<HandlerEntry>>,
+    free: Vec<usize>,
+    generation: u64,
+}
+
+impl HandlerSlab {
+    fn insert(&mut self, mask: EventMask, handler: Arc<RealmEventHandler>) -> SubscriptionId {
+        self.generation += 1;
+        let generation = self.generation;
+        let entry = Some(HandlerEntry { generation, mask, handler });
+        let slot = match self.free.pop() {
+            Some(slot) => {
+                self.entries[slot] = entry;
+                slot
+            },
+            None => {
+                self.entries.push(entry);
+                self.entries.len() - 1
+            },
+        };
+        SubscriptionId { slot, generation }
+    }
+
+    fn remove(&mut self, id: SubscriptionId) -> bool {
+        match self.entries.get_mut(id.slot) {
+            Some(entry) if entry.as_ref().map(|e| e.generation) == Some(id.generation) => {
+                *entry = None;
+                self.free.push(id.slot);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    fn matching(&self, event: &RealmEvent) -> Vec<Arc<RealmEventHandler>> {
+        let mask = event.mask();
+        self.entries.iter()
+            .flatten()
+            .filter(|e| e.mask.contains(mask))
+            .map(|e| e.handler.clone())
+            .collect()
+    }
+}
 
 pub struct RealmEventListener {
     inner: Arc<RwLock<Inner>>,
+    handlers: Arc<RwLock<HandlerSlab>>,
     running: Arc<AtomicBool>,
     join: Vec<JoinHandle<Result<()>>>,
 }
 
 struct Inner {
     manager: Weak<RealmManager>,
-    handlers: Vec<Box<RealmEventHandler>>,
+    handlers: Arc<RwLock<HandlerSlab>>,
     quit: Arc<AtomicBool>,
 }
 
 impl Inner {
-    fn new() -> Self {
+    fn new(handlers: Arc<RwLock<HandlerSlab>>) -> Self {
         Inner {
             manager: Weak::new(),
-            handlers: Vec::new(),
+            handlers,
             quit: Arc::new(AtomicBool::new(false)),
         }
     }
@@ -59,15 +180,11 @@ impl Inner {
         self.manager = Arc::downgrade(manager);
     }
 
-    pub fn add_handler<F>(&mut self, handler: F)
-        where F: Fn(&RealmEvent),
-              F: 'static + Send + Sync
-    {
-        self.handlers.push(Box::new(handler));
-    }
-
+    // The handler lock is released before the handlers are called so that a
+    // handler may add or remove handlers, including itself.
     fn send_event(&self, event: RealmEvent) {
-        self.handlers.iter().for_each(|cb| (cb)(&event));
+        let handlers = self.handlers.read().unwrap().matching(&event);
+        handlers.iter().for_each(|cb| (cb)(&event));
     }
 
     fn quit_flag(&self) -> bool {
@@ -90,8 +207,10 @@ impl Inner {
 impl RealmEventListener {
 
     pub fn new() -> Self {
+        let handlers = Arc::new(RwLock::new(HandlerSlab::default()));
         RealmEventListener {
-            inner: Arc::new(RwLock::new(Inner::new())),
+            inner: Arc::new(RwLock::new(Inner::new(handlers.clone()))),
+            handlers,
             running: Arc::new(AtomicBool::new(false)),
             join: Vec::new(),
         }
@@ -109,24 +228,37 @@ impl RealmEventListener {
         self.running.swap(val, Ordering::SeqCst)
     }
 
-    pub fn add_handler<F>(&self, handler: F)
+    /// Add a handler which is called for each event with a type in `mask`.
+    pub fn add_handler<F>(&self, mask: EventMask, handler: F) -> SubscriptionId
         where F: Fn(&RealmEvent),
               F: 'static + Send + Sync
     {
-        self.inner_mut().add_handler(handler);
+        self.handlers.write().unwrap().insert(mask, Arc::new(handler))
     }
 
-    fn inner_mut(&self) -> RwLockWriteGuard<Inner> {
+    /// Remove a handler and return `true` if it was still registered. A handler
+    /// removed while an event is being dispatched may still receive that event.
+    pub fn remove_handler(&self, id: SubscriptionId) -> bool {
+        self.handlers.write().unwrap().remove(id)
+    }
+
+    pub fn send_event(&self, event: RealmEvent) {
+        self.inner().send_event(event);
+    }
+
+    fn inner_mut(&self) -> RwLockWriteGuard<'_, Inner> {
         self.inner.write().unwrap()
     }
 
-    fn inner(&self) -> RwLockReadGuard<Inner> {
+    fn inner(&self) -> RwLockReadGuard<'_, Inner> {
         self.inner.read().unwrap()
     }
 
+    /// Start the tasks which listen for realm events. Calling this when the
+    /// tasks are already running does nothing.
     pub fn start_event_task(&mut self) -> Result<()> {
         if self.set_running(true) {
-            warn!("RealmEventListener already running");
+            verbose!("RealmEventListener already running");
             return Ok(());
         }
 
@@ -224,7 +356,7 @@ impl DbusEventListener {
         Ok(())
     }
 
-    fn inner(&self) -> RwLockReadGuard<Inner> {
+    fn inner(&self) -> RwLockReadGuard<'_, Inner> {
         self.inner.read().unwrap()
     }
 
@@ -258,9 +390,10 @@ impl DbusEventListener {
 
     fn on_machine_new(&self, name: &str) {
         self.inner().with_manager(|m| {
-            if let Some(realm) = m.realm_by_name(name) {
-                realm.set_active(true);
-                self.inner().send_event(RealmEvent::Started(realm))
+            if let Some(realm) = m.on_machine_new(name) {
+                if !realm.defer_started_event() {
+                    self.inner().send_event(RealmEvent::Started(realm))
+                }
             }
         });
     }
@@ -280,7 +413,8 @@ struct InotifyEventListener {
     inotify: Inotify,
     realms_watch: WatchDescriptor,
     current_watch: WatchDescriptor,
-
+    // Watches on realm directories for changes to config files, mapped to realm name
+    config_watches: HashMap<WatchDescriptor, String>,
 }
 
 impl InotifyEventListener {
@@ -290,7 +424,8 @@ impl InotifyEventListener {
         let realms_watch = inotify.add_watch("/realms", WatchMask::MOVED_FROM|WatchMask::MOVED_TO)?;
         let current_watch = inotify.add_watch("/run/citadel/realms/current", WatchMask::CREATE|WatchMask::MOVED_TO)?;
 
-        Ok(InotifyEventListener { inner, inotify, realms_watch, current_watch, })
+        let config_watches = HashMap::new();
+        Ok(InotifyEventListener { inner, inotify, realms_watch, current_watch, config_watches })
     }
 
     fn wake_inotify() -> Result<()> {
@@ -305,6 +440,14 @@ impl InotifyEventListener {
     }
 
     fn inotify_event_loop(&mut self) -> Result<()> {
+        // Not done in create() because the realm manager is locked while the task is started
+        let realms = self.inner().manager.upgrade()
+            .map(|m| m.realm_list())
+            .unwrap_or_default();
+        for realm in &realms {
+            self.add_config_watch(realm);
+        }
+
         let mut buffer = [0; 1024];
         while !self.inner().quit_flag() {
             let events = self.inotify.read_events_blocking(&mut buffer)?;
@@ -319,15 +462,51 @@ impl InotifyEventListener {
         Ok(())
     }
 
-    fn handle_event(&self, event: Event<&OsStr>) {
+    fn handle_event(&mut self, event: Event<&OsStr>) {
         self.log_event(&event);
         if event.wd == self.current_watch {
             self.handle_current_event();
         } else if event.wd == self.realms_watch {
             self.handle_realm_event();
+        } else if event.name == Some(OsStr::new("config")) {
+            if let Some(name) = self.config_watches.get(&event.wd) {
+                self.handle_config_event(name);
+            }
+        }
+    }
+
+    fn add_config_watch(&mut self, realm: &Realm) {
+        match self.inotify.add_watch(realm.base_path(), WatchMask::CLOSE_WRITE|WatchMask::MOVED_TO) {
+            Ok(wd) => { self.config_watches.insert(wd, realm.name().to_string()); },
+            Err(e) => warn!("error watching config file of realm {}: {}", realm.name(), e),
+        }
+    }
+
+    fn remove_config_watch(&mut self, realm: &Realm) {
+        let wd = self.config_watches.iter()
+            .find(|(_, name)| name.as_str() == realm.name())
+            .map(|(wd, _)| wd.clone());
+        if let Some(wd) = wd {
+            self.config_watches.remove(&wd);
+            // Fails if the directory is already gone, which also removes the watch
+            let _ = self.inotify.rm_watch(wd);
         }
     }
 
+    fn handle_config_event(&self, name: &str) {
+        self.inner().with_manager(|m| {
+            if let Some(realm) = m.realm_by_name(name) {
+                m.check_reserved_addresses();
+                if realm.is_active() {
+                    if let Err(e) = HomeQuota::new(&realm).apply() {
+                        warn!("Failed to apply home quota of realm {}: {}", realm.name(), e);
+                    }
+                }
+                self.inner().send_event(RealmEvent::ConfigChanged(realm));
+            }
+        })
+    }
+
     fn log_event(&self, event: &Event<&OsStr>) {
         if let Some(name) = event.name {
             let path = path::Path::new("/realms").join(name);
@@ -338,7 +517,7 @@ impl InotifyEventListener {
         }
     }
 
-    fn inner(&self) -> RwLockReadGuard<Inner> {
+    fn inner(&self) -> RwLockReadGuard<'_, Inner> {
         self.inner.read().unwrap()
     }
 
@@ -350,21 +529,81 @@ impl InotifyEventListener {
         })
     }
 
-    fn handle_realm_event(&self) {
-        self.inner().with_manager(|m| {
-            let (added,removed) = match m.rescan_realms() {
-                Ok(result) => result,
-                Err(e) => {
-                    warn!("error rescanning realms: {}", e);
-                    return;
-                }
-            };
-            for realm in added {
-                self.inner().send_event(RealmEvent::New(realm));
+    fn handle_realm_event(&mut self) {
+        let manager = match self.inner().manager.upgrade() {
+            Some(manager) => manager,
+            None => return,
+        };
+        let (added,removed) = match manager.rescan_realms() {
+            Ok(result) => result,
+            Err(e) => {
+                warn!("error rescanning realms: {}", e);
+                return;
             }
-            for realm in removed {
-                self.inner().send_event(RealmEvent::Removed(realm));
+        };
+        for realm in added {
+            self.add_config_watch(&realm);
+            self.inner().send_event(RealmEvent::New(realm));
+        }
+        for realm in removed {
+            self.remove_config_watch(&realm);
+            self.inner().send_event(RealmEvent::Removed(realm));
+        }
+    }
+}
+
+#[test]
+fn test_event_handler_subscriptions() {
+    use std::sync::Mutex;
+    use std::sync::atomic::AtomicUsize;
+
+    const EVENT_COUNT: usize = 1000;
+    let listener = Arc::new(RealmEventListener::new());
+    let received = Arc::new(AtomicUsize::new(0));
+    let masked = Arc::new(AtomicUsize::new(0));
+    let once = Arc::new(AtomicUsize::new(0));
+
+    listener.add_handler(EventMask::CURRENT | EventMask::NEW, {
+        let received = received.clone();
+        move |_| { received.fetch_add(1, Ordering::SeqCst); }
+    });
+    listener.add_handler(EventMask::STARTED | EventMask::STOPPED, {
+        let masked = masked.clone();
+        move |_| { masked.fetch_add(1, Ordering::SeqCst); }
+    });
+
+    // A handler which removes itself from inside the dispatch of its first event
+    let own_id = Arc::new(Mutex::new(None));
+    let id = listener.add_handler(EventMask::ALL, {
+        let (listener, own_id, once) = (Arc::downgrade(&listener), own_id.clone(), once.clone());
+        move |_| {
+            once.fetch_add(1, Ordering::SeqCst);
+            if let (Some(listener), Some(id)) = (listener.upgrade(), own_id.lock().unwrap().take()) {
+                assert!(listener.remove_handler(id));
+            }
+        }
+    });
+    *own_id.lock().unwrap() = Some(id);
+
+    // Add and remove handlers from other threads while events are dispatched
+    let churn = (0..4).map(|_| {
+        let listener = listener.clone();
+        thread::spawn(move || {
+            for _ in 0..EVENT_COUNT / 4 {
+                let id = listener.add_handler(EventMask::ALL, |_| {});
+                assert!(listener.remove_handler(id));
+                assert!(!listener.remove_handler(id));
             }
         })
+    }).collect::<Vec<_>>();
+
+    for _ in 0..EVENT_COUNT {
+        listener.send_event(RealmEvent::Current(None));
     }
+    churn.into_iter().for_each(|h| h.join().unwrap());
+
+    assert_eq!(received.load(Ordering::SeqCst), EVENT_COUNT);
+    assert_eq!(masked.load(Ordering::SeqCst), 0);
+    assert_eq!(once.load(Ordering::SeqCst), 1);
+    assert!(!listener.remove_handler(id));
 }
@@ -0,0 +1,322 @@
+use std::cmp;
+use std::fs::{self,OpenOptions};
+use std::io::Write;
+use std::path::{Path,PathBuf};
+use std::process::{Command,Stdio};
+
+use sodiumoxide::randombytes::randombytes_into;
+use walkdir::WalkDir;
+
+use crate::{BootStatus, Exec, KeyRing, Mounts, Realm, Result, util};
+
+const CRYPTSETUP_PATH: &str = "/sbin/cryptsetup";
+const MKFS_EXT4_PATH: &str = "/sbin/mkfs.ext4";
+const CP_PATH: &str = "/usr/bin/cp";
+const KEYRING_PATH: &str = "/storage/keyring";
+
+/// Container file in the realm directory holding the encrypted home directory
+const CONTAINER_FILE: &str = "home.luks";
+/// Directory the container is mounted on while it is being created
+const STAGING_DIR: &str = "home.encrypting";
+/// Name the plaintext home directory is renamed to when it is migrated into a container
+const PLAINTEXT_BACKUP_DIR: &str = "home.plaintext";
+
+const KEY_LEN: usize = 64;
+/// The container file is sparse so only the space used by files is allocated
+const DEFAULT_CONTAINER_SIZE: u64 = 32 * 1024 * 1024 * 1024;
+
+/// Error returned when the encrypted home directory of a realm cannot be
+/// unlocked because its key is not available in the kernel keyring.
+#[derive(Debug,Fail)]
+#[fail(display = "keyring locked: cannot unlock encrypted home of realm {}: {}", realm, reason)]
+pub struct KeyringLockedError {
+    realm: String,
+    reason: String,
+}
+
+///
+/// The home directory of a realm configured with `encrypted-home = true`.
+///
+/// The files are stored in a LUKS2 container /realms/realm-${name}/home.luks
+/// keyed by the key `realm-home-${name}` from the keyring. While the realm is
+/// running the container is opened as /dev/mapper/citadel-home-${name} and
+/// mounted on /realms/realm-${name}/home.
+///
+pub struct EncryptedHome<'a> {
+    realm: &'a Realm,
+}
+
+impl <'a> EncryptedHome<'a> {
+    pub fn new(realm: &'a Realm) -> Self {
+        EncryptedHome { realm }
+    }
+
+    /// Name of the key in the keyring which unlocks the container
+    pub fn key_name(&self) -> String {
+        format!("realm-home-{}", self.realm.name())
+    }
+
+    fn mapping_name(&self) -> String {
+        format!("citadel-home-{}", self.realm.name())
+    }
+
+    fn mapping_device(&self) -> PathBuf {
+        Path::new("/dev/mapper").join(self.mapping_name())
+    }
+
+    pub fn container_path(&self) -> PathBuf {
+        self.realm.base_path_file(CONTAINER_FILE)
+    }
+
+    fn home_path(&self) -> PathBuf {
+        self.realm.base_path_file("home")
+    }
+
+    /// Returns `true` if the container file has been created
+    pub fn exists(&self) -> bool {
+        self.container_path().exists()
+    }
+
+    /// Returns `true` if the container is mapped or mounted
+    pub fn is_open(&self) -> bool {
+        self.mapping_device().exists() || Mounts::is_source_mounted(self.mapping_device()).unwrap_or(false)
+    }
+
+    ///
+    /// Unlock the container and mount it on the home directory of the realm,
+    /// creating the container first if it does not exist yet. A mapping left
+    /// behind by a realm which was not stopped cleanly is closed first so that
+    /// it does not prevent the container from being opened.
+    ///
+    pub fn open(&self) -> Result<()> {
+        if self.is_open() {
+            warn!("Closing stale encrypted home mapping {} of realm {}", self.mapping_name(), self.realm.name());
+            self.close()?;
+        }
+        let home = self.home_path();
+        let mut key = if self.exists() {
+            self.unlock_key()?
+        } else {
+            if Self::has_files(&home)? {
+                bail!("realm {} has a plaintext home directory, use 'citadel-tool realm encrypt-home {}' to move it into an encrypted container",
+                      self.realm.name(), self.realm.name());
+            }
+            info!("Creating encrypted home directory for realm {}", self.realm.name());
+            let key = self.new_key()?;
+            self.build_container(&key, DEFAULT_CONTAINER_SIZE, None)?;
+            key
+        };
+        let result = self.mount(&key, &home);
+        key.iter_mut().for_each(|b| *b = 0);
+        result
+    }
+
+    /// Unmount the home directory and close the container mapping.
+    pub fn close(&self) -> Result<()> {
+        for dir in &[self.home_path(), self.realm.base_path_file(STAGING_DIR)] {
+            if Mounts::is_target_mounted(dir)? {
+                util::umount(dir)?;
+            }
+        }
+        if self.mapping_device().exists() {
+            self.close_mapping()?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Copy the existing plaintext home directory of the stopped realm into a
+    /// new container. Once the copy is complete the plaintext directory is
+    /// renamed to /realms/realm-${name}/home.plaintext and replaced with an
+    /// empty mount point. Returns the path of the plaintext directory.
+    ///
+    pub fn migrate(&self) -> Result<PathBuf> {
+        if self.exists() {
+            bail!("realm {} already has an encrypted home directory", self.realm.name());
+        }
+        let home = self.home_path();
+        let backup = self.realm.base_path_file(PLAINTEXT_BACKUP_DIR);
+        if backup.exists() {
+            bail!("{} already exists, remove it before encrypting the home directory again", backup.display());
+        }
+        if self.is_open() {
+            self.close()?;
+        }
+        let size = cmp::max(DEFAULT_CONTAINER_SIZE, Self::tree_size(&home)? * 2);
+        let mut key = self.new_key()?;
+        let result = self.build_container(&key, size, Some(&home));
+        key.iter_mut().for_each(|b| *b = 0);
+        result?;
+
+        if home.exists() {
+            fs::rename(&home, &backup)?;
+        }
+        fs::create_dir(&home)?;
+        util::chown_user(&home)?;
+        Ok(backup)
+    }
+
+    // Read the key of the container from the kernel keyring.
+    fn unlock_key(&self) -> Result<Vec<u8>> {
+        KeyRing::get_kernel_secret(&self.key_name())
+            .map_err(|_| self.keyring_locked(None).into())
+    }
+
+    // Generate a key for a new container and add it to the keyring. A key left
+    // in the kernel keyring by an earlier attempt which did not complete is reused.
+    fn new_key(&self) -> Result<Vec<u8>> {
+        if let Ok(key) = KeyRing::get_kernel_secret(&self.key_name()) {
+            return Ok(key);
+        }
+        let mut key = vec![0u8; KEY_LEN];
+        randombytes_into(&mut key);
+        if let Err(e) = KeyRing::add_key_with_cryptsetup_passphrase(KEYRING_PATH, &self.key_name(), &key) {
+            key.iter_mut().for_each(|b| *b = 0);
+            return Err(self.keyring_locked(Some(&e.to_string())).into());
+        }
+        Ok(key)
+    }
+
+    fn keyring_locked(&self, error: Option<&str>) -> KeyringLockedError {
+        KeyringLockedError {
+            realm: self.realm.name().to_string(),
+            reason: Self::locked_reason(&self.key_name(), BootStatus::load().keyring_failed(), error),
+        }
+    }
+
+    // A keyring failure recorded at boot is the most likely reason that the
+    // key is missing, so it is reported in preference to the immediate error.
+    fn locked_reason(key_name: &str, boot_failure: Option<&str>, error: Option<&str>) -> String {
+        match (boot_failure, error) {
+            (Some(failure), _) => format!("the keyring failed to load at boot: {}", failure),
+            (None, Some(error)) => format!("could not add key {} to the keyring: {}", key_name, error),
+            (None, None) => format!("key {} is not in the kernel keyring", key_name),
+        }
+    }
+
+    // Format a new container of `size` bytes, create a filesystem in it and
+    // copy the files of `source` into it. The container is written to a
+    // temporary file which is renamed once it is complete, so that a failure
+    // never leaves a partial container behind.
+    fn build_container(&self, key: &[u8], size: u64, source: Option<&Path>) -> Result<()> {
+        let container = self.container_path();
+        let tmp = container.with_extension("luks.tmp");
+        let staging = self.realm.base_path_file(STAGING_DIR);
+        let result = self.format_container(&tmp, key, size)
+            .and_then(|_| self.populate_container(&staging, source));
+
+        if Mounts::is_target_mounted(&staging).unwrap_or(false) {
+            if let Err(e) = util::umount(&staging) {
+                warn!("failed to unmount {}: {}", staging.display(), e);
+            }
+        }
+        let _ = fs::remove_dir(&staging);
+        if self.mapping_device().exists() {
+            if let Err(e) = self.close_mapping() {
+                warn!("failed to close {}: {}", self.mapping_name(), e);
+            }
+        }
+        match result {
+            Ok(()) => fs::rename(&tmp, &container)?,
+            Err(e) => {
+                let _ = fs::remove_file(&tmp);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn format_container(&self, path: &Path, key: &[u8], size: u64) -> Result<()> {
+        let file = OpenOptions::new().write(true).create_new(true).open(path)
+            .map_err(|e| format_err!("failed to create {}: {}", path.display(), e))?;
+        file.set_len(size)?;
+        let path = path.display().to_string();
+        Self::cryptsetup(&["luksFormat", "--type", "luks2", "--batch-mode", &path], key)?;
+        Self::cryptsetup(&["open", "--type", "luks2", &path, &self.mapping_name()], key)?;
+        Exec::new(MKFS_EXT4_PATH)
+            .args(["-q", "-L", "home"])
+            .arg(self.mapping_device().display().to_string())
+            .capture()?
+            .check()
+    }
+
+    fn populate_container(&self, staging: &Path, source: Option<&Path>) -> Result<()> {
+        fs::create_dir_all(staging)?;
+        util::mount(self.mapping_device().display().to_string(), staging, None)?;
+        util::chown_user(staging)?;
+        if let Some(source) = source.filter(|s| s.exists()) {
+            info!("Copying {} into encrypted home directory of realm {}", source.display(), self.realm.name());
+            Exec::new(CP_PATH)
+                .args(["-a", &format!("{}/.", source.display()), &format!("{}/", staging.display())])
+                .capture()?
+                .check()?;
+        }
+        util::umount(staging)
+    }
+
+    fn mount(&self, key: &[u8], home: &Path) -> Result<()> {
+        let container = self.container_path().display().to_string();
+        Self::cryptsetup(&["open", "--type", "luks2", &container, &self.mapping_name()], key)?;
+        fs::create_dir_all(home)?;
+        if let Err(e) = util::mount(self.mapping_device().display().to_string(), home, None) {
+            self.close_mapping()?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn close_mapping(&self) -> Result<()> {
+        Exec::new(CRYPTSETUP_PATH)
+            .args(["close", &self.mapping_name()])
+            .capture()?
+            .check()
+    }
+
+    // Run cryptsetup with `key` passed on stdin so that it is never written to disk
+    fn cryptsetup(args: &[&str], key: &[u8]) -> Result<()> {
+        let mut child = Command::new(CRYPTSETUP_PATH)
+            .args(args)
+            .arg("--key-file=-")
+            .stdin(Stdio::piped()).stdout(Stdio::null()).stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format_err!("failed to execute {}: {}", CRYPTSETUP_PATH, e))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(key)?;
+        }
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            bail!("cryptsetup {} failed: {}", args[0], String::from_utf8_lossy(&output.stderr).trim());
+        }
+        Ok(())
+    }
+
+    fn has_files(dir: &Path) -> Result<bool> {
+        if !dir.exists() {
+            return Ok(false);
+        }
+        Ok(fs::read_dir(dir)?.next().is_some())
+    }
+
+    fn tree_size(dir: &Path) -> Result<u64> {
+        let mut size = 0;
+        if dir.exists() {
+            for entry in WalkDir::new(dir) {
+                size += entry?.metadata()?.len();
+            }
+        }
+        Ok(size)
+    }
+}
+
+#[test]
+fn test_keyring_locked_reason() {
+    assert_eq!(EncryptedHome::locked_reason("realm-home-main", None, None),
+               "key realm-home-main is not in the kernel keyring");
+    assert_eq!(EncryptedHome::locked_reason("realm-home-main", None, Some("kernel key 'cryptsetup' not found")),
+               "could not add key realm-home-main to the keyring: kernel key 'cryptsetup' not found");
+    assert_eq!(EncryptedHome::locked_reason("realm-home-main", Some("Failed to decrypt keyring"), Some("ignored")),
+               "the keyring failed to load at boot: Failed to decrypt keyring");
+
+    let err = KeyringLockedError { realm: "main".to_string(), reason: "key realm-home-main is not in the kernel keyring".to_string() };
+    assert!(err.to_string().starts_with("keyring locked: "), "{}", err);
+}
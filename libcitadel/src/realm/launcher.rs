@@ -1,18 +1,28 @@
+use std::collections::{BTreeMap,HashSet};
+use std::env;
 use std::fs;
 use std::fmt::Write;
 
-use crate::{Realm,Result};
-use std::path::{Path, PathBuf};
-use crate::realm::network::NetworkConfig;
+use crate::{Realm,Result,HomeMode};
+use crate::realm::config::is_valid_environment_item;
+use std::path::{Component, Path, PathBuf};
+use crate::realm::network::{NetworkConfig,NetnsManager,HostsEntry,HostsAddress};
+use crate::realm::security::{SyscallProfile,SECCOMP_PROFILE_PATH};
+use crate::realm::systemd::Systemd;
+use crate::realm::usb::UsbDevice;
+use crate::realm::block::BlockDevices;
+use crate::realm::dbus_proxy::{SessionBusProxy,REALM_PROXY_DIR};
 
 const NSPAWN_FILE_TEMPLATE: &str = "\
 [Exec]
 Boot=true
+$EXEC_TIMEZONE
+$EXEC_ENVIRONMENT
+$SECURITY_OPTIONS
 $NETWORK_CONFIG
 
 [Files]
-BindReadOnly=/opt/share
-BindReadOnly=/storage/citadel-state/resolv.conf:/etc/resolv.conf
+$RESOLV_CONF_BIND
 
 $EXTRA_BIND_MOUNTS
 
@@ -23,55 +33,531 @@ $EXTRA_FILE_OPTIONS
 const REALM_SERVICE_TEMPLATE: &str = "\
 [Unit]
 Description=Application Image $REALM_NAME instance
+After=citadel-apply-staged-update.service
+$START_LIMIT
 
 [Service]
 
 DevicePolicy=closed
 $DEVICE_ALLOW
+$RESOURCE_OPTIONS
 
 Environment=SYSTEMD_NSPAWN_SHARE_NS_IPC=1
-ExecStart=/usr/bin/systemd-nspawn --quiet --notify-ready=yes --keep-unit $NETNS_ARG --machine=$REALM_NAME --link-journal=auto --directory=$ROOTFS
+ExecStart=/usr/bin/systemd-nspawn --quiet --notify-ready=yes --keep-unit $NETNS_ARG --machine=$REALM_NAME --link-journal=$LINK_JOURNAL --directory=$ROOTFS
 
 KillMode=mixed
 Type=notify
-RestartForceExitStatus=133
+$RESTART_OPTIONS
 SuccessExitStatus=133
 ";
 
 const SYSTEMD_NSPAWN_PATH: &str = "/run/systemd/nspawn";
+
+/// Delay before restarting a realm when a restart-policy is configured
+const RESTART_SEC: u32 = 5;
+const ZONEINFO_PATH: &str = "/usr/share/zoneinfo";
+const LOCALTIME_FILE: &str = "localtime";
+const LOCALE_CONF_FILE: &str = "locale.conf";
+const RESOLV_CONF_FILE: &str = "resolv.conf";
+const HOSTS_FILE: &str = "hosts";
+const JOURNALD_CONF_FILE: &str = "journald.conf";
+const JOURNALD_DROPIN_PATH: &str = "/etc/systemd/journald.conf.d/citadel.conf";
+/// Location in the realm of the directory with the info document written by realmsd
+const REALM_INFO_PATH: &str = "/run/citadel/realm-info";
+/// Location in the realm of the directory with the clipboard socket served by realmsd
+const REALM_CLIPBOARD_DIR: &str = "/run/user/host/clipboard";
+const REALM_CLIPBOARD_SOCKET: &str = "/run/user/host/clipboard/clipboard.sock";
+
+/// Host directories shared read-only with realms when desktop-integration is enabled
+const DESKTOP_SHARE_PATHS: &[&str] = &["/usr/share/fonts", "/usr/share/icons", "/etc/fonts"];
+
+/// Theme variables passed from the desktop session to realms when desktop-integration is enabled
+const DESKTOP_ENV_VARS: &[&str] = &["GTK_THEME", "XCURSOR_THEME", "XCURSOR_SIZE", "QT_STYLE_OVERRIDE", "QT_QPA_PLATFORMTHEME"];
+
+/// Additional device nodes needed by the proprietary NVIDIA driver
+const NVIDIA_DEVICES: &[&str] = &["nvidia0", "nvidiactl", "nvidia-uvm"];
+const USER_RUNTIME_DIR: &str = "/run/user/1000";
+const PULSE_SOCKET_PATH: &str = "/run/user/1000/pulse";
+const PIPEWIRE_SOCKET_PATH: &str = "/run/user/1000/pipewire-0";
+const PIPEWIRE_REALM_SOCKET: &str = "/run/user/host/pipewire-0";
+const SHARED_DIR_PATH: &str = "/realms/Shared";
+const GLOBAL_RESOLV_CONF: &str = "/storage/citadel-state/resolv.conf";
 const SYSTEMD_UNIT_PATH: &str = "/run/systemd/system";
 
+/// Directory of admin provided drop-in fragments for realm services. Fragments in the
+/// `all` subdirectory apply to every realm and those in a subdirectory named after a
+/// realm apply only to that realm.
+const REALM_DROPINS_PATH: &str = "/etc/citadel/realm-dropins";
+
+/// File in the realm run directory describing the optional bindings of the last start
+const LAUNCH_REPORT_FILE: &str = "launch-report.json";
+
+/// Paths inside the realm which may not be replaced by an extra bind mount
+const DENIED_BIND_DESTINATIONS: &[&str] = &["/", "/etc", "/usr", "/proc", "/sys"];
+
+/// Options permitted in the third field of an extra bind mount
+const BIND_OPTIONS: &[&str] = &["norbind", "idmap"];
+
+/// An entry from the `extra-bindmounts` or `extra-bindmounts-ro` config options.
+///
+/// Entries have the form `source[:dest[:options]]` as described for the
+/// `Bind=` option in systemd.nspawn(5). A ':' character which is part of a
+/// path is escaped with a backslash.
+///
+#[derive(Debug)]
+struct BindItem {
+    source: PathBuf,
+    dest: PathBuf,
+}
+
+impl BindItem {
+    fn parse(item: &str) -> Result<Self> {
+        if item.contains('\n') {
+            bail!("bind mount must not contain newline characters");
+        }
+        let fields = Self::split_fields(item)?;
+        if fields.len() > 3 {
+            bail!("too many ':' separated fields");
+        }
+
+        let source = Self::parse_path(&fields[0], "source")?;
+        let dest = match fields.get(1) {
+            Some(dest) => Self::parse_path(dest, "destination")?,
+            None => source.clone(),
+        };
+
+        if DENIED_BIND_DESTINATIONS.iter().any(|&denied| dest == Path::new(denied)) {
+            bail!("destination {} is not permitted. Denied destinations are: {}", dest.display(), DENIED_BIND_DESTINATIONS.join(", "));
+        }
+
+        if let Some(options) = fields.get(2) {
+            for opt in options.split(',') {
+                if !BIND_OPTIONS.contains(&opt) {
+                    bail!("unknown bind mount option '{}'. Valid options are: {}", opt, BIND_OPTIONS.join(", "));
+                }
+            }
+        }
+        Ok(BindItem { source, dest })
+    }
+
+    // Split on ':' characters which are not escaped by a backslash and remove escapes.
+    fn split_fields(item: &str) -> Result<Vec<String>> {
+        let mut fields = Vec::new();
+        let mut current = String::new();
+        let mut chars = item.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some(escaped) => current.push(escaped),
+                    None => bail!("trailing '\\' character"),
+                },
+                ':' => fields.push(current.split_off(0)),
+                c => current.push(c),
+            }
+        }
+        fields.push(current);
+        Ok(fields)
+    }
+
+    fn parse_path(path: &str, name: &str) -> Result<PathBuf> {
+        let path = Path::new(path);
+        if !path.is_absolute() {
+            bail!("{} path '{}' is not an absolute path", name, path.display());
+        }
+        if path.components().any(|c| c == Component::ParentDir) {
+            bail!("{} path '{}' must not contain '..' components", name, path.display());
+        }
+        Ok(path.to_path_buf())
+    }
+}
+
+///
+/// The launch config files of a realm generated by `RealmLauncher::plan()`
+/// without changing anything on the system. `RealmLauncher::apply()` writes
+/// the files and allocates the planned network address.
+///
+pub struct LaunchPlan {
+    nspawn_contents: String,
+    service_contents: String,
+    hosts_contents: Option<String>,
+    address: Option<PlannedAddress>,
+    report: LaunchReport,
+}
+
+// Network address the realm is given in its zone when the plan is applied
+struct PlannedAddress {
+    zone: String,
+    ip: String,
+    ip6: Option<String>,
+    mac: String,
+}
+
+impl LaunchPlan {
+    /// Contents of the .nspawn file for the realm
+    pub fn nspawn_contents(&self) -> &str {
+        &self.nspawn_contents
+    }
+
+    /// Contents of the realm service unit
+    pub fn service_contents(&self) -> &str {
+        &self.service_contents
+    }
+
+    /// Address with prefix length allocated to the realm in its network zone, or
+    /// `None` if the realm has no network or uses a separate network namespace.
+    pub fn allocated_ip(&self) -> Option<&str> {
+        self.address.as_ref().map(|address| address.ip.as_str())
+    }
+
+    /// Optional bindings requested by the realm config and whether each was added
+    pub fn report(&self) -> &LaunchReport {
+        &self.report
+    }
+}
+
+/// Result of adding an optional device or socket binding requested by the realm config
+#[derive(Serialize,Deserialize,Clone,Debug,PartialEq)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum BindingResult {
+    /// Host paths which were bound into the realm
+    Applied { paths: Vec<String> },
+    /// Nothing was bound because the device or socket was not found
+    Missing { reason: String },
+}
+
+///
+/// The optional bindings (gpu, camera, sound, wayland, ...) requested by the
+/// config of a realm and whether each one was added when launch config files
+/// were generated. A realm starts without a device or socket which is missing
+/// on the host, so the report is saved to the realm run directory when the
+/// realm is started to explain what is unavailable inside the realm.
+///
+#[derive(Serialize,Deserialize,Clone,Debug,Default,PartialEq)]
+pub struct LaunchReport {
+    bindings: BTreeMap<String, BindingResult>,
+}
+
+impl LaunchReport {
+    /// Read the report saved when `realm` was last started, or `None` if the
+    /// realm has not been started since boot.
+    pub fn load(realm: &Realm) -> Result<Option<Self>> {
+        let path = realm.run_path_file(LAUNCH_REPORT_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(&path)?;
+        let report = serde_json::from_str(&content)
+            .map_err(|e| format_err!("failed to parse {}: {}", path.display(), e))?;
+        Ok(Some(report))
+    }
+
+    fn save(&self, realm: &Realm) -> Result<()> {
+        let path = realm.run_path_file(LAUNCH_REPORT_FILE);
+        fs::write(&path, serde_json::to_string_pretty(self)?)
+            .map_err(|e| format_err!("failed to write {}: {}", path.display(), e))?;
+        Ok(())
+    }
+
+    fn applied(&mut self, binding: &str, paths: Vec<String>) {
+        self.bindings.insert(binding.to_string(), BindingResult::Applied { paths });
+    }
+
+    fn missing(&mut self, binding: &str, reason: String) {
+        self.bindings.insert(binding.to_string(), BindingResult::Missing { reason });
+    }
+
+    pub fn binding(&self, binding: &str) -> Option<&BindingResult> {
+        self.bindings.get(binding)
+    }
+
+    /// Return (binding, reason) for each requested binding which was not added.
+    pub fn missing_bindings(&self) -> Vec<(&str, &str)> {
+        self.bindings.iter()
+            .flat_map(|(binding, result)| match result {
+                BindingResult::Missing { reason } => Some((binding.as_str(), reason.as_str())),
+                BindingResult::Applied { .. } => None,
+            })
+            .collect()
+    }
+
+    /// Describe each binding as "applied: <paths>" or "missing: <reason>"
+    pub fn summary(&self) -> BTreeMap<String, String> {
+        self.bindings.iter()
+            .map(|(binding, result)| {
+                let description = match result {
+                    BindingResult::Applied { paths } => format!("applied: {}", paths.join(" ")),
+                    BindingResult::Missing { reason } => format!("missing: {}", reason),
+                };
+                (binding.clone(), description)
+            })
+            .collect()
+    }
+}
+
 pub struct RealmLauncher<'a> {
     realm: &'a Realm,
     service: String,
     devices: Vec<String>,
+    sound_sockets: SoundSockets,
+    wayland_socket: Option<PathBuf>,
+    home: PathBuf,
+    shared_dir_exists: bool,
+    desktop_paths: Vec<&'static str>,
+    desktop_env: Vec<String>,
+    // Results of adding the devices requested by the realm config
+    report: LaunchReport,
+}
+
+// Sound server sockets found on the host when the realm is launched
+struct SoundSockets {
+    pulse: bool,
+    pipewire: bool,
+}
+
+impl SoundSockets {
+    fn detect() -> Self {
+        SoundSockets {
+            pulse: Path::new(PULSE_SOCKET_PATH).exists(),
+            pipewire: Path::new(PIPEWIRE_SOCKET_PATH).exists(),
+        }
+    }
 }
 
 impl <'a> RealmLauncher <'a> {
     pub fn new(realm: &'a Realm) -> Self {
-        let service = format!("realm-{}.service", realm.name());
+        let service = Self::service_name_for(realm.name());
         RealmLauncher {
             realm, service,
             devices: Vec::new(),
+            sound_sockets: SoundSockets::detect(),
+            wayland_socket: Self::find_wayland_socket(realm, Path::new(USER_RUNTIME_DIR), env::var("WAYLAND_DISPLAY").ok()),
+            home: realm.base_path_file("home"),
+            shared_dir_exists: Path::new(SHARED_DIR_PATH).exists(),
+            desktop_paths: DESKTOP_SHARE_PATHS.iter().cloned().filter(|p| Path::new(p).exists()).collect(),
+            desktop_env: if realm.config().desktop_integration() { Self::host_desktop_env() } else { Vec::new() },
+            report: LaunchReport::default(),
         }
     }
 
+    // Theme variables set in the desktop session as KEY=value items, read from
+    // the environment of the systemd user manager
+    fn host_desktop_env() -> Vec<String> {
+        match Systemd::user_manager_environment() {
+            Ok(environment) => Self::parse_desktop_env(&environment),
+            Err(e) => {
+                warn!("Could not read desktop session environment: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    fn parse_desktop_env(environment: &str) -> Vec<String> {
+        environment.lines()
+            .filter(|line| DESKTOP_ENV_VARS.iter().any(|var| line.split('=').next() == Some(var)))
+            .filter(|item| is_valid_environment_item(item))
+            .map(|item| item.to_string())
+            .collect()
+    }
+
+    // Find the host wayland socket from the wayland-socket config option, the
+    // WAYLAND_DISPLAY environment variable, or the most recently created wayland
+    // socket in `runtime_dir`, in that order.
+    fn find_wayland_socket(realm: &Realm, runtime_dir: &Path, wayland_display: Option<String>) -> Option<PathBuf> {
+        if let Some(socket) = realm.config().wayland_socket() {
+            return Some(runtime_dir.join(socket));
+        }
+        if let Some(path) = wayland_display.map(|display| runtime_dir.join(display)).filter(|path| path.exists()) {
+            return Some(path);
+        }
+        fs::read_dir(runtime_dir).ok()?
+            .flat_map(|e| e.ok())
+            .filter(|e| {
+                let name = e.file_name().to_string_lossy().to_string();
+                name.starts_with("wayland-") && !name.ends_with(".lock")
+            })
+            .flat_map(|e| e.metadata().and_then(|m| m.modified()).ok().map(|mtime| (mtime, e.path())))
+            .max()
+            .map(|(_, path)| path)
+    }
+
     fn add_devices(&mut self) {
         let config = self.realm.config();
 
         if config.kvm() {
-            self.add_device("/dev/kvm");
+            self.add_kvm_device(Path::new("/dev"));
         }
         if config.gpu() {
-            self.add_device("/dev/dri/renderD128");
+            self.add_gpu_devices(Path::new("/sys"), Path::new("/dev"));
+        }
+        if config.camera() {
+            self.add_camera_devices(Path::new("/dev"));
+        }
+        let matchers = config.usb_devices();
+        if !matchers.is_empty() {
+            for dev in UsbDevice::scan().iter().filter(|dev| matchers.iter().any(|m| m.matches(dev))) {
+                info!("Adding USB device {} ({}) to realm {}", dev.dev_path().display(), dev.id(), self.realm.name());
+                self.add_device(&dev.dev_path().to_string_lossy());
+            }
+        }
+        let patterns = config.block_devices();
+        if !patterns.is_empty() {
+            self.add_block_devices(&patterns);
+        }
+    }
+
+    fn add_kvm_device(&mut self, dev_dir: &Path) {
+        let kvm = dev_dir.join("kvm");
+        if kvm.exists() {
+            self.add_device(&kvm.display().to_string());
+            self.report.applied("kvm", vec![kvm.display().to_string()]);
+        } else {
+            self.report.missing("kvm", format!("{} does not exist", kvm.display()));
+        }
+    }
+
+    fn add_block_devices(&mut self, patterns: &[&str]) {
+        if !BlockDevices::allowed_by_host() {
+            warn!("Ignoring block-devices option of realm {} because allow-block-devices is not enabled on the host", self.realm.name());
+            return;
+        }
+        let block_devices = match BlockDevices::load() {
+            Ok(block_devices) => block_devices,
+            Err(e) => {
+                warn!("Not adding block devices to realm {}: could not determine host storage devices: {}", self.realm.name(), e);
+                return;
+            }
+        };
+        let nodes = block_devices.resolve(patterns);
+        if nodes.is_empty() {
+            info!("No block devices granted to realm {}", self.realm.name());
+        }
+        for node in nodes {
+            info!("Granting block device {} to realm {}", node.display(), self.realm.name());
+            self.add_device(&node.to_string_lossy());
+        }
+    }
+
+    // Add render nodes selected by the gpu-device or gpu-vendor options, or all
+    // render nodes if neither is set, along with the card node for each render
+    // node if gpu-card0 is set.
+    fn add_gpu_devices(&mut self, sys_dir: &Path, dev_dir: &Path) {
+        let config = self.realm.config();
+        let dri = dev_dir.join("dri");
+        let nodes = Self::numbered_device_nodes(&dri, "renderD");
+        let selected = match (config.gpu_device(), config.gpu_vendor()) {
+            (Some(device), _) => nodes.into_iter()
+                .filter(|node| Self::device_name(node) == device)
+                .collect(),
+            (None, Some(vendor)) => nodes.into_iter()
+                .filter(|node| Self::gpu_vendor(sys_dir, Self::device_name(node)) == Some(vendor))
+                .take(1)
+                .collect(),
+            (None, None) => nodes,
+        };
+        if selected.is_empty() {
+            warn!("No matching render node found in {} for realm {}", dri.display(), self.realm.name());
+            self.report.missing("gpu", format!("no matching render node in {}", dri.display()));
+            return;
+        }
+        let start = self.devices.len();
+        for node in &selected {
+            let name = Self::device_name(node);
+            self.add_device(node);
             if config.gpu_card0() {
-                self.add_device("/dev/dri/card0");
+                match Self::card_for_render_node(sys_dir, name) {
+                    Some(card) => self.add_device(&dri.join(card).display().to_string()),
+                    None => warn!("Could not find card device for render node {}", name),
+                }
             }
+            if Self::gpu_vendor(sys_dir, name) == Some("nvidia") {
+                for nv in NVIDIA_DEVICES {
+                    self.add_device(&dev_dir.join(nv).display().to_string());
+                }
+            }
+        }
+        let added = self.devices[start..].to_vec();
+        self.report.applied("gpu", added);
+    }
+
+    fn device_name(path: &str) -> &str {
+        Path::new(path).file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(path)
+    }
+
+    // Vendor of a drm device as determined by the name of the kernel driver
+    fn gpu_vendor(sys_dir: &Path, name: &str) -> Option<&'static str> {
+        let driver = fs::read_link(sys_dir.join("class/drm").join(name).join("device/driver")).ok()?;
+        match driver.file_name()?.to_str()? {
+            "amdgpu" | "radeon" => Some("amd"),
+            "i915" | "xe" => Some("intel"),
+            "nvidia" | "nouveau" => Some("nvidia"),
+            _ => None,
         }
     }
 
+    // The card and render nodes of a gpu are both listed in the drm directory of the device
+    fn card_for_render_node(sys_dir: &Path, name: &str) -> Option<String> {
+        let drm = sys_dir.join("class/drm").join(name).join("device/drm");
+        fs::read_dir(drm).ok()?
+            .flat_map(|e| e.ok())
+            .flat_map(|e| e.file_name().into_string().ok())
+            .find(|n| n.starts_with("card") && n.len() > 4 && n[4..].chars().all(|c| c.is_ascii_digit()))
+    }
+
+    // Cameras which are plugged in after the realm is started are not added.
+    fn add_camera_devices(&mut self, dev_dir: &Path) {
+        let mut videos = Self::numbered_device_nodes(dev_dir, "video");
+        if videos.is_empty() {
+            info!("No video devices found for realm {} with camera enabled", self.realm.name());
+            self.report.missing("camera", format!("no video devices in {}", dev_dir.display()));
+            return;
+        }
+        videos.append(&mut Self::numbered_device_nodes(dev_dir, "media"));
+        self.report.applied("camera", videos.clone());
+        self.devices.append(&mut videos);
+    }
+
+    // Return paths of device nodes in `dev_dir` named `prefix` followed by a number
+    fn numbered_device_nodes(dev_dir: &Path, prefix: &str) -> Vec<String> {
+        let entries = match fs::read_dir(dev_dir) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        let mut nodes = entries.flat_map(|e| e.ok())
+            .flat_map(|e| e.file_name().into_string().ok())
+            .filter(|name| name.starts_with(prefix) && name.len() > prefix.len())
+            .filter_map(|name| name[prefix.len()..].parse::<u32>().ok().map(|n| (n, name)))
+            .collect::<Vec<_>>();
+        nodes.sort();
+        nodes.into_iter()
+            .map(|(_,name)| dev_dir.join(name).display().to_string())
+            .collect()
+    }
+
+    fn has_camera_devices(&self) -> bool {
+        self.devices.iter().any(|dev| Self::is_camera_device(dev))
+    }
+
+    fn is_camera_device(dev: &str) -> bool {
+        Path::new(dev).file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| name.starts_with("video"))
+            .unwrap_or(false)
+    }
+
+    /// Return number of video devices which were added to the realm when it
+    /// was started, as recorded in the generated nspawn file.
+    pub fn camera_device_count(&self) -> usize {
+        fs::read_to_string(self.realm_nspawn_path())
+            .map(|content| content.lines()
+                .filter(|line| line.starts_with("Bind=") && Self::is_camera_device(&line["Bind=".len()..]))
+                .count())
+            .unwrap_or(0)
+    }
+
     fn add_device(&mut self, device: &str) {
-        if Path::new(device).exists() {
+        if Path::new(device).exists() && !self.devices.iter().any(|d| d == device) {
             self.devices.push(device.to_string());
         }
     }
@@ -85,26 +571,211 @@ impl <'a> RealmLauncher <'a> {
         if service_path.exists() {
             fs::remove_file(&service_path)?;
         }
+        let dropin_path = self.realm_dropin_path();
+        if dropin_path.exists() {
+            fs::remove_dir_all(&dropin_path)?;
+        }
+        for name in &[LOCALTIME_FILE, LOCALE_CONF_FILE, RESOLV_CONF_FILE, HOSTS_FILE, JOURNALD_CONF_FILE] {
+            let path = self.realm.run_path_file(name);
+            if path.exists() {
+                fs::remove_file(&path)?;
+            }
+        }
+        let info_path = self.realm.info_path();
+        if info_path.exists() {
+            fs::remove_dir_all(&info_path)?;
+        }
         Ok(())
     }
 
-    pub fn write_launch_config_files(&mut self, rootfs: &Path, netconfig: &mut NetworkConfig) -> Result<()> {
+    /// Generate the launch config files for starting the realm with `rootfs`. The
+    /// network address of the realm is chosen from the current allocations in
+    /// `netconfig` but is not allocated until the plan is applied.
+    pub fn plan(&mut self, rootfs: &Path, netconfig: &NetworkConfig) -> Result<LaunchPlan> {
         if self.devices.is_empty() {
             self.add_devices();
         }
+        let hosts_contents = if self.realm.config().extra_hosts().is_empty() {
+            None
+        } else {
+            Some(self.generate_hosts_file(netconfig)?)
+        };
+        let address = self.plan_address(netconfig)?;
+        let nspawn_contents = self.generate_nspawn_file(netconfig, address.as_ref())?;
+        let service_contents = self.generate_service_file(rootfs);
+        let report = self.launch_report();
+        Ok(LaunchPlan { nspawn_contents, service_contents, hosts_contents, address, report })
+    }
+
+    // Add the sound and wayland sockets requested by the realm config to the
+    // results of adding devices.
+    fn launch_report(&self) -> LaunchReport {
+        let config = self.realm.config();
+        let mut report = self.report.clone();
+        if config.sound() {
+            if self.sound_sockets.pulse {
+                report.applied("sound", vec![PULSE_SOCKET_PATH.to_string()]);
+            } else {
+                report.missing("sound", format!("{} does not exist", PULSE_SOCKET_PATH));
+            }
+        }
+        if self.use_pipewire() {
+            report.applied("pipewire", vec![PIPEWIRE_SOCKET_PATH.to_string()]);
+        } else if config.pipewire() {
+            report.missing("pipewire", format!("{} does not exist", PIPEWIRE_SOCKET_PATH));
+        }
+        if config.wayland() {
+            match self.wayland_socket {
+                Some(ref socket) => report.applied("wayland", vec![socket.display().to_string()]),
+                None => report.missing("wayland", format!("no wayland socket found in {}", USER_RUNTIME_DIR)),
+            }
+        }
+        report
+    }
+
+    /// Allocate the network address chosen in `plan` and write the launch config files.
+    pub fn apply(&self, plan: &LaunchPlan, netconfig: &mut NetworkConfig) -> Result<()> {
+        self.write_localization_files()?;
+        self.write_resolv_conf()?;
+        // The info document is written by realmsd once the realm has started
+        fs::create_dir_all(self.realm.info_path())?;
+        // The clipboard socket is created by realmsd once the realm has started
+        if self.realm.config().clipboard().is_some() {
+            if let Some(dir) = self.realm.clipboard_socket().parent() {
+                fs::create_dir_all(dir)?;
+            }
+        }
+        if let Some(ref hosts) = plan.hosts_contents {
+            fs::write(self.realm.run_path_file(HOSTS_FILE), hosts)?;
+        }
+        if let Some(ref address) = plan.address {
+            self.allocate_planned_address(address, netconfig)?;
+        }
         let nspawn_path = self.realm_nspawn_path();
-        let nspawn_content = self.generate_nspawn_file(netconfig)?;
-        self.write_launch_config_file(&nspawn_path, &nspawn_content)
+        self.write_launch_config_file(&nspawn_path, &plan.nspawn_contents)
             .map_err(|e| format_err!("failed to write nspawn config file {}: {}", nspawn_path.display(), e))?;
 
         let service_path = self.realm_service_path();
-        let service_content = self.generate_service_file(rootfs);
-        self.write_launch_config_file(&service_path, &service_content)
+        self.write_launch_config_file(&service_path, &plan.service_contents)
             .map_err(|e| format_err!("failed to write service config file {}: {}", service_path.display(), e))?;
 
+        let dropin_path = self.realm_dropin_path();
+        self.write_dropin_files(Path::new(REALM_DROPINS_PATH), &dropin_path)
+            .map_err(|e| format_err!("failed to write service drop-in files to {}: {}", dropin_path.display(), e))?;
+
+        // Kept when the realm stops so that it describes the last start
+        plan.report.save(self.realm)?;
+
         Ok(())
     }
 
+    // Replace the contents of the service drop-in directory `target` with the
+    // *.conf fragments from the `all` and realm subdirectories of `source`. A
+    // per-realm fragment replaces an `all` fragment with the same file name.
+    fn write_dropin_files(&self, source: &Path, target: &Path) -> Result<()> {
+        if target.exists() {
+            fs::remove_dir_all(target)?;
+        }
+        let mut fragments = BTreeMap::new();
+        for subdir in &["all", self.realm.name()] {
+            let dir = source.join(subdir);
+            if !dir.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if path.is_file() && path.extension().map(|ext| ext == "conf").unwrap_or(false) {
+                    if let Some(name) = path.file_name() {
+                        fragments.insert(name.to_os_string(), path.clone());
+                    }
+                }
+            }
+        }
+        if fragments.is_empty() {
+            return Ok(());
+        }
+        fs::create_dir_all(target)?;
+        for (name, path) in fragments {
+            verbose!("Adding drop-in {} to {}", path.display(), self.service);
+            fs::copy(&path, target.join(name))?;
+        }
+        Ok(())
+    }
+
+    /// Write files which are bind mounted into the realm when the timezone
+    /// or locale config options are set to explicit values or when a journal
+    /// size limit is configured.
+    fn write_localization_files(&self) -> Result<()> {
+        let config = self.realm.config();
+        if let Some(tz) = config.timezone().filter(|&tz| tz != "host") {
+            let zoneinfo = Path::new(ZONEINFO_PATH).join(tz);
+            if !zoneinfo.is_file() {
+                bail!("timezone '{}' does not exist in {}", tz, ZONEINFO_PATH);
+            }
+            fs::copy(&zoneinfo, self.realm.run_path_file(LOCALTIME_FILE))
+                .map_err(|e| format_err!("failed to copy timezone file {}: {}", zoneinfo.display(), e))?;
+        }
+        if let Some(locale) = config.locale().filter(|&locale| locale != "host") {
+            fs::write(self.realm.run_path_file(LOCALE_CONF_FILE), format!("LANG={}\n", locale))?;
+        }
+        if let Some(size) = config.journal_max_size() {
+            fs::write(self.realm.run_path_file(JOURNALD_CONF_FILE), format!("[Journal]\nSystemMaxUse={}\n", size))?;
+        }
+        Ok(())
+    }
+
+    /// Write a resolv.conf for the realm if DNS servers are configured with the
+    /// `dns` config option.
+    fn write_resolv_conf(&self) -> Result<()> {
+        let config = self.realm.config();
+        match config.dns() {
+            Some(ref servers) if !servers.is_empty() => {
+                let content = Self::generate_resolv_conf(servers, &config.dns_search());
+                fs::write(self.realm.run_path_file(RESOLV_CONF_FILE), content)?;
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    fn generate_resolv_conf(servers: &[&str], search: &[&str]) -> String {
+        let mut s = String::new();
+        for server in servers {
+            s.push_str(&format!("nameserver {}\n", server));
+        }
+        if !search.is_empty() {
+            s.push_str(&format!("search {}\n", search.join(" ")));
+        }
+        s
+    }
+
+    /// Generate an /etc/hosts file for the realm from the `extra-hosts` config
+    /// option. Entries referring to an address which is not available are
+    /// skipped with a warning.
+    fn generate_hosts_file(&self, netconfig: &NetworkConfig) -> Result<String> {
+        let config = self.realm.config();
+        let mut s = String::new();
+        writeln!(s, "127.0.0.1\tlocalhost")?;
+        writeln!(s, "::1\tlocalhost ip6-localhost ip6-loopback")?;
+        writeln!(s, "127.0.1.1\t{}", self.realm.name())?;
+
+        for line in config.extra_hosts() {
+            let entry = HostsEntry::parse(line)
+                .map_err(|e| format_err!("invalid extra-hosts entry '{}' for realm {}: {}", line, self.realm.name(), e))?;
+            let address = match entry.address() {
+                HostsAddress::Ip(ip) => Some(ip.to_string()),
+                HostsAddress::Host if config.network() && (!config.has_netns() || config.managed_netns()) => Some(netconfig.gateway(config.network_zone())?),
+                HostsAddress::Host => None,
+                HostsAddress::Realm(name) => netconfig.allocated_address(name).map(|ip| ip.to_string()),
+            };
+            match address {
+                Some(address) => writeln!(s, "{}\t{}", address, entry.names().join(" "))?,
+                None => warn!("Skipping extra-hosts entry '{}' for realm {} because address is not available", line, self.realm.name()),
+            }
+        }
+        Ok(s)
+    }
+
     pub fn realm_service_name(&self) -> &str {
         &self.service
     }
@@ -124,25 +795,155 @@ impl <'a> RealmLauncher <'a> {
         Ok(())
     }
 
-    fn generate_nspawn_file(&mut self, netconfig: &mut NetworkConfig) -> Result<String> {
+    fn generate_nspawn_file(&self, netconfig: &NetworkConfig, address: Option<&PlannedAddress>) -> Result<String> {
         Ok(NSPAWN_FILE_TEMPLATE
+            .replace("$RESOLV_CONF_BIND", &self.generate_resolv_conf_bind()?)
             .replace("$EXTRA_BIND_MOUNTS", &self.generate_extra_bind_mounts()?)
             .replace("$EXTRA_FILE_OPTIONS", &self.generate_extra_file_options()?)
-            .replace("$NETWORK_CONFIG", &self.generate_network_config(netconfig)?))
+            .replace("$EXEC_TIMEZONE", &self.generate_timezone()?)
+            .replace("$EXEC_ENVIRONMENT", &self.generate_environment()?)
+            .replace("$SECURITY_OPTIONS", &self.generate_security_options(Path::new(SECCOMP_PROFILE_PATH))?)
+            .replace("$NETWORK_CONFIG", &self.generate_network_config(netconfig, address)?))
+    }
+
+    fn generate_timezone(&self) -> Result<String> {
+        let mut s = String::new();
+        match self.realm.config().timezone() {
+            Some("host") => writeln!(s, "Timezone=bind")?,
+            // explicit timezone file is bind mounted in generate_extra_bind_mounts()
+            Some(_) => writeln!(s, "Timezone=off")?,
+            None => {},
+        }
+        Ok(s)
+    }
+
+    fn generate_environment(&self) -> Result<String> {
+        let mut s = String::new();
+        for item in self.realm.config().environment() {
+            writeln!(s, "Environment={}", item)?;
+        }
+        if self.use_pipewire() {
+            writeln!(s, "Environment=PIPEWIRE_REMOTE={}", PIPEWIRE_REALM_SOCKET)?;
+        }
+        if self.realm.config().wayland() && self.wayland_socket.is_some() {
+            writeln!(s, "Environment=WAYLAND_DISPLAY=wayland-0")?;
+        }
+        if self.realm.config().session_bus_filtered() {
+            writeln!(s, "Environment=DBUS_SESSION_BUS_ADDRESS={}", SessionBusProxy::realm_bus_address())?;
+        }
+        if self.realm.config().clipboard().is_some() {
+            writeln!(s, "Environment=CITADEL_CLIPBOARD_SOCKET={}", REALM_CLIPBOARD_SOCKET)?;
+        }
+        if self.realm.config().desktop_integration() {
+            for item in &self.desktop_env {
+                writeln!(s, "Environment={}", item)?;
+            }
+        }
+        Ok(s)
+    }
+
+    // Bind mount each listed subdirectory of /realms/realm-${name}/home into the
+    // home directory of the realm. Mounts are sorted by systemd-nspawn so these are
+    // mounted on top of the tmpfs or overlay home directory before the realm boots.
+    fn generate_persistent_dir_binds<S: AsRef<str>>(&self, s: &mut String, dirs: &[S], idmap: &str) -> Result<()> {
+        if !self.home.exists() {
+            return Ok(());
+        }
+        let home = self.home.canonicalize()?;
+        for dir in dirs {
+            let dir = dir.as_ref();
+            let src = home.join(dir);
+            if dir.contains(':') || !src.exists() {
+                warn!("Not adding persistent directory '{}' to realm {}", dir, self.realm.name());
+                continue;
+            }
+            let src = src.canonicalize()?;
+            if src.starts_with(&home) {
+                writeln!(s, "Bind={}:{}{}", src.display(), Path::new("/home/user").join(dir).display(), idmap)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn use_pipewire(&self) -> bool {
+        let config = self.realm.config();
+        (config.pipewire() || config.sound()) && self.sound_sockets.pipewire
+    }
+
+    // `profile_dir` is the directory system call filter profiles are loaded from
+    fn generate_security_options(&self, profile_dir: &Path) -> Result<String> {
+        let config = self.realm.config();
+        let mut s = String::new();
+
+        let caps = config.drop_capabilities();
+        if !caps.is_empty() {
+            writeln!(s, "DropCapability={}", caps.join(" "))?;
+        }
+
+        if config.private_users() {
+            match Systemd::home_uid_shift(self.realm) {
+                Some(shift) => writeln!(s, "PrivateUsers={}", shift)?,
+                None => writeln!(s, "PrivateUsers=pick")?,
+            }
+        }
+
+        if config.no_new_privileges() {
+            writeln!(s, "NoNewPrivileges=yes")?;
+        }
+
+        if let Some(name) = config.system_call_filter() {
+            let profile = SyscallProfile::load_from(profile_dir, name)?;
+            if !profile.syscalls().is_empty() {
+                writeln!(s, "SystemCallFilter={}", profile.syscalls().join(" "))?;
+            }
+        }
+        Ok(s)
+    }
+
+    fn generate_resolv_conf_bind(&self) -> Result<String> {
+        let mut s = String::new();
+        match self.realm.config().dns() {
+            None => writeln!(s, "BindReadOnly={}:/etc/resolv.conf", GLOBAL_RESOLV_CONF)?,
+            // Realm runs its own resolver
+            Some(ref servers) if servers.is_empty() => {},
+            Some(_) => writeln!(s, "BindReadOnly={}:/etc/resolv.conf", self.realm.run_path_file(RESOLV_CONF_FILE).display())?,
+        }
+        Ok(s)
     }
 
     fn generate_extra_bind_mounts(&self) -> Result<String> {
         let config = self.realm.config();
         let mut s = String::new();
 
-        if config.ephemeral_home() {
-            writeln!(s, "TemporaryFileSystem=/home/user:mode=755,uid=1000,gid=1000")?;
-        } else {
-            writeln!(s, "Bind={}:/home/user", self.realm.base_path_file("home").display())?;
+        if config.share_opt() {
+            writeln!(s, "BindReadOnly=/opt/share")?;
+        }
+
+        let home = &self.home;
+        let idmap = if config.private_users() && Systemd::supports_idmap() { ":idmap" } else { "" };
+        match config.home_mode() {
+            HomeMode::Persistent => {
+                writeln!(s, "Bind={}:/home/user{}", home.display(), idmap)?;
+                for dir in config.ephemeral_dirs() {
+                    writeln!(s, "TemporaryFileSystem=/home/user/{}:mode=755,uid=1000,gid=1000", dir)?;
+                }
+            },
+            HomeMode::Ephemeral => {
+                writeln!(s, "TemporaryFileSystem=/home/user:mode=755,uid=1000,gid=1000")?;
+                self.generate_persistent_dir_binds(&mut s, &config.ephemeral_persistent_dirs(), idmap)?;
+            },
+            HomeMode::ReadOnlyOverlay => {
+                // Real home directory is the read-only lower layer and the empty
+                // upper path places writes on a tmpfs which is discarded when the
+                // realm stops.
+                writeln!(s, "Overlay={}::/home/user", home.display())?;
+                // Home directory is never idmapped as the lower layer of the overlay
+                self.generate_persistent_dir_binds(&mut s, &config.persistent_dirs(), "")?;
+            },
         }
 
-        if config.shared_dir() && Path::new("/realms/Shared").exists() {
-            writeln!(s, "Bind=/realms/Shared:/home/user/Shared")?;
+        if config.shared_dir() && self.shared_dir_exists {
+            writeln!(s, "Bind={}:/home/user/Shared", SHARED_DIR_PATH)?;
         }
 
         for dev in &self.devices {
@@ -150,33 +951,97 @@ impl <'a> RealmLauncher <'a> {
         }
 
         if config.sound() {
-            writeln!(s, "BindReadOnly=/run/user/1000/pulse:/run/user/host/pulse")?;
+            if self.sound_sockets.pulse {
+                writeln!(s, "BindReadOnly={}:/run/user/host/pulse", PULSE_SOCKET_PATH)?;
+            } else {
+                info!("Not adding PulseAudio socket to realm {} because {} does not exist", self.realm.name(), PULSE_SOCKET_PATH);
+            }
+        }
+
+        if config.session_bus_filtered() {
+            let dir = SessionBusProxy::new(self.realm).socket_dir();
+            writeln!(s, "BindReadOnly={}:{}", dir.display(), REALM_PROXY_DIR)?;
+        }
+
+        if config.clipboard().is_some() {
+            if let Some(dir) = self.realm.clipboard_socket().parent() {
+                writeln!(s, "BindReadOnly={}:{}", dir.display(), REALM_CLIPBOARD_DIR)?;
+            }
+        }
+
+        if config.camera() && self.has_camera_devices() {
+            writeln!(s, "BindReadOnly=/run/udev/data")?;
+        }
+
+        if self.use_pipewire() {
+            writeln!(s, "BindReadOnly={}:{}", PIPEWIRE_SOCKET_PATH, PIPEWIRE_REALM_SOCKET)?;
+        } else if config.pipewire() {
+            info!("Not adding PipeWire socket to realm {} because {} does not exist", self.realm.name(), PIPEWIRE_SOCKET_PATH);
         }
 
         if config.x11() {
             writeln!(s, "BindReadOnly=/tmp/.X11-unix")?;
         }
 
+        if config.desktop_integration() {
+            for path in &self.desktop_paths {
+                writeln!(s, "BindReadOnly={}", path)?;
+            }
+        }
+
         if config.wayland() {
-            writeln!(s, "BindReadOnly=/run/user/1000/wayland-0:/run/user/host/wayland-0")?;
+            match self.wayland_socket {
+                Some(ref socket) => writeln!(s, "BindReadOnly={}:/run/user/host/wayland-0", socket.display())?,
+                None => warn!("No wayland socket found in {} for realm {}", USER_RUNTIME_DIR, self.realm.name()),
+            }
+        }
+
+        match config.timezone() {
+            Some("host") | None => {},
+            Some(_) => writeln!(s, "BindReadOnly={}:/etc/localtime", self.realm.run_path_file(LOCALTIME_FILE).display())?,
         }
 
+        if !config.extra_hosts().is_empty() {
+            writeln!(s, "BindReadOnly={}:/etc/hosts", self.realm.run_path_file(HOSTS_FILE).display())?;
+        }
+
+        match config.locale() {
+            Some("host") => writeln!(s, "BindReadOnly=/etc/locale.conf")?,
+            Some(_) => writeln!(s, "BindReadOnly={}:/etc/locale.conf", self.realm.run_path_file(LOCALE_CONF_FILE).display())?,
+            None => {},
+        }
+
+        if config.journal_max_size().is_some() {
+            writeln!(s, "BindReadOnly={}:{}", self.realm.run_path_file(JOURNALD_CONF_FILE).display(), JOURNALD_DROPIN_PATH)?;
+        }
+
+        writeln!(s, "BindReadOnly={}:{}", self.realm.info_path().display(), REALM_INFO_PATH)?;
+
         for bind in config.extra_bindmounts() {
-            if Self::is_valid_bind_item(bind) {
+            if self.check_bind_item(bind)? {
                 writeln!(s, "Bind={}", bind)?;
             }
         }
 
         for bind in config.extra_bindmounts_ro() {
-            if Self::is_valid_bind_item(bind) {
+            if self.check_bind_item(bind)? {
                 writeln!(s, "BindReadOnly={}", bind)?;
             }
         }
         Ok(s)
     }
 
-    fn is_valid_bind_item(item: &str) -> bool {
-        !item.contains('\n')
+    // Returns an error if bind mount item is invalid and `false` if it is valid
+    // but should be skipped because the source path does not exist.
+    fn check_bind_item(&self, item: &str) -> Result<bool> {
+        let bind = BindItem::parse(item)
+            .map_err(|e| format_err!("invalid bind mount '{}' for realm {}: {}", item, self.realm.name(), e))?;
+        if !bind.source.exists() {
+            warn!("Skipping bind mount of {} to {} for realm {} because source path does not exist",
+                  bind.source.display(), bind.dest.display(), self.realm.name());
+            return Ok(false);
+        }
+        Ok(true)
     }
 
     fn generate_extra_file_options(&self) -> Result<String> {
@@ -185,27 +1050,76 @@ impl <'a> RealmLauncher <'a> {
             writeln!(s, "ReadOnly=true")?;
             writeln!(s, "Overlay=+/var::/var")?;
         }
+        if self.realm.config().private_users() {
+            if Systemd::supports_idmap() {
+                writeln!(s, "PrivateUsersOwnership=auto")?;
+            } else {
+                writeln!(s, "PrivateUsersChown=true")?;
+            }
+        }
         Ok(s)
     }
 
-    fn generate_network_config(&mut self, netconfig: &mut NetworkConfig) -> Result<String> {
+    // The address which will be allocated to the realm in its network zone, or
+    // `None` if the realm has no network or uses a separate network namespace.
+    fn plan_address(&self, netconfig: &NetworkConfig) -> Result<Option<PlannedAddress>> {
+        let config = self.realm.config();
+        if !config.network() || config.has_netns() {
+            return Ok(None);
+        }
+        let zone = config.network_zone();
+        if !netconfig.has_zone(zone) {
+            bail!("realm {} uses network zone '{}' which is not defined", self.realm.name(), zone);
+        }
+        let name = self.realm.name();
+        Ok(Some(PlannedAddress {
+            zone: zone.to_string(),
+            ip: netconfig.preview_allocation_for(zone, name, config.reserved_ip())?,
+            ip6: netconfig.preview_ipv6_for(zone, name, config.reserved_ip())?,
+            mac: netconfig.preview_mac_for(zone, name)?,
+        }))
+    }
+
+    // The network lock is held from planning until the plan is applied, so the
+    // allocation only differs from the plan if the plan was generated earlier.
+    fn allocate_planned_address(&self, address: &PlannedAddress, netconfig: &mut NetworkConfig) -> Result<()> {
+        let config = self.realm.config();
+        let name = self.realm.name();
+        let ip = netconfig.allocate_for_realm(&address.zone, name, config.reserved_ip())?;
+        let ip6 = netconfig.allocate_ipv6_for(&address.zone, name, config.reserved_ip())?;
+        netconfig.mac_for(&address.zone, name)?;
+        if ip != address.ip || ip6 != address.ip6 {
+            bail!("address {} allocated to realm {} does not match planned address {}", ip, name, address.ip);
+        }
+        Ok(())
+    }
+
+    fn generate_network_config(&self, netconfig: &NetworkConfig, address: Option<&PlannedAddress>) -> Result<String> {
         let config = self.realm.config();
         let mut s = String::new();
         if config.network() {
             if config.has_netns() {
                 return Ok(s);
             }
-            let zone = config.network_zone();
-            let addr = if let Some(addr) = config.reserved_ip() {
-                netconfig.allocate_reserved(zone, self.realm.name(), addr)?
-            } else {
-                netconfig.allocate_address_for(zone, self.realm.name())?
-            };
-            let gw = netconfig.gateway(zone)?;
-            writeln!(s, "Environment=IFCONFIG_IP={}", addr)?;
-            writeln!(s, "Environment=IFCONFIG_GW={}", gw)?;
+            let address = address.ok_or_else(|| format_err!("no network address planned for realm {}", self.realm.name()))?;
+            let zone = address.zone.as_str();
+            writeln!(s, "Environment=IFCONFIG_IP={}", address.ip)?;
+            writeln!(s, "Environment=IFCONFIG_GW={}", netconfig.gateway(zone)?)?;
+            writeln!(s, "Environment=IFCONFIG_MAC={}", address.mac)?;
+            if let Some(ref addr6) = address.ip6 {
+                writeln!(s, "Environment=IFCONFIG_IP6={}", addr6)?;
+                if let Some(gw6) = netconfig.gateway6(zone) {
+                    writeln!(s, "Environment=IFCONFIG_GW6={}", gw6)?;
+                }
+            }
             writeln!(s, "[Network]")?;
-            writeln!(s, "Zone=clear")?;
+            // systemd-nspawn creates the bridge vz-$zone for Zone=$zone
+            let bridge = netconfig.bridge_for(zone)?;
+            if bridge == format!("vz-{}", zone) {
+                writeln!(s, "Zone={}", zone)?;
+            } else {
+                writeln!(s, "Bridge={}", bridge)?;
+            }
         } else {
             writeln!(s, "[Network]")?;
             writeln!(s, "Private=true")?;
@@ -215,7 +1129,15 @@ impl <'a> RealmLauncher <'a> {
 
     fn generate_service_file(&self, rootfs: &Path) -> String {
         let rootfs = rootfs.display().to_string();
-        let netns_arg = match self.realm.config().netns() {
+        let config = self.realm.config();
+        let netns_arg = match config.netns() {
+            Some(_) if config.managed_netns() => {
+                if config.network() {
+                    format!("--network-namespace-path=/run/netns/{}", NetnsManager::netns_name(self.realm.name()))
+                } else {
+                    "".into()
+                }
+            },
             Some(netns) => format!("--network-namespace-path=/run/netns/{}", netns),
             None => "".into(),
         };
@@ -228,14 +1150,835 @@ impl <'a> RealmLauncher <'a> {
         REALM_SERVICE_TEMPLATE.replace("$REALM_NAME", self.realm.name())
             .replace("$ROOTFS", &rootfs)
             .replace("$NETNS_ARG", &netns_arg)
+            .replace("$LINK_JOURNAL", Self::link_journal_arg(config.journal()))
             .replace("$DEVICE_ALLOW", &s)
+            .replace("$START_LIMIT", &self.generate_start_limit())
+            .replace("$RESTART_OPTIONS", &self.generate_restart_options())
+            .replace("$RESOURCE_OPTIONS", &Systemd::resource_properties(&config).join("\n"))
+    }
+
+    // Value of --link-journal= for the journal config option
+    fn link_journal_arg(journal: &str) -> &'static str {
+        match journal {
+            "host" => "host",
+            "off" => "no",
+            _ => "auto",
+        }
+    }
+
+    // Exit status 133 is returned when the realm is rebooted from inside the
+    // realm. It restarts the realm unless the restart policy is "no".
+    fn generate_restart_options(&self) -> String {
+        match self.realm.config().restart_policy() {
+            Some("no") => "Restart=no".to_string(),
+            Some(policy) => format!("Restart={}\nRestartSec={}\nRestartForceExitStatus=133", policy, RESTART_SEC),
+            None => "RestartForceExitStatus=133".to_string(),
+        }
+    }
+
+    fn generate_start_limit(&self) -> String {
+        match self.realm.config().restart_max_per_hour() {
+            Some(n) => format!("StartLimitIntervalSec=3600\nStartLimitBurst={}", n),
+            None => String::new(),
+        }
     }
 
     fn realm_service_path(&self) -> PathBuf {
         PathBuf::from(SYSTEMD_UNIT_PATH).join(self.realm_service_name())
     }
 
+    fn realm_dropin_path(&self) -> PathBuf {
+        PathBuf::from(SYSTEMD_UNIT_PATH).join(format!("{}.d", self.realm_service_name()))
+    }
+
     fn realm_nspawn_path(&self) -> PathBuf {
-        PathBuf::from(SYSTEMD_NSPAWN_PATH).join(format!("{}.nspawn", self.realm.name()))
+        PathBuf::from(SYSTEMD_NSPAWN_PATH).join(Self::nspawn_file_name_for(self.realm.name()))
+    }
+
+    /// Name of the service unit which runs the realm `name`
+    pub fn service_name_for(name: &str) -> String {
+        format!("realm-{}.service", name)
     }
-}
\ No newline at end of file
+
+    /// Name of the .nspawn file generated for the realm `name`
+    pub fn nspawn_file_name_for(name: &str) -> String {
+        format!("{}.nspawn", name)
+    }
+
+    /// If `file_name` is a realm service unit or the drop-in directory of
+    /// one, return the name of the realm.
+    pub fn realm_for_unit_file(file_name: &str) -> Option<&str> {
+        let unit = file_name.strip_suffix(".d").unwrap_or(file_name);
+        let name = unit.strip_prefix("realm-")?.strip_suffix(".service")?;
+        Some(name).filter(|name| Realm::is_valid_name(name))
+    }
+
+    /// If `file_name` is a realm .nspawn file, return the name of the realm.
+    pub fn realm_for_nspawn_file(file_name: &str) -> Option<&str> {
+        let name = file_name.strip_suffix(".nspawn")?;
+        Some(name).filter(|name| Realm::is_valid_name(name))
+    }
+
+    // Realm name and path of every launch config file in `unit_dir` and `nspawn_dir`
+    fn launch_config_files(unit_dir: &Path, nspawn_dir: &Path) -> Vec<(String, PathBuf)> {
+        let list = |dir: &Path, realm_for: fn(&str) -> Option<&str>| {
+            fs::read_dir(dir).into_iter()
+                .flat_map(|entries| entries.flatten())
+                .flat_map(|entry| {
+                    let file_name = entry.file_name().to_string_lossy().to_string();
+                    realm_for(&file_name).map(|name| (name.to_string(), entry.path()))
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut files = list(unit_dir, Self::realm_for_unit_file);
+        files.extend(list(nspawn_dir, Self::realm_for_nspawn_file));
+        files.sort();
+        files
+    }
+
+    /// Remove the service units, drop-in directories and .nspawn files of realms
+    /// which are not in `known`, for example because the realm was deleted or
+    /// renamed while it was running. Files of realms with a service in `active`
+    /// are never removed and are only logged. Returns the paths which were removed.
+    pub fn remove_orphaned_launch_config_files(known: &[&str], active: &HashSet<String>) -> Result<Vec<PathBuf>> {
+        Self::remove_orphans(Path::new(SYSTEMD_UNIT_PATH), Path::new(SYSTEMD_NSPAWN_PATH), known, active)
+    }
+
+    fn remove_orphans(unit_dir: &Path, nspawn_dir: &Path, known: &[&str], active: &HashSet<String>) -> Result<Vec<PathBuf>> {
+        let mut removed = Vec::new();
+        for (name, path) in Self::launch_config_files(unit_dir, nspawn_dir) {
+            if known.contains(&name.as_str()) {
+                continue;
+            }
+            if active.contains(&name) {
+                warn!("Not removing {} for unknown realm {} because the realm service is still active", path.display(), name);
+                continue;
+            }
+            if path.is_dir() {
+                fs::remove_dir_all(&path)?;
+            } else {
+                fs::remove_file(&path)?;
+            }
+            info!("Removed {} of unknown realm {}", path.display(), name);
+            removed.push(path);
+        }
+        Ok(removed)
+    }
+}
+#[test]
+fn test_nspawn_environment() {
+    let realm = Realm::new("envtest");
+    realm.config();
+    realm.with_mut_config(|c| {
+        c.use_network = Some(false);
+        c.environment = Some(vec!["HTTP_PROXY=http://proxy:3128".to_string(), "1BAD=x".to_string()]);
+    });
+    let launcher = RealmLauncher::new(&realm);
+    let content = launcher.generate_nspawn_file(&NetworkConfig::new(), None).unwrap();
+    assert!(content.contains("[Exec]\nBoot=true\n\nEnvironment=HTTP_PROXY=http://proxy:3128\n"));
+    assert!(!content.contains("1BAD"));
+}
+
+#[test]
+fn test_nspawn_security_options() {
+    let realm = Realm::new("sectest");
+    realm.config();
+    realm.with_mut_config(|c| {
+        c.use_network = Some(false);
+        c.drop_capabilities = Some(vec!["CAP_SYS_PTRACE".to_string(), "CAP_NET_RAW".to_string()]);
+        c.no_new_privileges = Some(true);
+        c.private_users = Some(true);
+    });
+    let content = RealmLauncher::new(&realm).generate_nspawn_file(&NetworkConfig::new(), None).unwrap();
+    assert!(content.contains("[Exec]\nBoot=true\n\n\nDropCapability=CAP_SYS_PTRACE CAP_NET_RAW\nPrivateUsers=pick\nNoNewPrivileges=yes\n"), "{}", content);
+    assert!(!content.contains("SystemCallFilter="));
+
+    let profiles = crate::util::TempDir::new("seccomp-test").unwrap();
+    fs::write(profiles.join("browser"), "# browser profile\n@system-service\n\nptrace\n").unwrap();
+    realm.with_mut_config(|c| {
+        c.drop_capabilities = None;
+        c.no_new_privileges = Some(false);
+        c.private_users = Some(false);
+        c.system_call_filter = Some("browser".to_string());
+    });
+    let launcher = RealmLauncher::new(&realm);
+    assert_eq!(launcher.generate_security_options(&profiles).unwrap(), "SystemCallFilter=@system-service ptrace\n");
+
+    realm.with_mut_config(|c| c.system_call_filter = Some("missing".to_string()));
+    let err = launcher.generate_security_options(&profiles).unwrap_err().to_string();
+    assert!(err.contains("unknown system-call-filter profile 'missing'") && err.ends_with("browser"), "{}", err);
+}
+
+#[test]
+fn test_nspawn_localization() {
+    let realm = Realm::new("tztest");
+    realm.config();
+    let generate = |tz: Option<&str>, locale: Option<&str>| {
+        realm.with_mut_config(|c| {
+            c.use_network = Some(false);
+            c.timezone = tz.map(String::from);
+            c.locale = locale.map(String::from);
+        });
+        RealmLauncher::new(&realm).generate_nspawn_file(&NetworkConfig::new(), None).unwrap()
+    };
+
+    let content = generate(None, None);
+    assert!(!content.contains("Timezone="));
+    assert!(!content.contains("/etc/localtime"));
+    assert!(!content.contains("/etc/locale.conf"));
+
+    let content = generate(Some("host"), Some("host"));
+    assert!(content.contains("[Exec]\nBoot=true\nTimezone=bind\n"));
+    assert!(!content.contains("/etc/localtime"));
+    assert!(content.contains("BindReadOnly=/etc/locale.conf\n"));
+
+    let content = generate(Some("Europe/Berlin"), Some("de_DE.UTF-8"));
+    assert!(content.contains("[Exec]\nBoot=true\nTimezone=off\n"));
+    assert!(content.contains("BindReadOnly=/run/citadel/realms/realm-tztest/localtime:/etc/localtime\n"));
+    assert!(content.contains("BindReadOnly=/run/citadel/realms/realm-tztest/locale.conf:/etc/locale.conf\n"));
+}
+
+#[test]
+fn test_journal_options() {
+    let realm = Realm::new("journaltest");
+    realm.config();
+    let generate = |journal: Option<&str>, max_size: Option<&str>| {
+        realm.with_mut_config(|c| {
+            c.use_network = Some(false);
+            c.journal = journal.map(String::from);
+            c.journal_max_size = max_size.map(String::from);
+        });
+        let launcher = RealmLauncher::new(&realm);
+        let nspawn = launcher.generate_nspawn_file(&NetworkConfig::new(), None).unwrap();
+        (launcher.generate_service_file(Path::new("/rootfs")), nspawn)
+    };
+
+    for &(journal, arg) in &[(None, "auto"), (Some("auto"), "auto"), (Some("host"), "host"), (Some("off"), "no")] {
+        let (service, nspawn) = generate(journal, None);
+        assert!(service.contains(&format!(" --link-journal={} ", arg)), "journal {:?}", journal);
+        assert!(!nspawn.contains("journald.conf"));
+    }
+
+    let (_, nspawn) = generate(Some("off"), Some("200M"));
+    assert!(nspawn.contains("BindReadOnly=/run/citadel/realms/realm-journaltest/journald.conf:/etc/systemd/journald.conf.d/citadel.conf\n"));
+}
+
+#[test]
+fn test_parse_bind_item() {
+    let bind = BindItem::parse("/storage/data").unwrap();
+    assert_eq!(bind.source, Path::new("/storage/data"));
+    assert_eq!(bind.dest, Path::new("/storage/data"));
+
+    let bind = BindItem::parse("/storage/a\\:b:/home/user/c\\:d:norbind,idmap").unwrap();
+    assert_eq!(bind.source, Path::new("/storage/a:b"));
+    assert_eq!(bind.dest, Path::new("/home/user/c:d"));
+
+    let bind = BindItem::parse("/storage\\:/etc").unwrap();
+    assert_eq!(bind.dest, Path::new("/storage:/etc"));
+
+    for bad in &[
+        "/storage:/etc", "/storage://etc/", "/storage:/usr/../etc", "/storage:/", "/sys",
+        "storage:/mnt", "/storage:mnt", "/storage:", ":/mnt", "",
+        "/storage:/mnt:rw", "/storage:/mnt:norbind:extra", "/storage:/mnt\\",
+        "/storage:/mnt\nBindReadOnly=/:/etc",
+    ] {
+        assert!(BindItem::parse(bad).is_err(), "expected '{}' to be rejected", bad);
+    }
+}
+
+#[test]
+fn test_nspawn_home_mode() {
+    let realm = Realm::new("hometest");
+    realm.config();
+    let generate = |mode: &str| {
+        realm.with_mut_config(|c| {
+            c.use_network = Some(false);
+            c.home_mode = Some(mode.to_string());
+        });
+        RealmLauncher::new(&realm).generate_nspawn_file(&NetworkConfig::new(), None).unwrap()
+    };
+
+    let content = generate("persistent");
+    assert!(content.contains("Bind=/realms/realm-hometest/home:/home/user\n"));
+    assert!(!content.contains("TemporaryFileSystem=/home/user"));
+    assert!(!content.contains("Overlay=/realms"));
+
+    let content = generate("ephemeral");
+    assert!(content.contains("TemporaryFileSystem=/home/user:mode=755,uid=1000,gid=1000\n"));
+    assert!(!content.contains("/realms/realm-hometest/home"));
+
+    let content = generate("readonly-overlay");
+    assert!(content.contains("Overlay=/realms/realm-hometest/home::/home/user\n"));
+    assert!(!content.contains("Bind=/realms/realm-hometest/home"));
+    assert!(!content.contains("TemporaryFileSystem=/home/user"));
+}
+
+#[test]
+fn test_nspawn_ephemeral_persistent_dirs() {
+    let home = crate::util::TempDir::new("home-test").unwrap();
+    for dir in &[".mozilla", "Documents"] {
+        fs::create_dir_all(home.join(dir)).unwrap();
+    }
+    let realm = Realm::new("persisttest");
+    realm.config();
+    realm.with_mut_config(|c| {
+        c.use_network = Some(false);
+        c.use_ephemeral_home = Some(true);
+        c.ephemeral_persistent_dirs = Some(vec![".mozilla".to_string(), "Missing".to_string(), "../escape".to_string()]);
+    });
+    let mut launcher = RealmLauncher::new(&realm);
+    launcher.home = home.to_path_buf();
+    let content = launcher.generate_nspawn_file(&NetworkConfig::new(), None).unwrap();
+    let canonical = home.canonicalize().unwrap();
+
+    assert!(content.contains(&format!("TemporaryFileSystem=/home/user:mode=755,uid=1000,gid=1000\nBind={}/.mozilla:/home/user/.mozilla\n", canonical.display())));
+    assert!(!content.contains("Documents"));
+    assert!(!content.contains("Missing"));
+    assert!(!content.contains("escape"));
+}
+
+#[test]
+fn test_nspawn_ephemeral_dirs() {
+    let realm = Realm::new("cachetest");
+    realm.config();
+    realm.with_mut_config(|c| {
+        c.use_network = Some(false);
+        c.ephemeral_dirs = Some(vec!["./.cache/".to_string(), ".local/share/../share/Trash".to_string(), "../escape".to_string()]);
+    });
+    let content = RealmLauncher::new(&realm).generate_nspawn_file(&NetworkConfig::new(), None).unwrap();
+    assert!(content.contains("Bind=/realms/realm-cachetest/home:/home/user\n\
+        TemporaryFileSystem=/home/user/.cache:mode=755,uid=1000,gid=1000\n\
+        TemporaryFileSystem=/home/user/.local/share/Trash:mode=755,uid=1000,gid=1000\n"));
+    assert!(!content.contains("escape"));
+
+    realm.with_mut_config(|c| c.home_mode = Some("ephemeral".to_string()));
+    let content = RealmLauncher::new(&realm).generate_nspawn_file(&NetworkConfig::new(), None).unwrap();
+    assert!(!content.contains("/home/user/.cache"));
+}
+
+#[test]
+fn test_nspawn_shared_binds() {
+    let realm = Realm::new("sharetest");
+    assert!(realm.config().share_opt() && realm.config().shared_dir());
+    for &(share_opt, shared_dir) in &[(true, true), (true, false), (false, true), (false, false)] {
+        realm.with_mut_config(|c| {
+            c.use_network = Some(false);
+            c.share_opt = Some(share_opt);
+            c.use_shared_dir = Some(shared_dir);
+        });
+        let mut launcher = RealmLauncher::new(&realm);
+        launcher.shared_dir_exists = true;
+        let content = launcher.generate_nspawn_file(&NetworkConfig::new(), None).unwrap();
+        assert_eq!(content.contains("BindReadOnly=/opt/share\n"), share_opt);
+        assert_eq!(content.contains("Bind=/realms/Shared:/home/user/Shared\n"), shared_dir);
+    }
+
+    let mut launcher = RealmLauncher::new(&realm);
+    realm.with_mut_config(|c| c.use_shared_dir = Some(true));
+    launcher.shared_dir_exists = false;
+    let content = launcher.generate_nspawn_file(&NetworkConfig::new(), None).unwrap();
+    assert!(!content.contains("/realms/Shared"));
+    // Only the info directory of the realm itself is visible inside it
+    assert!(content.contains("BindReadOnly=/run/citadel/realms/realm-sharetest/info:/run/citadel/realm-info\n"));
+}
+
+#[test]
+fn test_service_dropin_files() {
+    let base = crate::util::TempDir::new("dropin-test").unwrap();
+    let source = base.join("realm-dropins");
+    let target = base.join("realm-droptest.service.d");
+    let add = |dir: &str, name: &str, content: &str| {
+        fs::create_dir_all(source.join(dir)).unwrap();
+        fs::write(source.join(dir).join(name), content).unwrap();
+    };
+    add("all", "10-hardening.conf", "[Service]\nProtectClock=yes\n");
+    add("all", "20-after.conf", "[Unit]\nAfter=all.target\n");
+    add("all", "README", "not a fragment");
+    add("droptest", "20-after.conf", "[Unit]\nAfter=droptest.target\n");
+    add("other", "30-other.conf", "[Unit]\nAfter=other.target\n");
+
+    let realm = Realm::new("droptest");
+    realm.config();
+    let launcher = RealmLauncher::new(&realm);
+    fs::create_dir_all(&target).unwrap();
+    fs::write(target.join("stale.conf"), "").unwrap();
+    launcher.write_dropin_files(&source, &target).unwrap();
+
+    let mut names = fs::read_dir(&target).unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, vec!["10-hardening.conf", "20-after.conf"]);
+    assert_eq!(fs::read_to_string(target.join("10-hardening.conf")).unwrap(), "[Service]\nProtectClock=yes\n");
+    assert_eq!(fs::read_to_string(target.join("20-after.conf")).unwrap(), "[Unit]\nAfter=droptest.target\n");
+
+    // No fragments removes the drop-in directory entirely
+    fs::remove_dir_all(&source).unwrap();
+    launcher.write_dropin_files(&source, &target).unwrap();
+    assert!(!target.exists());
+}
+
+#[test]
+fn test_nspawn_desktop_integration() {
+    let realm = Realm::new("desktoptest");
+    realm.config();
+    let generate = |x11: bool, wayland: bool, integration: Option<bool>| {
+        realm.with_mut_config(|c| {
+            c.use_network = Some(false);
+            c.use_x11 = Some(x11);
+            c.use_wayland = Some(wayland);
+            c.desktop_integration = integration;
+        });
+        let mut launcher = RealmLauncher::new(&realm);
+        launcher.desktop_paths = vec!["/usr/share/fonts", "/etc/fonts"];
+        launcher.desktop_env = vec!["GTK_THEME=Adwaita:dark".to_string(), "XCURSOR_SIZE=24".to_string()];
+        launcher.generate_nspawn_file(&NetworkConfig::new(), None).unwrap()
+    };
+
+    let content = generate(false, true, None);
+    assert!(content.contains("BindReadOnly=/usr/share/fonts\nBindReadOnly=/etc/fonts\n"));
+    assert!(content.contains("Environment=GTK_THEME=Adwaita:dark\nEnvironment=XCURSOR_SIZE=24\n"));
+    assert!(!content.contains("/usr/share/icons"));
+    assert!(generate(true, false, None).contains("BindReadOnly=/usr/share/fonts\n"));
+
+    for content in &[generate(false, false, None), generate(true, true, Some(false))] {
+        assert!(!content.contains("/usr/share/fonts"));
+        assert!(!content.contains("GTK_THEME"));
+    }
+    assert!(generate(false, false, Some(true)).contains("BindReadOnly=/etc/fonts\n"));
+
+    let environment = "HOME=/home/citadel\nGTK_THEME=Adwaita:dark\nGTK_THEME_VARIANT=dark\nXCURSOR_SIZE=24\n";
+    assert_eq!(RealmLauncher::parse_desktop_env(environment), vec!["GTK_THEME=Adwaita:dark", "XCURSOR_SIZE=24"]);
+}
+
+#[test]
+fn test_resolv_conf() {
+    assert_eq!(RealmLauncher::generate_resolv_conf(&["10.8.0.1", "fd00::1"], &[]),
+               "nameserver 10.8.0.1\nnameserver fd00::1\n");
+    assert_eq!(RealmLauncher::generate_resolv_conf(&["10.8.0.1"], &["corp.example.com", "example.com"]),
+               "nameserver 10.8.0.1\nsearch corp.example.com example.com\n");
+
+    let realm = Realm::new("dnstest");
+    realm.config();
+    let generate = |dns: Option<Vec<&str>>| {
+        realm.with_mut_config(|c| {
+            c.use_network = Some(false);
+            c.dns = dns.map(|v| v.iter().map(|s| s.to_string()).collect());
+        });
+        RealmLauncher::new(&realm).generate_nspawn_file(&NetworkConfig::new(), None).unwrap()
+    };
+
+    let content = generate(None);
+    assert!(content.contains("BindReadOnly=/storage/citadel-state/resolv.conf:/etc/resolv.conf\n"));
+
+    let content = generate(Some(vec!["10.8.0.1"]));
+    assert!(content.contains("BindReadOnly=/run/citadel/realms/realm-dnstest/resolv.conf:/etc/resolv.conf\n"));
+    assert!(!content.contains("/storage/citadel-state/resolv.conf"));
+
+    let content = generate(Some(vec![]));
+    assert!(!content.contains("/etc/resolv.conf"));
+}
+
+#[test]
+fn test_hosts_file() {
+    let realm = Realm::new("hoststest");
+    realm.config();
+    realm.with_mut_config(|c| {
+        c.use_network = Some(true);
+        c.extra_hosts = Some(vec!["10.42.0.5 api.local api".to_string(), "@host host.local".to_string(), "@realm:missing db.local".to_string()]);
+    });
+    let netconfig = NetworkConfig::with_builtin_zones().unwrap();
+    let launcher = RealmLauncher::new(&realm);
+    let hosts = launcher.generate_hosts_file(&netconfig).unwrap();
+    assert_eq!(hosts, "127.0.0.1\tlocalhost\n::1\tlocalhost ip6-localhost ip6-loopback\n127.0.1.1\thoststest\n\
+                       10.42.0.5\tapi.local api\n172.17.0.1\thost.local\n");
+    let content = launcher.generate_extra_bind_mounts().unwrap();
+    assert!(content.contains("BindReadOnly=/run/citadel/realms/realm-hoststest/hosts:/etc/hosts\n"));
+}
+
+#[test]
+fn test_nspawn_sound_sockets() {
+    let realm = Realm::new("soundtest");
+    realm.config();
+    let generate = |pipewire_flag: bool, pulse: bool, pipewire: bool| {
+        realm.with_mut_config(|c| {
+            c.use_network = Some(false);
+            c.use_sound = Some(!pipewire_flag);
+            c.use_pipewire = Some(pipewire_flag);
+        });
+        let mut launcher = RealmLauncher::new(&realm);
+        launcher.sound_sockets = SoundSockets { pulse, pipewire };
+        launcher.generate_nspawn_file(&NetworkConfig::new(), None).unwrap()
+    };
+    let pulse_bind = "BindReadOnly=/run/user/1000/pulse:/run/user/host/pulse\n";
+    let pipewire_bind = "BindReadOnly=/run/user/1000/pipewire-0:/run/user/host/pipewire-0\n";
+    let pipewire_env = "Environment=PIPEWIRE_REMOTE=/run/user/host/pipewire-0\n";
+
+    let content = generate(false, true, true);
+    assert!(content.contains(pulse_bind));
+    assert!(content.contains(pipewire_bind));
+    assert!(content.contains(pipewire_env));
+
+    let content = generate(false, true, false);
+    assert!(content.contains(pulse_bind));
+    assert!(!content.contains(pipewire_bind));
+    assert!(!content.contains(pipewire_env));
+
+    let content = generate(false, false, true);
+    assert!(!content.contains(pulse_bind));
+    assert!(content.contains(pipewire_bind));
+    assert!(content.contains(pipewire_env));
+
+    let content = generate(false, false, false);
+    assert!(!content.contains(pulse_bind));
+    assert!(!content.contains(pipewire_bind));
+
+    // pipewire enabled without sound
+    let content = generate(true, true, true);
+    assert!(!content.contains(pulse_bind));
+    assert!(content.contains(pipewire_bind));
+    assert!(!generate(true, true, false).contains(pipewire_env));
+}
+
+#[test]
+fn test_camera_devices() {
+    let dev = crate::util::TempDir::new("camera-test").unwrap();
+    let realm = Realm::new("cameratest");
+    realm.config();
+    realm.with_mut_config(|c| {
+        c.use_network = Some(false);
+        c.use_camera = Some(true);
+    });
+    let generate = |nodes: &[&str]| {
+        let _ = fs::remove_dir_all(&dev);
+        fs::create_dir_all(&dev).unwrap();
+        for node in nodes {
+            fs::write(dev.join(node), "").unwrap();
+        }
+        let mut launcher = RealmLauncher::new(&realm);
+        launcher.add_camera_devices(&dev);
+        let nspawn = launcher.generate_nspawn_file(&NetworkConfig::new(), None).unwrap();
+        let service = launcher.generate_service_file(Path::new("/rootfs"));
+        (launcher.devices.len(), nspawn, service)
+    };
+    let d = dev.display();
+
+    let (count, nspawn, _) = generate(&["media0", "vcs1"]);
+    assert_eq!(count, 0);
+    assert!(!nspawn.contains("/run/udev/data"));
+
+    let (count, nspawn, service) = generate(&["video0", "media0", "videodev"]);
+    assert_eq!(count, 2);
+    assert!(nspawn.contains(&format!("Bind={}/video0\nBind={}/media0\n", d, d)));
+    assert!(nspawn.contains("BindReadOnly=/run/udev/data\n"));
+    assert!(service.contains(&format!("DeviceAllow={}/video0\n", d)));
+
+    let (count, nspawn, service) = generate(&["video10", "video2", "video1", "media1", "media0"]);
+    assert_eq!(count, 5);
+    assert!(nspawn.contains(&format!("Bind={}/video1\nBind={}/video2\nBind={}/video10\nBind={}/media0\nBind={}/media1\n", d, d, d, d, d)));
+    assert_eq!(service.matches("DeviceAllow=").count(), 5);
+}
+
+#[test]
+fn test_gpu_devices() {
+    use std::os::unix::fs::symlink;
+    let root = crate::util::TempDir::new("gpu-test").unwrap();
+    let (sys, dev) = (root.join("sys"), root.join("dev"));
+    for (render, card, driver) in &[("renderD128", "card0", "i915"), ("renderD129", "card1", "nvidia")] {
+        let device = sys.join("class/drm").join(render).join("device");
+        fs::create_dir_all(device.join("drm").join(card)).unwrap();
+        fs::create_dir_all(device.join("drm").join(render)).unwrap();
+        symlink(format!("../../../bus/pci/drivers/{}", driver), device.join("driver")).unwrap();
+    }
+    fs::create_dir_all(dev.join("dri")).unwrap();
+    for node in &["dri/renderD128", "dri/renderD129", "dri/card0", "dri/card1", "nvidia0", "nvidiactl", "nvidia-uvm"] {
+        fs::write(dev.join(node), "").unwrap();
+    }
+
+    let realm = Realm::new("gputest");
+    realm.config();
+    let devices = |device: Option<&str>, vendor: Option<&str>, card: bool| {
+        realm.with_mut_config(|c| {
+            c.use_gpu = Some(true);
+            c.use_gpu_card0 = Some(card);
+            c.gpu_device = device.map(String::from);
+            c.gpu_vendor = vendor.map(String::from);
+        });
+        let mut launcher = RealmLauncher::new(&realm);
+        launcher.add_gpu_devices(&sys, &dev);
+        launcher.devices.iter()
+            .map(|d| d[dev.display().to_string().len() + 1..].to_string())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(devices(None, None, false), vec!["dri/renderD128", "dri/renderD129", "nvidia0", "nvidiactl", "nvidia-uvm"]);
+    assert_eq!(devices(Some("renderD128"), None, true), vec!["dri/renderD128", "dri/card0"]);
+    assert_eq!(devices(None, Some("nvidia"), true), vec!["dri/renderD129", "dri/card1", "nvidia0", "nvidiactl", "nvidia-uvm"]);
+    assert!(devices(None, Some("amd"), false).is_empty());
+}
+
+#[test]
+fn test_launch_report() {
+    use std::os::unix::fs::symlink;
+    let root = crate::util::TempDir::new("report-test").unwrap();
+    let (sys, dev) = (root.join("sys"), root.join("dev"));
+    let device = sys.join("class/drm/renderD129/device");
+    fs::create_dir_all(device.join("drm/card1")).unwrap();
+    symlink("../../../bus/pci/drivers/amdgpu", device.join("driver")).unwrap();
+    fs::create_dir_all(dev.join("dri")).unwrap();
+    for node in &["dri/renderD129", "dri/card1", "video0", "media0"] {
+        fs::write(dev.join(node), "").unwrap();
+    }
+    let d = dev.display();
+
+    let realm = Realm::new("reporttest");
+    realm.config();
+    realm.with_mut_config(|c| {
+        c.use_network = Some(false);
+        c.use_gpu_card0 = Some(true);
+        c.gpu_vendor = Some("amd".to_string());
+        c.use_sound = Some(true);
+        c.use_wayland = Some(true);
+        c.use_pipewire = Some(false);
+    });
+    let mut launcher = RealmLauncher::new(&realm);
+    launcher.sound_sockets = SoundSockets { pulse: false, pipewire: true };
+    launcher.wayland_socket = Some(PathBuf::from("/run/user/1000/wayland-0"));
+    launcher.add_kvm_device(&dev);
+    launcher.add_gpu_devices(&sys, &dev);
+    launcher.add_camera_devices(&dev);
+    let report = launcher.launch_report();
+
+    let applied = |paths: Vec<String>| BindingResult::Applied { paths };
+    assert_eq!(report.binding("gpu"), Some(&applied(vec![format!("{}/dri/renderD129", d), format!("{}/dri/card1", d)])));
+    assert_eq!(report.binding("camera"), Some(&applied(vec![format!("{}/video0", d), format!("{}/media0", d)])));
+    assert_eq!(report.binding("pipewire"), Some(&applied(vec!["/run/user/1000/pipewire-0".to_string()])));
+    assert_eq!(report.binding("wayland"), Some(&applied(vec!["/run/user/1000/wayland-0".to_string()])));
+    assert_eq!(report.missing_bindings(), vec![
+        ("kvm", format!("{}/kvm does not exist", d).as_str()),
+        ("sound", "/run/user/1000/pulse does not exist"),
+    ]);
+    assert_eq!(report.summary()["gpu"], format!("applied: {}/dri/renderD129 {}/dri/card1", d, d));
+
+    // Devices which were not requested are not reported
+    realm.with_mut_config(|c| {
+        c.use_sound = Some(false);
+        c.use_wayland = Some(false);
+        c.gpu_vendor = Some("intel".to_string());
+    });
+    fs::remove_file(dev.join("video0")).unwrap();
+    let mut launcher = RealmLauncher::new(&realm);
+    launcher.sound_sockets = SoundSockets { pulse: false, pipewire: false };
+    launcher.add_gpu_devices(&sys, &dev);
+    launcher.add_camera_devices(&dev);
+    let report = launcher.launch_report();
+    assert_eq!(report.summary().keys().collect::<Vec<_>>(), vec!["camera", "gpu"]);
+    assert_eq!(report.missing_bindings(), vec![
+        ("camera", format!("no video devices in {}", d).as_str()),
+        ("gpu", format!("no matching render node in {}/dri", d).as_str()),
+    ]);
+    let json = serde_json::to_string(&report).unwrap();
+    assert_eq!(serde_json::from_str::<LaunchReport>(&json).unwrap(), report);
+}
+
+#[test]
+fn test_wayland_socket() {
+    let runtime = crate::util::TempDir::new("wayland-test").unwrap();
+    let realm = Realm::new("waylandtest");
+    realm.config();
+    realm.with_mut_config(|c| {
+        c.use_network = Some(false);
+        c.use_wayland = Some(true);
+    });
+    let find = |display: Option<&str>| RealmLauncher::find_wayland_socket(&realm, &runtime, display.map(String::from));
+
+    assert_eq!(find(None), None);
+    let mut launcher = RealmLauncher::new(&realm);
+    launcher.wayland_socket = None;
+    let content = launcher.generate_nspawn_file(&NetworkConfig::new(), None).unwrap();
+    assert!(!content.contains("wayland-0"));
+
+    fs::write(runtime.join("wayland-0"), "").unwrap();
+    fs::write(runtime.join("wayland-0.lock"), "").unwrap();
+    assert_eq!(find(None), Some(runtime.join("wayland-0")));
+    assert_eq!(find(Some("wayland-5")), Some(runtime.join("wayland-0")));
+    fs::write(runtime.join("wayland-1"), "").unwrap();
+    assert_eq!(find(Some("wayland-0")), Some(runtime.join("wayland-0")));
+
+    realm.with_mut_config(|c| c.wayland_socket = Some("/run/compositor/display".to_string()));
+    assert_eq!(find(Some("wayland-0")), Some(PathBuf::from("/run/compositor/display")));
+
+    launcher.wayland_socket = Some(runtime.join("wayland-1"));
+    let content = launcher.generate_nspawn_file(&NetworkConfig::new(), None).unwrap();
+    assert!(content.contains(&format!("BindReadOnly={}/wayland-1:/run/user/host/wayland-0\n", runtime.display())));
+    assert!(content.contains("Environment=WAYLAND_DISPLAY=wayland-0\n"));
+}
+
+#[test]
+fn test_nspawn_session_bus() {
+    let realm = Realm::new("bustest");
+    realm.config();
+    realm.with_mut_config(|c| {
+        c.use_network = Some(false);
+        c.session_bus = Some("filtered".to_string());
+    });
+    let content = RealmLauncher::new(&realm).generate_nspawn_file(&NetworkConfig::new(), None).unwrap();
+    assert!(content.contains("BindReadOnly=/run/citadel/realms/realm-bustest/dbus-proxy:/run/user/host/dbus-proxy\n"));
+    assert!(content.contains("Environment=DBUS_SESSION_BUS_ADDRESS=unix:path=/run/user/host/dbus-proxy/bus\n"));
+
+    realm.with_mut_config(|c| c.session_bus = Some("none".to_string()));
+    let content = RealmLauncher::new(&realm).generate_nspawn_file(&NetworkConfig::new(), None).unwrap();
+    assert!(!content.contains("dbus-proxy"));
+}
+
+#[test]
+fn test_nspawn_clipboard() {
+    let realm = Realm::new("cliptest");
+    realm.config();
+    realm.with_mut_config(|c| {
+        c.use_network = Some(false);
+        c.clipboard = Some("outbound-only".to_string());
+    });
+    let content = RealmLauncher::new(&realm).generate_nspawn_file(&NetworkConfig::new(), None).unwrap();
+    assert!(content.contains("BindReadOnly=/run/citadel/realms/realm-cliptest/clipboard:/run/user/host/clipboard\n"));
+    assert!(content.contains("Environment=CITADEL_CLIPBOARD_SOCKET=/run/user/host/clipboard/clipboard.sock\n"));
+
+    realm.with_mut_config(|c| c.clipboard = None);
+    let content = RealmLauncher::new(&realm).generate_nspawn_file(&NetworkConfig::new(), None).unwrap();
+    assert!(!content.contains("clipboard"));
+}
+
+#[test]
+fn test_service_restart_policy() {
+    let realm = Realm::new("restarttest");
+    realm.config();
+    let generate = |policy: Option<&str>, max: Option<u32>| {
+        realm.with_mut_config(|c| {
+            c.restart_policy = policy.map(String::from);
+            c.restart_max_per_hour = max;
+        });
+        RealmLauncher::new(&realm).generate_service_file(Path::new("/rootfs"))
+    };
+
+    let content = generate(None, None);
+    assert!(content.contains("Type=notify\nRestartForceExitStatus=133\nSuccessExitStatus=133\n"));
+    assert!(!content.contains("Restart="));
+    assert!(!content.contains("StartLimit"));
+
+    let content = generate(Some("no"), None);
+    assert!(content.contains("Restart=no\n"));
+    assert!(!content.contains("RestartForceExitStatus"));
+
+    let content = generate(Some("on-failure"), Some(3));
+    assert!(content.contains("instance\nAfter=citadel-apply-staged-update.service\nStartLimitIntervalSec=3600\nStartLimitBurst=3\n"));
+    assert!(content.contains("Restart=on-failure\nRestartSec=5\nRestartForceExitStatus=133\n"));
+}
+
+#[test]
+fn test_service_resource_options() {
+    let realm = Realm::new("resourcetest");
+    realm.config();
+    let content = RealmLauncher::new(&realm).generate_service_file(Path::new("/rootfs"));
+    assert!(!content.contains("AllowedCPUs") && !content.contains("CPUWeight") && !content.contains("Nice"));
+
+    realm.with_mut_config(|c| {
+        c.allowed_cpus = Some("2-5".to_string());
+        c.cpu_weight = Some(50);
+        c.nice = Some(10);
+    });
+    let content = RealmLauncher::new(&realm).generate_service_file(Path::new("/rootfs"));
+    assert!(content.contains("\nAllowedCPUs=2-5\nCPUWeight=50\nNice=10\n"));
+}
+
+#[test]
+fn test_remove_orphaned_launch_config_files() {
+    assert_eq!(RealmLauncher::realm_for_unit_file(&RealmLauncher::service_name_for("main")), Some("main"));
+    assert_eq!(RealmLauncher::realm_for_unit_file("realm-main.service.d"), Some("main"));
+    assert_eq!(RealmLauncher::realm_for_unit_file("realm-.service"), None);
+    assert_eq!(RealmLauncher::realm_for_unit_file("boot.mount"), None);
+    assert_eq!(RealmLauncher::realm_for_nspawn_file(&RealmLauncher::nspawn_file_name_for("main")), Some("main"));
+    assert_eq!(RealmLauncher::realm_for_nspawn_file("main.conf"), None);
+
+    let base = crate::util::TempDir::new("orphan-test").unwrap();
+    let unit_dir = base.join("system");
+    let nspawn_dir = base.join("nspawn");
+    fs::create_dir_all(unit_dir.join("realm-deleted.service.d")).unwrap();
+    fs::create_dir_all(&nspawn_dir).unwrap();
+    for name in &["main", "deleted", "running"] {
+        fs::write(unit_dir.join(RealmLauncher::service_name_for(name)), "").unwrap();
+        fs::write(nspawn_dir.join(RealmLauncher::nspawn_file_name_for(name)), "").unwrap();
+    }
+    fs::write(unit_dir.join("boot.automount"), "").unwrap();
+
+    // "deleted" and "running" no longer exist but the service of "running" is still active
+    let active = ["main", "running"].iter().map(|s| s.to_string()).collect::<HashSet<_>>();
+    let removed = RealmLauncher::remove_orphans(&unit_dir, &nspawn_dir, &["main"], &active).unwrap();
+    let remaining = RealmLauncher::launch_config_files(&unit_dir, &nspawn_dir);
+    let automount = unit_dir.join("boot.automount").exists();
+
+    assert_eq!(removed, vec![
+        nspawn_dir.join("deleted.nspawn"),
+        unit_dir.join("realm-deleted.service"),
+        unit_dir.join("realm-deleted.service.d"),
+    ]);
+    assert_eq!(remaining.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>(),
+               vec!["main", "main", "running", "running"]);
+    assert!(automount);
+}
+
+#[test]
+fn test_launch_plan() {
+    let realm = Realm::new("plantest");
+    realm.config();
+    let netconfig = NetworkConfig::with_builtin_zones().unwrap();
+    let generate = || RealmLauncher::new(&realm).plan(Path::new("/rootfs"), &netconfig).unwrap();
+
+    // Each flag and a line which is in the .nspawn file or the service unit only when the flag is set
+    type SetFlag = fn(&mut crate::RealmConfig, bool);
+    let flags: &[(SetFlag, &str)] = &[
+        (|c, on| c.share_opt = Some(on), "BindReadOnly=/opt/share\n"),
+        (|c, on| c.use_x11 = Some(on), "BindReadOnly=/tmp/.X11-unix\n"),
+        (|c, on| c.use_ephemeral_home = Some(on), "TemporaryFileSystem=/home/user:mode=755,uid=1000,gid=1000\n"),
+        (|c, on| c.private_users = Some(on), "PrivateUsers=pick\n"),
+        (|c, on| c.no_new_privileges = Some(on), "NoNewPrivileges=yes\n"),
+        (|c, on| c.use_network = Some(!on), "Private=true\n"),
+        (|c, on| c.restart_policy = if on { Some("no".to_string()) } else { None }, "Restart=no\n"),
+        (|c, on| c.restart_max_per_hour = if on { Some(3) } else { None }, "StartLimitBurst=3\n"),
+        (|c, on| c.cpu_weight = if on { Some(50) } else { None }, "CPUWeight=50\n"),
+        (|c, on| c.netns = if on { Some("vpn".to_string()) } else { None }, "--network-namespace-path=/run/netns/vpn"),
+    ];
+    for &(set, line) in flags {
+        for &on in &[true, false] {
+            realm.with_mut_config(|c| set(c, on));
+            let plan = generate();
+            let contents = format!("{}{}", plan.nspawn_contents(), plan.service_contents());
+            assert_eq!(contents.contains(line), on, "'{}' with flag set to {}", line.trim(), on);
+        }
+    }
+
+    // An address is planned for a realm on the network but not allocated
+    realm.with_mut_config(|c| c.use_network = Some(true));
+    let plan = generate();
+    let ip = plan.allocated_ip().expect("no address planned");
+    assert!(ip.starts_with("172.17.0.") && ip.ends_with("/24"), "unexpected address {}", ip);
+    assert!(plan.nspawn_contents().contains(&format!("Environment=IFCONFIG_IP={}\nEnvironment=IFCONFIG_GW=172.17.0.1\n", ip)));
+    assert!(plan.nspawn_contents().contains("[Network]\nZone=clear\n"));
+    assert!(netconfig.allocated_address("plantest").is_none());
+
+    realm.with_mut_config(|c| c.reserved_ip = Some(210));
+    assert_eq!(generate().allocated_ip(), Some("172.17.0.210/24"));
+
+    realm.with_mut_config(|c| c.netns = Some("vpn".to_string()));
+    assert_eq!(generate().allocated_ip(), None);
+    realm.with_mut_config(|c| {
+        c.netns = None;
+        c.use_network = Some(false);
+    });
+    assert_eq!(generate().allocated_ip(), None);
+
+    realm.with_mut_config(|c| {
+        c.network_zone = Some("nowhere".to_string());
+        c.use_network = Some(true);
+    });
+    assert!(RealmLauncher::new(&realm).plan(Path::new("/rootfs"), &netconfig).is_err());
+}
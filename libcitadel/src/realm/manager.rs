@@ -1,14 +1,27 @@
 use std::collections::HashSet;
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::io;
+use std::net::{IpAddr,Ipv4Addr};
+use std::path::{Component, Path, PathBuf};
+use std::process::ExitStatus;
 use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration,Instant};
 
-use crate::{Mountpoint, Activation,Result, Realms, RealmFS, Realm, util};
+use crate::{Mountpoint, Activation,Result, Realms, RealmFS, Realm, RealmConfig, BootStatus, CommandLine, Metrics, KeyPair, util};
 use crate::realmfs::realmfs_set::RealmFSSet;
+use crate::terminal::TerminalCommand;
 
 use super::systemd::Systemd;
-use super::network::NetworkConfig;
-use super::events::{RealmEventListener, RealmEvent};
+use super::launcher::{LaunchPlan,LaunchReport,RealmLauncher};
+use super::network::{NetworkConfig,NetnsManager,PortForwarder,KillSwitch,NetworkBlockState,Reservation,ReservationConflict};
+use super::events::{RealmEventListener, RealmEvent, EventMask, SubscriptionId};
+use super::snapshot::{HomeSnapshots, RealmSnapshot};
+use super::home::EncryptedHome;
+use super::manifest::{self, ExportManifest, ManifestTrust};
+use super::quota::HomeQuota;
+use super::defaults::RealmDefaults;
+use super::resources::StartThresholds;
+use super::startup::{self, BootRealm, DEFAULT_BOOT_PARALLELISM};
 use crate::realm::realms::HasCurrentChanged;
 
 pub struct RealmManager {
@@ -33,23 +46,21 @@ impl Inner {
 
 impl RealmManager {
 
-    fn create_network_config() -> Result<NetworkConfig> {
-        let mut network = NetworkConfig::new();
-        network.add_bridge("clear", "172.17.0.0/24")?;
-        Ok(network)
-    }
+    /// Host directories which files may be copied to or from a realm through realmsd
+    pub const COPY_HOST_PATHS: &'static [&'static str] = &["/home/citadel", "/realms/Shared"];
 
     pub fn load() -> Result<Arc<Self>> {
         let inner = Inner::new()?;
         let inner = RwLock::new(inner);
 
-        let network = Self::create_network_config()?;
+        let network = NetworkConfig::load()?;
         let systemd =  Systemd::new(network);
 
         let manager = RealmManager{ inner, systemd };
         let manager = Arc::new(manager);
 
         manager.set_manager(&manager);
+        manager.check_reserved_addresses();
 
         Ok(manager)
     }
@@ -61,17 +72,153 @@ impl RealmManager {
         inner.realmfs_set.set_manager(manager);
     }
 
-    pub fn add_event_handler<F>(&self, handler: F)
+    /// Add a handler which is called for every `RealmEvent`.
+    pub fn add_event_handler<F>(&self, handler: F) -> SubscriptionId
         where F: Fn(&RealmEvent),
               F: 'static + Send + Sync
     {
-        self.inner_mut().events.add_handler(handler);
+        self.subscribe_events(EventMask::ALL, handler)
+    }
+
+    /// Add a handler which is only called for events with a type in `mask`.
+    pub fn subscribe_events<F>(&self, mask: EventMask, handler: F) -> SubscriptionId
+        where F: Fn(&RealmEvent),
+              F: 'static + Send + Sync
+    {
+        self.inner().events.add_handler(mask, handler)
+    }
+
+    /// Remove an event handler. Returns `false` if the handler was already removed.
+    pub fn remove_event_handler(&self, id: SubscriptionId) -> bool {
+        self.inner().events.remove_handler(id)
+    }
+
+    /// Remove network namespaces created for realms with `netns = "auto"` which
+    /// are no longer running. Should be called when the realm manager daemon starts.
+    pub fn remove_orphaned_netns(&self) -> Result<()> {
+        let active = self.active_realms(false);
+        let names = active.iter().map(|r| r.name()).collect::<Vec<_>>();
+        NetnsManager::remove_orphaned(&names)
+    }
+
+    /// Install the baseline firewall isolating the network zones from each other.
+    /// Should be called when the realm manager daemon starts.
+    pub fn install_firewall(&self) -> Result<()> {
+        self.systemd.install_firewall()
+    }
+
+    /// Add network zones which were defined after the realm manager was loaded
+    /// and install the firewall again if there are any. Returns the names of
+    /// the added zones.
+    pub fn load_new_network_zones(&self) -> Result<Vec<String>> {
+        self.systemd.load_new_network_zones()
+    }
+
+    /// Remove port forward rules installed for realms which are no longer
+    /// running. Should be called when the realm manager daemon starts.
+    pub fn flush_orphaned_port_forwards(&self) -> Result<()> {
+        let active = self.active_realms(false);
+        let names = active.iter().map(|r| r.name()).collect::<Vec<_>>();
+        PortForwarder::flush_orphans(&names)
+    }
+
+    /// Give a config error to realms which reserve an address that another realm
+    /// in the same network zone also reserves, so that they are not started.
+    /// Called when realms are loaded and when a realm config file changes.
+    pub(crate) fn check_reserved_addresses(&self) {
+        let realms = self.realm_list();
+        let reservations = realms.iter()
+            .flat_map(Reservation::for_realm)
+            .collect::<Vec<_>>();
+        let conflicts = ReservationConflict::find(&reservations);
+        for realm in &realms {
+            let conflict = conflicts.iter()
+                .find(|c| c.others().iter().any(|name| name == realm.name()))
+                .map(|c| c.message_for(realm.name()));
+            if realm.set_address_conflict(conflict.clone()) {
+                match conflict {
+                    Some(conflict) => warn!("Realm {} cannot be started: {}", realm.name(), conflict),
+                    None => info!("Reserved address conflict of realm {} is resolved", realm.name()),
+                }
+            }
+        }
+    }
+
+    /// Remove realm service units and .nspawn files left in /run/systemd for realms
+    /// which no longer exist. Should be called when the realm manager daemon starts.
+    pub fn remove_orphaned_launch_config_files(&self) -> Result<Vec<PathBuf>> {
+        let realms = self.realm_list();
+        let names = realms.iter().map(|r| r.name()).collect::<Vec<_>>();
+        self.systemd.remove_orphaned_launch_config_files(&names)
+    }
+
+    /// Set the minimum available memory and free storage checked before a realm is started
+    pub fn set_start_thresholds(&self, thresholds: StartThresholds) {
+        self.systemd.set_start_thresholds(thresholds);
+    }
+
+    /// Return a `LowResourcesError` if `realm` would be refused to start because
+    /// the host is low on memory or storage.
+    pub fn check_start_resources(&self, realm: &Realm) -> Result<()> {
+        self.systemd.check_start_resources(realm)
+    }
+
+    /// Terminate machines left registered with systemd-machined by realms which
+    /// crashed, so that the realms can be started again. Should be called when
+    /// the realm manager daemon starts.
+    pub fn terminate_stale_machines(&self) -> Result<()> {
+        for name in Systemd::terminate_stale_machines()? {
+            info!("Terminated stale machine {} which had no active realm service", name);
+        }
+        Ok(())
+    }
+
+    /// Terminate the machine of `realm` if it is still registered although the
+    /// realm is not running. Returns `true` if a stale machine was terminated.
+    pub fn recover_realm(&self, realm: &Realm) -> Result<bool> {
+        Systemd::recover_realm_machine(realm.name())
+    }
+
+    /// Free network addresses still allocated to realms which are no longer running,
+    /// for example because the realm service exited without `stop_realm()` being called.
+    pub fn reconcile_network_allocations(&self) -> Result<()> {
+        for name in self.systemd.reconcile_network_allocations()? {
+            info!("Freed network address allocated to realm {} which is not running", name);
+        }
+        Ok(())
+    }
+
+    /// Return the name, subnet, number of allocated addresses, and number of
+    /// allocatable addresses of each network zone.
+    pub fn network_zones(&self) -> Vec<(String, String, u32, u32)> {
+        self.systemd.network_zones()
+    }
+
+    /// Return the network address allocations currently held as `(zone, realm, address)`
+    pub fn network_allocations(&self) -> Vec<(String, String, IpAddr)> {
+        self.systemd.network_allocations()
+    }
+
+    /// Allocate addresses recorded by a previous realmsd process to running
+    /// realms which no longer have an allocation in their zone.
+    pub fn restore_network_allocations(&self, allocations: &[(String, String, IpAddr)]) -> Result<()> {
+        for name in self.systemd.restore_network_allocations(allocations)? {
+            info!("Restored network address allocation of realm {}", name);
+        }
+        Ok(())
+    }
+
+    /// The IPv4 address allocated to `realm` on its zone bridge, if any
+    pub fn realm_address(&self, realm: &Realm) -> Option<Ipv4Addr> {
+        self.systemd.realm_address(realm)
     }
 
     pub fn start_event_task(&self) -> Result<()> {
         self.inner_mut().events.start_event_task()
     }
 
+    /// Stop the event listening tasks started with `start_event_task()`. The tasks
+    /// exit in the background shortly after this returns.
     pub fn stop_event_task(&self) {
         self.inner_mut().events.stop();
     }
@@ -84,24 +231,52 @@ impl RealmManager {
     /// a user (uid = 1000) shell.
     ///
     pub fn launch_shell(&self, realm: &Realm, root_shell: bool) -> Result<()> {
-        Systemd::machinectl_exec_shell(realm, root_shell, true)?;
+        let username = if root_shell { "root" } else { "user" };
+        Systemd::machinectl_exec_shell(realm, username, true)?;
         info!("exiting shell in realm '{}'", realm.name());
         Ok(())
     }
 
     pub fn launch_terminal(&self, realm: &Realm) -> Result<()> {
-        info!("opening terminal in realm '{}'", realm.name());
-        let title_arg = format!("Realm: {}", realm.name());
-        let args = &["/usr/bin/gnome-terminal".to_owned(), "--title".to_owned(), title_arg];
-        Systemd::machinectl_shell(realm, args, "user", true, true)?;
+        let command = self.choose_terminal(realm)?;
+        self.launch_terminal_command(realm, &command, "user")
+    }
+
+    /// Open a terminal running `command` in `realm` as the account `user`.
+    pub fn launch_terminal_command(&self, realm: &Realm, command: &TerminalCommand, user: &str) -> Result<()> {
+        info!("opening terminal in realm '{}' as {}", realm.name(), user);
+        Systemd::machinectl_shell(realm, command.args(), user, true, true)?;
         Ok(())
     }
 
-    pub fn run_in_realm<S: AsRef<str>>(&self, realm: &Realm, args: &[S], use_launcher: bool) -> Result<()> {
-        Systemd::machinectl_shell(realm, args, "user", use_launcher, false)
+    /// Find the first terminal command which is available in `realm` by testing
+    /// if the terminal program exists inside the running realm.
+    pub fn choose_terminal(&self, realm: &Realm) -> Result<TerminalCommand> {
+        let title = format!("Realm: {}", realm.name());
+        let candidates = TerminalCommand::load_candidates(realm.config().terminal_command(), &title);
+        for command in &candidates {
+            let status = Systemd::machinectl_shell(realm, &["/usr/bin/test", "-x", command.program()], "user", false, true)?;
+            if status.success() {
+                info!("Using terminal {} in realm '{}' ({})", command.program(), realm.name(), command.reason());
+                return Ok(command.clone());
+            }
+            verbose!("Terminal {} not found in realm '{}'", command.program(), realm.name());
+        }
+        let tried = candidates.iter().map(|c| c.program()).collect::<Vec<_>>();
+        bail!("No terminal program found in realm '{}'. Tried: {}", realm.name(), tried.join(", "))
     }
 
-    pub fn run_in_current<S: AsRef<str>>(args: &[S], use_launcher: bool) -> Result<()> {
+    /// Run a command in `realm` as the account `user` and return the exit status of the command.
+    pub fn run_in_realm<S: AsRef<str>>(&self, realm: &Realm, args: &[S], user: &str, use_launcher: bool) -> Result<ExitStatus> {
+        Systemd::machinectl_shell(realm, args, user, use_launcher, false)
+    }
+
+    /// Return an error unless `user` is a valid name for an account to run commands as in a realm
+    pub fn check_username(user: &str) -> Result<()> {
+        Systemd::check_username(user)
+    }
+
+    pub fn run_in_current<S: AsRef<str>>(args: &[S], use_launcher: bool) -> Result<ExitStatus> {
         let realm = Realms::load_current_realm()
             .ok_or_else(|| format_err!("Could not find current realm"))?;
 
@@ -112,15 +287,121 @@ impl RealmManager {
     }
 
     pub fn copy_to_realm<P: AsRef<Path>, Q:AsRef<Path>>(&self, realm: &Realm, from: P, to: Q) -> Result<()> {
+        if !realm.is_active() {
+            bail!("Cannot copy files into realm {} because it is not running", realm.name());
+        }
         let from = from.as_ref().to_string_lossy();
         let to = to.as_ref().to_string_lossy();
         self.systemd.machinectl_copy_to(realm, from.as_ref(), to.as_ref())
     }
 
+    /// Copy file or directory `from` inside `realm` to host path `to`. The
+    /// destination must be located inside one of `COPY_HOST_PATHS`.
+    pub fn copy_from_realm<P: AsRef<Path>, Q:AsRef<Path>>(&self, realm: &Realm, from: P, to: Q) -> Result<()> {
+        if !realm.is_active() {
+            bail!("Cannot copy files from realm {} because it is not running", realm.name());
+        }
+        let to = Self::check_host_copy_path(to.as_ref())?;
+        let from = from.as_ref().to_string_lossy();
+        self.systemd.machinectl_copy_from(realm, from.as_ref(), &to)
+    }
+
+    /// Bind mount host directory `from` at `to` inside the running `realm`.
+    /// The directory `to` is created if it does not exist.
+    pub fn bind_into_realm<P: AsRef<Path>>(&self, realm: &Realm, from: P, to: &str) -> Result<()> {
+        if !realm.is_active() {
+            bail!("Cannot bind {} into realm {} because it is not running", from.as_ref().display(), realm.name());
+        }
+        self.systemd.machinectl_bind(realm, from.as_ref(), to)
+    }
+
+    /// Unmount a directory bound into `realm` with `bind_into_realm()`
+    pub fn unbind_from_realm(&self, realm: &Realm, path: &str) -> Result<()> {
+        if !realm.is_active() {
+            return Ok(());
+        }
+        self.systemd.machinectl_umount(realm, path)
+    }
+
+    /// Update the VPN kill switch of a running realm with the `vpn-required` option
+    /// to match the state of the VPN interface. Returns the new state if it changed.
+    pub fn update_kill_switch(&self, realm: &Realm) -> Result<Option<NetworkBlockState>> {
+        if !realm.is_active() {
+            return Ok(None);
+        }
+        self.systemd.update_kill_switch(realm)
+    }
+
+    /// Return the restrictions the VPN kill switch currently applies to `realm`
+    pub fn network_block_state(&self, realm: &Realm) -> Result<NetworkBlockState> {
+        KillSwitch::state(realm.name())
+    }
+
+    /// Generate the launch config files of `realm` without starting it. The
+    /// rootfs of a realm which is not running is only set up when it starts, so
+    /// the rootfs symlink in the realm run directory is used in its place.
+    pub fn preview_launch_config(&self, realm: &Realm) -> Result<LaunchPlan> {
+        let rootfs = realm.rootfs().unwrap_or_else(|| realm.run_path_file("rootfs"));
+        self.systemd.preview_realm(realm, &rootfs)
+    }
+
+    /// Return the optional bindings which were requested and added when `realm`
+    /// was last started, or `None` if it has not been started since boot.
+    pub fn launch_report(&self, realm: &Realm) -> Result<Option<LaunchReport>> {
+        LaunchReport::load(realm)
+    }
+
+    /// Return the number of video devices which were added to `realm` when it
+    /// was started. Devices plugged in after the realm started are not counted
+    /// because they are not available inside the realm.
+    pub fn camera_device_count(&self, realm: &Realm) -> usize {
+        if !realm.is_active() || !realm.config().camera() {
+            return 0;
+        }
+        RealmLauncher::new(realm).camera_device_count()
+    }
+
+    /// Return an error unless `path` is an absolute path located inside one of
+    /// the directories listed in `COPY_HOST_PATHS`. Symlinks are resolved before
+    /// checking, including `path` itself if it exists, and the resolved path is
+    /// returned so that the copy does not follow a symlink which was not checked.
+    pub fn check_host_copy_path(path: &Path) -> Result<PathBuf> {
+        if !path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+            bail!("host path {} must be an absolute path without '..' components", path.display());
+        }
+        let (parent, file_name) = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(file_name)) => (parent, file_name),
+            _ => bail!("host path {} is not a valid copy path", path.display()),
+        };
+        let resolved = if path.symlink_metadata().is_ok() {
+            path.canonicalize()
+                .map_err(|e| format_err!("cannot resolve host path {}: {}", path.display(), e))?
+        } else {
+            let resolved = parent.canonicalize()
+                .map_err(|e| format_err!("cannot resolve directory {}: {}", parent.display(), e))?;
+            resolved.join(file_name)
+        };
+        if !Self::COPY_HOST_PATHS.iter().any(|dir| resolved.starts_with(dir)) {
+            bail!("host path {} is not inside an allowed directory ({})", path.display(), Self::COPY_HOST_PATHS.join(", "));
+        }
+        Ok(resolved)
+    }
+
     pub fn realm_list(&self) -> Vec<Realm> {
         self.inner_mut().realms.sorted()
     }
 
+    /// Return all realms after reading the active state of every realm from
+    /// systemd with a single `systemctl is-active` call.
+    pub fn realm_list_refreshed(&self) -> Result<Vec<Realm>> {
+        let realms = self.realm_list();
+        let active = Systemd::are_realms_active(&realms)?;
+        for (realm, active) in realms.iter().zip(active) {
+            realm.set_active(active);
+        }
+        Ok(realms)
+    }
+
     pub fn active_realms(&self, ignore_system: bool) -> Vec<Realm> {
         self.inner().realms.active(ignore_system)
     }
@@ -131,7 +412,7 @@ impl RealmManager {
             .into_iter()
             .filter(|r| {
                 r.realmfs_mountpoint()
-                    .map_or(false, |mp| activation.is_mountpoint(&mp))
+                    .is_some_and(|mp| activation.is_mountpoint(&mp))
             })
             .collect()
     }
@@ -184,24 +465,136 @@ impl RealmManager {
             .collect()
     }
 
+    /// Start the default realm and all realms with `autostart` enabled, together
+    /// with the realms they depend on. Up to `citadel.realm_parallelism=` realms
+    /// (default 3) are started at the same time, and a realm is only started after
+    /// the realms it depends on. The time taken to start each realm is recorded
+    /// in the boot status file.
     pub fn start_boot_realms(&self) -> Result<()> {
-        if let Some(realm) = self.default_realm() {
-            if let Err(e) = self.start_realm(&realm) {
-                bail!("Failed to start default realm '{}': {}", realm.name(), e);
+        let default = match self.default_realm() {
+            Some(realm) => realm,
+            None => bail!("No default realm to start"),
+        };
+        let realms = self.boot_realms(&default);
+        let boot_realms = realms.iter()
+            .map(|r| BootRealm::new(r.name(), &r.config().realm_depends()))
+            .collect::<Vec<_>>();
+        let parallelism = CommandLine::realm_parallelism().unwrap_or(DEFAULT_BOOT_PARALLELISM);
+
+        info!("Starting {} realms at boot, {} at a time", realms.len(), parallelism);
+        let started = Instant::now();
+        let results = startup::start_realms(&boot_realms, parallelism, |name| {
+            match realms.iter().find(|r| r.name() == name) {
+                Some(realm) => self.start_realm(realm),
+                None => bail!("Realm '{}' not found", name),
             }
-        } else {
-            bail!("No default realm to start");
+        });
+        let total = started.elapsed();
+
+        for result in &results {
+            match result.error() {
+                Some(err) => warn!("Failed to start realm '{}' after {}ms: {}", result.name(), result.duration().as_millis(), err),
+                None => info!("Started realm '{}' in {}ms", result.name(), result.duration().as_millis()),
+            }
+        }
+        info!("Started boot realms in {}ms", total.as_millis());
+
+        // Realms started concurrently may have been made current before the default realm
+        if default.is_active() && !default.is_current() {
+            self.set_current_realm(&default)
+                .unwrap_or_else(|e| warn!("Failed to set default realm as current: {}", e));
+        }
+
+        let failed = results.iter()
+            .filter(|r| r.error().is_some())
+            .map(|r| r.name().to_string())
+            .collect::<Vec<_>>();
+        let default_error = results.iter()
+            .find(|r| r.name() == default.name())
+            .and_then(|r| r.error().map(|e| e.to_string()));
+        if let Err(e) = BootStatus::record_realm_startup(results, total) {
+            warn!("Failed to record realm start times: {}", e);
+        }
+        if let Some(err) = default_error {
+            bail!("Failed to start default realm '{}': {}", default.name(), err);
+        }
+        if !failed.is_empty() {
+            bail!("Failed to start realms: {}", failed.join(", "));
         }
         Ok(())
     }
 
+    // The default realm and the realms with autostart enabled, followed by
+    // any realms they depend on which would not otherwise be started.
+    fn boot_realms(&self, default: &Realm) -> Vec<Realm> {
+        let mut realms = vec![default.clone()];
+        for realm in self.realm_list() {
+            if realm.config().autostart() && realm.name() != default.name() {
+                realms.push(realm);
+            }
+        }
+        let mut idx = 0;
+        while idx < realms.len() {
+            let depends = realms[idx].config().realm_depends().iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>();
+            for name in depends {
+                if !realms.iter().any(|r| r.name() == name) {
+                    if let Some(realm) = self.realm_by_name(&name) {
+                        realms.push(realm);
+                    }
+                }
+            }
+            idx += 1;
+        }
+        realms
+    }
+
     pub fn start_realm(&self, realm: &Realm) -> Result<()> {
         if realm.is_active() {
             info!("ignoring start request on already running realm '{}'", realm.name());
             return Ok(());
         }
+        if realm.is_restoring() {
+            bail!("Cannot start realm {} while its home directory is being restored from a snapshot", realm.name());
+        }
         info!("Starting realm {}", realm.name());
-        self._start_realm(realm, &mut HashSet::new())?;
+        let started = Instant::now();
+        let wait_for_network = realm.config().wait_for_network();
+        if wait_for_network {
+            realm.hold_started_event();
+        }
+        if let Err(e) = self._start_realm(realm, &mut HashSet::new()) {
+            Metrics::realm_started(realm.name(), started.elapsed(), false);
+            realm.release_started_event();
+            // Remove launch config files so that a retry starts clean
+            if let Err(e) = RealmLauncher::new(realm).remove_launch_config_files() {
+                warn!("Failed to remove launch config files for realm {}: {}", realm.name(), e);
+            }
+            // The managed network namespace and its veth on the zone bridge are
+            // created before the launch config files are planned and written
+            if realm.config().managed_netns() {
+                if let Err(e) = NetnsManager::remove(realm.name()) {
+                    warn!("Failed to remove network namespace of realm {}: {}", realm.name(), e);
+                }
+            }
+            if let Err(e) = self.systemd.free_network_allocation(realm) {
+                warn!("Failed to free network address of realm {}: {}", realm.name(), e);
+            }
+            if let Err(e) = self.systemd.remove_kill_switch(realm) {
+                warn!("Failed to remove VPN kill switch of realm {}: {}", realm.name(), e);
+            }
+            self.inner().events.send_event(RealmEvent::Failed(realm.clone(), e.to_string()));
+            return Err(e);
+        }
+
+        if wait_for_network {
+            self.wait_for_realm_network(realm);
+            if realm.release_started_event() {
+                self.inner().events.send_event(RealmEvent::Started(realm.clone()));
+            }
+        }
+        Metrics::realm_started(realm.name(), started.elapsed(), true);
 
         if !Realms::is_some_realm_current() {
             self.inner_mut().realms.set_realm_current(realm)
@@ -212,6 +605,10 @@ impl RealmManager {
 
     fn _start_realm(&self, realm: &Realm, starting: &mut HashSet<String>) -> Result<()> {
 
+        if let Some(error) = realm.config_error() {
+            bail!("Cannot start realm {} because its config file has errors: {}", realm.name(), error);
+        }
+
         self.start_realm_dependencies(realm, starting)?;
 
         let home = realm.base_path_file("home");
@@ -221,6 +618,8 @@ impl RealmManager {
             util::chown_user(&home)?;
         }
 
+        HomeQuota::new(realm).check_start()?;
+
         let rootfs = realm.setup_rootfs()?;
 
         realm.update_timestamp()?;
@@ -236,6 +635,21 @@ impl RealmManager {
         Ok(())
     }
 
+    // Wait for the network setup inside the realm to bring up the address
+    // allocated to it. A realm which is slow to configure the network is still
+    // running so only a warning is logged if the timeout expires.
+    fn wait_for_realm_network(&self, realm: &Realm) {
+        let address = match self.systemd.realm_address(realm) {
+            Some(address) => address,
+            None => return,
+        };
+        let timeout = realm.config().network_wait_timeout();
+        verbose!("Waiting up to {} seconds for network of realm {} at {}", timeout, realm.name(), address);
+        if !NetworkConfig::wait_for_address(address, Duration::from_secs(timeout.into())) {
+            warn!("Network of realm {} at {} did not respond within {} seconds", realm.name(), address, timeout);
+        }
+    }
+
     fn create_realm_namefile(&self, realm: &Realm) -> Result<()> {
         let namefile = realm.run_path_file("realm-name");
         fs::write(&namefile, realm.name())?;
@@ -261,7 +675,73 @@ impl RealmManager {
     }
 
     fn link_wayland_socket(&self, realm: &Realm) -> Result<()> {
-        self.run_in_realm(realm, &["/usr/bin/ln", "-s", "/run/user/host/wayland-0", "/run/user/1000/wayland-0"], false)
+        let status = self.run_in_realm(realm, &["/usr/bin/ln", "-s", "/run/user/host/wayland-0", "/run/user/1000/wayland-0"], "user", false)?;
+        if !status.success() {
+            bail!("creating wayland socket link failed: {}", status);
+        }
+        Ok(())
+    }
+
+    /// Suspend all processes in a running realm. The current realm is only
+    /// frozen if `force` is `true`.
+    pub fn freeze_realm(&self, realm: &Realm, force: bool) -> Result<()> {
+        if !realm.is_active() {
+            bail!("Cannot freeze realm {} because it is not running", realm.name());
+        }
+        if realm.is_frozen() {
+            return Ok(());
+        }
+        if realm.is_current() && !force {
+            bail!("Cannot freeze realm {} because it is the current realm", realm.name());
+        }
+        info!("Freezing realm {}", realm.name());
+        self.systemd.freeze_realm(realm)?;
+        realm.set_frozen(true);
+        self.inner().events.send_event(RealmEvent::Frozen(realm.clone()));
+        Ok(())
+    }
+
+    /// Resume processes in a realm which was suspended with `freeze_realm()`
+    pub fn thaw_realm(&self, realm: &Realm) -> Result<()> {
+        if !realm.is_frozen() {
+            return Ok(());
+        }
+        info!("Thawing realm {}", realm.name());
+        self.systemd.thaw_realm(realm)?;
+        realm.set_frozen(false);
+        self.inner().events.send_event(RealmEvent::Thawed(realm.clone()));
+        Ok(())
+    }
+
+    /// Change the `allowed-cpus`, `cpu-weight` or `nice` resource limits of a
+    /// running realm until it is stopped.
+    pub fn apply_resource_limits(&self, realm: &Realm, limits: &[(String, String)]) -> Result<()> {
+        if !realm.is_active() {
+            bail!("Cannot change resource limits of realm {} because it is not running", realm.name());
+        }
+        info!("Applying resource limits to realm {}", realm.name());
+        self.systemd.apply_resource_properties(realm, limits)
+    }
+
+    /// Clear the failed state of `realm` so that it can be started again after
+    /// systemd has stopped restarting it because of `restart-max-per-hour`.
+    pub fn reset_failed_realm(&self, realm: &Realm) -> Result<()> {
+        self.systemd.reset_failed(realm)
+    }
+
+    /// Return `true` if `realm` is no longer being restarted because it hit the
+    /// start limit configured with `restart-max-per-hour`.
+    pub fn is_start_limit_hit(&self, realm: &Realm) -> bool {
+        Systemd::is_start_limit_hit(realm).unwrap_or_else(|e| {
+            warn!("Could not read service result for realm {}: {}", realm.name(), e);
+            false
+        })
+    }
+
+    /// Free the network address allocation of `realm` once it has failed and is
+    /// not being restarted.
+    pub fn free_network_allocation(&self, realm: &Realm) -> Result<()> {
+        self.systemd.free_network_allocation(realm)
     }
 
     pub fn stop_realm(&self, realm: &Realm) -> Result<()> {
@@ -272,8 +752,17 @@ impl RealmManager {
 
         info!("Stopping realm {}", realm.name());
 
+        // A frozen realm cannot respond to the shutdown request
+        if realm.is_frozen() {
+            self.thaw_realm(realm)
+                .unwrap_or_else(|e| warn!("Failed to thaw realm {} before stopping: {}", realm.name(), e));
+        }
+
         realm.set_active(false);
-        self.systemd.stop_realm(realm)?;
+        let started = Instant::now();
+        let result = self.systemd.stop_realm(realm);
+        Metrics::realm_stopped(realm.name(), started.elapsed(), result.is_ok());
+        result?;
         realm.cleanup_rootfs();
 
         if realm.is_current() {
@@ -282,13 +771,20 @@ impl RealmManager {
         Ok(())
     }
 
-    fn inner(&self) -> RwLockReadGuard<Inner> {
+    fn inner(&self) -> RwLockReadGuard<'_, Inner> {
         self.inner.read().unwrap()
     }
-    fn inner_mut(&self) -> RwLockWriteGuard<Inner> {
+    fn inner_mut(&self) -> RwLockWriteGuard<'_, Inner> {
         self.inner.write().unwrap()
     }
 
+    pub(crate) fn on_machine_new(&self, name: &str) -> Option<Realm> {
+        let realm = self.inner().realms.by_name(name)?;
+        realm.set_active(true);
+        realm.set_started(Duration::from_secs(0));
+        Some(realm)
+    }
+
     pub(crate) fn on_machine_removed(&self, name: &str) -> Option<Realm> {
         let realm = match self.inner().realms.by_name(name) {
             Some(ref realm) if realm.is_active() => realm.clone(),
@@ -298,6 +794,11 @@ impl RealmManager {
         // XXX do something to detect realmfs/overlay that is not cleaned up
         realm.set_active(false);
 
+        // The address of the realm may be allocated to another realm next
+        if let Err(e) = self.systemd.remove_kill_switch(&realm) {
+            warn!("Failed to remove VPN kill switch of realm {}: {}", realm.name(), e);
+        }
+
         if realm.is_current() {
             self.choose_some_current_realm();
         }
@@ -330,13 +831,32 @@ impl RealmManager {
         self.inner().realms.by_name(name)
     }
 
+    /// Return the realm which the process `pid` belongs to. The realm is found
+    /// from the realm service in the control group of the process, or if the
+    /// process is not in a realm service from the realm-name file in its root.
     pub fn realm_by_pid(&self, pid: u32) -> Option<Realm> {
+        let from_cgroup = fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()
+            .and_then(|content| Self::realm_name_from_cgroup(&content))
+            .and_then(|name| self.realm_by_name(&name));
+        if from_cgroup.is_some() {
+            return from_cgroup;
+        }
         match Self::read_realm_name_by_pid(pid) {
             Ok(name) => self.realm_by_name(name.as_str()),
             Err(_) => None,
         }
     }
 
+    // Processes of a realm are in the control group of the realm service or a
+    // group below it, such as 0::/system.slice/realm-main.service/payload
+    fn realm_name_from_cgroup(content: &str) -> Option<String> {
+        content.lines()
+            .flat_map(|line| line.splitn(3, ':').nth(2))
+            .flat_map(|path| path.split('/'))
+            .find_map(RealmLauncher::realm_for_unit_file)
+            .map(|name| name.to_string())
+    }
+
     fn read_realm_name_by_pid(pid: u32) -> Result<String> {
         let run = PathBuf::from(format!("/proc/{}/root/run", pid));
         let realm_name = run.join("realm-name");
@@ -369,7 +889,135 @@ impl RealmManager {
     }
 
     pub fn new_realm(&self, name: &str) -> Result<Realm> {
-        self.inner_mut().realms.create_realm(name)
+        self.new_realm_with_config(name, &RealmConfig::empty())
+    }
+
+    /// Create a new realm `name` with the values set in `config`. Keys which
+    /// are not set in `config` are taken from /etc/citadel/realm-defaults.conf.
+    pub fn new_realm_with_config(&self, name: &str, config: &RealmConfig) -> Result<Realm> {
+        let defaults = RealmDefaults::load()?;
+        self.inner_mut().realms.create_realm(name, config, &defaults)
+    }
+
+    /// Create a new realm `new_name` using the realm `source` as a template. If
+    /// `require_stopped` is set the operation fails when `source` is running,
+    /// otherwise a running realm is cloned with a best-effort copy of the home directory.
+    pub fn clone_realm(&self, source: &Realm, new_name: &str, clone_home: bool, require_stopped: bool) -> Result<Realm> {
+        self.clone_realm_with_progress(source, new_name, clone_home, require_stopped, &mut |_| {})
+    }
+
+    /// Like `clone_realm()` but `progress` is called with the number of files
+    /// of the home directory copied so far.
+    pub fn clone_realm_with_progress(&self, source: &Realm, new_name: &str, clone_home: bool, require_stopped: bool, progress: &mut dyn FnMut(usize)) -> Result<Realm> {
+        if source.is_active() {
+            if require_stopped {
+                bail!("Cannot clone realm {} while it is running", source.name());
+            }
+            if clone_home {
+                warn!("Realm {} is running, files in home directory may change while they are copied", source.name());
+            }
+        }
+        if self.realm_by_name(new_name).is_some() {
+            bail!("A realm with name '{}' already exists", new_name);
+        }
+        Realms::create_clone(source, new_name, clone_home, progress)?;
+        let realm = self.inner_mut().realms.add_created_realm(new_name);
+        self.inner().events.send_event(RealmEvent::New(realm.clone()));
+        Ok(realm)
+    }
+
+    /// Write the config file and home directory of the stopped realm `realm` to a
+    /// new realm export archive at `target`. The archive manifest is signed with
+    /// `keypair` if one is given.
+    pub fn export_realm(&self, realm: &Realm, target: &Path, keypair: Option<&KeyPair>) -> Result<ExportManifest> {
+        if realm.is_active() {
+            bail!("Cannot export realm {} while it is running", realm.name());
+        }
+        if realm.config().encrypted_home() {
+            bail!("Cannot export realm {} because it has an encrypted home directory", realm.name());
+        }
+        let base = realm.base_path();
+        let mut manifest = ExportManifest::new(realm.name());
+        manifest.add_file("config", fs::File::open(base.join("config"))?)?;
+        manifest.add_directory(&base, &base.join("home"))?;
+        let signed = match keypair {
+            Some(keypair) => manifest.sign_with(keypair)?,
+            None => manifest.unsigned()?,
+        };
+
+        let file = fs::OpenOptions::new().write(true).create_new(true).open(target)
+            .map_err(|e| format_err!("failed to create realm archive {}: {}", target.display(), e))?;
+        info!("Exporting realm {} to {} ({} files)", realm.name(), target.display(), manifest.files().len());
+        if let Err(e) = manifest::write_archive(io::BufWriter::new(file), &base, &manifest, &signed) {
+            let _ = fs::remove_file(target);
+            return Err(e);
+        }
+        Ok(manifest)
+    }
+
+    /// Create a new realm from the realm export archive at `path`. The archive
+    /// manifest must be signed with the key trusted by `trust` unless
+    /// `allow_unsigned` is set.
+    pub fn import_realm(&self, path: &Path, trust: &ManifestTrust, allow_unsigned: bool) -> Result<Realm> {
+        let name = Realms::import(path, trust, allow_unsigned)?;
+        let realm = self.inner_mut().realms.add_created_realm(&name);
+        info!("Imported realm {} from {}", realm.name(), path.display());
+        self.inner().events.send_event(RealmEvent::New(realm.clone()));
+        Ok(realm)
+    }
+
+    /// Save a snapshot of the home directory of `realm` labeled with `label`.
+    /// If the realm is running the snapshot is a best-effort copy.
+    pub fn snapshot_home(&self, realm: &Realm, label: &str) -> Result<RealmSnapshot> {
+        self.snapshot_home_with_progress(realm, label, &mut |_| {})
+    }
+
+    /// Like `snapshot_home()` but `progress` is called with the number of files
+    /// copied so far.
+    pub fn snapshot_home_with_progress(&self, realm: &Realm, label: &str, progress: &mut dyn FnMut(usize)) -> Result<RealmSnapshot> {
+        HomeSnapshots::new(realm).create(label, progress)
+    }
+
+    /// Move the plaintext home directory of the stopped realm `realm` into an
+    /// encrypted container and enable `encrypted-home` in the realm config. The
+    /// plaintext directory is kept and its path is returned so that it can be
+    /// removed once the encrypted home has been checked.
+    pub fn encrypt_home(&self, realm: &Realm) -> Result<PathBuf> {
+        if realm.is_active() {
+            bail!("Cannot encrypt home directory while realm {} is running", realm.name());
+        }
+        let plaintext = EncryptedHome::new(realm).migrate()?;
+        realm.with_mut_config(|c| {
+            c.encrypted_home = Some(true);
+            c.write()
+        })?;
+        Ok(plaintext)
+    }
+
+    /// List the snapshots of the home directory of `realm` from oldest to newest.
+    pub fn list_snapshots(&self, realm: &Realm) -> Result<Vec<RealmSnapshot>> {
+        HomeSnapshots::new(realm).list()
+    }
+
+    /// Replace the home directory of the stopped realm `realm` with the snapshot `id`.
+    /// The current home directory is saved as an 'undo' snapshot which is returned.
+    pub fn restore_snapshot(&self, realm: &Realm, id: &str) -> Result<RealmSnapshot> {
+        self.restore_snapshot_with_progress(realm, id, &mut |_| {})
+    }
+
+    /// Like `restore_snapshot()` but `progress` is called with the number of files
+    /// copied so far. Starting the realm is refused until the restore completes.
+    pub fn restore_snapshot_with_progress(&self, realm: &Realm, id: &str, progress: &mut dyn FnMut(usize)) -> Result<RealmSnapshot> {
+        // Marked before checking that the realm is stopped so that the realm
+        // cannot be started between the check and copying the snapshot
+        realm.set_restoring(true);
+        let result = if realm.is_active() {
+            Err(format_err!("Cannot restore snapshot while realm {} is running", realm.name()))
+        } else {
+            HomeSnapshots::new(realm).restore(id, progress)
+        };
+        realm.set_restoring(false);
+        result
     }
 
     pub fn delete_realm(&self, realm: &Realm, save_home: bool) -> Result<()> {
@@ -397,3 +1045,13 @@ impl RealmManager {
         Ok(())
     }
 }
+
+#[test]
+fn test_realm_name_from_cgroup() {
+    let parse = RealmManager::realm_name_from_cgroup;
+    assert_eq!(parse("0::/system.slice/realm-main.service/payload/system.slice/dbus.service\n"), Some("main".to_string()));
+    assert_eq!(parse("12:pids:/system.slice/realm-work.service\n1:name=systemd:/system.slice/realm-work.service/payload\n"), Some("work".to_string()));
+    assert_eq!(parse("0::/user.slice/user-1000.slice/session-2.scope\n"), None);
+    assert_eq!(parse("0::/system.slice/realm-.service\n"), None);
+    assert_eq!(parse(""), None);
+}
@@ -0,0 +1,532 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Component, Path};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sodiumoxide::crypto::hash::sha256;
+use walkdir::WalkDir;
+
+use crate::{KeyPair, KeyRing, PublicKey, Result};
+
+/// Name of the manifest member stored first in a realm export archive
+pub const MANIFEST_FILE: &str = "MANIFEST.json";
+
+const MANIFEST_VERSION: u32 = 1;
+
+// Larger manifest members are rejected before they are parsed
+const MAX_MANIFEST_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Size and sha256 of one file in a realm export archive
+#[derive(Serialize,Deserialize,Clone,Debug,PartialEq)]
+pub struct ManifestEntry {
+    path: String,
+    size: u64,
+    sha256: String,
+}
+
+impl ManifestEntry {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn sha256(&self) -> &str {
+        &self.sha256
+    }
+}
+
+///
+/// Lists every file in a realm export archive with its size and sha256, so
+/// that an archive restored on another machine can be checked as it is
+/// unpacked.
+///
+#[derive(Serialize,Deserialize,Clone,Debug,PartialEq)]
+pub struct ExportManifest {
+    version: u32,
+    realm: String,
+    // Seconds since the Unix epoch
+    created: u64,
+    #[serde(rename="total-size")]
+    total_size: u64,
+    files: Vec<ManifestEntry>,
+}
+
+impl ExportManifest {
+    pub fn new(realm: &str) -> Self {
+        let created = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        ExportManifest {
+            version: MANIFEST_VERSION,
+            realm: realm.to_string(),
+            created,
+            total_size: 0,
+            files: Vec::new(),
+        }
+    }
+
+    /// Create a manifest of every regular file below `base` with paths relative to `base`.
+    pub fn for_directory(realm: &str, base: &Path) -> Result<Self> {
+        let mut manifest = Self::new(realm);
+        manifest.add_directory(base, base)?;
+        Ok(manifest)
+    }
+
+    /// Add every regular file below `dir` with paths relative to `base`.
+    pub fn add_directory(&mut self, base: &Path, dir: &Path) -> Result<()> {
+        for entry in WalkDir::new(dir).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let relative = entry.path().strip_prefix(base)?;
+            let path = relative.to_str()
+                .ok_or_else(|| format_err!("path {} is not valid UTF-8", relative.display()))?;
+            self.add_file(path, File::open(entry.path())?)?;
+        }
+        Ok(())
+    }
+
+    /// Add the file `path` with the content read from `reader`.
+    pub fn add_file<R: Read>(&mut self, path: &str, mut reader: R) -> Result<()> {
+        check_member_path(path)?;
+        let mut state = sha256::State::new();
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut size = 0u64;
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            state.update(&buf[..n]);
+            size += n as u64;
+        }
+        self.total_size += size;
+        self.files.push(ManifestEntry {
+            path: path.to_string(),
+            size,
+            sha256: hex::encode(&state.finalize()[..]),
+        });
+        Ok(())
+    }
+
+    pub fn realm(&self) -> &str {
+        &self.realm
+    }
+
+    pub fn created(&self) -> u64 {
+        self.created
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.total_size
+    }
+
+    pub fn files(&self) -> &[ManifestEntry] {
+        &self.files
+    }
+
+    /// Sign the manifest with the signing key of `keyring`.
+    pub fn sign(&self, keyring: &KeyRing) -> Result<SignedManifest> {
+        let manifest = serde_json::to_string_pretty(self)?;
+        let (public_key, signature) = keyring.sign(manifest.as_bytes())?;
+        Ok(SignedManifest {
+            manifest,
+            public_key: Some(public_key.to_hex()),
+            signature: Some(hex::encode(signature.to_bytes())),
+        })
+    }
+
+    /// Sign the manifest with `keypair`, such as the signing key of the keyring
+    /// read from the kernel keyring.
+    pub fn sign_with(&self, keypair: &KeyPair) -> Result<SignedManifest> {
+        let manifest = serde_json::to_string_pretty(self)?;
+        let signature = keypair.sign(manifest.as_bytes());
+        Ok(SignedManifest {
+            manifest,
+            public_key: Some(keypair.public_key().to_hex()),
+            signature: Some(hex::encode(signature.to_bytes())),
+        })
+    }
+
+    pub fn unsigned(&self) -> Result<SignedManifest> {
+        let manifest = serde_json::to_string_pretty(self)?;
+        Ok(SignedManifest { manifest, public_key: None, signature: None })
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let manifest = serde_json::from_str::<Self>(content)?;
+        if manifest.version != MANIFEST_VERSION {
+            bail!("unsupported manifest version {}", manifest.version);
+        }
+        for file in &manifest.files {
+            check_member_path(&file.path)?;
+        }
+        Ok(manifest)
+    }
+}
+
+// Archive members are unpacked below the new realm directory, so a path
+// must not be able to name anything outside of it.
+fn check_member_path(path: &str) -> Result<()> {
+    let valid = !path.is_empty() && path != MANIFEST_FILE &&
+        Path::new(path).components().all(|c| matches!(c, Component::Normal(_)));
+    if !valid {
+        bail!("invalid archive member path '{}'", path);
+    }
+    Ok(())
+}
+
+/// Keys accepted for the signature of an imported manifest
+pub enum ManifestTrust<'a> {
+    /// Signed with the signing key of the local keyring
+    Keyring(&'a KeyRing),
+    /// Signed with a public key given by the user
+    PublicKey(&'a PublicKey),
+}
+
+///
+/// The manifest stored in a realm export archive. The manifest is kept as the
+/// exact text which was signed so that the signature can be checked without
+/// depending on how it is serialized.
+///
+#[derive(Serialize,Deserialize,Clone,Debug)]
+pub struct SignedManifest {
+    manifest: String,
+    #[serde(rename="public-key", skip_serializing_if = "Option::is_none")]
+    public_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+}
+
+impl SignedManifest {
+    pub fn from_json(json: &str) -> Result<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn is_signed(&self) -> bool {
+        self.signature.is_some()
+    }
+
+    /// Hex encoded public key the manifest claims to be signed with. It is only
+    /// shown to the user and never trusted for verification.
+    pub fn public_key(&self) -> Option<&str> {
+        self.public_key.as_deref()
+    }
+
+    /// Check the signature against `trust` and return the manifest. An unsigned
+    /// manifest is only accepted if `allow_unsigned` is set.
+    pub fn verify(&self, trust: &ManifestTrust, allow_unsigned: bool) -> Result<ExportManifest> {
+        match self.signature {
+            Some(ref signature) => {
+                let signature = hex::decode(signature)
+                    .map_err(|_| format_err!("manifest signature is not valid hex"))?;
+                let data = self.manifest.as_bytes();
+                let valid = match trust {
+                    ManifestTrust::Keyring(keyring) => keyring.verify(data, &signature),
+                    ManifestTrust::PublicKey(key) => key.verify(data, &signature),
+                };
+                if !valid {
+                    match trust {
+                        ManifestTrust::Keyring(_) => bail!("manifest is not signed with the signing key of the keyring"),
+                        ManifestTrust::PublicKey(key) => bail!("manifest is not signed with key {}", key.to_hex()),
+                    }
+                }
+            },
+            None if allow_unsigned => warn!("Accepting unsigned realm export manifest"),
+            None => bail!("manifest is not signed, import of unsigned archives must be explicitly allowed"),
+        }
+        ExportManifest::parse(&self.manifest)
+    }
+}
+
+///
+/// Checks the members of an archive against a verified manifest while they are
+/// unpacked. Each member is read through a `VerifyingReader` which fails as soon
+/// as the content differs from the manifest, and `finish()` fails if any file
+/// listed in the manifest was not in the archive.
+///
+pub struct ManifestVerifier {
+    expected: HashMap<String, ManifestEntry>,
+}
+
+impl ManifestVerifier {
+    pub fn new(manifest: &ExportManifest) -> Self {
+        let expected = manifest.files.iter()
+            .map(|entry| (entry.path.clone(), entry.clone()))
+            .collect();
+        ManifestVerifier { expected }
+    }
+
+    /// Return a reader for the content of archive member `path` which checks
+    /// the content as it is read.
+    pub fn member<R: Read>(&mut self, path: &str, reader: R) -> Result<VerifyingReader<R>> {
+        match self.expected.remove(path) {
+            Some(entry) => Ok(VerifyingReader::new(entry, reader)),
+            None => bail!("archive member {} is not listed in the manifest or appears twice", path),
+        }
+    }
+
+    pub fn finish(self) -> Result<()> {
+        let mut missing = self.expected.keys().cloned().collect::<Vec<_>>();
+        if !missing.is_empty() {
+            missing.sort();
+            bail!("archive is missing files listed in the manifest: {}", missing.join(", "));
+        }
+        Ok(())
+    }
+}
+
+/// Reader returned by `ManifestVerifier::member()`. A read fails with
+/// `ErrorKind::InvalidData` once more data than the size in the manifest has been
+/// read, or at the end of the member if the size or sha256 does not match.
+pub struct VerifyingReader<R: Read> {
+    entry: ManifestEntry,
+    inner: R,
+    state: Option<sha256::State>,
+    count: u64,
+}
+
+impl <R: Read> VerifyingReader<R> {
+    fn new(entry: ManifestEntry, inner: R) -> Self {
+        VerifyingReader { entry, inner, state: Some(sha256::State::new()), count: 0 }
+    }
+
+    fn mismatch(&self, what: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData,
+                       format!("{} of archive member {} does not match the manifest", what, self.entry.path))
+    }
+}
+
+impl <R: Read> Read for VerifyingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        let state = match self.state.as_mut() {
+            Some(state) => state,
+            None => return Ok(n),
+        };
+        if n > 0 {
+            self.count += n as u64;
+            if self.count > self.entry.size {
+                return Err(self.mismatch("size"));
+            }
+            state.update(&buf[..n]);
+            return Ok(n);
+        }
+        let digest = self.state.take().map(|state| hex::encode(&state.finalize()[..]));
+        if self.count != self.entry.size {
+            return Err(self.mismatch("size"));
+        }
+        if digest.as_deref() != Some(self.entry.sha256.as_str()) {
+            return Err(self.mismatch("sha256"));
+        }
+        Ok(0)
+    }
+}
+
+///
+/// Write a realm export archive to `writer`. The archive is a tar archive with
+/// `signed` stored as the first member followed by each file in `manifest`,
+/// read from below `base`.
+///
+pub fn write_archive<W: Write>(writer: W, base: &Path, manifest: &ExportManifest, signed: &SignedManifest) -> Result<()> {
+    let mut builder = tar::Builder::new(writer);
+    let json = signed.to_json()?;
+    append_member(&mut builder, MANIFEST_FILE, 0o644, json.len() as u64, json.as_bytes())?;
+    for entry in &manifest.files {
+        let path = base.join(&entry.path);
+        let file = File::open(&path)
+            .map_err(|e| format_err!("failed to open {}: {}", path.display(), e))?;
+        let mode = file.metadata()?.permissions().mode();
+        append_member(&mut builder, &entry.path, mode, entry.size, file.take(entry.size))?;
+    }
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
+
+fn append_member<W: Write, R: Read>(builder: &mut tar::Builder<W>, path: &str, mode: u32, size: u64, data: R) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(size);
+    header.set_mode(mode & 0o777);
+    builder.append_data(&mut header, path, data)
+        .map_err(|e| format_err!("failed to add {} to archive: {}", path, e))?;
+    Ok(())
+}
+
+///
+/// Unpack a realm export archive read from `reader` into the directory `target`,
+/// which must not exist yet. The manifest is verified with `trust` before
+/// anything is unpacked and each file is checked against the manifest while it
+/// is written, so unpacking stops at the first file which does not match. On
+/// failure `target` may contain a partially unpacked archive.
+///
+pub fn unpack_archive<R: Read>(reader: R, target: &Path, trust: &ManifestTrust, allow_unsigned: bool) -> Result<ExportManifest> {
+    let mut archive = tar::Archive::new(reader);
+    let mut entries = archive.entries()?;
+    let manifest = match entries.next() {
+        Some(entry) => {
+            let entry = entry?;
+            if entry.path()?.to_str() != Some(MANIFEST_FILE) {
+                bail!("first archive member is not {}", MANIFEST_FILE);
+            }
+            let mut json = String::new();
+            entry.take(MAX_MANIFEST_SIZE).read_to_string(&mut json)?;
+            SignedManifest::from_json(&json)?.verify(trust, allow_unsigned)?
+        },
+        None => bail!("archive is empty"),
+    };
+
+    fs::create_dir(target)
+        .map_err(|e| format_err!("failed to create directory {}: {}", target.display(), e))?;
+    let mut verifier = ManifestVerifier::new(&manifest);
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path()?.to_str()
+            .ok_or_else(|| format_err!("archive member path is not valid UTF-8"))?
+            .to_string();
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            bail!("archive member {} is not a regular file", path);
+        }
+        let mode = entry.header().mode()? & 0o777;
+        // Only paths listed in the verified manifest are accepted
+        let mut reader = verifier.member(&path, entry)?;
+        let dest = target.join(&path);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new().write(true).create_new(true).mode(mode).open(&dest)
+            .map_err(|e| format_err!("failed to create {}: {}", dest.display(), e))?;
+        io::copy(&mut reader, &mut file)?;
+    }
+    verifier.finish()?;
+    Ok(manifest)
+}
+
+#[cfg(test)]
+fn unpack_members(manifest: &ExportManifest, members: &[(&str, &[u8])], unpacked: &mut Vec<String>) -> Result<()> {
+    let mut verifier = ManifestVerifier::new(manifest);
+    for (path, data) in members {
+        let mut reader = verifier.member(path, *data)?;
+        io::copy(&mut reader, &mut io::sink())?;
+        unpacked.push(path.to_string());
+    }
+    verifier.finish()
+}
+
+#[test]
+fn test_export_manifest() {
+    let members: &[(&str, &[u8])] = &[
+        ("config", b"realmfs = \"base\"\n"),
+        ("home/.bashrc", b"export PS1='$ '\n"),
+        ("home/notes.txt", b"remember the milk\n"),
+    ];
+    let mut manifest = ExportManifest::new("main");
+    for (path, data) in members {
+        manifest.add_file(path, *data).unwrap();
+    }
+    assert_eq!(manifest.total_size(), members.iter().map(|(_, d)| d.len() as u64).sum::<u64>());
+    assert!(manifest.add_file("../etc/passwd", &b""[..]).is_err());
+    assert!(manifest.add_file(MANIFEST_FILE, &b""[..]).is_err());
+
+    let keyring = KeyRing::create_new();
+    let signed = manifest.sign(&keyring).unwrap();
+    let signed = SignedManifest::from_json(&signed.to_json().unwrap()).unwrap();
+    assert_eq!(signed.verify(&ManifestTrust::Keyring(&keyring), false).unwrap(), manifest);
+    let (public_key, _) = keyring.sign(b"").unwrap();
+    assert_eq!(signed.public_key(), Some(public_key.to_hex().as_str()));
+    assert!(signed.verify(&ManifestTrust::PublicKey(&public_key), false).is_ok());
+
+    let mut unpacked = Vec::new();
+    unpack_members(&manifest, members, &mut unpacked).unwrap();
+    assert_eq!(unpacked.len(), 3);
+
+    // A tampered member fails while it is unpacked and nothing after it is unpacked
+    let tampered: &[(&str, &[u8])] = &[members[0], ("home/.bashrc", b"export PS1='# '\n"), members[2]];
+    let mut unpacked = Vec::new();
+    let err = unpack_members(&manifest, tampered, &mut unpacked).unwrap_err();
+    assert_eq!(err.to_string(), "sha256 of archive member home/.bashrc does not match the manifest");
+    assert_eq!(unpacked, vec!["config"]);
+    let longer: &[(&str, &[u8])] = &[("config", b"realmfs = \"base\"\nextra = true\n")];
+    let err = unpack_members(&manifest, longer, &mut Vec::new()).unwrap_err();
+    assert_eq!(err.to_string(), "size of archive member config does not match the manifest");
+    let err = unpack_members(&manifest, &members[..2], &mut Vec::new()).unwrap_err();
+    assert_eq!(err.to_string(), "archive is missing files listed in the manifest: home/notes.txt");
+    assert!(unpack_members(&manifest, &[members[0], members[0]], &mut Vec::new()).is_err());
+
+    // A tampered manifest or a signature made with another key is rejected
+    let mut modified = signed.clone();
+    modified.manifest = modified.manifest.replace("\"main\"", "\"work\"");
+    assert!(modified.verify(&ManifestTrust::Keyring(&keyring), false).is_err());
+    let other = KeyRing::create_new();
+    let err = signed.verify(&ManifestTrust::Keyring(&other), false).unwrap_err();
+    assert_eq!(err.to_string(), "manifest is not signed with the signing key of the keyring");
+    let (other_key, _) = other.sign(b"").unwrap();
+    assert!(signed.verify(&ManifestTrust::PublicKey(&other_key), false).is_err());
+    let resigned = manifest.sign(&other).unwrap();
+    assert!(resigned.verify(&ManifestTrust::PublicKey(&public_key), true).is_err());
+
+    // Unsigned manifests are only accepted when allowed
+    let unsigned = manifest.unsigned().unwrap();
+    assert!(!unsigned.is_signed());
+    assert!(unsigned.verify(&ManifestTrust::Keyring(&keyring), false).is_err());
+    assert_eq!(unsigned.verify(&ManifestTrust::Keyring(&keyring), true).unwrap(), manifest);
+}
+
+#[test]
+fn test_realm_archive() {
+    let dir = crate::util::TempDir::new("realm-archive").unwrap();
+    let base = dir.join("main");
+    fs::create_dir_all(base.join("home/.config")).unwrap();
+    fs::write(base.join("config"), "realmfs = \"base\"\n").unwrap();
+    fs::write(base.join("home/.config/settings"), "theme = dark\n").unwrap();
+    fs::write(base.join("home/notes.txt"), "remember the milk\n").unwrap();
+    fs::set_permissions(base.join("home/notes.txt"), fs::Permissions::from_mode(0o600)).unwrap();
+
+    let keypair = KeyPair::generate();
+    let manifest = ExportManifest::for_directory("main", &base).unwrap();
+    let signed = manifest.sign_with(&keypair).unwrap();
+    let mut archive = Vec::new();
+    write_archive(&mut archive, &base, &manifest, &signed).unwrap();
+
+    let public_key = keypair.public_key();
+    let trust = ManifestTrust::PublicKey(&public_key);
+    let target = dir.join("imported");
+    assert_eq!(unpack_archive(&archive[..], &target, &trust, false).unwrap(), manifest);
+    assert_eq!(fs::read_to_string(target.join("home/notes.txt")).unwrap(), "remember the milk\n");
+    assert_eq!(fs::read_to_string(target.join("home/.config/settings")).unwrap(), "theme = dark\n");
+    let mode = fs::metadata(target.join("home/notes.txt")).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    // A tampered member is rejected while it is unpacked
+    let mut tampered = archive.clone();
+    let offset = tampered.windows(8).position(|w| w == b"remember").unwrap();
+    tampered[offset] = b'R';
+    let target = dir.join("tampered");
+    let err = unpack_archive(&tampered[..], &target, &trust, false).unwrap_err();
+    assert_eq!(err.to_string(), "sha256 of archive member home/notes.txt does not match the manifest");
+
+    // A signature made with another key is rejected before anything is unpacked
+    let other = KeyPair::generate().public_key();
+    let target = dir.join("wrong-key");
+    assert!(unpack_archive(&archive[..], &target, &ManifestTrust::PublicKey(&other), true).is_err());
+    assert!(!target.exists());
+
+    // Unsigned archives are only unpacked when allowed
+    let mut unsigned = Vec::new();
+    write_archive(&mut unsigned, &base, &manifest, &manifest.unsigned().unwrap()).unwrap();
+    let target = dir.join("unsigned");
+    assert!(unpack_archive(&unsigned[..], &target, &trust, false).is_err());
+    assert!(unpack_archive(&unsigned[..], &target, &trust, true).is_ok());
+}
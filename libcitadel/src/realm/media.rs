@@ -0,0 +1,230 @@
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path,PathBuf};
+
+use crate::{Result,util};
+use crate::realm::block::BlockDevices;
+
+const SYS_CLASS_BLOCK: &str = "/sys/class/block";
+const DISK_BY_LABEL: &str = "/dev/disk/by-label";
+
+/// Directory on the host below which removable media are mounted
+pub const HOST_MEDIA_PATH: &str = "/run/citadel/media";
+
+/// Directory inside a realm below which removable media are bound
+pub const REALM_MEDIA_PATH: &str = "/run/media";
+
+const MEDIA_MOUNT_OPTIONS: &str = "-o nosuid,nodev,noexec";
+
+///
+/// A filesystem on a removable block device such as a USB stick or an SD card.
+///
+/// Partitions of removable disks are listed, and removable disks which have no
+/// partition table are listed as a single filesystem. Devices which back the
+/// host rootfs or /storage are never listed, so a Citadel system booted from a
+/// USB stick does not offer its own boot device.
+///
+#[derive(Debug,Clone,PartialEq)]
+pub struct RemovableMedia {
+    name: String,
+    label: Option<String>,
+}
+
+impl RemovableMedia {
+
+    /// Return all filesystems on removable block devices currently attached to the system
+    pub fn scan() -> Vec<RemovableMedia> {
+        let media = Self::scan_path(Path::new(SYS_CLASS_BLOCK), Path::new(DISK_BY_LABEL));
+        match BlockDevices::load() {
+            Ok(block_devices) => media.into_iter()
+                .filter(|m| !block_devices.is_protected(&m.name))
+                .collect(),
+            Err(e) => {
+                warn!("Not listing removable media because protected block devices could not be determined: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    fn scan_path(sys: &Path, by_label: &Path) -> Vec<RemovableMedia> {
+        let entries = match fs::read_dir(sys) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Could not read block devices from {}: {}", sys.display(), e);
+                return Vec::new();
+            }
+        };
+        let labels = Self::read_labels(by_label);
+        let mut media = entries.flat_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .filter(|name| Self::is_removable_filesystem(&sys.join(name)))
+            .map(|name| {
+                let label = labels.iter().find(|(n,_)| *n == name).map(|(_,label)| label.clone());
+                RemovableMedia { name, label }
+            })
+            .collect::<Vec<_>>();
+        media.sort_by(|a,b| a.name.cmp(&b.name));
+        media
+    }
+
+    fn is_removable_filesystem(dir: &Path) -> bool {
+        let read = |dir: &Path, name: &str| fs::read_to_string(dir.join(name)).ok().map(|s| s.trim().to_string());
+        if read(dir, "size").as_deref() == Some("0") {
+            return false;
+        }
+        let dir = match dir.canonicalize() {
+            Ok(dir) => dir,
+            Err(_) => return false,
+        };
+        if dir.join("partition").exists() {
+            return dir.parent().and_then(|disk| read(disk, "removable")).as_deref() == Some("1");
+        }
+        let has_partitions = fs::read_dir(&dir)
+            .map(|entries| entries.flat_map(|e| e.ok()).any(|e| e.path().join("partition").exists()))
+            .unwrap_or(false);
+        !has_partitions && read(&dir, "removable").as_deref() == Some("1")
+    }
+
+    // Pairs of (kernel name, label) from the symlinks in /dev/disk/by-label
+    fn read_labels(by_label: &Path) -> Vec<(String, String)> {
+        let entries = match fs::read_dir(by_label) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        entries.flat_map(|e| e.ok())
+            .flat_map(|e| {
+                let node = e.path().canonicalize().ok()?;
+                let name = node.file_name()?.to_string_lossy().to_string();
+                Some((name, decode_label(&e.file_name().to_string_lossy())))
+            })
+            .collect()
+    }
+
+    /// Kernel name of the device such as `sdb1` or `mmcblk0p1`
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Filesystem label if the filesystem has one
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// Path of device node such as /dev/sdb1
+    pub fn dev_path(&self) -> PathBuf {
+        Path::new("/dev").join(&self.name)
+    }
+
+    /// Name of the directory the filesystem is mounted on. This is the label
+    /// with any characters which are not safe in a path component replaced,
+    /// or the kernel name of the device if there is no usable label.
+    pub fn mount_name(&self) -> String {
+        self.label.as_ref()
+            .and_then(|label| sanitize_label(label))
+            .unwrap_or_else(|| self.name.clone())
+    }
+
+    /// Mount the filesystem on the host at `target` with options nosuid,nodev,noexec
+    pub fn mount(&self, target: &Path) -> Result<()> {
+        fs::create_dir_all(target)?;
+        if let Err(e) = util::mount(self.dev_path().to_string_lossy(), target, Some(MEDIA_MOUNT_OPTIONS)) {
+            let _ = fs::remove_dir(target);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Unmount a filesystem mounted with `mount()` and remove the mount directory.
+    /// If the filesystem is busy because files are still open it is detached
+    /// immediately and cleaned up by the kernel once it is no longer in use.
+    pub fn unmount(target: &Path) -> Result<()> {
+        if let Err(e) = util::umount(target) {
+            info!("Lazily unmounting {} after unmount failed: {}", target.display(), e);
+            umount_detach(target)
+                .map_err(|e| format_err!("failed to detach {}: {}", target.display(), e))?;
+        }
+        fs::remove_dir(target)?;
+        Ok(())
+    }
+}
+
+fn umount_detach(path: &Path) -> io::Result<()> {
+    let cstr = CString::new(path.as_os_str().as_bytes())?;
+    unsafe {
+        if libc::umount2(cstr.as_ptr(), libc::MNT_DETACH) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// udev escapes characters in /dev/disk/by-label names as \xNN
+fn decode_label(name: &str) -> String {
+    let mut bytes = Vec::new();
+    let mut rest = name.as_bytes();
+    while !rest.is_empty() {
+        if rest.len() >= 4 && rest.starts_with(b"\\x") {
+            if let Ok(b) = u8::from_str_radix(&String::from_utf8_lossy(&rest[2..4]), 16) {
+                bytes.push(b);
+                rest = &rest[4..];
+                continue;
+            }
+        }
+        bytes.push(rest[0]);
+        rest = &rest[1..];
+    }
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+fn sanitize_label(label: &str) -> Option<String> {
+    let name = label.trim().chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "-_.".contains(c) { c } else { '_' })
+        .collect::<String>();
+    let name = name.trim_start_matches('.');
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.to_string())
+    }
+}
+
+#[test]
+fn test_removable_media() {
+    assert_eq!(decode_label("USB\\x20DISK"), "USB DISK");
+    assert_eq!(decode_label("a\\x2fb\\xzz"), "a/b\\xzz");
+    assert_eq!(sanitize_label("USB DISK"), Some("USB_DISK".to_string()));
+    assert_eq!(sanitize_label("../etc"), Some("_etc".to_string()));
+    assert_eq!(sanitize_label(".."), None);
+
+    // sdb is a removable disk with partition sdb1, mmcblk0 is a removable
+    // disk without partitions, sda is not removable.
+    let base = crate::util::TempDir::new("media-test").unwrap();
+    let devices = base.join("devices");
+    let sys = base.join("class");
+    let by_label = base.join("by-label");
+    let dev = base.join("dev");
+    for dir in &[devices.join("sdb/sdb1"), devices.join("sda/sda1"), devices.join("mmcblk0"), sys.clone(), by_label.clone(), dev.clone()] {
+        fs::create_dir_all(dir).unwrap();
+    }
+    for (file, value) in &[("sdb/removable", "1"), ("sdb/sdb1/partition", "1"), ("sda/removable", "0"),
+                           ("sda/sda1/partition", "1"), ("mmcblk0/removable", "1")] {
+        fs::write(devices.join(file), format!("{}\n", value)).unwrap();
+    }
+    for name in &["sdb", "sdb1", "sda", "sda1", "mmcblk0"] {
+        let path = if name.len() == 4 { devices.join(&name[..3]).join(name) } else { devices.join(name) };
+        std::os::unix::fs::symlink(path, sys.join(name)).unwrap();
+        fs::write(dev.join(name), "").unwrap();
+    }
+    std::os::unix::fs::symlink(dev.join("sdb1"), by_label.join("USB\\x20DISK")).unwrap();
+
+    let media = RemovableMedia::scan_path(&sys, &by_label);
+    assert_eq!(media, vec![
+        RemovableMedia { name: "mmcblk0".to_string(), label: None },
+        RemovableMedia { name: "sdb1".to_string(), label: Some("USB DISK".to_string()) },
+    ]);
+    assert_eq!(media[0].mount_name(), "mmcblk0");
+    assert_eq!(media[1].mount_name(), "USB_DISK");
+    assert_eq!(media[1].dev_path(), Path::new("/dev/sdb1"));
+}
@@ -7,9 +7,23 @@ pub(crate) mod manager;
 pub(crate) mod realm;
 pub (crate) mod network;
 pub(crate) mod create;
+pub(crate) mod defaults;
+pub(crate) mod snapshot;
+pub(crate) mod home;
+pub(crate) mod quota;
+pub(crate) mod manifest;
+pub(crate) mod schema;
 pub(crate) mod events;
-mod systemd;
-mod launcher;
+pub(crate) mod systemd;
+pub(crate) mod usb;
+pub(crate) mod media;
+pub(crate) mod startup;
+pub(crate) mod resources;
+mod block;
+mod rootfs;
+mod dbus_proxy;
+pub(crate) mod launcher;
+mod security;
 
 pub(crate) use self::network::BridgeAllocator;
 
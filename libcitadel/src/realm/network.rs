@@ -1,240 +1,2165 @@
-use std::path::{Path,PathBuf};
-use std::net::Ipv4Addr;
-use std::collections::{HashSet,HashMap};
-use std::io::{BufReader,BufRead,Write};
-use std::fs::{self,File};
+use std::path::Path;
+use std::net::{IpAddr,Ipv4Addr,Ipv6Addr};
+use std::collections::{BTreeMap,HashSet,HashMap};
+use std::fs;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash,Hasher};
+use std::process::{Command,Stdio};
+use std::thread;
+use std::time::{Duration,Instant};
 
-use crate::Result;
+use crate::{Metrics,Realm,RealmConfig,Result,util};
 
-const REALMS_RUN_PATH: &str = "/run/citadel/realms";
+/// Address allocations of all zones, preserved across restarts of realmsd
+const NETWORK_ALLOCATIONS_PATH: &str = "/run/citadel/network-allocations.json";
+const ALLOCATIONS_FILE_VERSION: u32 = 1;
 
 const CLEAR_BRIDGE_NETWORK: &str = "172.17.0.0/24";
 
 const MIN_MASK: usize = 16;
 const MAX_MASK: usize = 24;
-const RESERVED_START: u8 = 200;
+const RESERVED_START: u32 = 200;
+const RESERVED_END: u32 = 254;
+const DEFAULT_GATEWAY_OFFSET: u32 = 1;
+
+/// Network zone definitions. If this file does not exist only the `clear` zone is defined.
+const NETWORK_ZONES_PATH: &str = "/etc/citadel/network-zones.conf";
+
+const IP_PATH: &str = "/usr/sbin/ip";
+const PING_PATH: &str = "/usr/bin/ping";
+
+/// Interval between checks that a realm network address is reachable
+const NETWORK_POLL_INTERVAL: Duration = Duration::from_millis(250);
+const NFT_PATH: &str = "/usr/sbin/nft";
+
+/// nftables table (in the `inet` family) of the baseline firewall installed by `Firewall`
+const FIREWALL_TABLE: &str = "citadel";
+/// File the generated firewall ruleset is written to before it is loaded with `nft -f`
+const FIREWALL_RULES_PATH: &str = "/run/citadel/firewall.nft";
+/// Directory the kill switch rules of each realm are written to before they are loaded with `nft -f`
+const KILL_SWITCH_RULES_DIR: &str = "/run/citadel/kill-switch";
+const NETNS_RUN_PATH: &str = "/run/netns";
+const MANAGED_NETNS_PREFIX: &str = "citadel-";
 
 /// Manage ip address assignment for bridges
 pub struct NetworkConfig {
     allocators: HashMap<String, BridgeAllocator>,
+    zones: Vec<NetworkZone>,
+}
+
+impl NetworkConfig {
+    pub fn new() -> NetworkConfig {
+        NetworkConfig {
+            allocators: HashMap::new(),
+            zones: Vec::new(),
+        }
+    }
+
+    /// Create a configuration with the zones defined in /etc/citadel/network-zones.conf,
+    /// or the built-in `clear` zone if the file does not exist.
+    ///
+    /// Address allocations are restored from /run/citadel/network-allocations.json,
+    /// dropping any which are not valid for the zone or which belong to realms
+    /// that no longer exist.
+    pub fn load() -> Result<NetworkConfig> {
+        let mut network = NetworkConfig::new();
+        for zone in NetworkZone::load_all()? {
+            network.add_zone(&zone);
+        }
+        network.load_allocations()?;
+        Ok(network)
+    }
+
+    // Configuration with the built-in zones and no allocations for tests which
+    // must not depend on the files of the host
+    #[cfg(test)]
+    pub(crate) fn with_builtin_zones() -> Result<NetworkConfig> {
+        let mut network = NetworkConfig::new();
+        for zone in NetworkZone::builtin()? {
+            network.add_zone(&zone);
+        }
+        Ok(network)
+    }
+
+    fn add_zone(&mut self, zone: &NetworkZone) {
+        self.allocators.insert(zone.name.clone(), BridgeAllocator::with_zone(zone));
+        self.zones.push(zone.clone());
+    }
+
+    /// Add zones which have been defined in /etc/citadel/network-zones.conf
+    /// since the configuration was loaded and install the firewall again so
+    /// that it covers them. Zones which were already loaded are not changed.
+    /// Returns the names of the added zones.
+    pub fn load_new_zones(&mut self) -> Result<Vec<String>> {
+        let added = NetworkZone::load_all()?.into_iter()
+            .filter(|zone| !self.has_zone(&zone.name))
+            .collect::<Vec<_>>();
+        if added.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut zones = self.zones.clone();
+        zones.extend(added.iter().cloned());
+        NetworkZone::check_overlaps(&zones)?;
+        for zone in &added {
+            self.add_zone(zone);
+        }
+        self.install_firewall()?;
+        Ok(added.into_iter().map(|zone| zone.name).collect())
+    }
+
+    /// Install the baseline firewall for all loaded zones
+    pub fn install_firewall(&self) -> Result<()> {
+        Firewall::install(&self.zones)
+    }
+
+    fn load_allocations(&mut self) -> Result<()> {
+        let file = AllocationsFile::load();
+        let realm_exists = |name: &str| Realm::new(name).base_path().exists();
+        let mut dropped = file.allocations.iter().any(|entry| !self.has_zone(&entry.zone));
+        for allocator in self.allocators.values_mut() {
+            dropped |= allocator.load_allocations(&file, realm_exists);
+        }
+        if dropped {
+            let mut file = AllocationsFile::new();
+            for allocator in self.allocators.values() {
+                file.update_zone(allocator);
+            }
+            file.write()?;
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if a network zone named `zone` is defined
+    pub fn has_zone(&self, zone: &str) -> bool {
+        self.allocators.contains_key(zone)
+    }
+
+    /// Names of all defined network zones in sorted order
+    pub fn zone_names(&self) -> Vec<String> {
+        let mut names = self.allocators.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// IPv4 subnet of `zone` in the form `172.17.0.0/24`
+    pub fn subnet(&self, zone: &str) -> Result<String> {
+        Ok(self.allocator(zone)?.subnet())
+    }
+
+    /// Return the number of IPv4 addresses allocated in `zone` and the total
+    /// number of addresses which can be allocated, including reserved addresses.
+    pub fn zone_utilization(&self, zone: &str) -> Result<(u32, u32)> {
+        Ok(self.allocator(zone)?.utilization())
+    }
+
+    /// Name of the host bridge interface realms in `zone` are attached to
+    pub fn bridge_for(&self, zone: &str) -> Result<String> {
+        Ok(self.allocator(zone)?.interface.clone())
+    }
+
+    fn allocator(&self, zone: &str) -> Result<&BridgeAllocator> {
+        match self.allocators.get(zone) {
+            Some(allocator) => Ok(allocator),
+            None => bail!("network zone '{}' is not defined in {}", zone, NETWORK_ZONES_PATH),
+        }
+    }
+
+    fn allocator_mut(&mut self, zone: &str) -> Result<&mut BridgeAllocator> {
+        match self.allocators.get_mut(zone) {
+            Some(allocator) => Ok(allocator),
+            None => bail!("network zone '{}' is not defined in {}", zone, NETWORK_ZONES_PATH),
+        }
+    }
+
+    pub fn gateway(&self, bridge: &str) -> Result<String> {
+        Ok(self.allocator(bridge)?.gateway())
+    }
+
+    pub fn allocate_address_for(&mut self, bridge: &str, realm_name: &str) -> Result<String> {
+        self.allocator_mut(bridge)?.allocate_address_for(realm_name)
+    }
+
+    pub fn free_allocation_for(&mut self, bridge: &str, realm_name: &str) -> Result<()> {
+        self.allocator_mut(bridge)?.free_allocation_for(realm_name)
+    }
+
+    pub fn allocate_reserved(&mut self, bridge: &str, realm_name: &str, offset: u32) -> Result<String> {
+        self.allocator_mut(bridge)?.allocate_reserved(realm_name, offset)
+    }
+
+    /// Record `address` as allocated to `realm_name` on `bridge`, for example to restore
+    /// the allocation of a realm which is already running with that address.
+    pub fn restore_allocation(&mut self, bridge: &str, realm_name: &str, address: Ipv4Addr) -> Result<()> {
+        let allocator = self.allocator_mut(bridge)?;
+        allocator.restore_allocation(realm_name, address)?;
+        allocator.write_state()
+    }
+
+    /// Allocate an address for `realm_name` on `bridge`, either the reserved
+    /// address with host offset `reserved` or the next free address.
+    pub fn allocate_for_realm(&mut self, bridge: &str, realm_name: &str, reserved: Option<u32>) -> Result<String> {
+        let result = match reserved {
+            Some(offset) => self.allocate_reserved(bridge, realm_name, offset),
+            None => self.allocate_address_for(bridge, realm_name),
+        };
+        if result.is_err() {
+            Metrics::network_allocation_failed(bridge);
+        }
+        result
+    }
+
+    /// Return the address `allocate_for_realm()` would allocate for `realm_name`
+    /// on `bridge` without recording an allocation.
+    pub fn preview_allocation_for(&self, bridge: &str, realm_name: &str, reserved: Option<u32>) -> Result<String> {
+        self.allocator(bridge)?.preview_address_for(realm_name, reserved)
+    }
+
+    /// Return the IPv6 address `allocate_ipv6_for()` would allocate for `realm_name`
+    /// on `bridge` without recording an allocation.
+    pub fn preview_ipv6_for(&self, bridge: &str, realm_name: &str, reserved: Option<u32>) -> Result<Option<String>> {
+        let allocator = self.allocator(bridge)?;
+        Ok(allocator.ipv6_address_for(realm_name, reserved)?
+            .map(|addr| format!("{}/64", addr)))
+    }
+
+    /// Allocate an IPv6 address for `realm_name` on `bridge` if IPv6 is enabled
+    /// for the bridge, otherwise return `None`.
+    pub fn allocate_ipv6_for(&mut self, bridge: &str, realm_name: &str, reserved: Option<u32>) -> Result<Option<String>> {
+        self.allocator_mut(bridge)?.allocate_ipv6_for(realm_name, reserved)
+    }
+
+    /// Return IPv6 gateway address for `bridge` if IPv6 is enabled for the bridge.
+    pub fn gateway6(&self, bridge: &str) -> Option<String> {
+        self.allocators.get(bridge).and_then(|allocator| allocator.gateway6())
+    }
+
+    /// Return the realm name and address of each allocation on the bridge for `zone`
+    pub fn allocations(&self, zone: &str) -> Vec<(String, IpAddr)> {
+        self.allocators.get(zone)
+            .map(|allocator| allocator.allocations())
+            .unwrap_or_default()
+    }
+
+    /// Free the allocations on all bridges of each realm for which `keep` returns
+    /// `false` and return the names of those realms.
+    pub fn retain_allocations<F: FnMut(&str) -> bool>(&mut self, mut keep: F) -> Result<Vec<String>> {
+        let mut freed = Vec::new();
+        for allocator in self.allocators.values_mut() {
+            let removed = allocator.retain(&mut keep);
+            if !removed.is_empty() {
+                allocator.write_state()?;
+                freed.extend(removed);
+            }
+        }
+        freed.sort();
+        freed.dedup();
+        Ok(freed)
+    }
+
+    /// Wait until `address` answers a ping from the host, returning `false` if
+    /// it does not respond within `timeout`.
+    pub fn wait_for_address(address: Ipv4Addr, timeout: Duration) -> bool {
+        poll_until(timeout, NETWORK_POLL_INTERVAL, || {
+            Command::new(PING_PATH)
+                .args(["-c", "1", "-W", "1", "-q", &address.to_string()])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .map(|status| status.success())
+                .unwrap_or(false)
+        })
+    }
+
+    /// Return a stable, locally administered unicast MAC address for the interface
+    /// of `realm_name` in `zone`. The address is saved with the allocations so that
+    /// an address which was changed to avoid a collision is kept by the realm.
+    pub fn mac_for(&mut self, zone: &str, realm_name: &str) -> Result<String> {
+        let allocator = self.allocator_mut(zone)?;
+        let mac = allocator.mac_for(realm_name);
+        allocator.write_state()?;
+        Ok(mac)
+    }
+
+    /// Return the MAC address `mac_for()` would return without recording it
+    pub fn preview_mac_for(&self, zone: &str, realm_name: &str) -> Result<String> {
+        Ok(BridgeAllocator::format_mac(self.allocator(zone)?.realm_mac(realm_name)))
+    }
+
+    /// Return the address currently allocated to realm `realm_name` on any bridge
+    pub fn allocated_address(&self, realm_name: &str) -> Option<Ipv4Addr> {
+        self.allocators.values()
+            .flat_map(|allocator| allocator.allocated_address(realm_name))
+            .next()
+    }
+}
+
+// Call `check` every `interval` until it returns `true` or `timeout` has passed.
+fn poll_until<F: FnMut() -> bool>(timeout: Duration, interval: Duration, mut check: F) -> bool {
+    let start = Instant::now();
+    loop {
+        if check() {
+            return true;
+        }
+        if start.elapsed() >= timeout {
+            return false;
+        }
+        thread::sleep(interval);
+    }
+}
+
+#[derive(Deserialize,Default)]
+struct NetworkZonesFile {
+    #[serde(default)]
+    zone: HashMap<String, ZoneEntry>,
+}
+
+#[derive(Deserialize)]
+struct ZoneEntry {
+    bridge: Option<String>,
+    subnet: String,
+    #[serde(rename="gateway-offset")]
+    gateway_offset: Option<u32>,
+    reserved: Option<Vec<String>>,
+    #[serde(rename="ipv6-prefix")]
+    ipv6_prefix: Option<String>,
+}
+
+///
+/// A network zone defined in /etc/citadel/network-zones.conf
+///
+/// ```text
+/// [zone.clear]
+/// subnet = "10.77.0.0/24"
+/// ipv6-prefix = "fd17:c17a:de1::/64"
+///
+/// [zone.work]
+/// bridge = "br-work"
+/// subnet = "10.78.0.0/22"
+/// gateway-offset = 1
+/// reserved = ["200-254", "1000-1022"]
+/// ```
+///
+/// The bridge defaults to `vz-$zone`, which is the bridge systemd-nspawn creates for
+/// `Zone=$zone`. Gateway offset and reserved ranges are offsets of host addresses
+/// from the start of the subnet. Addresses in reserved ranges are only assigned to
+/// realms with a matching `reserved-ip` and the default reserved range is 200-254.
+/// IPv6 is enabled for a zone by configuring a ULA prefix.
+///
+#[derive(Debug,Clone,PartialEq)]
+pub struct NetworkZone {
+    name: String,
+    bridge: String,
+    network: Ipv4Addr,
+    mask_size: usize,
+    gateway_offset: u32,
+    reserved: Vec<(u32, u32)>,
+    ipv6_prefix: Option<Ipv6Addr>,
+}
+
+impl NetworkZone {
+    fn new(name: &str, subnet: &str) -> Result<Self> {
+        let (network, mask_size) = Self::parse_subnet(subnet)?;
+        let zone = NetworkZone {
+            name: name.to_string(),
+            bridge: format!("vz-{}", name),
+            network, mask_size,
+            gateway_offset: DEFAULT_GATEWAY_OFFSET,
+            reserved: vec![(RESERVED_START, RESERVED_END)],
+            ipv6_prefix: None,
+        };
+        Ok(zone)
+    }
+
+    // The zones used when /etc/citadel/network-zones.conf does not exist
+    fn builtin() -> Result<Vec<Self>> {
+        Ok(vec![Self::new("clear", CLEAR_BRIDGE_NETWORK)?])
+    }
+
+    /// Load zones from /etc/citadel/network-zones.conf or return the built-in
+    /// `clear` zone if the file does not exist.
+    pub fn load_all() -> Result<Vec<Self>> {
+        let path = Path::new(NETWORK_ZONES_PATH);
+        if !path.exists() {
+            return Self::builtin();
+        }
+        let content = fs::read_to_string(path)?;
+        Self::parse_zones(&content)
+            .map_err(|e| format_err!("failed to load {}: {}", NETWORK_ZONES_PATH, e))
+    }
+
+    fn parse_zones(content: &str) -> Result<Vec<Self>> {
+        let file: NetworkZonesFile = toml::from_str(content)?;
+        let mut zones = Vec::new();
+        for (name, entry) in &file.zone {
+            let zone = Self::from_entry(name, entry)
+                .map_err(|e| format_err!("zone {}: {}", name, e))?;
+            zones.push(zone);
+        }
+        zones.sort_by(|a, b| a.name.cmp(&b.name));
+        Self::check_overlaps(&zones)?;
+        Ok(zones)
+    }
+
+    fn from_entry(name: &str, entry: &ZoneEntry) -> Result<Self> {
+        let mut zone = Self::new(name, &entry.subnet)?;
+        if let Some(ref bridge) = entry.bridge {
+            if bridge.is_empty() || bridge.len() > 15 || bridge.contains(|c: char| c == '/' || c.is_whitespace()) {
+                bail!("invalid bridge name '{}'", bridge);
+            }
+            zone.bridge = bridge.clone();
+        }
+        if let Some(offset) = entry.gateway_offset {
+            zone.gateway_offset = offset;
+        }
+        if let Some(ref ranges) = entry.reserved {
+            zone.reserved = ranges.iter()
+                .map(|r| Self::parse_range(r))
+                .collect::<Result<Vec<_>>>()?;
+        }
+        if let Some(ref prefix) = entry.ipv6_prefix {
+            let prefix = BridgeAllocator::parse_ipv6_prefix(prefix, &zone.bridge)?;
+            zone.ipv6_prefix = Some(prefix);
+        }
+        zone.validate()?;
+        Ok(zone)
+    }
+
+    fn parse_subnet(subnet: &str) -> Result<(Ipv4Addr, usize)> {
+        let (addr_str, mask_size) = match subnet.find('/') {
+            Some(idx) => {
+                let (net,bits) = subnet.split_at(idx);
+                (net.to_owned(), bits[1..].parse()?)
+            },
+            None => (subnet.to_owned(), 24),
+        };
+        if !(MIN_MASK..=MAX_MASK).contains(&mask_size) {
+            bail!("Unsupported network mask size of {}", mask_size);
+        }
+
+        let mask = (1u32 << (32 - mask_size)) - 1;
+        let ip = addr_str.parse::<Ipv4Addr>()?;
+
+        if (u32::from(ip) & mask) != 0 {
+            bail!("network {} has masked bits with netmask /{}", addr_str, mask_size);
+        }
+        Ok((ip, mask_size))
+    }
+
+    fn parse_range(range: &str) -> Result<(u32, u32)> {
+        let parse = |s: &str| s.trim().parse::<u32>()
+            .map_err(|_| format_err!("invalid reserved range '{}'", range));
+        let (start, end) = match range.find('-') {
+            Some(idx) => (parse(&range[..idx])?, parse(&range[idx + 1..])?),
+            None => (parse(range)?, parse(range)?),
+        };
+        if start > end {
+            bail!("invalid reserved range '{}'", range);
+        }
+        Ok((start, end))
+    }
+
+    // Offset of the broadcast address, which is also the number of usable host addresses plus one
+    fn broadcast_offset(&self) -> u32 {
+        (1u32 << (32 - self.mask_size)) - 1
+    }
+
+    fn validate(&self) -> Result<()> {
+        let last = self.broadcast_offset() - 1;
+        if self.gateway_offset < 1 || self.gateway_offset > last {
+            bail!("gateway-offset {} is outside of subnet {}/{}", self.gateway_offset, self.network, self.mask_size);
+        }
+        for &(start, end) in &self.reserved {
+            if start < 1 || end > last {
+                bail!("reserved range {}-{} is outside of subnet {}/{}", start, end, self.network, self.mask_size);
+            }
+            if start <= self.gateway_offset && self.gateway_offset <= end {
+                bail!("reserved range {}-{} contains the gateway address", start, end);
+            }
+        }
+        Ok(())
+    }
+
+    fn overlaps(&self, other: &NetworkZone) -> bool {
+        let mask_size = self.mask_size.min(other.mask_size);
+        let mask = !((1u32 << (32 - mask_size)) - 1);
+        u32::from(self.network) & mask == u32::from(other.network) & mask
+    }
+
+    fn check_overlaps(zones: &[NetworkZone]) -> Result<()> {
+        for (i, a) in zones.iter().enumerate() {
+            for b in &zones[i + 1..] {
+                if a.overlaps(b) {
+                    bail!("subnet {}/{} of zone {} overlaps subnet {}/{} of zone {}",
+                          a.network, a.mask_size, a.name, b.network, b.mask_size, b.name);
+                }
+                if a.bridge == b.bridge {
+                    bail!("zones {} and {} use the same bridge {}", a.name, b.name, a.bridge);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+///
+/// Manages network namespaces for realms configured with `netns = "auto"`.
+///
+/// Each managed namespace is named `citadel-$realm` and contains one end of a
+/// veth pair called `host0` configured with the address allocated to the realm.
+/// The other end of the pair is attached to the bridge of the realm network zone.
+///
+pub struct NetnsManager;
+
+impl NetnsManager {
+
+    pub fn netns_name(realm_name: &str) -> String {
+        format!("{}{}", MANAGED_NETNS_PREFIX, realm_name)
+    }
+
+    fn netns_exists(netns: &str) -> bool {
+        Path::new(NETNS_RUN_PATH).join(netns).exists()
+    }
+
+    // Network interface names are limited to 15 characters so long realm
+    // names are replaced with a hash.
+    fn veth_name(realm_name: &str) -> String {
+        if realm_name.len() <= 12 {
+            format!("vc-{}", realm_name)
+        } else {
+            let mut hasher = DefaultHasher::new();
+            realm_name.hash(&mut hasher);
+            format!("vc-{:012x}", hasher.finish() & 0xFFFF_FFFF_FFFF)
+        }
+    }
+
+    /// Create network namespace for realm `realm_name` connected to the host
+    /// interface `bridge` with `address` (in CIDR notation) and default route through `gateway`.
+    /// The interface inside the namespace is given the MAC address `mac`.
+    pub fn create(realm_name: &str, bridge: &str, address: &str, gateway: &str, mac: &str) -> Result<()> {
+        let netns = Self::netns_name(realm_name);
+        if Self::netns_exists(&netns) {
+            warn!("Removing stale network namespace {}", netns);
+            Self::remove_netns(&netns)?;
+        }
+        cmd!(IP_PATH, "netns add {}", netns)?;
+        if let Err(e) = Self::setup_netns(&netns, realm_name, bridge, address, gateway, mac) {
+            if let Err(e) = Self::remove_netns(&netns) {
+                warn!("Failed to remove network namespace {}: {}", netns, e);
+            }
+            return Err(e);
+        }
+        info!("Created network namespace {} with address {}", netns, address);
+        Ok(())
+    }
+
+    fn setup_netns(netns: &str, realm_name: &str, bridge: &str, address: &str, gateway: &str, mac: &str) -> Result<()> {
+        if !Path::new("/sys/class/net").join(bridge).exists() {
+            cmd!(IP_PATH, "link add {} type bridge", bridge)?;
+            cmd!(IP_PATH, "link set {} up", bridge)?;
+        }
+        let veth = Self::veth_name(realm_name);
+        cmd!(IP_PATH, "link add {} type veth peer name host0 address {} netns {}", veth, mac, netns)?;
+        cmd!(IP_PATH, "link set {} master {}", veth, bridge)?;
+        cmd!(IP_PATH, "link set {} up", veth)?;
+        cmd!(IP_PATH, "-n {} link set lo up", netns)?;
+        cmd!(IP_PATH, "-n {} addr add {} dev host0", netns, address)?;
+        cmd!(IP_PATH, "-n {} link set host0 up", netns)?;
+        cmd!(IP_PATH, "-n {} route add default via {}", netns, gateway)?;
+        Ok(())
+    }
+
+    /// Remove network namespace of realm `realm_name` if it exists. The veth pair
+    /// is removed along with the namespace.
+    pub fn remove(realm_name: &str) -> Result<()> {
+        let netns = Self::netns_name(realm_name);
+        if Self::netns_exists(&netns) {
+            Self::remove_netns(&netns)?;
+        }
+        Ok(())
+    }
+
+    fn remove_netns(netns: &str) -> Result<()> {
+        cmd!(IP_PATH, "netns delete {}", netns)
+    }
+
+    /// Remove any managed network namespaces which do not belong to one of
+    /// the realms listed in `active`. These are left behind when a realm
+    /// crashes or realmsd exits without stopping a realm.
+    pub fn remove_orphaned(active: &[&str]) -> Result<()> {
+        let dir = Path::new(NETNS_RUN_PATH);
+        if !dir.exists() {
+            return Ok(());
+        }
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            if name.starts_with(MANAGED_NETNS_PREFIX) && !active.contains(&&name[MANAGED_NETNS_PREFIX.len()..]) {
+                warn!("Removing orphaned network namespace {}", name);
+                if let Err(e) = Self::remove_netns(&name) {
+                    warn!("Failed to remove network namespace {}: {}", name, e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    fn from_str_value(value: &str) -> Option<Self> {
+        match value {
+            "tcp" => Some(Protocol::Tcp),
+            "udp" => Some(Protocol::Udp),
+            _ => None,
+        }
+    }
+
+    pub fn to_str_value(self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        }
+    }
+}
+
+///
+/// A forward of a port on the host to a port on the address allocated to a realm
+/// from the `port-forwards` realm config option.
+///
+///    tcp:8080:80
+///    udp:5353:5353
+///
+#[derive(Debug,Clone,PartialEq)]
+pub struct PortForward {
+    protocol: Protocol,
+    host_port: u16,
+    realm_port: u16,
+}
+
+impl PortForward {
+    pub fn parse(entry: &str) -> Result<Self> {
+        let fields = entry.split(':').collect::<Vec<_>>();
+        if fields.len() != 3 {
+            bail!("expected protocol:host-port:realm-port");
+        }
+        let protocol = Protocol::from_str_value(fields[0])
+            .ok_or_else(|| format_err!("protocol must be tcp or udp"))?;
+        let host_port = Self::parse_port(fields[1])?;
+        let realm_port = Self::parse_port(fields[2])?;
+        Ok(PortForward { protocol, host_port, realm_port })
+    }
+
+    fn parse_port(port: &str) -> Result<u16> {
+        match port.parse::<u16>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => bail!("invalid port '{}'", port),
+        }
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn host_port(&self) -> u16 {
+        self.host_port
+    }
+
+    pub fn realm_port(&self) -> u16 {
+        self.realm_port
+    }
+}
+
+// A port forward rule read back from the nftables ruleset
+#[derive(Debug,PartialEq)]
+struct ForwardRule {
+    realm: String,
+    protocol: Protocol,
+    host_port: u16,
+    handle: u32,
+}
+
+impl ForwardRule {
+    // Parse a line of output from `nft -a list chain` such as:
+    //
+    //    fib daddr type local tcp dport 8080 dnat to 172.17.0.5:80 comment "realm:main" # handle 4
+    //
+    fn parse(line: &str) -> Option<Self> {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let idx = words.iter().position(|&w| w == "dport")?;
+        let protocol = Protocol::from_str_value(words.get(idx.checked_sub(1)?)?)?;
+        let host_port = words.get(idx + 1)?.parse().ok()?;
+        let comment = words.iter().find(|w| w.starts_with("\"realm:"))?;
+        let realm = comment.trim_matches('"')["realm:".len()..].to_string();
+        let handle = words.iter().position(|&w| w == "handle")
+            .and_then(|idx| words.get(idx + 1))
+            .and_then(|h| h.parse().ok())?;
+        Some(ForwardRule { realm, protocol, host_port, handle })
+    }
+}
+
+///
+/// Manages nftables DNAT rules which forward ports on the host to realms.
+///
+/// Rules are added to the `realm-prerouting` and `realm-output` hook chains of
+/// the firewall table `inet citadel` and are tagged with a comment naming the
+/// realm they belong to so they can be found again and removed when the realm stops.
+///
+pub struct PortForwarder;
+
+impl PortForwarder {
+    const CHAINS: &'static [&'static str] = &["realm-prerouting", "realm-output"];
+
+    /// Return an error if any of `forwards` uses a host port which is already
+    /// forwarded to a realm other than `realm_name`.
+    pub fn check_conflicts(realm_name: &str, forwards: &[PortForward]) -> Result<()> {
+        if forwards.is_empty() || !Firewall::is_installed() {
+            return Ok(());
+        }
+        for rule in Self::list_rules("realm-prerouting")? {
+            if rule.realm == realm_name {
+                continue;
+            }
+            if forwards.iter().any(|f| f.protocol == rule.protocol && f.host_port == rule.host_port) {
+                bail!("{} port {} is already forwarded to realm {}", rule.protocol.to_str_value(), rule.host_port, rule.realm);
+            }
+        }
+        Ok(())
+    }
+
+    /// Install rules forwarding each entry in `forwards` to `address` of realm `realm_name`
+    /// which is attached to the host interface `bridge`.
+    pub fn add(realm_name: &str, bridge: &str, address: Ipv4Addr, forwards: &[PortForward]) -> Result<()> {
+        if forwards.is_empty() {
+            return Ok(());
+        }
+        Self::check_conflicts(realm_name, forwards)?;
+        Firewall::ensure_installed()?;
+        // Allow connections to forwarded ports on localhost to be routed to the realm
+        let route_localnet = Path::new("/proc/sys/net/ipv4/conf").join(bridge).join("route_localnet");
+        if route_localnet.exists() {
+            fs::write(&route_localnet, "1")?;
+        }
+        for f in forwards {
+            for chain in Self::CHAINS {
+                cmd!(NFT_PATH, "add rule inet {} {} fib daddr type local {} dport {} dnat ip to {}:{} comment \"realm:{}\"",
+                     FIREWALL_TABLE, chain, f.protocol.to_str_value(), f.host_port, address, f.realm_port, realm_name)?;
+            }
+            cmd!(NFT_PATH, "add rule inet {} realm-postrouting ip saddr 127.0.0.0/8 ip daddr {} {} dport {} masquerade comment \"realm:{}\"",
+                 FIREWALL_TABLE, address, f.protocol.to_str_value(), f.realm_port, realm_name)?;
+            info!("Forwarding {} port {} to {}:{} for realm {}", f.protocol.to_str_value(), f.host_port, address, f.realm_port, realm_name);
+        }
+        Ok(())
+    }
+
+    /// Remove all port forward rules belonging to realm `realm_name`
+    pub fn remove(realm_name: &str) -> Result<()> {
+        Self::remove_matching(|name| name == realm_name)
+    }
+
+    /// Remove port forward rules belonging to any realm which is not listed
+    /// in `active`. These are left behind when a realm crashes or realmsd
+    /// exits without stopping a realm.
+    pub fn flush_orphans(active: &[&str]) -> Result<()> {
+        Self::remove_matching(|name| !active.contains(&name))
+    }
+
+    fn remove_matching<F: Fn(&str) -> bool>(matches: F) -> Result<()> {
+        if !Firewall::is_installed() {
+            return Ok(());
+        }
+        for chain in Self::CHAINS.iter().chain(&["realm-postrouting"]) {
+            for rule in Self::list_rules(chain)? {
+                if matches(&rule.realm) {
+                    cmd!(NFT_PATH, "delete rule inet {} {} handle {}", FIREWALL_TABLE, chain, rule.handle)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn list_rules(chain: &str) -> Result<Vec<ForwardRule>> {
+        let output = cmd_with_output!(NFT_PATH, "-a list chain inet {} {}", FIREWALL_TABLE, chain)?;
+        Ok(output.lines().flat_map(ForwardRule::parse).collect())
+    }
+}
+
+/// Traffic restrictions applied to a realm with the `vpn-required` option
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum NetworkBlockState {
+    /// No restrictions are applied
+    Open,
+    /// Traffic is only forwarded out through the VPN interface
+    VpnOnly,
+    /// All traffic from the realm is dropped because the VPN is down
+    Blocked,
+}
+
+impl NetworkBlockState {
+    pub fn to_str_value(self) -> &'static str {
+        match self {
+            NetworkBlockState::Open => "open",
+            NetworkBlockState::VpnOnly => "vpn-only",
+            NetworkBlockState::Blocked => "blocked",
+        }
+    }
+}
+
+// A kill switch rule read back from the nftables ruleset
+#[derive(Debug,PartialEq)]
+struct KillSwitchRule {
+    realm: String,
+    accept: bool,
+    handle: u32,
+}
+
+impl KillSwitchRule {
+    // Parse a line of output from `nft -a list chain` such as:
+    //
+    //    ip saddr 172.17.0.5 oifname "wg0" accept comment "realm:main" # handle 7
+    //
+    fn parse(line: &str) -> Option<Self> {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let comment = words.iter().find(|w| w.starts_with("\"realm:"))?;
+        let realm = comment.trim_matches('"')["realm:".len()..].to_string();
+        let accept = words.contains(&"accept");
+        let handle = words.iter().position(|&w| w == "handle")
+            .and_then(|idx| words.get(idx + 1))
+            .and_then(|h| h.parse().ok())?;
+        Some(KillSwitchRule { realm, accept, handle })
+    }
+}
+
+///
+/// Manages nftables rules which stop traffic from realms with the `vpn-required`
+/// option from leaving the host other than through a VPN interface.
+///
+/// Rules are added to the `realm-forward` hook chain of the firewall table
+/// `inet citadel` and are tagged with a comment naming the realm they belong to. While the VPN
+/// interface is up, forwarded traffic from the realm addresses is accepted only
+/// when it leaves through that interface. While it is down, all forwarded traffic
+/// from the realm is dropped.
+///
+pub struct KillSwitch;
+
+impl KillSwitch {
+    /// Returns `true` if the network interface `name` exists and is up
+    pub fn is_interface_up(name: &str) -> bool {
+        let flags = Path::new("/sys/class/net").join(name).join("flags");
+        fs::read_to_string(flags).ok()
+            .and_then(|s| u32::from_str_radix(s.trim().trim_start_matches("0x"), 16).ok())
+            .map(|flags| flags & 1 != 0)
+            .unwrap_or(false)
+    }
+
+    /// Replace the rules for realm `realm_name` with rules for `addresses` which
+    /// only allow traffic through `interface` if `vpn_up` is `true` and otherwise
+    /// drop all traffic.
+    ///
+    /// The new rules are added and the previous rules deleted in a single transaction
+    /// so there is no moment at which traffic from the realm is unrestricted.
+    pub fn apply(realm_name: &str, addresses: &[IpAddr], interface: &str, vpn_up: bool) -> Result<NetworkBlockState> {
+        if addresses.is_empty() {
+            bail!("realm {} has no network address to restrict to VPN interface {}", realm_name, interface);
+        }
+        Firewall::ensure_installed()?;
+        let previous = Self::list_rules()?.into_iter()
+            .filter(|rule| rule.realm == realm_name)
+            .map(|rule| rule.handle)
+            .collect::<Vec<_>>();
+        fs::create_dir_all(KILL_SWITCH_RULES_DIR)?;
+        let path = Path::new(KILL_SWITCH_RULES_DIR).join(format!("{}.nft", realm_name));
+        fs::write(&path, Self::ruleset(realm_name, addresses, interface, vpn_up, &previous))?;
+        let result = cmd!(NFT_PATH, "-f {}", path.display());
+        let _ = fs::remove_file(&path);
+        result.map_err(|e| format_err!("failed to load kill switch rules from {}: {}", path.display(), e))?;
+        Ok(if vpn_up { NetworkBlockState::VpnOnly } else { NetworkBlockState::Blocked })
+    }
+
+    // Generate the nftables script which adds the rules for `realm_name` and then
+    // deletes the rules with the `previous` handles.
+    fn ruleset(realm_name: &str, addresses: &[IpAddr], interface: &str, vpn_up: bool, previous: &[u32]) -> String {
+        let mut script = String::new();
+        for address in addresses {
+            let family = if address.is_ipv4() { "ip" } else { "ip6" };
+            if vpn_up {
+                script += &format!("add rule inet {} realm-forward {} saddr {} oifname \"{}\" accept comment \"realm:{}\"\n",
+                                   FIREWALL_TABLE, family, address, interface, realm_name);
+            }
+            script += &format!("add rule inet {} realm-forward {} saddr {} drop comment \"realm:{}\"\n",
+                               FIREWALL_TABLE, family, address, realm_name);
+        }
+        for handle in previous {
+            script += &format!("delete rule inet {} realm-forward handle {}\n", FIREWALL_TABLE, handle);
+        }
+        script
+    }
+
+    /// Remove all kill switch rules belonging to realm `realm_name`
+    pub fn remove(realm_name: &str) -> Result<()> {
+        if !Firewall::is_installed() {
+            return Ok(());
+        }
+        for rule in Self::list_rules()? {
+            if rule.realm == realm_name {
+                cmd!(NFT_PATH, "delete rule inet {} realm-forward handle {}", FIREWALL_TABLE, rule.handle)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the restrictions currently applied to realm `realm_name`
+    pub fn state(realm_name: &str) -> Result<NetworkBlockState> {
+        if !Firewall::is_installed() {
+            return Ok(NetworkBlockState::Open);
+        }
+        Ok(Self::state_from_rules(realm_name, &Self::list_rules()?))
+    }
+
+    fn state_from_rules(realm_name: &str, rules: &[KillSwitchRule]) -> NetworkBlockState {
+        let mut rules = rules.iter().filter(|r| r.realm == realm_name).peekable();
+        if rules.peek().is_none() {
+            NetworkBlockState::Open
+        } else if rules.any(|r| r.accept) {
+            NetworkBlockState::VpnOnly
+        } else {
+            NetworkBlockState::Blocked
+        }
+    }
+
+    fn list_rules() -> Result<Vec<KillSwitchRule>> {
+        let output = cmd_with_output!(NFT_PATH, "-a list chain inet {} realm-forward", FIREWALL_TABLE)?;
+        Ok(output.lines().flat_map(KillSwitchRule::parse).collect())
+    }
+}
+
+///
+/// Installs the baseline nftables table `inet citadel` for the network zones.
+///
+/// Forwarding between the bridges of different zones is dropped and traffic
+/// from each zone subnet leaving through any other interface is masqueraded.
+/// Each base chain first applies the zone rules and then jumps to a hook chain
+/// (`realm-forward`, `realm-prerouting`, `realm-output` and `realm-postrouting`)
+/// which holds the per-realm rules added by `KillSwitch` and `PortForwarder`.
+///
+/// Installing the ruleset again replaces the zone rules but keeps the rules in
+/// the hook chains, and no other table is changed.
+///
+pub struct Firewall;
+
+impl Firewall {
+    // Base chains of the table with the type, hook and priority of each. Every
+    // base chain has a hook chain with the same name prefixed by `realm-`.
+    const BASE_CHAINS: &'static [(&'static str, &'static str, i32)] = &[
+        ("forward", "filter", 0),
+        ("prerouting", "nat", -100),
+        ("output", "nat", -100),
+        ("postrouting", "nat", 100),
+    ];
+
+    /// Generate the nftables script which installs the firewall for `zones`
+    pub fn ruleset(zones: &[NetworkZone]) -> String {
+        let mut script = format!("table inet {} {{\n", FIREWALL_TABLE);
+        for (chain, _, _) in Self::BASE_CHAINS {
+            script += &format!("    chain realm-{} {{\n    }}\n", chain);
+        }
+        for (chain, kind, priority) in Self::BASE_CHAINS {
+            script += &format!("    chain {} {{\n        type {} hook {} priority {};\n    }}\n", chain, kind, chain, priority);
+        }
+        script += "}\n";
+
+        for (chain, _, _) in Self::BASE_CHAINS {
+            script += &format!("flush chain inet {} {}\n", FIREWALL_TABLE, chain);
+        }
+        let bridges = |exclude: Option<&str>| zones.iter()
+            .map(|zone| zone.bridge.as_str())
+            .filter(|&bridge| Some(bridge) != exclude)
+            .collect::<Vec<_>>();
+        for zone in zones {
+            let others = bridges(Some(&zone.bridge));
+            if !others.is_empty() {
+                script += &format!("add rule inet {} forward iifname \"{}\" oifname {} drop comment \"zone:{}\"\n",
+                                   FIREWALL_TABLE, zone.bridge, Self::interface_set(&others), zone.name);
+            }
+        }
+        for zone in zones {
+            script += &format!("add rule inet {} postrouting ip saddr {}/{} oifname != {} masquerade comment \"zone:{}\"\n",
+                               FIREWALL_TABLE, zone.network, zone.mask_size, Self::interface_set(&bridges(None)), zone.name);
+        }
+        for (chain, _, _) in Self::BASE_CHAINS {
+            script += &format!("add rule inet {} {} jump realm-{}\n", FIREWALL_TABLE, chain, chain);
+        }
+        script
+    }
+
+    // A single quoted interface name or an anonymous set of them
+    fn interface_set(names: &[&str]) -> String {
+        let quoted = names.iter().map(|name| format!("\"{}\"", name)).collect::<Vec<_>>();
+        if quoted.len() == 1 {
+            quoted[0].clone()
+        } else {
+            format!("{{ {} }}", quoted.join(", "))
+        }
+    }
+
+    /// Install the firewall for `zones`, replacing the zone rules if it is already installed.
+    /// The ruleset is loaded in a single transaction so a failure leaves the table unchanged.
+    pub fn install(zones: &[NetworkZone]) -> Result<()> {
+        let path = Path::new(FIREWALL_RULES_PATH);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, Self::ruleset(zones))?;
+        cmd!(NFT_PATH, "-f {}", FIREWALL_RULES_PATH)
+            .map_err(|e| format_err!("failed to load firewall ruleset from {}: {}", FIREWALL_RULES_PATH, e))?;
+        verbose!("Installed firewall for {} network zones", zones.len());
+        Ok(())
+    }
+
+    /// Install the firewall for the zones in /etc/citadel/network-zones.conf unless it is already installed
+    pub fn ensure_installed() -> Result<()> {
+        if Self::is_installed() {
+            return Ok(());
+        }
+        Self::install(&NetworkZone::load_all()?)
+    }
+
+    pub fn is_installed() -> bool {
+        cmd_with_output!(NFT_PATH, "list tables inet")
+            .map(|out| out.lines().any(|line| line.trim() == format!("table inet {}", FIREWALL_TABLE)))
+            .unwrap_or(false)
+    }
+
+    /// Delete the firewall table together with all per-realm rules
+    pub fn remove() -> Result<()> {
+        if !Self::is_installed() {
+            return Ok(());
+        }
+        cmd!(NFT_PATH, "delete table inet {}", FIREWALL_TABLE)?;
+        info!("Removed firewall table inet {}", FIREWALL_TABLE);
+        Ok(())
+    }
 }
 
-impl NetworkConfig {
-    pub fn new() -> NetworkConfig {
-        NetworkConfig {
-            allocators: HashMap::new(),
+/// Address field of an `extra-hosts` entry
+#[derive(Debug,Clone,PartialEq)]
+pub enum HostsAddress {
+    /// An ip address
+    Ip(IpAddr),
+    /// `@host` is the gateway address of the network zone of the realm
+    Host,
+    /// `@realm:<name>` is the address allocated to the realm with that name
+    Realm(String),
+}
+
+///
+/// An entry from the `extra-hosts` realm config option which is written to
+/// the /etc/hosts file of the realm.
+///
+///    10.42.0.5 api.local
+///    @host host.local
+///    @realm:db db.local database.local
+///
+#[derive(Debug,Clone)]
+pub struct HostsEntry {
+    address: HostsAddress,
+    names: Vec<String>,
+}
+
+impl HostsEntry {
+    pub fn parse(line: &str) -> Result<Self> {
+        let mut fields = line.split_whitespace();
+        let address = match fields.next() {
+            Some("@host") => HostsAddress::Host,
+            Some(s) if s.starts_with("@realm:") => {
+                let name = &s["@realm:".len()..];
+                if !Realm::is_valid_name(name) {
+                    bail!("invalid realm name '{}'", name);
+                }
+                HostsAddress::Realm(name.to_string())
+            },
+            Some(s) => HostsAddress::Ip(s.parse().map_err(|_| format_err!("invalid address '{}'", s))?),
+            None => bail!("entry is empty"),
+        };
+        let names = fields.map(String::from).collect::<Vec<_>>();
+        if names.is_empty() {
+            bail!("no hostnames listed");
+        }
+        if let Some(bad) = names.iter().find(|name| !Self::is_valid_hostname(name)) {
+            bail!("invalid hostname '{}'", bad);
         }
+        Ok(HostsEntry { address, names })
     }
 
-    pub fn add_bridge(&mut self, name: &str, network: &str) -> Result<()> {
-        let allocator = BridgeAllocator::for_bridge(name, network)
-            .map_err(|e| format_err!("Failed to create bridge allocator: {}", e))?;
-        self.allocators.insert(name.to_owned(), allocator);
-        Ok(())
+    fn is_valid_hostname(name: &str) -> bool {
+        !name.is_empty() && !name.starts_with('-') && !name.starts_with('.') &&
+            name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
     }
 
-    pub fn gateway(&self, bridge: &str) -> Result<String> {
-        match self.allocators.get(bridge) {
-            Some(allocator) => Ok(allocator.gateway()),
-            None => bail!("Failed to return gateway address for bridge {} because it does not exist", bridge),
+    pub fn address(&self) -> &HostsAddress {
+        &self.address
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+}
+
+///
+/// Address allocations of every zone as stored in /run/citadel/network-allocations.json
+///
+///    {
+///      "version": 1,
+///      "allocations": [
+///        { "zone": "clear", "realm": "main", "address": "172.17.0.2" },
+///        { "zone": "clear", "realm": "main", "address": "fd17:c17a:de1:0:8a3e:1f2b:9c4d:e5f6" }
+///      ],
+///      "previous": [
+///        { "zone": "clear", "realm": "work", "address": "172.17.0.3" }
+///      ],
+///      "macs": [
+///        { "zone": "clear", "realm": "main", "mac": "96:2b:5e:0c:71:d4" }
+///      ]
+///    }
+///
+/// `previous` holds the last IPv4 address of realms which are not currently
+/// allocated one, so that a realm is given the same address when it is started again.
+/// `macs` holds the MAC address each realm was given in the zone.
+///
+#[derive(Serialize,Deserialize,Debug,PartialEq)]
+struct AllocationsFile {
+    version: u32,
+    #[serde(default)]
+    allocations: Vec<AllocationEntry>,
+    #[serde(default)]
+    previous: Vec<AllocationEntry>,
+    #[serde(default)]
+    macs: Vec<MacEntry>,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone,PartialEq)]
+struct AllocationEntry {
+    zone: String,
+    realm: String,
+    address: IpAddr,
+}
+
+// The MAC address a realm was given in a zone, which is kept because it may
+// differ from the hashed address to avoid a collision with another realm
+#[derive(Serialize,Deserialize,Debug,Clone,PartialEq)]
+struct MacEntry {
+    zone: String,
+    realm: String,
+    mac: String,
+}
+
+/// Return the current network address allocations as `(zone, realm, address)`
+/// read from the allocation file.
+pub fn network_allocations() -> Vec<(String, String, IpAddr)> {
+    AllocationsFile::load().allocations.into_iter()
+        .map(|entry| (entry.zone, entry.realm, entry.address))
+        .collect()
+}
+
+/// An address reserved with `reserved-ip` in the config of a realm
+pub(crate) struct Reservation {
+    realm: String,
+    zone: String,
+    offset: u32,
+    active: bool,
+}
+
+impl Reservation {
+    pub(crate) fn new(realm: &str, zone: &str, offset: u32, active: bool) -> Self {
+        Reservation { realm: realm.to_string(), zone: zone.to_string(), offset, active }
+    }
+
+    /// The reservation of `realm` if it uses the network and has a reserved address
+    pub(crate) fn for_realm(realm: &Realm) -> Option<Self> {
+        Self::for_config(realm.name(), &realm.config(), realm.is_active())
+    }
+
+    pub(crate) fn for_config(realm: &str, config: &RealmConfig, active: bool) -> Option<Self> {
+        if !config.network() {
+            return None;
         }
+        config.reserved_ip().map(|offset| Self::new(realm, config.network_zone(), offset, active))
     }
+}
 
-    pub fn allocate_address_for(&mut self, bridge: &str, realm_name: &str) -> Result<String> {
-        match self.allocators.get_mut(bridge) {
-            Some(allocator) => allocator.allocate_address_for(realm_name),
-            None => bail!("Failed to allocate address for bridge {} because it does not exist", bridge),
+/// Check that host offset `offset` from `reserved-ip` is in a reserved range of
+/// network zone `zone`. A zone which is not defined is reported when the realm starts.
+pub(crate) fn check_reserved_ip(zone: &str, offset: u32) -> Result<()> {
+    if let Some(zone) = NetworkZone::load_all()?.iter().find(|z| z.name == zone) {
+        BridgeAllocator::with_zone(zone).reserved_address(offset)?;
+    }
+    Ok(())
+}
+
+/// Realms which reserve the same address in the same zone
+#[derive(Debug,PartialEq)]
+pub(crate) struct ReservationConflict {
+    zone: String,
+    offset: u32,
+    holder: String,
+    others: Vec<String>,
+}
+
+impl ReservationConflict {
+    /// The realm which is given the address
+    pub(crate) fn holder(&self) -> &str {
+        &self.holder
+    }
+
+    /// Realms which cannot be started because the address is given to `holder()`
+    pub(crate) fn others(&self) -> &[String] {
+        &self.others
+    }
+
+    pub(crate) fn message_for(&self, realm: &str) -> String {
+        let other = if realm == self.holder { &self.others[0] } else { &self.holder };
+        format!("reserved-ip {} in network zone '{}' is also reserved by realm '{}'", self.offset, self.zone, other)
+    }
+
+    ///
+    /// Find addresses reserved by more than one realm. The address is held by a
+    /// running realm if there is one and otherwise by the realm whose name sorts
+    /// first, so the result does not depend on the order in which realms were loaded.
+    ///
+    pub(crate) fn find(reservations: &[Reservation]) -> Vec<ReservationConflict> {
+        let mut by_address = BTreeMap::new();
+        for r in reservations {
+            by_address.entry((r.zone.as_str(), r.offset)).or_insert_with(Vec::new).push(r);
         }
+        by_address.into_iter()
+            .filter(|(_, realms)| realms.len() > 1)
+            .map(|((zone, offset), mut realms)| {
+                realms.sort_by(|a, b| b.active.cmp(&a.active).then_with(|| a.realm.cmp(&b.realm)));
+                ReservationConflict {
+                    zone: zone.to_string(),
+                    offset,
+                    holder: realms[0].realm.clone(),
+                    others: realms[1..].iter().map(|r| r.realm.clone()).collect(),
+                }
+            })
+            .collect()
+    }
+}
+
+impl AllocationEntry {
+    fn new(zone: &str, realm: &str, address: IpAddr) -> Self {
+        AllocationEntry { zone: zone.to_string(), realm: realm.to_string(), address }
     }
+}
 
-    pub fn free_allocation_for(&mut self, bridge: &str, realm_name: &str) -> Result<()> {
-        match self.allocators.get_mut(bridge) {
-            Some(allocator) => allocator.free_allocation_for(realm_name),
-            None => bail!("Failed to free address on bridge {} because it does not exist", bridge),
+impl AllocationsFile {
+    fn new() -> Self {
+        AllocationsFile {
+            version: ALLOCATIONS_FILE_VERSION,
+            allocations: Vec::new(),
+            previous: Vec::new(),
+            macs: Vec::new(),
         }
     }
 
-    pub fn allocate_reserved(&mut self, bridge: &str, realm_name: &str, octet: u8) -> Result<String> {
-        match self.allocators.get_mut(bridge) {
-            Some(allocator) => allocator.allocate_reserved(realm_name, octet),
-            None => bail!("Failed to allocate address for bridge {} because it does not exist", bridge),
+    // An empty table is returned if the file does not exist or cannot be parsed
+    fn load() -> Self {
+        let path = Path::new(NETWORK_ALLOCATIONS_PATH);
+        if !path.exists() {
+            return AllocationsFile::new();
+        }
+        match fs::read_to_string(path).map_err(|e| e.into()).and_then(|s| Self::parse(&s)) {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("Ignoring network allocation file {}: {}", path.display(), e);
+                AllocationsFile::new()
+            }
+        }
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let file: AllocationsFile = serde_json::from_str(content)?;
+        if file.version != ALLOCATIONS_FILE_VERSION {
+            bail!("unsupported version {}", file.version);
         }
+        Ok(file)
+    }
+
+    fn write(&self) -> Result<()> {
+        util::write_file_atomic(NETWORK_ALLOCATIONS_PATH, serde_json::to_string_pretty(self)?)
+            .map_err(|e| format_err!("failed to save network allocation file: {}", e))
+    }
+
+    // Replace all entries for the zone of `allocator` with its current allocations
+    fn update_zone(&mut self, allocator: &BridgeAllocator) {
+        let zone = allocator.bridge.as_str();
+        self.allocations.retain(|entry| entry.zone != zone);
+        self.previous.retain(|entry| entry.zone != zone);
+        self.macs.retain(|entry| entry.zone != zone);
+        self.allocations.extend(allocator.allocations().into_iter()
+            .map(|(realm, address)| AllocationEntry::new(zone, &realm, address)));
+        let mut previous = allocator.previous.iter().collect::<Vec<_>>();
+        previous.sort();
+        self.previous.extend(previous.into_iter()
+            .map(|(realm, &address)| AllocationEntry::new(zone, realm, IpAddr::V4(address))));
+        let mut macs = allocator.macs.iter().collect::<Vec<_>>();
+        macs.sort();
+        self.macs.extend(macs.into_iter()
+            .map(|(realm, &mac)| MacEntry { zone: zone.to_string(), realm: realm.clone(), mac: BridgeAllocator::format_mac(mac) }));
     }
 }
 
 ///
 /// Allocates IP addresses for a bridge shared by multiple realms.
 ///
-/// State information is stored in /run/citadel/realms/network-$bridge as
-/// colon ':' separated pairs of realm name and allocated ip address
-///
-///    realm-a:172.17.0.2
-///    realm-b:172.17.0.3
+/// Allocations are saved to /run/citadel/network-allocations.json after every change
+/// and the address a realm was last given is remembered so that it is preferred
+/// the next time an address is allocated for the realm.
 ///
 pub struct BridgeAllocator {
     bridge: String,
+    interface: String,
     network: Ipv4Addr,
     mask_size: usize,
+    gateway_offset: u32,
+    reserved: Vec<(u32, u32)>,
     allocated: HashSet<Ipv4Addr>,
     allocations: HashMap<String, Ipv4Addr>,
+    previous: HashMap<String, Ipv4Addr>,
+    macs: HashMap<String, [u8; 6]>,
+    ipv6_prefix: Option<Ipv6Addr>,
+    allocations6: HashMap<String, Ipv6Addr>,
 }
 
 impl BridgeAllocator {
 
 
     pub fn default_bridge() -> Result<BridgeAllocator> {
-        BridgeAllocator::for_bridge("clear", CLEAR_BRIDGE_NETWORK)
+        match NetworkConfig::load()?.allocators.remove("clear") {
+            Some(allocator) => Ok(allocator),
+            None => BridgeAllocator::for_bridge("clear", CLEAR_BRIDGE_NETWORK),
+        }
     }
 
     pub fn for_bridge(bridge: &str, network: &str) -> Result<BridgeAllocator> {
-        let (addr_str, mask_size) = match network.find('/') {
-            Some(idx) => {
-                let (net,bits) = network.split_at(idx);
-                (net.to_owned(), bits[1..].parse()?)
-            },
-            None => (network.to_owned(), 24),
-        };
-        if mask_size > MAX_MASK || mask_size < MIN_MASK {
-            bail!("Unsupported network mask size of {}", mask_size);
-        }
-        
-        let mask = (1u32 << (32 - mask_size)) - 1;
-        let ip = addr_str.parse::<Ipv4Addr>()?;
-
-        if (u32::from(ip) & mask) != 0 {
-            bail!("network {} has masked bits with netmask /{}", addr_str, mask_size);
-        }
+        BridgeAllocator::for_zone(&NetworkZone::new(bridge, network)?)
+    }
 
-        let mut conf = BridgeAllocator::new(bridge, ip, mask_size);
-        conf.load_state()?;
+    fn for_zone(zone: &NetworkZone) -> Result<BridgeAllocator> {
+        let mut conf = BridgeAllocator::with_zone(zone);
+        conf.load_allocations(&AllocationsFile::load(), |_| true);
         Ok(conf)
     }
 
+    fn with_zone(zone: &NetworkZone) -> BridgeAllocator {
+        let mut conf = BridgeAllocator::new(&zone.name, zone.network, zone.mask_size);
+        conf.interface = zone.bridge.clone();
+        conf.gateway_offset = zone.gateway_offset;
+        conf.reserved = zone.reserved.clone();
+        conf.ipv6_prefix = zone.ipv6_prefix;
+        conf
+    }
+
     fn new(bridge: &str, network: Ipv4Addr, mask_size: usize) -> BridgeAllocator {
         BridgeAllocator {
             bridge: bridge.to_owned(),
+            interface: format!("vz-{}", bridge),
+            gateway_offset: DEFAULT_GATEWAY_OFFSET,
+            reserved: vec![(RESERVED_START, RESERVED_END)],
             allocated: HashSet::new(),
             allocations: HashMap::new(),
+            previous: HashMap::new(),
+            macs: HashMap::new(),
+            ipv6_prefix: None,
+            allocations6: HashMap::new(),
             network, mask_size,
         }
     }
 
+    /// Allocate an address for `realm_name`, which is the address the realm
+    /// currently has or was last given if that address is still free.
     pub fn allocate_address_for(&mut self, realm_name: &str) -> Result<String> {
-        match self.find_free_address() {
-            Some(addr) => {
-                self.allocated.insert(addr);
-                if let Some(old) = self.allocations.insert(realm_name.to_owned(), addr) {
-                    self.allocated.remove(&old);
-                }
-                self.write_state()?;
-                Ok(format!("{}/{}", addr, self.mask_size))
-            },
+        let addr = self.next_address_for(realm_name)?;
+        self.store_allocation(realm_name, addr)?;
+        Ok(format!("{}/{}", addr, self.mask_size))
+    }
+
+    fn next_address_for(&self, realm_name: &str) -> Result<Ipv4Addr> {
+        match self.preferred_address(realm_name).or_else(|| self.find_free_address()) {
+            Some(addr) => Ok(addr),
             None => bail!("No free IP address could be found to assign to {}", realm_name),
         }
+    }
 
+    // Address which would be allocated to `realm_name` by `allocate_reserved()`
+    // or `allocate_address_for()`
+    fn preview_address_for(&self, realm_name: &str, reserved: Option<u32>) -> Result<String> {
+        let addr = match reserved {
+            Some(offset) => self.check_reserved(realm_name, offset)?,
+            None => self.next_address_for(realm_name)?,
+        };
+        Ok(format!("{}/{}", addr, self.mask_size))
     }
 
     fn store_allocation(&mut self, realm_name: &str, address: Ipv4Addr) -> Result<()> {
         self.allocated.insert(address);
         if let Some(old) = self.allocations.insert(realm_name.to_string(), address) {
-            self.allocated.remove(&old);
+            if old != address {
+                self.allocated.remove(&old);
+            }
         }
+        self.previous.remove(realm_name);
         self.write_state()
     }
 
+    fn preferred_address(&self, realm_name: &str) -> Option<Ipv4Addr> {
+        let usable = |addr: Ipv4Addr| self.is_host_address(addr) && !self.is_reserved(self.host_offset(addr));
+        match self.allocations.get(realm_name) {
+            Some(&addr) => Some(addr).filter(|&addr| usable(addr)),
+            None => self.previous.get(realm_name).cloned()
+                .filter(|&addr| usable(addr) && !self.allocated.contains(&addr)),
+        }
+    }
+
+    // Addresses which were last given to other realms are only used once no
+    // other address is free.
     fn find_free_address(&self) -> Option<Ipv4Addr> {
         let mask = (1u32 << (32 - self.mask_size)) - 1;
         let net =  u32::from(self.network);
-        for i in 2..mask {
-            let addr = Ipv4Addr::from(net + i);
-            if !Self::is_reserved(addr) && !self.allocated.contains(&addr) {
-                return Some(addr);
+        let free = (1..mask)
+            .filter(|&i| i != self.gateway_offset && !self.is_reserved(i))
+            .map(|i| Ipv4Addr::from(net + i))
+            .filter(|addr| !self.allocated.contains(addr));
+        let previous = self.previous.values().collect::<HashSet<_>>();
+        free.clone().find(|addr| !previous.contains(addr))
+            .or_else(|| free.clone().next())
+    }
+
+    // Offset of `address` from the start of the subnet
+    fn host_offset(&self, address: Ipv4Addr) -> u32 {
+        u32::from(address).wrapping_sub(u32::from(self.network))
+    }
+
+    // Returns `true` if `address` is in the subnet and is not the network,
+    // broadcast, or gateway address.
+    fn is_host_address(&self, address: Ipv4Addr) -> bool {
+        let offset = self.host_offset(address);
+        offset > 0 && offset < (1u32 << (32 - self.mask_size)) - 1 && offset != self.gateway_offset
+    }
+
+    #[cfg(test)]
+    fn set_ipv6_prefix(&mut self, prefix: &str) -> Result<()> {
+        self.ipv6_prefix = Some(Self::parse_ipv6_prefix(prefix, &self.bridge)?);
+        Ok(())
+    }
+
+    // A ULA `prefix` in the form `fdxx:xxxx:xxxx:xxxx::/64` enabling IPv6 for `bridge`
+    fn parse_ipv6_prefix(prefix: &str, bridge: &str) -> Result<Ipv6Addr> {
+        let (addr, len) = match prefix.find('/') {
+            Some(idx) => (&prefix[..idx], &prefix[idx + 1..]),
+            None => (prefix, "64"),
+        };
+        if len != "64" {
+            bail!("Unsupported IPv6 prefix length /{} for bridge {}, only /64 is supported", len, bridge);
+        }
+        let addr = addr.parse::<Ipv6Addr>()
+            .map_err(|_| format_err!("Invalid IPv6 prefix '{}' for bridge {}", prefix, bridge))?;
+        let segments = addr.segments();
+        if segments[0] & 0xfe00 != 0xfc00 {
+            bail!("IPv6 prefix {} for bridge {} is not a unique local address (fc00::/7)", prefix, bridge);
+        }
+        if segments[4..].iter().any(|&s| s != 0) {
+            bail!("IPv6 prefix {} for bridge {} has bits set in interface identifier", prefix, bridge);
+        }
+        Ok(addr)
+    }
+
+    pub fn gateway6(&self) -> Option<String> {
+        self.ipv6_prefix.map(|prefix| Self::ipv6_with_iid(prefix, 1).to_string())
+    }
+
+    fn allocate_ipv6_for(&mut self, realm_name: &str, reserved: Option<u32>) -> Result<Option<String>> {
+        let addr = match self.ipv6_address_for(realm_name, reserved)? {
+            Some(addr) => addr,
+            None => return Ok(None),
+        };
+        self.allocations6.insert(realm_name.to_string(), addr);
+        self.write_state()?;
+        Ok(Some(format!("{}/64", addr)))
+    }
+
+    // IPv6 address for `realm_name` if IPv6 is enabled for this bridge
+    fn ipv6_address_for(&self, realm_name: &str, reserved: Option<u32>) -> Result<Option<Ipv6Addr>> {
+        let prefix = match self.ipv6_prefix {
+            Some(prefix) => prefix,
+            None => return Ok(None),
+        };
+        let addr = match reserved {
+            Some(offset) => {
+                let addr = Self::ipv6_with_iid(prefix, u64::from(offset));
+                if self.allocations6.iter().any(|(name, &a)| a == addr && name != realm_name) {
+                    bail!("Already in use: {}", addr);
+                }
+                addr
+            },
+            None => self.find_free_ipv6(prefix, realm_name),
+        };
+        Ok(Some(addr))
+    }
+
+    // The interface identifier is derived from a hash of the realm name so that a
+    // realm is given the same address each time it is started. Hashed identifiers
+    // are always above 0xFFFF to stay clear of the gateway and reserved addresses.
+    fn find_free_ipv6(&self, prefix: Ipv6Addr, realm_name: &str) -> Ipv6Addr {
+        let mut iid = Self::realm_name_hash(realm_name) | 0x1_0000;
+        loop {
+            let addr = Self::ipv6_with_iid(prefix, iid);
+            if !self.allocations6.iter().any(|(name, &a)| a == addr && name != realm_name) {
+                return addr;
+            }
+            iid = iid.wrapping_add(1) | 0x1_0000;
+        }
+    }
+
+    // FNV-1a hash which unlike DefaultHasher is guaranteed to be stable
+    fn realm_name_hash(realm_name: &str) -> u64 {
+        realm_name.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+            (hash ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3)
+        })
+    }
+
+    /// Return the MAC address for `realm_name` which is derived from a hash of the
+    /// zone and realm names. If the address collides with the address of another
+    /// realm in the zone, a counter is added to the hashed names until it does not.
+    pub fn mac_for(&mut self, realm_name: &str) -> String {
+        let mac = self.realm_mac(realm_name);
+        self.macs.insert(realm_name.to_string(), mac);
+        Self::format_mac(mac)
+    }
+
+    fn realm_mac(&self, realm_name: &str) -> [u8; 6] {
+        match self.macs.get(realm_name) {
+            Some(&mac) => mac,
+            None => (0..)
+                .map(|n| Self::hashed_mac(&self.bridge, realm_name, n))
+                .find(|mac| !self.macs.values().any(|m| m == mac))
+                .unwrap(),
+        }
+    }
+
+    fn format_mac(mac: [u8; 6]) -> String {
+        mac.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":")
+    }
+
+    fn parse_mac(s: &str) -> Option<[u8; 6]> {
+        let octets = s.split(':')
+            .map(|b| u8::from_str_radix(b, 16).ok())
+            .collect::<Option<Vec<_>>>()?;
+        if octets.len() != 6 {
+            return None;
+        }
+        let mut mac = [0u8; 6];
+        mac.copy_from_slice(&octets);
+        Some(mac)
+    }
+
+    // Sets the locally administered bit and clears the multicast bit of the first octet
+    fn hashed_mac(zone: &str, realm_name: &str, n: u32) -> [u8; 6] {
+        let key = match n {
+            0 => format!("{}:{}", zone, realm_name),
+            n => format!("{}:{}:{}", zone, realm_name, n),
+        };
+        let hash = Self::realm_name_hash(&key).to_be_bytes();
+        let mut mac = [0u8; 6];
+        mac.copy_from_slice(&hash[2..]);
+        mac[0] = (mac[0] & 0xfc) | 0x02;
+        mac
+    }
+
+    fn ipv6_with_iid(prefix: Ipv6Addr, iid: u64) -> Ipv6Addr {
+        let prefix = u128::from(prefix) & !u128::from(u64::MAX);
+        Ipv6Addr::from(prefix | u128::from(iid))
+    }
+
+    fn allocated_address(&self, realm_name: &str) -> Option<Ipv4Addr> {
+        self.allocations.get(realm_name).cloned()
+    }
+
+    fn allocations(&self) -> Vec<(String, IpAddr)> {
+        let mut v = self.allocations.iter()
+            .map(|(name, &addr)| (name.clone(), IpAddr::V4(addr)))
+            .chain(self.allocations6.iter().map(|(name, &addr)| (name.clone(), IpAddr::V6(addr))))
+            .collect::<Vec<_>>();
+        v.sort();
+        v
+    }
+
+    // Allocate exactly `address` to `realm_name` without writing the state file
+    fn restore_allocation(&mut self, realm_name: &str, address: Ipv4Addr) -> Result<()> {
+        self.insert_allocation(realm_name, IpAddr::V4(address))?;
+        self.previous.remove(realm_name);
+        Ok(())
+    }
+
+    // Remove allocations of realms for which `keep` returns `false` without
+    // writing the state file, and return the names of those realms.
+    fn retain<F: FnMut(&str) -> bool>(&mut self, keep: &mut F) -> Vec<String> {
+        let mut removed = self.allocations.keys()
+            .chain(self.allocations6.keys())
+            .filter(|name| !keep(name))
+            .cloned()
+            .collect::<Vec<_>>();
+        removed.sort();
+        removed.dedup();
+        for name in &removed {
+            if let Some(ip) = self.allocations.remove(name) {
+                self.allocated.remove(&ip);
+                self.previous.insert(name.clone(), ip);
             }
+            self.allocations6.remove(name);
         }
-        None
+        removed
     }
 
-    fn is_reserved(addr: Ipv4Addr) -> bool {
-        addr.octets()[3] >= RESERVED_START
+    // Returns `true` if host address `offset` from the start of the subnet is in a reserved range
+    fn is_reserved(&self, offset: u32) -> bool {
+        self.reserved.iter().any(|&(start, end)| start <= offset && offset <= end)
     }
 
     pub fn gateway(&self) -> String {
-        let gw = u32::from(self.network) + 1;
+        let gw = u32::from(self.network) + self.gateway_offset;
         let addr = Ipv4Addr::from(gw);
         addr.to_string()
     }
 
-    fn allocate_reserved(&mut self, realm_name: &str, octet: u8) -> Result<String> {
-        if octet < RESERVED_START {
-            bail!("Not a reserved octet: {}", octet);
+    fn allocate_reserved(&mut self, realm_name: &str, offset: u32) -> Result<String> {
+        let addr = self.check_reserved(realm_name, offset)?;
+        self.store_allocation(realm_name, addr)?;
+        Ok(format!("{}/{}", addr, self.mask_size))
+    }
+
+    // Return the address with host offset `offset` if it can be reserved for `realm_name`
+    fn check_reserved(&self, realm_name: &str, offset: u32) -> Result<Ipv4Addr> {
+        let addr = self.reserved_address(offset)?;
+        let owner = self.allocations.iter()
+            .find(|&(name, &a)| a == addr && name != realm_name)
+            .map(|(name, _)| name);
+        if let Some(owner) = owner {
+            bail!("Cannot reserve {} for realm {} because it is already allocated to realm {}", addr, realm_name, owner);
         }
-        let rsv = u32::from(self.network) | u32::from(octet);
-        let addr = Ipv4Addr::from(rsv);
-        let s = format!("{}/{}", addr, self.mask_size);
-        if self.allocated.contains(&addr) {
-            bail!("Already in use: {}", s);
+        Ok(addr)
+    }
+
+    // Return the address with host offset `offset` if it is in a reserved range of the zone
+    fn reserved_address(&self, offset: u32) -> Result<Ipv4Addr> {
+        if offset >= 1u32 << (32 - self.mask_size) {
+            bail!("reserved-ip {} is outside of subnet {} of zone {}", offset, self.subnet(), self.bridge);
         }
-        self.store_allocation(realm_name, addr)?;
-        Ok(s)
+        let addr = Ipv4Addr::from(u32::from(self.network) + offset);
+        if !self.is_host_address(addr) {
+            bail!("Cannot reserve {} because it is the network, broadcast, or gateway address of zone {}", addr, self.bridge);
+        }
+        if !self.is_reserved(offset) {
+            bail!("reserved-ip {} is not in a reserved range of zone {}", offset, self.bridge);
+        }
+        Ok(addr)
+    }
+
+    fn subnet(&self) -> String {
+        format!("{}/{}", self.network, self.mask_size)
+    }
+
+    // The network, broadcast, and gateway addresses are never allocated
+    fn utilization(&self) -> (u32, u32) {
+        let capacity = (1u32 << (32 - self.mask_size)) - 3;
+        (self.allocations.len() as u32, capacity)
     }
 
     pub fn free_allocation_for(&mut self, realm_name: &str) -> Result<()> {
         match self.allocations.remove(realm_name) {
             Some(ip) =>  {
                 self.allocated.remove(&ip);
+                self.previous.insert(realm_name.to_string(), ip);
                 self.write_state()?;
             }
             None => warn!("No address allocation found for realm {}", realm_name),
         };
+        if self.allocations6.remove(realm_name).is_some() {
+            self.write_state()?;
+        }
         Ok(())
     }
 
-    fn state_file_path(&self) -> PathBuf {
-        Path::new(REALMS_RUN_PATH).with_file_name(format!("network-{}", self.bridge))
-    }
-
-
-    fn load_state(&mut self) -> Result<()> {
-        let path = self.state_file_path();
-        if !path.exists() {
-            return Ok(())
+    // Load the allocations of this zone from `file` and return `true` if any
+    // entries were dropped because they are not valid for the zone or
+    // `realm_exists` returns `false` for the realm.
+    fn load_allocations<F: Fn(&str) -> bool>(&mut self, file: &AllocationsFile, realm_exists: F) -> bool {
+        let zone = self.bridge.clone();
+        let mut dropped = false;
+        for entry in file.allocations.iter().filter(|entry| entry.zone == zone) {
+            let result = if realm_exists(&entry.realm) {
+                self.insert_allocation(&entry.realm, entry.address)
+            } else {
+                Err(format_err!("realm no longer exists"))
+            };
+            if let Err(e) = result {
+                info!("Dropping network allocation {} for realm {}: {}", entry.address, entry.realm, e);
+                dropped = true;
+            }
         }
-        let f = File::open(path)?;
-        let reader = BufReader::new(f);
-        for line in reader.lines() {
-            let line = &line?;
-            self.parse_state_line(line)?;
+        for entry in file.previous.iter().filter(|entry| entry.zone == zone) {
+            match entry.address {
+                IpAddr::V4(ip) if self.is_host_address(ip) && realm_exists(&entry.realm) => {
+                    self.previous.insert(entry.realm.clone(), ip);
+                },
+                _ => dropped = true,
+            }
         }
-
-        Ok(())
+        for entry in file.macs.iter().filter(|entry| entry.zone == zone) {
+            match Self::parse_mac(&entry.mac) {
+                Some(mac) if realm_exists(&entry.realm) => {
+                    self.macs.insert(entry.realm.clone(), mac);
+                },
+                _ => dropped = true,
+            }
+        }
+        dropped
     }
 
-    fn parse_state_line(&mut self, line: &str) -> Result<()> {
-        match line.find(':') {
-            Some(idx) => {
-                let (name,addr) = line.split_at(idx);
-                let ip = addr[1..].parse::<Ipv4Addr>()?;
+    // Add an allocation restored from the allocation file after checking that it
+    // is valid for this zone and does not conflict with another allocation.
+    fn insert_allocation(&mut self, realm_name: &str, address: IpAddr) -> Result<()> {
+        match address {
+            IpAddr::V4(ip) => {
+                if !self.is_host_address(ip) {
+                    bail!("not a host address in the subnet of zone {}", self.bridge);
+                }
+                if self.allocated.contains(&ip) || self.allocations.contains_key(realm_name) {
+                    bail!("conflicts with another allocation");
+                }
                 self.allocated.insert(ip);
-                self.allocations.insert(name.to_owned(), ip);
+                self.allocations.insert(realm_name.to_string(), ip);
+            },
+            IpAddr::V6(ip) => {
+                let in_prefix = self.ipv6_prefix
+                    .map(|prefix| Self::ipv6_with_iid(prefix, 0) == Self::ipv6_with_iid(ip, 0))
+                    .unwrap_or(false);
+                if !in_prefix {
+                    bail!("not in the IPv6 prefix of zone {}", self.bridge);
+                }
+                if self.allocations6.values().any(|&a| a == ip) || self.allocations6.contains_key(realm_name) {
+                    bail!("conflicts with another allocation");
+                }
+                self.allocations6.insert(realm_name.to_string(), ip);
             },
-            None => bail!("Could not parse line from network state file: {}", line),
         }
         Ok(())
     }
 
     fn write_state(&mut self) -> Result<()> {
-        let path = self.state_file_path();
-        let dir = path.parent().unwrap();
-        if !dir.exists() {
-            fs::create_dir_all(dir)
-                .map_err(|e| format_err!("failed to create directory {} for network allocation state file: {}", dir.display(), e))?;
-        }
-        let mut f = File::create(&path)
-            .map_err(|e| format_err!("failed to open network state file {} for writing: {}", path.display(), e))?;
+        let mut file = AllocationsFile::load();
+        file.update_zone(self);
+        file.write()
+    }
+}
 
-        for (realm,addr) in &self.allocations {
-            writeln!(f, "{}:{}", realm, addr)?;
-        }
-        Ok(())
+#[test]
+fn test_parse_hosts_entry() {
+    let entry = HostsEntry::parse("10.42.0.5 api.local api").unwrap();
+    assert_eq!(entry.address(), &HostsAddress::Ip("10.42.0.5".parse().unwrap()));
+    assert_eq!(entry.names(), &["api.local", "api"]);
+    assert_eq!(HostsEntry::parse("@host host.local").unwrap().address(), &HostsAddress::Host);
+    assert_eq!(HostsEntry::parse("@realm:db db.local").unwrap().address(), &HostsAddress::Realm("db".to_string()));
+
+    for bad in &["", "10.42.0.5", "api.local 10.42.0.5", "10.42.0.5 bad_name", "@realm: x", "@realm:-x x", "@gateway x"] {
+        assert!(HostsEntry::parse(bad).is_err(), "expected '{}' to be rejected", bad);
+    }
+}
+
+#[test]
+fn test_netns_veth_name() {
+    assert_eq!(NetnsManager::veth_name("main"), "vc-main");
+    assert_eq!(NetnsManager::veth_name("abcdefghijkl"), "vc-abcdefghijkl");
+    let long = NetnsManager::veth_name("development-realm");
+    assert_eq!(long.len(), 15);
+    assert_ne!(long, NetnsManager::veth_name("development-realm2"));
+    assert_eq!(NetnsManager::netns_name("main"), "citadel-main");
+}
+
+#[test]
+fn test_ipv6_allocation() {
+    let mut allocator = BridgeAllocator::new("test", "172.17.0.0".parse().unwrap(), 24);
+    assert!(allocator.set_ipv6_prefix("2001:db8::/64").is_err());
+    assert!(allocator.set_ipv6_prefix("fd17:c17a:de1::/48").is_err());
+    allocator.set_ipv6_prefix("fd17:c17a:de1::/64").unwrap();
+    assert_eq!(allocator.gateway6().unwrap(), "fd17:c17a:de1::1");
+
+    let prefix = allocator.ipv6_prefix.unwrap();
+    let a = allocator.find_free_ipv6(prefix, "main");
+    assert_eq!(a, allocator.find_free_ipv6(prefix, "main"));
+    assert_ne!(a, allocator.find_free_ipv6(prefix, "work"));
+    assert_eq!(&a.segments()[..4], &[0xfd17, 0xc17a, 0xde1, 0]);
+    assert!(u128::from(a) as u64 > 0xFFFF);
+
+    allocator.allocations6.insert("other".to_string(), a);
+    assert_ne!(allocator.find_free_ipv6(prefix, "main"), a);
+
+    allocator.insert_allocation("work", "fd17:c17a:de1::c8".parse().unwrap()).unwrap();
+    assert_eq!(allocator.allocations6.get("work"), Some(&"fd17:c17a:de1::c8".parse().unwrap()));
+    assert!(allocator.insert_allocation("elsewhere", "fd17:c17a:de2::c8".parse().unwrap()).is_err());
+}
+
+#[test]
+fn test_mac_addresses() {
+    let mut allocator = BridgeAllocator::new("clear", "172.17.0.0".parse().unwrap(), 24);
+    let main = allocator.mac_for("main");
+    assert_eq!(main, allocator.mac_for("main"));
+    assert_eq!(main, BridgeAllocator::new("clear", "172.17.0.0".parse().unwrap(), 24).mac_for("main"));
+    assert_ne!(main, BridgeAllocator::new("work", "172.18.0.0".parse().unwrap(), 24).mac_for("main"));
+    assert_ne!(main, allocator.mac_for("work"));
+
+    let octets = main.split(':').map(|b| u8::from_str_radix(b, 16).unwrap()).collect::<Vec<_>>();
+    assert_eq!(octets.len(), 6);
+    assert_eq!(octets[0] & 0x03, 0x02);
+    for n in 0..100 {
+        assert_eq!(BridgeAllocator::hashed_mac("clear", &format!("realm{}", n), 0)[0] & 0x03, 0x02);
+    }
+
+    // A realm whose hashed address is already taken in the zone is given the next candidate
+    let mut allocator = BridgeAllocator::new("clear", "172.17.0.0".parse().unwrap(), 24);
+    allocator.macs.insert("other".to_string(), BridgeAllocator::hashed_mac("clear", "main", 0));
+    let perturbed = allocator.mac_for("main");
+    assert_ne!(perturbed, main);
+    assert_eq!(perturbed, allocator.mac_for("main"));
+    let expected = BridgeAllocator::hashed_mac("clear", "main", 1);
+    assert_eq!(perturbed, expected.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":"));
+}
+
+#[test]
+fn test_parse_network_zones() {
+    let zones = NetworkZone::parse_zones(r#"
+        [zone.clear]
+        subnet = "10.77.0.0/24"
+
+        [zone.work]
+        bridge = "br-work"
+        subnet = "10.78.0.0/22"
+        gateway-offset = 10
+        reserved = ["200-254", "1000"]
+        ipv6-prefix = "fd17:c17a:de1::/64"
+    "#).unwrap();
+    assert_eq!(zones.len(), 2);
+    assert_eq!(zones[0], NetworkZone {
+        name: "clear".to_string(), bridge: "vz-clear".to_string(),
+        network: "10.77.0.0".parse().unwrap(), mask_size: 24,
+        gateway_offset: 1, reserved: vec![(200, 254)], ipv6_prefix: None,
+    });
+    assert_eq!((zones[1].bridge.as_str(), zones[1].mask_size), ("br-work", 22));
+
+    let mut allocator = BridgeAllocator::with_zone(&zones[1]);
+    assert_eq!(allocator.gateway(), "10.78.0.10");
+    let net = u32::from(allocator.network);
+    allocator.allocated.extend((1..10).map(|i| Ipv4Addr::from(net + i)));
+    assert_eq!(allocator.find_free_address(), Some("10.78.0.11".parse().unwrap()));
+    assert!(allocator.is_reserved(1000) && !allocator.is_reserved(999) && !allocator.is_reserved(255));
+    assert_eq!(allocator.gateway6(), Some("fd17:c17a:de1::1".to_string()));
+
+    for bad in &[
+        "[zone.a]\nsubnet = \"10.0.0.1/24\"",
+        "[zone.a]\nsubnet = \"10.0.0.0/8\"",
+        "[zone.a]\nsubnet = \"10.0.0.0/24\"\ngateway-offset = 255",
+        "[zone.a]\nsubnet = \"10.0.0.0/24\"\nreserved = [\"250-260\"]",
+        "[zone.a]\nsubnet = \"10.0.0.0/24\"\nreserved = [\"1-10\"]",
+        "[zone.a]\nsubnet = \"10.0.0.0/24\"\nreserved = [\"20-10\"]",
+        "[zone.a]\nbridge = \"bridge-name-too-long\"\nsubnet = \"10.0.0.0/24\"",
+        "[zone.a]\nsubnet = \"10.0.0.0/24\"\nipv6-prefix = \"2001:db8::/64\"",
+    ] {
+        assert!(NetworkZone::parse_zones(bad).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn test_network_zone_overlaps() {
+    let zone = |name: &str, subnet: &str| NetworkZone::new(name, subnet).unwrap();
+    assert!(zone("a", "10.0.0.0/16").overlaps(&zone("b", "10.0.5.0/24")));
+    assert!(zone("a", "10.0.5.0/24").overlaps(&zone("b", "10.0.0.0/16")));
+    assert!(!zone("a", "10.0.0.0/24").overlaps(&zone("b", "10.0.1.0/24")));
+    assert!(!zone("a", "10.0.0.0/23").overlaps(&zone("b", "10.0.2.0/24")));
+
+    assert!(NetworkZone::check_overlaps(&[zone("a", "10.0.0.0/24"), zone("b", "10.0.1.0/24")]).is_ok());
+    assert!(NetworkZone::check_overlaps(&[zone("a", "10.0.0.0/22"), zone("b", "10.0.3.0/24")]).is_err());
+    let mut b = zone("b", "10.0.1.0/24");
+    b.bridge = "vz-a".to_string();
+    assert!(NetworkZone::check_overlaps(&[zone("a", "10.0.0.0/24"), b]).is_err());
+
+    let mut network = NetworkConfig::new();
+    assert!(network.gateway("missing").unwrap_err().to_string().contains("network zone 'missing' is not defined"));
+    assert!(network.allocate_address_for("missing", "main").is_err());
+}
+
+#[test]
+fn test_retain_allocations() {
+    let mut allocator = BridgeAllocator::new("test", "172.17.0.0".parse().unwrap(), 24);
+    allocator.set_ipv6_prefix("fd17:c17a:de1::/64").unwrap();
+    for (name, addr) in &[("main", "172.17.0.2"), ("work", "172.17.0.3"), ("work", "fd17:c17a:de1::c8"), ("stale", "172.17.0.4"), ("stale6", "fd17:c17a:de1::5")] {
+        allocator.insert_allocation(name, addr.parse().unwrap()).unwrap();
+    }
+    let removed = allocator.retain(&mut |name: &str| name == "main" || name == "work");
+    assert_eq!(removed, vec!["stale", "stale6"]);
+    assert_eq!(allocator.allocations(), vec![
+        ("main".to_string(), "172.17.0.2".parse().unwrap()),
+        ("work".to_string(), "172.17.0.3".parse().unwrap()),
+        ("work".to_string(), "fd17:c17a:de1::c8".parse().unwrap()),
+    ]);
+    assert_eq!(allocator.find_free_address(), Some("172.17.0.5".parse().unwrap()));
+    assert_eq!(allocator.preferred_address("stale"), Some("172.17.0.4".parse().unwrap()));
+    assert!(allocator.retain(&mut |_: &str| true).is_empty());
+}
+
+#[test]
+fn test_restore_allocation() {
+    let addr = |s: &str| s.parse::<Ipv4Addr>().unwrap();
+    let mut allocator = BridgeAllocator::new("test", addr("10.5.0.0"), 16);
+    allocator.previous.insert("main".to_string(), addr("10.5.0.9"));
+
+    // The whole address is restored, not just the last octet
+    allocator.restore_allocation("main", addr("10.5.3.7")).unwrap();
+    assert_eq!(allocator.allocated_address("main"), Some(addr("10.5.3.7")));
+    assert_eq!(allocator.preferred_address("main"), Some(addr("10.5.3.7")));
+    assert!(allocator.previous.is_empty());
+
+    assert!(allocator.restore_allocation("work", addr("10.5.3.7")).is_err());
+    assert!(allocator.restore_allocation("work", addr("10.6.0.2")).is_err());
+    assert!(allocator.restore_allocation("main", addr("10.5.3.8")).is_err());
+    assert_eq!(allocator.allocated_address("work"), None);
+}
+
+#[test]
+fn test_reserved_allocation_conflicts() {
+    let addr = |s: &str| s.parse::<Ipv4Addr>().unwrap();
+
+    // A reserved address is never handed out dynamically
+    let mut allocator = BridgeAllocator::new("test", addr("172.17.0.0"), 24);
+    allocator.reserved = vec![(2, 3)];
+    assert_eq!(allocator.check_reserved("db", 2).unwrap(), addr("172.17.0.2"));
+    allocator.insert_allocation("db", "172.17.0.2".parse().unwrap()).unwrap();
+    assert_eq!(allocator.find_free_address(), Some(addr("172.17.0.4")));
+    assert!(allocator.check_reserved("web", 2).unwrap_err().to_string().contains("allocated to realm db"));
+    assert!(allocator.check_reserved("db", 2).is_ok());
+
+    // An address allocated dynamically before the reserved range was configured
+    let mut allocator = BridgeAllocator::new("test", addr("172.17.0.0"), 24);
+    allocator.insert_allocation("main", "172.17.0.2".parse().unwrap()).unwrap();
+    allocator.reserved = vec![(0, 255)];
+    assert!(allocator.check_reserved("db", 2).unwrap_err().to_string().contains("allocated to realm main"));
+    for &offset in &[0, 1, 255] {
+        assert!(allocator.check_reserved("db", offset).unwrap_err().to_string().contains("network, broadcast, or gateway"));
+    }
+    assert!(BridgeAllocator::new("test", addr("172.17.0.0"), 24).check_reserved("db", 10).is_err());
+
+    assert_eq!(allocator.subnet(), "172.17.0.0/24");
+    assert_eq!(allocator.utilization(), (1, 253));
+    assert_eq!(BridgeAllocator::new("test", addr("10.0.0.0"), 22).utilization(), (0, 1021));
+}
+
+#[test]
+fn test_reserved_offsets_in_22_zone() {
+    let zones = NetworkZone::parse_zones(r#"
+        [zone.work]
+        subnet = "10.78.0.0/22"
+        reserved = ["200-254", "1000-1022"]
+    "#).unwrap();
+    let mut allocator = BridgeAllocator::with_zone(&zones[0]);
+
+    // Offsets above 255 reach past the last octet of the network address
+    assert_eq!(allocator.preview_address_for("db", Some(1000)).unwrap(), "10.78.3.232/22");
+    allocator.insert_allocation("db", "10.78.3.232".parse().unwrap()).unwrap();
+    assert_eq!(allocator.check_reserved("web", 232).unwrap(), "10.78.0.232".parse::<Ipv4Addr>().unwrap());
+    assert!(allocator.check_reserved("web", 1000).unwrap_err().to_string().contains("allocated to realm db"));
+
+    let error = |offset: u32| allocator.reserved_address(offset).unwrap_err().to_string();
+    assert!(error(1023).contains("network, broadcast, or gateway"));
+    assert!(error(1024).contains("outside of subnet 10.78.0.0/22"));
+    assert!(error(u32::MAX).contains("outside of subnet"));
+    assert!(error(500).contains("not in a reserved range"));
+
+    let reservations = vec![Reservation::new("db", "work", 1000, false), Reservation::new("web", "work", 232, false)];
+    assert!(ReservationConflict::find(&reservations).is_empty());
+}
+
+#[test]
+fn test_allocations_file() {
+    let mut allocator = BridgeAllocator::new("clear", "172.17.0.0".parse().unwrap(), 24);
+    allocator.set_ipv6_prefix("fd17:c17a:de1::/64").unwrap();
+    allocator.insert_allocation("main", "172.17.0.2".parse().unwrap()).unwrap();
+    allocator.insert_allocation("main", "fd17:c17a:de1::c8".parse().unwrap()).unwrap();
+    allocator.previous.insert("work".to_string(), "172.17.0.3".parse().unwrap());
+    allocator.macs.insert("other".to_string(), BridgeAllocator::hashed_mac("clear", "main", 0));
+    let perturbed = allocator.mac_for("main");
+
+    let mut file = AllocationsFile::new();
+    file.update_zone(&allocator);
+    let json = serde_json::to_string_pretty(&file).unwrap();
+    assert!(json.contains("\"version\": 1"));
+    assert_eq!(AllocationsFile::parse(&json).unwrap(), file);
+    assert_eq!(AllocationsFile::parse(r#"{"version": 1}"#).unwrap(), AllocationsFile::new());
+    assert!(AllocationsFile::parse(r#"{"version": 2, "allocations": []}"#).is_err());
+    assert!(AllocationsFile::parse(r#"{"allocations": []}"#).is_err());
+
+    let entry = |zone: &str, realm: &str, addr: &str| AllocationEntry::new(zone, realm, addr.parse().unwrap());
+    file.allocations.extend(vec![
+        entry("clear", "gone", "172.17.0.9"),
+        entry("clear", "outside", "10.0.0.5"),
+        entry("clear", "gateway", "172.17.0.1"),
+        entry("clear", "duplicate", "172.17.0.2"),
+        entry("work", "other", "10.1.0.2"),
+    ]);
+    let mut loaded = BridgeAllocator::new("clear", "172.17.0.0".parse().unwrap(), 24);
+    loaded.set_ipv6_prefix("fd17:c17a:de1::/64").unwrap();
+    assert!(loaded.load_allocations(&file, |name| name != "gone"));
+    assert_eq!(loaded.allocations(), allocator.allocations());
+    assert_eq!(loaded.previous, allocator.previous);
+    assert_eq!(loaded.mac_for("main"), perturbed);
+
+    // The previous address of a realm is preferred unless another realm now holds it
+    assert_eq!(loaded.preferred_address("work"), Some("172.17.0.3".parse().unwrap()));
+    assert_eq!(loaded.preferred_address("main"), Some("172.17.0.2".parse().unwrap()));
+    assert_eq!(loaded.find_free_address(), Some("172.17.0.4".parse().unwrap()));
+    loaded.insert_allocation("new", "172.17.0.3".parse().unwrap()).unwrap();
+    assert_eq!(loaded.preferred_address("work"), None);
+}
+
+#[test]
+fn test_port_forwards() {
+    let f = PortForward::parse("tcp:8080:80").unwrap();
+    assert_eq!((f.protocol(), f.host_port(), f.realm_port()), (Protocol::Tcp, 8080, 80));
+    assert!(PortForward::parse("udp:5353:5353").is_ok());
+    assert!(PortForward::parse("sctp:1:1").is_err());
+    assert!(PortForward::parse("tcp:0:80").is_err());
+    assert!(PortForward::parse("tcp:8080").is_err());
+    assert!(PortForward::parse("tcp:8080:70000").is_err());
+
+    let line = "\t\tfib daddr type local tcp dport 8080 dnat to 172.17.0.5:80 comment \"realm:main\" # handle 4";
+    assert_eq!(ForwardRule::parse(line), Some(ForwardRule {
+        realm: "main".to_string(), protocol: Protocol::Tcp, host_port: 8080, handle: 4,
+    }));
+    assert_eq!(ForwardRule::parse("\tchain prerouting { # handle 1"), None);
+    let line = "\t\tfib daddr type local udp dport 5353 dnat ip to 172.17.0.5:5353 comment \"realm:work\" # handle 12";
+    assert_eq!(ForwardRule::parse(line).map(|r| (r.protocol, r.host_port, r.handle)), Some((Protocol::Udp, 5353, 12)));
+}
+
+#[test]
+fn test_firewall_ruleset() {
+    let clear = NetworkZone::new("clear", CLEAR_BRIDGE_NETWORK).unwrap();
+    let rules = Firewall::ruleset(&[clear]);
+    assert!(rules.starts_with("table inet citadel {\n    chain realm-forward {\n    }\n"));
+    assert!(rules.contains("    chain postrouting {\n        type nat hook postrouting priority 100;\n    }\n"));
+    assert!(rules.contains("    chain forward {\n        type filter hook forward priority 0;\n    }\n"));
+    let commands = rules.lines().filter(|line| !line.starts_with(' ') && !line.starts_with("table") && *line != "}").collect::<Vec<_>>();
+    assert_eq!(commands, vec![
+        "flush chain inet citadel forward",
+        "flush chain inet citadel prerouting",
+        "flush chain inet citadel output",
+        "flush chain inet citadel postrouting",
+        "add rule inet citadel postrouting ip saddr 172.17.0.0/24 oifname != \"vz-clear\" masquerade comment \"zone:clear\"",
+        "add rule inet citadel forward jump realm-forward",
+        "add rule inet citadel prerouting jump realm-prerouting",
+        "add rule inet citadel output jump realm-output",
+        "add rule inet citadel postrouting jump realm-postrouting",
+    ]);
+
+    let zones = NetworkZone::parse_zones(r#"
+        [zone.clear]
+        subnet = "172.17.0.0/24"
+        [zone.work]
+        bridge = "br-work"
+        subnet = "10.78.0.0/22"
+        [zone.vpn]
+        subnet = "10.79.0.0/24"
+    "#).unwrap();
+    let rules = Firewall::ruleset(&zones);
+    for line in &[
+        "add rule inet citadel forward iifname \"vz-clear\" oifname { \"vz-vpn\", \"br-work\" } drop comment \"zone:clear\"\n",
+        "add rule inet citadel forward iifname \"vz-vpn\" oifname { \"vz-clear\", \"br-work\" } drop comment \"zone:vpn\"\n",
+        "add rule inet citadel forward iifname \"br-work\" oifname { \"vz-clear\", \"vz-vpn\" } drop comment \"zone:work\"\n",
+        "add rule inet citadel postrouting ip saddr 10.78.0.0/22 oifname != { \"vz-clear\", \"vz-vpn\", \"br-work\" } masquerade comment \"zone:work\"\n",
+    ] {
+        assert!(rules.contains(line), "missing rule: {}", line);
     }
+    // Zone rules are applied before the per-realm rules in the hook chains
+    assert!(rules.find("comment \"zone:work\"").unwrap() < rules.find("jump realm-forward").unwrap());
+    assert_eq!(rules.matches(" drop ").count(), 3);
+    assert_eq!(rules.matches(" masquerade ").count(), 3);
+    // Installing the ruleset again does not touch the hook chains or other tables
+    assert!(!rules.contains("flush chain inet citadel realm-"));
+    assert!(!rules.contains("flush table") && !rules.contains("delete"));
+}
+
+#[test]
+fn test_kill_switch_rules() {
+    let rules = [
+        "\t\tip saddr 172.17.0.5 oifname \"wg0\" accept comment \"realm:main\" # handle 7",
+        "\t\tip saddr 172.17.0.5 drop comment \"realm:main\" # handle 8",
+        "\t\tip saddr 172.17.0.6 drop comment \"realm:work\" # handle 9",
+        "\tchain forward { # handle 1",
+    ].iter().flat_map(|line| KillSwitchRule::parse(line)).collect::<Vec<_>>();
+    assert_eq!(rules.len(), 3);
+    assert_eq!(rules[0], KillSwitchRule { realm: "main".to_string(), accept: true, handle: 7 });
+    assert_eq!(KillSwitch::state_from_rules("main", &rules), NetworkBlockState::VpnOnly);
+    assert_eq!(KillSwitch::state_from_rules("work", &rules), NetworkBlockState::Blocked);
+    assert_eq!(KillSwitch::state_from_rules("other", &rules), NetworkBlockState::Open);
+
+    let address: IpAddr = "172.17.0.5".parse().unwrap();
+    let script = KillSwitch::ruleset("main", &[address], "wg0", false, &[7, 8]);
+    let lines = script.lines().collect::<Vec<_>>();
+    assert_eq!(lines, vec![
+        "add rule inet citadel realm-forward ip saddr 172.17.0.5 drop comment \"realm:main\"",
+        "delete rule inet citadel realm-forward handle 7",
+        "delete rule inet citadel realm-forward handle 8",
+    ]);
+}
+
+#[test]
+fn test_poll_until() {
+    let mut calls = 0;
+    assert!(poll_until(Duration::from_secs(5), Duration::from_millis(1), || { calls += 1; calls == 3 }));
+    assert_eq!(calls, 3);
+
+    let start = Instant::now();
+    assert!(!poll_until(Duration::from_millis(20), Duration::from_millis(5), || false));
+    assert!(start.elapsed() >= Duration::from_millis(20));
+}
+
+#[test]
+fn test_reservation_conflicts() {
+    let reservations = vec![
+        Reservation::new("web", "clear", 210, false),
+        Reservation::new("db", "clear", 210, false),
+        Reservation::new("db2", "clear", 211, false),
+        Reservation::new("vpn-db", "vpn", 210, false),
+        Reservation::new("api", "clear", 210, false),
+    ];
+    let expected = vec![ReservationConflict {
+        zone: "clear".to_string(), offset: 210, holder: "api".to_string(), others: vec!["db".to_string(), "web".to_string()],
+    }];
+    assert_eq!(ReservationConflict::find(&reservations), expected);
+
+    // Detection does not depend on the order the realms were loaded in
+    let mut reversed = reservations;
+    reversed.reverse();
+    assert_eq!(ReservationConflict::find(&reversed), expected);
+    reversed.rotate_left(2);
+    assert_eq!(ReservationConflict::find(&reversed), expected);
+    assert_eq!(expected[0].message_for("web"), "reserved-ip 210 in network zone 'clear' is also reserved by realm 'api'");
+    assert_eq!(expected[0].message_for("api"), "reserved-ip 210 in network zone 'clear' is also reserved by realm 'db'");
+
+    // A running realm keeps its address
+    let reservations = vec![Reservation::new("api", "clear", 210, false), Reservation::new("web", "clear", 210, true)];
+    let conflicts = ReservationConflict::find(&reservations);
+    assert_eq!(conflicts[0].holder(), "web");
+    assert_eq!(conflicts[0].others(), &["api".to_string()]);
+    assert!(ReservationConflict::find(&[]).is_empty());
 }
@@ -75,7 +75,7 @@ impl RealmOverlay {
             OverlayType::Storage => self.remove_btrfs(&base)?,
             _ => unreachable!(),
         };
-        Ok(lower?)
+        lower
     }
 
     pub fn exists(&self) -> bool {
@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{Exec, Mounts, Realm, Realms, Result};
+use crate::realm::resources::StartThresholds;
+use crate::realm::snapshot::disk_usage;
+
+const CHATTR_PATH: &str = "/usr/bin/chattr";
+const SETQUOTA_PATH: &str = "/usr/sbin/setquota";
+const XFS_QUOTA_PATH: &str = "/usr/sbin/xfs_quota";
+
+/// File in the realm directory holding the quota project id of the realm home directory
+const PROJECT_ID_FILE: &str = "home-project-id";
+
+/// Project ids assigned to realm home directories start from this value
+const FIRST_PROJECT_ID: u32 = 10000;
+
+// Project quota type for quotactl(2)
+const PRJQUOTA: libc::c_int = 2;
+
+const MIB: u64 = 1024 * 1024;
+
+/// Error returned when a realm is not started because its home directory is
+/// over the quota on a filesystem without project quota support.
+#[derive(Debug,Fail)]
+#[fail(display = "quota exceeded: home directory of realm {} uses {} MiB of its {} MiB quota", realm, usage, quota)]
+pub struct QuotaExceededError {
+    realm: String,
+    // MiB
+    usage: u64,
+    quota: u64,
+}
+
+/// How the `home-quota` of a realm is enforced on the filesystem of its home directory
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum QuotaMode {
+    /// Project quota on ext4, set with setquota
+    Ext4,
+    /// Project quota on xfs, set with xfs_quota
+    Xfs,
+    /// The filesystem has no project quota support, so the realm is only
+    /// refused to start once its home directory is over the quota.
+    Soft,
+}
+
+impl QuotaMode {
+    /// Choose how quotas are enforced on a filesystem of type `fstype` mounted with `options`.
+    /// Project quotas are only used when the filesystem is mounted with project quota accounting.
+    pub fn for_filesystem(fstype: &str, options: &HashMap<&str, &str>) -> QuotaMode {
+        let project_quota = options.contains_key("prjquota") || options.contains_key("pquota");
+        match fstype {
+            "ext4" if project_quota => QuotaMode::Ext4,
+            "xfs" if project_quota => QuotaMode::Xfs,
+            _ => QuotaMode::Soft,
+        }
+    }
+
+    pub fn is_hard(self) -> bool {
+        self != QuotaMode::Soft
+    }
+
+    pub fn to_str_value(self) -> &'static str {
+        match self {
+            QuotaMode::Ext4 | QuotaMode::Xfs => "hard",
+            QuotaMode::Soft => "soft",
+        }
+    }
+
+    /// Return `true` if a realm with a home directory using `usage` bytes must
+    /// not be started because it is over `quota` and the quota is not enforced
+    /// by the filesystem.
+    pub fn refuse_start(self, usage: u64, quota: u64) -> bool {
+        !self.is_hard() && usage >= quota
+    }
+}
+
+/// Parse a `home-quota` value such as `20G` into a number of bytes
+pub fn parse_quota(size: &str) -> Result<u64> {
+    match StartThresholds::parse_size(size)? {
+        0 => bail!("quota must be larger than 0"),
+        quota => Ok(quota),
+    }
+}
+
+// Filesystem containing the realm home directory as listed in /proc/mounts
+struct HomeMount {
+    source: String,
+    target: PathBuf,
+    mode: QuotaMode,
+}
+
+impl HomeMount {
+    // The mount with the longest target which is a parent of `path`
+    fn find(path: &Path) -> Result<HomeMount> {
+        let mounts = Mounts::load()?;
+        mounts.mounts()
+            .filter(|m| path.starts_with(m.target_path()))
+            .max_by_key(|m| m.target().len())
+            .map(|m| HomeMount {
+                source: m.source().to_string(),
+                target: m.target_path().to_path_buf(),
+                mode: QuotaMode::for_filesystem(m.fstype(), &m.options()),
+            })
+            .ok_or_else(|| format_err!("no filesystem found for {}", path.display()))
+    }
+}
+
+///
+/// The `home-quota` of a realm. Where the filesystem supports project quotas
+/// the home directory is assigned a project id and the quota is a hard limit
+/// enforced by the kernel. Otherwise the realm is refused to start once its
+/// home directory has grown over the quota.
+///
+/// An encrypted home directory is not limited since its size is already fixed
+/// by its container.
+///
+pub struct HomeQuota<'a> {
+    realm: &'a Realm,
+}
+
+impl <'a> HomeQuota<'a> {
+    pub fn new(realm: &'a Realm) -> Self {
+        HomeQuota { realm }
+    }
+
+    /// The configured quota in bytes, or `None` if no quota is configured
+    pub fn limit(&self) -> Result<Option<u64>> {
+        if self.realm.config().encrypted_home() {
+            return Ok(None);
+        }
+        match self.realm.config().home_quota() {
+            Some(size) => Ok(Some(parse_quota(size)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn home(&self) -> PathBuf {
+        self.realm.base_path_file("home")
+    }
+
+    /// How the quota is enforced on the filesystem of the home directory
+    pub fn mode(&self) -> Result<QuotaMode> {
+        Ok(HomeMount::find(&self.home())?.mode)
+    }
+
+    /// Space in bytes used by the home directory, read from the project quota
+    /// if one has been set and otherwise by adding up the size of every file.
+    pub fn usage(&self) -> Result<u64> {
+        let mount = HomeMount::find(&self.home())?;
+        match self.assigned_project_id()? {
+            Some(id) if mount.mode.is_hard() => Self::project_usage(&mount, id),
+            _ => Ok(disk_usage(&self.home())),
+        }
+    }
+
+    /// Check the quota before the realm is started. Hard quotas are set again in
+    /// case the quota was changed while the realm was stopped, and a realm whose
+    /// home directory is over a soft quota is refused to start.
+    pub fn check_start(&self) -> Result<()> {
+        let quota = match self.limit()? {
+            Some(quota) => quota,
+            None => return self.apply(),
+        };
+        let mount = HomeMount::find(&self.home())?;
+        if mount.mode.is_hard() {
+            return self.apply_to(&mount, Some(quota));
+        }
+        let usage = disk_usage(&self.home());
+        if mount.mode.refuse_start(usage, quota) {
+            return Err(QuotaExceededError {
+                realm: self.realm.name().to_string(),
+                usage: usage / MIB,
+                quota: quota / MIB,
+            }.into());
+        }
+        Ok(())
+    }
+
+    /// Set the project quota of the home directory to the configured quota, or
+    /// remove the limit if the quota was removed from the config. Nothing is done
+    /// on a filesystem without project quota support.
+    pub fn apply(&self) -> Result<()> {
+        let quota = self.limit()?;
+        if quota.is_none() && self.assigned_project_id()?.is_none() {
+            return Ok(());
+        }
+        let mount = HomeMount::find(&self.home())?;
+        self.apply_to(&mount, quota)
+    }
+
+    fn apply_to(&self, mount: &HomeMount, quota: Option<u64>) -> Result<()> {
+        if !mount.mode.is_hard() {
+            return Ok(());
+        }
+        let id = match self.assigned_project_id()? {
+            Some(id) => id,
+            None if quota.is_some() => self.assign_project_id(mount)?,
+            None => return Ok(()),
+        };
+        // A limit of 0 means no limit
+        let kib = quota.map(|q| q.div_ceil(1024)).unwrap_or(0);
+        match mount.mode {
+            QuotaMode::Ext4 => Exec::new(SETQUOTA_PATH)
+                .args(["-P", &id.to_string(), "0", &kib.to_string(), "0", "0"])
+                .arg(mount.target.display().to_string())
+                .capture()?
+                .check(),
+            QuotaMode::Xfs => Exec::new(XFS_QUOTA_PATH)
+                .args(["-x", "-c", &format!("limit -p bhard={}k {}", kib, id)])
+                .arg(mount.target.display().to_string())
+                .capture()?
+                .check(),
+            QuotaMode::Soft => Ok(()),
+        }
+    }
+
+    fn project_id_path(&self) -> PathBuf {
+        self.realm.base_path_file(PROJECT_ID_FILE)
+    }
+
+    fn assigned_project_id(&self) -> Result<Option<u32>> {
+        read_project_id(&self.project_id_path())
+    }
+
+    // Choose a project id not used by another realm, and assign it to the home
+    // directory and everything in it. New files inherit the id of their directory.
+    fn assign_project_id(&self, mount: &HomeMount) -> Result<u32> {
+        let id = next_project_id(Path::new(Realms::BASE_PATH))?;
+        let home = self.home().display().to_string();
+        info!("Assigning quota project id {} to home directory of realm {}", id, self.realm.name());
+        match mount.mode {
+            QuotaMode::Ext4 => Exec::new(CHATTR_PATH)
+                .args(["-R", "+P", "-p", &id.to_string(), &home])
+                .capture()?
+                .check()?,
+            QuotaMode::Xfs => Exec::new(XFS_QUOTA_PATH)
+                .args(["-x", "-c", &format!("project -s -p {} {}", home, id)])
+                .arg(mount.target.display().to_string())
+                .capture()?
+                .check()?,
+            QuotaMode::Soft => bail!("{} has no project quota support", mount.target.display()),
+        }
+        fs::write(self.project_id_path(), format!("{}\n", id))?;
+        Ok(id)
+    }
+
+    fn project_usage(mount: &HomeMount, id: u32) -> Result<u64> {
+        let device = CString::new(mount.source.as_str())?;
+        unsafe {
+            let mut quota: libc::dqblk = std::mem::zeroed();
+            let cmd = libc::QCMD(libc::Q_GETQUOTA, PRJQUOTA);
+            if libc::quotactl(cmd, device.as_ptr(), id as libc::c_int, &mut quota as *mut libc::dqblk as *mut libc::c_char) == -1 {
+                let err = io::Error::last_os_error();
+                bail!("failed to read quota of project {} on {}: {}", id, mount.source, err);
+            }
+            Ok(quota.dqb_curspace)
+        }
+    }
+}
+
+fn read_project_id(path: &Path) -> Result<Option<u32>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content = fs::read_to_string(path)?;
+    let id = content.trim().parse::<u32>()
+        .map_err(|_| format_err!("invalid project id in {}", path.display()))?;
+    Ok(Some(id))
+}
+
+// One more than the largest project id assigned to a realm in `realms_dir`
+fn next_project_id(realms_dir: &Path) -> Result<u32> {
+    let mut next = FIRST_PROJECT_ID;
+    for entry in fs::read_dir(realms_dir)? {
+        let path = entry?.path().join(PROJECT_ID_FILE);
+        if let Ok(Some(id)) = read_project_id(&path) {
+            next = next.max(id + 1);
+        }
+    }
+    Ok(next)
+}
+
+#[test]
+fn test_home_quota() {
+    const GIB: u64 = 1024 * MIB;
+    assert_eq!(parse_quota("20G").unwrap(), 20 * GIB);
+    assert_eq!(parse_quota("512M").unwrap(), 512 * MIB);
+    for bad in &["0", "", "20GB", "-1G", "twenty"] {
+        assert!(parse_quota(bad).is_err(), "{}", bad);
+    }
+
+    let options = |s: &'static str| s.split(',').map(|o| (o, "")).collect::<HashMap<_, _>>();
+    assert_eq!(QuotaMode::for_filesystem("ext4", &options("rw,relatime,prjquota")), QuotaMode::Ext4);
+    assert_eq!(QuotaMode::for_filesystem("xfs", &options("rw,attr2,inode64,prjquota")), QuotaMode::Xfs);
+    assert_eq!(QuotaMode::for_filesystem("xfs", &options("rw,pquota")), QuotaMode::Xfs);
+    assert_eq!(QuotaMode::for_filesystem("ext4", &options("rw,relatime")), QuotaMode::Soft);
+    assert_eq!(QuotaMode::for_filesystem("btrfs", &options("rw,prjquota")), QuotaMode::Soft);
+
+    // Only a soft quota refuses to start a realm, and only once it is reached
+    assert!(QuotaMode::Soft.refuse_start(20 * GIB, 20 * GIB));
+    assert!(!QuotaMode::Soft.refuse_start(20 * GIB - 1, 20 * GIB));
+    assert!(!QuotaMode::Ext4.refuse_start(30 * GIB, 20 * GIB));
+    assert!(!QuotaMode::Xfs.refuse_start(30 * GIB, 20 * GIB));
+
+    let error = QuotaExceededError { realm: "main".to_string(), usage: 2048, quota: 1024 };
+    assert_eq!(error.to_string(), "quota exceeded: home directory of realm main uses 2048 MiB of its 1024 MiB quota");
+
+    let realms = crate::util::TempDir::new("quota-test").unwrap();
+    fs::create_dir_all(realms.join("realm-main")).unwrap();
+    fs::create_dir_all(realms.join("realm-work")).unwrap();
+    assert_eq!(next_project_id(&realms).unwrap(), FIRST_PROJECT_ID);
+    fs::write(realms.join("realm-work").join(PROJECT_ID_FILE), "10004\n").unwrap();
+    assert_eq!(next_project_id(&realms).unwrap(), 10005);
+    assert_eq!(read_project_id(&realms.join("realm-work").join(PROJECT_ID_FILE)).unwrap(), Some(10004));
+    assert_eq!(read_project_id(&realms.join("realm-main").join(PROJECT_ID_FILE)).unwrap(), None);
+}
@@ -3,15 +3,18 @@ use std::fs;
 use std::path::{PathBuf, Path};
 use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak};
 use std::os::unix::fs::MetadataExt;
+use std::time::{Duration, Instant, SystemTime};
 
 
 use super::overlay::RealmOverlay;
 use super::config::{RealmConfig,GLOBAL_CONFIG,OverlayType};
 use super::realms::Realms;
 use super::systemd::Systemd;
+use super::rootfs::{check_rootfs_tree,ExpectedImage};
+use super::defaults::DEFAULTS_KEYS_FILE;
 
 use crate::realmfs::{Mountpoint, Activation};
-use crate::{symlink, util, Result, RealmFS, CommandLine, RealmManager};
+use crate::{symlink, util, Result, RealmFS, CommandLine, RealmManager, Mounts};
 
 
 const MAX_REALM_NAME_LEN:usize = 128;
@@ -25,14 +28,13 @@ enum RealmActiveState {
     Failed,
 }
 
-impl RealmActiveState {
-    fn from_sysctl_output(line: &str) -> Self {
-        match line {
-            "active" => RealmActiveState::Active,
-            "inactive" => RealmActiveState::Inactive,
-            _ => RealmActiveState::Unknown,
-        }
-    }
+// Controls whether the RealmStarted event is sent when the realm machine is
+// registered, or held back until RealmManager has finished starting the realm.
+#[derive(Clone,Copy,PartialEq)]
+enum StartedEvent {
+    Immediate,
+    Hold,
+    Deferred,
 }
 
 struct Inner {
@@ -40,6 +42,14 @@ struct Inner {
     timestamp: i64,
     leader_pid: Option<u32>,
     active: RealmActiveState,
+    frozen: bool,
+    started_event: StartedEvent,
+    // Wall clock and monotonic time at which the running realm was started
+    started: Option<(SystemTime, Instant)>,
+    // Reserved address conflict with another realm found by RealmManager
+    address_conflict: Option<String>,
+    // Home directory is being restored from a snapshot, realm must not start
+    restoring: bool,
 }
 
 impl Inner {
@@ -49,6 +59,11 @@ impl Inner {
             timestamp: 0,
             leader_pid: None,
             active: RealmActiveState::Unknown,
+            frozen: false,
+            started_event: StartedEvent::Immediate,
+            started: None,
+            address_conflict: None,
+            restoring: false,
         }
     }
 }
@@ -84,11 +99,11 @@ impl Realm {
         }
     }
 
-    fn inner(&self) -> RwLockReadGuard<Inner> {
+    fn inner(&self) -> RwLockReadGuard<'_, Inner> {
         self.inner.read().unwrap()
     }
 
-    fn inner_mut(&self) -> RwLockWriteGuard<Inner> {
+    fn inner_mut(&self) -> RwLockWriteGuard<'_, Inner> {
         self.inner.write().unwrap()
     }
 
@@ -108,14 +123,131 @@ impl Realm {
         self.set_active_state(state);
     }
 
+    /// Returns `true` if the processes of this realm have been frozen with `RealmManager::freeze_realm()`
+    pub fn is_frozen(&self) -> bool {
+        self.inner().frozen
+    }
+
+    pub(crate) fn set_frozen(&self, frozen: bool) {
+        self.inner_mut().frozen = frozen;
+    }
+
+    /// Hold back the RealmStarted event until `release_started_event()` is called.
+    pub(crate) fn hold_started_event(&self) {
+        self.inner_mut().started_event = StartedEvent::Hold;
+    }
+
+    /// Returns `true` if the RealmStarted event is being held and should not be
+    /// sent now. The event is then sent by the caller of `release_started_event()`.
+    pub(crate) fn defer_started_event(&self) -> bool {
+        let mut inner = self.inner_mut();
+        if inner.started_event == StartedEvent::Immediate {
+            return false;
+        }
+        inner.started_event = StartedEvent::Deferred;
+        true
+    }
+
+    /// Stop holding back the RealmStarted event and return `true` if an event
+    /// was deferred while it was held and should be sent now.
+    pub(crate) fn release_started_event(&self) -> bool {
+        let mut inner = self.inner_mut();
+        let deferred = inner.started_event == StartedEvent::Deferred;
+        inner.started_event = StartedEvent::Immediate;
+        deferred
+    }
+
+    /// Mark the home directory of the realm as being restored from a snapshot
+    /// so that starting the realm is refused until `set_restoring(false)`.
+    pub(crate) fn set_restoring(&self, restoring: bool) {
+        self.inner_mut().restoring = restoring;
+    }
+
+    pub fn is_restoring(&self) -> bool {
+        self.inner().restoring
+    }
+
+    /// Record that the realm was started `elapsed` ago
+    pub(crate) fn set_started(&self, elapsed: Duration) {
+        let now = Instant::now();
+        let instant = now.checked_sub(elapsed).unwrap_or(now);
+        let time = SystemTime::now().checked_sub(elapsed).unwrap_or_else(SystemTime::now);
+        self.inner_mut().started = Some((time, instant));
+    }
+
+    /// Record that the realm was started at `time`, as saved by a previous
+    /// realmsd process when it handed over to a new one.
+    pub fn restore_started_at(&self, time: SystemTime) {
+        let elapsed = SystemTime::now().duration_since(time).unwrap_or_default();
+        self.set_started(elapsed);
+    }
+
+    // Realms which were already running when the manager was created have no
+    // start time recorded, so it is recovered from the realm service unit.
+    fn start_time(&self) -> Option<(SystemTime, Instant)> {
+        if !self.is_active() {
+            return None;
+        }
+        if let Some(started) = self.inner().started {
+            return Some(started);
+        }
+        match Systemd::active_enter_elapsed(self) {
+            Ok(Some(elapsed)) => self.set_started(elapsed),
+            Ok(None) => return None,
+            Err(e) => {
+                warn!("Failed to determine start time of realm {}: {}", self.name(), e);
+                return None;
+            }
+        }
+        self.inner().started
+    }
+
+    /// Time at which the realm was started, or `None` if it is not running
+    pub fn started_at(&self) -> Option<SystemTime> {
+        self.start_time().map(|(time, _)| time)
+    }
+
+    /// How long the realm has been running, or `None` if it is not running.
+    /// Measured with the monotonic clock so that changes to the system time do
+    /// not affect it.
+    pub fn uptime(&self) -> Option<Duration> {
+        self.start_time().map(|(_, instant)| instant.elapsed())
+    }
+
     pub fn is_system(&self) -> bool {
         self.config().system_realm()
     }
 
+    /// Return the error message if the config file of this realm could not be
+    /// loaded or the config conflicts with the config of another realm
+    pub fn config_error(&self) -> Option<String> {
+        self.config().error().map(|s| s.to_string())
+            .or_else(|| self.inner().address_conflict.clone())
+    }
+
+    /// Names of the config keys which were set from /etc/citadel/realm-defaults.conf
+    /// when this realm was created.
+    pub fn config_keys_from_defaults(&self) -> Vec<String> {
+        fs::read_to_string(self.base_path_file(DEFAULTS_KEYS_FILE))
+            .map(|content| content.lines().map(|line| line.trim().to_string()).filter(|key| !key.is_empty()).collect())
+            .unwrap_or_default()
+    }
+
+    /// Set or clear the reserved address conflict reported by `config_error()`.
+    /// Returns `true` if the conflict changed.
+    pub(crate) fn set_address_conflict(&self, conflict: Option<String>) -> bool {
+        let mut inner = self.inner_mut();
+        let changed = inner.address_conflict != conflict;
+        inner.address_conflict = conflict;
+        changed
+    }
+
     fn set_active_state(&self, state: RealmActiveState) {
         let mut inner = self.inner_mut();
         if state != RealmActiveState::Active {
             inner.leader_pid = None;
+            inner.frozen = false;
+            inner.started = None;
         }
         inner.active = state;
     }
@@ -130,10 +262,6 @@ impl Realm {
         }
     }
 
-    pub fn set_active_from_systemctl(&self, output: &str) {
-        self.set_active_state(RealmActiveState::from_sysctl_output(output));
-    }
-
     pub fn name(&self) -> &str {
         &self.name
     }
@@ -227,6 +355,33 @@ impl Realm {
         Ok(rootfs)
     }
 
+    /// Check that `rootfs` returned by `setup_rootfs()` can still be used to
+    /// start the realm. If the RealmFS mountpoint is no longer mounted it is
+    /// mounted again, but if it belongs to an image other than the current
+    /// version of the configured RealmFS an error is returned.
+    pub(crate) fn verify_rootfs(&self, rootfs: &Path) -> Result<()> {
+        if let Some(mountpoint) = self.realmfs_mountpoint() {
+            let config = self.config();
+            let name = config.realmfs();
+            let realmfs = self.manager().realmfs_by_name(name)
+                .ok_or_else(|| format_err!("RealmFS {} of realm {} no longer exists", name, self.name()))?;
+            ExpectedImage::for_realmfs(&realmfs).check_mountpoint(self.name(), &mountpoint)?;
+            if !Mounts::is_target_mounted(mountpoint.path())? {
+                self.remount_realmfs(&realmfs, &mountpoint)?;
+            }
+        }
+        check_rootfs_tree(self.name(), rootfs)
+    }
+
+    fn remount_realmfs(&self, realmfs: &RealmFS, mountpoint: &Mountpoint) -> Result<()> {
+        let activation = realmfs.activation()
+            .filter(|activation| activation.is_mountpoint(mountpoint))
+            .ok_or_else(|| format_err!("RealmFS {} is not mounted at {} and is no longer activated", realmfs.name(), mountpoint))?;
+        warn!("RealmFS mountpoint {} for realm {} is not mounted, mounting it again", mountpoint, self.name());
+        activation.remount()
+            .map_err(|e| format_err!("failed to mount RealmFS {} at {}: {}", realmfs.name(), mountpoint, e))
+    }
+
     fn choose_mountpoint<'a>(&self, writeable: bool, activation: &'a Activation) -> Result<&'a Mountpoint> {
         if !writeable {
             Ok(activation.mountpoint())
@@ -331,7 +486,9 @@ impl Realm {
     ///
     /// The base path of a realm with name 'main' would be:
     ///
-    ///     /realms/realm-main
+    /// ```text
+    /// /realms/realm-main
+    /// ```
     ///
     pub fn base_path(&self) -> PathBuf {
         Path::new(Realms::BASE_PATH).join(self.dir_name())
@@ -346,7 +503,9 @@ impl Realm {
     ///
     /// The run path of a realm with name 'main' would be:
     ///
-    ///     /run/citadel/realms/realm-main
+    /// ```text
+    /// /run/citadel/realms/realm-main
+    /// ```
     ///
     pub fn run_path(&self) -> PathBuf {
         Path::new(Realms::RUN_PATH).join(self.dir_name())
@@ -357,6 +516,19 @@ impl Realm {
         self.run_path().join(name)
     }
 
+    /// Return the directory containing the info document of this realm, which
+    /// is bind mounted read-only into the realm at /run/citadel/realm-info.
+    pub fn info_path(&self) -> PathBuf {
+        self.run_path_file("info")
+    }
+
+    /// Return the path of the socket on which realmsd relays the clipboard of
+    /// this realm. The directory containing the socket is bind mounted read-only
+    /// into the realm at /run/user/host/clipboard.
+    pub fn clipboard_socket(&self) -> PathBuf {
+        self.run_path_file("clipboard").join("clipboard.sock")
+    }
+
     /// Return `Arc<RealmConfig>` containing the configuration of this realm.
     /// If the config file has not yet been loaded from disk, it is lazy loaded
     /// the first time this method is called.
@@ -2,9 +2,11 @@ use std::collections::{HashMap, HashSet};
 use std::path::{Path,PathBuf};
 use std::fs;
 
-use crate::{Realm, Result, symlink, RealmManager,FileLock};
+use crate::{Realm, RealmConfig, Result, symlink, RealmManager,FileLock};
 use std::sync::{Arc, Weak};
 use super::create::RealmCreateDestroy;
+use super::defaults::RealmDefaults;
+use super::manifest::ManifestTrust;
 use crate::realm::systemd::Systemd;
 
 struct RealmMapList {
@@ -86,6 +88,16 @@ impl Realms {
     }
 
 
+    /// Sorted names of the realms in the realms directory. Unlike `load()`
+    /// this does not take the realms lock or ask systemd which realms are running.
+    pub fn realm_names() -> Result<Vec<String>> {
+        let mut names = Self::all_realms(false)?.iter()
+            .map(|r| r.name().to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        Ok(names)
+    }
+
     fn all_realms(mark_active: bool) -> Result<Vec<Realm>> {
         let mut v = Vec::new();
         for entry in fs::read_dir(Realms::BASE_PATH)? {
@@ -95,7 +107,7 @@ impl Realms {
             }
         }
         if mark_active {
-            Realms::mark_active_realms(&mut v)?;
+            Realms::mark_active_realms(&v)?;
         }
         Ok(v)
     }
@@ -125,14 +137,12 @@ impl Realms {
     }
 
     // Determine which realms are running with a single 'systemctl is-active' call.
-    fn mark_active_realms(realms: &mut Vec<Realm>) -> Result<()> {
-
-        let output = Systemd::are_realms_active(realms)?;
+    fn mark_active_realms(realms: &[Realm]) -> Result<()> {
 
-        // process the lines of output together with the list of realms with .zip()
-        realms.iter_mut()
-            .zip(output.lines())
-            .for_each(|(r,line)| r.set_active_from_systemctl(line));
+        let active = Systemd::are_realms_active(realms)?;
+        realms.iter()
+            .zip(active)
+            .for_each(|(r, active)| r.set_active(active));
 
         Ok(())
     }
@@ -227,7 +237,7 @@ impl Realms {
         FileLock::acquire(lockpath)
     }
 
-    pub fn create_realm(&mut self, name: &str) -> Result<Realm> {
+    pub fn create_realm(&mut self, name: &str, config: &RealmConfig, defaults: &RealmDefaults) -> Result<Realm> {
         let _lock = Self::realmslock()?;
 
         if !Realm::is_valid_name(name) {
@@ -236,11 +246,44 @@ impl Realms {
             bail!("A realm with name '{}' already exists", name);
         }
 
-        RealmCreateDestroy::new(name).create()?;
+        RealmCreateDestroy::new(name).create(config, defaults)?;
 
         Ok(self.add_realm(name))
     }
 
+    /// Create the directory of a new realm `name` with a copy of the configuration
+    /// of `source`. Settings which identify a single realm on the network such as
+    /// the reserved ip address and port forwards are not copied. The set of realms
+    /// is not borrowed while the home directory is copied, the new realm is added
+    /// to it afterwards with `add_created_realm()`.
+    pub fn create_clone(source: &Realm, name: &str, clone_home: bool, progress: &mut dyn FnMut(usize)) -> Result<()> {
+        let _lock = Self::realmslock()?;
+
+        if !Realm::is_valid_name(name) {
+            bail!("'{}' is not a valid realm name. Only letters, numbers and dash '-' symbol allowed in name. First character must be a letter", name);
+        }
+
+        let mut config = (*source.config()).clone();
+        config.reserved_ip = None;
+        config.port_forwards = None;
+
+        RealmCreateDestroy::new(name).create_clone(source.name(), &config, clone_home, progress)
+    }
+
+    /// Add the realm `name` after its directory was created by `create_clone()`
+    /// or `import()`.
+    pub fn add_created_realm(&mut self, name: &str) -> Realm {
+        self.add_realm(name)
+    }
+
+    /// Create the directory of a new realm from the realm export archive at `path`
+    /// and return the name of the realm, which is recorded in the archive manifest.
+    /// As with `create_clone()` the new realm is added with `add_created_realm()`.
+    pub fn import(path: &Path, trust: &ManifestTrust, allow_unsigned: bool) -> Result<String> {
+        let _lock = Self::realmslock()?;
+        RealmCreateDestroy::import(path, trust, allow_unsigned)
+    }
+
     pub fn delete_realm(&mut self, name: &str, save_home: bool) -> Result<()> {
         let _lock = Self::realmslock()?;
 
@@ -336,7 +379,9 @@ impl Realms {
     ///
     /// The current realm is determined by reading symlink at path:
     ///
-    ///     /run/citadel/realms/current/current.realm
+    /// ```text
+    /// /run/citadel/realms/current/current.realm
+    /// ```
     ///
     /// If the symlink exists it will point to run path of the current realm.
     ///
@@ -349,7 +394,9 @@ impl Realms {
     /// Whether or not a realm has been marked as current is determined
     /// by checking for the existence of the symlink at path:
     ///
-    ///     /run/citadel/realms/current/current.realm
+    /// ```text
+    /// /run/citadel/realms/current/current.realm
+    /// ```
     ///
     pub fn is_some_realm_current() -> bool {
         Self::current_realm_symlink().exists()
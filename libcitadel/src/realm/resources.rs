@@ -0,0 +1,151 @@
+use std::fs;
+
+use crate::{Result, util};
+
+const MEMINFO_PATH: &str = "/proc/meminfo";
+
+/// Filesystem checked for free space before a realm is started
+const STORAGE_PATH: &str = "/storage";
+
+const MIB: u64 = 1024 * 1024;
+
+/// Error returned when a realm is not started because available memory or
+/// free storage is below the configured minimum.
+#[derive(Debug,Fail)]
+#[fail(display = "refusing to start realm {} because {}", realm, shortfall)]
+pub struct LowResourcesError {
+    realm: String,
+    shortfall: String,
+}
+
+///
+/// Minimum available memory and free space on /storage for starting a realm,
+/// and whether a realm is refused to start or only a warning is logged when
+/// the host has less. A minimum of 0 is not checked.
+///
+#[derive(Clone,Copy,Debug,Default,PartialEq)]
+pub struct StartThresholds {
+    min_memory: u64,
+    min_storage: u64,
+    refuse: bool,
+}
+
+impl StartThresholds {
+    pub fn new(min_memory: u64, min_storage: u64, refuse: bool) -> Self {
+        StartThresholds { min_memory, min_storage, refuse }
+    }
+
+    /// Parse a size such as `512M` or `2G` into a number of bytes. A plain
+    /// number is a number of bytes and the suffixes K, M, G and T are powers of 1024.
+    pub fn parse_size(size: &str) -> Result<u64> {
+        let size = size.trim();
+        let (digits, multiplier) = match size.chars().last() {
+            Some('K') => (&size[..size.len() - 1], 1024),
+            Some('M') => (&size[..size.len() - 1], MIB),
+            Some('G') => (&size[..size.len() - 1], MIB * 1024),
+            Some('T') => (&size[..size.len() - 1], MIB * 1024 * 1024),
+            _ => (size, 1),
+        };
+        if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+            bail!("invalid size '{}'. Expected a number with an optional K, M, G or T suffix such as '512M'", size);
+        }
+        digits.parse::<u64>().ok()
+            .and_then(|n| n.checked_mul(multiplier))
+            .ok_or_else(|| format_err!("size '{}' is too large", size))
+    }
+
+    pub fn refuse(&self) -> bool {
+        self.refuse
+    }
+
+    /// Describe which of `memory` and `storage` in bytes are below the thresholds,
+    /// or return `None` if there is enough of both.
+    pub fn shortfall(&self, memory: u64, storage: u64) -> Option<String> {
+        let mut low = Vec::new();
+        if memory < self.min_memory {
+            low.push(format!("available memory {} MiB is below the minimum of {} MiB", memory / MIB, self.min_memory / MIB));
+        }
+        if storage < self.min_storage {
+            low.push(format!("free space on {} {} MiB is below the minimum of {} MiB", STORAGE_PATH, storage / MIB, self.min_storage / MIB));
+        }
+        if low.is_empty() {
+            None
+        } else {
+            Some(low.join(" and "))
+        }
+    }
+
+    /// Check the memory and storage currently available on the host against the
+    /// thresholds. A value which cannot be read is not checked.
+    pub(crate) fn host_shortfall(&self) -> Option<String> {
+        if self.min_memory == 0 && self.min_storage == 0 {
+            return None;
+        }
+        let memory = if self.min_memory == 0 {
+            u64::MAX
+        } else {
+            read_available_memory().unwrap_or_else(|e| {
+                warn!("Could not read available memory: {}", e);
+                u64::MAX
+            })
+        };
+        let storage = if self.min_storage == 0 {
+            u64::MAX
+        } else {
+            util::filesystem_space(STORAGE_PATH).map(|(_, free)| free).unwrap_or_else(|e| {
+                warn!("Could not read free space on {}: {}", STORAGE_PATH, e);
+                u64::MAX
+            })
+        };
+        self.shortfall(memory, storage)
+    }
+
+    pub(crate) fn low_resources_error(realm: &str, shortfall: &str) -> LowResourcesError {
+        LowResourcesError { realm: realm.to_string(), shortfall: shortfall.to_string() }
+    }
+}
+
+fn read_available_memory() -> Result<u64> {
+    let meminfo = fs::read_to_string(MEMINFO_PATH)?;
+    parse_mem_available(&meminfo)
+        .ok_or_else(|| format_err!("no MemAvailable line in {}", MEMINFO_PATH))
+}
+
+// The line in /proc/meminfo is: MemAvailable:    3524604 kB
+fn parse_mem_available(meminfo: &str) -> Option<u64> {
+    meminfo.lines()
+        .find(|line| line.starts_with("MemAvailable:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+}
+
+#[test]
+fn test_start_thresholds() {
+    assert_eq!(StartThresholds::parse_size("512M").unwrap(), 512 * MIB);
+    assert_eq!(StartThresholds::parse_size("2G").unwrap(), 2048 * MIB);
+    assert_eq!(StartThresholds::parse_size("4096").unwrap(), 4096);
+    assert_eq!(StartThresholds::parse_size("0").unwrap(), 0);
+    for bad in &["", "M", "1.5G", "-1M", "200MB", "lots", "99999999999T"] {
+        assert!(StartThresholds::parse_size(bad).is_err(), "{}", bad);
+    }
+
+    let meminfo = "MemTotal:       16318480 kB\nMemFree:          204800 kB\nMemAvailable:     204800 kB\n";
+    assert_eq!(parse_mem_available(meminfo), Some(200 * MIB));
+    assert_eq!(parse_mem_available("MemTotal: 16318480 kB\n"), None);
+
+    let thresholds = StartThresholds::new(512 * MIB, 2048 * MIB, false);
+    assert_eq!(thresholds.shortfall(4096 * MIB, 8192 * MIB), None);
+    assert_eq!(thresholds.shortfall(512 * MIB, 2048 * MIB), None);
+    assert_eq!(thresholds.shortfall(200 * MIB, 8192 * MIB).unwrap(),
+               "available memory 200 MiB is below the minimum of 512 MiB");
+    assert_eq!(thresholds.shortfall(200 * MIB, 1024 * MIB).unwrap(),
+               "available memory 200 MiB is below the minimum of 512 MiB and free space on /storage 1024 MiB is below the minimum of 2048 MiB");
+
+    // Thresholds of 0 are never reached and do not read the host values
+    assert_eq!(StartThresholds::default().shortfall(0, 0), None);
+    assert_eq!(StartThresholds::default().host_shortfall(), None);
+
+    let error = StartThresholds::low_resources_error("main", "available memory 200 MiB is below the minimum of 512 MiB");
+    assert_eq!(error.to_string(), "refusing to start realm main because available memory 200 MiB is below the minimum of 512 MiB");
+}
@@ -0,0 +1,101 @@
+use std::path::Path;
+
+use crate::{RealmFS, Result};
+use crate::realmfs::Mountpoint;
+
+/// Directories which every realm root filesystem contains
+const ROOTFS_REQUIRED_DIRS: &[&str] = &["usr", "etc"];
+
+///
+/// Check that `rootfs` is a directory which looks like a root filesystem
+/// before launch config files pointing at it are written for `realm`.
+/// Otherwise systemd-nspawn fails at ExecStart with an error which does not
+/// say what is wrong with the rootfs.
+///
+pub(crate) fn check_rootfs_tree(realm: &str, rootfs: &Path) -> Result<()> {
+    if !rootfs.exists() {
+        bail!("rootfs {} of realm {} does not exist", rootfs.display(), realm);
+    }
+    if !rootfs.is_dir() {
+        bail!("rootfs {} of realm {} is not a directory", rootfs.display(), realm);
+    }
+    let missing = ROOTFS_REQUIRED_DIRS.iter()
+        .filter(|dir| !rootfs.join(dir).is_dir())
+        .map(|dir| format!("/{}", dir))
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        bail!("rootfs {} of realm {} is not a root filesystem because {} is missing", rootfs.display(), realm, missing.join(" and "));
+    }
+    Ok(())
+}
+
+///
+/// The RealmFS image a realm is expected to run, compared against the
+/// mountpoint its rootfs was set up from.
+///
+pub(crate) struct ExpectedImage {
+    name: String,
+    version: u32,
+    // Mountpoint tags of the current activation of the image. A sealed image
+    // is mounted at a mountpoint tagged with its verity root and an unsealed
+    // image at a pair of "ro" and "rw" mountpoints.
+    tags: Vec<String>,
+}
+
+impl ExpectedImage {
+    pub(crate) fn new(name: &str, version: u32, tags: &[&str]) -> Self {
+        ExpectedImage {
+            name: name.to_string(),
+            version,
+            tags: tags.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    pub(crate) fn for_realmfs(realmfs: &RealmFS) -> Self {
+        let metainfo = realmfs.metainfo();
+        if realmfs.is_sealed() {
+            Self::new(realmfs.name(), metainfo.version(), &[&metainfo.verity_tag()])
+        } else {
+            Self::new(realmfs.name(), metainfo.version(), &["ro", "rw"])
+        }
+    }
+
+    /// Return an error naming the expected image and the image which was found
+    /// if `mountpoint` does not belong to the current version of the image.
+    pub(crate) fn check_mountpoint(&self, realm: &str, mountpoint: &Mountpoint) -> Result<()> {
+        if mountpoint.realmfs() != self.name || !self.tags.iter().any(|tag| tag == mountpoint.tag()) {
+            bail!("rootfs of realm {} is mounted from RealmFS image {} ({}) but image {} version {} ({}) was expected",
+                  realm, mountpoint.realmfs(), mountpoint.tag(), self.name, self.version, self.tags.join("/"));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_check_rootfs() {
+    let base = crate::util::TempDir::new("rootfs-test").unwrap();
+    let rootfs = base.join("rootfs");
+    std::fs::create_dir_all(rootfs.join("usr")).unwrap();
+    std::fs::write(base.join("file"), "").unwrap();
+
+    let missing_etc = check_rootfs_tree("main", &rootfs).unwrap_err().to_string();
+    std::fs::create_dir(rootfs.join("etc")).unwrap();
+    let complete = check_rootfs_tree("main", &rootfs);
+    let not_dir = check_rootfs_tree("main", &base.join("file")).unwrap_err().to_string();
+    let not_found = check_rootfs_tree("main", &base.join("missing")).unwrap_err().to_string();
+
+    assert!(complete.is_ok());
+    assert!(missing_etc.ends_with("is not a root filesystem because /etc is missing"), "{}", missing_etc);
+    assert!(not_dir.ends_with("is not a directory"), "{}", not_dir);
+    assert!(not_found.ends_with("does not exist"), "{}", not_found);
+
+    let sealed = ExpectedImage::new("base", 7, &["1a2b3c4d"]);
+    assert!(sealed.check_mountpoint("main", &Mountpoint::new("base", "1a2b3c4d")).is_ok());
+    let stale = sealed.check_mountpoint("main", &Mountpoint::new("base", "99aa88bb")).unwrap_err().to_string();
+    assert_eq!(stale, "rootfs of realm main is mounted from RealmFS image base (99aa88bb) but image base version 7 (1a2b3c4d) was expected");
+    assert!(sealed.check_mountpoint("main", &Mountpoint::new("other", "1a2b3c4d")).is_err());
+
+    let unsealed = ExpectedImage::new("main", 2, &["ro", "rw"]);
+    assert!(unsealed.check_mountpoint("main", &Mountpoint::new("main", "rw")).is_ok());
+    assert!(unsealed.check_mountpoint("main", &Mountpoint::new("main", "1a2b3c4d")).is_err());
+}
@@ -0,0 +1,385 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use toml::Value;
+use toml::value::Table;
+
+use crate::{RealmConfig, Realms, Result};
+use crate::realm::network::{self, Reservation, ReservationConflict};
+
+/// Type of value a realm config key accepts
+#[derive(Clone,Copy)]
+enum KeyType {
+    Bool,
+    /// An integer in the inclusive range (min, max)
+    Int(i64, i64),
+    Str,
+    StrList,
+}
+
+impl KeyType {
+    fn name(self) -> &'static str {
+        match self {
+            KeyType::Bool => "a boolean",
+            KeyType::Int(..) => "an integer",
+            KeyType::Str => "a string",
+            KeyType::StrList => "an array of strings",
+        }
+    }
+}
+
+struct SchemaKey {
+    name: &'static str,
+    key_type: KeyType,
+    /// If not empty a string value must be one of these
+    values: &'static [&'static str],
+}
+
+const fn key(name: &'static str, key_type: KeyType) -> SchemaKey {
+    SchemaKey { name, key_type, values: &[] }
+}
+
+const fn key_values(name: &'static str, values: &'static [&'static str]) -> SchemaKey {
+    SchemaKey { name, key_type: KeyType::Str, values }
+}
+
+/// Every key which may appear in a realm config file. Defaults are not repeated
+/// here, they are the values set in `RealmConfig::default()`.
+const SCHEMA: &[SchemaKey] = &[
+    key("use-shared-dir", KeyType::Bool),
+    key("shared-dir", KeyType::Bool),
+    key("share-opt", KeyType::Bool),
+    key("use-ephemeral-home", KeyType::Bool),
+    key("ephemeral-persistent-dirs", KeyType::StrList),
+    key_values("home-mode", &["persistent", "ephemeral", "readonly-overlay"]),
+    key("persistent-dirs", KeyType::StrList),
+    key("encrypted-home", KeyType::Bool),
+    key("home-quota", KeyType::Str),
+    key("ephemeral-dirs", KeyType::StrList),
+    key("use-sound", KeyType::Bool),
+    key("use-pipewire", KeyType::Bool),
+    key("use-x11", KeyType::Bool),
+    key("use-wayland", KeyType::Bool),
+    key("desktop-integration", KeyType::Bool),
+    key("wayland-socket", KeyType::Str),
+    key_values("session-bus", &["none", "filtered"]),
+    key("session-bus-allow", KeyType::StrList),
+    key_values("clipboard", &["isolated", "shared", "outbound-only"]),
+    key("use-kvm", KeyType::Bool),
+    key("use-camera", KeyType::Bool),
+    key("usb-devices", KeyType::StrList),
+    key("block-devices", KeyType::StrList),
+    key("auto-mount-removable", KeyType::Bool),
+    key("use-gpu", KeyType::Bool),
+    key("use-gpu-card0", KeyType::Bool),
+    key("gpu-device", KeyType::Str),
+    key_values("gpu-vendor", &["amd", "intel", "nvidia"]),
+    key("use-network", KeyType::Bool),
+    key("network-zone", KeyType::Str),
+    key("vpn-required", KeyType::Str),
+    key("reserved-ip", KeyType::Int(1, u32::MAX as i64)),
+    key("wait-for-network", KeyType::Bool),
+    key("network-wait-timeout", KeyType::Int(1, 300)),
+    key_values("restart-policy", &["no", "on-failure", "always"]),
+    key("restart-max-per-hour", KeyType::Int(1, u32::MAX as i64)),
+    key("allowed-cpus", KeyType::Str),
+    key("cpu-weight", KeyType::Int(1, 10000)),
+    key("nice", KeyType::Int(-20, 19)),
+    key("system-realm", KeyType::Bool),
+    key("autostart", KeyType::Bool),
+    key("follow-focus", KeyType::Bool),
+    key("description", KeyType::Str),
+    key("extra-bindmounts", KeyType::StrList),
+    key("extra-bindmounts-ro", KeyType::StrList),
+    key("realm-depends", KeyType::StrList),
+    key("realmfs", KeyType::Str),
+    key("realmfs-write", KeyType::Bool),
+    key("terminal-scheme", KeyType::Str),
+    key("terminal-command", KeyType::Str),
+    key_values("overlay", &["tmpfs", "storage", "none"]),
+    key("netns", KeyType::Str),
+    key("environment", KeyType::StrList),
+    key("drop-capabilities", KeyType::StrList),
+    key("no-new-privileges", KeyType::Bool),
+    key("system-call-filter", KeyType::Str),
+    key("private-users", KeyType::Bool),
+    key("timezone", KeyType::Str),
+    key("locale", KeyType::Str),
+    key_values("journal", &["auto", "host", "off"]),
+    key("journal-max-size", KeyType::Str),
+    key("dns", KeyType::StrList),
+    key("dns-search", KeyType::StrList),
+    key("extra-hosts", KeyType::StrList),
+    key("port-forwards", KeyType::StrList),
+];
+
+/// Unknown keys are only reported with a suggestion when they are at most
+/// this many edits away from a known key.
+const MAX_SUGGESTION_DISTANCE: usize = 3;
+
+/// A problem found while checking a realm config file.
+pub struct ConfigIssue {
+    error: bool,
+    path: String,
+    line: Option<usize>,
+    message: String,
+}
+
+impl ConfigIssue {
+    /// Return `true` if this issue prevents the config file from being used.
+    /// Otherwise the issue is a warning such as an unknown key.
+    pub fn is_error(&self) -> bool {
+        self.error
+    }
+
+    /// Line number of the issue in the config file starting from 1 if known.
+    pub fn line(&self) -> Option<usize> {
+        self.line
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let level = if self.error { "error" } else { "warning" };
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}: {}", self.path, line, level, self.message),
+            None => write!(f, "{}: {}: {}", self.path, level, self.message),
+        }
+    }
+}
+
+/// Result of checking a realm config file against the schema of known keys
+/// and then validating the option values with `RealmConfig::validate()`.
+pub struct ConfigCheck {
+    issues: Vec<ConfigIssue>,
+}
+
+impl ConfigCheck {
+    pub fn check_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| format_err!("failed to read config file {}: {}", path.display(), e))?;
+        Ok(Self::check_str(path, &text))
+    }
+
+    /// Check the content `text` of the config file at `path`.
+    pub fn check_str(path: &Path, text: &str) -> Self {
+        let mut check = ConfigCheck { issues: Vec::new() };
+        match text.parse::<Value>() {
+            Ok(Value::Table(table)) => return Self::check_table(path, text, table),
+            Ok(_) => {
+                check.add(true, &path.display().to_string(), None, "config file is not a table of keys".to_string());
+            }
+            Err(e) => {
+                let line = e.line_col().map(|(line, _)| line + 1);
+                check.add(true, &path.display().to_string(), line, format!("syntax error: {}", e));
+            }
+        }
+        check
+    }
+
+    /// Check the keys in `table` which were parsed from the content `text` of
+    /// the file at `path`. Used for files which contain realm config keys
+    /// together with other keys which have been removed from `table`.
+    pub(crate) fn check_table(path: &Path, text: &str, table: Table) -> Self {
+        let mut check = ConfigCheck { issues: Vec::new() };
+        let path = path.display().to_string();
+
+        for (name, value) in &table {
+            let line = key_line(text, name);
+            match SCHEMA.iter().find(|k| k.name == name) {
+                Some(key) => if let Some(msg) = check_value(key, value) {
+                    check.add(true, &path, line, msg);
+                },
+                None => check.add(false, &path, line, unknown_key_message(name)),
+            }
+        }
+
+        if !check.has_errors() {
+            let result = Value::Table(table).try_into::<RealmConfig>()
+                .map_err(|e| format_err!("{}", e))
+                .and_then(|config| {
+                    config.validate()?;
+                    match config.reserved_ip() {
+                        Some(offset) => network::check_reserved_ip(config.network_zone(), offset),
+                        None => Ok(()),
+                    }
+                });
+            if let Err(e) = result {
+                check.add(true, &path, None, e.to_string());
+            }
+        }
+        check.issues.sort_by_key(|i| i.line);
+        check
+    }
+
+    /// Check whether realm `name` reserves the same address with `reserved-ip` as
+    /// another realm in the same network zone. This is an error for a realm which
+    /// realmsd will refuse to start, and a warning for the realm which is given
+    /// the address unless another of the realms is already running.
+    pub fn check_reserved_address(&mut self, name: &str) {
+        let names = match Realms::realm_names() {
+            Ok(names) => names,
+            Err(e) => {
+                warn!("Could not list realms to check for reserved address conflicts: {}", e);
+                return;
+            }
+        };
+        let reservations = names.iter()
+            .flat_map(|realm| {
+                let mut config = RealmConfig::unloaded_realm_config(realm);
+                config.reload().ok()?;
+                Reservation::for_config(realm, &config, false)
+            })
+            .collect::<Vec<_>>();
+        let path = Path::new(Realms::BASE_PATH).join(format!("realm-{}", name)).join("config");
+        for conflict in ReservationConflict::find(&reservations) {
+            if conflict.holder() == name {
+                self.add(false, &path.display().to_string(), None, conflict.message_for(name));
+            } else if conflict.others().iter().any(|realm| realm == name) {
+                let message = format!("{}, realm will not be started", conflict.message_for(name));
+                self.add(true, &path.display().to_string(), None, message);
+            }
+        }
+    }
+
+    fn add(&mut self, error: bool, path: &str, line: Option<usize>, message: String) {
+        let path = path.to_string();
+        self.issues.push(ConfigIssue { error, path, line, message });
+    }
+
+    pub fn issues(&self) -> &[ConfigIssue] {
+        &self.issues
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.issues.iter().any(|i| i.is_error())
+    }
+
+    pub fn first_error(&self) -> Option<&ConfigIssue> {
+        self.issues.iter().find(|i| i.is_error())
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item=&ConfigIssue> {
+        self.issues.iter().filter(|i| !i.is_error())
+    }
+}
+
+fn check_value(key: &SchemaKey, value: &Value) -> Option<String> {
+    let type_ok = match (key.key_type, value) {
+        (KeyType::Bool, Value::Boolean(_)) => true,
+        (KeyType::Int(min, max), Value::Integer(n)) => {
+            if *n < min || *n > max {
+                return Some(format!("value {} of key '{}' is out of range. Must be between {} and {}", n, key.name, min, max));
+            }
+            true
+        },
+        (KeyType::Str, Value::String(s)) => {
+            if !key.values.is_empty() && !key.values.contains(&s.as_str()) {
+                return Some(format!("invalid value '{}' for key '{}'. Valid values are: {}", s, key.name, key.values.join(", ")));
+            }
+            true
+        },
+        (KeyType::StrList, Value::Array(items)) => items.iter().all(|v| v.is_str()),
+        _ => false,
+    };
+    if type_ok {
+        None
+    } else {
+        Some(format!("key '{}' must be {}, found {}", key.name, key.key_type.name(), describe_value(value)))
+    }
+}
+
+fn describe_value(value: &Value) -> String {
+    match value {
+        Value::Array(items) if !items.iter().all(|v| v.is_str()) => "an array containing non-string values".to_string(),
+        Value::Array(_) => "an array".to_string(),
+        Value::Table(_) => "a table".to_string(),
+        v => format!("{} {}", if v.type_str() == "integer" { "an" } else { "a" }, v.type_str()),
+    }
+}
+
+fn unknown_key_message(name: &str) -> String {
+    // Also compare without the 'use-' prefix since it is often left out
+    let nearest = SCHEMA.iter()
+        .map(|k| {
+            let short = k.name.trim_start_matches("use-");
+            (edit_distance(name, k.name).min(edit_distance(name, short)), k.name)
+        })
+        .min();
+    match nearest {
+        Some((distance, known)) if distance <= MAX_SUGGESTION_DISTANCE =>
+            format!("unknown key '{}' is ignored, did you mean '{}'?", name, known),
+        _ => format!("unknown key '{}' is ignored", name),
+    }
+}
+
+// Find the line number (starting from 1) where the top level key `name` is assigned
+fn key_line(text: &str, name: &str) -> Option<usize> {
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim_start();
+        if line.starts_with('[') {
+            if line.trim_start_matches('[').trim_start().starts_with(name) {
+                return Some(idx + 1);
+            }
+            continue;
+        }
+        let key = line.split('=').next().unwrap_or("").trim().trim_matches('"');
+        if key == name && line.contains('=') {
+            return Some(idx + 1);
+        }
+    }
+    None
+}
+
+// Levenshtein distance between `a` and `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut row = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            row.push((prev[j] + cost).min(prev[j + 1] + 1).min(row[j] + 1));
+        }
+        prev = row;
+    }
+    prev[b.len()]
+}
+
+#[test]
+fn test_config_check_corpus() {
+    let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/realm-config");
+    let check_dir = |dir: &str| {
+        let mut entries = fs::read_dir(corpus.join(dir)).unwrap()
+            .map(|e| e.unwrap().path())
+            .collect::<Vec<_>>();
+        entries.sort();
+        assert!(!entries.is_empty());
+        entries.into_iter().map(|p| (p.clone(), ConfigCheck::check_file(&p).unwrap()))
+    };
+
+    for (path, check) in check_dir("good") {
+        let issues = check.issues().iter().map(|i| i.to_string()).collect::<Vec<_>>();
+        assert!(issues.is_empty(), "{}: {:?}", path.display(), issues);
+    }
+
+    // Each bad file starts with a comment containing the expected first issue
+    for (path, check) in check_dir("bad") {
+        let text = fs::read_to_string(&path).unwrap();
+        let expected = text.lines().next().unwrap().trim_start_matches('#').trim();
+        let issue = check.issues().first()
+            .unwrap_or_else(|| panic!("{}: no issues found", path.display()))
+            .to_string();
+        assert!(issue.contains(expected), "{}: expected '{}', found '{}'", path.display(), expected, issue);
+    }
+
+    assert_eq!(edit_distance("use-ephemral-home", "use-ephemeral-home"), 1);
+    assert_eq!(unknown_key_message("ephemral-home"), "unknown key 'ephemral-home' is ignored, did you mean 'use-ephemeral-home'?");
+    assert_eq!(unknown_key_message("frobnicate"), "unknown key 'frobnicate' is ignored");
+}
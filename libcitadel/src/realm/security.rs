@@ -0,0 +1,108 @@
+use std::fs;
+use std::path::Path;
+
+use crate::Result;
+
+/// Directory containing named system call filter profiles
+pub(crate) const SECCOMP_PROFILE_PATH: &str = "/etc/citadel/seccomp";
+
+/// All capability names understood by systemd-nspawn `DropCapability=`
+const CAPABILITIES: &[&str] = &[
+    "CAP_CHOWN", "CAP_DAC_OVERRIDE", "CAP_DAC_READ_SEARCH", "CAP_FOWNER",
+    "CAP_FSETID", "CAP_KILL", "CAP_SETGID", "CAP_SETUID", "CAP_SETPCAP",
+    "CAP_LINUX_IMMUTABLE", "CAP_NET_BIND_SERVICE", "CAP_NET_BROADCAST",
+    "CAP_NET_ADMIN", "CAP_NET_RAW", "CAP_IPC_LOCK", "CAP_IPC_OWNER",
+    "CAP_SYS_MODULE", "CAP_SYS_RAWIO", "CAP_SYS_CHROOT", "CAP_SYS_PTRACE",
+    "CAP_SYS_PACCT", "CAP_SYS_ADMIN", "CAP_SYS_BOOT", "CAP_SYS_NICE",
+    "CAP_SYS_RESOURCE", "CAP_SYS_TIME", "CAP_SYS_TTY_CONFIG", "CAP_MKNOD",
+    "CAP_LEASE", "CAP_AUDIT_WRITE", "CAP_AUDIT_CONTROL", "CAP_SETFCAP",
+    "CAP_MAC_OVERRIDE", "CAP_MAC_ADMIN", "CAP_SYSLOG", "CAP_WAKE_ALARM",
+    "CAP_BLOCK_SUSPEND", "CAP_AUDIT_READ",
+];
+
+/// Capabilities which the systemd instance booted inside a realm (Boot=true)
+/// cannot do without. Dropping any of these prevents the realm from starting.
+const REQUIRED_BOOT_CAPABILITIES: &[&str] = &[
+    "CAP_SYS_ADMIN", "CAP_SETUID", "CAP_SETGID", "CAP_SETPCAP",
+];
+
+/// Verify that every entry in `caps` is a known capability name and that
+/// none of the capabilities required to boot the realm init are dropped.
+pub fn validate_drop_capabilities(caps: &[String]) -> Result<()> {
+    for cap in caps {
+        if !CAPABILITIES.contains(&cap.as_str()) {
+            bail!("unknown capability '{}' in drop-capabilities. Valid values are: {}", cap, CAPABILITIES.join(", "));
+        }
+        if REQUIRED_BOOT_CAPABILITIES.contains(&cap.as_str()) {
+            bail!("cannot drop capability '{}' because it is required to boot realm init. Capabilities which cannot be dropped: {}",
+                  cap, REQUIRED_BOOT_CAPABILITIES.join(", "));
+        }
+    }
+    Ok(())
+}
+
+/// A named list of system calls to pass to systemd-nspawn `SystemCallFilter=`
+///
+/// Profiles are stored as files in /etc/citadel/seccomp with one system call
+/// or system call group (such as `@system-service`) per line. Empty lines and
+/// lines starting with '#' are ignored.
+///
+pub struct SyscallProfile {
+    syscalls: Vec<String>,
+}
+
+impl SyscallProfile {
+
+    /// Load the profile called `name` from /etc/citadel/seccomp.
+    pub fn load(name: &str) -> Result<Self> {
+        Self::load_from(Path::new(SECCOMP_PROFILE_PATH), name)
+    }
+
+    /// Load the profile called `name` from the profile directory `dir`.
+    pub fn load_from(dir: &Path, name: &str) -> Result<Self> {
+        let path = dir.join(name);
+        if !Self::is_valid_profile_name(name) || !path.exists() {
+            bail!("unknown system-call-filter profile '{}'. Valid values are: {}", name, Self::profiles_in(dir).join(", "));
+        }
+        let content = fs::read_to_string(&path)
+            .map_err(|e| format_err!("failed to read system call filter profile {}: {}", path.display(), e))?;
+        let syscalls = content.lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| line.to_string())
+            .collect::<Vec<_>>();
+
+        if let Some(bad) = syscalls.iter().find(|s| s.contains(char::is_whitespace)) {
+            bail!("system call filter profile {} has invalid entry '{}'", path.display(), bad);
+        }
+        Ok(SyscallProfile { syscalls })
+    }
+
+    fn profiles_in(dir: &Path) -> Vec<String> {
+        let mut v = match fs::read_dir(dir) {
+            Ok(entries) => entries.flat_map(|e| e.ok())
+                .flat_map(|e| e.file_name().into_string().ok())
+                .filter(|name| Self::is_valid_profile_name(name))
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        v.sort();
+        v
+    }
+
+    fn is_valid_profile_name(name: &str) -> bool {
+        !name.is_empty() && !name.starts_with('.') &&
+            name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+    }
+
+    pub fn syscalls(&self) -> &[String] {
+        &self.syscalls
+    }
+}
+
+#[test]
+fn test_validate_drop_capabilities() {
+    assert!(validate_drop_capabilities(&["CAP_NET_RAW".to_string(), "CAP_SYS_MODULE".to_string()]).is_ok());
+    assert!(validate_drop_capabilities(&["CAP_NOT_REAL".to_string()]).is_err());
+    assert!(validate_drop_capabilities(&["CAP_SYS_ADMIN".to_string()]).is_err());
+}
@@ -0,0 +1,220 @@
+use std::collections::HashSet;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use walkdir::WalkDir;
+
+use crate::{Realm, Result, util};
+
+const SNAPSHOT_DIR: &str = "snapshots";
+const MAX_LABEL_LEN: usize = 32;
+const UNDO_LABEL: &str = "undo";
+
+/// A saved copy of a realm home directory stored below the realm base
+/// path in `snapshots/<timestamp>-<label>`.
+#[derive(Clone)]
+pub struct RealmSnapshot {
+    id: String,
+    label: String,
+    timestamp: u64,
+    size: u64,
+}
+
+impl RealmSnapshot {
+    fn from_dir(path: &Path) -> Option<Self> {
+        let id = path.file_name()?.to_str()?.to_string();
+        let (timestamp, label) = Self::parse_id(&id)?;
+        let size = disk_usage(path);
+        Some(RealmSnapshot { id, label, timestamp, size })
+    }
+
+    // Split an id of the form <timestamp>-<label>
+    fn parse_id(id: &str) -> Option<(u64, String)> {
+        let idx = id.find('-')?;
+        let timestamp = id[..idx].parse().ok()?;
+        let label = &id[idx + 1..];
+        if !util::is_valid_name(label, MAX_LABEL_LEN) {
+            return None;
+        }
+        Some((timestamp, label.to_string()))
+    }
+
+    /// Identifier of this snapshot which is the name of the snapshot directory.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// Time the snapshot was created as seconds since the epoch.
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Disk space used by the files in the snapshot in bytes. Extents which are
+    /// shared with the home directory or other snapshots by reflinking are counted
+    /// as if they were not shared, so this is the upper bound of space that would
+    /// be released by removing the snapshot.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+/// Creates, lists and restores snapshots of the home directory of a realm.
+///
+/// Files are reflinked into the snapshot when the filesystem supports it and
+/// copied otherwise. Hard links are never used because a file modified in place
+/// in the home directory would silently modify the snapshot as well.
+pub(crate) struct HomeSnapshots {
+    base: PathBuf,
+}
+
+impl HomeSnapshots {
+    pub fn new(realm: &Realm) -> Self {
+        HomeSnapshots { base: realm.base_path() }
+    }
+
+    fn home(&self) -> PathBuf {
+        self.base.join("home")
+    }
+
+    fn snapshot_dir(&self) -> PathBuf {
+        self.base.join(SNAPSHOT_DIR)
+    }
+
+    pub fn list(&self) -> Result<Vec<RealmSnapshot>> {
+        let dir = self.snapshot_dir();
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut snapshots = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                if let Some(snapshot) = RealmSnapshot::from_dir(&path) {
+                    snapshots.push(snapshot);
+                }
+            }
+        }
+        snapshots.sort_by(|a, b| (a.timestamp, &a.id).cmp(&(b.timestamp, &b.id)));
+        Ok(snapshots)
+    }
+
+    pub fn create(&self, label: &str, progress: &mut dyn FnMut(usize)) -> Result<RealmSnapshot> {
+        if !util::is_valid_name(label, MAX_LABEL_LEN) {
+            bail!("'{}' is not a valid snapshot label. Only letters, numbers and dash '-' symbol allowed in label. First character must be a letter", label);
+        }
+        let home = self.home();
+        if !home.exists() {
+            bail!("Realm home directory {} does not exist", home.display());
+        }
+        let target = self.new_snapshot_path(label)?;
+        let tmp = self.snapshot_dir().join(format!(".tmp-{}", Self::filename(&target)));
+
+        info!("Creating snapshot of {} at {}", home.display(), target.display());
+        if let Err(e) = util::clone_tree(&home, &tmp, progress) {
+            let _ = fs::remove_dir_all(&tmp);
+            bail!("failed to copy {} to snapshot: {}", home.display(), e);
+        }
+        fs::rename(&tmp, &target)?;
+        RealmSnapshot::from_dir(&target)
+            .ok_or_else(|| format_err!("failed to read snapshot {}", target.display()))
+    }
+
+    /// Replace the home directory with a copy of the snapshot `id` and save the
+    /// displaced home directory as a new snapshot which is returned. The caller is
+    /// responsible for making sure the realm is not running.
+    pub fn restore(&self, id: &str, progress: &mut dyn FnMut(usize)) -> Result<RealmSnapshot> {
+        let source = self.snapshot_dir().join(id);
+        if RealmSnapshot::parse_id(id).is_none() || !source.is_dir() {
+            bail!("Unknown snapshot '{}'", id);
+        }
+        let home = self.home();
+        if !home.exists() {
+            bail!("Realm home directory {} does not exist", home.display());
+        }
+        let restore = self.base.join(".home-restore");
+        if restore.exists() {
+            fs::remove_dir_all(&restore)?;
+        }
+
+        info!("Restoring snapshot {} to {}", source.display(), home.display());
+        if let Err(e) = util::clone_tree(&source, &restore, progress) {
+            let _ = fs::remove_dir_all(&restore);
+            bail!("failed to copy snapshot {}: {}", id, e);
+        }
+
+        let undo = match self.new_snapshot_path(UNDO_LABEL) {
+            Ok(undo) => undo,
+            Err(e) => {
+                let _ = fs::remove_dir_all(&restore);
+                return Err(e);
+            }
+        };
+        if let Err(e) = fs::rename(&home, &undo) {
+            let _ = fs::remove_dir_all(&restore);
+            bail!("failed to move {} to {}: {}", home.display(), undo.display(), e);
+        }
+        if let Err(e) = fs::rename(&restore, &home) {
+            let _ = fs::rename(&undo, &home);
+            bail!("failed to move restored snapshot to {}: {}", home.display(), e);
+        }
+        RealmSnapshot::from_dir(&undo)
+            .ok_or_else(|| format_err!("failed to read snapshot {}", undo.display()))
+    }
+
+    fn new_snapshot_path(&self, label: &str) -> Result<PathBuf> {
+        let dir = self.snapshot_dir();
+        fs::create_dir_all(&dir)?;
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let path = dir.join(format!("{}-{}", timestamp, label));
+        if path.exists() {
+            bail!("Snapshot {} already exists", path.display());
+        }
+        Ok(path)
+    }
+
+    fn filename(path: &Path) -> String {
+        path.file_name().map(|s| s.to_string_lossy().to_string()).unwrap_or_default()
+    }
+}
+
+// Sum of allocated blocks of every file below `path` counting hard linked files once
+pub(crate) fn disk_usage(path: &Path) -> u64 {
+    let mut inodes = HashSet::new();
+    WalkDir::new(path).into_iter()
+        .flatten()
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|meta| meta.nlink() == 1 || inodes.insert((meta.dev(), meta.ino())))
+        .map(|meta| meta.blocks() * 512)
+        .sum()
+}
+
+#[test]
+fn test_home_snapshots() {
+    let base = crate::util::TempDir::new("snapshot-test").unwrap();
+    fs::create_dir_all(base.join("home")).unwrap();
+    fs::write(base.join("home/file"), b"before").unwrap();
+    let snapshots = HomeSnapshots { base: base.to_path_buf() };
+
+    assert!(snapshots.create("not valid", &mut |_| {}).is_err());
+    let snapshot = snapshots.create("test", &mut |_| {}).unwrap();
+    assert_eq!(snapshot.label(), "test");
+    assert!(snapshot.id().ends_with("-test"));
+    fs::write(base.join("home/file"), b"after").unwrap();
+
+    assert!(snapshots.restore("12345-unknown", &mut |_| {}).is_err());
+    assert!(snapshots.restore("../home", &mut |_| {}).is_err());
+    let undo = snapshots.restore(snapshot.id(), &mut |_| {}).unwrap();
+    assert_eq!(undo.label(), UNDO_LABEL);
+    assert_eq!(fs::read(base.join("home/file")).unwrap(), b"before");
+    assert_eq!(fs::read(base.join(SNAPSHOT_DIR).join(undo.id()).join("file")).unwrap(), b"after");
+
+    let ids = snapshots.list().unwrap().iter().map(|s| s.id().to_string()).collect::<Vec<_>>();
+    assert_eq!(ids.len(), 2);
+    assert!(ids.contains(&snapshot.id().to_string()) && ids.contains(&undo.id().to_string()));
+}
@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::sync::{Condvar,Mutex};
+use std::thread;
+use std::time::{Duration,Instant};
+
+use crate::Result;
+
+/// Number of realms started at the same time during boot unless set with
+/// `citadel.realm_parallelism=` on the kernel command line
+pub const DEFAULT_BOOT_PARALLELISM: usize = 3;
+
+///
+/// How long starting a realm at boot took and the error if it failed to start.
+/// Recorded in the boot status file by `RealmManager::start_boot_realms()`.
+///
+#[derive(Serialize,Deserialize,Debug,Clone,PartialEq)]
+pub struct RealmStartStatus {
+    name: String,
+    duration_ms: u64,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+impl RealmStartStatus {
+    fn new(name: &str, duration: Duration, error: Option<String>) -> Self {
+        RealmStartStatus { name: name.to_string(), duration_ms: duration.as_millis() as u64, error }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.duration_ms)
+    }
+
+    /// The error message if the realm failed to start
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+}
+
+/// A realm to start at boot and the names of the realms it depends on
+pub(crate) struct BootRealm {
+    name: String,
+    depends: Vec<String>,
+}
+
+impl BootRealm {
+    pub(crate) fn new(name: &str, depends: &[&str]) -> Self {
+        BootRealm {
+            name: name.to_string(),
+            depends: depends.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+struct StartState {
+    pending: Vec<usize>,
+    running: usize,
+    // true if the realm started successfully
+    finished: HashMap<String, bool>,
+    results: Vec<Option<RealmStartStatus>>,
+}
+
+impl StartState {
+    // Index of the next pending realm whose dependencies in `realms` have all
+    // finished starting. If the remaining realms depend on each other and
+    // nothing is running to break the cycle, the first pending realm is chosen.
+    fn next_ready(&mut self, realms: &[BootRealm]) -> Option<usize> {
+        let in_set = |name: &String| realms.iter().any(|r| r.name == *name);
+        let pos = self.pending.iter().position(|&idx| {
+            realms[idx].depends.iter().all(|dep| !in_set(dep) || self.finished.contains_key(dep))
+        });
+        match pos {
+            Some(pos) => Some(self.pending.remove(pos)),
+            None if self.running == 0 && !self.pending.is_empty() => Some(self.pending.remove(0)),
+            None => None,
+        }
+    }
+
+    fn failed_dependency<'a>(&self, realm: &'a BootRealm) -> Option<&'a str> {
+        realm.depends.iter()
+            .find(|dep| self.finished.get(dep.as_str()) == Some(&false))
+            .map(|dep| dep.as_str())
+    }
+}
+
+///
+/// Call `start` for each realm in `realms` from up to `parallelism` threads. A
+/// realm is not started until the realms it depends on which are also in
+/// `realms` have finished starting, and is not started at all if one of them
+/// failed. Returns the result for each realm in the same order as `realms`.
+///
+pub(crate) fn start_realms<F>(realms: &[BootRealm], parallelism: usize, start: F) -> Vec<RealmStartStatus>
+    where F: Fn(&str) -> Result<()> + Sync
+{
+    let state = Mutex::new(StartState {
+        pending: (0..realms.len()).collect(),
+        running: 0,
+        finished: HashMap::new(),
+        results: vec![None; realms.len()],
+    });
+    let ready = Condvar::new();
+    let workers = parallelism.max(1).min(realms.len());
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| start_worker(realms, &state, &ready, &start));
+        }
+    });
+
+    state.into_inner().unwrap().results.into_iter()
+        .flatten()
+        .collect()
+}
+
+fn start_worker<F>(realms: &[BootRealm], state: &Mutex<StartState>, ready: &Condvar, start: &F)
+    where F: Fn(&str) -> Result<()>
+{
+    let mut lock = state.lock().unwrap();
+    loop {
+        let idx = match lock.next_ready(realms) {
+            Some(idx) => idx,
+            None if lock.pending.is_empty() => return,
+            None => {
+                lock = ready.wait(lock).unwrap();
+                continue;
+            }
+        };
+        let realm = &realms[idx];
+        if let Some(dep) = lock.failed_dependency(realm) {
+            let error = format!("dependency realm '{}' failed to start", dep);
+            lock.finished.insert(realm.name.clone(), false);
+            lock.results[idx] = Some(RealmStartStatus::new(&realm.name, Duration::from_secs(0), Some(error)));
+            ready.notify_all();
+            continue;
+        }
+
+        lock.running += 1;
+        drop(lock);
+        let started = Instant::now();
+        let result = start(&realm.name);
+        let duration = started.elapsed();
+        lock = state.lock().unwrap();
+        lock.running -= 1;
+
+        lock.finished.insert(realm.name.clone(), result.is_ok());
+        let error = result.err().map(|e| e.to_string());
+        lock.results[idx] = Some(RealmStartStatus::new(&realm.name, duration, error));
+        ready.notify_all();
+    }
+}
+
+#[test]
+fn test_start_realms_concurrently() {
+    use std::sync::atomic::{AtomicUsize,Ordering};
+
+    // Fake realms "r0".."r19" where every fifth realm depends on the one before
+    // it and "r7" fails to start.
+    let realms = (0..20).map(|i| {
+        let name = format!("r{}", i);
+        let dep = format!("r{}", i - 1);
+        let depends = if i % 5 == 4 { vec![dep.as_str()] } else { vec![] };
+        BootRealm::new(&name, &depends)
+    }).collect::<Vec<_>>();
+
+    let active = AtomicUsize::new(0);
+    let max_active = AtomicUsize::new(0);
+    let order = Mutex::new(Vec::new());
+    let results = start_realms(&realms, 3, |name| {
+        let n = active.fetch_add(1, Ordering::SeqCst) + 1;
+        max_active.fetch_max(n, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(5));
+        order.lock().unwrap().push(name.to_string());
+        active.fetch_sub(1, Ordering::SeqCst);
+        if name == "r7" {
+            bail!("fake start failure");
+        }
+        Ok(())
+    });
+
+    let max_active = max_active.load(Ordering::SeqCst);
+    assert!(max_active > 1 && max_active <= 3, "{} realms started at the same time", max_active);
+    assert_eq!(results.iter().map(|r| r.name()).collect::<Vec<_>>(),
+               realms.iter().map(|r| r.name.as_str()).collect::<Vec<_>>());
+    let order = order.into_inner().unwrap();
+    assert_eq!(order.len(), 20);
+    for i in &[4, 9, 14, 19] {
+        let position = |name: String| order.iter().position(|n| *n == name).unwrap();
+        assert!(position(format!("r{}", i - 1)) < position(format!("r{}", i)));
+    }
+    assert_eq!(results[7].error(), Some("fake start failure"));
+    assert!(results.iter().filter(|r| r.name() != "r7").all(|r| r.error().is_none()));
+
+    // A realm is not started if a dependency failed and dependency cycles do not deadlock
+    let realms = vec![BootRealm::new("a", &["b"]), BootRealm::new("b", &[]), BootRealm::new("c", &["d"]), BootRealm::new("d", &["c"])];
+    let results = start_realms(&realms, 2, |name| if name == "b" { bail!("failed") } else { Ok(()) });
+    assert_eq!(results[0].error(), Some("dependency realm 'b' failed to start"));
+    assert!(results[2].error().is_none() && results[3].error().is_none());
+    assert!(start_realms(&[], 3, |_| Ok(())).is_empty());
+}
@@ -1,77 +1,705 @@
-use std::process::Command;
-use std::path::Path;
+use std::process::ExitStatus;
+use std::path::{Path,PathBuf};
 use std::env;
+use std::fs;
+use std::io;
 
 const SYSTEMCTL_PATH: &str = "/usr/bin/systemctl";
 const MACHINECTL_PATH: &str = "/usr/bin/machinectl";
+const SYSTEMD_RUN_PATH: &str = "/usr/bin/systemd-run";
 
-use crate::Result;
+/// Oldest systemd version supporting `systemd-run --wait --pipe` for commands run in a container
+const SYSTEMD_RUN_PIPE_MIN_VERSION: u32 = 235;
 
-use crate::Realm;
+/// Oldest systemd version supporting `PrivateUsers=pick` in .nspawn files
+const PRIVATE_USERS_MIN_VERSION: u32 = 230;
+
+/// Oldest systemd version supporting idmapped bind mounts and `PrivateUsersOwnership=`
+const IDMAP_MIN_VERSION: u32 = 250;
+
+/// Oldest systemd version supporting `systemctl freeze` and `systemctl thaw`
+const FREEZE_MIN_VERSION: u32 = 246;
+
+/// Mount point of the cgroup filesystem
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// File in realm base directory recording the uid shift currently applied to the realm home
+const HOME_UID_SHIFT_FILE: &str = "home-uid-shift";
+
+/// Size of the uid range systemd-nspawn assigns to a container with `PrivateUsers=pick`
+const UID_RANGE_SIZE: u32 = 0x10000;
+
+/// First uid of the ranges assigned to realms with a shifted home directory,
+/// the lowest uid systemd-nspawn picks a range from
+const UID_SHIFT_BASE: u32 = 0x0008_0000;
+
+/// Runtime directory of the desktop user, where the systemd user manager is reached
+const USER_RUNTIME_DIR: &str = "/run/user/1000";
+
+/// Maximum length of a user name which commands are run as inside a realm
+const MAX_USERNAME_LEN: usize = 32;
+
+/// Arguments to systemctl listing realm services which are running, starting or waiting to restart
+const LIST_REALM_UNITS_ARGS: &[&str] = &["list-units", "--type=service", "--state=active,activating,deactivating,reloading",
+    "--no-legend", "--plain", "realm-*.service"];
+
+use crate::{Result,Exec,HomeMode,RealmConfig,util};
+
+use crate::{Realm,Realms};
 use std::sync::Mutex;
 use std::process::Stdio;
-use crate::realm::network::NetworkConfig;
-use crate::realm::launcher::RealmLauncher;
+use std::os::unix::process::CommandExt;
+use std::net::{IpAddr,Ipv4Addr};
+use std::collections::HashSet;
+use std::time::Duration;
+use crate::realm::network::{NetworkConfig,NetnsManager,PortForward,PortForwarder,KillSwitch,NetworkBlockState};
+use crate::realm::launcher::{LaunchPlan,RealmLauncher};
+use crate::realm::dbus_proxy::SessionBusProxy;
+use crate::realm::home::EncryptedHome;
+use crate::realm::resources::StartThresholds;
+
+lazy_static! {
+    static ref SYSTEMD_VERSION: Option<u32> = Systemd::read_systemd_version();
+}
+
+/// Error returned when the program used to run a command in a realm could not be executed.
+#[derive(Debug,Fail)]
+#[fail(display = "failed to execute {}: {}", command, error)]
+pub struct ShellSpawnError {
+    command: &'static str,
+    #[cause] error: io::Error,
+}
+
+/// Program used to run commands inside a realm
+#[derive(Debug,Copy,Clone,PartialEq)]
+enum ShellBackend {
+    Machinectl,
+    SystemdRun,
+}
+
+impl ShellBackend {
+    fn for_version(version: Option<u32>) -> Self {
+        match version {
+            Some(v) if v >= SYSTEMD_RUN_PIPE_MIN_VERSION => ShellBackend::SystemdRun,
+            _ => ShellBackend::Machinectl,
+        }
+    }
+
+    fn path(self) -> &'static str {
+        match self {
+            ShellBackend::Machinectl => MACHINECTL_PATH,
+            ShellBackend::SystemdRun => SYSTEMD_RUN_PATH,
+        }
+    }
+}
 
 pub struct Systemd {
     network: Mutex<NetworkConfig>,
+    // Names of realms which have been allocated a network address but whose
+    // service may not be running yet. Always locked after `network`.
+    starting: Mutex<HashSet<String>>,
+    thresholds: Mutex<StartThresholds>,
 }
 
 impl Systemd {
 
     pub fn new(network: NetworkConfig) -> Systemd {
         let network = Mutex::new(network);
-        Systemd { network }
+        Systemd { network, starting: Mutex::new(HashSet::new()), thresholds: Mutex::new(StartThresholds::default()) }
+    }
+
+    /// Set the minimum available memory and free storage for starting a realm
+    pub fn set_start_thresholds(&self, thresholds: StartThresholds) {
+        *self.thresholds.lock().unwrap() = thresholds;
     }
 
+    /// Return a `LowResourcesError` if the host has less memory or storage
+    /// available than the start thresholds and realms are refused to start
+    /// when resources are low.
+    pub fn check_start_resources(&self, realm: &Realm) -> Result<()> {
+        let thresholds = *self.thresholds.lock().unwrap();
+        match thresholds.host_shortfall() {
+            Some(ref shortfall) if thresholds.refuse() => Err(StartThresholds::low_resources_error(realm.name(), shortfall).into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Start the realm service of `realm`. The network lock is only held while the
+    /// network address is allocated and the launch config files are written, so
+    /// that several realms can be started at the same time.
     pub fn start_realm(&self, realm: &Realm, rootfs: &Path) -> Result<()> {
-        let mut lock = self.network.lock().unwrap();
+        let thresholds = *self.thresholds.lock().unwrap();
+        if let Some(shortfall) = thresholds.host_shortfall() {
+            if thresholds.refuse() {
+                return Err(StartThresholds::low_resources_error(realm.name(), &shortfall).into());
+            }
+            warn!("Starting realm {} although {}", realm.name(), shortfall);
+        }
+        realm.verify_rootfs(rootfs)?;
+        if !realm.config().encrypted_home() {
+            return self.launch_realm(realm, rootfs);
+        }
+        // The home directory is mounted before the launch config files are
+        // written and closed again if the realm does not start.
+        let home = EncryptedHome::new(realm);
+        home.open()?;
+        let result = self.launch_realm(realm, rootfs);
+        if result.is_err() {
+            if let Err(e) = home.close() {
+                warn!("failed to close encrypted home of realm {}: {}", realm.name(), e);
+            }
+        }
+        result
+    }
+
+    fn launch_realm(&self, realm: &Realm, rootfs: &Path) -> Result<()> {
+        // Ownership is shifted before the launch config files are written because
+        // the uid range is passed to systemd-nspawn in the .nspawn file. Without a
+        // shift a previous one is undone, for example when private-users has been
+        // disabled or idmapped mounts are now used instead.
+        let shift = if Self::needs_home_uid_shift(realm) {
+            Self::assign_uid_shift(realm)?
+        } else {
+            0
+        };
+        self.shift_home_ownership(realm, shift)?;
         let mut launcher = RealmLauncher::new(realm);
-        launcher.write_launch_config_files(rootfs, &mut lock)?;
-        self.systemctl_start(&launcher.realm_service_name())?;
-        if realm.config().ephemeral_home() {
+        let forwards = realm.config().port_forwards();
+        {
+            let mut lock = self.network.lock().unwrap();
+            if realm.config().managed_netns() && realm.config().network() {
+                self.create_managed_netns(realm, &mut lock)?;
+            }
+            PortForwarder::check_conflicts(realm.name(), &forwards)?;
+            let plan = launcher.plan(rootfs, &lock)?;
+            launcher.apply(&plan, &mut lock)?;
+            if let Some(interface) = realm.config().vpn_required() {
+                // Installed before the realm service starts so that no traffic can
+                // leave the realm before the restrictions are in place
+                self.apply_kill_switch(realm, &lock, interface)?;
+            }
+            self.starting.lock().unwrap().insert(realm.name().to_string());
+        }
+        let result = self.start_realm_service(realm, &launcher, &forwards);
+        self.starting.lock().unwrap().remove(realm.name());
+        result
+    }
+
+    /// Generate the launch config files `start_realm()` would write for `realm`
+    /// without writing them or allocating a network address.
+    pub fn preview_realm(&self, realm: &Realm, rootfs: &Path) -> Result<LaunchPlan> {
+        let network = self.network.lock().unwrap();
+        RealmLauncher::new(realm).plan(rootfs, &network)
+    }
+
+    fn start_realm_service(&self, realm: &Realm, launcher: &RealmLauncher, forwards: &[PortForward]) -> Result<()> {
+        let filtered = realm.config().session_bus_filtered();
+        if filtered {
+            SessionBusProxy::new(realm).start()
+                .map_err(|e| format_err!("failed to start session bus proxy for realm {}: {}", realm.name(), e))?;
+        }
+        let result = self.start_realm_unit(realm, launcher, forwards);
+        // The proxy is otherwise only stopped when the realm stops
+        if result.is_err() && filtered {
+            if let Err(e) = SessionBusProxy::new(realm).stop() {
+                warn!("failed to stop session bus proxy for realm {}: {}", realm.name(), e);
+            }
+        }
+        result
+    }
+
+    fn start_realm_unit(&self, realm: &Realm, launcher: &RealmLauncher, forwards: &[PortForward]) -> Result<()> {
+        let service = launcher.realm_service_name();
+        if !self.systemctl_start(service)? && !self.retry_start_without_stale_machine(realm, service)? {
+            let message = Self::start_failure_message(launcher.realm_service_name(), |cmd, args| {
+                Exec::new(cmd).args(args).capture()
+                    .map(|out| out.stdout().to_string())
+            });
+            bail!("{}", message);
+        }
+        if !forwards.is_empty() {
+            let lock = self.network.lock().unwrap();
+            // Checked again because another realm may have added conflicting forwards
+            PortForwarder::check_conflicts(realm.name(), forwards)?;
+            self.add_port_forwards(realm, &lock, forwards)?;
+        }
+        if realm.config().home_mode() == HomeMode::Ephemeral {
             self.setup_ephemeral_home(realm)?;
         }
         Ok(())
     }
 
-    fn setup_ephemeral_home(&self, realm: &Realm) -> Result<()> {
+    // A machine left registered by a crashed realm makes systemd-nspawn fail
+    // with "machine already exists". If that is why the service did not start,
+    // terminate the machine and start the service once more.
+    fn retry_start_without_stale_machine(&self, realm: &Realm, service: &str) -> Result<bool> {
+        match Self::recover_realm_machine(realm.name()) {
+            Ok(true) => {
+                info!("Retrying start of {} after terminating stale machine {}", service, realm.name());
+                self.systemctl_start(service)
+            },
+            Ok(false) => Ok(false),
+            Err(e) => {
+                warn!("failed to check for stale machine of realm {}: {}", realm.name(), e);
+                Ok(false)
+            },
+        }
+    }
 
-        // 1) if exists: machinectl copy-to /realms/skel /home/user
-        if Path::new("/realms/skel").exists() {
-            self.machinectl_copy_to(realm, "/realms/skel", "/home/user")?;
+    /// Terminate the machine `name` registered with systemd-machined.
+    pub fn terminate_machine(name: &str) -> Result<()> {
+        let status = Exec::new(MACHINECTL_PATH)
+            .args(["terminate", name])
+            .status()
+            .map_err(|e| format_err!("failed to execute {}: {}", MACHINECTL_PATH, e))?;
+        if !status.success() {
+            bail!("machinectl terminate {} failed", name);
+        }
+        Ok(())
+    }
+
+    /// Terminate the machine of realm `name` if it is still registered although
+    /// the realm service is not active. Returns `true` if a machine was terminated.
+    pub fn recover_realm_machine(name: &str) -> Result<bool> {
+        if !Self::stale_realm_machines(Self::capture_stdout)?.iter().any(|m| m == name) {
+            return Ok(false);
+        }
+        warn!("Terminating stale machine {} which has no active realm service", name);
+        Self::terminate_machine(name)?;
+        Ok(true)
+    }
+
+    /// Terminate every machine named like a realm which has no active realm
+    /// service and return the names of the machines which were terminated.
+    pub fn terminate_stale_machines() -> Result<Vec<String>> {
+        let mut terminated = Vec::new();
+        for name in Self::stale_realm_machines(Self::capture_stdout)? {
+            match Self::terminate_machine(&name) {
+                Ok(()) => terminated.push(name),
+                Err(e) => warn!("failed to terminate stale machine {}: {}", name, e),
+            }
+        }
+        Ok(terminated)
+    }
+
+    fn capture_stdout(cmd: &str, args: &[&str]) -> io::Result<String> {
+        Exec::new(cmd).args(args).capture()
+            .map(|out| out.stdout().to_string())
+    }
+
+    // Machines registered with systemd-machined which are named like a realm but
+    // have no realm service which is active. The commands are run with `run` so
+    // the decision can be tested without systemd.
+    fn stale_realm_machines<F>(run: F) -> Result<Vec<String>>
+        where F: Fn(&str, &[&str]) -> io::Result<String>
+    {
+        let machines = run(MACHINECTL_PATH, &["list", "--no-legend", "--no-pager"])
+            .map_err(|e| format_err!("failed to execute {}: {}", MACHINECTL_PATH, e))?;
+        let units = run(SYSTEMCTL_PATH, LIST_REALM_UNITS_ARGS)
+            .map_err(|e| format_err!("failed to execute {}: {}", SYSTEMCTL_PATH, e))?;
+        let active = Self::parse_realm_units(&units);
+        Ok(Self::parse_machine_list(&machines).into_iter()
+            .filter(|name| Realm::is_valid_name(name) && !active.contains(name))
+            .collect())
+    }
+
+    // Each line of `machinectl list` is: MACHINE CLASS SERVICE OS VERSION ADDRESSES
+    fn parse_machine_list(output: &str) -> Vec<String> {
+        output.lines()
+            .map(|line| line.split_whitespace().collect::<Vec<_>>())
+            .filter(|fields| fields.len() >= 3 && fields[1] == "container" && fields[2] == "systemd-nspawn")
+            .map(|fields| fields[0].to_string())
+            .collect()
+    }
+
+    // Build the error reported when the realm service fails to start, including
+    // the recent log lines of the unit from `systemctl status`. The command is run
+    // with `run` so the message can be tested without systemd.
+    fn start_failure_message<F>(service: &str, run: F) -> String
+        where F: FnOnce(&str, &[&str]) -> io::Result<String>
+    {
+        let mut message = format!("failed to start {}", service);
+        match run(SYSTEMCTL_PATH, &["status", "--no-pager", "--lines", "20", service]) {
+            Ok(ref status) if !status.trim().is_empty() => {
+                message.push_str(":\n");
+                message.push_str(status.trim_end());
+            },
+            Ok(_) => {},
+            Err(e) => message.push_str(&format!(" (could not read unit status: {})", e)),
+        }
+        message
+    }
+
+    fn read_systemd_version() -> Option<u32> {
+        let output = Exec::new(SYSTEMCTL_PATH).arg("--version").capture().ok()?;
+        Self::parse_systemd_version(output.stdout())
+    }
+
+    // First line of output from systemctl --version is: systemd 239 (239)
+    fn parse_systemd_version(output: &str) -> Option<u32> {
+        let mut words = output.split_whitespace();
+        if words.next() != Some("systemd") {
+            return None;
         }
+        words.next().and_then(|v| v.parse().ok())
+    }
+
+    /// Return an error explaining why `PrivateUsers=` cannot be used if the
+    /// running systemd is too old to support it.
+    pub fn check_private_users_supported() -> Result<()> {
+        match *SYSTEMD_VERSION {
+            Some(v) if v >= PRIVATE_USERS_MIN_VERSION => Ok(()),
+            Some(v) => bail!("private-users option requires systemd version {} or newer but running systemd is version {}", PRIVATE_USERS_MIN_VERSION, v),
+            None => bail!("private-users option cannot be enabled because the running systemd version could not be determined"),
+        }
+    }
+
+    /// Return the environment of the systemd user manager of the desktop user as
+    /// KEY=value lines. Variables exported by the desktop session are imported
+    /// into this environment but are not in the environment of system services.
+    pub fn user_manager_environment() -> Result<String> {
+        let output = std::process::Command::new(SYSTEMCTL_PATH)
+            .args(["--user", "show-environment"])
+            .uid(1000).gid(1000)
+            .env_clear()
+            .env("XDG_RUNTIME_DIR", USER_RUNTIME_DIR)
+            .stderr(Stdio::null())
+            .output()
+            .map_err(|e| format_err!("failed to execute {}: {}", SYSTEMCTL_PATH, e))?;
+        if !output.status.success() {
+            bail!("{} --user show-environment failed: {}", SYSTEMCTL_PATH, output.status);
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// Return `true` if the running systemd supports idmapped bind mounts.
+    pub fn supports_idmap() -> bool {
+        SYSTEMD_VERSION.map(|v| v >= IDMAP_MIN_VERSION).unwrap_or(false)
+    }
+
+    // Files in the realm home directory must be owned by the uid range of the
+    // container when private-users is enabled and the home directory cannot be
+    // mounted with an idmapped bind mount. The home directory is never idmapped
+    // when it is the lower layer of an overlay.
+    fn needs_home_uid_shift(realm: &Realm) -> bool {
+        let config = realm.config();
+        config.private_users() && (config.home_mode() == HomeMode::ReadOnlyOverlay || !Self::supports_idmap())
+    }
+
+    /// Return the base of the uid range systemd-nspawn must assign to `realm`
+    /// because its home directory has been shifted to that range, or `None` if
+    /// any range can be picked.
+    pub(crate) fn home_uid_shift(realm: &Realm) -> Option<u32> {
+        if !Self::needs_home_uid_shift(realm) {
+            return None;
+        }
+        Self::read_uid_shift_marker(&realm.base_path_file(HOME_UID_SHIFT_FILE))
+    }
 
-        // 2) if exists: machinectl copy-to /realms/realm-$name /home/user
-        let realm_skel = realm.base_path_file("skel");
-        if realm_skel.exists() {
-            self.machinectl_copy_to(realm, realm_skel.to_str().unwrap(), "/home/user")?;
+    // The range recorded for the realm is kept, otherwise the lowest range not
+    // recorded for any other realm is assigned.
+    fn assign_uid_shift(realm: &Realm) -> Result<u32> {
+        if let Some(shift) = Self::read_uid_shift_marker(&realm.base_path_file(HOME_UID_SHIFT_FILE)) {
+            return Ok(shift);
         }
+        let mut used = Vec::new();
+        for entry in fs::read_dir(Realms::BASE_PATH)? {
+            if let Some(shift) = Self::read_uid_shift_marker(&entry?.path().join(HOME_UID_SHIFT_FILE)) {
+                used.push(shift);
+            }
+        }
+        let mut shift = UID_SHIFT_BASE;
+        while used.contains(&shift) {
+            shift += UID_RANGE_SIZE;
+        }
+        Ok(shift)
+    }
 
+    fn read_uid_shift_marker(marker: &Path) -> Option<u32> {
+        fs::read_to_string(marker).ok()
+            .and_then(|s| s.trim().parse::<u32>().ok())
+            .filter(|&shift| shift != 0)
+    }
+
+    // Shift ownership of files in realm home directory so that they belong to
+    // the uid range starting at `shift`. The currently applied shift is tracked
+    // in a marker file so the (potentially slow) shift only runs when the
+    // assigned uid range changes.
+    fn shift_home_ownership(&self, realm: &Realm, shift: u32) -> Result<()> {
         let home = realm.base_path_file("home");
-        if !home.exists() {
+        let marker = realm.base_path_file(HOME_UID_SHIFT_FILE);
+        let current = Self::read_uid_shift_marker(&marker).unwrap_or(0);
+
+        if current == shift || !home.exists() {
             return Ok(());
         }
 
-        for dir in realm.config().ephemeral_persistent_dirs() {
-            let src = home.join(&dir);
-            if src.exists() {
-                let src = src.canonicalize()?;
-                if src.starts_with(&home) && src.exists() {
-                    let dst = Path::new("/home/user").join(&dir);
-                    self.machinectl_bind(realm, &src, &dst)?;
+        warn!("Changing ownership of all files in {} from uid range {} to {} for realm {}. This is done once and may take some time.",
+              home.display(), current, shift, realm.name());
+
+        util::shift_tree_ownership(&home, current, shift, UID_RANGE_SIZE)?;
+        if shift == 0 {
+            fs::remove_file(&marker)?;
+        } else {
+            fs::write(&marker, format!("{}\n", shift))?;
+        }
+        Ok(())
+    }
+
+    // Persistent directories are already bind mounted by systemd-nspawn when
+    // this runs, so skel files are not copied over anything inside them.
+    fn setup_ephemeral_home(&self, realm: &Realm) -> Result<()> {
+        let persistent = realm.config().ephemeral_persistent_dirs();
+
+        // 1) if exists: machinectl copy-to /realms/skel /home/user
+        // 2) if exists: machinectl copy-to /realms/realm-$name/skel /home/user
+        for skel in &[PathBuf::from("/realms/skel"), realm.base_path_file("skel")] {
+            if skel.exists() {
+                for (src, dst) in Self::skel_copy_items(skel, Path::new(""), &persistent)? {
+                    let dst = Path::new("/home/user").join(dst);
+                    self.machinectl_copy_to(realm, &src, &dst.display().to_string())?;
                 }
             }
         }
 
+        // 3) copied files keep host uids which are not mapped inside a private-users realm
+        if realm.config().private_users() {
+            self.machinectl_chown_home(realm)?;
+        }
+        Ok(())
+    }
+
+    // List files below `skel` to copy into the home directory, as pairs of source
+    // path and path relative to the home directory. Persistent directories are
+    // skipped and directories containing a persistent directory are descended into
+    // rather than copied whole.
+    fn skel_copy_items(skel: &Path, relative: &Path, persistent: &[String]) -> Result<Vec<(PathBuf, PathBuf)>> {
+        let mut items = Vec::new();
+        let mut entries = fs::read_dir(skel.join(relative))?
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let rel = relative.join(entry.file_name());
+            if persistent.iter().any(|dir| Path::new(dir) == rel) {
+                continue;
+            }
+            let contains_persistent = persistent.iter().any(|dir| Path::new(dir).starts_with(&rel));
+            if contains_persistent && entry.file_type()?.is_dir() {
+                items.extend(Self::skel_copy_items(skel, &rel, persistent)?);
+            } else {
+                items.push((entry.path(), rel));
+            }
+        }
+        Ok(items)
+    }
+
+    fn create_managed_netns(&self, realm: &Realm, network: &mut NetworkConfig) -> Result<()> {
+        let config = realm.config();
+        let zone = config.network_zone();
+        let addr = network.allocate_for_realm(zone, realm.name(), config.reserved_ip())?;
+        let gw = network.gateway(zone)?;
+        let bridge = network.bridge_for(zone)?;
+        let mac = network.mac_for(zone, realm.name())?;
+        if let Err(e) = NetnsManager::create(realm.name(), &bridge, &addr, &gw, &mac) {
+            network.free_allocation_for(zone, realm.name())?;
+            bail!("failed to create network namespace for realm {}: {}", realm.name(), e);
+        }
+        Ok(())
+    }
+
+    /// Free network address allocations of realms whose service is not running.
+    ///
+    /// A realm is recorded as starting by `start_realm()` while the network lock
+    /// is held for allocating its address, and until its service has started, so
+    /// the address of a realm which is being started is never freed here.
+    pub fn reconcile_network_allocations(&self) -> Result<Vec<String>> {
+        let mut network = self.network.lock().unwrap();
+        let starting = self.starting.lock().unwrap();
+        let running = Self::running_realm_services()?;
+        network.retain_allocations(|name| running.contains(name) || starting.contains(name))
+    }
+
+    /// Return the current network address allocations as `(zone, realm, address)`
+    pub fn network_allocations(&self) -> Vec<(String, String, IpAddr)> {
+        let network = self.network.lock().unwrap();
+        network.zone_names().into_iter()
+            .flat_map(|zone| network.allocations(&zone).into_iter()
+                .map(move |(realm, address)| (zone.clone(), realm, address)))
+            .collect()
+    }
+
+    /// Allocate the IPv4 addresses in `allocations` to running realms which have
+    /// no allocation in the zone, for example when the allocations were saved by
+    /// a previous realmsd process but the allocation file has been lost. Returns
+    /// the names of the realms which were given an address.
+    pub fn restore_network_allocations(&self, allocations: &[(String, String, IpAddr)]) -> Result<Vec<String>> {
+        let mut network = self.network.lock().unwrap();
+        let running = Self::running_realm_services()?;
+        let mut restored = Vec::new();
+        for (zone, realm, address) in allocations {
+            let ip = match address {
+                IpAddr::V4(ip) => *ip,
+                IpAddr::V6(_) => continue,
+            };
+            if !running.contains(realm) || !network.has_zone(zone) || network.allocations(zone).iter().any(|(name, _)| name == realm) {
+                continue;
+            }
+            match network.restore_allocation(zone, realm, ip) {
+                Ok(_) => restored.push(realm.clone()),
+                Err(e) => warn!("Could not restore network address {} of realm {}: {}", address, realm, e),
+            }
+        }
+        Ok(restored)
+    }
+
+    pub fn install_firewall(&self) -> Result<()> {
+        self.network.lock().unwrap().install_firewall()
+    }
+
+    pub fn load_new_network_zones(&self) -> Result<Vec<String>> {
+        self.network.lock().unwrap().load_new_zones()
+    }
+
+    /// Return the name, subnet, number of allocated addresses, and number of
+    /// allocatable addresses of each network zone.
+    pub fn network_zones(&self) -> Vec<(String, String, u32, u32)> {
+        let network = self.network.lock().unwrap();
+        network.zone_names().into_iter().flat_map(|zone| {
+            let subnet = network.subnet(&zone).ok()?;
+            let (used, capacity) = network.zone_utilization(&zone).ok()?;
+            Some((zone, subnet, used, capacity))
+        }).collect()
+    }
+
+    /// Free the network address allocation of `realm` after it failed to start.
+    pub fn free_network_allocation(&self, realm: &Realm) -> Result<()> {
+        let mut network = self.network.lock().unwrap();
+        if Self::is_active(realm)? {
+            return Ok(());
+        }
+        network.retain_allocations(|name| name != realm.name())?;
+        Ok(())
+    }
+
+    /// Remove launch config files left in /run/systemd for realms which are not
+    /// in `known`. Files of a realm service which is still active are kept.
+    pub fn remove_orphaned_launch_config_files(&self, known: &[&str]) -> Result<Vec<PathBuf>> {
+        let active = Self::running_realm_services()?;
+        RealmLauncher::remove_orphaned_launch_config_files(known, &active)
+    }
+
+    // Names of realms with a realm service which is running, starting or waiting to restart
+    fn running_realm_services() -> Result<HashSet<String>> {
+        let output = Exec::new(SYSTEMCTL_PATH)
+            .args(LIST_REALM_UNITS_ARGS)
+            .capture()
+            .map_err(|e| format_err!("failed to execute {}: {}", SYSTEMCTL_PATH, e))?;
+        output.check()?;
+        Ok(Self::parse_realm_units(output.stdout()))
+    }
+
+    fn parse_realm_units(output: &str) -> HashSet<String> {
+        output.lines()
+            .flat_map(|line| line.split_whitespace().next())
+            .filter(|unit| unit.starts_with("realm-") && unit.ends_with(".service"))
+            .map(|unit| unit["realm-".len()..unit.len() - ".service".len()].to_string())
+            .filter(|name| !name.is_empty())
+            .collect()
+    }
+
+    /// The IPv4 address allocated to `realm` on its zone bridge, if any
+    pub fn realm_address(&self, realm: &Realm) -> Option<Ipv4Addr> {
+        self.network.lock().unwrap().allocated_address(realm.name())
+    }
+
+    fn add_port_forwards(&self, realm: &Realm, network: &NetworkConfig, forwards: &[PortForward]) -> Result<()> {
+        let address = match network.allocated_address(realm.name()) {
+            Some(address) => address,
+            None => bail!("cannot forward ports to realm {} because it has no network address", realm.name()),
+        };
+        let bridge = network.bridge_for(realm.config().network_zone())?;
+        if let Err(e) = PortForwarder::add(realm.name(), &bridge, address, forwards) {
+            if let Err(e) = PortForwarder::remove(realm.name()) {
+                warn!("failed to remove port forwards for realm {}: {}", realm.name(), e);
+            }
+            bail!("failed to forward ports to realm {}: {}", realm.name(), e);
+        }
         Ok(())
     }
 
+    fn apply_kill_switch(&self, realm: &Realm, network: &NetworkConfig, interface: &str) -> Result<NetworkBlockState> {
+        let addresses = network.allocations(realm.config().network_zone()).into_iter()
+            .filter(|(name, _)| name == realm.name())
+            .map(|(_, address)| address)
+            .collect::<Vec<_>>();
+        let vpn_up = KillSwitch::is_interface_up(interface);
+        if !vpn_up {
+            warn!("VPN interface {} is down, blocking network traffic of realm {}", interface, realm.name());
+        }
+        KillSwitch::apply(realm.name(), &addresses, interface, vpn_up)
+            .map_err(|e| format_err!("failed to install VPN kill switch for realm {}: {}", realm.name(), e))
+    }
+
+    /// Update the kill switch rules of `realm` to match the current state of the
+    /// interface named by its `vpn-required` option. Returns the new state if it changed.
+    pub fn update_kill_switch(&self, realm: &Realm) -> Result<Option<NetworkBlockState>> {
+        let interface = match realm.config().vpn_required() {
+            Some(interface) => interface.to_string(),
+            None => return Ok(None),
+        };
+        let network = self.network.lock().unwrap();
+        let wanted = if KillSwitch::is_interface_up(&interface) {
+            NetworkBlockState::VpnOnly
+        } else {
+            NetworkBlockState::Blocked
+        };
+        if KillSwitch::state(realm.name())? == wanted {
+            return Ok(None);
+        }
+        self.apply_kill_switch(realm, &network, &interface).map(Some)
+    }
+
+    /// Remove the kill switch rules of `realm` unless its realm service is running
+    pub fn remove_kill_switch(&self, realm: &Realm) -> Result<()> {
+        if Self::is_active(realm)? {
+            return Ok(());
+        }
+        KillSwitch::remove(realm.name())
+    }
+
     pub fn stop_realm(&self, realm: &Realm) -> Result<()> {
         let launcher = RealmLauncher::new(realm);
-        self.systemctl_stop(&launcher.realm_service_name())?;
+        self.systemctl_stop(launcher.realm_service_name())?;
         launcher.remove_launch_config_files()?;
 
+        if let Err(e) = PortForwarder::remove(realm.name()) {
+            warn!("failed to remove port forwards for realm {}: {}", realm.name(), e);
+        }
+
+        if let Err(e) = KillSwitch::remove(realm.name()) {
+            warn!("failed to remove VPN kill switch for realm {}: {}", realm.name(), e);
+        }
+
+        if realm.config().session_bus_filtered() {
+            if let Err(e) = SessionBusProxy::new(realm).stop() {
+                warn!("failed to stop session bus proxy for realm {}: {}", realm.name(), e);
+            }
+        }
+
+        if realm.config().managed_netns() {
+            if let Err(e) = NetnsManager::remove(realm.name()) {
+                warn!("failed to remove network namespace for realm {}: {}", realm.name(), e);
+            }
+        }
+
+        // Closed even if encrypted-home has since been disabled in the config
+        let home = EncryptedHome::new(realm);
+        if home.is_open() {
+            if let Err(e) = home.close() {
+                warn!("failed to close encrypted home of realm {}: {}", realm.name(), e);
+            }
+        }
+
         let mut network = self.network.lock().unwrap();
         network.free_allocation_for(realm.config().network_zone(), realm.name())?;
         Ok(())
@@ -86,7 +714,7 @@ impl Systemd {
     }
 
     fn run_systemctl(&self, op: &str, name: &str) -> Result<bool> {
-        Command::new(SYSTEMCTL_PATH)
+        Exec::new(SYSTEMCTL_PATH)
             .arg(op)
             .arg(name)
             .status()
@@ -97,85 +725,547 @@ impl Systemd {
     pub fn machinectl_copy_to(&self, realm: &Realm, from: impl AsRef<Path>, to: &str) -> Result<()> {
         let from = from.as_ref().to_str().unwrap();
         info!("calling machinectl copy-to {} {} {}", realm.name(), from, to);
-        Command::new(MACHINECTL_PATH)
-            .args(&["copy-to", realm.name(), from, to ])
+        let status = Exec::new(MACHINECTL_PATH)
+            .args(["copy-to", realm.name(), from, to ])
             .status()
             .map_err(|e| format_err!("failed to machinectl copy-to {} {} {}: {}", realm.name(), from, to, e))?;
+        if !status.success() {
+            bail!("machinectl copy-to {} {} {} failed: {}", realm.name(), from, to, status);
+        }
+        Ok(())
+    }
+
+    pub fn machinectl_copy_from(&self, realm: &Realm, from: &str, to: impl AsRef<Path>) -> Result<()> {
+        let to = to.as_ref().to_str().unwrap();
+        info!("calling machinectl copy-from {} {} {}", realm.name(), from, to);
+        let status = Exec::new(MACHINECTL_PATH)
+            .args(["copy-from", realm.name(), from, to ])
+            .status()
+            .map_err(|e| format_err!("failed to machinectl copy-from {} {} {}: {}", realm.name(), from, to, e))?;
+        if !status.success() {
+            bail!("machinectl copy-from {} {} {} failed: {}", realm.name(), from, to, status);
+        }
         Ok(())
     }
 
-    fn machinectl_bind(&self, realm: &Realm, from: &Path, to: &Path) -> Result<()> {
-        let from = from.display().to_string();
-        let to = to.display().to_string();
-        Command::new(MACHINECTL_PATH)
-            .args(&["--mkdir", "bind", realm.name(), from.as_str(), to.as_str() ])
+    pub fn machinectl_bind(&self, realm: &Realm, from: &Path, to: &str) -> Result<()> {
+        let from = from.to_str().unwrap();
+        info!("calling machinectl bind {} {} {}", realm.name(), from, to);
+        let status = Exec::new(MACHINECTL_PATH)
+            .args(["bind", "--mkdir", realm.name(), from, to])
             .status()
             .map_err(|e| format_err!("failed to machinectl bind {} {} {}: {}", realm.name(), from, to, e))?;
+        if !status.success() {
+            bail!("machinectl bind {} {} {} failed: {}", realm.name(), from, to, status);
+        }
         Ok(())
     }
 
+    // Mounts which are still in use are detached so that the mount point is
+    // removed immediately.
+    pub fn machinectl_umount(&self, realm: &Realm, path: &str) -> Result<()> {
+        let status = Exec::new(MACHINECTL_PATH)
+            .args(["--quiet", "shell", &format!("root@{}", realm.name()), "/usr/bin/umount", "--lazy", path])
+            .status()
+            .map_err(|e| format_err!("failed to unmount {} in realm {}: {}", path, realm.name(), e))?;
+        if !status.success() {
+            bail!("unmounting {} in realm {} failed: {}", path, realm.name(), status);
+        }
+        Ok(())
+    }
+
+    fn machinectl_chown_home(&self, realm: &Realm) -> Result<()> {
+        let status = Exec::new(MACHINECTL_PATH)
+            .args(["--quiet", "shell", &format!("root@{}", realm.name()), "/usr/bin/chown", "-R", "--no-dereference", "1000:1000", "/home/user"])
+            .status()
+            .map_err(|e| format_err!("failed to change ownership of /home/user in realm {}: {}", realm.name(), e))?;
+        if !status.success() {
+            bail!("failed to change ownership of /home/user in realm {}: chown exited with {}", realm.name(), status);
+        }
+        Ok(())
+    }
+
+    /// Suspend all processes of the realm.
+    pub fn freeze_realm(&self, realm: &Realm) -> Result<()> {
+        self.set_realm_frozen(realm, true)
+    }
+
+    /// Resume all processes of a realm suspended with `freeze_realm()`.
+    pub fn thaw_realm(&self, realm: &Realm) -> Result<()> {
+        self.set_realm_frozen(realm, false)
+    }
+
+    // Older versions of systemd lack the freeze and thaw verbs so the cgroup
+    // freezer of the realm service is written directly.
+    fn set_realm_frozen(&self, realm: &Realm, frozen: bool) -> Result<()> {
+        let service = format!("realm-{}.service", realm.name());
+        if SYSTEMD_VERSION.map(|v| v >= FREEZE_MIN_VERSION).unwrap_or(false) {
+            let verb = if frozen { "freeze" } else { "thaw" };
+            if !self.run_systemctl(verb, &service)? {
+                bail!("systemctl {} {} failed", verb, service);
+            }
+            return Ok(());
+        }
+        let cgroup = Self::control_group(&service)?;
+        match Self::cgroup_freezer_file(Path::new(CGROUP_ROOT), &cgroup, frozen) {
+            Some((path, value)) => fs::write(&path, value)
+                .map_err(|e| format_err!("failed to write {}: {}", path.display(), e)),
+            None => bail!("no cgroup freezer found for {} in {}", service, CGROUP_ROOT),
+        }
+    }
+
+    fn control_group(service: &str) -> Result<String> {
+        let output = Exec::new(SYSTEMCTL_PATH)
+            .args(["show", "--property=ControlGroup", "--value", service])
+            .capture()
+            .map_err(|e| format_err!("failed to execute {}: {}", SYSTEMCTL_PATH, e))?;
+        let cgroup = output.stdout().trim().to_string();
+        if cgroup.is_empty() {
+            bail!("could not determine control group of {}", service);
+        }
+        Ok(cgroup)
+    }
+
+    // Return the freezer file of `cgroup` and the value to write to it for the
+    // unified (v2) hierarchy if available, otherwise for the v1 freezer controller.
+    fn cgroup_freezer_file(root: &Path, cgroup: &str, frozen: bool) -> Option<(PathBuf, &'static str)> {
+        let cgroup = cgroup.trim_start_matches('/');
+        let unified = root.join(cgroup).join("cgroup.freeze");
+        if unified.exists() {
+            return Some((unified, if frozen { "1" } else { "0" }));
+        }
+        let legacy = root.join("freezer").join(cgroup).join("freezer.state");
+        if legacy.exists() {
+            return Some((legacy, if frozen { "FROZEN" } else { "THAWED" }));
+        }
+        None
+    }
+
+    /// Unit properties for the `allowed-cpus`, `cpu-weight` and `nice` options of `config`
+    pub fn resource_properties(config: &RealmConfig) -> Vec<String> {
+        let mut props = Vec::new();
+        if let Some(cpus) = config.allowed_cpus() {
+            props.push(format!("AllowedCPUs={}", cpus));
+        }
+        if let Some(weight) = config.cpu_weight() {
+            props.push(format!("CPUWeight={}", weight));
+        }
+        if let Some(nice) = config.nice() {
+            props.push(format!("Nice={}", nice));
+        }
+        props
+    }
+
+    /// Change resource limits of a running realm without restarting it. `limits` are
+    /// pairs of config option name and value as accepted by `RealmConfig::from_resource_limits()`.
+    ///
+    /// The change only lasts until the realm is stopped. `Nice=` is not a resource control
+    /// property which `systemctl set-property` can change so the processes already running in the
+    /// realm are reniced instead.
+    pub fn apply_resource_properties(&self, realm: &Realm, limits: &[(String, String)]) -> Result<()> {
+        let config = RealmConfig::from_resource_limits(limits)?;
+        let service = format!("realm-{}.service", realm.name());
+        let props = Self::resource_properties(&config).into_iter()
+            .filter(|p| !p.starts_with("Nice="))
+            .collect::<Vec<_>>();
+        if !props.is_empty() {
+            Exec::new(SYSTEMCTL_PATH)
+                .args(["set-property", "--runtime", &service])
+                .args(&props)
+                .capture()
+                .map_err(|e| format_err!("failed to execute {}: {}", SYSTEMCTL_PATH, e))?
+                .check()?;
+        }
+        if let Some(nice) = config.nice() {
+            Self::renice_service(&service, nice)?;
+        }
+        Ok(())
+    }
+
+    fn renice_service(service: &str, nice: i32) -> Result<()> {
+        let cgroup = Self::control_group(service)?;
+        let cgroup = cgroup.trim_start_matches('/');
+        let mut dir = Path::new(CGROUP_ROOT).join(cgroup);
+        if !dir.join("cgroup.procs").exists() {
+            dir = Path::new(CGROUP_ROOT).join("systemd").join(cgroup);
+        }
+        for pid in Self::cgroup_pids(&dir)? {
+            unsafe {
+                if libc::setpriority(libc::PRIO_PROCESS as _, pid, nice) == -1 {
+                    warn!("Failed to set nice {} on process {}: {}", nice, pid, io::Error::last_os_error());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Collect process ids from cgroup.procs of `dir` and all child cgroups
+    fn cgroup_pids(dir: &Path) -> Result<Vec<u32>> {
+        let mut pids = fs::read_to_string(dir.join("cgroup.procs"))
+            .map_err(|e| format_err!("failed to read processes of cgroup {}: {}", dir.display(), e))?
+            .lines()
+            .flat_map(|line| line.trim().parse().ok())
+            .collect::<Vec<u32>>();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                pids.extend(Self::cgroup_pids(&entry.path())?);
+            }
+        }
+        Ok(pids)
+    }
+
+    /// Clear the failed state of the realm service, including the start rate limit.
+    pub fn reset_failed(&self, realm: &Realm) -> Result<()> {
+        let service = format!("realm-{}.service", realm.name());
+        if !self.run_systemctl("reset-failed", &service)? {
+            bail!("systemctl reset-failed {} failed", service);
+        }
+        Ok(())
+    }
+
+    /// Return `true` if systemd has stopped restarting the realm service
+    /// because it was started too many times within the start limit interval.
+    pub fn is_start_limit_hit(realm: &Realm) -> Result<bool> {
+        let output = Exec::new(SYSTEMCTL_PATH)
+            .args(["show", "--property=Result", "--value"])
+            .arg(format!("realm-{}.service", realm.name()))
+            .capture()
+            .map_err(|e| format_err!("failed to execute {}: {}", SYSTEMCTL_PATH, e))?;
+        Ok(output.stdout().trim() == "start-limit-hit")
+    }
+
+    /// Return how long ago the realm service entered the active state, or `None`
+    /// if it has not. Read from the `ActiveEnterTimestampMonotonic` property of
+    /// the unit so that changes to the system clock do not affect the result.
+    pub fn active_enter_elapsed(realm: &Realm) -> Result<Option<Duration>> {
+        let output = Exec::new(SYSTEMCTL_PATH)
+            .args(["show", "--property=ActiveEnterTimestampMonotonic"])
+            .arg(format!("realm-{}.service", realm.name()))
+            .capture()
+            .map_err(|e| format_err!("failed to execute {}: {}", SYSTEMCTL_PATH, e))?;
+        output.check()?;
+        let entered = match Self::parse_active_enter_timestamp(output.stdout()) {
+            Some(entered) => entered,
+            None => return Ok(None),
+        };
+        Ok(util::monotonic_time()?.checked_sub(entered))
+    }
+
+    // Parse the output of `systemctl show --property=ActiveEnterTimestampMonotonic`
+    // which is microseconds since boot, or 0 if the unit was never active.
+    fn parse_active_enter_timestamp(output: &str) -> Option<Duration> {
+        output.lines()
+            .flat_map(|line| line.trim().strip_prefix("ActiveEnterTimestampMonotonic="))
+            .next()
+            .and_then(|usec| usec.parse::<u64>().ok())
+            .filter(|&usec| usec > 0)
+            .map(Duration::from_micros)
+    }
+
     pub fn is_active(realm: &Realm) -> Result<bool> {
-        Command::new(SYSTEMCTL_PATH)
-            .args(&["--quiet", "is-active"])
+        Exec::new(SYSTEMCTL_PATH)
+            .args(["--quiet", "is-active"])
             .arg(format!("realm-{}", realm.name()))
             .status()
             .map(|status| status.success())
             .map_err(|e| format_err!("failed to execute {}: {}", SYSTEMCTL_PATH, e))
     }
 
-    pub fn are_realms_active(realms: &mut Vec<Realm>) -> Result<String> {
+    /// Return whether the service of each realm in `realms` is active, determined
+    /// with a single `systemctl is-active` call. systemctl exits with a non-zero
+    /// status when any of the units is not active, so the exit status is ignored.
+    pub fn are_realms_active(realms: &[Realm]) -> Result<Vec<bool>> {
+        if realms.is_empty() {
+            return Ok(Vec::new());
+        }
         let args: Vec<String> = realms.iter()
             .map(|r| format!("realm-{}", r.name()))
             .collect();
 
-        let result = Command::new("/usr/bin/systemctl")
+        let output = Exec::new(SYSTEMCTL_PATH)
             .arg("is-active")
             .args(args)
-            .stderr(Stdio::inherit())
-            .output()?;
+            .capture()
+            .map_err(|e| format_err!("failed to execute {}: {}", SYSTEMCTL_PATH, e))?;
 
-        Ok(String::from_utf8(result.stdout).unwrap().trim().to_owned())
+        Self::parse_is_active_output(output.stdout(), realms.len())
     }
 
-    pub fn machinectl_exec_shell(realm: &Realm, as_root: bool, launcher: bool) -> Result<()> {
-        let username = if as_root { "root" } else { "user" };
-        let args = ["/bin/bash".to_string()];
-        Self::machinectl_shell(realm, &args, username, launcher, false)
+    // One state per line in the order the units were given. A unit which does
+    // not exist is reported as "inactive" or, by older versions, "unknown".
+    fn parse_is_active_output(output: &str, count: usize) -> Result<Vec<bool>> {
+        let states = output.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|state| state == "active" || state == "reloading")
+            .collect::<Vec<_>>();
+        if states.len() != count {
+            bail!("systemctl is-active returned {} unit states for {} realms", states.len(), count);
+        }
+        Ok(states)
     }
 
-    pub fn machinectl_shell<S: AsRef<str>>(realm: &Realm, args: &[S], user: &str, launcher: bool, quiet: bool) -> Result<()> {
-        let mut cmd = Command::new(MACHINECTL_PATH);
-        cmd.arg("--quiet");
+    pub fn machinectl_exec_shell(realm: &Realm, username: &str, launcher: bool) -> Result<ExitStatus> {
+        let args = ["/bin/bash".to_string()];
+        // An interactive shell needs the pty which machinectl shell allocates
+        Self::run_shell(ShellBackend::Machinectl, realm, &args, username, launcher, false)
+    }
 
-        cmd.arg(format!("--setenv=REALM_NAME={}", realm.name()));
+    /// Run a command in `realm` as `user` and return the exit status of the command.
+    ///
+    /// If the running systemd supports it the command is run with `systemd-run --wait --pipe`
+    /// rather than `machinectl shell` since machinectl does not reliably return the exit
+    /// code of the command.
+    pub fn machinectl_shell<S: AsRef<str>>(realm: &Realm, args: &[S], user: &str, launcher: bool, quiet: bool) -> Result<ExitStatus> {
+        let backend = ShellBackend::for_version(*SYSTEMD_VERSION);
+        Self::run_shell(backend, realm, args, user, launcher, quiet)
+    }
 
-        if let Ok(val) = env::var("DESKTOP_STARTUP_ID") {
-            cmd.arg(format!("--setenv=DESKTOP_STARTUP_ID={}", val));
+    /// Return an error unless `user` is a user name which commands may be run as
+    /// inside a realm. Only names of lowercase letters, digits, '_' and '-' which
+    /// start with a letter or '_' are accepted.
+    pub fn check_username(user: &str) -> Result<()> {
+        let valid = user.len() <= MAX_USERNAME_LEN &&
+            user.starts_with(|c: char| c.is_ascii_lowercase() || c == '_') &&
+            user.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-');
+        if !valid {
+            bail!("invalid user name '{}'", user);
         }
+        Ok(())
+    }
 
-        let config = realm.config();
-        if config.wayland() && !config.x11() {
-            cmd.arg("--setenv=GDK_BACKEND=wayland");
+    fn run_shell<S: AsRef<str>>(backend: ShellBackend, realm: &Realm, args: &[S], user: &str, launcher: bool, quiet: bool) -> Result<ExitStatus> {
+        Self::check_username(user)?;
+        let mut cmd = Exec::new(backend.path());
+        cmd.args(Self::shell_args(backend, realm, args, user, launcher));
+
+        if quiet {
+            cmd.stdin(Stdio::null());
+            cmd.quiet();
         }
 
-        cmd.arg("shell");
-        cmd.arg(format!("{}@{}", user, realm.name()));
+        let status = cmd.status()
+            .map_err(|error| ShellSpawnError { command: backend.path(), error })?;
+        Ok(status)
+    }
+
+    fn shell_args<S: AsRef<str>>(backend: ShellBackend, realm: &Realm, args: &[S], user: &str, launcher: bool) -> Vec<String> {
+        let mut v = vec!["--quiet".to_string()];
+        match backend {
+            ShellBackend::Machinectl => {
+                v.extend(Self::setenv_args(realm));
+                v.push("shell".to_string());
+                v.push(format!("{}@{}", user, realm.name()));
+            },
+            ShellBackend::SystemdRun => {
+                v.push("--wait".to_string());
+                v.push("--pipe".to_string());
+                v.push(format!("--machine={}", realm.name()));
+                v.push(format!("--uid={}", user));
+                // Set up a login session as machinectl shell does
+                v.push("--property=PAMName=login".to_string());
+                v.extend(Self::setenv_args(realm));
+            },
+        }
 
         if launcher {
-            cmd.arg("/usr/libexec/launch");
+            v.push("/usr/libexec/launch".to_string());
         }
 
-        if quiet {
-            cmd.stdin(Stdio::null());
-            cmd.stdout(Stdio::null());
-            cmd.stderr(Stdio::null());
+        v.extend(args.iter().map(|s| s.as_ref().to_string()));
+        v
+    }
+
+    // Build the list of --setenv arguments passed to machinectl shell so that
+    // processes launched in the realm see the same environment as the realm
+    // was booted with.
+    fn setenv_args(realm: &Realm) -> Vec<String> {
+        let mut args = vec![format!("--setenv=REALM_NAME={}", realm.name())];
+
+        if let Ok(val) = env::var("DESKTOP_STARTUP_ID") {
+            args.push(format!("--setenv=DESKTOP_STARTUP_ID={}", val));
         }
 
-        for arg in args {
-            cmd.arg(arg.as_ref());
+        let config = realm.config();
+        if config.wayland() && !config.x11() {
+            args.push("--setenv=GDK_BACKEND=wayland".to_string());
         }
 
-        cmd.status().map_err(|e| format_err!("failed to execute{}: {}", MACHINECTL_PATH, e))?;
-        Ok(())
+        for item in config.environment() {
+            args.push(format!("--setenv={}", item));
+        }
+        args
     }
 }
+
+#[test]
+fn test_setenv_args() {
+    let realm = Realm::new("envtest");
+    realm.config();
+    realm.with_mut_config(|c| {
+        c.environment = Some(vec!["HTTP_PROXY=http://proxy:3128".to_string(), "BAD KEY=x".to_string()]);
+    });
+    let args = Systemd::setenv_args(&realm);
+    assert_eq!(args[0], "--setenv=REALM_NAME=envtest");
+    assert!(args.contains(&"--setenv=HTTP_PROXY=http://proxy:3128".to_string()));
+    assert!(!args.iter().any(|a| a.contains("BAD KEY")));
+}
+
+#[test]
+fn test_parse_systemd_version() {
+    assert_eq!(Systemd::parse_systemd_version("systemd 239 (239)\n+PAM +AUDIT -SELINUX\n"), Some(239));
+    assert_eq!(Systemd::parse_systemd_version("systemd 252 (252.6-1)\n"), Some(252));
+    assert_eq!(Systemd::parse_systemd_version("not systemd\n"), None);
+    assert_eq!(Systemd::parse_systemd_version(""), None);
+    assert_eq!(ShellBackend::for_version(Some(234)), ShellBackend::Machinectl);
+    assert_eq!(ShellBackend::for_version(Some(239)), ShellBackend::SystemdRun);
+    assert_eq!(ShellBackend::for_version(None), ShellBackend::Machinectl);
+}
+
+#[test]
+fn test_shell_args() {
+    let realm = Realm::new("shelltest");
+    realm.config();
+    let args = Systemd::shell_args(ShellBackend::Machinectl, &realm, &["/usr/bin/ls", "-l"], "user", true);
+    assert_eq!(args, vec!["--quiet", "--setenv=REALM_NAME=shelltest", "shell", "user@shelltest",
+                          "/usr/libexec/launch", "/usr/bin/ls", "-l"]);
+
+    let args = Systemd::shell_args(ShellBackend::SystemdRun, &realm, &["/usr/bin/ls", "-l"], "root", false);
+    assert_eq!(args, vec!["--quiet", "--wait", "--pipe", "--machine=shelltest", "--uid=root",
+                          "--property=PAMName=login", "--setenv=REALM_NAME=shelltest", "/usr/bin/ls", "-l"]);
+
+    for user in &["user", "root", "guest", "_svc-daemon2"] {
+        assert!(Systemd::check_username(user).is_ok(), "{}", user);
+    }
+    for user in &["", "Guest", "2user", "-user", "us er", "user@host", "../root", &"u".repeat(33)] {
+        assert!(Systemd::check_username(user).is_err(), "{}", user);
+    }
+}
+
+#[test]
+fn test_skel_copy_items() {
+    let skel = crate::util::TempDir::new("skel-test").unwrap();
+    for dir in &[".config/chromium", ".config/gtk-3.0", ".mozilla/firefox", "Documents"] {
+        fs::create_dir_all(skel.join(dir)).unwrap();
+    }
+    fs::write(skel.join(".bashrc"), "").unwrap();
+    fs::write(skel.join(".config/user-dirs.dirs"), "").unwrap();
+
+    let persistent = vec![".mozilla".to_string(), ".config/chromium".to_string()];
+    let items = Systemd::skel_copy_items(&skel, Path::new(""), &persistent).unwrap();
+    let rel = items.iter().map(|(_, rel)| rel.display().to_string()).collect::<Vec<_>>();
+    assert_eq!(rel, vec![".bashrc", ".config/gtk-3.0", ".config/user-dirs.dirs", "Documents"]);
+    assert_eq!(items[1].0, skel.join(".config/gtk-3.0"));
+}
+
+#[test]
+fn test_start_failure_message() {
+    let message = Systemd::start_failure_message("realm-main.service", |cmd, args| {
+        assert_eq!(cmd, SYSTEMCTL_PATH);
+        assert_eq!(args, &["status", "--no-pager", "--lines", "20", "realm-main.service"]);
+        Ok("realm-main.service - Application Image main instance\n   Active: failed (Result: exit-code)\n".to_string())
+    });
+    assert_eq!(message, "failed to start realm-main.service:\nrealm-main.service - Application Image main instance\n   Active: failed (Result: exit-code)");
+
+    let message = Systemd::start_failure_message("realm-main.service", |_,_| Ok(String::new()));
+    assert_eq!(message, "failed to start realm-main.service");
+
+    let message = Systemd::start_failure_message("realm-main.service", |_,_| Err(io::Error::new(io::ErrorKind::NotFound, "not found")));
+    assert_eq!(message, "failed to start realm-main.service (could not read unit status: not found)");
+}
+
+#[test]
+fn test_cgroup_freezer_file() {
+    let root = crate::util::TempDir::new("cgroup-test").unwrap();
+    let cgroup = "/system.slice/realm-main.service";
+    fs::create_dir_all(root.join("freezer/system.slice/realm-main.service")).unwrap();
+    fs::write(root.join("freezer/system.slice/realm-main.service/freezer.state"), "THAWED").unwrap();
+    assert_eq!(Systemd::cgroup_freezer_file(&root, cgroup, true),
+               Some((root.join("freezer/system.slice/realm-main.service/freezer.state"), "FROZEN")));
+
+    fs::create_dir_all(root.join("system.slice/realm-main.service")).unwrap();
+    fs::write(root.join("system.slice/realm-main.service/cgroup.freeze"), "0").unwrap();
+    assert_eq!(Systemd::cgroup_freezer_file(&root, cgroup, false),
+               Some((root.join("system.slice/realm-main.service/cgroup.freeze"), "0")));
+
+    assert_eq!(Systemd::cgroup_freezer_file(&root, "/system.slice/realm-other.service", true), None);
+}
+
+#[test]
+fn test_resource_properties() {
+    let limits = |pairs: &[(&str, &str)]| pairs.iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect::<Vec<_>>();
+
+    let config = RealmConfig::from_resource_limits(&limits(&[("nice", "-5"), ("allowed-cpus", "0,2-3")])).unwrap();
+    assert_eq!(Systemd::resource_properties(&config), vec!["AllowedCPUs=0,2-3", "Nice=-5"]);
+    let config = RealmConfig::from_resource_limits(&limits(&[("cpu-weight", "10000")])).unwrap();
+    assert_eq!(Systemd::resource_properties(&config), vec!["CPUWeight=10000"]);
+    assert!(Systemd::resource_properties(&RealmConfig::empty()).is_empty());
+
+    for bad in &[("cpu-weight", "0"), ("cpu-weight", "10001"), ("cpu-weight", "x"), ("nice", "20"), ("nice", "-21"),
+                 ("allowed-cpus", "5-2"), ("allowed-cpus", "1,,2"), ("allowed-cpus", "-1"), ("memory-max", "1G")] {
+        assert!(RealmConfig::from_resource_limits(&limits(&[*bad])).is_err(), "{:?}", bad);
+    }
+}
+
+#[test]
+fn test_parse_realm_units() {
+    let output = "realm-main.service loaded active running Application Image main instance\n\
+                  realm-work.service loaded activating auto-restart Application Image work instance\n\
+                  realm-.service loaded active running broken\n";
+    let units = Systemd::parse_realm_units(output);
+    assert_eq!(units.len(), 2);
+    assert!(units.contains("main") && units.contains("work"));
+    assert!(Systemd::parse_realm_units("").is_empty());
+}
+
+#[test]
+fn test_parse_active_enter_timestamp() {
+    let parse = Systemd::parse_active_enter_timestamp;
+    assert_eq!(parse("ActiveEnterTimestampMonotonic=8052417233\n"), Some(Duration::from_micros(8052417233)));
+    assert_eq!(parse("ActiveEnterTimestampMonotonic=0\n"), None);
+    assert_eq!(parse("ActiveEnterTimestampMonotonic=\n"), None);
+    assert_eq!(parse(""), None);
+}
+
+#[test]
+fn test_parse_is_active_output() {
+    let parse = Systemd::parse_is_active_output;
+    // main is running, work has stopped, deleted has no unit and old is failed
+    assert_eq!(parse("active\ninactive\ninactive\nfailed\n", 4).unwrap(), vec![true, false, false, false]);
+    assert_eq!(parse("unknown\nreloading\nactivating\n", 3).unwrap(), vec![false, true, false]);
+    assert!(parse("active\n", 2).is_err());
+    assert!(parse("", 0).unwrap().is_empty());
+}
+
+#[test]
+fn test_stale_realm_machines() {
+    // main is running, work crashed and left its machine registered, vm is not
+    // a container and 1build is not named like a realm
+    let machines = "main container systemd-nspawn arch - 172.17.0.2\n\
+                    work container systemd-nspawn arch - -\n\
+                    vm   vm        libvirt-qemu   -    - -\n\
+                    1build container systemd-nspawn debian 12 -\n";
+    let units = "realm-main.service loaded active running Application Image main instance\n";
+    let run = |machines: &'static str, units: &'static str| move |cmd: &str, args: &[&str]| {
+        if cmd == MACHINECTL_PATH {
+            assert_eq!(args, &["list", "--no-legend", "--no-pager"]);
+            Ok(machines.to_string())
+        } else {
+            assert_eq!(args, LIST_REALM_UNITS_ARGS);
+            Ok(units.to_string())
+        }
+    };
+    assert_eq!(Systemd::stale_realm_machines(run(machines, units)).unwrap(), vec!["work"]);
+
+    // A realm waiting to be restarted by systemd still has an active unit
+    let restarting = "realm-main.service loaded active running main\nrealm-work.service loaded activating auto-restart work\n";
+    assert!(Systemd::stale_realm_machines(run(machines, restarting)).unwrap().is_empty());
+    assert_eq!(Systemd::stale_realm_machines(run(machines, "")).unwrap(), vec!["main", "work"]);
+    assert!(Systemd::stale_realm_machines(run("", "")).unwrap().is_empty());
+
+    let failed = Systemd::stale_realm_machines(|_, _| Err(io::Error::new(io::ErrorKind::NotFound, "not found")));
+    assert!(failed.is_err());
+}
@@ -0,0 +1,141 @@
+use std::fs;
+use std::path::{Path,PathBuf};
+
+use crate::Result;
+
+/// Directory listing all USB devices attached to the system
+pub const USB_SYSFS_PATH: &str = "/sys/bus/usb/devices";
+
+///
+/// Matches USB devices by vendor and product id. Matchers are written as
+/// two 4 digit hexadecimal ids separated by ':' and the product id may be
+/// replaced with '*' to match any product from the vendor.
+///
+///    1050:0407
+///    0483:*
+///
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct UsbMatcher {
+    vendor: u16,
+    product: Option<u16>,
+}
+
+impl UsbMatcher {
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut fields = s.split(':');
+        let (vendor, product) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(vendor), Some(product), None) => (vendor, product),
+            _ => bail!("expected vendor:product"),
+        };
+        let vendor = Self::parse_id(vendor)?;
+        let product = if product == "*" {
+            None
+        } else {
+            Some(Self::parse_id(product)?)
+        };
+        Ok(UsbMatcher { vendor, product })
+    }
+
+    fn parse_id(id: &str) -> Result<u16> {
+        if id.len() != 4 || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("'{}' is not a 4 digit hexadecimal id", id);
+        }
+        Ok(u16::from_str_radix(id, 16)?)
+    }
+
+    pub fn matches(&self, device: &UsbDevice) -> bool {
+        self.vendor == device.vendor && self.product.map(|p| p == device.product).unwrap_or(true)
+    }
+}
+
+/// A USB device found in sysfs
+#[derive(Debug,Clone,PartialEq)]
+pub struct UsbDevice {
+    name: String,
+    vendor: u16,
+    product: u16,
+    busnum: u32,
+    devnum: u32,
+}
+
+impl UsbDevice {
+
+    /// Return all USB devices currently attached to the system
+    pub fn scan() -> Vec<UsbDevice> {
+        Self::scan_path(Path::new(USB_SYSFS_PATH))
+    }
+
+    // Entries for device interfaces such as 1-2:1.0 are skipped since they
+    // do not contain vendor and product ids.
+    fn scan_path(sysfs: &Path) -> Vec<UsbDevice> {
+        let entries = match fs::read_dir(sysfs) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Could not read USB devices from {}: {}", sysfs.display(), e);
+                return Vec::new();
+            }
+        };
+        let mut devices = entries.flat_map(|e| e.ok())
+            .filter(|e| !e.file_name().to_string_lossy().contains(':'))
+            .flat_map(|e| Self::load(&e.path()))
+            .collect::<Vec<_>>();
+        devices.sort_by(|a,b| a.name.cmp(&b.name));
+        devices
+    }
+
+    fn load(path: &Path) -> Option<UsbDevice> {
+        let read = |name: &str| fs::read_to_string(path.join(name)).ok().map(|s| s.trim().to_string());
+        let name = path.file_name()?.to_string_lossy().to_string();
+        let vendor = u16::from_str_radix(&read("idVendor")?, 16).ok()?;
+        let product = u16::from_str_radix(&read("idProduct")?, 16).ok()?;
+        let busnum = read("busnum")?.parse().ok()?;
+        let devnum = read("devnum")?.parse().ok()?;
+        Some(UsbDevice { name, vendor, product, busnum, devnum })
+    }
+
+    /// Name of device in sysfs such as `1-2.1`
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return `vendor:product` ids of device in the same form as `UsbMatcher`
+    pub fn id(&self) -> String {
+        format!("{:04x}:{:04x}", self.vendor, self.product)
+    }
+
+    /// Path of device node such as /dev/bus/usb/001/004
+    pub fn dev_path(&self) -> PathBuf {
+        PathBuf::from(format!("/dev/bus/usb/{:03}/{:03}", self.busnum, self.devnum))
+    }
+}
+
+#[test]
+fn test_usb_devices() {
+    assert_eq!(UsbMatcher::parse("1050:0407").unwrap(), UsbMatcher { vendor: 0x1050, product: Some(0x0407) });
+    assert_eq!(UsbMatcher::parse("0483:*").unwrap(), UsbMatcher { vendor: 0x0483, product: None });
+    for bad in &["1050", "1050:407", "*:0407", "1050:0407:1", "zzzz:0407", "+050:0407"] {
+        assert!(UsbMatcher::parse(bad).is_err(), "{}", bad);
+    }
+
+    let sysfs = crate::util::TempDir::new("usb-test").unwrap();
+    let add = |name: &str, ids: &[(&str, &str)]| {
+        let dir = sysfs.join(name);
+        fs::create_dir_all(&dir).unwrap();
+        for (file, value) in ids {
+            fs::write(dir.join(file), format!("{}\n", value)).unwrap();
+        }
+    };
+    add("1-2", &[("idVendor", "1050"), ("idProduct", "0407"), ("busnum", "1"), ("devnum", "4")]);
+    add("1-2:1.0", &[("bInterfaceClass", "03")]);
+    add("2-1", &[("idVendor", "0483"), ("idProduct", "3748"), ("busnum", "2"), ("devnum", "12")]);
+
+    let devices = UsbDevice::scan_path(&sysfs);
+    assert_eq!(devices.len(), 2);
+    assert_eq!(devices[0].dev_path(), Path::new("/dev/bus/usb/001/004"));
+    assert_eq!(devices[1].id(), "0483:3748");
+
+    let any_st = UsbMatcher::parse("0483:*").unwrap();
+    let yubikey = UsbMatcher::parse("1050:0407").unwrap();
+    assert!(yubikey.matches(&devices[0]) && !yubikey.matches(&devices[1]));
+    assert!(any_st.matches(&devices[1]) && !any_st.matches(&devices[0]));
+}
@@ -52,7 +52,7 @@ impl ActivationState {
         let header = realmfs.header();
         let mut lock = self.state_mut();
         if let Some(ref activation) = *lock {
-            return Ok(activation.clone());
+            Ok(activation.clone())
         } else {
             let activation = self._activate(realmfs, header)?;
             let activation = Arc::new(activation);
@@ -94,11 +94,11 @@ impl ActivationState {
             .unwrap_or(false)
     }
 
-    fn state(&self) -> RwLockReadGuard<Option<Arc<Activation>>> {
+    fn state(&self) -> RwLockReadGuard<'_, Option<Arc<Activation>>> {
         self.state.read().unwrap()
     }
 
-    fn state_mut(&self) -> RwLockWriteGuard<Option<Arc<Activation>>>{
+    fn state_mut(&self) -> RwLockWriteGuard<'_, Option<Arc<Activation>>>{
         self.state.write().unwrap()
     }
 }
@@ -184,7 +184,24 @@ impl Activation {
             },
             Activation::Verity { mountpoint, device } => {
                 mountpoint.deactivate()?;
-                Verity::close_device(&device)
+                Verity::close_device(device)
+            },
+        }
+    }
+
+    /// Mount the device of this `Activation` again after its mountpoints were
+    /// unmounted, for example by running `umount` by hand.
+    pub fn remount(&self) -> Result<()> {
+        match self {
+            Activation::Loop { ro_mountpoint, rw_mountpoint, device } => {
+                ro_mountpoint.create_dir()?;
+                rw_mountpoint.create_dir()?;
+                device.mount_pair(rw_mountpoint.path(), ro_mountpoint.path())
+            },
+            Activation::Verity { mountpoint, device } => {
+                mountpoint.create_dir()?;
+                cmd!("/usr/bin/mount", "-oro /dev/mapper/{} {}", device, mountpoint)?;
+                Ok(())
             },
         }
     }
@@ -204,15 +221,15 @@ impl Activation {
     /// Return read-only `Mountpoint` for this `Activation`
     pub fn mountpoint(&self) -> &Mountpoint {
         match self {
-            Activation::Loop { ro_mountpoint, ..} => &ro_mountpoint,
-            Activation::Verity { mountpoint, ..} => &mountpoint,
+            Activation::Loop { ro_mountpoint, ..} => ro_mountpoint,
+            Activation::Verity { mountpoint, ..} => mountpoint,
         }
     }
 
     /// Return read-write `Mountpoint` if present for this `Activation` type.
     pub fn mountpoint_rw(&self) -> Option<&Mountpoint> {
         match self {
-            Activation::Loop { rw_mountpoint, ..} => Some(&rw_mountpoint),
+            Activation::Loop { rw_mountpoint, ..} => Some(rw_mountpoint),
             Activation::Verity { .. } => None,
         }
     }
@@ -221,7 +238,7 @@ impl Activation {
     pub fn device(&self) -> &str{
         match self {
             Activation::Loop { device, ..} => device.device_str(),
-            Activation::Verity { device, ..} => &device,
+            Activation::Verity { device, ..} => device,
         }
     }
 
@@ -293,12 +310,12 @@ impl <'a> VerityActivator <'a> {
         if !self.header.has_flag(ImageHeader::FLAG_HASH_TREE) {
             self.generate_verity()?;
         }
-        Verity::new(self.realmfs.path()).setup(&self.header.metainfo())
+        Verity::new(self.realmfs.path(), self.header.size()).setup(&self.header.metainfo())
     }
 
     fn generate_verity(&self) -> Result<()> {
         info!("Generating verity hash tree");
-        Verity::new(self.realmfs.path()).generate_image_hashtree(&self.header.metainfo())?;
+        Verity::new(self.realmfs.path(), self.header.size()).generate_image_hashtree(&self.header.metainfo())?;
         info!("Writing header...");
         self.header.set_flag(ImageHeader::FLAG_HASH_TREE);
         self.header.write_header_to(self.realmfs.path())?;
@@ -355,7 +372,7 @@ impl <'a> LoopActivator<'a> {
         ro.create_dir()?;
         rw.create_dir()?;
 
-        let loopdev = LoopDevice::create(self.realmfs.path(), Some(4096), false)?;
+        let loopdev = LoopDevice::create(self.realmfs.path(), Some(self.realmfs.header().size()), false)?;
 
         loopdev.mount_pair(rw.path(), ro.path())?;
 
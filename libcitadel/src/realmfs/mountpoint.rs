@@ -35,7 +35,9 @@ impl Mountpoint {
     ///
     /// The directory name of the mountpoint will have the structure:
     ///
-    ///     realmfs-$name-$tag.mountpoint
+    /// ```text
+    /// realmfs-$name-$tag.mountpoint
+    /// ```
     ///
     pub fn new(name: &str, tag: &str) -> Self {
         let filename = format!("realmfs-{}-{}.mountpoint", name, tag);
@@ -90,7 +92,7 @@ impl Mountpoint {
     }
 
     fn has_valid_extention(&self) -> bool {
-        self.path().extension().map_or(false, |e| e == "mountpoint")
+        self.path().extension().is_some_and(|e| e == "mountpoint")
     }
 
     fn filename_fields(path: &Path) -> Option<impl Iterator<Item=&str>> {
@@ -5,7 +5,6 @@ use std::os::unix::fs::MetadataExt;
 use std::path::{Path,PathBuf};
 
 use sodiumoxide::randombytes::randombytes;
-use hex;
 
 use crate::{CommandLine, ImageHeader, MetaInfo, Result, KeyRing, KeyPair, Signature, util, RealmManager};
 
@@ -135,7 +134,7 @@ impl RealmFS {
 
     fn load_realmfs_header(path: &Path) -> Result<ImageHeader> {
         let header = ImageHeader::from_file(path)?;
-        if !header.is_magic_valid() {
+        if header.magic_version().is_none() {
             bail!("Image file {} does not have a valid header", path.display());
         }
         let metainfo = header.metainfo();
@@ -320,7 +319,7 @@ impl RealmFS {
 
     }
 
-    pub fn update(&self) -> Update {
+    pub fn update(&self) -> Update<'_> {
         Update::new(self)
     }
 
@@ -363,14 +362,15 @@ impl RealmFS {
             Err(err) => bail!("Cannot seal realmfs image, no sealing keys available: {}", err),
         };
         let metainfo = self.metainfo();
-        let metainfo_bytes = self.generate_sealed_metainfo(self.name(), metainfo.verity_salt(), metainfo.verity_root());
+        let metainfo_bytes = self.generate_sealed_metainfo(self.name(), metainfo.verity_salt(), metainfo.verity_root())?;
         let sig = keys.sign(&metainfo_bytes);
         self.write_new_metainfo(&metainfo_bytes, Some(sig))
     }
 
     /// Convert to unsealed RealmFS image by removing dm-verity metadata and hash tree
     pub fn unseal(&self) -> Result<()> {
-        let bytes = Self::generate_unsealed_metainfo(self.name(), self.metainfo().nblocks(), None);
+        let mut bytes = Self::generate_unsealed_metainfo(self.name(), self.metainfo().nblocks(), None);
+        bytes.extend(self.metainfo().extra_section()?.as_bytes());
         self.write_new_metainfo(&bytes, None)?;
         if self.has_verity_tree() {
             self.truncate_verity()?;
@@ -397,7 +397,8 @@ impl RealmFS {
         if self.is_sealed() {
             bail!("Cannot update metainfo on sealed realmfs image");
         }
-        let metainfo_bytes = Self::generate_unsealed_metainfo(name, nblocks, owner_realm);
+        let mut metainfo_bytes = Self::generate_unsealed_metainfo(name, nblocks, owner_realm);
+        metainfo_bytes.extend(self.metainfo().extra_section()?.as_bytes());
         self.write_new_metainfo(&metainfo_bytes, None)
     }
 
@@ -420,12 +421,13 @@ impl RealmFS {
         v
     }
 
-    fn generate_sealed_metainfo(&self, name: &str, verity_salt: &str, verity_root: &str) -> Vec<u8> {
+    fn generate_sealed_metainfo(&self, name: &str, verity_salt: &str, verity_root: &str) -> Result<Vec<u8>> {
         let mut v = Self::generate_unsealed_metainfo(name, self.metainfo().nblocks(), None);
         writeln!(v, "channel = \"{}\"", Self::USER_KEYNAME).unwrap();
         writeln!(v, "verity-salt = \"{}\"", verity_salt).unwrap();
         writeln!(v, "verity-root = \"{}\"", verity_root).unwrap();
-        v
+        v.extend(self.metainfo().extra_section()?.as_bytes());
+        Ok(v)
     }
 
     // Remove verity tree from image file by truncating file to the number of blocks in metainfo
@@ -450,11 +452,11 @@ impl RealmFS {
     fn file_nblocks(&self) -> Result<usize> {
         let meta = self.path.metadata()?;
         let len = meta.len() as usize;
-        if len % 4096 != 0 {
+        if !len.is_multiple_of(4096) {
             bail!("realmfs image file '{}' has size which is not a multiple of block size", self.path.display());
         }
         let nblocks = len / 4096;
-        if nblocks < (self.metainfo().nblocks() + 1) {
+        if nblocks < self.metainfo_nblocks() {
             bail!("realmfs image file '{}' has shorter length than nblocks field of image header", self.path.display());
         }
         Ok(nblocks)
@@ -523,13 +525,13 @@ impl RealmFS {
     fn generate_sealing_verity(&self, keys: &KeyPair, name: &str) -> Result<()> {
         info!("Generating verity hash tree for sealed realmfs ({})", self.path().display());
         let salt = hex::encode(randombytes(32));
-        let output = Verity::new(self.path()).generate_image_hashtree_with_salt(&self.metainfo(), &salt)?;
+        let output = Verity::new(self.path(), self.header().size()).generate_image_hashtree_with_salt(&self.metainfo(), &salt)?;
         let root_hash = output.root_hash()
             .ok_or_else(|| format_err!("no root hash returned from verity format operation"))?;
         info!("root hash is {}", output.root_hash().unwrap());
 
         info!("Signing new image with user realmfs keys");
-        let metainfo_bytes = self.generate_sealed_metainfo(name, &salt, &root_hash);
+        let metainfo_bytes = self.generate_sealed_metainfo(name, &salt, root_hash)?;
         let sig = keys.sign(&metainfo_bytes);
 
         self.header().set_flag(ImageHeader::FLAG_HASH_TREE);
@@ -572,7 +574,7 @@ impl RealmFS {
     }
 
     pub fn free_size_blocks(&self) -> Result<usize> {
-        let sb = Superblock::load(self.path(), 4096)?;
+        let sb = Superblock::load(self.path(), self.header().size() as u64)?;
         Ok(sb.free_block_count() as usize)
     }
 
@@ -581,16 +583,16 @@ impl RealmFS {
         Ok(meta.blocks() as usize / 8)
     }
 
-    /// Size of image file in blocks (including header block) based on metainfo `nblocks` field.
+    /// Size of image file in blocks (including header blocks) based on metainfo `nblocks` field.
     pub fn metainfo_nblocks(&self) -> usize {
-        self.metainfo().nblocks() + 1
+        self.metainfo().nblocks() + self.header().nblocks()
     }
 
     /// Return `true` if mountpoint belongs to current `Activation` state of
     /// this `RealmFS`
     pub fn release_mountpoint(&self, mountpoint: &Mountpoint)  -> bool {
         let is_ours = self.activation()
-            .map_or(false, |a| a.is_mountpoint(mountpoint));
+            .is_some_and(|a| a.is_mountpoint(mountpoint));
 
         if is_ours {
             if let Err(e) = self.deactivate() {
@@ -65,7 +65,7 @@ impl RealmFSSet {
 
     pub fn realmfs_list(&self) -> Vec<RealmFS> {
         let mut v = self.realmfs_map.values().cloned().collect::<Vec<RealmFS>>();
-        v.sort_unstable_by(|a,b| a.name().cmp(&b.name()));
+        v.sort_unstable_by(|a,b| a.name().cmp(b.name()));
         v
     }
 }
\ No newline at end of file
@@ -96,7 +96,7 @@ impl <'a> ImageResizer<'a> {
             info!("Running resize2fs {:?}", open_loop);
             cmd!(RESIZE2FS, "{}", open_loop.device().display())?;
         } else {
-            LoopDevice::with_loop(self.image.path(), Some(4096), false, |loopdev| {
+            LoopDevice::with_loop(self.image.path(), Some(self.image.header().size()), false, |loopdev| {
             	info!("Running e2fsck {:?}", loopdev);
            	cmd!(E2FSCK,"{} {} {}","-f","-p",loopdev.device().display())?;
                 info!("Running resize2fs {:?}", loopdev);
@@ -105,7 +105,7 @@ impl <'a> ImageResizer<'a> {
             })?;
         }
         let owner = self.image.metainfo().realmfs_owner().map(|s| s.to_owned());
-        self.image.update_unsealed_metainfo(self.image.name(), new_nblocks - 1, owner)?;
+        self.image.update_unsealed_metainfo(self.image.name(), new_nblocks - self.image.header().nblocks(), owner)?;
         Ok(())
     }
 
@@ -134,7 +134,7 @@ impl <'a> ImageResizer<'a> {
     /// recommended size. Pass this value to `ImageResizer.grow_to()` to
     /// complete the resize.
     pub fn auto_resize_size(realmfs: &RealmFS) -> Option<ResizeSize> {
-        let sb = match Superblock::load(realmfs.path(), 4096) {
+        let sb = match Superblock::load(realmfs.path(), realmfs.header().size() as u64) {
             Ok(sb) => sb,
             Err(e) => {
                 warn!("Error reading superblock from {}: {}", realmfs.path().display(), e);
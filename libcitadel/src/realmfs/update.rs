@@ -40,7 +40,7 @@ impl <'a> Update<'a> {
         if let UpdateType::Sealed(ref image) = self.update_type {
             image
         } else {
-            &self.realmfs
+            self.realmfs
         }
     }
 
@@ -76,10 +76,12 @@ impl <'a> Update<'a> {
         let mut alloc = BridgeAllocator::default_bridge()?;
         let addr = alloc.allocate_address_for(&self.name())?;
         let gw = alloc.gateway();
+        let mac = alloc.mac_for(&self.name());
         self.network_allocated = true;
         Command::new("/usr/bin/systemd-nspawn")
             .arg(format!("--setenv=IFCONFIG_IP={}", addr))
             .arg(format!("--setenv=IFCONFIG_GW={}", gw))
+            .arg(format!("--setenv=IFCONFIG_MAC={}", mac))
             .arg("--quiet")
             .arg(format!("--machine={}", self.name()))
             .arg(format!("--directory={}", mountpoint))
@@ -88,9 +90,8 @@ impl <'a> Update<'a> {
             .arg("-c")
             .arg(command)
             .status()
-            .map_err(|e| {
+            .inspect_err(|_| {
                 let _ = self.cleanup();
-                e
             })?;
         self.deactivate_update()?;
         Ok(())
@@ -1,17 +1,23 @@
+use std::cmp::Ordering;
 use std::fs::{self,File,DirEntry};
 use std::ffi::OsStr;
-use std::io::{self,Seek,SeekFrom};
+use std::io::{self,Read,Seek,SeekFrom};
 use std::path::{Path, PathBuf};
+use std::process::{Command,Stdio};
 
-use crate::{CommandLine, OsRelease, ImageHeader, MetaInfo, Result, Partition, Mounts, util, LoopDevice};
+use crate::{CommandLine, OsRelease, ImageHeader, MetaInfo, Result, Partition, util, LoopDevice};
 
 use failure::ResultExt;
 use std::sync::Arc;
 use crate::UtsName;
 use crate::verity::Verity;
+use crate::blockdev::{BlockDev,BlockDevReader};
+use crate::storage;
 
 const STORAGE_BASEDIR: &str = "/sysroot/storage/resources";
 const RUN_DIRECTORY: &str = "/run/citadel/images";
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+const SYS_BLOCK: &str = "/sys/block";
 
 /// Locates and mounts a resource image file.
 ///
@@ -24,8 +30,10 @@ const RUN_DIRECTORY: &str = "/run/citadel/images";
 /// Various kernel command line options control how the resource file is
 /// searched for and how it is mounted.
 ///
-///     citadel.noverity:     Mount image without dm-verity. Also do not verify header signature.
-///     citadel.nosignatures: Do not verify header signature.
+/// ```text
+/// citadel.noverity:     Mount image without dm-verity. Also do not verify header signature.
+/// citadel.nosignatures: Do not verify header signature.
+/// ```
 ///
 /// A requested image file will be searched for first in /run/citadel/images and if not found there the
 /// usual location of /storage/resources is searched.
@@ -39,51 +47,98 @@ impl ResourceImage {
     /// Locate and return a resource image of type `image_type`.
     /// First the /run/citadel/images directory is searched, and if not found there,
     /// the image will be searched for in /storage/resources/$channel
+    ///
+    /// If several images match, the best candidate is chosen as described
+    /// for `find_candidates()`.
     pub fn find(image_type: &str) -> Result<Self> {
+        Self::find_candidates(image_type)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| format_err!("Failed to find resource image of type: {}", image_type))
+    }
+
+    /// Locate all resource images of type `image_type` in the first directory
+    /// containing any, ordered from best to worst candidate:
+    ///
+    ///   1. Highest `version`
+    ///   2. Newest `timestamp`
+    ///
+    /// Kernel images are only candidates if their `kernel-version` matches the
+    /// running kernel.
+    ///
+    fn find_candidates(image_type: &str) -> Result<Vec<Self>> {
         let channel = Self::rootfs_channel();
 
         info!("Searching run directory for image {} with channel {}", image_type, channel);
 
-        if let Some(image) = search_directory(RUN_DIRECTORY, image_type, Some(&channel))? {
-            return Ok(image);
+        let candidates = search_directory(RUN_DIRECTORY, image_type, Some(channel))?;
+        if !candidates.is_empty() {
+            return Ok(candidates);
         }
 
-        if !Self::ensure_storage_mounted()? {
-            bail!("Unable to mount /storage");
-        }
+        Self::ensure_storage_mounted()?;
 
-        let storage_path = Path::new(STORAGE_BASEDIR).join(&channel);
+        let storage_path = Path::new(STORAGE_BASEDIR).join(channel);
+        search_directory(storage_path, image_type, Some(channel))
+    }
 
-        if let Some(image) = search_directory(storage_path, image_type, Some(&channel))? {
-           return Ok(image);
+    /// Mount the best image of type `image_type`. If an image fails to mount,
+    /// for example because dm-verity setup fails, the next candidate is tried.
+    pub fn mount_image_type(image_type: &str) -> Result<()> {
+        let candidates = Self::find_candidates(image_type)?;
+        if candidates.is_empty() {
+            bail!("Failed to find resource image of type: {}", image_type);
+        }
+        for mut image in candidates {
+            let mount_path = image.mount_path();
+            match image.mount_at(&mount_path) {
+                Ok(_) => return image.process_manifest_file(),
+                Err(err) => warn!("Failed to mount {} image {}: {}", image_type, image.path().display(), err),
+            }
         }
+        bail!("Failed to mount any resource image of type: {}", image_type)
+    }
 
-        Err(format_err!("Failed to find resource image of type: {}", image_type))
+    /// List resource images which are currently mounted below /run/citadel/images
+    pub fn mounted_images() -> Vec<MountedImage> {
+        match fs::read_to_string(MOUNTINFO) {
+            Ok(content) => Self::mounted_images_from(&content),
+            Err(e) => {
+                warn!("Failed to read {}: {}", MOUNTINFO, e);
+                Vec::new()
+            }
+        }
     }
 
-    pub fn mount_image_type(image_type: &str) -> Result<()> {
-        let mut image = Self::find(image_type)?;
-        image.mount()
+    /// Return the resource images mounted below /run/citadel/images listed in
+    /// `content`, which is in the format of /proc/self/mountinfo.
+    pub fn mounted_images_from(content: &str) -> Vec<MountedImage> {
+        parse_mountinfo(content, Path::new(RUN_DIRECTORY))
+            .into_iter()
+            .map(|(mountpoint, source)| MountedImage::new(mountpoint, source))
+            .collect()
     }
 
     /// Locate a rootfs image in /run/citadel/images and return it
     pub fn find_rootfs() -> Result<Self> {
-        match search_directory(RUN_DIRECTORY, "rootfs", None)? {
-            Some(image) => Ok(image),
-            None => Err(format_err!("Failed to find rootfs resource image")),
+        let matches = search_directory(RUN_DIRECTORY, "rootfs", None)?;
+        if matches.len() > 1 {
+            warn!("Found multiple rootfs images in {}, but no channel specified", RUN_DIRECTORY);
         }
+        matches.into_iter().next()
+            .ok_or_else(|| format_err!("Failed to find rootfs resource image"))
     }
 
     pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
         let header = ImageHeader::from_file(path.as_ref())?;
-        if !header.is_magic_valid() {
+        if header.magic_version().is_none() {
             bail!("Image file {} does not have a valid header", path.as_ref().display());
         }
         Ok(Self::new(path.as_ref(), header ))
     }
 
     pub fn is_valid_image(&self) -> bool {
-        self.header.is_magic_valid()
+        self.header.magic_version().is_some()
     }
 
     /// Return path to the resource image file.
@@ -92,7 +147,7 @@ impl ResourceImage {
     }
 
     fn verity(&self) -> Verity {
-        Verity::new(self.path())
+        Verity::new(self.path(), self.header.size())
     }
 
     pub fn header(&self) -> &ImageHeader {
@@ -122,11 +177,49 @@ impl ResourceImage {
     /// Mount resource image at specified path without processing manifest file.
     /// Returns a ResourceMount object which can be stored and later used to unmount
     /// the image.
+    ///
+    /// If the image file is stored compressed (`FLAG_KEEP_COMPRESSED`) the
+    /// image data is first uncompressed to a copy in /run/citadel/images and
+    /// the copy is mounted instead. The copy is deleted once it has been
+    /// mounted, so the space it uses is released when the image is unmounted.
     pub fn mount_at<P: AsRef<Path>>(&mut self, mount_path: P) -> Result<ResourceMount> {
+        if !self.is_kept_compressed() {
+            return self.mount_image_at(mount_path.as_ref());
+        }
+        let filename = self.path.file_name()
+            .ok_or_else(|| format_err!("Image path {} has no filename", self.path.display()))?;
+        let target = Path::new(RUN_DIRECTORY).join(filename);
+        info!("Image file {} is stored compressed, mounting uncompressed copy {}", self.path.display(), target.display());
+        let copy = match self.decompress_copy(&target) {
+            Ok(copy) => copy,
+            Err(e) => {
+                Self::remove_uncompressed_copy(&target);
+                return Err(e);
+            }
+        };
+        *self = copy;
+        let result = self.mount_image_at(mount_path.as_ref());
+        // The loop device holds a reference to the file so the data remains
+        // available until the image is unmounted.
+        Self::remove_uncompressed_copy(&target);
+        result
+    }
+
+    fn mount_image_at(&self, mount_path: &Path) -> Result<ResourceMount> {
         if CommandLine::noverity() {
-            self.mount_noverity(mount_path.as_ref())
+            self.mount_noverity(mount_path)
         } else {
-            self.mount_verity(mount_path.as_ref())
+            self.mount_verity(mount_path)
+        }
+    }
+
+    fn remove_uncompressed_copy(path: &Path) {
+        for path in &[path.to_path_buf(), path.with_extension("tmp"), path.with_extension("tmp.xz")] {
+            if path.exists() {
+                if let Err(e) = fs::remove_file(path) {
+                    warn!("Failed to remove uncompressed image copy {}: {}", path.display(), e);
+                }
+            }
         }
     }
 
@@ -143,23 +236,55 @@ impl ResourceImage {
             return Ok(())
         }
         info!("decompressing image file {}", self.path().display());
+        self.decompress_data_to(self.path())?;
+
+        self.header.clear_flag(ImageHeader::FLAG_DATA_COMPRESSED);
+        self.header.write_header_to(self.path())?;
+
+        Ok(())
+    }
+
+    /// Returns `true` if the image file is stored compressed and should
+    /// only be uncompressed to a temporary copy.
+    pub fn is_kept_compressed(&self) -> bool {
+        self.is_compressed() && self.header.has_flag(ImageHeader::FLAG_KEEP_COMPRESSED)
+    }
+
+    /// Write an uncompressed copy of this compressed image to `target` and
+    /// return it. The image file itself is not modified.
+    pub fn decompress_copy(&self, target: &Path) -> Result<ResourceImage> {
+        if !self.is_compressed() {
+            bail!("Cannot create uncompressed copy of {} because image is not compressed", self.path.display());
+        }
+        info!("decompressing image file {} to {}", self.path.display(), target.display());
+        self.decompress_data_to(target)?;
+
+        let header = ImageHeader::from_file(self.path())?;
+        header.clear_flag(ImageHeader::FLAG_DATA_COMPRESSED);
+        header.clear_flag(ImageHeader::FLAG_KEEP_COMPRESSED);
+        header.write_header_to(target)?;
+        Ok(ResourceImage::new(target, header))
+    }
+
+    // Uncompress the image data into `target`. The space for the header at
+    // the start of `target` is left empty.
+    fn decompress_data_to(&self, target: &Path) -> Result<()> {
         let mut reader = File::open(self.path())?;
-        reader.seek(SeekFrom::Start(4096))?;
+        reader.seek(SeekFrom::Start(self.header.size() as u64))?;
 
-        let xzfile = self.path.with_extension("tmp.xz");
+        let xzfile = target.with_extension("tmp.xz");
         let mut out = File::create(&xzfile)?;
         io::copy(&mut reader, &mut out)?;
 
         util::xz_decompress(xzfile)?;
-        fs::rename(self.path.with_extension("tmp"), self.path())?;
-
-        self.header.clear_flag(ImageHeader::FLAG_DATA_COMPRESSED);
-        self.header.write_header_to(self.path())?;
-
+        fs::rename(target.with_extension("tmp"), target)?;
         Ok(())
     }
 
-    pub fn write_to_partition(&self, partition: &Partition) -> Result<()> {
+    /// Write a rootfs image to `partition`. If `verify` is `true` the written data is read
+    /// back from the device and compared with the shasum in the metainfo. If this fails
+    /// the partition header is cleared so that the partition is left uninitialized.
+    pub fn write_to_partition(&self, partition: &Partition, verify: bool) -> Result<()> {
         if self.metainfo().image_type() != "rootfs" {
             bail!("Cannot write to partition, image type is not rootfs");
         }
@@ -169,7 +294,15 @@ impl ResourceImage {
         }
 
         info!("writing rootfs image to {}", partition.path().display());
-        cmd_with_output!("/bin/dd", "if={} of={} bs=4096 skip=1", self.path.display(), partition.path().display())?;
+        if verify {
+            cmd_with_output!("/bin/dd", "if={} of={} bs=4096 skip={} conv=fsync", self.path.display(), partition.path().display(), self.header.nblocks())?;
+            if let Err(err) = self.verify_partition_write(partition) {
+                ImageHeader::clear_partition(partition.path())?;
+                return Err(err);
+            }
+        } else {
+            cmd_with_output!("/bin/dd", "if={} of={} bs=4096 skip={}", self.path.display(), partition.path().display(), self.header.nblocks())?;
+        }
 
         /*
         let args = format!("if={} of={} bs=4096 skip=1",
@@ -183,6 +316,22 @@ impl ResourceImage {
         Ok(())
     }
 
+    // Read back the image data written to `partition` and check that the sha256
+    // matches the metainfo shasum.
+    fn verify_partition_write(&self, partition: &Partition) -> Result<()> {
+        let len = self.metainfo().nblocks() * 4096;
+        info!("Verifying image data written to {}", partition.path().display());
+        let dev = BlockDev::open_ro(partition.path())?;
+        let mut reader = ProgressReader::new(BlockDevReader::new(dev, len)?, len);
+        let shasum = util::sha256_reader(&mut reader, None)?;
+        if shasum != self.metainfo().shasum() {
+            bail!("Data read back from {} does not match image shasum (expected {}, read {})",
+                  partition.path().display(), self.metainfo().shasum(), shasum);
+        }
+        info!("Image data written to {} verified", partition.path().display());
+        Ok(())
+    }
+
     fn mount_verity(&self, mount_path: &Path) -> Result<ResourceMount> {
         let verity_dev = self.setup_verity_device()?;
         let verity_path = format!("/dev/mapper/{}", verity_dev);
@@ -244,12 +393,39 @@ impl ResourceImage {
             self.decompress()?;
         }
         info!("Calculating sha256 of image");
-        let output = util::exec_cmdline_pipe_input("sha256sum", "-", self.path(), util::FileRange::Range{offset: 4096, len: self.metainfo().nblocks() * 4096})
-            .context(format!("failed to calculate sha256 on {}", self.path().display()))?;
-        let v: Vec<&str> = output.split_whitespace().collect();
-        let shasum = v[0].trim().to_owned();
-        Ok(shasum)
+        let len = self.metainfo().nblocks() as u64 * 4096;
+        util::sha256_partial(self.path(), self.header.size() as u64, len)
+    }
 
+    /// Calculate the sha256 of the image data without changing the image file.
+    /// Unlike `generate_shasum()` a compressed image is not decompressed in place
+    /// but is streamed through `xz` instead.
+    pub fn calculate_shasum(&self) -> Result<String> {
+        let len = self.metainfo().nblocks() as u64 * 4096;
+        if !self.is_compressed() {
+            return util::sha256_partial(self.path(), self.header.size() as u64, len);
+        }
+        let mut f = File::open(self.path())?;
+        f.seek(SeekFrom::Start(self.header.size() as u64))?;
+        let mut child = Command::new("/usr/bin/xz")
+            .arg("-dc")
+            .stdin(f)
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("unable to execute /usr/bin/xz")?;
+
+        let mut out = child.stdout.take().unwrap();
+        // The start of the compressed data is replaced by the header when decompressed
+        let mut count = 0;
+        let result = io::copy(&mut (&mut out).take(self.header.size() as u64), &mut io::sink())
+            .map_err(|e| e.into())
+            .and_then(|_| util::sha256_reader((&mut out).take(len), Some(&mut |n| count = n)));
+        drop(out);
+        child.wait()?;
+        if result.is_ok() && count != len {
+            bail!("Compressed image data in {} ended before calculating sha256 on {} bytes", self.path.display(), len);
+        }
+        result
     }
 
     // Mount the resource image but use a simple loop mount rather than setting up a dm-verity
@@ -261,14 +437,14 @@ impl ResourceImage {
             self.decompress()?;
         }
 
-        let loopdev = LoopDevice::create(self.path(), Some(4096), true)?;
+        let loopdev = LoopDevice::create(self.path(), Some(self.header.size()), true)?;
 
         info!("Loop device created: {}", loopdev);
         info!("Mounting to: {}", mount_path.display());
 
         fs::create_dir_all(mount_path)?;
 
-        util::mount(&loopdev.device_str(), mount_path, Some("-oro"))?;
+        util::mount(loopdev.device_str(), mount_path, Some("-oro"))?;
 
         Ok(ResourceMount::new_loop(mount_path, loopdev))
     }
@@ -293,7 +469,7 @@ impl ResourceImage {
         }
         let s = fs::read_to_string(manifest)?;
         for line in s.lines() {
-            if let Err(e) = self.process_manifest_line(&line) {
+            if let Err(e) = self.process_manifest_line(line) {
                 warn!("Processing manifest file for resource image ({}): {}", self.path.display(), e);
             }
         }
@@ -322,30 +498,15 @@ impl ResourceImage {
         let to = Path::new("/sysroot").join(path_to);
 
         info!("Bind mounting {} to {} from manifest", from.display(), to.display());
-        util::mount(&from.to_string_lossy(), to, Some("--bind"))
+        util::mount(from.to_string_lossy(), to, Some("--bind"))
     }
 
     // If the /storage directory is not mounted, attempt to mount it.
     // Return true if already mounted or if the attempt to mount it succeeds.
-    pub fn ensure_storage_mounted() -> Result<bool> {
-        if Mounts::is_source_mounted("/dev/mapper/citadel-storage")? {
-            return Ok(true);
-        }
-        let path = Path::new("/dev/mapper/citadel-storage");
-        if !path.exists() {
-            return Ok(false);
-        }
-        info!("Mounting /sysroot/storage directory");
-        let res = util::mount(
-            "/dev/mapper/citadel-storage",
-            "/sysroot/storage",
-            Some("-odefaults,nossd,noatime,commit=120")
-        );
-        if let Err(err) = res {
-            warn!("failed to mount /sysroot/storage: {}", err);
-            return Ok(false);
-        }
-        Ok(true)
+    /// Mount the storage partition on /sysroot/storage if it is not already mounted,
+    /// waiting for the storage device to appear. See `storage::ensure_storage_mounted()`.
+    pub fn ensure_storage_mounted() -> Result<()> {
+        storage::ensure_storage_mounted()
     }
 
     fn rootfs_channel() -> &'static str {
@@ -357,72 +518,65 @@ impl ResourceImage {
 }
 
 
-// Search directory for a resource image with the specified channel and image_type
-// in the image header metainfo.  If multiple matches are found, return the image
-// with the highest version number. If multiple images have the same highest version
-// number, return the image with the newest file creation time.
-fn search_directory<P: AsRef<Path>>(dir: P, image_type: &str, channel: Option<&str>) -> Result<Option<ResourceImage>> {
+// Search directory for resource images with the specified channel and image_type
+// in the image header metainfo. The matching images are returned sorted from the
+// best to the worst candidate according to compare_candidates().
+fn search_directory<P: AsRef<Path>>(dir: P, image_type: &str, channel: Option<&str>) -> Result<Vec<ResourceImage>> {
     if !dir.as_ref().exists() {
-        return Ok(None)
+        return Ok(Vec::new())
     }
 
-    let mut best = None;
+    let kernel_version = current_kernel_version();
+    let kv = if image_type == "kernel" { Some(kernel_version.as_str()) } else { None };
 
-    let mut matches = all_matching_images(dir.as_ref(), image_type, channel)?;
+    let mut matches = all_matching_images(dir.as_ref(), image_type, channel, kv)?;
     debug!("Found {} matching images", matches.len());
 
-    if channel.is_none() {
-        if matches.is_empty() {
-            return Ok(None);
-        }
-        if matches.len() > 1 {
-           warn!("Found multiple images of type {} in {}, but no channel specified. Returning arbitrary image",
-                 image_type, dir.as_ref().display());
-        }
-        return Ok(Some(matches.remove(0)))
-    }
+    matches.sort_by(|a, b| compare_candidates(&a.metainfo(), &b.metainfo(), kv));
+    log_candidates(&matches, kv);
+    Ok(matches)
+}
 
-    for image in matches {
-        best = Some(compare_images(best, image)?);
+fn log_candidates(images: &[ResourceImage], kernel_version: Option<&str>) {
+    if images.len() < 2 {
+        return;
     }
-
-    Ok(best)
+    for image in images {
+        let metainfo = image.metainfo();
+        info!("Candidate {} image {} (version={}, timestamp={}, kernel-version={})",
+              metainfo.image_type(), image.path().display(), metainfo.version(),
+              metainfo.timestamp(), metainfo.kernel_version().unwrap_or("none"));
+    }
+    let reason = preference_reason(&images[0].metainfo(), &images[1].metainfo(), kernel_version);
+    info!("Choosing {} because it has {}", images[0].path().display(), reason);
 }
 
-// Compare two images (a and b) and return the image with the highest version number. If
-// both images have the same version return the one with the newest file creation
-// time.  Image a is an Option type, if it is None then just return b.
-fn compare_images(a: Option<ResourceImage>, b: ResourceImage) -> Result<ResourceImage> {
-    let a = match a {
-        Some(img) => img,
-        None => return Ok(b),
-    };
-
-    let ver_a = a.metainfo().version();
-    let ver_b = b.metainfo().version();
+// Order two candidate images so that the preferred image sorts first. For kernel
+// images an image matching the running kernel version is preferred, then the image
+// with the highest version number, then the image with the newest build timestamp.
+fn compare_candidates(a: &MetaInfo, b: &MetaInfo, kernel_version: Option<&str>) -> Ordering {
+    let kernel_match = |m: &MetaInfo| kernel_version.is_some() && m.kernel_version() == kernel_version;
+    kernel_match(b).cmp(&kernel_match(a))
+        .then(b.version().cmp(&a.version()))
+        .then(parse_timestamp(b).cmp(&parse_timestamp(a)))
+}
 
-    if ver_a > ver_b {
-        Ok(a)
-    } else if ver_b > ver_a {
-        Ok(b)
+// Describe the criterion by which candidate `a` was preferred over `b`
+fn preference_reason(a: &MetaInfo, b: &MetaInfo, kernel_version: Option<&str>) -> &'static str {
+    if kernel_version.is_some() && a.kernel_version() == kernel_version && b.kernel_version() != kernel_version {
+        "a kernel-version matching the running kernel"
+    } else if a.version() != b.version() {
+        "the highest version"
+    } else if parse_timestamp(a) != parse_timestamp(b) {
+        "the newest timestamp"
     } else {
-        // versions are the same so compare build timestamps
-        let ts_a = parse_timestamp(&a)?;
-        let ts_b = parse_timestamp(&b)?;
-        if ts_a > ts_b {
-            Ok(a)
-        } else {
-            Ok(b)
-        }
+        "the same version and timestamp as the next candidate"
     }
 }
 
-fn parse_timestamp(img: &ResourceImage) -> Result<usize> {
-    let ts = img.metainfo()
-        .timestamp()
-        .parse::<usize>()
-        .context(format!("Error parsing timestamp for resource image {}", img.path().display()))?;
-    Ok(ts)
+// Images with a timestamp which does not parse are considered older than all others
+fn parse_timestamp(metainfo: &MetaInfo) -> Option<u64> {
+    metainfo.timestamp().parse::<u64>().ok()
 }
 
 fn current_kernel_version() -> String {
@@ -435,19 +589,12 @@ fn current_kernel_version() -> String {
 // Read a directory search for ResourceImages which match the channel
 // and image_type.
 //
-fn all_matching_images(dir: &Path, image_type: &str, channel: Option<&str>) -> Result<Vec<ResourceImage>> {
-    let kernel_version = current_kernel_version();
-    let kv = if image_type == "kernel" {
-        Some(kernel_version.as_str())
-    } else {
-        None
-    };
-
+fn all_matching_images(dir: &Path, image_type: &str, channel: Option<&str>, kernel_version: Option<&str>) -> Result<Vec<ResourceImage>> {
     let kernel_id = OsRelease::citadel_kernel_id();
 
     let mut v = Vec::new();
     for entry in fs::read_dir(dir)? {
-        maybe_add_dir_entry(entry?, image_type, channel, kv, kernel_id, &mut v)?;
+        maybe_add_dir_entry(entry?, image_type, channel, kernel_version, kernel_id, &mut v)?;
     }
     Ok(v)
 }
@@ -475,7 +622,7 @@ fn maybe_add_dir_entry(entry: DirEntry,
         return Ok(())
     }
     let header = ImageHeader::from_file(&path)?;
-    if !header.is_magic_valid() {
+    if header.magic_version().is_none() {
         return Ok(())
     }
 
@@ -502,6 +649,34 @@ fn maybe_add_dir_entry(entry: DirEntry,
     Ok(())
 }
 
+// Logs the percentage of `total` bytes which have been read at each 10% step
+struct ProgressReader<R: Read> {
+    inner: R,
+    total: usize,
+    count: usize,
+    reported: usize,
+}
+
+impl <R: Read> ProgressReader<R> {
+    fn new(inner: R, total: usize) -> Self {
+        ProgressReader { inner, total, count: 0, reported: 0 }
+    }
+}
+
+impl <R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n;
+        if let Some(percent) = (self.count * 100).checked_div(self.total) {
+            if percent >= self.reported + 10 {
+                self.reported = percent - percent % 10;
+                info!("{}% verified", self.reported);
+            }
+        }
+        Ok(n)
+    }
+}
+
 enum ResourceMountType {
     Unmounted,
     Verity(String),
@@ -529,6 +704,16 @@ impl ResourceMount {
         }
     }
 
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    /// Return a guard which unmounts the image when it is dropped, so that an
+    /// early return cannot leave a temporary mount behind.
+    pub fn into_guard(self) -> MountGuard {
+        MountGuard(self)
+    }
+
     pub fn unmount(&mut self) -> Result<()> {
         match self.mounttype {
             ResourceMountType::Verity(ref dev) => {
@@ -548,3 +733,211 @@ impl ResourceMount {
         Ok(())
     }
 }
+
+///
+/// Unmounts a `ResourceMount` and removes the mountpoint directory when dropped.
+///
+pub struct MountGuard(ResourceMount);
+
+impl MountGuard {
+    pub fn mountpoint(&self) -> &Path {
+        self.0.mountpoint()
+    }
+}
+
+impl Drop for MountGuard {
+    fn drop(&mut self) {
+        info!("Unmounting resource image from {}", self.0.mountpoint.display());
+        if let Err(e) = self.0.unmount() {
+            warn!("Failed to unmount resource image from {}: {}", self.0.mountpoint.display(), e);
+            return;
+        }
+        if let Err(e) = fs::remove_dir(&self.0.mountpoint) {
+            warn!("Failed to remove mountpoint directory {}: {}", self.0.mountpoint.display(), e);
+        }
+    }
+}
+
+///
+/// A resource image mount found below /run/citadel/images in /proc/self/mountinfo.
+///
+/// Images are mounted either directly from a loop device or from a dm-verity
+/// device on top of a loop device. The backing image file is found by following
+/// the device to the loop device in /sys/block.
+///
+pub struct MountedImage {
+    mountpoint: PathBuf,
+    source: PathBuf,
+    loop_device: Option<LoopDevice>,
+    verity_device: Option<String>,
+    backing_file: Option<PathBuf>,
+}
+
+impl MountedImage {
+    fn new(mountpoint: PathBuf, source: PathBuf) -> Self {
+        let sys = Path::new(SYS_BLOCK);
+        let kernel_name = source.canonicalize().ok()
+            .and_then(|p| p.file_name().map(|n| n.to_string_lossy().to_string()))
+            .unwrap_or_default();
+
+        let (loop_device, verity_device, loop_name) = if kernel_name.starts_with("loop") {
+            (Some(LoopDevice::new(&source)), None, Some(kernel_name))
+        } else if kernel_name.starts_with("dm-") {
+            let verity = fs::read_to_string(sys.join(&kernel_name).join("dm/name")).ok()
+                .map(|s| s.trim().to_string());
+            // the loop device is set to autoclear and is detached when the verity device is closed
+            let slave = fs::read_dir(sys.join(&kernel_name).join("slaves")).ok()
+                .and_then(|mut entries| entries.next())
+                .and_then(|e| e.ok())
+                .map(|e| e.file_name().to_string_lossy().to_string());
+            (None, verity, slave)
+        } else {
+            (None, None, None)
+        };
+        let backing_file = loop_name
+            .and_then(|name| fs::read_to_string(sys.join(name).join("loop/backing_file")).ok())
+            .map(|s| PathBuf::from(s.trim()));
+
+        MountedImage { mountpoint, source, loop_device, verity_device, backing_file }
+    }
+
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    /// The device which is mounted
+    pub fn source(&self) -> &Path {
+        &self.source
+    }
+
+    pub fn loop_device(&self) -> Option<&LoopDevice> {
+        self.loop_device.as_ref()
+    }
+
+    /// Name of the dm-verity device if the image was mounted with verity
+    pub fn verity_device(&self) -> Option<&str> {
+        self.verity_device.as_deref()
+    }
+
+    /// Image file backing the loop device if it could be determined
+    pub fn backing_file(&self) -> Option<&Path> {
+        self.backing_file.as_deref()
+    }
+
+    /// Unmount the image and close the verity device or detach the loop device.
+    pub fn unmount(&self) -> Result<()> {
+        info!("Unmounting resource image from {}", self.mountpoint.display());
+        util::umount(&self.mountpoint)?;
+        if let Some(ref verity) = self.verity_device {
+            Verity::close_device(verity)?;
+        }
+        if let Some(ref loopdev) = self.loop_device {
+            loopdev.detach()?;
+        }
+        Ok(())
+    }
+}
+
+// Return (mountpoint, source) pairs for mounts below `base` from the content
+// of a mountinfo file. The lines look like this:
+//
+//    98 25 7:1 / /run/citadel/images/kernel.mountpoint ro,relatime shared:50 - squashfs /dev/loop1 ro
+//
+fn parse_mountinfo(content: &str, base: &Path) -> Vec<(PathBuf, PathBuf)> {
+    content.lines().flat_map(|line| {
+        let mut parts = line.splitn(2, " - ");
+        let fields = parts.next()?.split_whitespace().collect::<Vec<_>>();
+        let source = parts.next()?.split_whitespace().nth(1)?;
+        let mountpoint = PathBuf::from(unescape_mountinfo(fields.get(4)?));
+        if mountpoint.starts_with(base) && mountpoint != base {
+            Some((mountpoint, PathBuf::from(unescape_mountinfo(source))))
+        } else {
+            None
+        }
+    }).collect()
+}
+
+// Spaces, tabs, newlines and backslashes in mountinfo paths are escaped as octal sequences such as '\040'
+fn unescape_mountinfo(s: &str) -> String {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(idx) = rest.find('\\') {
+        out.push_str(&rest[..idx]);
+        let code = rest.get(idx + 1..idx + 4).and_then(|oct| u8::from_str_radix(oct, 8).ok());
+        match code {
+            Some(c) => {
+                out.push(c as char);
+                rest = &rest[idx + 4..];
+            },
+            None => {
+                out.push('\\');
+                rest = &rest[idx + 1..];
+            },
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[test]
+fn test_parse_mountinfo() {
+    let content = "\
+22 1 253:0 / / ro,relatime shared:1 - ext4 /dev/mapper/rootfs ro
+98 25 7:1 / /run/citadel/images/kernel.mountpoint ro,relatime shared:50 - squashfs /dev/loop1 ro
+99 25 253:2 / /run/citadel/images/extra.mountpoint ro,relatime shared:51 - squashfs /dev/mapper/verity-extra-1234abcd ro
+100 25 7:4 / /run/citadel/images/kernel-install\\040copy.mountpoint ro shared:52 - squashfs /dev/loop4 ro
+101 25 0:44 / /run/citadel/images rw shared:53 - tmpfs tmpfs rw
+102 25 7:5 / /run/citadel/realmfs/main-realmfs.mountpoint ro - ext4 /dev/loop5 ro
+";
+    let mounts = parse_mountinfo(content, Path::new(RUN_DIRECTORY));
+    let expected = [
+        ("/run/citadel/images/kernel.mountpoint", "/dev/loop1"),
+        ("/run/citadel/images/extra.mountpoint", "/dev/mapper/verity-extra-1234abcd"),
+        ("/run/citadel/images/kernel-install copy.mountpoint", "/dev/loop4"),
+    ];
+    assert_eq!(mounts.len(), expected.len());
+    for ((mountpoint, source), &(m, s)) in mounts.iter().zip(expected.iter()) {
+        assert_eq!((mountpoint.as_path(), source.as_path()), (Path::new(m), Path::new(s)));
+    }
+    assert_eq!(unescape_mountinfo("a\\011b\\134c\\"), "a\tb\\c\\");
+}
+
+#[test]
+fn test_candidate_order() {
+    let metainfo = |version: u32, timestamp: &str, kernel: &str| {
+        let doc = format!("image-type = \"kernel\"\nversion = {}\ntimestamp = \"{}\"\nkernel-version = \"{}\"\n", version, timestamp, kernel);
+        MetaInfo::parse_bytes(doc.as_bytes()).unwrap()
+    };
+    let order = |list: &mut Vec<MetaInfo>, kv: Option<&str>| {
+        list.sort_by(|a, b| compare_candidates(a, b, kv));
+        list.iter().map(|m| (m.version(), m.timestamp().to_string(), m.kernel_version().unwrap().to_string())).collect::<Vec<_>>()
+    };
+    let entry = |v: u32, ts: &str, kv: &str| (v, ts.to_string(), kv.to_string());
+
+    let mut list = vec![
+        metainfo(3, "20190601000000", "5.1.4"),
+        metainfo(5, "20190501000000", "5.1.3"),
+        metainfo(4, "20190701000000", "5.1.4"),
+        metainfo(4, "20190702000000", "5.1.4"),
+        metainfo(4, "bad", "5.1.4"),
+    ];
+    // running kernel version is preferred over a higher image version
+    assert_eq!(order(&mut list, Some("5.1.4")), vec![
+        entry(4, "20190702000000", "5.1.4"),
+        entry(4, "20190701000000", "5.1.4"),
+        entry(4, "bad", "5.1.4"),
+        entry(3, "20190601000000", "5.1.4"),
+        entry(5, "20190501000000", "5.1.3"),
+    ]);
+    assert_eq!(preference_reason(&list[0], &list[1], Some("5.1.4")), "the newest timestamp");
+    assert_eq!(preference_reason(&list[3], &list[4], Some("5.1.4")), "a kernel-version matching the running kernel");
+
+    // without a kernel version only version and timestamp are considered
+    assert_eq!(order(&mut list, None)[0], entry(5, "20190501000000", "5.1.3"));
+    assert_eq!(preference_reason(&list[0], &list[1], None), "the highest version");
+    assert_eq!(order(&mut list, Some("4.19.0"))[0], entry(5, "20190501000000", "5.1.3"));
+
+    let mut same = vec![metainfo(1, "1", "5.1.4"), metainfo(1, "1", "5.1.4")];
+    order(&mut same, Some("5.1.4"));
+    assert_eq!(preference_reason(&same[0], &same[1], Some("5.1.4")), "the same version and timestamp as the next candidate");
+}
@@ -0,0 +1,547 @@
+use std::fs::{self,OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command,Stdio};
+use std::thread;
+use std::time::{Duration,Instant};
+
+use crate::{CommandLine, KeyRing, Mounts, RealmStartStatus, Result, util};
+
+const STORAGE_DEVICE: &str = "/dev/mapper/citadel-storage";
+const STORAGE_VOLUME_GROUP: &str = "citadel";
+const STORAGE_MOUNTPOINT: &str = "/sysroot/storage";
+const STORAGE_MOUNT_OPTIONS: &str = "-odefaults,nossd,noatime,commit=120";
+const STORAGE_READ_ONLY_MOUNT_OPTIONS: &str = "-oro,nossd,noatime";
+
+/// File written during boot describing the result of checking and mounting the storage partition
+pub const BOOT_STATUS_PATH: &str = "/run/citadel/boot-status.json";
+
+const FSCK_TIMEOUT: Duration = Duration::from_secs(120);
+const FSCK_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+const DEFAULT_DEVICE_TIMEOUT: Duration = Duration::from_secs(15);
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+const MOUNT_RETRIES: usize = 3;
+const MOUNT_RETRY_DELAY: Duration = Duration::from_millis(500);
+const LUKS_OPEN_RETRIES: usize = 3;
+
+/// Mount the storage partition on /sysroot/storage if it is not already mounted.
+///
+/// On slow devices the storage device may not exist yet when this is called
+/// during early boot, so wait up to `citadel.storage_timeout=` seconds
+/// (default 15) for it to appear and retry the mount a few times. If the LUKS
+/// partition has not been opened by then, opening it with the passphrase cached
+/// by systemd-cryptsetup is retried as well. If mounting ultimately fails the
+/// error describes which stage failed.
+///
+/// Before mounting, the filesystem is checked with `e2fsck -p` or
+/// `btrfs check --readonly` unless `citadel.nofsck` is set. If the storage
+/// partition can only be mounted read-only, this is recorded as degraded in
+/// `/run/citadel/boot-status.json`.
+pub fn ensure_storage_mounted() -> Result<()> {
+    let timeout = CommandLine::storage_timeout()
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_DEVICE_TIMEOUT);
+    if let Some(status) = ensure_mounted(&SystemProber, timeout, !CommandLine::nofsck())? {
+        if let Err(e) = status.write() {
+            warn!("Failed to write {}: {}", BOOT_STATUS_PATH, e);
+        }
+    }
+    Ok(())
+}
+
+///
+/// Outcome of checking and mounting the storage partition and of starting
+/// realms during boot, read from `/run/citadel/boot-status.json`.
+///
+#[derive(Serialize,Deserialize,Debug,Clone,Default,PartialEq)]
+pub struct BootStatus {
+    #[serde(default)]
+    storage_fsck: Option<String>,
+    #[serde(default)]
+    storage_degraded: bool,
+    #[serde(default)]
+    storage_message: Option<String>,
+    #[serde(default)]
+    realms: Vec<RealmStartStatus>,
+    #[serde(default)]
+    realms_duration_ms: Option<u64>,
+    #[serde(default)]
+    keyring_failed: Option<String>,
+}
+
+impl BootStatus {
+    /// Load the boot status file. If the file does not exist (for example when
+    /// booted in live mode) the default status which is not degraded is returned.
+    pub fn load() -> BootStatus {
+        let path = Path::new(BOOT_STATUS_PATH);
+        if !path.exists() {
+            return BootStatus::default();
+        }
+        match fs::read_to_string(path).map_err(|e| e.into()).and_then(|s| Self::parse(&s)) {
+            Ok(status) => status,
+            Err(e) => {
+                warn!("Failed to read boot status from {}: {}", path.display(), e);
+                BootStatus::default()
+            }
+        }
+    }
+
+    fn parse(content: &str) -> Result<BootStatus> {
+        Ok(serde_json::from_str(content)?)
+    }
+
+    fn write(&self) -> Result<()> {
+        let path = Path::new(BOOT_STATUS_PATH);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Result of the filesystem check such as "clean", "repaired" or "errors",
+    /// or "skipped" if the check was disabled with `citadel.nofsck`
+    pub fn storage_fsck(&self) -> Option<&str> {
+        self.storage_fsck.as_deref()
+    }
+
+    /// Returns `true` if the storage partition could only be mounted read-only
+    pub fn storage_degraded(&self) -> bool {
+        self.storage_degraded
+    }
+
+    /// Description of why storage is degraded
+    pub fn storage_message(&self) -> Option<&str> {
+        self.storage_message.as_deref()
+    }
+
+    /// How long each realm started at boot took to start
+    pub fn realm_startup(&self) -> &[RealmStartStatus] {
+        &self.realms
+    }
+
+    /// Total time taken to start the realms started at boot
+    pub fn realm_startup_duration(&self) -> Option<Duration> {
+        self.realms_duration_ms.map(Duration::from_millis)
+    }
+
+    /// Error from loading the keyring into the kernel during boot, if it failed
+    pub fn keyring_failed(&self) -> Option<&str> {
+        self.keyring_failed.as_deref()
+    }
+
+    /// Record in the boot status file that the keyring could not be loaded
+    pub fn record_keyring_failure(message: &str) -> Result<()> {
+        let mut status = Self::load();
+        status.keyring_failed = Some(message.to_string());
+        status.write()
+    }
+
+    /// Add the realm start times to the boot status file
+    pub(crate) fn record_realm_startup(realms: Vec<RealmStartStatus>, total: Duration) -> Result<()> {
+        let mut status = Self::load();
+        status.realms = realms;
+        status.realms_duration_ms = Some(total.as_millis() as u64);
+        status.write()
+    }
+
+    fn set_degraded(&mut self, message: String) {
+        self.storage_degraded = true;
+        self.storage_message = Some(message);
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum FsckResult {
+    Clean,
+    Repaired,
+    Errors,
+    TimedOut,
+    Failed,
+    Unsupported,
+}
+
+impl FsckResult {
+    // Map the exit code of e2fsck or btrfs check to a result. A missing exit
+    // code means the process was killed by a signal.
+    fn from_exit_code(fstype: &str, code: Option<i32>) -> FsckResult {
+        match (fstype, code) {
+            (_, None) => FsckResult::Failed,
+            (_, Some(0)) => FsckResult::Clean,
+            ("btrfs", Some(_)) => FsckResult::Errors,
+            (_, Some(code)) if code & 4 != 0 => FsckResult::Errors,
+            (_, Some(code)) if code & !3 == 0 => FsckResult::Repaired,
+            _ => FsckResult::Failed,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            FsckResult::Clean => "clean",
+            FsckResult::Repaired => "repaired",
+            FsckResult::Errors => "errors",
+            FsckResult::TimedOut => "timed-out",
+            FsckResult::Failed => "failed",
+            FsckResult::Unsupported => "unsupported",
+        }
+    }
+}
+
+// Operations needed to mount the storage partition, abstracted so that the
+// retry sequencing can be tested without real devices or delays.
+trait StorageProber {
+    fn is_mounted(&self) -> Result<bool>;
+    fn device_exists(&self) -> bool;
+    fn luks_volume_open(&self) -> bool;
+    fn luks_open(&self) -> Result<()>;
+    fn fsck(&self, timeout: Duration) -> FsckResult;
+    fn mount(&self) -> Result<()>;
+    fn mount_read_only(&self) -> Result<()>;
+    fn is_read_only(&self) -> bool;
+    fn kmsg(&self, message: &str);
+    fn sleep(&self, duration: Duration);
+}
+
+struct SystemProber;
+
+impl StorageProber for SystemProber {
+    fn is_mounted(&self) -> Result<bool> {
+        Mounts::is_source_mounted(STORAGE_DEVICE)
+    }
+
+    fn device_exists(&self) -> bool {
+        Path::new(STORAGE_DEVICE).exists()
+    }
+
+    // systemd-cryptsetup names opened LUKS volumes luks-$UUID
+    fn luks_volume_open(&self) -> bool {
+        let entries = match fs::read_dir("/dev/mapper") {
+            Ok(entries) => entries,
+            Err(_) => return false,
+        };
+        entries.flatten()
+            .any(|e| e.file_name().to_string_lossy().starts_with("luks-"))
+    }
+
+    // Open the LUKS partition with the passphrase which systemd-cryptsetup leaves
+    // in the kernel keyring and activate the logical volumes inside it.
+    fn luks_open(&self) -> Result<()> {
+        let partition = cmd_with_output!("/sbin/blkid", "-t TYPE=crypto_LUKS -o device")?;
+        let partition = match partition.lines().next() {
+            Some(partition) => partition.to_string(),
+            None => bail!("no LUKS partition found"),
+        };
+        let uuid = cmd_with_output!("/sbin/blkid", "-o value -s UUID {}", partition)?;
+        let passphrase = KeyRing::get_cryptsetup_passphrase()?;
+
+        info!("Opening LUKS partition {}", partition);
+        let mut child = Command::new("/sbin/cryptsetup")
+            .args(["open", "--type", "luks", "--key-file=-", &partition, &format!("luks-{}", uuid)])
+            .stdin(Stdio::piped()).stdout(Stdio::null()).stderr(Stdio::null())
+            .spawn()?;
+        child.stdin.take().expect("cryptsetup stdin").write_all(passphrase.as_bytes())?;
+        if !child.wait()?.success() {
+            bail!("cryptsetup open failed on {}", partition);
+        }
+        cmd!("/sbin/vgchange", "-ay {}", STORAGE_VOLUME_GROUP)
+    }
+
+    fn fsck(&self, timeout: Duration) -> FsckResult {
+        let fstype = match cmd_with_output!("/sbin/blkid", "-o value -s TYPE {}", STORAGE_DEVICE) {
+            Ok(fstype) => fstype,
+            Err(e) => {
+                warn!("Could not determine filesystem type of {}: {}", STORAGE_DEVICE, e);
+                return FsckResult::Failed;
+            }
+        };
+        let (cmd, args): (&str, &[&str]) = match fstype.trim() {
+            "btrfs" => ("/usr/bin/btrfs", &["check", "--readonly"]),
+            "ext2" | "ext3" | "ext4" => ("/sbin/e2fsck", &["-p"]),
+            other => {
+                warn!("No filesystem check for {} filesystem on {}", other, STORAGE_DEVICE);
+                return FsckResult::Unsupported;
+            }
+        };
+        info!("Checking {} filesystem on {}", fstype.trim(), STORAGE_DEVICE);
+        let mut child = match Command::new(cmd).args(args).arg(STORAGE_DEVICE)
+            .stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null())
+            .spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Failed to run {}: {}", cmd, e);
+                return FsckResult::Failed;
+            }
+        };
+        let start = Instant::now();
+        loop {
+            match child.try_wait() {
+                Ok(Some(status)) => return FsckResult::from_exit_code(fstype.trim(), status.code()),
+                Ok(None) if start.elapsed() >= timeout => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return FsckResult::TimedOut;
+                },
+                Ok(None) => thread::sleep(FSCK_POLL_INTERVAL),
+                Err(e) => {
+                    warn!("Error waiting for {}: {}", cmd, e);
+                    return FsckResult::Failed;
+                },
+            }
+        }
+    }
+
+    fn mount(&self) -> Result<()> {
+        util::mount(STORAGE_DEVICE, STORAGE_MOUNTPOINT, Some(STORAGE_MOUNT_OPTIONS))
+    }
+
+    fn mount_read_only(&self) -> Result<()> {
+        util::mount(STORAGE_DEVICE, STORAGE_MOUNTPOINT, Some(STORAGE_READ_ONLY_MOUNT_OPTIONS))
+    }
+
+    // The kernel may remount a filesystem read-only if it finds errors while mounting
+    fn is_read_only(&self) -> bool {
+        Mounts::load()
+            .map(|mounts| mounts.mounts().any(|m| m.target_path() == Path::new(STORAGE_MOUNTPOINT) && m.options().contains_key("ro")))
+            .unwrap_or(false)
+    }
+
+    // Messages are written to the kernel log because the journal is not
+    // available yet when the storage partition is mounted in the initramfs
+    fn kmsg(&self, message: &str) {
+        let result = OpenOptions::new().write(true).open("/dev/kmsg")
+            .and_then(|mut f| f.write_all(format!("citadel-storage: {}\n", message).as_bytes()));
+        if let Err(e) = result {
+            warn!("Failed to write to /dev/kmsg: {}", e);
+        }
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+// Returns the boot status if the storage partition was mounted, or `None` if
+// it was already mounted.
+fn ensure_mounted(prober: &dyn StorageProber, timeout: Duration, fsck: bool) -> Result<Option<BootStatus>> {
+    if prober.is_mounted()? {
+        return Ok(None);
+    }
+    wait_for_device(prober, timeout)?;
+
+    let mut status = BootStatus::default();
+    let fsck_result = if fsck {
+        let result = prober.fsck(FSCK_TIMEOUT);
+        prober.kmsg(&format!("filesystem check of {}: {}", STORAGE_DEVICE, result.as_str()));
+        if result == FsckResult::Errors || result == FsckResult::TimedOut {
+            warn!("Filesystem check of {} did not complete cleanly: {}", STORAGE_DEVICE, result.as_str());
+        }
+        result.as_str()
+    } else {
+        prober.kmsg("filesystem check skipped because citadel.nofsck is set");
+        "skipped"
+    };
+    status.storage_fsck = Some(fsck_result.to_string());
+
+    if let Err(err) = mount_with_retry(prober) {
+        warn!("{}, trying to mount read-only", err);
+        prober.mount_read_only()
+            .map_err(|e| format_err!("{}, mounting read-only also failed: {}", err, e))?;
+        status.set_degraded(format!("Storage partition could only be mounted read-only: {}", err));
+    } else if prober.is_read_only() {
+        status.set_degraded("Storage partition was mounted read-only by the kernel".to_string());
+    }
+    if let Some(message) = status.storage_message() {
+        warn!("{}", message);
+        prober.kmsg(message);
+    }
+    Ok(Some(status))
+}
+
+fn wait_for_device(prober: &dyn StorageProber, timeout: Duration) -> Result<()> {
+    if poll_for_device(prober, timeout) {
+        return Ok(());
+    }
+    if prober.luks_volume_open() {
+        bail!("Storage device {} did not appear after {} seconds: the LUKS volume is open but the storage volume was not activated",
+              STORAGE_DEVICE, timeout.as_secs());
+    }
+    warn!("Storage device {} did not appear after {} seconds, opening LUKS partition", STORAGE_DEVICE, timeout.as_secs());
+    retry(prober, "luksOpen of the storage partition", LUKS_OPEN_RETRIES, || prober.luks_open())
+        .map_err(|(attempts, err)| format_err!("Storage device {} did not appear after {} seconds and luksOpen of the storage partition failed after {} attempts: {}",
+                                              STORAGE_DEVICE, timeout.as_secs(), attempts, err))?;
+    if !poll_for_device(prober, timeout) {
+        bail!("Storage device {} did not appear after opening the LUKS partition: the storage volume was not activated", STORAGE_DEVICE);
+    }
+    Ok(())
+}
+
+// Returns `false` if the storage device did not appear within `timeout`
+fn poll_for_device(prober: &dyn StorageProber, timeout: Duration) -> bool {
+    let mut waited = Duration::from_secs(0);
+    while !prober.device_exists() {
+        if waited >= timeout {
+            return false;
+        }
+        if waited == Duration::from_secs(0) {
+            info!("Waiting for storage device {} to appear", STORAGE_DEVICE);
+        }
+        prober.sleep(DEVICE_POLL_INTERVAL);
+        waited += DEVICE_POLL_INTERVAL;
+    }
+    true
+}
+
+fn mount_with_retry(prober: &dyn StorageProber) -> Result<()> {
+    retry(prober, "Mounting the storage partition", MOUNT_RETRIES, || {
+        info!("Mounting {} directory", STORAGE_MOUNTPOINT);
+        prober.mount()
+    }).map_err(|(attempts, err)| format_err!("Failed to mount storage device {} on {} after {} attempts: {}",
+                                            STORAGE_DEVICE, STORAGE_MOUNTPOINT, attempts, err))
+}
+
+// Run `op` and retry it up to `retries` times with an increasing delay. On failure
+// the number of attempts is returned with the error from the last attempt.
+fn retry<F>(prober: &dyn StorageProber, what: &str, retries: usize, op: F) -> std::result::Result<(), (usize, failure::Error)>
+    where F: Fn() -> Result<()>
+{
+    let mut delay = MOUNT_RETRY_DELAY;
+    let mut attempt = 0;
+    loop {
+        let err = match op() {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        if attempt == retries {
+            return Err((attempt + 1, err));
+        }
+        warn!("{} failed, retrying in {}ms: {}", what, delay.as_millis(), err);
+        prober.sleep(delay);
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+#[test]
+fn test_storage_mount_retry() {
+    use std::cell::{Cell,RefCell};
+
+    // Device appears after `device_after` polls and mount fails `mount_failures` times
+    struct MockProber {
+        device_after: usize,
+        mount_failures: usize,
+        luks_failures: usize,
+        luks_opens: Cell<usize>,
+        fsck_result: FsckResult,
+        read_only_ok: bool,
+        polls: Cell<usize>,
+        mounts: Cell<usize>,
+        fscks: Cell<usize>,
+        sleeps: RefCell<Vec<u128>>,
+        kmsgs: RefCell<Vec<String>>,
+    }
+    impl StorageProber for MockProber {
+        fn is_mounted(&self) -> Result<bool> { Ok(false) }
+        fn device_exists(&self) -> bool {
+            self.polls.set(self.polls.get() + 1);
+            self.polls.get() > self.device_after || self.luks_opens.get() > self.luks_failures
+        }
+        fn luks_volume_open(&self) -> bool { false }
+        fn luks_open(&self) -> Result<()> {
+            self.luks_opens.set(self.luks_opens.get() + 1);
+            if self.luks_opens.get() <= self.luks_failures {
+                bail!("cryptsetup open failed");
+            }
+            Ok(())
+        }
+        fn fsck(&self, _timeout: Duration) -> FsckResult {
+            self.fscks.set(self.fscks.get() + 1);
+            self.fsck_result
+        }
+        fn mount(&self) -> Result<()> {
+            self.mounts.set(self.mounts.get() + 1);
+            if self.mounts.get() <= self.mount_failures {
+                bail!("mount failed");
+            }
+            Ok(())
+        }
+        fn mount_read_only(&self) -> Result<()> {
+            if !self.read_only_ok {
+                bail!("read-only mount failed");
+            }
+            Ok(())
+        }
+        fn is_read_only(&self) -> bool { false }
+        fn kmsg(&self, message: &str) {
+            self.kmsgs.borrow_mut().push(message.to_string());
+        }
+        fn sleep(&self, duration: Duration) {
+            self.sleeps.borrow_mut().push(duration.as_millis());
+        }
+    }
+    fn mock(device_after: usize, mount_failures: usize) -> MockProber {
+        MockProber {
+            device_after, mount_failures, luks_failures: 10, luks_opens: Cell::new(0),
+            fsck_result: FsckResult::Clean, read_only_ok: false,
+            polls: Cell::new(0), mounts: Cell::new(0), fscks: Cell::new(0),
+            sleeps: RefCell::new(Vec::new()), kmsgs: RefCell::new(Vec::new()),
+        }
+    }
+    let timeout = Duration::from_secs(1);
+
+    let p = mock(2, 2);
+    let status = ensure_mounted(&p, timeout, true).unwrap().unwrap();
+    assert_eq!(p.mounts.get(), 3);
+    assert_eq!(*p.sleeps.borrow(), vec![250, 250, 500, 1000]);
+    assert_eq!(status.storage_fsck(), Some("clean"));
+    assert!(!status.storage_degraded());
+    assert_eq!(*p.kmsgs.borrow(), vec!["filesystem check of /dev/mapper/citadel-storage: clean"]);
+
+    let p = mock(100, 0);
+    let err = ensure_mounted(&p, timeout, true).unwrap_err().to_string();
+    assert!(err.contains("did not appear after 1 seconds") && err.contains("luksOpen"), "{}", err);
+    assert!(err.contains("after 4 attempts: cryptsetup open failed"), "{}", err);
+    assert_eq!(*p.sleeps.borrow(), vec![250, 250, 250, 250, 500, 1000, 2000]);
+    assert_eq!(p.luks_opens.get(), 4);
+    assert_eq!(p.mounts.get(), 0);
+    assert_eq!(p.fscks.get(), 0);
+
+    // The device appears once a retried luksOpen succeeds
+    let mut p = mock(100, 0);
+    p.luks_failures = 1;
+    ensure_mounted(&p, timeout, true).unwrap().unwrap();
+    assert_eq!(p.luks_opens.get(), 2);
+    assert_eq!(*p.sleeps.borrow(), vec![250, 250, 250, 250, 500]);
+    assert_eq!(p.mounts.get(), 1);
+
+    let p = mock(0, 10);
+    let err = ensure_mounted(&p, timeout, true).unwrap_err().to_string();
+    assert!(err.contains("after 4 attempts: mount failed"), "{}", err);
+    assert!(err.contains("mounting read-only also failed"), "{}", err);
+    assert_eq!(*p.sleeps.borrow(), vec![500, 1000, 2000]);
+
+    // Storage which can only be mounted read-only is degraded
+    let mut p = mock(0, 10);
+    p.fsck_result = FsckResult::Errors;
+    p.read_only_ok = true;
+    let status = ensure_mounted(&p, timeout, true).unwrap().unwrap();
+    assert_eq!(status.storage_fsck(), Some("errors"));
+    assert!(status.storage_degraded());
+    assert!(status.storage_message().unwrap().contains("only be mounted read-only"));
+    assert_eq!(p.kmsgs.borrow().len(), 2);
+
+    let p = mock(0, 0);
+    let status = ensure_mounted(&p, timeout, false).unwrap().unwrap();
+    assert_eq!(p.fscks.get(), 0);
+    assert_eq!(status.storage_fsck(), Some("skipped"));
+
+    let status = BootStatus::parse(&serde_json::to_string(&status).unwrap()).unwrap();
+    assert_eq!(status.storage_fsck(), Some("skipped"));
+    assert_eq!(BootStatus::parse("{}").unwrap(), BootStatus::default());
+
+    assert_eq!(FsckResult::from_exit_code("ext4", Some(1)), FsckResult::Repaired);
+    assert_eq!(FsckResult::from_exit_code("ext4", Some(4)), FsckResult::Errors);
+    assert_eq!(FsckResult::from_exit_code("ext4", Some(8)), FsckResult::Failed);
+    assert_eq!(FsckResult::from_exit_code("btrfs", Some(1)), FsckResult::Errors);
+    assert_eq!(FsckResult::from_exit_code("btrfs", None), FsckResult::Failed);
+}
@@ -12,7 +12,7 @@ impl LoopDevice {
     const LOSETUP: &'static str = "/usr/sbin/losetup";
     const MOUNT: &'static str = "/usr/bin/mount";
 
-    fn new<P: AsRef<Path>>(device: P) -> LoopDevice {
+    pub(crate) fn new<P: AsRef<Path>>(device: P) -> LoopDevice {
         let device = device.as_ref().to_path_buf();
         LoopDevice(device)
     }
@@ -64,7 +64,7 @@ impl LoopDevice {
         // /dev/loop1: [0036]:64845938 (/storage/resources/dev/citadel-extra-dev-001.img), offset 4096
         let output:String = cmd_with_output!(Self::LOSETUP, "-j {}", image.display())?;
         Ok(output.lines()
-            .flat_map(|line| line.splitn(2, ':').next())
+            .flat_map(|line| line.split(':').next())
             .map(LoopDevice::new)
             .collect())
     }
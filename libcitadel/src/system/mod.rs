@@ -1,9 +1,11 @@
 mod lock;
 mod loopdev;
 mod mounts;
+mod root;
 mod uname;
 
 pub use self::uname::UtsName;
 pub use self::loopdev::LoopDevice;
 pub use self::mounts::{Mounts,MountLine};
 pub use self::lock::FileLock;
+pub use self::root::SystemRoot;
@@ -38,7 +38,7 @@ impl Mounts {
         Ok(Mounts { content })
     }
 
-    pub fn mounts(&self) -> impl Iterator<Item=MountLine> {
+    pub fn mounts(&self) -> impl Iterator<Item=MountLine<'_>> {
         self.content.lines().flat_map(MountLine::new)
     }
 }
@@ -49,7 +49,7 @@ pub struct MountLine<'a> {
 
 impl <'a> MountLine<'a> {
 
-    fn new(line: &str) -> Option<MountLine> {
+    fn new(line: &str) -> Option<MountLine<'_>> {
         if line.split_whitespace().count() >= 4 {
             Some(MountLine { line })
         } else {
@@ -0,0 +1,58 @@
+use std::path::{Path,PathBuf};
+
+///
+/// The directory below which the absolute system paths such as `/storage`, `/boot`
+/// and `/run` are found. The default root is `/` so that paths resolve unchanged,
+/// tests use a temporary directory populated with the files the code under test
+/// expects to find.
+///
+#[derive(Clone,Debug,PartialEq)]
+pub struct SystemRoot {
+    prefix: PathBuf,
+}
+
+impl SystemRoot {
+    pub fn new<P: AsRef<Path>>(prefix: P) -> Self {
+        SystemRoot { prefix: prefix.as_ref().to_path_buf() }
+    }
+
+    pub fn prefix(&self) -> &Path {
+        &self.prefix
+    }
+
+    /// Resolve the absolute path `path` below this root
+    pub fn path<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        let path = path.as_ref();
+        self.prefix.join(path.strip_prefix("/").unwrap_or(path))
+    }
+
+    pub fn storage(&self) -> PathBuf {
+        self.path("/storage")
+    }
+
+    pub fn boot(&self) -> PathBuf {
+        self.path("/boot")
+    }
+
+    pub fn run(&self) -> PathBuf {
+        self.path("/run")
+    }
+}
+
+impl Default for SystemRoot {
+    fn default() -> Self {
+        SystemRoot::new("/")
+    }
+}
+
+#[test]
+fn test_system_root_paths() {
+    let root = SystemRoot::default();
+    assert_eq!(root.path("/storage/resources/dev"), Path::new("/storage/resources/dev"));
+    assert_eq!(root.boot(), Path::new("/boot"));
+
+    let root = SystemRoot::new("/tmp/fake-root");
+    assert_eq!(root.path("/storage/resources/dev"), Path::new("/tmp/fake-root/storage/resources/dev"));
+    assert_eq!(root.path("boot/loader"), Path::new("/tmp/fake-root/boot/loader"));
+    assert_eq!(root.run(), Path::new("/tmp/fake-root/run"));
+}
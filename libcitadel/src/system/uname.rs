@@ -12,9 +12,9 @@ pub struct UtsName(libc::utsname);
 impl UtsName {
     pub fn uname() -> UtsName {
         unsafe {
-            let mut ret: UtsName = mem::uninitialized();
-            libc::uname(&mut ret.0);
-            ret
+            let mut ret = mem::MaybeUninit::<libc::utsname>::zeroed();
+            libc::uname(ret.as_mut_ptr());
+            UtsName(ret.assume_init())
         }
     }
 
@@ -112,7 +112,7 @@ impl AnsiControl {
             bail!("bad elem {}", s);
         }
         let idx = v[0].parse::<u32>()?;
-        let color = Color::parse(&v[1])?;
+        let color = Color::parse(v[1])?;
         Ok((idx, color))
     }
 
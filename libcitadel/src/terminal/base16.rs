@@ -137,7 +137,10 @@ impl Base16Scheme {
         let source = source.join(filename);
         let dest = Path::new("/tmp").join(filename);
         manager.copy_to_realm(realm, source, &dest)?;
-        manager.run_in_realm(realm, &["/usr/bin/mv", "-ft", "/home/user", dest.to_string_lossy().as_ref()], false)?;
+        let status = manager.run_in_realm(realm, &["/usr/bin/mv", "-ft", "/home/user", dest.to_string_lossy().as_ref()], "user", false)?;
+        if !status.success() {
+            bail!("moving {} into home directory of realm {} failed: {}", filename, realm.name(), status);
+        }
         Ok(())
     }
 
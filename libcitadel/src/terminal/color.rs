@@ -15,9 +15,9 @@ impl Color {
         if s.starts_with("rgb:") {
             let parts = s.trim_start_matches("rgb:").split('/').collect::<Vec<_>>();
             if parts.len() == 3 {
-                let r = u16::from_str_radix(&parts[0], 16)?;
-                let g = u16::from_str_radix(&parts[1], 16)?;
-                let b = u16::from_str_radix(&parts[2], 16)?;
+                let r = u16::from_str_radix(parts[0], 16)?;
+                let g = u16::from_str_radix(parts[1], 16)?;
+                let b = u16::from_str_radix(parts[2], 16)?;
                 return Ok(Color(r, g, b))
             }
         }
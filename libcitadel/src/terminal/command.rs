@@ -0,0 +1,120 @@
+use std::fs;
+use std::path::Path;
+
+use toml;
+
+/// Global terminal configuration file
+const TERMINAL_CONFIG_PATH: &str = "/etc/citadel/terminal.conf";
+
+/// Terminal programs which are tried in order when no configured terminal
+/// command is available, together with the argument used to set the window title.
+const DEFAULT_TERMINALS: &[(&str, &str)] = &[
+    ("/usr/bin/gnome-terminal", "--title"),
+    ("/usr/bin/foot", "--title"),
+    ("/usr/bin/alacritty", "--title"),
+    ("/usr/bin/xterm", "-title"),
+];
+
+#[derive(Deserialize,Default)]
+struct TerminalConfig {
+    #[serde(rename="terminal-command")]
+    terminal_command: Option<String>,
+}
+
+impl TerminalConfig {
+    fn load() -> Self {
+        let path = Path::new(TERMINAL_CONFIG_PATH);
+        if !path.exists() {
+            return Self::default();
+        }
+        match fs::read_to_string(path) {
+            Ok(s) => toml::from_str(&s).unwrap_or_else(|e| {
+                warn!("Error parsing {}: {}", TERMINAL_CONFIG_PATH, e);
+                Self::default()
+            }),
+            Err(e) => {
+                warn!("Error reading {}: {}", TERMINAL_CONFIG_PATH, e);
+                Self::default()
+            }
+        }
+    }
+}
+
+/// A command which launches a terminal inside a realm.
+///
+/// Candidates are produced in the order they should be tried:
+///
+///   1. `terminal-command` from the realm config
+///   2. `terminal-command` from /etc/citadel/terminal.conf
+///   3. The built-in list of gnome-terminal, foot, alacritty, xterm
+///
+#[derive(Clone,Debug)]
+pub struct TerminalCommand {
+    args: Vec<String>,
+    reason: &'static str,
+}
+
+impl TerminalCommand {
+
+    /// Return all candidate terminal commands for a realm which has `realm_command`
+    /// configured, reading the global terminal command from /etc/citadel/terminal.conf
+    pub fn load_candidates(realm_command: Option<&str>, title: &str) -> Vec<TerminalCommand> {
+        let global = TerminalConfig::load();
+        Self::candidates(realm_command, global.terminal_command.as_deref(), title)
+    }
+
+    fn candidates(realm_command: Option<&str>, global_command: Option<&str>, title: &str) -> Vec<TerminalCommand> {
+        let mut v = Vec::new();
+        if let Some(cmd) = realm_command.and_then(|cmd| Self::parse(cmd, "configured in realm config")) {
+            v.push(cmd);
+        }
+        if let Some(cmd) = global_command.and_then(|cmd| Self::parse(cmd, "configured in /etc/citadel/terminal.conf")) {
+            v.push(cmd);
+        }
+        for &(path, title_arg) in DEFAULT_TERMINALS {
+            let args = vec![path.to_string(), title_arg.to_string(), title.to_string()];
+            v.push(TerminalCommand { args, reason: "first available built-in default" });
+        }
+        v
+    }
+
+    // Split a configured command line into arguments. A program name which is
+    // not an absolute path is expected to be found in /usr/bin.
+    fn parse(command: &str, reason: &'static str) -> Option<TerminalCommand> {
+        let mut args: Vec<String> = command.split_whitespace().map(String::from).collect();
+        if args.is_empty() {
+            return None;
+        }
+        if !args[0].starts_with('/') {
+            args[0] = format!("/usr/bin/{}", args[0]);
+        }
+        Some(TerminalCommand { args, reason })
+    }
+
+    /// Path of the terminal program inside the realm
+    pub fn program(&self) -> &str {
+        &self.args[0]
+    }
+
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    /// Describes why this terminal command was chosen
+    pub fn reason(&self) -> &str {
+        self.reason
+    }
+}
+
+#[test]
+fn test_terminal_candidates() {
+    let candidates = TerminalCommand::candidates(Some("foot --app-id realm"), Some("/opt/bin/kitty"), "Realm: main");
+    let programs = candidates.iter().map(|c| c.program()).collect::<Vec<_>>();
+    assert_eq!(programs, vec!["/usr/bin/foot", "/opt/bin/kitty", "/usr/bin/gnome-terminal",
+                              "/usr/bin/foot", "/usr/bin/alacritty", "/usr/bin/xterm"]);
+    assert_eq!(candidates[0].args(), &["/usr/bin/foot", "--app-id", "realm"]);
+    assert_eq!(candidates[5].args(), &["/usr/bin/xterm", "-title", "Realm: main"]);
+
+    let candidates = TerminalCommand::candidates(Some("  "), None, "Realm: main");
+    assert_eq!(candidates.len(), DEFAULT_TERMINALS.len());
+}
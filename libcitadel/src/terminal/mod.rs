@@ -4,9 +4,11 @@ mod base16_shell;
 mod ansi;
 mod raw;
 mod color;
+mod command;
 
 pub use self::raw::RawTerminal;
 pub use self::base16::Base16Scheme;
 pub use self::color::{Color,TerminalPalette};
 pub use self::ansi::{AnsiTerminal,AnsiControl};
-pub use self::base16_shell::Base16Shell;
\ No newline at end of file
+pub use self::base16_shell::Base16Shell;
+pub use self::command::TerminalCommand;
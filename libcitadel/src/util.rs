@@ -6,8 +6,11 @@ use std::env;
 use std::fs::{self,File};
 use std::ffi::CString;
 use std::io::{self, Seek, Read, BufReader, SeekFrom};
+use std::ops::Deref;
+use std::time::Duration;
 
 use failure::ResultExt;
+use sodiumoxide::crypto::hash::sha256;
 use walkdir::WalkDir;
 use libc;
 
@@ -35,6 +38,16 @@ pub fn is_first_char_alphabetic(s: &str) -> bool {
     false
 }
 
+/// Returns `true` if the effective user id of the current process is root.
+pub fn is_euid_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Returns `true` if stdout is connected to a terminal.
+pub fn is_stdout_tty() -> bool {
+    unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 }
+}
+
 fn search_path(filename: &str) -> Result<PathBuf> {
     let path_var = env::var("PATH")?;
     for mut path in env::split_paths(&path_var) {
@@ -58,13 +71,30 @@ pub fn ensure_command_exists(cmd: &str) -> Result<()> {
 }
 
 
+///
+/// Calculate the sha256 of the file at `path`.
+///
 pub fn sha256<P: AsRef<Path>>(path: P) -> Result<String> {
     let path = path.as_ref();
-    let output = cmd_with_output!("/usr/bin/sha256sum", "{}", path.display())
-        .context(format!("failed to calculate sha256 on {}", path.display()))?;
+    let f = File::open(path)
+        .context(format!("failed to open {} to calculate sha256", path.display()))?;
+    sha256_reader(f, None)
+}
 
-    let v: Vec<&str> = output.split_whitespace().collect();
-    Ok(v[0].trim().to_owned())
+///
+/// Calculate the sha256 of exactly `len` bytes of the file at `path` starting at `offset`.
+///
+pub fn sha256_partial<P: AsRef<Path>>(path: P, offset: u64, len: u64) -> Result<String> {
+    let path = path.as_ref();
+    let mut f = File::open(path)
+        .context(format!("failed to open {} to calculate sha256", path.display()))?;
+    f.seek(SeekFrom::Start(offset))?;
+    let mut count = 0;
+    let shasum = sha256_reader(f.take(len), Some(&mut |n| count = n))?;
+    if count != len {
+        bail!("{} ended before calculating sha256 on {} bytes at offset {}", path.display(), len, offset);
+    }
+    Ok(shasum)
 }
 
 #[derive(Copy,Clone)]
@@ -74,6 +104,30 @@ pub enum FileRange {
     Range{offset: usize, len: usize},
 }
 
+///
+/// Calculate the sha256 of all data read from `r`. If `progress` is provided it is
+/// called with the total number of bytes read so far after each read.
+///
+pub fn sha256_reader<R: Read>(mut r: R, mut progress: Option<&mut dyn FnMut(u64)>) -> Result<String> {
+    let mut state = sha256::State::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut total = 0u64;
+    loop {
+        let n = match r.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        state.update(&buf[..n]);
+        total += n as u64;
+        if let Some(ref mut progress) = progress {
+            progress(total);
+        }
+    }
+    Ok(hex::encode(&state.finalize()[..]))
+}
+
 fn ranged_reader<P: AsRef<Path>>(path: P, range: FileRange) -> Result<Box<dyn Read>> {
     let mut f = File::open(path.as_ref())?;
     let offset = match range {
@@ -158,6 +212,96 @@ pub fn chown(path: &Path, uid: u32, gid: u32) -> io::Result<()> {
     Ok(())
 }
 
+/// Return the total size and the space available to unprivileged users in bytes
+/// of the filesystem containing `path`.
+pub fn filesystem_space<P: AsRef<Path>>(path: P) -> io::Result<(u64, u64)> {
+    let cstr = CString::new(path.as_ref().as_os_str().as_bytes())?;
+    unsafe {
+        let mut buf: libc::statvfs = std::mem::zeroed();
+        if libc::statvfs(cstr.as_ptr(), &mut buf) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let frsize = buf.f_frsize as u64;
+        Ok((buf.f_blocks as u64 * frsize, buf.f_bavail as u64 * frsize))
+    }
+}
+
+/// Replace the file at `path` with `contents` by writing a temporary file in the
+/// same directory and renaming it, so that readers never see a partially written
+/// file. The parent directory is created if it does not exist.
+pub fn write_file_atomic<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) -> Result<()> {
+    let path = path.as_ref();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format_err!("failed to create directory {}: {}", parent.display(), e))?;
+    }
+    let mut tmp = path.as_os_str().to_os_string();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    fs::write(&tmp, contents)
+        .map_err(|e| format_err!("failed to write {}: {}", tmp.display(), e))?;
+    if let Err(e) = fs::rename(&tmp, path) {
+        let _ = fs::remove_file(&tmp);
+        bail!("failed to rename {} to {}: {}", tmp.display(), path.display(), e);
+    }
+    Ok(())
+}
+
+///
+/// A directory below the system temporary directory which is removed together
+/// with its contents when dropped, including when a test fails part way through.
+///
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Create an empty directory named `citadel-{name}-{pid}`, replacing any
+    /// directory of the same name left behind by an earlier run.
+    pub fn new(name: &str) -> io::Result<Self> {
+        let path = env::temp_dir().join(format!("citadel-{}-{}", name, std::process::id()));
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        fs::create_dir_all(&path)?;
+        Ok(TempDir(path))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Current value of the CLOCK_MONOTONIC clock, which systemd uses for the
+/// monotonic timestamps of units.
+pub fn monotonic_time() -> io::Result<Duration> {
+    unsafe {
+        let mut ts: libc::timespec = std::mem::zeroed();
+        if libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+    }
+}
+
 fn copy_path(from: &Path, to: &Path, chown_to: Option<(u32,u32)>) -> Result<()> {
     if to.exists() {
         bail!("destination path {} already exists which is not expected", to.display());
@@ -168,7 +312,7 @@ fn copy_path(from: &Path, to: &Path, chown_to: Option<(u32,u32)>) -> Result<()>
     if from.is_dir() {
         fs::create_dir(to)?;
     } else {
-        fs::copy(&from, &to)?;
+        fs::copy(from, to)?;
     }
 
     if let Some((uid,gid)) = chown_to {
@@ -200,6 +344,96 @@ fn _copy_tree(from_base: &Path, to_base: &Path, chown_to: Option<(u32,u32)>) ->
     Ok(())
 }
 
+/// Copy the tree of files at `from_base` to a new directory `to_base` preserving
+/// ownership, permissions and symlinks. Regular files are reflinked when the
+/// filesystem supports it and copied otherwise. `progress` is called with the
+/// number of entries copied so far after each entry.
+pub fn clone_tree(from_base: &Path, to_base: &Path, progress: &mut dyn FnMut(usize)) -> Result<()> {
+    let mut directories = Vec::new();
+    let mut count = 0;
+    for entry in WalkDir::new(from_base) {
+        let entry = entry?;
+        let from = entry.path();
+        let to = to_base.join(from.strip_prefix(from_base)?);
+        let meta = entry.metadata()?;
+        clone_path(from, &to, &meta)
+            .map_err(|e| format_err!("failed to copy {} to {}: {}", from.display(), to.display(), e))?;
+        if meta.is_dir() {
+            directories.push((to, meta));
+        }
+        count += 1;
+        progress(count);
+    }
+    // Set directory permissions last so that read-only directories can be populated
+    for (dir, meta) in directories {
+        fs::set_permissions(&dir, meta.permissions())?;
+    }
+    Ok(())
+}
+
+fn clone_path(from: &Path, to: &Path, meta: &fs::Metadata) -> Result<()> {
+    let ftype = meta.file_type();
+    if ftype.is_symlink() {
+        let target = fs::read_link(from)?;
+        std::os::unix::fs::symlink(&target, to)?;
+    } else if ftype.is_dir() {
+        fs::create_dir(to)?;
+    } else if ftype.is_file() {
+        clone_file(from, to)?;
+        fs::set_permissions(to, meta.permissions())?;
+    } else {
+        warn!("Skipping special file {}", from.display());
+        return Ok(());
+    }
+    lchown(to, meta.uid(), meta.gid())?;
+    Ok(())
+}
+
+// Try to share extents with the FICLONE ioctl and fall back to copying data
+fn clone_file(from: &Path, to: &Path) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+    const FICLONE: libc::c_ulong = 0x4004_9409;
+
+    let mut src = File::open(from)?;
+    let mut dst = fs::OpenOptions::new().write(true).create_new(true).open(to)?;
+    let ret = unsafe { libc::ioctl(dst.as_raw_fd(), FICLONE, src.as_raw_fd()) };
+    if ret != 0 {
+        io::copy(&mut src, &mut dst)?;
+    }
+    Ok(())
+}
+
+pub fn lchown(path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+    let cstr = CString::new(path.as_os_str().as_bytes())?;
+    unsafe {
+        if libc::lchown(cstr.as_ptr(), uid, gid) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Move ownership of every file under `base` (including `base` itself) from
+/// the id range of `range_size` ids starting at `from` to the range starting at `to`.
+/// Files owned by ids outside of the `from` range are not changed and symlinks
+/// are not followed.
+pub fn shift_tree_ownership(base: &Path, from: u32, to: u32, range_size: u32) -> Result<()> {
+    let shift = |id: u32| {
+        if id >= from && id - from < range_size {
+            id - from + to
+        } else {
+            id
+        }
+    };
+    for entry in WalkDir::new(base) {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        lchown(entry.path(), shift(meta.uid()), shift(meta.gid()))
+            .map_err(|e| format_err!("failed to change ownership of {}: {}", entry.path().display(), e))?;
+    }
+    Ok(())
+}
+
 pub fn chown_tree(base: &Path, chown_to: (u32,u32), include_base: bool) -> Result<()> {
     for entry in WalkDir::new(base) {
         let entry = entry?;
@@ -209,3 +443,55 @@ pub fn chown_tree(base: &Path, chown_to: (u32,u32), include_base: bool) -> Resul
     }
     Ok(())
 }
+
+#[test]
+fn test_sha256() {
+    let dir = TempDir::new("sha256-test").unwrap();
+    let empty = dir.join("empty");
+    let abc = dir.join("abc");
+    fs::write(&empty, b"").unwrap();
+    fs::write(&abc, b"xxabcxx").unwrap();
+
+    assert_eq!(sha256(&empty).unwrap(), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    assert_eq!(sha256_partial(&abc, 2, 3).unwrap(), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    assert!(sha256_partial(&abc, 2, 10).is_err());
+
+    let data = vec![0x5au8; 200 * 1024];
+    let mut reports = Vec::new();
+    let shasum = sha256_reader(&data[..], Some(&mut |n| reports.push(n))).unwrap();
+    assert_eq!(shasum, sha256_reader(io::repeat(0x5a).take(200 * 1024), None).unwrap());
+    assert_eq!(reports.last(), Some(&(200 * 1024)));
+    assert!(reports.windows(2).all(|w| w[0] < w[1]));
+}
+
+#[test]
+fn test_write_file_atomic() {
+    let dir = TempDir::new("write-atomic-test").unwrap();
+    let path = dir.join("state/file.json");
+    write_file_atomic(&path, "first").unwrap();
+    write_file_atomic(&path, "second").unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+    let names: Vec<_> = fs::read_dir(dir.join("state")).unwrap()
+        .map(|e| e.unwrap().file_name())
+        .collect();
+    assert_eq!(names, vec!["file.json"]);
+}
+
+#[test]
+fn test_clone_tree() {
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    let dir = TempDir::new("clone-tree-test").unwrap();
+    let from = dir.join("from");
+    let to = dir.join("to");
+    fs::create_dir_all(from.join("sub")).unwrap();
+    fs::write(from.join("sub/file"), b"data").unwrap();
+    fs::set_permissions(from.join("sub/file"), fs::Permissions::from_mode(0o640)).unwrap();
+    symlink("sub/file", from.join("link")).unwrap();
+
+    let mut count = 0;
+    clone_tree(&from, &to, &mut |n| count = n).unwrap();
+    assert_eq!(count, 4);
+    assert_eq!(fs::read(to.join("sub/file")).unwrap(), b"data");
+    assert_eq!(fs::metadata(to.join("sub/file")).unwrap().permissions().mode() & 0o777, 0o640);
+    assert_eq!(fs::read_link(to.join("link")).unwrap(), Path::new("sub/file"));
+}
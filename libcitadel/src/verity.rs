@@ -8,14 +8,17 @@ use crate::{Result, MetaInfo, Partition, LoopDevice, Mountpoint};
 
 pub struct Verity {
     image: PathBuf,
+    // Offset of the image data in the file, which is the size of the image header
+    data_offset: usize,
 }
 
 impl Verity {
     const VERITYSETUP: &'static str = "/sbin/veritysetup";
 
-    pub fn new(image: impl AsRef<Path>) -> Self {
+    /// Verity for the image data stored at `data_offset` in the file `image`
+    pub fn new(image: impl AsRef<Path>, data_offset: usize) -> Self {
         let image = image.as_ref().to_path_buf();
-        Verity { image }
+        Verity { image, data_offset }
     }
 
     pub fn generate_initial_hashtree(&self, output: impl AsRef<Path>) -> Result<VerityOutput> {
@@ -38,11 +41,11 @@ impl Verity {
         // Make sure file size is correct or else verity tree will be appended in wrong place
         let meta = self.image.metadata()?;
         let len = meta.len() as usize;
-        let expected = (nblocks + 1) * 4096;
+        let expected = nblocks * 4096 + self.data_offset;
         if len != expected {
             bail!("Actual file size ({}) does not match expected size ({})", len, expected);
         }
-        let vout = LoopDevice::with_loop(self.path(), Some(4096), true, |loopdev| {
+        let vout = LoopDevice::with_loop(self.path(), Some(self.data_offset), true, |loopdev| {
             let output = cmd_with_output!(Self::VERITYSETUP, "--data-blocks={} --salt={} format {} {}",
                 nblocks, salt, loopdev, verityfile.display())?;
             Ok(VerityOutput::parse(&output))
@@ -55,7 +58,7 @@ impl Verity {
     }
 
     pub fn verify(&self, metainfo: &MetaInfo) -> Result<bool> {
-        LoopDevice::with_loop(self.path(), Some(4096), true, |loopdev| {
+        LoopDevice::with_loop(self.path(), Some(self.data_offset), true, |loopdev| {
             cmd_ok!(Self::VERITYSETUP, "--hash-offset={} verify {} {} {}",
             metainfo.nblocks() * 4096,
             loopdev, loopdev, metainfo.verity_root())
@@ -63,7 +66,7 @@ impl Verity {
     }
 
     pub fn setup(&self, metainfo: &MetaInfo) -> Result<String> {
-        LoopDevice::with_loop(self.path(), Some(4096), true, |loopdev| {
+        LoopDevice::with_loop(self.path(), Some(self.data_offset), true, |loopdev| {
             let devname = Self::device_name(metainfo);
             let srcdev = loopdev.to_string();
             Self::setup_device(&srcdev, &devname, metainfo)?;
@@ -0,0 +1,422 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use libcitadel::{ClipboardPolicy, Realm, RealmManager, Result, util};
+
+/// Socket on which the clipboard agent of the host desktop session exchanges
+/// the host clipboard with realmsd
+pub const HOST_CLIPBOARD_SOCKET: &str = "/run/citadel/clipboard.sock";
+
+/// Largest clipboard content relayed in bytes
+pub const MAX_CLIPBOARD_SIZE: usize = 1024 * 1024;
+
+/// A client which sends nothing for this long is disconnected
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(60);
+
+const FRAME_COPY: u8 = b'C';
+const FRAME_PASTE: u8 = b'P';
+const FRAME_OK: u8 = b'K';
+const FRAME_TEXT: u8 = b'T';
+const FRAME_ERROR: u8 = b'E';
+// Type byte and u32 payload length
+const FRAME_HEADER_LEN: usize = 5;
+
+///
+/// A message on a clipboard socket. A frame is a type byte followed by the
+/// length of the payload as a big-endian u32 and then the payload, which must
+/// be UTF-8 text. Clients send `Copy` or `Paste` and receive `Ok`, `Text` or
+/// `Error` in reply.
+///
+#[derive(Clone,Debug,PartialEq)]
+pub enum Frame {
+    /// Replace the clipboard with the text
+    Copy(String),
+    /// Request the clipboard
+    Paste,
+    /// Reply to `Copy`
+    Ok,
+    /// Reply to `Paste`, empty if the clipboard is empty
+    Text(String),
+    /// Reply to a refused request with the reason
+    Error(String),
+}
+
+impl Frame {
+    pub fn write_to<W: Write>(&self, w: &mut W) -> Result<()> {
+        let (kind, payload) = match self {
+            Frame::Copy(text) => (FRAME_COPY, text.as_str()),
+            Frame::Paste => (FRAME_PASTE, ""),
+            Frame::Ok => (FRAME_OK, ""),
+            Frame::Text(text) => (FRAME_TEXT, text.as_str()),
+            Frame::Error(message) => (FRAME_ERROR, message.as_str()),
+        };
+        if payload.len() > MAX_CLIPBOARD_SIZE {
+            bail!("clipboard content of {} bytes exceeds the limit of {} bytes", payload.len(), MAX_CLIPBOARD_SIZE);
+        }
+        let mut buf = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+        buf.push(kind);
+        buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        buf.extend_from_slice(payload.as_bytes());
+        w.write_all(&buf)?;
+        Ok(())
+    }
+
+    /// Read the next frame, or return `None` if the stream ends before a frame
+    /// starts. The length is checked against the limit before the payload is read.
+    pub fn read_from<R: Read>(r: &mut R) -> Result<Option<Frame>> {
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        match r.read_exact(&mut header[..1]) {
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        r.read_exact(&mut header[1..])?;
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&header[1..]);
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_CLIPBOARD_SIZE {
+            bail!("clipboard content of {} bytes exceeds the limit of {} bytes", len, MAX_CLIPBOARD_SIZE);
+        }
+        let mut payload = vec![0u8; len];
+        r.read_exact(&mut payload)?;
+        let text = String::from_utf8(payload)
+            .map_err(|_| format_err!("clipboard content is not UTF-8 text"))?;
+        let frame = match header[0] {
+            FRAME_COPY => Frame::Copy(text),
+            FRAME_PASTE => Frame::Paste,
+            FRAME_OK => Frame::Ok,
+            FRAME_TEXT => Frame::Text(text),
+            FRAME_ERROR => Frame::Error(text),
+            kind => bail!("unknown clipboard frame type {:#04x}", kind),
+        };
+        Ok(Some(frame))
+    }
+}
+
+// Whether text copied on `origin` may be pasted on `target`, where `None` is
+// the host. Since only shared realms receive, text is never relayed between
+// two realms which are not shared.
+fn relay_allowed(origin: Option<ClipboardPolicy>, target: Option<ClipboardPolicy>) -> bool {
+    origin.is_none_or(ClipboardPolicy::sends) && target.is_none_or(ClipboardPolicy::receives)
+}
+
+fn endpoint_label(realm: Option<&str>) -> String {
+    match realm {
+        Some(name) => format!("realm {}", name),
+        None => "the host".to_string(),
+    }
+}
+
+struct RealmClipboard {
+    policy: ClipboardPolicy,
+    socket: PathBuf,
+    closed: Arc<AtomicBool>,
+}
+
+impl RealmClipboard {
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        // Wake the listener thread so that it sees the flag and drops the listener
+        let _ = UnixStream::connect(&self.socket);
+        if let Err(e) = fs::remove_file(&self.socket) {
+            verbose!("Failed to remove clipboard socket {}: {}", self.socket.display(), e);
+        }
+    }
+}
+
+struct ClipboardContent {
+    // Realm the text was copied in, or `None` if it came from the host
+    origin: Option<String>,
+    text: String,
+}
+
+#[derive(Default)]
+struct BrokerState {
+    realms: HashMap<String, RealmClipboard>,
+    content: Option<ClipboardContent>,
+}
+
+///
+/// Relays clipboard text between running realms configured with a `clipboard`
+/// policy and the host. Each such realm has a socket bound into it at
+/// /run/user/host/clipboard/clipboard.sock and the host clipboard agent uses
+/// /run/citadel/clipboard.sock. Text copied by a realm is dropped when the
+/// realm stops.
+///
+#[derive(Clone,Default)]
+pub struct ClipboardBroker {
+    state: Arc<Mutex<BrokerState>>,
+}
+
+impl ClipboardBroker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, BrokerState> {
+        self.state.lock().unwrap()
+    }
+
+    /// Listen on the host socket and on the sockets of realms which are already running.
+    pub fn start(&self, manager: &RealmManager) {
+        if let Err(e) = self.listen(None, Path::new(HOST_CLIPBOARD_SOCKET)) {
+            warn!("Failed to create host clipboard socket: {}", e);
+        }
+        for realm in manager.active_realms(false) {
+            self.realm_started(&realm);
+        }
+    }
+
+    pub fn realm_started(&self, realm: &Realm) {
+        let policy = match realm.config().clipboard() {
+            Some(policy) => policy,
+            None => return,
+        };
+        if self.state().realms.contains_key(realm.name()) {
+            return;
+        }
+        let socket = realm.clipboard_socket();
+        match self.listen(Some(realm.name()), &socket) {
+            Ok(closed) => {
+                let clipboard = RealmClipboard { policy, socket, closed };
+                self.state().realms.insert(realm.name().to_string(), clipboard);
+            },
+            Err(e) => warn!("Failed to create clipboard socket for realm {}: {}", realm.name(), e),
+        }
+    }
+
+    /// Close the socket of a realm which stopped and drop text copied in it.
+    pub fn realm_stopped(&self, name: &str) {
+        let mut state = self.state();
+        if let Some(clipboard) = state.realms.remove(name) {
+            clipboard.close();
+        }
+        if state.content.as_ref().is_some_and(|c| c.origin.as_deref() == Some(name)) {
+            state.content = None;
+        }
+    }
+
+    /// Change the policy of a running realm until it stops. The realm must have
+    /// been started with a `clipboard` policy so that it has a socket.
+    pub fn set_policy(&self, name: &str, policy: ClipboardPolicy) -> Result<()> {
+        match self.state().realms.get_mut(name) {
+            Some(clipboard) => {
+                info!("Clipboard policy of realm {} set to {}", name, policy.to_str_value());
+                clipboard.policy = policy;
+                Ok(())
+            },
+            None => bail!("realm {} has no clipboard socket, set the clipboard option in its config and restart it", name),
+        }
+    }
+
+    /// The policy in effect for a running realm, or `None` if it has no clipboard socket
+    pub fn policy(&self, name: &str) -> Option<ClipboardPolicy> {
+        self.state().realms.get(name).map(|clipboard| clipboard.policy)
+    }
+
+    fn listen(&self, realm: Option<&str>, path: &Path) -> Result<Arc<AtomicBool>> {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)
+            .map_err(|e| format_err!("failed to bind {}: {}", path.display(), e))?;
+        util::chown_user(path)?;
+        let closed = Arc::new(AtomicBool::new(false));
+        let broker = self.clone();
+        let realm = realm.map(String::from);
+        let stop = closed.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                match stream {
+                    Ok(stream) => broker.spawn_client(stream, realm.clone()),
+                    Err(e) => warn!("Error accepting clipboard connection from {}: {}", endpoint_label(realm.as_deref()), e),
+                }
+            }
+        });
+        Ok(closed)
+    }
+
+    fn spawn_client(&self, stream: UnixStream, realm: Option<String>) {
+        let broker = self.clone();
+        thread::spawn(move || {
+            if let Err(e) = broker.serve(stream, realm.as_deref()) {
+                verbose!("Clipboard connection from {} closed: {}", endpoint_label(realm.as_deref()), e);
+            }
+        });
+    }
+
+    // Handle requests until the client disconnects. A frame which cannot be read
+    // is answered with an error and the connection is closed since the rest of
+    // the stream cannot be trusted to start on a frame.
+    fn serve(&self, stream: UnixStream, realm: Option<&str>) -> Result<()> {
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        let mut reader = stream.try_clone()?;
+        let mut writer = stream;
+        loop {
+            let frame = match Frame::read_from(&mut reader) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    let _ = Frame::Error(e.to_string()).write_to(&mut writer);
+                    return Err(e);
+                }
+            };
+            self.handle(realm, frame).write_to(&mut writer)?;
+        }
+    }
+
+    fn handle(&self, realm: Option<&str>, frame: Frame) -> Frame {
+        let mut state = self.state();
+        let policy = match realm {
+            Some(name) => match state.realms.get(name) {
+                Some(clipboard) => Some(clipboard.policy),
+                None => return Frame::Error(format!("realm {} is not running", name)),
+            },
+            None => None,
+        };
+        match frame {
+            Frame::Copy(text) => {
+                if !policy.is_none_or(ClipboardPolicy::sends) {
+                    return Frame::Error(format!("clipboard of {} is isolated", endpoint_label(realm)));
+                }
+                state.content = Some(ClipboardContent { origin: realm.map(String::from), text });
+                Frame::Ok
+            },
+            Frame::Paste => {
+                let content = match state.content {
+                    Some(ref content) => content,
+                    None => return Frame::Text(String::new()),
+                };
+                let origin = content.origin.as_deref();
+                let origin_policy = origin.and_then(|name| state.realms.get(name)).map(|c| c.policy);
+                if relay_allowed(origin_policy, policy) {
+                    Frame::Text(content.text.clone())
+                } else {
+                    Frame::Error(format!("clipboard policy does not allow relaying from {} to {}", endpoint_label(origin), endpoint_label(realm)))
+                }
+            },
+            frame => Frame::Error(format!("unexpected clipboard request {:?}", frame)),
+        }
+    }
+}
+
+#[cfg(test)]
+fn add_test_realm(broker: &ClipboardBroker, name: &str, policy: ClipboardPolicy) {
+    let clipboard = RealmClipboard { policy, socket: PathBuf::from("/nonexistent"), closed: Arc::new(AtomicBool::new(false)) };
+    broker.state().realms.insert(name.to_string(), clipboard);
+}
+
+#[test]
+fn test_clipboard_frames() {
+    let frames = vec![
+        Frame::Copy("héllo\nworld".to_string()),
+        Frame::Paste,
+        Frame::Ok,
+        Frame::Text(String::new()),
+        Frame::Error("refused".to_string()),
+    ];
+    let mut buf = Vec::new();
+    for frame in &frames {
+        frame.write_to(&mut buf).unwrap();
+    }
+    assert_eq!(&buf[..10], b"C\x00\x00\x00\x0ch\xc3\xa9ll");
+    let mut reader = io::Cursor::new(buf);
+    for frame in &frames {
+        assert_eq!(Frame::read_from(&mut reader).unwrap().as_ref(), Some(frame));
+    }
+    assert_eq!(Frame::read_from(&mut reader).unwrap(), None);
+
+    // A frame which ends early, is too large, is not text or has an unknown type is an error
+    assert!(Frame::read_from(&mut &b"C\x00\x00\x00\x05abc"[..]).is_err());
+    assert!(Frame::read_from(&mut &b"C\x00\x00"[..]).is_err());
+    let err = Frame::read_from(&mut &b"C\x00\x10\x00\x01"[..]).unwrap_err();
+    assert_eq!(err.to_string(), "clipboard content of 1048577 bytes exceeds the limit of 1048576 bytes");
+    let err = Frame::read_from(&mut &b"C\x00\x00\x00\x02\xff\xfe"[..]).unwrap_err();
+    assert_eq!(err.to_string(), "clipboard content is not UTF-8 text");
+    assert!(Frame::read_from(&mut &b"X\x00\x00\x00\x00"[..]).is_err());
+    assert!(Frame::Copy("x".repeat(MAX_CLIPBOARD_SIZE + 1)).write_to(&mut Vec::new()).is_err());
+}
+
+#[test]
+fn test_clipboard_relay() {
+    use ClipboardPolicy::*;
+    let text = |s: &str| Frame::Text(s.to_string());
+    let copy = |s: &str| Frame::Copy(s.to_string());
+
+    let broker = ClipboardBroker::new();
+    add_test_realm(&broker, "shared", Shared);
+    add_test_realm(&broker, "out", OutboundOnly);
+    add_test_realm(&broker, "out2", OutboundOnly);
+    add_test_realm(&broker, "isolated", Isolated);
+
+    assert_eq!(broker.handle(None, Frame::Paste), text(""));
+    assert_eq!(broker.handle(None, copy("from host")), Frame::Ok);
+    assert_eq!(broker.handle(Some("shared"), Frame::Paste), text("from host"));
+    assert!(matches!(broker.handle(Some("out"), Frame::Paste), Frame::Error(_)));
+    assert!(matches!(broker.handle(Some("isolated"), Frame::Paste), Frame::Error(_)));
+
+    assert_eq!(broker.handle(Some("out"), copy("from out")), Frame::Ok);
+    assert_eq!(broker.handle(None, Frame::Paste), text("from out"));
+    assert_eq!(broker.handle(Some("shared"), Frame::Paste), text("from out"));
+    // Two realms which are not shared never exchange text
+    assert_eq!(broker.handle(Some("out2"), Frame::Paste),
+               Frame::Error("clipboard policy does not allow relaying from realm out to realm out2".to_string()));
+    assert!(!relay_allowed(Some(OutboundOnly), Some(OutboundOnly)));
+    assert!(!relay_allowed(Some(Isolated), Some(Shared)));
+    assert!(!relay_allowed(Some(Shared), Some(Isolated)));
+    assert!(relay_allowed(Some(Shared), None));
+
+    assert_eq!(broker.handle(Some("isolated"), copy("secret")),
+               Frame::Error("clipboard of realm isolated is isolated".to_string()));
+    assert_eq!(broker.handle(None, Frame::Paste), text("from out"));
+
+    // A policy change applies to text already copied
+    broker.set_policy("out", Isolated).unwrap();
+    assert_eq!(broker.policy("out"), Some(Isolated));
+    assert!(matches!(broker.handle(None, Frame::Paste), Frame::Error(_)));
+    assert!(broker.set_policy("stopped", Shared).is_err());
+
+    // Text copied by a realm is dropped when it stops
+    broker.set_policy("out", OutboundOnly).unwrap();
+    broker.state().realms.remove("out");
+    broker.realm_stopped("out");
+    assert_eq!(broker.handle(None, Frame::Paste), text(""));
+    assert_eq!(broker.handle(Some("out"), copy("late")), Frame::Error("realm out is not running".to_string()));
+    assert_eq!(broker.policy("out"), None);
+    assert!(matches!(broker.handle(None, Frame::Ok), Frame::Error(_)));
+}
+
+#[test]
+fn test_clipboard_socket_relay() {
+    let broker = ClipboardBroker::new();
+    add_test_realm(&broker, "work", ClipboardPolicy::Shared);
+    let (client, server) = UnixStream::pair().unwrap();
+    let handle = {
+        let broker = broker.clone();
+        thread::spawn(move || broker.serve(server, Some("work")))
+    };
+    let mut client = client;
+    Frame::Copy("copied in work".to_string()).write_to(&mut client).unwrap();
+    assert_eq!(Frame::read_from(&mut client).unwrap(), Some(Frame::Ok));
+    Frame::Paste.write_to(&mut client).unwrap();
+    assert_eq!(Frame::read_from(&mut client).unwrap(), Some(Frame::Text("copied in work".to_string())));
+    assert_eq!(broker.handle(None, Frame::Paste), Frame::Text("copied in work".to_string()));
+
+    // An oversized frame is refused before its payload is sent and the connection is closed
+    client.write_all(b"C\xff\xff\xff\xff").unwrap();
+    match Frame::read_from(&mut client).unwrap() {
+        Some(Frame::Error(message)) => assert!(message.contains("exceeds the limit"), "{}", message),
+        other => panic!("unexpected reply {:?}", other),
+    }
+    assert_eq!(Frame::read_from(&mut client).unwrap(), None);
+    assert!(handle.join().unwrap().is_err());
+}
@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use libcitadel::{Logger, Result, StartThresholds};
+
+pub const DAEMON_CONFIG_PATH: &str = "/etc/citadel/realmsd.conf";
+
+/// Interval between checks for network addresses allocated to realms which are no longer running
+const DEFAULT_RECONCILE_INTERVAL: u64 = 300;
+
+/// Window over which realm start requests are counted for `start-rate-limit`
+const START_RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Minimum time between changes of the current realm made because a window of another realm received focus
+const FOCUS_CHANGE_INTERVAL: Duration = Duration::from_millis(500);
+
+///
+/// Daemon settings read from /etc/citadel/realmsd.conf. Every setting has a
+/// default, so the file does not need to exist and may set only some of them.
+///
+///     # Log level specification, see `Logger::set_log_spec()`
+///     log-level = "info,libcitadel::realm=debug"
+///
+///     # Maximum number of Start requests for each realm per minute, 0 for no limit
+///     start-rate-limit = 5
+///
+///     # Seconds between checks for network addresses of stopped realms
+///     reconcile-interval = 300
+///
+///     # Serve metrics over HTTP on /run/citadel/metrics.sock
+///     metrics-socket = true
+///
+///     # Minimum available memory and free space on /storage for starting a realm
+///     min-start-memory = "512M"
+///     min-start-storage = "2G"
+///
+///     # Refuse to start a realm below the minimums instead of logging a warning
+///     refuse-start-on-low-resources = true
+///
+///     # Delete the citadel firewall table when realmsd exits
+///     remove-firewall-on-exit = true
+///
+/// `log-level`, `start-rate-limit`, `remove-firewall-on-exit` and the start
+/// resource settings are applied again when realmsd receives SIGHUP, other
+/// settings only take effect when realmsd is restarted.
+///
+#[derive(Deserialize,Clone,Debug,PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub struct DaemonConfig {
+    #[serde(rename="log-level")]
+    log_level: Option<String>,
+    #[serde(rename="start-rate-limit")]
+    start_rate_limit: u32,
+    #[serde(rename="reconcile-interval")]
+    reconcile_interval: u64,
+    #[serde(rename="metrics-socket")]
+    metrics_socket: bool,
+    #[serde(rename="min-start-memory")]
+    min_start_memory: Option<String>,
+    #[serde(rename="min-start-storage")]
+    min_start_storage: Option<String>,
+    #[serde(rename="refuse-start-on-low-resources")]
+    refuse_start_on_low_resources: bool,
+    #[serde(rename="remove-firewall-on-exit")]
+    remove_firewall_on_exit: bool,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        DaemonConfig {
+            log_level: None,
+            start_rate_limit: 0,
+            reconcile_interval: DEFAULT_RECONCILE_INTERVAL,
+            metrics_socket: false,
+            min_start_memory: None,
+            min_start_storage: None,
+            refuse_start_on_low_resources: false,
+            remove_firewall_on_exit: false,
+        }
+    }
+}
+
+impl DaemonConfig {
+    /// Read /etc/citadel/realmsd.conf, or return the default settings if it does not exist.
+    pub fn load() -> Result<Self> {
+        let path = Path::new(DAEMON_CONFIG_PATH);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = fs::read_to_string(path)
+            .map_err(|e| format_err!("failed to read {}: {}", DAEMON_CONFIG_PATH, e))?;
+        Self::parse(&content)
+            .map_err(|e| format_err!("invalid configuration in {}: {}", DAEMON_CONFIG_PATH, e))
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let config: DaemonConfig = toml::from_str(content)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if let Some(ref spec) = self.log_level {
+            Logger::check_log_spec(spec)
+                .map_err(|e| format_err!("invalid value for key `log-level`: {}", e))?;
+        }
+        if self.reconcile_interval == 0 {
+            bail!("invalid value for key `reconcile-interval`: must be at least 1 second");
+        }
+        if let Some(ref size) = self.min_start_memory {
+            StartThresholds::parse_size(size)
+                .map_err(|e| format_err!("invalid value for key `min-start-memory`: {}", e))?;
+        }
+        if let Some(ref size) = self.min_start_storage {
+            StartThresholds::parse_size(size)
+                .map_err(|e| format_err!("invalid value for key `min-start-storage`: {}", e))?;
+        }
+        Ok(())
+    }
+
+    pub fn log_level(&self) -> Option<&str> {
+        self.log_level.as_deref()
+    }
+
+    /// Maximum number of Start requests accepted for each realm per minute, 0 if there is no limit
+    pub fn start_rate_limit(&self) -> u32 {
+        self.start_rate_limit
+    }
+
+    pub fn reconcile_interval(&self) -> Duration {
+        Duration::from_secs(self.reconcile_interval)
+    }
+
+    pub fn metrics_socket(&self) -> bool {
+        self.metrics_socket
+    }
+
+    /// Remove the firewall installed at startup when realmsd exits. It is kept
+    /// by default so that realms left running stay isolated from each other.
+    pub fn remove_firewall_on_exit(&self) -> bool {
+        self.remove_firewall_on_exit
+    }
+
+    /// Minimum memory and storage for starting a realm. Sizes are checked by
+    /// `validate()` so an unset or invalid size is a minimum of 0.
+    pub fn start_thresholds(&self) -> StartThresholds {
+        let size = |s: &Option<String>| s.as_deref()
+            .and_then(|s| StartThresholds::parse_size(s).ok())
+            .unwrap_or(0);
+        StartThresholds::new(size(&self.min_start_memory), size(&self.min_start_storage), self.refuse_start_on_low_resources)
+    }
+
+    pub fn apply_log_level(&self) {
+        if let Some(spec) = self.log_level() {
+            if let Err(e) = Logger::set_log_spec(spec) {
+                warn!("Failed to apply log-level from {}: {}", DAEMON_CONFIG_PATH, e);
+            }
+        }
+    }
+
+    ///
+    /// Return the settings in effect after reloading the configuration file
+    /// which now contains `reloaded`, together with the names of the settings
+    /// which changed but keep their current value until realmsd is restarted.
+    ///
+    pub fn reload(&self, reloaded: DaemonConfig) -> (DaemonConfig, Vec<&'static str>) {
+        let mut restart_required = Vec::new();
+        if reloaded.reconcile_interval != self.reconcile_interval {
+            restart_required.push("reconcile-interval");
+        }
+        if reloaded.metrics_socket != self.metrics_socket {
+            restart_required.push("metrics-socket");
+        }
+        let config = DaemonConfig {
+            reconcile_interval: self.reconcile_interval,
+            metrics_socket: self.metrics_socket,
+            ..reloaded
+        };
+        (config, restart_required)
+    }
+
+    /// Effective value of each setting, returned by the GetDaemonConfig method
+    pub fn values(&self) -> HashMap<String, String> {
+        let mut values = HashMap::new();
+        values.insert("log-level".to_string(), self.log_level().unwrap_or("").to_string());
+        values.insert("start-rate-limit".to_string(), self.start_rate_limit.to_string());
+        values.insert("reconcile-interval".to_string(), self.reconcile_interval.to_string());
+        values.insert("metrics-socket".to_string(), self.metrics_socket.to_string());
+        values.insert("min-start-memory".to_string(), self.min_start_memory.clone().unwrap_or_default());
+        values.insert("min-start-storage".to_string(), self.min_start_storage.clone().unwrap_or_default());
+        values.insert("refuse-start-on-low-resources".to_string(), self.refuse_start_on_low_resources.to_string());
+        values.insert("remove-firewall-on-exit".to_string(), self.remove_firewall_on_exit.to_string());
+        values
+    }
+}
+
+///
+/// Counts Start requests for each realm over the last minute to enforce the
+/// `start-rate-limit` setting.
+///
+#[derive(Default)]
+pub struct StartRateLimiter {
+    requests: HashMap<String, Vec<Instant>>,
+}
+
+impl StartRateLimiter {
+    /// Record a Start request for `realm` made at `now` and return `false` if
+    /// `limit` requests were already accepted for the realm in the last minute.
+    pub fn allow(&mut self, realm: &str, limit: u32, now: Instant) -> bool {
+        let requests = self.requests.entry(realm.to_string()).or_default();
+        requests.retain(|&t| now.duration_since(t) < START_RATE_WINDOW);
+        if limit > 0 && requests.len() >= limit as usize {
+            return false;
+        }
+        requests.push(now);
+        true
+    }
+}
+
+///
+/// Limits how often the current realm is changed by focus reports from the
+/// compositor, so that rapidly switching between windows of different realms
+/// does not cause a burst of RealmCurrent signals.
+///
+#[derive(Default)]
+pub struct FocusRateLimiter {
+    last_change: Option<Instant>,
+}
+
+impl FocusRateLimiter {
+    /// Return `true` if the current realm should be changed from `current` to
+    /// `focused` at `now`, and record the change.
+    pub fn allow(&mut self, focused: &str, current: Option<&str>, now: Instant) -> bool {
+        if current == Some(focused) {
+            return false;
+        }
+        if let Some(last) = self.last_change {
+            if now.duration_since(last) < FOCUS_CHANGE_INTERVAL {
+                return false;
+            }
+        }
+        self.last_change = Some(now);
+        true
+    }
+}
+
+#[test]
+fn test_daemon_config() {
+    let config = DaemonConfig::parse("").unwrap();
+    assert_eq!(config, DaemonConfig::default());
+    assert_eq!(config.log_level(), None);
+    assert_eq!(config.start_rate_limit(), 0);
+    assert_eq!(config.reconcile_interval(), Duration::from_secs(300));
+
+    // Settings which are not in the file keep their defaults
+    let config = DaemonConfig::parse("start-rate-limit = 5\nlog-level = \"debug\"\n").unwrap();
+    assert_eq!(config.start_rate_limit(), 5);
+    assert_eq!(config.log_level(), Some("debug"));
+    assert_eq!(config.reconcile_interval(), Duration::from_secs(300));
+    assert_eq!(config.values()["start-rate-limit"], "5");
+    assert_eq!(config.start_thresholds(), StartThresholds::default());
+
+    let config = DaemonConfig::parse("min-start-memory = \"512M\"\nrefuse-start-on-low-resources = true").unwrap();
+    assert_eq!(config.start_thresholds(), StartThresholds::new(512 * 1024 * 1024, 0, true));
+    assert!(!config.remove_firewall_on_exit());
+    assert!(DaemonConfig::parse("remove-firewall-on-exit = true").unwrap().remove_firewall_on_exit());
+
+    for (content, key) in &[("start-rate-limit = -1", "start-rate-limit"),
+                            ("start-rate-limit = \"often\"", "start-rate-limit"),
+                            ("log-level = \"loud\"", "log-level"),
+                            ("reconcile-interval = 0", "reconcile-interval"),
+                            ("min-start-memory = \"lots\"", "min-start-memory"),
+                            ("min-start-storage = 2048", "min-start-storage"),
+                            ("idle-exit = true", "idle-exit")] {
+        let err = DaemonConfig::parse(content).unwrap_err().to_string();
+        assert!(err.contains(key), "error for '{}' does not name the key: {}", content, err);
+    }
+
+    let (reloaded, restart) = config.reload(DaemonConfig::parse("start-rate-limit = 2\nreconcile-interval = 60\nmetrics-socket = true").unwrap());
+    assert_eq!(restart, vec!["reconcile-interval", "metrics-socket"]);
+    assert!(!reloaded.metrics_socket());
+    assert_eq!(reloaded.start_rate_limit(), 2);
+    assert_eq!(reloaded.log_level(), None);
+    assert_eq!(reloaded.reconcile_interval(), Duration::from_secs(300));
+}
+
+#[test]
+fn test_start_rate_limiter() {
+    let mut limiter = StartRateLimiter::default();
+    let now = Instant::now();
+    assert!(limiter.allow("work", 2, now));
+    assert!(limiter.allow("work", 2, now + Duration::from_secs(1)));
+    assert!(!limiter.allow("work", 2, now + Duration::from_secs(2)));
+    assert!(limiter.allow("main", 2, now + Duration::from_secs(2)));
+    assert!(limiter.allow("work", 2, now + Duration::from_secs(61)));
+    assert!((0..10).all(|_| limiter.allow("main", 0, now)));
+}
+
+#[test]
+fn test_focus_rate_limiter() {
+    let mut limiter = FocusRateLimiter::default();
+    let now = Instant::now();
+    assert!(!limiter.allow("main", Some("main"), now));
+    assert!(limiter.allow("work", Some("main"), now));
+    assert!(!limiter.allow("main", Some("work"), now + Duration::from_millis(100)));
+    assert!(limiter.allow("main", Some("work"), now + Duration::from_millis(600)));
+    assert!(limiter.allow("work", None, now + Duration::from_secs(2)));
+}
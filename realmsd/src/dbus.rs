@@ -1,43 +1,127 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::collections::HashMap;
 use std::{result, thread};
+use std::time::{Duration, Instant};
 
 use dbus::tree::{self, Factory, MTFn, MethodResult, Tree, MethodErr};
-use dbus::{Connection, NameFlag, Message};
-use libcitadel::{Result, RealmManager, Realm, RealmEvent};
+use dbus::{Connection, NameFlag, Message, MessageItem, RequestNameReply};
+use libcitadel::{Result, RealmManager, Realm, RealmEvent, NetworkBlockState, BootStatus, Metrics, LowResourcesError, Firewall, HomeQuota, QuotaExceededError, ClipboardPolicy, KeyRing, ManifestTrust, PublicKey, RealmFS};
 use std::fmt;
+use std::path::{Component, Path};
+use std::sync::mpsc::Sender;
+
+use crate::clipboard::ClipboardBroker;
+use crate::config::{DaemonConfig,FocusRateLimiter,StartRateLimiter,DAEMON_CONFIG_PATH};
+use crate::devices::{UsbMonitor,MediaMonitor};
+use crate::handoff::{self, HandoffState};
+use crate::history::{EventHistory, EVENT_HISTORY_SIZE};
+use crate::info::RealmInfoWriter;
+use crate::instance::{self, AlreadyRunning, BusName, NameRequest};
+use crate::queue::{JobId,QueueBusy,RealmQueue,MAX_QUEUED_OPERATIONS};
+use crate::signals::{SignalSender, SignalSink, PENDING_SIGNALS_SIZE};
+use crate::vpn::VpnMonitor;
 
 type MethodInfo<'a> = tree::MethodInfo<'a, MTFn<TData>, TData>;
 
+// Name of the current realm and (name, status, has_gpu) of each realm
+type SwitcherState = (String, Vec<(String, u8, bool)>);
+
 const STATUS_REALM_NOT_RUNNING: u8 = 0;
 const STATUS_REALM_RUNNING_NOT_CURRENT: u8 = 1;
 const STATUS_REALM_RUNNING_CURRENT: u8 = 2;
+const STATUS_REALM_FROZEN: u8 = 3;
+const STATUS_REALM_CONFIG_ERROR: u8 = 4;
 
 const OBJECT_PATH: &str = "/com/subgraph/realms";
+
+/// Time to wait after a realm stops before checking if it hit the start limit
+const START_LIMIT_CHECK_DELAY: Duration = Duration::from_secs(10);
+/// How long the result of GetSwitcherState is returned again to repeated calls
+const SWITCHER_STATE_CACHE_TIME: Duration = Duration::from_secs(1);
+/// Number of files copied between SnapshotProgress and CloneProgress signals
+const SNAPSHOT_PROGRESS_INTERVAL: usize = 500;
+/// Queue of ImportRealm operations, which is not a valid realm name
+const IMPORT_QUEUE: &str = "@import";
 const INTERFACE_NAME: &str = "com.subgraph.realms.Manager";
 const BUS_NAME: &str = "com.subgraph.realms";
 
 const OBJECT_MANAGER_INTERFACE: &str = "org.freedesktop.DBus.ObjectManager";
+const ERROR_INVALID_ARGS: &str = "org.freedesktop.DBus.Error.InvalidArgs";
+const ERROR_ACCESS_DENIED: &str = "org.freedesktop.DBus.Error.AccessDenied";
+/// Error returned when a realm is not started because the host is low on memory or storage
+const ERROR_LOW_RESOURCES: &str = "com.subgraph.realms.Error.LowResources";
+const ERROR_QUOTA_EXCEEDED: &str = "com.subgraph.realms.Error.QuotaExceeded";
+/// Error returned when a realm already has the maximum number of operations queued
+const ERROR_BUSY: &str = "com.subgraph.realms.Error.Busy";
+
+/// Account which commands are run as in a realm unless the "user" option is passed
+const DEFAULT_RUN_USER: &str = "user";
 const VPN_CONNECTION_INTERFACE: &str = "org.freedesktop.VPN.Connection";
 
+const DBUS_BUS_NAME: &str = "org.freedesktop.DBus";
+const DBUS_OBJECT_PATH: &str = "/org/freedesktop/DBus";
+/// Interface of the signal libdbus delivers when the connection to the bus is lost
+const DBUS_LOCAL_INTERFACE: &str = "org.freedesktop.DBus.Local";
+/// Delay before the first attempt to reconnect after the bus connection is lost,
+/// doubled after each failed attempt up to RECONNECT_MAX_DELAY
+const RECONNECT_MIN_DELAY: Duration = Duration::from_millis(500);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+/// Timeout in milliseconds for calls made by realmsd to the bus daemon or another realmsd instance
+const BUS_CALL_TIMEOUT: i32 = 5000;
+
 pub struct DbusServer {
-    connection: Arc<Connection>,
     manager: Arc<RealmManager>,
     events: EventHandler,
+    config: Arc<RwLock<DaemonConfig>>,
+    // Shared by the object tree registered on each new connection so that
+    // queued operations and rate limits survive a reconnect
+    data: TreeData,
+    // Set to exit the message loop
+    quit: Arc<AtomicBool>,
+    // Set to exit the message loop and execute a new realmsd process
+    restart: Arc<AtomicBool>,
 }
 
 impl DbusServer {
 
-    pub fn connect(manager: Arc<RealmManager>) -> Result<DbusServer> {
+    pub fn connect(manager: Arc<RealmManager>, config: DaemonConfig) -> Result<DbusServer> {
         let connection = Arc::new(Connection::get_private(dbus::BusType::System)?);
-        let events = EventHandler::new(connection.clone());
-        let server = DbusServer { events, connection, manager };
+        let events = EventHandler::new(connection, manager.clone());
+        let config = Arc::new(RwLock::new(config));
+        let quit = Arc::new(AtomicBool::new(false));
+        let restart = Arc::new(AtomicBool::new(false));
+        let data = TreeData::new(manager.clone(), events.clone(), config.clone(), quit.clone(), restart.clone());
+        let server = DbusServer { manager, events, config, data, quit, restart };
         Ok(server)
     }
 
+    // The current bus connection, which is replaced when realmsd reconnects
+    fn connection(&self) -> Arc<Connection> {
+        self.events.sender.connection()
+    }
+
+    /// Acquire the bus name, or if `replace` is set replace the realmsd
+    /// instance which owns it. Returns an `AlreadyRunning` error if another
+    /// instance owns the name and `replace` is not set.
+    pub fn acquire_name(&self, replace: bool) -> Result<()> {
+        instance::acquire_name(self, replace)
+    }
+
+    /// Acquire the bus name released by the realmsd process which executed this
+    /// one to restart. Any other owner is replaced since this process carries on
+    /// with the state of the previous one.
+    pub fn acquire_name_for_handoff(&self) -> Result<()> {
+        let flags = NameFlag::ReplaceExisting as u32 | NameFlag::DoNotQueue as u32;
+        match self.connection().register_name(BUS_NAME, flags)? {
+            RequestNameReply::PrimaryOwner | RequestNameReply::AlreadyOwner => Ok(()),
+            RequestNameReply::InQueue | RequestNameReply::Exists => Err(AlreadyRunning::new(self.owner_pid()).into()),
+        }
+    }
+
     fn build_tree(&self) -> Tree<MTFn<TData>, TData> {
         let f = Factory::new_fn::<TData>();
-        let data = TreeData::new(self.manager.clone());
+        let data = self.data.clone();
         let interface = f.interface(INTERFACE_NAME, ())
             // Methods
             .add_m(f.method("SetCurrent", (), Self::do_set_current)
@@ -49,23 +133,145 @@ impl DbusServer {
             .add_m(f.method("List", (), Self::do_list)
                 .out_arg(("realms", "a{sy}")))
 
+            .add_m(f.method("ListDetailed", (), Self::do_list_detailed)
+                .out_arg(("realms", "a(syuttts)")))
+
+            .add_m(f.method("GetSwitcherState", (), Self::do_get_switcher_state)
+                .out_arg(("current", "s"))
+                .out_arg(("realms", "a(syb)")))
+
             .add_m(f.method("Start", (), Self::do_start)
-                .in_arg(("name", "s")))
+                .in_arg(("name", "s"))
+                .out_arg(("job", "t")))
 
             .add_m(f.method("Stop", (), Self::do_stop)
+                .in_arg(("name", "s"))
+                .out_arg(("job", "t")))
+
+            .add_m(f.method("Freeze", (), Self::do_freeze)
+                .in_arg(("name", "s"))
+                .in_arg(("force", "b"))
+                .out_arg(("job", "t")))
+
+            .add_m(f.method("Thaw", (), Self::do_thaw)
+                .in_arg(("name", "s"))
+                .out_arg(("job", "t")))
+
+            .add_m(f.method("SetResourceLimits", (), Self::do_set_resource_limits)
+                .in_arg(("name", "s"))
+                .in_arg(("limits", "a{ss}"))
+                .out_arg(("job", "t")))
+
+            .add_m(f.method("SetClipboardPolicy", (), Self::do_set_clipboard_policy)
+                .in_arg(("name", "s"))
+                .in_arg(("policy", "s"))
+                .out_arg(("job", "t")))
+
+            .add_m(f.method("ResetFailedRealm", (), Self::do_reset_failed)
                 .in_arg(("name", "s")))
 
             .add_m(f.method("Terminal", (), Self::do_terminal)
-                .in_arg(("name", "s")))
+                .in_arg(("name", "s"))
+                .in_arg(("options", "a{ss}")))
 
             .add_m(f.method("Run", (), Self::do_run)
                 .in_arg(("name", "s"))
-                .in_arg(("args", "as")))
+                .in_arg(("args", "as"))
+                .in_arg(("options", "a{ss}"))
+                .out_arg(("job", "t")))
+
+            .add_m(f.method("CloneRealm", (), Self::do_clone_realm)
+                .in_arg(("source", "s"))
+                .in_arg(("name", "s"))
+                .in_arg(("clone_home", "b"))
+                .in_arg(("require_stopped", "b"))
+                .out_arg(("job", "t")))
+
+            .add_m(f.method("ExportRealm", (), Self::do_export_realm)
+                .in_arg(("name", "s"))
+                .in_arg(("path", "s"))
+                .in_arg(("sign", "b"))
+                .out_arg(("job", "t")))
+
+            .add_m(f.method("ImportRealm", (), Self::do_import_realm)
+                .in_arg(("path", "s"))
+                .in_arg(("public_key", "s"))
+                .in_arg(("allow_unsigned", "b"))
+                .out_arg(("name", "s")))
+
+            .add_m(f.method("SnapshotHome", (), Self::do_snapshot_home)
+                .in_arg(("name", "s"))
+                .in_arg(("label", "s")))
+
+            .add_m(f.method("ListSnapshots", (), Self::do_list_snapshots)
+                .in_arg(("name", "s"))
+                .out_arg(("snapshots", "a(sstt)")))
+
+            .add_m(f.method("RestoreSnapshot", (), Self::do_restore_snapshot)
+                .in_arg(("name", "s"))
+                .in_arg(("id", "s"))
+                .out_arg(("job", "t")))
+
+            .add_m(f.method("CopyIntoRealm", (), Self::do_copy_into_realm)
+                .in_arg(("name", "s"))
+                .in_arg(("host_src", "s"))
+                .in_arg(("realm_dst", "s")))
+
+            .add_m(f.method("CopyFromRealm", (), Self::do_copy_from_realm)
+                .in_arg(("name", "s"))
+                .in_arg(("realm_src", "s"))
+                .in_arg(("host_dst", "s")))
+
+            .add_m(f.method("ListPortForwards", (), Self::do_list_port_forwards)
+                .in_arg(("name", "s"))
+                .out_arg(("forwards", "a(sqq)")))
+
+            .add_m(f.method("PreviewLaunchConfig", (), Self::do_preview_launch_config)
+                .in_arg(("name", "s"))
+                .out_arg(("nspawn", "s"))
+                .out_arg(("service", "s"))
+                .out_arg(("address", "s"))
+                .out_arg(("bindings", "a{ss}")))
+
+            .add_m(f.method("GetLaunchReport", (), Self::do_get_launch_report)
+                .in_arg(("name", "s"))
+                .out_arg(("bindings", "a{ss}")))
+
+            .add_m(f.method("GetRealmDiskUsage", (), Self::do_get_realm_disk_usage)
+                .in_arg(("name", "s"))
+                .out_arg(("usage", "t"))
+                .out_arg(("quota", "t"))
+                .out_arg(("enforcement", "s")))
+
+            .add_m(f.method("Quit", (), Self::do_quit))
+
+            .add_m(f.method("Restart", (), Self::do_restart))
+
+            .add_m(f.method("GetMetrics", (), Self::do_get_metrics)
+                .out_arg(("metrics", "s")))
+
+            .add_m(f.method("GetEventHistory", (), Self::do_get_event_history)
+                .in_arg(("name", "s"))
+                .in_arg(("limit", "u"))
+                .out_arg(("events", "a(tss)")))
+
+            .add_m(f.method("GetDaemonConfig", (), Self::do_get_daemon_config)
+                .out_arg(("config", "a{ss}")))
+
+            .add_m(f.method("GetNetworkZones", (), Self::do_get_network_zones)
+                .out_arg(("zones", "a(ssuu)")))
+
+            .add_m(f.method("GetNetworkBlockState", (), Self::do_get_network_block_state)
+                .in_arg(("name", "s"))
+                .out_arg(("state", "s")))
 
             .add_m(f.method("RealmFromCitadelPid", (), Self::do_pid_to_realm)
                 .in_arg(("pid", "u"))
                 .out_arg(("realm", "s")))
 
+            .add_m(f.method("ReportFocusedPid", (), Self::do_report_focused_pid)
+                .in_arg(("pid", "u")))
+
             // Signals
             .add_s(f.signal("RealmStarted", ())
                 .arg(("realm", "s")))
@@ -77,7 +283,52 @@ impl DbusServer {
                 .arg(("realm","s")))
             .add_s(f.signal("RealmCurrent", ())
                 .arg(("realm", "s")))
-            .add_s(f.signal("ServiceStarted", ()));
+            .add_s(f.signal("RealmFrozen", ())
+                .arg(("realm", "s")))
+            .add_s(f.signal("RealmThawed", ())
+                .arg(("realm", "s")))
+            .add_s(f.signal("RealmConfigChanged", ())
+                .arg(("realm", "s")))
+            .add_s(f.signal("RealmFailed", ())
+                .arg(("realm", "s"))
+                .arg(("reason", "s")))
+            .add_s(f.signal("UsbDeviceMatched", ())
+                .arg(("realm", "s"))
+                .arg(("dev", "s")))
+            .add_s(f.signal("RealmNetworkBlocked", ())
+                .arg(("realm", "s")))
+            .add_s(f.signal("RealmNetworkRestored", ())
+                .arg(("realm", "s")))
+            .add_s(f.signal("RemovableDeviceAttached", ())
+                .arg(("realm", "s"))
+                .arg(("label", "s"))
+                .arg(("path", "s")))
+            .add_s(f.signal("SnapshotProgress", ())
+                .arg(("realm", "s"))
+                .arg(("files", "t")))
+            .add_s(f.signal("CloneProgress", ())
+                .arg(("realm", "s"))
+                .arg(("name", "s"))
+                .arg(("files", "t")))
+            .add_s(f.signal("SnapshotFinished", ())
+                .arg(("realm", "s"))
+                .arg(("id", "s"))
+                .arg(("error", "s")))
+            .add_s(f.signal("RealmJobFinished", ())
+                .arg(("realm", "s"))
+                .arg(("job", "t"))
+                .arg(("error", "s")))
+            .add_s(f.signal("StorageDegraded", ())
+                .arg(("message", "s")))
+            .add_s(f.signal("ServiceStarted", ()))
+
+            // Properties
+            .add_p(f.property::<bool,_>("StorageDegraded", ())
+                .access(tree::Access::Read)
+                .on_get(|iter, _| {
+                    iter.append(BootStatus::load().storage_degraded());
+                    Ok(())
+                }));
 
         let obpath = f.object_path(OBJECT_PATH, ())
             .introspectable()
@@ -91,6 +342,22 @@ impl DbusServer {
         Ok(vec![m.msg.method_return().append1(list)])
     }
 
+    // Each entry is (name, status, camera_devices, uptime_secs) where camera_devices
+    // is the number of video devices added to the realm when it was started and
+    // uptime_secs is how long a running realm has been running, or 0.
+    fn do_list_detailed(m: &MethodInfo) -> MethodResult {
+        let list = m.tree.get_data().realm_list_detailed();
+        Ok(vec![m.msg.method_return().append1(list)])
+    }
+
+    // Everything the realm switcher polls for in one call. The current realm
+    // is an empty string if no realm is current.
+    fn do_get_switcher_state(m: &MethodInfo) -> MethodResult {
+        let (current, realms) = m.tree.get_data().switcher_state()
+            .map_err(|e| MethodErr::failed(&format!("Failed to read realm state: {}", e)))?;
+        Ok(vec![m.msg.method_return().append2(current, realms)])
+    }
+
     fn do_set_current(m: &MethodInfo) -> MethodResult {
         let manager = m.tree.get_data().manager();
         let name = m.msg.read1()?;
@@ -116,62 +383,454 @@ impl DbusServer {
         let name = m.msg.read1()?;
         let data = m.tree.get_data().clone();
         let realm = data.realm_by_name(name)?;
-        thread::spawn(move || {
-            if let Err(e) = data.manager().start_realm(&realm) {
-                warn!("failed to start realm {}: {}", realm.name(), e);
+        data.check_start_rate(&realm)?;
+        data.manager().check_start_resources(&realm)
+            .map_err(|e| Self::start_error(name, &e))?;
+        let job = data.enqueue(&realm, |data, realm| data.manager().start_realm(realm))?;
+        Ok(vec![m.msg.method_return().append1(job)])
+    }
+
+    // A start refused because resources are low or the home directory is over
+    // its quota has a distinct error name so that clients can show a specific message.
+    fn start_error(name: &str, e: &failure::Error) -> MethodErr {
+        if e.downcast_ref::<LowResourcesError>().is_some() {
+            MethodErr::from((ERROR_LOW_RESOURCES, e.to_string()))
+        } else if e.downcast_ref::<QuotaExceededError>().is_some() {
+            MethodErr::from((ERROR_QUOTA_EXCEEDED, e.to_string()))
+        } else {
+            MethodErr::failed(&format!("Failed to start realm {}: {}", name, e))
+        }
+    }
+
+    fn do_freeze(m: &MethodInfo) -> MethodResult {
+        let (name, force) = m.msg.read2::<&str, bool>()?;
+        let data = m.tree.get_data();
+        let realm = data.active_realm_by_name(name)?;
+        let job = data.enqueue(&realm, move |data, realm| data.manager().freeze_realm(realm, force))?;
+        Ok(vec![m.msg.method_return().append1(job)])
+    }
+
+    fn do_thaw(m: &MethodInfo) -> MethodResult {
+        let name = m.msg.read1()?;
+        let data = m.tree.get_data();
+        let realm = data.active_realm_by_name(name)?;
+        let job = data.enqueue(&realm, |data, realm| data.manager().thaw_realm(realm))?;
+        Ok(vec![m.msg.method_return().append1(job)])
+    }
+
+    // The clone is queued behind other operations on the source realm and sends
+    // CloneProgress signals while the home directory is copied. Unless
+    // `require_stopped` is set the home directory of a running source realm is
+    // copied on a best-effort basis.
+    fn do_clone_realm(m: &MethodInfo) -> MethodResult {
+        let (source, name, clone_home, require_stopped) = m.msg.read4::<&str, &str, bool, bool>()?;
+        let data = m.tree.get_data();
+        let realm = data.realm_by_name(source)?;
+        if !Realm::is_valid_name(name) {
+            return Err(MethodErr::from((ERROR_INVALID_ARGS, format!("'{}' is not a valid realm name", name))));
+        }
+        if data.manager().realm_by_name(name).is_some() {
+            return Err(MethodErr::failed(&format!("A realm with name '{}' already exists", name)));
+        }
+        let name = name.to_string();
+        let job = data.enqueue(&realm, move |data, realm| {
+            data.manager().clone_realm_with_progress(realm, &name, clone_home, require_stopped, &mut |n| {
+                if n % SNAPSHOT_PROGRESS_INTERVAL == 0 {
+                    data.events.on_clone_progress(realm, &name, n);
+                }
+            })?;
+            Ok(())
+        })?;
+        Ok(vec![m.msg.method_return().append1(job)])
+    }
+
+    // The archive manifest is signed with the user signing key from the kernel
+    // keyring when `sign` is set
+    fn do_export_realm(m: &MethodInfo) -> MethodResult {
+        let (name, path, sign) = m.msg.read3::<&str, &str, bool>()?;
+        let data = m.tree.get_data();
+        data.check_root_caller(m.msg)?;
+        let path = Self::absolute_path_arg(path)?;
+        let realm = data.realm_by_name(name)?;
+        let keypair = if sign {
+            let keypair = KeyRing::get_kernel_keypair(RealmFS::USER_KEYNAME)
+                .map_err(|e| MethodErr::failed(&format!("Could not load signing key: {}", e)))?;
+            Some(keypair)
+        } else {
+            None
+        };
+        let path = path.to_path_buf();
+        let job = data.enqueue(&realm, move |data, realm| {
+            data.manager().export_realm(realm, &path, keypair.as_ref())?;
+            Ok(())
+        })?;
+        Ok(vec![m.msg.method_return().append1(job)])
+    }
+
+    // The archive manifest must be signed with `public_key`, or with the user
+    // signing key from the kernel keyring if `public_key` is empty. Unsigned
+    // archives are only imported if `allow_unsigned` is set. The name of the new
+    // realm is only known once the archive is unpacked, so imports have a queue
+    // of their own and the reply with the name is sent when the import is done.
+    fn do_import_realm(m: &MethodInfo) -> MethodResult {
+        let (path, public_key, allow_unsigned) = m.msg.read3::<&str, &str, bool>()?;
+        let data = m.tree.get_data().clone();
+        data.check_root_caller(m.msg)?;
+        let path = Self::absolute_path_arg(path)?;
+        let public_key = if public_key.is_empty() {
+            KeyRing::get_kernel_keypair(RealmFS::USER_KEYNAME)
+                .map(|keypair| keypair.public_key())
+                .map_err(|e| MethodErr::failed(&format!("Could not load signing key: {}", e)))?
+        } else {
+            PublicKey::from_hex(public_key)
+                .map_err(|e| MethodErr::from((ERROR_INVALID_ARGS, format!("Invalid public key: {}", e))))?
+        };
+        let reply = DeferredReply::new(&data.events.sender, m.msg, &format!("Failed to import realm from {}", path.display()));
+        let path = path.to_path_buf();
+        let queue = data.queue.clone();
+        queue.enqueue(IMPORT_QUEUE, move |_| {
+            match data.manager().import_realm(&path, &ManifestTrust::PublicKey(&public_key), allow_unsigned) {
+                Ok(realm) => reply.send(|msg| msg.append1(realm.name())),
+                Err(e) => reply.send_error(&e),
             }
+        }).map_err(TreeData::queue_error)?;
+        Ok(vec![])
+    }
+
+    fn absolute_path_arg(path: &str) -> result::Result<&Path, MethodErr> {
+        let path = Path::new(path);
+        if !path.is_absolute() {
+            return Err(MethodErr::from((ERROR_INVALID_ARGS, format!("Path {} is not absolute", path.display()))));
+        }
+        Ok(path)
+    }
+
+    // Snapshots are copied in a separate thread which sends SnapshotProgress
+    // signals while copying and a SnapshotFinished signal with the id of the
+    // new snapshot or an error message when done.
+    fn do_snapshot_home(m: &MethodInfo) -> MethodResult {
+        let (name, label) = m.msg.read2::<&str, &str>()?;
+        let data = m.tree.get_data().clone();
+        let realm = data.realm_by_name(name)?;
+        let label = label.to_string();
+        thread::spawn(move || {
+            let result = data.manager().snapshot_home_with_progress(&realm, &label, &mut |n| {
+                if n % SNAPSHOT_PROGRESS_INTERVAL == 0 {
+                    data.events.on_snapshot_progress(&realm, n);
+                }
+            });
+            data.events.on_snapshot_finished(&realm, result.map(|s| s.id().to_string()));
         });
         Ok(vec![m.msg.method_return()])
     }
 
+    // Each entry is (id, label, timestamp, size in bytes)
+    fn do_list_snapshots(m: &MethodInfo) -> MethodResult {
+        let name = m.msg.read1()?;
+        let data = m.tree.get_data();
+        let realm = data.realm_by_name(name)?;
+        let list = data.manager().list_snapshots(&realm)
+            .map_err(|e| MethodErr::failed(&e))?
+            .iter()
+            .map(|s| (s.id().to_string(), s.label().to_string(), s.timestamp(), s.size()))
+            .collect::<Vec<_>>();
+        Ok(vec![m.msg.method_return().append1(list)])
+    }
+
+    // The home directory is replaced by a queued operation which sends the same
+    // signals as SnapshotHome and the id in the SnapshotFinished signal is the id
+    // of the snapshot holding the previous home directory.
+    fn do_restore_snapshot(m: &MethodInfo) -> MethodResult {
+        let (name, id) = m.msg.read2::<&str, &str>()?;
+        let data = m.tree.get_data();
+        let realm = data.realm_by_name(name)?;
+        if realm.is_active() {
+            return Err(MethodErr::failed(&format!("Cannot restore snapshot while realm {} is running", name)));
+        }
+        let snapshots = data.manager().list_snapshots(&realm)
+            .map_err(|e| MethodErr::failed(&e))?;
+        if !snapshots.iter().any(|s| s.id() == id) {
+            return Err(MethodErr::failed(&format!("Unknown snapshot '{}' for realm {}", id, name)));
+        }
+        let id = id.to_string();
+        let job = data.enqueue(&realm, move |data, realm| {
+            let result = data.manager().restore_snapshot_with_progress(realm, &id, &mut |n| {
+                if n % SNAPSHOT_PROGRESS_INTERVAL == 0 {
+                    data.events.on_snapshot_progress(realm, n);
+                }
+            });
+            let result = result.map(|s| s.id().to_string());
+            let finished = result.as_ref().map(|_| ()).map_err(|e| format_err!("{}", e));
+            data.events.on_snapshot_finished(realm, result);
+            finished
+        })?;
+        Ok(vec![m.msg.method_return().append1(job)])
+    }
+
+    fn do_set_resource_limits(m: &MethodInfo) -> MethodResult {
+        let (name, limits) = m.msg.read2::<&str, HashMap<String, String>>()?;
+        let data = m.tree.get_data();
+        let realm = data.active_realm_by_name(name)?;
+        let limits = limits.into_iter().collect::<Vec<_>>();
+        let job = data.enqueue(&realm, move |data, realm| {
+            data.manager().apply_resource_limits(realm, &limits)
+                .map_err(|e| format_err!("Failed to set resource limits of realm {}: {}", realm.name(), e))
+        })?;
+        Ok(vec![m.msg.method_return().append1(job)])
+    }
+
+    // Change the clipboard policy of a running realm until it stops
+    fn do_set_clipboard_policy(m: &MethodInfo) -> MethodResult {
+        let (name, policy) = m.msg.read2::<&str, &str>()?;
+        let data = m.tree.get_data();
+        let realm = data.active_realm_by_name(name)?;
+        let policy = ClipboardPolicy::from_str_value(policy)
+            .ok_or_else(|| MethodErr::from((ERROR_INVALID_ARGS, format!("Invalid clipboard policy '{}'. Valid values are: isolated, shared, outbound-only", policy))))?;
+        let job = data.enqueue(&realm, move |data, realm| {
+            data.events.clipboard.set_policy(realm.name(), policy)
+                .map_err(|e| format_err!("Failed to set clipboard policy of realm {}: {}", realm.name(), e))
+        })?;
+        Ok(vec![m.msg.method_return().append1(job)])
+    }
+
+    fn do_reset_failed(m: &MethodInfo) -> MethodResult {
+        let name = m.msg.read1()?;
+        let data = m.tree.get_data();
+        let realm = data.realm_by_name(name)?;
+        data.manager().reset_failed_realm(&realm)
+            .map_err(|e| MethodErr::failed(&format!("Failed to reset realm {}: {}", name, e)))?;
+        Ok(vec![m.msg.method_return()])
+    }
+
     fn do_stop(m: &MethodInfo) -> MethodResult {
         let name = m.msg.read1()?;
         let data = m.tree.get_data().clone();
         let realm = data.realm_by_name(name)?;
-        thread::spawn(move || {
-            if let Err(e) = data.manager().stop_realm(&realm) {
-                warn!("failed to stop realm {}: {}", realm.name(), e);
-            }
-        });
-        Ok(vec![m.msg.method_return()])
+        let job = data.enqueue(&realm, |data, realm| data.manager().stop_realm(realm))?;
+        Ok(vec![m.msg.method_return().append1(job)])
     }
 
     fn do_terminal(m: &MethodInfo) -> MethodResult {
-        let name = m.msg.read1()?;
+        let mut iter = m.msg.iter_init();
+        let name: &str = iter.read()?;
+        let user = Self::read_run_user(&mut iter)?;
         let data = m.tree.get_data().clone();
         let realm = data.realm_by_name(name)?;
-        thread::spawn(move || {
-            if !realm.is_active() {
-                if let Err(err) = data.manager().start_realm(&realm) {
-                    warn!("failed to start realm {}: {}", realm.name(), err);
-                    return;
+
+        // Realm is started and terminal chosen before replying so that
+        // failure can be reported to the caller. The reply is sent from the
+        // queued operation rather than waiting for it here, and the request is
+        // refused with Error.Busy unless the queue is empty so that the reply
+        // does not depend on other operations queued for the realm. The terminal
+        // is launched after the reply without holding up the queue of the realm.
+        let reply = DeferredReply::new(&data.events.sender, m.msg, &format!("Failed to open terminal in realm {}", name));
+        data.enqueue_if_idle(&realm, move |data, realm| {
+            let started = if realm.is_active() {
+                Ok(())
+            } else {
+                data.manager().start_realm(realm)
+            };
+            let chosen = started.and_then(|()| data.manager().choose_terminal(realm));
+            let command = match chosen {
+                Ok(command) => command,
+                Err(e) => {
+                    reply.send_error(&e);
+                    return Err(e);
                 }
-            }
-            if let Err(err) = data.manager().launch_terminal(&realm) {
-                warn!("error launching terminal for realm {}: {}", realm.name(), err);
-            }
-        });
-        Ok(vec![m.msg.method_return()])
+            };
+            reply.send(|msg| msg);
+            let data = data.clone();
+            let realm = realm.clone();
+            thread::spawn(move || {
+                if let Err(err) = data.manager().launch_terminal_command(&realm, &command, &user) {
+                    warn!("error launching terminal for realm {}: {}", realm.name(), err);
+                }
+            });
+            Ok(())
+        })?;
+        Ok(vec![])
     }
 
     fn do_run(m: &MethodInfo) -> MethodResult {
-        let (name,args) = m.msg.read2::<&str, Vec<String>>()?;
+        let mut iter = m.msg.iter_init();
+        let name: &str = iter.read()?;
+        let args: Vec<String> = iter.read()?;
+        let user = Self::read_run_user(&mut iter)?;
         let data = m.tree.get_data().clone();
         let realm = data.realm_by_name(name)?;
-        thread::spawn(move || {
+        // Only starting the realm is queued. The command runs outside of the queue
+        // so that a long running command does not hold up later operations on the realm.
+        let job = data.enqueue(&realm, move |data, realm| {
             if !realm.is_active() {
-                if let Err(err) = data.manager().start_realm(&realm) {
-                    warn!("failed to start realm {}: {}", realm.name(), err);
-                    return;
-                }
-            }
-            if let Err(err) = data.manager().run_in_realm(&realm, &args, true) {
-                warn!("error running {:?} in realm {}: {}", args, realm.name(), err);
+                data.manager().start_realm(realm)?;
             }
-        });
+            let data = data.clone();
+            let realm = realm.clone();
+            thread::spawn(move || {
+                match data.manager().run_in_realm(&realm, &args, &user, true) {
+                    Ok(status) if !status.success() => warn!("running {:?} in realm {} failed: {}", args, realm.name(), status),
+                    Ok(_) => {},
+                    Err(err) => warn!("error running {:?} in realm {}: {}", args, realm.name(), err),
+                }
+            });
+            Ok(())
+        })?;
+        Ok(vec![m.msg.method_return().append1(job)])
+    }
+
+    // Run and Terminal accept an optional dict of options after their other
+    // arguments so that older callers which do not pass it still work. The
+    // "user" option names the account to run as.
+    fn read_run_user(iter: &mut dbus::arg::Iter) -> result::Result<String, MethodErr> {
+        let options = iter.get::<HashMap<String, String>>().unwrap_or_default();
+        let user = options.get("user").map(|s| s.as_str()).unwrap_or(DEFAULT_RUN_USER);
+        if user.is_empty() || user.contains(char::is_whitespace) {
+            return Err(MethodErr::from((ERROR_INVALID_ARGS, format!("Invalid user name '{}'", user))));
+        }
+        RealmManager::check_username(user)
+            .map_err(|e| MethodErr::from((ERROR_INVALID_ARGS, e.to_string())))?;
+        Ok(user.to_string())
+    }
+
+    fn do_copy_into_realm(m: &MethodInfo) -> MethodResult {
+        let (name, host_src, realm_dst) = m.msg.read3::<&str, &str, &str>()?;
+        let data = m.tree.get_data();
+        let realm = data.active_realm_by_name(name)?;
+        let host_src = RealmManager::check_host_copy_path(Path::new(host_src))
+            .map_err(|e| MethodErr::failed(&e))?;
+        if !host_src.exists() {
+            return Err(MethodErr::failed(&format!("Source path {} does not exist", host_src.display())));
+        }
+        Self::check_realm_path(realm_dst)?;
+        data.manager().copy_to_realm(&realm, &host_src, realm_dst)
+            .map_err(|e| MethodErr::failed(&format!("Failed to copy {} into realm {}: {}", host_src.display(), name, e)))?;
+        Ok(vec![m.msg.method_return()])
+    }
+
+    fn do_copy_from_realm(m: &MethodInfo) -> MethodResult {
+        let (name, realm_src, host_dst) = m.msg.read3::<&str, &str, &str>()?;
+        let data = m.tree.get_data();
+        let realm = data.active_realm_by_name(name)?;
+        Self::check_realm_path(realm_src)?;
+        data.manager().copy_from_realm(&realm, realm_src, host_dst)
+            .map_err(|e| MethodErr::failed(&format!("Failed to copy {} from realm {}: {}", realm_src, name, e)))?;
+        Ok(vec![m.msg.method_return()])
+    }
+
+    fn do_list_port_forwards(m: &MethodInfo) -> MethodResult {
+        let name = m.msg.read1::<&str>()?;
+        let realm = m.tree.get_data().realm_by_name(name)?;
+        let forwards = realm.config().port_forwards().iter()
+            .map(|f| (f.protocol().to_str_value().to_string(), f.host_port(), f.realm_port()))
+            .collect::<Vec<_>>();
+        Ok(vec![m.msg.method_return().append1(forwards)])
+    }
+
+    // The address is empty if the realm would not be allocated one
+    fn do_preview_launch_config(m: &MethodInfo) -> MethodResult {
+        let name = m.msg.read1()?;
+        let data = m.tree.get_data();
+        let realm = data.realm_by_name(name)?;
+        let plan = data.manager().preview_launch_config(&realm)
+            .map_err(|e| MethodErr::failed(&format!("Failed to generate launch config for realm {}: {}", name, e)))?;
+        let address = plan.allocated_ip().unwrap_or("");
+        let bindings = plan.report().summary().into_iter().collect::<HashMap<_,_>>();
+        Ok(vec![m.msg.method_return().append3(plan.nspawn_contents(), plan.service_contents(), address).append1(bindings)])
+    }
+
+    // Each binding maps to "applied: <paths>" or "missing: <reason>". The map is
+    // empty if the realm has not been started since boot.
+    fn do_get_launch_report(m: &MethodInfo) -> MethodResult {
+        let name = m.msg.read1()?;
+        let data = m.tree.get_data();
+        let realm = data.realm_by_name(name)?;
+        let report = data.manager().launch_report(&realm)
+            .map_err(|e| MethodErr::failed(&format!("Failed to read launch report of realm {}: {}", name, e)))?;
+        let bindings = report.map(|r| r.summary()).unwrap_or_default()
+            .into_iter()
+            .collect::<HashMap<_,_>>();
+        Ok(vec![m.msg.method_return().append1(bindings)])
+    }
+
+    // Space used by the home directory of a realm and its quota in bytes. The
+    // quota is 0 and enforcement is "none" if no quota is configured.
+    fn do_get_realm_disk_usage(m: &MethodInfo) -> MethodResult {
+        let name = m.msg.read1()?;
+        let data = m.tree.get_data();
+        let realm = data.realm_by_name(name)?;
+        let quota = HomeQuota::new(&realm);
+        let (usage, limit, enforcement) = quota.limit()
+            .and_then(|limit| {
+                let enforcement = match limit {
+                    Some(_) => quota.mode()?.to_str_value(),
+                    None => "none",
+                };
+                Ok((quota.usage()?, limit.unwrap_or(0), enforcement))
+            })
+            .map_err(|e| MethodErr::failed(&format!("Failed to read disk usage of realm {}: {}", name, e)))?;
+        Ok(vec![m.msg.method_return().append3(usage, limit, enforcement)])
+    }
+
+    // Shut down realmsd. Called by a new instance of realmsd started with --replace.
+    fn do_quit(m: &MethodInfo) -> MethodResult {
+        let data = m.tree.get_data();
+        data.check_root_caller(m.msg)?;
+        info!("Shutting down at the request of another realmsd instance");
+        data.quit.store(true, Ordering::SeqCst);
         Ok(vec![m.msg.method_return()])
     }
 
+    // Hand over to a new realmsd process executed from the installed binary,
+    // for example after realmsd has been upgraded. Same as sending SIGUSR2.
+    fn do_restart(m: &MethodInfo) -> MethodResult {
+        let data = m.tree.get_data();
+        data.check_root_caller(m.msg)?;
+        info!("Restarting at the request of a management client");
+        data.restart.store(true, Ordering::SeqCst);
+        Ok(vec![m.msg.method_return()])
+    }
+
+    // Metrics in the Prometheus text exposition format
+    fn do_get_metrics(m: &MethodInfo) -> MethodResult {
+        Ok(vec![m.msg.method_return().append1(Metrics::render())])
+    }
+
+    // Recent status changes of a realm, or of all realms if the name is empty
+    fn do_get_event_history(m: &MethodInfo) -> MethodResult {
+        let (name, limit) = m.msg.read2::<&str, u32>()?;
+        let events = m.tree.get_data().events.history.query(name, limit as usize);
+        Ok(vec![m.msg.method_return().append1(events)])
+    }
+
+    // Values of the settings from /etc/citadel/realmsd.conf currently in effect
+    fn do_get_daemon_config(m: &MethodInfo) -> MethodResult {
+        let values = m.tree.get_data().config.read().unwrap().values();
+        Ok(vec![m.msg.method_return().append1(values)])
+    }
+
+    fn do_get_network_zones(m: &MethodInfo) -> MethodResult {
+        let zones = m.tree.get_data().manager().network_zones();
+        Ok(vec![m.msg.method_return().append1(zones)])
+    }
+
+    // One of "open", "vpn-only" or "blocked"
+    fn do_get_network_block_state(m: &MethodInfo) -> MethodResult {
+        let name = m.msg.read1()?;
+        let data = m.tree.get_data();
+        let realm = data.realm_by_name(name)?;
+        let state = data.manager().network_block_state(&realm)
+            .map_err(|e| MethodErr::failed(&e))?;
+        Ok(vec![m.msg.method_return().append1(state.to_str_value())])
+    }
+
+    fn check_realm_path(path: &str) -> result::Result<(), MethodErr> {
+        let path = Path::new(path);
+        if !path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+            return Err(MethodErr::failed(&format!("Realm path {} must be an absolute path without '..' components", path.display())));
+        }
+        Ok(())
+    }
+
     fn do_pid_to_realm(m: &MethodInfo) -> MethodResult {
         let pid = m.msg.read1::<u32>()?;
         let manager = m.tree.get_data().manager();
@@ -183,52 +842,245 @@ impl DbusServer {
         Ok(vec![msg])
     }
 
+    // Called by the compositor when a window receives focus. The realm of the
+    // window becomes current if it has follow-focus enabled, which emits RealmCurrent.
+    fn do_report_focused_pid(m: &MethodInfo) -> MethodResult {
+        let pid = m.msg.read1::<u32>()?;
+        let data = m.tree.get_data();
+        let realm = match data.manager().realm_by_pid(pid) {
+            Some(realm) if realm.config().follow_focus() && realm.is_active() => realm,
+            _ => return Ok(vec![m.msg.method_return()]),
+        };
+        let current = data.manager().current_realm();
+        let current = current.as_ref().map(|r| r.name());
+        if data.focus_limiter.lock().unwrap().allow(realm.name(), current, Instant::now()) {
+            if let Err(e) = data.manager().set_current_realm(&realm) {
+                warn!("Failed to make focused realm {} current: {}", realm.name(), e);
+            }
+        }
+        Ok(vec![m.msg.method_return()])
+    }
 
-    pub fn start(&self) -> Result<()> {
-        let tree = self.build_tree();
-        self.connection.register_name(BUS_NAME, NameFlag::ReplaceExisting as u32)?;
-        tree.set_registered(&self.connection, true)?;
-        self.connection.add_handler(tree);
-
-        self.receive_signals_from(VPN_CONNECTION_INTERFACE)?;
-        self.receive_signals_from(OBJECT_MANAGER_INTERFACE)?;
+    /// Run the message loop. If `restored` is the state saved by the process
+    /// which executed this one it is applied before any realm events are handled.
+    pub fn start(&self, restored: Option<HandoffState>) -> Result<()> {
+        self.register(&self.connection())?;
 
+        // Read after the bus name is acquired so that a replaced instance has
+        // saved its history
+        match restored {
+            Some(state) => state.restore(&self.manager, &self.events.history),
+            None => self.events.history.load_saved(),
+        }
         self.manager.add_event_handler({
             let events = self.events.clone();
             move |ev| events.handle_event(ev)
         });
 
+        self.manager.add_event_handler({
+            let manager = self.manager.clone();
+            let writer = RealmInfoWriter::new();
+            writer.write_running(&manager);
+            move |ev| writer.handle_event(&manager, ev)
+        });
+
         if let Err(e) = self.manager.start_event_task() {
             warn!("error starting realm manager event task: {}", e);
         }
 
+        self.events.clipboard.start(&self.manager);
+
+        UsbMonitor::new(self.manager.clone()).start({
+            let events = self.events.clone();
+            move |realm, dev| events.on_usb_device_matched(realm, dev)
+        });
+
+        MediaMonitor::new(self.manager.clone()).start({
+            let events = self.events.clone();
+            move |realm, label, path| events.on_removable_device_attached(realm, label, path)
+        });
+
+        let vpn_changed = VpnMonitor::new(self.manager.clone()).start({
+            let events = self.events.clone();
+            move |realm, state| events.on_network_block_changed(realm, state)
+        });
+
         self.send_service_started();
+        self.send_storage_degraded();
+
+        // Exit the message loop on SIGTERM or SIGINT so that the event tasks are stopped cleanly
+        let quit = self.quit.clone();
+        signal_hook::flag::register(signal_hook::SIGTERM, quit.clone())?;
+        signal_hook::flag::register(signal_hook::SIGINT, quit.clone())?;
+        let reload = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(signal_hook::SIGHUP, reload.clone())?;
+        signal_hook::flag::register(signal_hook::SIGUSR2, self.restart.clone())?;
 
-        loop {
-            if let Some(msg) = self.connection.incoming(1000).next() {
-                self.process_message(msg)?;
+        while !quit.load(Ordering::SeqCst) && !self.restart.load(Ordering::SeqCst) {
+            if reload.swap(false, Ordering::SeqCst) {
+                self.reload_config();
+                self.load_new_network_zones();
+            }
+            let connection = self.connection();
+            let msg = connection.incoming(1000).next();
+            match msg {
+                Some(ref msg) if Self::is_disconnected(msg) => self.reconnect()?,
+                Some(msg) => self.process_message(msg, &vpn_changed)?,
+                None => {},
             }
         }
+        if self.restart.load(Ordering::SeqCst) {
+            return self.hand_off();
+        }
+        info!("Shutting down");
+        self.manager.stop_event_task();
+        self.events.history.save();
+        if self.config.read().unwrap().remove_firewall_on_exit() {
+            if let Err(e) = Firewall::remove() {
+                warn!("Error removing firewall: {}", e);
+            }
+        }
+        Ok(())
     }
 
-    fn process_message(&self, _msg: Message) -> Result<()> {
+    // The firewall is left installed and the event history is passed to the new
+    // process in the state file. If the state cannot be saved the new process
+    // starts without it.
+    fn hand_off(&self) -> Result<()> {
+        info!("Restarting");
+        self.manager.stop_event_task();
+        if let Err(e) = HandoffState::capture(&self.manager, &self.events.history).save() {
+            warn!("Failed to save state for the new realmsd process: {}", e);
+        }
+        handoff::exec_restart()
+    }
+
+    // An invalid configuration file is ignored and the current settings are kept
+    fn reload_config(&self) {
+        info!("Reloading {}", DAEMON_CONFIG_PATH);
+        let reloaded = match DaemonConfig::load() {
+            Ok(config) => config,
+            Err(e) => {
+                warn!("Not reloading configuration: {}", e);
+                return;
+            }
+        };
+        let mut config = self.config.write().unwrap();
+        let (reloaded, restart_required) = config.reload(reloaded);
+        for setting in restart_required {
+            warn!("Change to {} in {} takes effect when realmsd is restarted", setting, DAEMON_CONFIG_PATH);
+        }
+        reloaded.apply_log_level();
+        self.manager.set_start_thresholds(reloaded.start_thresholds());
+        *config = reloaded;
+    }
+
+    // Zones added to /etc/citadel/network-zones.conf are picked up on SIGHUP
+    fn load_new_network_zones(&self) {
+        match self.manager.load_new_network_zones() {
+            Ok(ref added) if !added.is_empty() => info!("Added network zones: {}", added.join(", ")),
+            Ok(_) => {},
+            Err(e) => warn!("Error loading new network zones: {}", e),
+        }
+    }
+
+    fn process_message(&self, msg: Message, vpn_changed: &Sender<()>) -> Result<()> {
         // add handlers for expected signals here
+        if msg.interface().as_deref() == Some(VPN_CONNECTION_INTERFACE) {
+            // Bring the kill switch rules in step with the VPN interfaces
+            let _ = vpn_changed.send(());
+        }
+        Ok(())
+    }
+
+    // Export the object tree and subscribe to the signals realmsd handles on `connection`
+    fn register(&self, connection: &Connection) -> Result<()> {
+        let tree = self.build_tree();
+        tree.set_registered(connection, true)?;
+        connection.add_handler(tree);
+        Self::receive_signals_from(connection, VPN_CONNECTION_INTERFACE)?;
+        Self::receive_signals_from(connection, OBJECT_MANAGER_INTERFACE)?;
         Ok(())
     }
 
-    fn receive_signals_from(&self, interface: &str) -> Result<()> {
+    fn receive_signals_from(connection: &Connection, interface: &str) -> Result<()> {
         let rule = format!("type=signal,interface={}", interface);
-        self.connection.add_match(rule.as_str())?;
+        connection.add_match(rule.as_str())?;
+        Ok(())
+    }
+
+    fn is_disconnected(msg: &Message) -> bool {
+        msg.interface().as_deref() == Some(DBUS_LOCAL_INTERFACE) && msg.member().as_deref() == Some("Disconnected")
+    }
+
+    // The connection is lost when the system bus restarts, for example when
+    // dbus-daemon is upgraded. Connect again, waiting longer after each failed
+    // attempt, and swap the new connection into the event handler. Signals for
+    // realm events which happen in the meantime are queued and sent once the
+    // connection is replaced. Returns an error if another instance of realmsd
+    // acquired the bus name while disconnected.
+    fn reconnect(&self) -> Result<()> {
+        warn!("Lost connection to the system bus, reconnecting");
+        self.events.sender.disconnected();
+        let mut delay = RECONNECT_MIN_DELAY;
+        while !self.quit.load(Ordering::SeqCst) {
+            thread::sleep(delay);
+            match self.try_reconnect() {
+                Ok(()) => {
+                    info!("Reconnected to the system bus");
+                    self.send_service_started();
+                    return Ok(());
+                },
+                Err(e) if e.downcast_ref::<AlreadyRunning>().is_some() => return Err(e),
+                Err(e) => warn!("Failed to reconnect to the system bus: {}", e),
+            }
+            delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+        }
+        Ok(())
+    }
+
+    fn try_reconnect(&self) -> Result<()> {
+        let connection = Arc::new(Connection::get_private(dbus::BusType::System)?);
+        if Self::request_name_on(&connection)? != NameRequest::Acquired {
+            return Err(AlreadyRunning::new(bus_credential(&connection, "GetConnectionUnixProcessID", BUS_NAME).ok()).into());
+        }
+        self.register(&connection)?;
+        if let Err(e) = self.events.sender.reconnected(connection) {
+            warn!("Failed to send queued signals: {}", e);
+        }
         Ok(())
     }
 
+    fn request_name_on(connection: &Connection) -> Result<NameRequest> {
+        let reply = connection.register_name(BUS_NAME, NameFlag::DoNotQueue as u32)?;
+        match reply {
+            RequestNameReply::PrimaryOwner | RequestNameReply::AlreadyOwner => Ok(NameRequest::Acquired),
+            RequestNameReply::InQueue | RequestNameReply::Exists => Ok(NameRequest::Owned),
+        }
+    }
+
     fn send_service_started(&self) {
         let signal = Self::create_signal("ServiceStarted");
-        if self.connection.send(signal).is_err() {
+        if self.events.sender.send(signal).is_err() {
             warn!("Failed to send ServiceStarted signal");
         }
     }
 
+    // Storage is checked and mounted during boot before realmsd starts, so
+    // the signal is sent once at startup if the boot status is degraded.
+    fn send_storage_degraded(&self) {
+        let status = BootStatus::load();
+        if !status.storage_degraded() {
+            return;
+        }
+        let message = status.storage_message().unwrap_or("Storage partition is mounted read-only");
+        warn!("{}", message);
+        let signal = Self::create_signal("StorageDegraded").append1(message);
+        if self.events.sender.send(signal).is_err() {
+            warn!("Failed to send StorageDegraded signal");
+        }
+    }
+
     fn create_signal(name: &str) -> Message {
         let path = dbus::Path::new(OBJECT_PATH).unwrap();
         let iface = dbus::Interface::new(INTERFACE_NAME).unwrap();
@@ -238,24 +1090,181 @@ impl DbusServer {
 
 }
 
-/// Wraps a connection instance and only expose the send() method.
-/// Sending a message does not read or write any of the internal
-/// Connection object state other than the native handle for the
-/// connection. It should be safe to share this across threads as
-/// internally libdbus uses a mutex to control concurrent access
+impl BusName for DbusServer {
+    fn request_name(&self) -> Result<NameRequest> {
+        Self::request_name_on(&self.connection())
+    }
+
+    fn owner_pid(&self) -> Option<u32> {
+        bus_credential(&self.connection(), "GetConnectionUnixProcessID", BUS_NAME)
+            .map_err(|e| warn!("Could not find process id of {} owner: {}", BUS_NAME, e))
+            .ok()
+    }
+
+    fn quit_owner(&self) -> Result<()> {
+        let msg = Message::new_method_call(BUS_NAME, OBJECT_PATH, INTERFACE_NAME, "Quit")
+            .map_err(|e| format_err!("{}", e))?;
+        self.connection().send_with_reply_and_block(msg, BUS_CALL_TIMEOUT)?;
+        Ok(())
+    }
+}
+
+// Ask the bus daemon for the uid or pid of the connection which owns `name`
+// by calling `method`, which is GetConnectionUnixUser or GetConnectionUnixProcessID.
+fn bus_credential(connection: &Connection, method: &str, name: &str) -> Result<u32> {
+    let msg = Message::new_method_call(DBUS_BUS_NAME, DBUS_OBJECT_PATH, DBUS_BUS_NAME, method)
+        .map_err(|e| format_err!("{}", e))?
+        .append1(name);
+    let reply = connection.send_with_reply_and_block(msg, BUS_CALL_TIMEOUT)?;
+    Ok(reply.read1::<u32>()?)
+}
+
+/// Wraps the current connection instance and only exposes sending signals
+/// and identifying the caller of a method. Sending a message does not read
+/// or write any of the internal Connection object state other than the
+/// native handle for the connection. It should be safe to share this across
+/// threads as internally libdbus uses a mutex to control concurrent access
 /// to the dbus_connection_send() function.
 #[derive(Clone)]
-struct ConnectionSender(Arc<Connection>);
+struct ConnectionSender {
+    // Replaced when realmsd reconnects to the bus
+    connection: Arc<RwLock<Arc<Connection>>>,
+    signals: Arc<SignalSender<BusSignal>>,
+}
 
 unsafe impl Send for ConnectionSender {}
 unsafe impl Sync for ConnectionSender {}
 
 impl ConnectionSender {
     fn new(connection: Arc<Connection>) -> Self {
-        ConnectionSender(connection)
+        ConnectionSender {
+            signals: Arc::new(SignalSender::new(Box::new(BusSink(connection.clone())), PENDING_SIGNALS_SIZE)),
+            connection: Arc::new(RwLock::new(connection)),
+        }
+    }
+
+    fn connection(&self) -> Arc<Connection> {
+        self.connection.read().unwrap().clone()
     }
 
     fn send(&self, msg: Message) -> Result<()> {
+        self.signals.send(BusSignal::from_message(&msg)?)
+    }
+
+    // Queue signals until a new connection is swapped in with `reconnected()`
+    fn disconnected(&self) {
+        self.signals.disconnected();
+    }
+
+    fn reconnected(&self, connection: Arc<Connection>) -> Result<()> {
+        *self.connection.write().unwrap() = connection.clone();
+        self.signals.reconnected(Box::new(BusSink(connection)))
+    }
+
+    fn caller_uid(&self, msg: &Message) -> Result<u32> {
+        let sender = msg.sender().ok_or_else(|| format_err!("message has no sender"))?;
+        bus_credential(&self.connection(), "GetConnectionUnixUser", &sender)
+    }
+}
+
+///
+/// Replies to a method call from a queued operation after the handler has
+/// returned. A reply can only be created from the method call message, which
+/// is not available once the handler returns, so each possible reply is built
+/// up front and the one matching the outcome of the operation is sent.
+///
+struct DeferredReply {
+    sender: ConnectionSender,
+    ok: Message,
+    failed: Message,
+    low_resources: Message,
+    quota_exceeded: Message,
+}
+
+unsafe impl Send for DeferredReply {}
+
+impl DeferredReply {
+    // `failure` is the text of the error reply sent if the operation fails
+    fn new(sender: &ConnectionSender, msg: &Message, failure: &str) -> Self {
+        let error = |name: &'static str| MethodErr::from((name, failure)).to_message(msg);
+        DeferredReply {
+            sender: sender.clone(),
+            ok: msg.method_return(),
+            failed: MethodErr::failed(&failure).to_message(msg),
+            low_resources: error(ERROR_LOW_RESOURCES),
+            quota_exceeded: error(ERROR_QUOTA_EXCEEDED),
+        }
+    }
+
+    /// Send the method return after `f` has appended any return values to it.
+    fn send<F>(self, f: F)
+        where F: FnOnce(Message) -> Message
+    {
+        let reply = f(self.ok);
+        Self::send_reply(&self.sender, reply);
+    }
+
+    /// Send the error reply matching `e`, which is logged as the reply only
+    /// carries the fixed text given to `new()`.
+    fn send_error(self, e: &failure::Error) {
+        warn!("{}", e);
+        let reply = if e.downcast_ref::<LowResourcesError>().is_some() {
+            self.low_resources
+        } else if e.downcast_ref::<QuotaExceededError>().is_some() {
+            self.quota_exceeded
+        } else {
+            self.failed
+        };
+        Self::send_reply(&self.sender, reply);
+    }
+
+    fn send_reply(sender: &ConnectionSender, reply: Message) {
+        if sender.connection().send(reply).is_err() {
+            warn!("failed to send deferred method reply");
+        }
+    }
+}
+
+/// The fields of a signal which is waiting to be sent. A `Message` is consumed
+/// when it is sent, so queued signals are rebuilt from these fields each time
+/// sending is attempted and stay queued if sending fails.
+struct BusSignal {
+    path: String,
+    interface: String,
+    member: String,
+    items: Vec<MessageItem>,
+}
+
+impl BusSignal {
+    fn from_message(msg: &Message) -> Result<Self> {
+        match msg.headers() {
+            (_, Some(path), Some(interface), Some(member)) => {
+                let items = msg.get_items();
+                Ok(BusSignal { path, interface, member, items })
+            }
+            _ => bail!("signal message is missing path, interface or member"),
+        }
+    }
+
+    fn to_message(&self) -> Result<Message> {
+        let path = dbus::Path::new(self.path.as_str()).map_err(failure::err_msg)?;
+        let interface = dbus::Interface::new(self.interface.as_str()).map_err(failure::err_msg)?;
+        let member = dbus::Member::new(self.member.as_str()).map_err(failure::err_msg)?;
+        let mut msg = Message::signal(&path, &interface, &member);
+        msg.append_items(&self.items);
+        Ok(msg)
+    }
+}
+
+/// Sends signals on one bus connection.
+struct BusSink(Arc<Connection>);
+
+unsafe impl Send for BusSink {}
+unsafe impl Sync for BusSink {}
+
+impl SignalSink<BusSignal> for BusSink {
+    fn send(&self, signal: &BusSignal) -> Result<()> {
+        let msg = signal.to_message()?;
         self.0.send(msg)
             .map_err(|()| failure::err_msg("failed to send message"))?;
         Ok(())
@@ -265,31 +1274,80 @@ impl ConnectionSender {
 #[derive(Clone)]
 struct EventHandler {
     sender: ConnectionSender,
+    manager: Arc<RealmManager>,
+    history: EventHistory,
+    clipboard: ClipboardBroker,
 }
 
 impl EventHandler {
-    fn new(conn: Arc<Connection>) -> EventHandler {
+    fn new(conn: Arc<Connection>, manager: Arc<RealmManager>) -> EventHandler {
         EventHandler {
             sender: ConnectionSender::new(conn),
+            manager,
+            history: EventHistory::new(EVENT_HISTORY_SIZE),
+            clipboard: ClipboardBroker::new(),
         }
     }
 
     fn handle_event(&self, ev: &RealmEvent) {
+       self.record_event(ev);
        match ev {
            RealmEvent::Started(realm) => self.on_started(realm),
            RealmEvent::Stopped(realm) => self.on_stopped(realm),
            RealmEvent::New(realm) => self.on_new(realm),
            RealmEvent::Removed(realm) => self.on_removed(realm),
            RealmEvent::Current(realm) => self.on_current(realm.as_ref()),
+           RealmEvent::Failed(realm, reason) => self.on_failed(realm, reason),
+           RealmEvent::Frozen(realm) => self.send_realm_signal("RealmFrozen", Some(realm)),
+           RealmEvent::Thawed(realm) => self.send_realm_signal("RealmThawed", Some(realm)),
+           RealmEvent::ConfigChanged(realm) => self.send_realm_signal("RealmConfigChanged", Some(realm)),
        }
     }
 
+    fn record_event(&self, ev: &RealmEvent) {
+        let (realm, event) = match ev {
+            RealmEvent::Started(realm) => (realm, "started"),
+            RealmEvent::Stopped(realm) => (realm, "stopped"),
+            RealmEvent::New(realm) => (realm, "new"),
+            RealmEvent::Removed(realm) => (realm, "removed"),
+            RealmEvent::Current(Some(realm)) => (realm, "current"),
+            RealmEvent::Frozen(realm) => (realm, "frozen"),
+            RealmEvent::Thawed(realm) => (realm, "thawed"),
+            RealmEvent::ConfigChanged(realm) => (realm, "config-changed"),
+            // Failures are recorded by on_failed() which is also called when
+            // a realm hits the start limit
+            RealmEvent::Current(None) | RealmEvent::Failed(..) => return,
+        };
+        self.history.record(realm.name(), event, "");
+    }
+
     fn on_started(&self, realm: &Realm) {
+        self.clipboard.realm_started(realm);
         self.send_realm_signal("RealmStarted", Some(realm));
     }
 
     fn on_stopped(&self, realm: &Realm) {
+        self.clipboard.realm_stopped(realm.name());
         self.send_realm_signal("RealmStopped", Some(realm));
+        if realm.config().restart_max_per_hour().is_some() {
+            self.check_start_limit(realm.clone());
+        }
+    }
+
+    // systemd only decides not to restart a realm once the restart delay has
+    // passed, so wait before checking whether the start limit was hit.
+    fn check_start_limit(&self, realm: Realm) {
+        let handler = self.clone();
+        thread::spawn(move || {
+            thread::sleep(START_LIMIT_CHECK_DELAY);
+            if !realm.is_active() && handler.manager.is_start_limit_hit(&realm) {
+                let reason = format!("Realm {} was restarted too many times and will not be restarted again until reset", realm.name());
+                if let Err(e) = handler.manager.free_network_allocation(&realm) {
+                    warn!("Failed to free network address of realm {}: {}", realm.name(), e);
+                }
+                handler.on_failed(&realm, &reason);
+            }
+        });
     }
 
     fn on_new(&self, realm: &Realm) {
@@ -304,6 +1362,87 @@ impl EventHandler {
         self.send_realm_signal("RealmCurrent", realm);
     }
 
+    fn on_failed(&self, realm: &Realm, reason: &str) {
+        self.history.record(realm.name(), "failed", reason);
+        let msg = Self::create_realm_signal("RealmFailed")
+            .append2(realm.name(), reason);
+        if let Err(e) = self.sender.send(msg) {
+            warn!("Could not send signal 'RealmFailed': {}", e);
+        }
+    }
+
+    fn on_usb_device_matched(&self, realm: &Realm, dev: &str) {
+        let msg = Self::create_realm_signal("UsbDeviceMatched")
+            .append2(realm.name(), dev);
+        if let Err(e) = self.sender.send(msg) {
+            warn!("Could not send signal 'UsbDeviceMatched': {}", e);
+        }
+    }
+
+    fn on_network_block_changed(&self, realm: &Realm, state: NetworkBlockState) {
+        if state == NetworkBlockState::Blocked {
+            self.history.record(realm.name(), "network-blocked", "");
+            self.send_realm_signal("RealmNetworkBlocked", Some(realm));
+        } else {
+            self.history.record(realm.name(), "network-restored", "");
+            self.send_realm_signal("RealmNetworkRestored", Some(realm));
+        }
+    }
+
+    fn on_removable_device_attached(&self, realm: &Realm, label: &str, path: &str) {
+        let msg = Self::create_realm_signal("RemovableDeviceAttached")
+            .append3(realm.name(), label, path);
+        if let Err(e) = self.sender.send(msg) {
+            warn!("Could not send signal 'RemovableDeviceAttached': {}", e);
+        }
+    }
+
+    fn on_snapshot_progress(&self, realm: &Realm, files: usize) {
+        let msg = Self::create_realm_signal("SnapshotProgress")
+            .append2(realm.name(), files as u64);
+        if let Err(e) = self.sender.send(msg) {
+            warn!("Could not send signal 'SnapshotProgress': {}", e);
+        }
+    }
+
+    fn on_clone_progress(&self, realm: &Realm, name: &str, files: usize) {
+        let msg = Self::create_realm_signal("CloneProgress")
+            .append3(realm.name(), name, files as u64);
+        if let Err(e) = self.sender.send(msg) {
+            warn!("Could not send signal 'CloneProgress': {}", e);
+        }
+    }
+
+    fn on_snapshot_finished(&self, realm: &Realm, result: Result<String>) {
+        let (id, error) = match result {
+            Ok(id) => (id, String::new()),
+            Err(e) => {
+                warn!("snapshot operation on realm {} failed: {}", realm.name(), e);
+                (String::new(), e.to_string())
+            }
+        };
+        let msg = Self::create_realm_signal("SnapshotFinished")
+            .append3(realm.name(), id, error);
+        if let Err(e) = self.sender.send(msg) {
+            warn!("Could not send signal 'SnapshotFinished': {}", e);
+        }
+    }
+
+    fn on_job_finished(&self, realm: &Realm, job: JobId, result: Result<()>) {
+        let error = match result {
+            Ok(()) => String::new(),
+            Err(e) => {
+                warn!("operation {} on realm {} failed: {}", job, realm.name(), e);
+                e.to_string()
+            }
+        };
+        let msg = Self::create_realm_signal("RealmJobFinished")
+            .append3(realm.name(), job, error);
+        if let Err(e) = self.sender.send(msg) {
+            warn!("Could not send signal 'RealmJobFinished': {}", e);
+        }
+    }
+
     fn create_realm_signal(name: &str) -> Message {
         let path = dbus::Path::new(OBJECT_PATH).unwrap();
         let iface = dbus::Interface::new(INTERFACE_NAME).unwrap();
@@ -329,15 +1468,94 @@ impl EventHandler {
 #[derive(Clone)]
 struct TreeData {
     manager: Arc<RealmManager>,
+    events: EventHandler,
+    config: Arc<RwLock<DaemonConfig>>,
+    start_limiter: Arc<Mutex<StartRateLimiter>>,
+    focus_limiter: Arc<Mutex<FocusRateLimiter>>,
+    switcher_cache: Arc<Mutex<Option<(Instant, SwitcherState)>>>,
+    queue: RealmQueue,
+    quit: Arc<AtomicBool>,
+    restart: Arc<AtomicBool>,
 }
 
 impl TreeData {
-    fn new(manager: Arc<RealmManager>) -> TreeData {
+    fn new(manager: Arc<RealmManager>, events: EventHandler, config: Arc<RwLock<DaemonConfig>>, quit: Arc<AtomicBool>, restart: Arc<AtomicBool>) -> TreeData {
         TreeData {
             manager,
+            events,
+            config,
+            start_limiter: Arc::new(Mutex::new(StartRateLimiter::default())),
+            focus_limiter: Arc::new(Mutex::new(FocusRateLimiter::default())),
+            switcher_cache: Arc::new(Mutex::new(None)),
+            queue: RealmQueue::new(MAX_QUEUED_OPERATIONS),
+            quit,
+            restart,
+        }
+    }
+
+    fn check_root_caller(&self, msg: &Message) -> result::Result<(), MethodErr> {
+        let uid = self.events.sender.caller_uid(msg)
+            .map_err(|e| MethodErr::failed(&format!("Could not identify caller: {}", e)))?;
+        if uid != 0 {
+            return Err(MethodErr::from((ERROR_ACCESS_DENIED, "Only root may call this method".to_string())));
+        }
+        Ok(())
+    }
+
+    ///
+    /// Add an operation which changes the state of `realm` to the queue of the
+    /// realm and return the job id sent in the RealmJobFinished signal when
+    /// the operation completes.
+    ///
+    fn enqueue<F>(&self, realm: &Realm, op: F) -> result::Result<JobId, MethodErr>
+        where F: FnOnce(&TreeData, &Realm) -> Result<()> + Send + 'static
+    {
+        let (job, position) = self.queue.enqueue(realm.name(), self.job(realm, op))
+            .map_err(Self::queue_error)?;
+        if position > 0 {
+            info!("Queued operation {} on realm {} behind {} others", job, realm.name(), position);
+        }
+        Ok(job)
+    }
+
+    /// Like `enqueue()` but fails with Error.Busy if any other operation is
+    /// running or waiting for `realm`, so that the operation starts immediately.
+    fn enqueue_if_idle<F>(&self, realm: &Realm, op: F) -> result::Result<JobId, MethodErr>
+        where F: FnOnce(&TreeData, &Realm) -> Result<()> + Send + 'static
+    {
+        self.queue.enqueue_if_idle(realm.name(), self.job(realm, op))
+            .map_err(Self::queue_error)
+    }
+
+    // Wrap `op` so that the RealmJobFinished signal is sent when it completes
+    fn job<F>(&self, realm: &Realm, op: F) -> impl FnOnce(JobId) + Send + 'static
+        where F: FnOnce(&TreeData, &Realm) -> Result<()> + Send + 'static
+    {
+        let data = self.clone();
+        let target = realm.clone();
+        move |job| {
+            let result = op(&data, &target);
+            data.events.on_job_finished(&target, job, result);
         }
     }
 
+    fn queue_error(e: failure::Error) -> MethodErr {
+        if e.downcast_ref::<QueueBusy>().is_some() {
+            MethodErr::from((ERROR_BUSY, e.to_string()))
+        } else {
+            MethodErr::failed(&e)
+        }
+    }
+
+    fn check_start_rate(&self, realm: &Realm) -> result::Result<(), MethodErr> {
+        let limit = self.config.read().unwrap().start_rate_limit();
+        if !self.start_limiter.lock().unwrap().allow(realm.name(), limit, Instant::now()) {
+            warn!("Refusing to start realm {}: more than {} start requests in the last minute", realm.name(), limit);
+            return result::Result::Err(MethodErr::failed(&format!("Too many start requests for realm {}, try again later", realm.name())));
+        }
+        Ok(())
+    }
+
     fn manager(&self) -> &RealmManager {
         &self.manager
     }
@@ -350,6 +1568,14 @@ impl TreeData {
         }
     }
 
+    fn active_realm_by_name(&self, name: &str) -> result::Result<Realm, MethodErr> {
+        let realm = self.realm_by_name(name)?;
+        if !realm.is_active() {
+            return result::Result::Err(MethodErr::failed(&format!("Realm {} is not running", name)));
+        }
+        Ok(realm)
+    }
+
     fn realm_list(&self) -> HashMap<String, u8> {
         self.manager.realm_list()
             .iter()
@@ -357,11 +1583,63 @@ impl TreeData {
             .collect()
     }
 
+    // The clipboard policy is empty for realms without a clipboard socket
+    fn realm_list_detailed(&self) -> Vec<(String, u8, u32, u64, u64, u64, String)> {
+        self.manager.realm_list()
+            .iter()
+            .map(|r| {
+                let uptime = r.uptime().map(|d| d.as_secs()).unwrap_or(0);
+                let (usage, quota) = Self::home_usage(r);
+                let clipboard = self.events.clipboard.policy(r.name()).map(|p| p.to_str_value()).unwrap_or("");
+                (r.name().to_owned(), Self::realm_status(r), self.manager.camera_device_count(r) as u32, uptime, usage, quota, clipboard.to_string())
+            })
+            .collect()
+    }
+
+    // Home directory usage and quota in bytes for ListDetailed. Usage is only read
+    // for realms with a quota since it may mean adding up the size of every file.
+    fn home_usage(realm: &Realm) -> (u64, u64) {
+        let quota = HomeQuota::new(realm);
+        let usage = quota.limit().and_then(|limit| match limit {
+            Some(limit) => Ok((quota.usage()?, limit)),
+            None => Ok((0, 0)),
+        });
+        usage.unwrap_or_else(|e| {
+            warn!("Failed to read home directory usage of realm {}: {}", realm.name(), e);
+            (0, 0)
+        })
+    }
+
+    // The realm switcher polls every second, so the state is cached briefly and
+    // the active state of all realms is read with a single systemctl call.
+    fn switcher_state(&self) -> Result<SwitcherState> {
+        let mut cache = self.switcher_cache.lock().unwrap();
+        if let Some((ref time, ref state)) = *cache {
+            if time.elapsed() < SWITCHER_STATE_CACHE_TIME {
+                return Ok(state.clone());
+            }
+        }
+        let realms = self.manager.realm_list_refreshed()?
+            .iter()
+            .map(|r| (r.name().to_owned(), Self::realm_status(r), r.config().gpu()))
+            .collect();
+        let current = self.manager.current_realm()
+            .map(|r| r.name().to_owned())
+            .unwrap_or_default();
+        let state = (current, realms);
+        *cache = Some((Instant::now(), state.clone()));
+        Ok(state)
+    }
+
     fn realm_status(realm: &Realm) -> u8 {
-        if realm.is_active() && realm.is_current() {
+        if realm.is_active() && realm.is_frozen() {
+            STATUS_REALM_FROZEN
+        } else if realm.is_active() && realm.is_current() {
             STATUS_REALM_RUNNING_CURRENT
         } else if realm.is_active() {
             STATUS_REALM_RUNNING_NOT_CURRENT
+        } else if realm.config_error().is_some() {
+            STATUS_REALM_CONFIG_ERROR
         } else {
             STATUS_REALM_NOT_RUNNING
         }
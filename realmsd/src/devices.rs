@@ -0,0 +1,181 @@
+use std::collections::HashSet;
+use std::path::{Path,PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use libcitadel::{RealmManager, Realm, UsbDevice, RemovableMedia, HOST_MEDIA_PATH, REALM_MEDIA_PATH};
+
+/// Interval between scans of attached USB devices
+const USB_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Interval between scans of removable media
+const MEDIA_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+///
+/// Watches for USB devices which are plugged in while a realm is running
+/// and which match the `usb-devices` config option of the realm.
+///
+/// Matching devices are only added to a realm when it starts, so the
+/// callback is used to notify the user that the realm must be restarted
+/// to use the device.
+///
+pub struct UsbMonitor {
+    manager: Arc<RealmManager>,
+}
+
+impl UsbMonitor {
+    pub fn new(manager: Arc<RealmManager>) -> Self {
+        UsbMonitor { manager }
+    }
+
+    /// Start a thread which calls `on_match` with the realm and device node
+    /// path each time a newly attached device matches a running realm.
+    pub fn start<F>(self, on_match: F)
+        where F: Fn(&Realm, &str) + Send + 'static
+    {
+        thread::spawn(move || {
+            let mut known = Self::device_names(&UsbDevice::scan());
+            loop {
+                thread::sleep(USB_POLL_INTERVAL);
+                let devices = UsbDevice::scan();
+                for dev in devices.iter().filter(|dev| !known.contains(dev.name())) {
+                    self.check_device(dev, &on_match);
+                }
+                known = Self::device_names(&devices);
+            }
+        });
+    }
+
+    fn check_device<F: Fn(&Realm, &str)>(&self, dev: &UsbDevice, on_match: &F) {
+        for realm in self.manager.active_realms(false) {
+            if realm.config().usb_devices().iter().any(|m| m.matches(dev)) {
+                let path = dev.dev_path();
+                info!("USB device {} ({}) matches running realm {}", path.display(), dev.id(), realm.name());
+                on_match(&realm, &path.to_string_lossy());
+            }
+        }
+    }
+
+    fn device_names(devices: &[UsbDevice]) -> HashSet<String> {
+        devices.iter().map(|dev| dev.name().to_string()).collect()
+    }
+}
+
+/// A removable filesystem mounted on the host and bound into a realm
+struct MediaMount {
+    device: String,
+    realm: String,
+    host_path: PathBuf,
+    realm_path: String,
+}
+
+///
+/// Mounts filesystems on removable devices which are plugged in while the
+/// current realm has the `auto-mount-removable` config option set.
+///
+/// The filesystem is mounted on the host below /run/citadel/media and bound
+/// into the current realm below /run/media. Media stay attached to the realm
+/// they were bound into when a different realm becomes current, and are
+/// unmounted when the device is removed or that realm stops.
+///
+pub struct MediaMonitor {
+    manager: Arc<RealmManager>,
+    mounts: Vec<MediaMount>,
+}
+
+impl MediaMonitor {
+    pub fn new(manager: Arc<RealmManager>) -> Self {
+        MediaMonitor { manager, mounts: Vec::new() }
+    }
+
+    /// Start a thread which calls `on_attach` with the realm, mount name and
+    /// path inside the realm each time a removable filesystem is bound into a realm.
+    pub fn start<F>(mut self, on_attach: F)
+        where F: Fn(&Realm, &str, &str) + Send + 'static
+    {
+        thread::spawn(move || {
+            let mut known = Self::device_names(&RemovableMedia::scan());
+            loop {
+                thread::sleep(MEDIA_POLL_INTERVAL);
+                let media = RemovableMedia::scan();
+                let present = Self::device_names(&media);
+                self.remove_stale_mounts(&present);
+                for m in media.iter().filter(|m| !known.contains(m.name())) {
+                    self.check_media(m, &on_attach);
+                }
+                known = present;
+            }
+        });
+    }
+
+    fn check_media<F: Fn(&Realm, &str, &str)>(&mut self, media: &RemovableMedia, on_attach: &F) {
+        let realm = match self.manager.current_realm() {
+            Some(realm) => realm,
+            None => return,
+        };
+        if !realm.is_active() || !realm.config().auto_mount_removable() {
+            return;
+        }
+        let name = self.unique_mount_name(media);
+        match self.attach(media, &realm, &name) {
+            Ok(realm_path) => on_attach(&realm, &name, &realm_path),
+            Err(e) => warn!("Failed to attach {} to realm {}: {}", media.dev_path().display(), realm.name(), e),
+        }
+    }
+
+    // Another device may already be mounted with the same label
+    fn unique_mount_name(&self, media: &RemovableMedia) -> String {
+        let name = media.mount_name();
+        if self.mounts.iter().any(|m| m.host_path.file_name() == Some(name.as_ref())) {
+            format!("{}-{}", name, media.name())
+        } else {
+            name
+        }
+    }
+
+    fn attach(&mut self, media: &RemovableMedia, realm: &Realm, name: &str) -> libcitadel::Result<String> {
+        let host_path = Path::new(HOST_MEDIA_PATH).join(name);
+        let realm_path = format!("{}/{}", REALM_MEDIA_PATH, name);
+        media.mount(&host_path)?;
+        if let Err(e) = self.manager.bind_into_realm(realm, &host_path, &realm_path) {
+            if let Err(e) = RemovableMedia::unmount(&host_path) {
+                warn!("{}", e);
+            }
+            return Err(e);
+        }
+        info!("Removable device {} mounted in realm {} at {}", media.dev_path().display(), realm.name(), realm_path);
+        self.mounts.push(MediaMount {
+            device: media.name().to_string(),
+            realm: realm.name().to_string(),
+            host_path,
+            realm_path: realm_path.clone(),
+        });
+        Ok(realm_path)
+    }
+
+    // Unmount media which were removed or which belong to a realm that is no longer running
+    fn remove_stale_mounts(&mut self, present: &HashSet<String>) {
+        let manager = self.manager.clone();
+        self.mounts.retain(|m| {
+            let realm = manager.realm_by_name(&m.realm).filter(|r| r.is_active());
+            if present.contains(&m.device) && realm.is_some() {
+                return true;
+            }
+            if let Some(realm) = realm {
+                if let Err(e) = manager.unbind_from_realm(&realm, &m.realm_path) {
+                    warn!("Failed to unmount {} in realm {}: {}", m.realm_path, m.realm, e);
+                }
+            }
+            match RemovableMedia::unmount(&m.host_path) {
+                Ok(()) => info!("Removable device {} unmounted from realm {}", m.device, m.realm),
+                Err(e) => warn!("Failed to unmount {}: {}", m.host_path.display(), e),
+            }
+            false
+        });
+    }
+
+    fn device_names(media: &[RemovableMedia]) -> HashSet<String> {
+        media.iter().map(|m| m.name().to_string()).collect()
+    }
+}
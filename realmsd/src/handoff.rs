@@ -0,0 +1,211 @@
+use std::env;
+use std::fs;
+use std::net::IpAddr;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, UNIX_EPOCH};
+
+use libcitadel::{RealmManager, Result, util};
+
+use crate::history::{EventHistory, HistoryEntry};
+
+/// File the runtime state is saved to when realmsd restarts itself, and which
+/// the new process restores from.
+pub const HANDOFF_STATE_PATH: &str = "/run/citadel/realmsd-state.json";
+
+/// Command line flag passed to the new process to restore the saved state
+pub const RESTORE_STATE_ARG: &str = "--restore-state";
+
+/// Version of the state file format. A file with any other version is ignored
+/// and the new process starts as it does at boot.
+const HANDOFF_STATE_VERSION: u32 = 1;
+
+#[derive(Serialize,Deserialize,Clone,Debug,PartialEq)]
+struct StartedRealm {
+    name: String,
+    // Seconds since the Unix epoch
+    started: u64,
+}
+
+#[derive(Serialize,Deserialize,Clone,Debug,PartialEq)]
+struct Allocation {
+    zone: String,
+    realm: String,
+    address: IpAddr,
+}
+
+///
+/// Runtime state of realmsd which is held only in memory, saved before realmsd
+/// executes a new (usually upgraded) binary of itself so that the new process
+/// continues where the old one stopped instead of starting as it does at boot.
+///
+#[derive(Serialize,Deserialize,Clone,Debug,PartialEq)]
+pub struct HandoffState {
+    version: u32,
+    current: Option<String>,
+    started: Vec<StartedRealm>,
+    allocations: Vec<Allocation>,
+    events: Vec<HistoryEntry>,
+}
+
+impl HandoffState {
+    pub fn capture(manager: &RealmManager, history: &EventHistory) -> Self {
+        let started = manager.active_realms(false).iter()
+            .flat_map(|realm| {
+                let started = realm.started_at()?.duration_since(UNIX_EPOCH).ok()?.as_secs();
+                Some(StartedRealm { name: realm.name().to_string(), started })
+            })
+            .collect();
+        let allocations = manager.network_allocations().into_iter()
+            .map(|(zone, realm, address)| Allocation { zone, realm, address })
+            .collect();
+        HandoffState {
+            version: HANDOFF_STATE_VERSION,
+            current: manager.current_realm().map(|realm| realm.name().to_string()),
+            started,
+            allocations,
+            events: history.entries(),
+        }
+    }
+
+    /// Write the state to /run/citadel/realmsd-state.json
+    pub fn save(&self) -> Result<()> {
+        self.write_file(Path::new(HANDOFF_STATE_PATH))
+    }
+
+    fn write_file(&self, path: &Path) -> Result<()> {
+        util::write_file_atomic(path, serde_json::to_string(self)?)
+    }
+
+    /// Read and remove the state saved by the process which executed this one.
+    /// Returns `None` if there is no saved state or it cannot be used.
+    pub fn take() -> Option<Self> {
+        Self::take_file(Path::new(HANDOFF_STATE_PATH))
+    }
+
+    fn take_file(path: &Path) -> Option<Self> {
+        if !path.exists() {
+            warn!("No saved state found in {}, starting without it", path.display());
+            return None;
+        }
+        let state = Self::read_file(path);
+        if let Err(e) = fs::remove_file(path) {
+            warn!("Failed to remove {}: {}", path.display(), e);
+        }
+        match state {
+            Ok(state) => Some(state),
+            Err(e) => {
+                warn!("Ignoring saved state in {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    fn read_file(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)?;
+        Self::parse(&content)
+    }
+
+    // The version is checked before the rest of the file is parsed because the
+    // other fields may be different in another version.
+    fn parse(content: &str) -> Result<Self> {
+        let value: serde_json::Value = serde_json::from_str(content)?;
+        match value.get("version").and_then(|v| v.as_u64()) {
+            Some(version) if version == u64::from(HANDOFF_STATE_VERSION) => {},
+            Some(version) => bail!("unsupported version {}", version),
+            None => bail!("version is missing"),
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// Apply the saved state. Realms which stopped while no realmsd process
+    /// was running are skipped, and a current realm which stopped is not
+    /// made current again since that would start it.
+    pub fn restore(self, manager: &RealmManager, history: &EventHistory) {
+        history.restore(self.events);
+        for started in &self.started {
+            if let Some(realm) = manager.realm_by_name(&started.name).filter(|realm| realm.is_active()) {
+                realm.restore_started_at(UNIX_EPOCH + Duration::from_secs(started.started));
+            }
+        }
+        let allocations = self.allocations.into_iter()
+            .map(|a| (a.zone, a.realm, a.address))
+            .collect::<Vec<_>>();
+        if let Err(e) = manager.restore_network_allocations(&allocations) {
+            warn!("Error restoring network address allocations: {}", e);
+        }
+        let current = self.current.as_deref()
+            .and_then(|name| manager.realm_by_name(name))
+            .filter(|realm| realm.is_active());
+        if let Some(realm) = current {
+            if let Err(e) = manager.set_current_realm(&realm) {
+                warn!("Failed to restore current realm {}: {}", realm.name(), e);
+            }
+        }
+    }
+}
+
+/// Replace this process with the realmsd binary, telling the new process to
+/// restore the saved state. Only returns if the binary cannot be executed.
+pub fn exec_restart() -> Result<()> {
+    let binary = binary_path(env::current_exe()?);
+    let args = env::args().skip(1)
+        .filter(|arg| arg != "--replace" && arg != RESTORE_STATE_ARG)
+        .collect::<Vec<_>>();
+    info!("Executing {} to hand over to a new realmsd process", binary.display());
+    let err = Command::new(&binary).args(&args).arg(RESTORE_STATE_ARG).exec();
+    bail!("failed to execute {}: {}", binary.display(), err)
+}
+
+// Once an upgrade has replaced the binary, the link to the executable of the
+// running process names the deleted file.
+fn binary_path(exe: PathBuf) -> PathBuf {
+    match exe.to_str().and_then(|path| path.strip_suffix(" (deleted)")) {
+        Some(path) => PathBuf::from(path),
+        None => exe,
+    }
+}
+
+#[test]
+fn test_handoff_state() {
+    let history = EventHistory::new(10);
+    history.record("main", "started", "");
+    history.record("work", "failed", "start limit hit");
+    let state = HandoffState {
+        version: HANDOFF_STATE_VERSION,
+        current: Some("main".to_string()),
+        started: vec![StartedRealm { name: "main".to_string(), started: 1_700_000_000 }],
+        allocations: vec![
+            Allocation { zone: "clear".to_string(), realm: "main".to_string(), address: "172.17.0.2".parse().unwrap() },
+            Allocation { zone: "clear".to_string(), realm: "main".to_string(), address: "fd17:c17a:de1::2".parse().unwrap() },
+        ],
+        events: history.entries(),
+    };
+
+    let dir = util::TempDir::new("realmsd-state").unwrap();
+    let path = dir.join("realmsd-state.json");
+    state.write_file(&path).unwrap();
+    let restored = HandoffState::take_file(&path);
+    assert!(!path.exists());
+    let restored = restored.unwrap();
+    assert_eq!(restored, state);
+    let restored_history = EventHistory::new(10);
+    restored_history.restore(restored.events);
+    assert_eq!(restored_history.query("", 0), history.query("", 0));
+
+    // The process starts without the saved state if the version does not match
+    let mut newer = state.clone();
+    newer.version = HANDOFF_STATE_VERSION + 1;
+    newer.write_file(&path).unwrap();
+    assert_eq!(HandoffState::take_file(&path), None);
+    assert!(!path.exists());
+
+    let err = HandoffState::parse(r#"{"version": 2, "realms": []}"#).unwrap_err();
+    assert_eq!(err.to_string(), "unsupported version 2");
+    assert!(HandoffState::parse(r#"{"current": "main"}"#).is_err());
+    assert_eq!(HandoffState::take_file(&path), None);
+
+    assert_eq!(binary_path(PathBuf::from("/usr/libexec/realmsd (deleted)")), PathBuf::from("/usr/libexec/realmsd"));
+    assert_eq!(binary_path(PathBuf::from("/usr/libexec/realmsd")), PathBuf::from("/usr/libexec/realmsd"));
+}
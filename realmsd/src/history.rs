@@ -0,0 +1,162 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use libcitadel::{Metrics, Result};
+
+/// Maximum number of events kept in the history
+pub const EVENT_HISTORY_SIZE: usize = 500;
+
+/// File the history is saved to when realmsd shuts down and read back from
+/// when it starts, so that restarting realmsd does not lose it.
+pub const EVENT_HISTORY_PATH: &str = "/run/citadel/realm-events.json";
+
+#[derive(Serialize,Deserialize,Clone,Debug,PartialEq)]
+pub struct HistoryEntry {
+    // Seconds since the Unix epoch
+    timestamp: u64,
+    realm: String,
+    event: String,
+    detail: String,
+}
+
+///
+/// The most recent realm status changes with the time each happened, so that
+/// a realm which repeatedly starts and fails can be investigated without
+/// searching the journal. Once full, the oldest event is dropped for each new one.
+///
+#[derive(Clone)]
+pub struct EventHistory {
+    entries: Arc<Mutex<VecDeque<HistoryEntry>>>,
+    capacity: usize,
+}
+
+impl EventHistory {
+    pub fn new(capacity: usize) -> Self {
+        EventHistory { entries: Arc::new(Mutex::new(VecDeque::new())), capacity }
+    }
+
+    /// Add the events saved to /run/citadel/realm-events.json if the file exists.
+    pub fn load_saved(&self) {
+        let path = Path::new(EVENT_HISTORY_PATH);
+        if path.exists() {
+            if let Err(e) = self.read_file(path) {
+                warn!("Failed to read realm event history from {}: {}", EVENT_HISTORY_PATH, e);
+            }
+        }
+    }
+
+    fn read_file(&self, path: &Path) -> Result<()> {
+        let content = fs::read_to_string(path)?;
+        let saved: Vec<HistoryEntry> = serde_json::from_str(&content)?;
+        self.restore(saved);
+        Ok(())
+    }
+
+    /// Write the history to /run/citadel/realm-events.json
+    pub fn save(&self) {
+        if let Err(e) = self.write_file(Path::new(EVENT_HISTORY_PATH)) {
+            warn!("Failed to save realm event history to {}: {}", EVENT_HISTORY_PATH, e);
+        }
+    }
+
+    fn write_file(&self, path: &Path) -> Result<()> {
+        let content = serde_json::to_string(&*self.entries.lock().unwrap())?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, content)?;
+        Ok(())
+    }
+
+    /// All events in the history, oldest first
+    pub fn entries(&self) -> Vec<HistoryEntry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Add events recorded by a previous realmsd process, oldest first
+    pub fn restore(&self, entries: Vec<HistoryEntry>) {
+        for entry in entries {
+            self.push(entry);
+        }
+    }
+
+    /// Add an event for `realm` which happened now and count it in the metrics.
+    pub fn record(&self, realm: &str, event: &str, detail: &str) {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Metrics::realm_event(realm, event);
+        self.push(HistoryEntry {
+            timestamp,
+            realm: realm.to_string(),
+            event: event.to_string(),
+            detail: detail.to_string(),
+        });
+    }
+
+    fn push(&self, entry: HistoryEntry) {
+        let mut entries = self.entries.lock().unwrap();
+        while entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    ///
+    /// Return (timestamp, event, detail) of the most recent `limit` events of
+    /// `realm`, oldest first. All events are returned if `limit` is 0. If `realm`
+    /// is empty the events of all realms are returned and the detail of each
+    /// event starts with the name of the realm.
+    ///
+    pub fn query(&self, realm: &str, limit: usize) -> Vec<(u64, String, String)> {
+        let entries = self.entries.lock().unwrap();
+        let mut matched = entries.iter()
+            .rev()
+            .filter(|e| realm.is_empty() || e.realm == realm)
+            .take(if limit == 0 { usize::MAX } else { limit })
+            .map(|e| {
+                let detail = if !realm.is_empty() {
+                    e.detail.clone()
+                } else if e.detail.is_empty() {
+                    e.realm.clone()
+                } else {
+                    format!("{}: {}", e.realm, e.detail)
+                };
+                (e.timestamp, e.event.clone(), detail)
+            })
+            .collect::<Vec<_>>();
+        matched.reverse();
+        matched
+    }
+}
+
+#[test]
+fn test_event_history() {
+    let history = EventHistory::new(3);
+    history.record("main", "started", "");
+    history.record("work", "started", "");
+    history.record("main", "failed", "start limit hit");
+    history.record("main", "stopped", "");
+
+    // The first event was dropped to keep 3 events
+    let all = history.query("", 0);
+    let events = all.iter().map(|(_, event, detail)| format!("{} {}", event, detail)).collect::<Vec<_>>();
+    assert_eq!(events, vec!["started work", "failed main: start limit hit", "stopped main"]);
+
+    let main = history.query("main", 0);
+    assert_eq!(main.iter().map(|(_, e, _)| e.as_str()).collect::<Vec<_>>(), vec!["failed", "stopped"]);
+    assert_eq!(main[0].2, "start limit hit");
+    assert_eq!(history.query("main", 1).len(), 1);
+    assert_eq!(history.query("main", 1)[0].1, "stopped");
+    assert!(history.query("other", 0).is_empty());
+
+    let dir = libcitadel::util::TempDir::new("realm-events").unwrap();
+    let path = dir.join("realm-events.json");
+    history.write_file(&path).unwrap();
+    let loaded = EventHistory::new(2);
+    loaded.read_file(&path).unwrap();
+    assert_eq!(loaded.query("", 0), all[1..].to_vec());
+}
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use libcitadel::{OsRelease, Realm, RealmEvent, RealmManager, Result, util};
+
+/// Name of the document in the info directory of each realm
+const REALM_INFO_FILE: &str = "realm.json";
+
+/// Contents of the info document of a realm
+#[derive(Serialize,Clone,Debug,PartialEq)]
+pub struct RealmInfo {
+    name: String,
+    description: String,
+    zone: String,
+    // Empty if the realm has no network address
+    ip: String,
+    current: bool,
+    #[serde(rename="citadel-version")]
+    citadel_version: String,
+}
+
+impl RealmInfo {
+    fn for_realm(manager: &RealmManager, realm: &Realm) -> Self {
+        let config = realm.config();
+        RealmInfo {
+            name: realm.name().to_string(),
+            description: config.description().to_string(),
+            zone: config.network_zone().to_string(),
+            ip: manager.realm_address(realm).map(|ip| ip.to_string()).unwrap_or_default(),
+            current: realm.is_current(),
+            citadel_version: OsRelease::get_value("VERSION_ID").unwrap_or("").to_string(),
+        }
+    }
+}
+
+///
+/// Writes a JSON document describing each running realm to the info directory
+/// of the realm, which is bind mounted read-only into the realm at
+/// /run/citadel/realm-info. Programs in a realm read it to find the name and
+/// zone of the realm and whether it is the current realm without access to
+/// the system bus. A realm can only see its own document.
+///
+///     {"name":"main","description":"","zone":"clear","ip":"172.17.0.2",
+///      "current":true,"citadel-version":"1"}
+///
+#[derive(Default)]
+pub struct RealmInfoWriter {
+    // Document last written for each realm, so that only changed documents are written again
+    written: Mutex<HashMap<String, RealmInfo>>,
+}
+
+impl RealmInfoWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn handle_event(&self, manager: &RealmManager, ev: &RealmEvent) {
+        match ev {
+            RealmEvent::Started(realm) | RealmEvent::ConfigChanged(realm) if realm.is_active() => {
+                self.update(&[(realm.info_path(), RealmInfo::for_realm(manager, realm))]);
+            },
+            // Changing the current realm changes the document of both the
+            // previous and the new current realm
+            RealmEvent::Current(_) => self.write_running(manager),
+            RealmEvent::Stopped(realm) | RealmEvent::Removed(realm) => {
+                self.written.lock().unwrap().remove(realm.name());
+            },
+            _ => {},
+        }
+    }
+
+    /// Write the document of every running realm
+    pub fn write_running(&self, manager: &RealmManager) {
+        let documents = manager.active_realms(false).iter()
+            .map(|realm| (realm.info_path(), RealmInfo::for_realm(manager, realm)))
+            .collect::<Vec<_>>();
+        self.update(&documents);
+    }
+
+    // Write each document which differs from the one last written to its directory
+    fn update(&self, documents: &[(PathBuf, RealmInfo)]) {
+        let mut written = self.written.lock().unwrap();
+        for (dir, info) in documents {
+            if written.get(&info.name) == Some(info) {
+                continue;
+            }
+            match Self::write_document(dir, info) {
+                Ok(()) => { written.insert(info.name.clone(), info.clone()); },
+                Err(e) => warn!("Failed to write info document for realm {} to {}: {}", info.name, dir.display(), e),
+            }
+        }
+    }
+
+    fn write_document(dir: &Path, info: &RealmInfo) -> Result<()> {
+        util::write_file_atomic(dir.join(REALM_INFO_FILE), serde_json::to_string(info)?)
+    }
+}
+
+#[test]
+fn test_realm_info_writer() {
+    use std::fs;
+    let base = util::TempDir::new("realm-info").unwrap();
+    let info = |name: &str, current: bool| RealmInfo {
+        name: name.to_string(),
+        description: format!("{} realm", name),
+        zone: "clear".to_string(),
+        ip: String::new(),
+        current,
+        citadel_version: "1".to_string(),
+    };
+    let read = |name: &str| -> serde_json::Value {
+        serde_json::from_str(&fs::read_to_string(base.join(name).join(REALM_INFO_FILE)).unwrap()).unwrap()
+    };
+
+    let writer = RealmInfoWriter::new();
+    writer.update(&[(base.join("main"), info("main", true)), (base.join("work"), info("work", false))]);
+    let main_before = read("main");
+    let work_before = read("work");
+
+    // The documents written when the current realm changes from main to work
+    writer.update(&[(base.join("main"), info("main", false)), (base.join("work"), info("work", true))]);
+    let main_after = read("main");
+    let work_after = read("work");
+    let leftover = fs::read_dir(base.join("main")).unwrap().count();
+
+    assert_eq!(main_before["current"], true);
+    assert_eq!(work_before["current"], false);
+    assert_eq!(main_after["current"], false);
+    assert_eq!(work_after["current"], true);
+    assert_eq!(work_after["name"], "work");
+    assert_eq!(work_after["description"], "work realm");
+    assert_eq!(work_after["citadel-version"], "1");
+    assert_eq!(leftover, 1);
+}
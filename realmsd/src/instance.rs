@@ -0,0 +1,130 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libcitadel::Result;
+
+/// Exit status of realmsd when another instance already owns the bus name
+pub const EXIT_ALREADY_RUNNING: i32 = 3;
+
+/// Time to wait for a replaced instance to shut down and release the bus name
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(10);
+const REPLACE_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Result of requesting the bus name without replacing an existing owner
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum NameRequest {
+    Acquired,
+    Owned,
+}
+
+/// Error returned when realmsd does not start because another instance owns the bus name
+#[derive(Debug,Fail)]
+#[fail(display = "realmsd is already running{}, use --replace to replace it", owner)]
+pub struct AlreadyRunning {
+    owner: String,
+}
+
+impl AlreadyRunning {
+    pub fn new(owner_pid: Option<u32>) -> Self {
+        let owner = owner_pid.map(|pid| format!(" as pid {}", pid)).unwrap_or_default();
+        AlreadyRunning { owner }
+    }
+}
+
+/// Operations on the bus name of realmsd, implemented by `DbusServer`
+pub trait BusName {
+    fn request_name(&self) -> Result<NameRequest>;
+
+    /// Process id of the connection which currently owns the name
+    fn owner_pid(&self) -> Option<u32>;
+
+    /// Call the Quit method of the instance which currently owns the name
+    fn quit_owner(&self) -> Result<()>;
+}
+
+///
+/// Acquire the bus name for this instance of realmsd. If another instance
+/// owns the name an `AlreadyRunning` error is returned, unless `replace` is
+/// set in which case the other instance is asked to shut down and the name
+/// is acquired once it has been released.
+///
+pub fn acquire_name<B: BusName>(bus: &B, replace: bool) -> Result<()> {
+    acquire_name_with_timeout(bus, replace, REPLACE_TIMEOUT)
+}
+
+fn acquire_name_with_timeout<B: BusName>(bus: &B, replace: bool, timeout: Duration) -> Result<()> {
+    if bus.request_name()? == NameRequest::Acquired {
+        return Ok(());
+    }
+    let owner = bus.owner_pid();
+    if !replace {
+        return Err(AlreadyRunning::new(owner).into());
+    }
+    match owner {
+        Some(pid) => info!("Asking running realmsd instance with pid {} to shut down", pid),
+        None => info!("Asking running realmsd instance to shut down"),
+    }
+    bus.quit_owner()
+        .map_err(|e| format_err!("failed to ask running realmsd instance to shut down: {}", e))?;
+
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if bus.request_name()? == NameRequest::Acquired {
+            return Ok(());
+        }
+        thread::sleep(REPLACE_POLL_INTERVAL);
+    }
+    bail!("running realmsd instance did not shut down within {} seconds", timeout.as_secs())
+}
+
+#[test]
+fn test_acquire_name() {
+    use std::cell::{Cell, RefCell};
+    use NameRequest::{Acquired, Owned};
+
+    struct MockBus {
+        // Results returned by successive name requests
+        requests: RefCell<Vec<NameRequest>>,
+        quit_calls: Cell<usize>,
+    }
+
+    impl BusName for MockBus {
+        fn request_name(&self) -> Result<NameRequest> {
+            Ok(self.requests.borrow_mut().remove(0))
+        }
+
+        fn owner_pid(&self) -> Option<u32> {
+            Some(1234)
+        }
+
+        fn quit_owner(&self) -> Result<()> {
+            self.quit_calls.set(self.quit_calls.get() + 1);
+            Ok(())
+        }
+    }
+
+    let bus = |requests: &[NameRequest]| MockBus {
+        requests: RefCell::new(requests.to_vec()),
+        quit_calls: Cell::new(0),
+    };
+
+    let free = bus(&[Acquired]);
+    assert!(acquire_name(&free, false).is_ok());
+    assert_eq!(free.quit_calls.get(), 0);
+
+    let running = bus(&[Owned]);
+    let err = acquire_name(&running, false).unwrap_err();
+    assert!(err.downcast_ref::<AlreadyRunning>().is_some());
+    assert_eq!(err.to_string(), "realmsd is already running as pid 1234, use --replace to replace it");
+    assert_eq!(running.quit_calls.get(), 0);
+
+    // The name is acquired once the replaced instance has released it
+    let replaced = bus(&[Owned, Owned, Acquired]);
+    assert!(acquire_name(&replaced, true).is_ok());
+    assert_eq!(replaced.quit_calls.get(), 1);
+
+    let stuck = bus(&[Owned; 64]);
+    let err = acquire_name_with_timeout(&stuck, true, Duration::from_millis(300)).unwrap_err();
+    assert!(err.downcast_ref::<AlreadyRunning>().is_none());
+    assert!(err.to_string().contains("did not shut down"), "{}", err);
+}
@@ -1,18 +1,88 @@
 #[macro_use] extern crate libcitadel;
+#[macro_use] extern crate failure;
+#[macro_use] extern crate serde_derive;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
 use libcitadel::{RealmManager,Result};
 
+use crate::config::DaemonConfig;
+use crate::handoff::{HandoffState, RESTORE_STATE_ARG};
+use crate::instance::{AlreadyRunning, EXIT_ALREADY_RUNNING};
+
+mod clipboard;
+mod config;
 mod dbus;
 mod devices;
+mod handoff;
+mod history;
+mod info;
+mod instance;
+mod metrics;
+mod queue;
+mod signals;
+mod vpn;
 
 fn main() {
-    if let Err(e) = run_dbus_server() {
+    // Replace a running instance instead of refusing to start
+    let replace = std::env::args().skip(1).any(|arg| arg == "--replace");
+    // Executed by a running instance which is restarting itself
+    let restore = std::env::args().skip(1).any(|arg| arg == RESTORE_STATE_ARG);
+    if let Err(e) = run_dbus_server(replace, restore) {
         warn!("Error: {}", e);
+        if e.downcast_ref::<AlreadyRunning>().is_some() {
+            process::exit(EXIT_ALREADY_RUNNING);
+        }
     }
 }
 
-fn run_dbus_server() -> Result<()> {
+fn run_dbus_server(replace: bool, restore: bool) -> Result<()> {
+    let config = DaemonConfig::load()?;
+    config.apply_log_level();
     let manager = RealmManager::load()?;
-    let server = dbus::DbusServer::connect(manager)?;
-    server.start()?;
+    // The bus name is acquired before cleaning up after a previous instance
+    // so that the state of an instance which is still running is not changed.
+    let server = dbus::DbusServer::connect(manager.clone(), config.clone())?;
+    let restored = if restore {
+        server.acquire_name_for_handoff()?;
+        HandoffState::take()
+    } else {
+        server.acquire_name(replace)?;
+        None
+    };
+    manager.set_start_thresholds(config.start_thresholds());
+    if let Err(e) = manager.install_firewall() {
+        warn!("Error installing firewall: {}", e);
+    }
+    if let Err(e) = manager.remove_orphaned_netns() {
+        warn!("Error removing orphaned network namespaces: {}", e);
+    }
+    if let Err(e) = manager.flush_orphaned_port_forwards() {
+        warn!("Error removing orphaned port forwards: {}", e);
+    }
+    if let Err(e) = manager.remove_orphaned_launch_config_files() {
+        warn!("Error removing launch config files of deleted realms: {}", e);
+    }
+    if let Err(e) = manager.terminate_stale_machines() {
+        warn!("Error terminating stale realm machines: {}", e);
+    }
+    reconcile_network_allocations(manager.clone(), config.reconcile_interval());
+    if config.metrics_socket() {
+        if let Err(e) = metrics::MetricsListener::start() {
+            warn!("Error starting metrics listener: {}", e);
+        }
+    }
+    server.start(restored)?;
     Ok(())
 }
+
+fn reconcile_network_allocations(manager: Arc<RealmManager>, interval: Duration) {
+    thread::spawn(move || loop {
+        if let Err(e) = manager.reconcile_network_allocations() {
+            warn!("Error freeing network addresses of stopped realms: {}", e);
+        }
+        thread::sleep(interval);
+    });
+}
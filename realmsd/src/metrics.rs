@@ -0,0 +1,52 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::thread;
+
+use libcitadel::{Metrics, Result};
+
+/// Socket on which metrics are served over HTTP when `metrics-socket` is enabled in realmsd.conf
+pub const METRICS_SOCKET_PATH: &str = "/run/citadel/metrics.sock";
+
+///
+/// Serves the metrics in the Prometheus text exposition format to every HTTP
+/// request received on /run/citadel/metrics.sock, whatever the request path.
+///
+pub struct MetricsListener;
+
+impl MetricsListener {
+    pub fn start() -> Result<()> {
+        let path = Path::new(METRICS_SOCKET_PATH);
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)
+            .map_err(|e| format_err!("failed to bind {}: {}", METRICS_SOCKET_PATH, e))?;
+        info!("Serving metrics on {}", METRICS_SOCKET_PATH);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => if let Err(e) = Self::handle_request(stream) {
+                        verbose!("Error handling metrics request: {}", e);
+                    },
+                    Err(e) => warn!("Error accepting connection on {}: {}", METRICS_SOCKET_PATH, e),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn handle_request(stream: UnixStream) -> Result<()> {
+        // Read the request headers up to the empty line, the request itself is ignored
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 0 && !line.trim_end().is_empty() {
+            line.clear();
+        }
+        let body = Metrics::render();
+        let mut stream = stream;
+        write!(stream, "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}", body.len(), body)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use libcitadel::Result;
+
+/// Maximum number of operations running or waiting for one realm
+pub const MAX_QUEUED_OPERATIONS: usize = 8;
+
+/// Identifies an operation added to the queue, included in the RealmJobFinished signal
+pub type JobId = u64;
+
+type Operation = Box<dyn FnOnce(JobId) + Send>;
+
+/// Error returned when an operation is added for a realm which already has
+/// the maximum number of operations queued.
+#[derive(Debug,Fail)]
+#[fail(display = "realm {} is busy with {} queued operations, try again later", realm, queued)]
+pub struct QueueBusy {
+    realm: String,
+    queued: usize,
+}
+
+#[derive(Default)]
+struct RealmJobs {
+    pending: VecDeque<(JobId, Operation)>,
+    // Job which the worker thread is running
+    active: Option<JobId>,
+    has_worker: bool,
+}
+
+#[derive(Default)]
+struct QueueState {
+    next_job: JobId,
+    realms: HashMap<String, RealmJobs>,
+}
+
+///
+/// Runs operations which change the state of a realm, such as starting or
+/// stopping it, strictly one at a time and in the order they were added for
+/// each realm. Operations on different realms run in parallel, each realm
+/// with queued operations having its own worker thread.
+///
+#[derive(Clone)]
+pub struct RealmQueue {
+    state: Arc<Mutex<QueueState>>,
+    limit: usize,
+}
+
+impl RealmQueue {
+    pub fn new(limit: usize) -> Self {
+        RealmQueue { state: Arc::new(Mutex::new(QueueState::default())), limit }
+    }
+
+    ///
+    /// Add `op` to the queue of `realm` and return the job id passed to `op`
+    /// together with the number of operations which run before it. Returns a
+    /// `QueueBusy` error if the realm already has the maximum number of
+    /// operations queued.
+    ///
+    pub fn enqueue<F>(&self, realm: &str, op: F) -> Result<(JobId, usize)>
+        where F: FnOnce(JobId) + Send + 'static
+    {
+        self.push(realm, self.limit, op)
+    }
+
+    ///
+    /// Add `op` to the queue of `realm` only if no other operation is running
+    /// or waiting for the realm, so that it starts running immediately. Returns
+    /// a `QueueBusy` error otherwise.
+    ///
+    pub fn enqueue_if_idle<F>(&self, realm: &str, op: F) -> Result<JobId>
+        where F: FnOnce(JobId) + Send + 'static
+    {
+        self.push(realm, 1, op).map(|(job, _)| job)
+    }
+
+    fn push<F>(&self, realm: &str, limit: usize, op: F) -> Result<(JobId, usize)>
+        where F: FnOnce(JobId) + Send + 'static
+    {
+        let mut state = self.state.lock().unwrap();
+        let position = state.realms.get(realm)
+            .map(|jobs| jobs.pending.len() + jobs.active.is_some() as usize)
+            .unwrap_or(0);
+        if position >= limit {
+            return Err(QueueBusy { realm: realm.to_string(), queued: position }.into());
+        }
+        state.next_job += 1;
+        let job = state.next_job;
+        let jobs = state.realms.entry(realm.to_string()).or_default();
+        jobs.pending.push_back((job, Box::new(op)));
+        if !jobs.has_worker {
+            jobs.has_worker = true;
+            let queue = self.clone();
+            let realm = realm.to_string();
+            thread::spawn(move || queue.run_worker(&realm));
+        }
+        Ok((job, position))
+    }
+
+    // Run the operations queued for `realm` until there are none left
+    fn run_worker(&self, realm: &str) {
+        loop {
+            let next = {
+                let mut state = self.state.lock().unwrap();
+                let jobs = state.realms.get_mut(realm).expect("worker running for realm without queue");
+                let next = jobs.pending.pop_front();
+                jobs.active = next.as_ref().map(|&(job, _)| job);
+                if next.is_none() {
+                    state.realms.remove(realm);
+                }
+                next
+            };
+            match next {
+                Some((job, op)) => op(job),
+                None => return,
+            }
+        }
+    }
+}
+
+#[test]
+fn test_realm_queue_order() {
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    // A mock manager recording when each operation starts and finishes
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let queue = RealmQueue::new(MAX_QUEUED_OPERATIONS);
+    let (done_tx, done_rx) = mpsc::channel();
+    let mut jobs = Vec::new();
+    for (realm, op) in &[("main", "start"), ("main", "stop"), ("work", "start"), ("main", "start")] {
+        let log = log.clone();
+        let done = done_tx.clone();
+        let name = format!("{} {}", op, realm);
+        let (job, position) = queue.enqueue(realm, move |job| {
+            log.lock().unwrap().push(format!("begin {}", name));
+            thread::sleep(Duration::from_millis(50));
+            log.lock().unwrap().push(format!("end {}", name));
+            done.send(job).unwrap();
+        }).unwrap();
+        jobs.push((job, position));
+    }
+    let positions = jobs.iter().map(|&(_, p)| p).collect::<Vec<_>>();
+    assert_eq!(positions, vec![0, 1, 0, 2]);
+    let mut finished = (0..4).map(|_| done_rx.recv_timeout(Duration::from_secs(5)).unwrap()).collect::<Vec<_>>();
+    finished.sort_unstable();
+    assert_eq!(finished, jobs.iter().map(|&(j, _)| j).collect::<Vec<_>>());
+
+    let log = log.lock().unwrap();
+    let main = log.iter().filter(|e| e.ends_with(" main")).cloned().collect::<Vec<_>>();
+    assert_eq!(main, vec!["begin start main", "end start main", "begin stop main", "end stop main",
+                          "begin start main", "end start main"]);
+    // work does not wait for the operations queued for main
+    let pos = |entry: &str| log.iter().position(|e| e == entry).unwrap();
+    assert!(pos("begin start work") < pos("end start main"));
+}
+
+#[test]
+fn test_realm_queue_limit() {
+    use std::sync::mpsc;
+
+    let queue = RealmQueue::new(2);
+    let (release_tx, release_rx) = mpsc::channel::<()>();
+    let (done_tx, done_rx) = mpsc::channel();
+    queue.enqueue("main", move |_| { release_rx.recv().unwrap(); }).unwrap();
+    let done = done_tx.clone();
+    queue.enqueue("main", move |job| done.send(job).unwrap()).unwrap();
+
+    let busy = queue.enqueue("main", |_| {}).unwrap_err();
+    assert!(busy.downcast_ref::<QueueBusy>().is_some());
+    assert_eq!(busy.to_string(), "realm main is busy with 2 queued operations, try again later");
+    let busy = queue.enqueue_if_idle("main", |_| {}).unwrap_err();
+    assert!(busy.downcast_ref::<QueueBusy>().is_some());
+    assert!(queue.enqueue("work", move |job| done_tx.send(job).unwrap()).is_ok());
+
+    release_tx.send(()).unwrap();
+    assert_eq!(done_rx.iter().take(2).count(), 2);
+    let (_, position) = queue.enqueue("main", |_| {}).unwrap();
+    assert!(position < 2);
+
+    let (done_tx, done_rx) = mpsc::channel();
+    let job = queue.enqueue_if_idle("idle", move |job| done_tx.send(job).unwrap()).unwrap();
+    assert_eq!(done_rx.recv().unwrap(), job);
+}
@@ -0,0 +1,142 @@
+use std::collections::VecDeque;
+use std::sync::{Mutex, RwLock};
+
+use libcitadel::Result;
+
+/// Maximum number of signals kept while realmsd is disconnected from the bus
+pub const PENDING_SIGNALS_SIZE: usize = 256;
+
+/// Sends signals on a bus connection, implemented for the system bus connection
+pub trait SignalSink<M>: Send + Sync {
+    fn send(&self, msg: &M) -> Result<()>;
+}
+
+struct PendingSignals<M> {
+    queue: VecDeque<M>,
+    // Number of signals dropped because the queue was full
+    dropped: usize,
+}
+
+///
+/// Sends signals on the current bus connection, which is replaced when realmsd
+/// reconnects after the system bus restarts. Signals sent while disconnected or
+/// which fail to send are queued and sent in order once a new connection is
+/// swapped in. When the queue is full the oldest signal is dropped.
+///
+pub struct SignalSender<M> {
+    // None while disconnected from the bus
+    sink: RwLock<Option<Box<dyn SignalSink<M>>>>,
+    pending: Mutex<PendingSignals<M>>,
+    capacity: usize,
+}
+
+impl <M> SignalSender<M> {
+    pub fn new(sink: Box<dyn SignalSink<M>>, capacity: usize) -> Self {
+        SignalSender {
+            sink: RwLock::new(Some(sink)),
+            pending: Mutex::new(PendingSignals { queue: VecDeque::new(), dropped: 0 }),
+            capacity,
+        }
+    }
+
+    /// Send `msg` after any queued signals. If the signal cannot be sent it is
+    /// queued and an error is returned. Sending while disconnected is not an error.
+    pub fn send(&self, msg: M) -> Result<()> {
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.queue.len() >= self.capacity {
+                pending.queue.pop_front();
+                pending.dropped += 1;
+            }
+            pending.queue.push_back(msg);
+        }
+        self.flush()
+    }
+
+    /// Queue signals instead of sending them until `reconnected()` is called.
+    pub fn disconnected(&self) {
+        *self.sink.write().unwrap() = None;
+    }
+
+    /// Send signals on `sink` from now on, starting with the queued signals.
+    pub fn reconnected(&self, sink: Box<dyn SignalSink<M>>) -> Result<()> {
+        *self.sink.write().unwrap() = Some(sink);
+        let dropped = {
+            let mut pending = self.pending.lock().unwrap();
+            let dropped = pending.dropped;
+            pending.dropped = 0;
+            dropped
+        };
+        if dropped > 0 {
+            warn!("{} signals were dropped while disconnected from the bus", dropped);
+        }
+        self.flush()
+    }
+
+    // Send queued signals in order, stopping at the first one which fails
+    fn flush(&self) -> Result<()> {
+        let sink = self.sink.read().unwrap();
+        let sink = match sink.as_ref() {
+            Some(sink) => sink,
+            None => return Ok(()),
+        };
+        let mut pending = self.pending.lock().unwrap();
+        while let Some(msg) = pending.queue.front() {
+            sink.send(msg)?;
+            pending.queue.pop_front();
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_signal_sender_reconnect() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    struct MockSink {
+        sent: Arc<Mutex<Vec<String>>>,
+        fail: Arc<AtomicBool>,
+    }
+
+    impl SignalSink<String> for MockSink {
+        fn send(&self, msg: &String) -> Result<()> {
+            if self.fail.load(Ordering::SeqCst) {
+                bail!("connection closed");
+            }
+            self.sent.lock().unwrap().push(msg.clone());
+            Ok(())
+        }
+    }
+
+    let sink = || {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let fail = Arc::new(AtomicBool::new(false));
+        (MockSink { sent: sent.clone(), fail: fail.clone() }, sent, fail)
+    };
+
+    let (old, old_sent, old_fail) = sink();
+    let sender = SignalSender::new(Box::new(old), 3);
+    sender.send("started main".to_string()).unwrap();
+    assert_eq!(*old_sent.lock().unwrap(), vec!["started main"]);
+
+    // A signal which fails to send on the dead connection is kept
+    old_fail.store(true, Ordering::SeqCst);
+    assert!(sender.send("stopped main".to_string()).is_err());
+
+    // Only the most recent signals are kept while disconnected
+    sender.disconnected();
+    for event in &["started work", "current work", "stopped work"] {
+        sender.send(event.to_string()).unwrap();
+    }
+
+    let (new, new_sent, _) = sink();
+    sender.reconnected(Box::new(new)).unwrap();
+    assert_eq!(*new_sent.lock().unwrap(), vec!["started work", "current work", "stopped work"]);
+    assert_eq!(old_sent.lock().unwrap().len(), 1);
+    assert_eq!(sender.pending.lock().unwrap().dropped, 0);
+
+    sender.send("started main".to_string()).unwrap();
+    assert_eq!(new_sent.lock().unwrap().last().unwrap(), "started main");
+    assert!(sender.pending.lock().unwrap().queue.is_empty());
+}
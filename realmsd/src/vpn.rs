@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+use libcitadel::{RealmManager, Realm, NetworkBlockState};
+
+///
+/// Keeps the VPN kill switch of each running realm with the `vpn-required`
+/// config option in step with the state of its VPN interface, so that traffic
+/// from the realm is dropped while the VPN is down and allowed again through
+/// the VPN interface once it comes back up.
+///
+pub struct VpnMonitor {
+    manager: Arc<RealmManager>,
+    states: HashMap<String, NetworkBlockState>,
+}
+
+impl VpnMonitor {
+    pub fn new(manager: Arc<RealmManager>) -> Self {
+        VpnMonitor { manager, states: HashMap::new() }
+    }
+
+    /// Start a thread which calls `on_change` each time the traffic of a realm
+    /// is blocked or restored. VPN interfaces are checked once when the thread
+    /// starts and then each time a message is sent on the returned channel.
+    pub fn start<F>(mut self, on_change: F) -> Sender<()>
+        where F: Fn(&Realm, NetworkBlockState) + Send + 'static
+    {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            self.check_realms(&on_change);
+            for () in receiver {
+                self.check_realms(&on_change);
+            }
+        });
+        sender
+    }
+
+    fn check_realms<F: Fn(&Realm, NetworkBlockState)>(&mut self, on_change: &F) {
+        let realms = self.manager.active_realms(false).into_iter()
+            .filter(|realm| realm.config().vpn_required().is_some())
+            .collect::<Vec<_>>();
+        self.states.retain(|name, _| realms.iter().any(|realm| realm.name() == name));
+
+        for realm in realms {
+            if let Err(e) = self.manager.update_kill_switch(&realm) {
+                warn!("Failed to update VPN kill switch of realm {}: {}", realm.name(), e);
+            }
+            let state = match self.manager.network_block_state(&realm) {
+                Ok(state) => state,
+                Err(e) => {
+                    warn!("Failed to read VPN kill switch state of realm {}: {}", realm.name(), e);
+                    continue;
+                }
+            };
+            let previous = self.states.insert(realm.name().to_string(), state);
+            if previous != Some(state) && (state == NetworkBlockState::Blocked || previous == Some(NetworkBlockState::Blocked)) {
+                info!("Network traffic of realm {} is {}", realm.name(), state.to_str_value());
+                on_change(&realm, state);
+            }
+        }
+    }
+}